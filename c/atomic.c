@@ -16,3 +16,19 @@ u64 cas_release(u64 *ptr, u64 *expect, u64 desired) {
 
 	return ret;
 }
+
+#if defined(__has_feature)
+#if __has_feature(thread_sanitizer)
+#define __SANITIZE_THREAD__ 1
+#endif
+#endif
+
+#ifdef __SANITIZE_THREAD__
+void __tsan_acquire(void *addr);
+void __tsan_release(void *addr);
+void tsan_acquire(void *addr) { __tsan_acquire(addr); }
+void tsan_release(void *addr) { __tsan_release(addr); }
+#else
+void tsan_acquire(void *addr) {}
+void tsan_release(void *addr) {}
+#endif
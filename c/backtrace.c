@@ -10,6 +10,7 @@
 #include <util.h>
 
 extern long long __alloc_count;
+void *alloc(unsigned long size);
 int backtrace(void **array, int capacity);
 char **backtrace_symbols(void **array, int capacity);
 
@@ -68,11 +69,9 @@ void backtrace_free(Backtrace *bt) {
 char *backtrace_to_string(Backtrace *bt, char *binary) {
 	if (bt == NULL || binary == NULL) return NULL;
 	bool term = false;
-	char *ret = malloc(MAX_BACKTRACE_LEN);
+	// the caller frees it with release
+	char *ret = alloc(MAX_BACKTRACE_LEN);
 	if (ret == NULL) return NULL;
-#ifdef TEST
-	__alloc_count++;
-#endif	// TEST
 	cstring_cat_n(ret, NULL, 0);
 	int len_sum = 0;
 
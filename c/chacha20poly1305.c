@@ -0,0 +1,330 @@
+#include <memory.h>
+#include <stddef.h>
+
+// ChaCha20-Poly1305 authenticated encryption as specified in RFC 8439
+
+#define ROTL32(v, n) (((v) << (n)) | ((v) >> (32 - (n))))
+#define QR(a, b, c, d)                 \
+	a += b;                        \
+	d = ROTL32(d ^ a, 16);         \
+	c += d;                        \
+	b = ROTL32(b ^ c, 12);         \
+	a += b;                        \
+	d = ROTL32(d ^ a, 8);          \
+	c += d;                        \
+	b = ROTL32(b ^ c, 7);
+
+typedef struct {
+	unsigned int r[5];
+	unsigned int h[5];
+	unsigned int pad[4];
+	unsigned char buf[16];
+	size_t buflen;
+} POLY1305_CTX;
+
+static unsigned int load32_le(const unsigned char *p) {
+	return (unsigned int)p[0] | ((unsigned int)p[1] << 8) |
+	       ((unsigned int)p[2] << 16) | ((unsigned int)p[3] << 24);
+}
+
+static void store32_le(unsigned char *p, unsigned int v) {
+	p[0] = v;
+	p[1] = v >> 8;
+	p[2] = v >> 16;
+	p[3] = v >> 24;
+}
+
+static void chacha20_block(const unsigned char key[32], unsigned int counter,
+			   const unsigned char nonce[12],
+			   unsigned char out[64]) {
+	unsigned int state[16], x[16];
+	int i;
+	state[0] = 0x61707865;
+	state[1] = 0x3320646e;
+	state[2] = 0x79622d32;
+	state[3] = 0x6b206574;
+	for (i = 0; i < 8; i++) state[4 + i] = load32_le(key + 4 * i);
+	state[12] = counter;
+	for (i = 0; i < 3; i++) state[13 + i] = load32_le(nonce + 4 * i);
+	memcpy(x, state, sizeof(x));
+	for (i = 0; i < 10; i++) {
+		QR(x[0], x[4], x[8], x[12]);
+		QR(x[1], x[5], x[9], x[13]);
+		QR(x[2], x[6], x[10], x[14]);
+		QR(x[3], x[7], x[11], x[15]);
+		QR(x[0], x[5], x[10], x[15]);
+		QR(x[1], x[6], x[11], x[12]);
+		QR(x[2], x[7], x[8], x[13]);
+		QR(x[3], x[4], x[9], x[14]);
+	}
+	for (i = 0; i < 16; i++) store32_le(out + 4 * i, x[i] + state[i]);
+}
+
+// xors len bytes of in with the keystream starting at block counter into out,
+// which may be the same buffer
+void chacha20_xor(const unsigned char key[32], unsigned int counter,
+		  const unsigned char nonce[12], const unsigned char *in,
+		  unsigned char *out, size_t len) {
+	unsigned char block[64];
+	size_t i, n;
+	while (len > 0) {
+		chacha20_block(key, counter++, nonce, block);
+		n = len < 64 ? len : 64;
+		for (i = 0; i < n; i++) out[i] = in[i] ^ block[i];
+		in += n;
+		out += n;
+		len -= n;
+	}
+	memset(block, 0, sizeof(block));
+}
+
+static void poly1305_init(POLY1305_CTX *ctx, const unsigned char key[32]) {
+	// r is clamped and kept in 26 bit limbs
+	ctx->r[0] = (load32_le(key + 0)) & 0x3ffffff;
+	ctx->r[1] = (load32_le(key + 3) >> 2) & 0x3ffff03;
+	ctx->r[2] = (load32_le(key + 6) >> 4) & 0x3ffc0ff;
+	ctx->r[3] = (load32_le(key + 9) >> 6) & 0x3f03fff;
+	ctx->r[4] = (load32_le(key + 12) >> 8) & 0x00fffff;
+	memset(ctx->h, 0, sizeof(ctx->h));
+	ctx->pad[0] = load32_le(key + 16);
+	ctx->pad[1] = load32_le(key + 20);
+	ctx->pad[2] = load32_le(key + 24);
+	ctx->pad[3] = load32_le(key + 28);
+	ctx->buflen = 0;
+}
+
+static void poly1305_blocks(POLY1305_CTX *ctx, const unsigned char *m,
+			    size_t len, unsigned int hibit) {
+	unsigned int r0 = ctx->r[0], r1 = ctx->r[1], r2 = ctx->r[2],
+		     r3 = ctx->r[3], r4 = ctx->r[4];
+	unsigned int s1 = r1 * 5, s2 = r2 * 5, s3 = r3 * 5, s4 = r4 * 5;
+	unsigned int h0 = ctx->h[0], h1 = ctx->h[1], h2 = ctx->h[2],
+		     h3 = ctx->h[3], h4 = ctx->h[4];
+	unsigned long long d0, d1, d2, d3, d4;
+	unsigned int c;
+	while (len >= 16) {
+		h0 += (load32_le(m + 0)) & 0x3ffffff;
+		h1 += (load32_le(m + 3) >> 2) & 0x3ffffff;
+		h2 += (load32_le(m + 6) >> 4) & 0x3ffffff;
+		h3 += (load32_le(m + 9) >> 6) & 0x3ffffff;
+		h4 += (load32_le(m + 12) >> 8) | hibit;
+
+		d0 = ((unsigned long long)h0 * r0) +
+		     ((unsigned long long)h1 * s4) +
+		     ((unsigned long long)h2 * s3) +
+		     ((unsigned long long)h3 * s2) +
+		     ((unsigned long long)h4 * s1);
+		d1 = ((unsigned long long)h0 * r1) +
+		     ((unsigned long long)h1 * r0) +
+		     ((unsigned long long)h2 * s4) +
+		     ((unsigned long long)h3 * s3) +
+		     ((unsigned long long)h4 * s2);
+		d2 = ((unsigned long long)h0 * r2) +
+		     ((unsigned long long)h1 * r1) +
+		     ((unsigned long long)h2 * r0) +
+		     ((unsigned long long)h3 * s4) +
+		     ((unsigned long long)h4 * s3);
+		d3 = ((unsigned long long)h0 * r3) +
+		     ((unsigned long long)h1 * r2) +
+		     ((unsigned long long)h2 * r1) +
+		     ((unsigned long long)h3 * r0) +
+		     ((unsigned long long)h4 * s4);
+		d4 = ((unsigned long long)h0 * r4) +
+		     ((unsigned long long)h1 * r3) +
+		     ((unsigned long long)h2 * r2) +
+		     ((unsigned long long)h3 * r1) +
+		     ((unsigned long long)h4 * r0);
+
+		c = (unsigned int)(d0 >> 26);
+		h0 = (unsigned int)d0 & 0x3ffffff;
+		d1 += c;
+		c = (unsigned int)(d1 >> 26);
+		h1 = (unsigned int)d1 & 0x3ffffff;
+		d2 += c;
+		c = (unsigned int)(d2 >> 26);
+		h2 = (unsigned int)d2 & 0x3ffffff;
+		d3 += c;
+		c = (unsigned int)(d3 >> 26);
+		h3 = (unsigned int)d3 & 0x3ffffff;
+		d4 += c;
+		c = (unsigned int)(d4 >> 26);
+		h4 = (unsigned int)d4 & 0x3ffffff;
+		h0 += c * 5;
+		c = h0 >> 26;
+		h0 &= 0x3ffffff;
+		h1 += c;
+
+		m += 16;
+		len -= 16;
+	}
+	ctx->h[0] = h0;
+	ctx->h[1] = h1;
+	ctx->h[2] = h2;
+	ctx->h[3] = h3;
+	ctx->h[4] = h4;
+}
+
+static void poly1305_update(POLY1305_CTX *ctx, const unsigned char *m,
+			    size_t len) {
+	size_t n, full;
+	if (ctx->buflen > 0) {
+		n = 16 - ctx->buflen;
+		if (n > len) n = len;
+		memcpy(ctx->buf + ctx->buflen, m, n);
+		ctx->buflen += n;
+		m += n;
+		len -= n;
+		if (ctx->buflen < 16) return;
+		poly1305_blocks(ctx, ctx->buf, 16, 1 << 24);
+		ctx->buflen = 0;
+	}
+	full = len & ~(size_t)15;
+	if (full > 0) {
+		poly1305_blocks(ctx, m, full, 1 << 24);
+		m += full;
+		len -= full;
+	}
+	if (len > 0) {
+		memcpy(ctx->buf, m, len);
+		ctx->buflen = len;
+	}
+}
+
+// pads the data absorbed so far to a multiple of 16 with zeros
+static void poly1305_pad16(POLY1305_CTX *ctx) {
+	static const unsigned char zeros[16] = {0};
+	if (ctx->buflen > 0) poly1305_update(ctx, zeros, 16 - ctx->buflen);
+}
+
+static void poly1305_finish(POLY1305_CTX *ctx, unsigned char tag[16]) {
+	unsigned int h0, h1, h2, h3, h4, c;
+	unsigned int g0, g1, g2, g3, g4, mask;
+	unsigned long long f;
+
+	if (ctx->buflen > 0) {
+		ctx->buf[ctx->buflen] = 1;
+		memset(ctx->buf + ctx->buflen + 1, 0, 16 - ctx->buflen - 1);
+		poly1305_blocks(ctx, ctx->buf, 16, 0);
+	}
+
+	h0 = ctx->h[0];
+	h1 = ctx->h[1];
+	h2 = ctx->h[2];
+	h3 = ctx->h[3];
+	h4 = ctx->h[4];
+
+	c = h1 >> 26;
+	h1 &= 0x3ffffff;
+	h2 += c;
+	c = h2 >> 26;
+	h2 &= 0x3ffffff;
+	h3 += c;
+	c = h3 >> 26;
+	h3 &= 0x3ffffff;
+	h4 += c;
+	c = h4 >> 26;
+	h4 &= 0x3ffffff;
+	h0 += c * 5;
+	c = h0 >> 26;
+	h0 &= 0x3ffffff;
+	h1 += c;
+
+	// h - p, selected in constant time if it did not go negative
+	g0 = h0 + 5;
+	c = g0 >> 26;
+	g0 &= 0x3ffffff;
+	g1 = h1 + c;
+	c = g1 >> 26;
+	g1 &= 0x3ffffff;
+	g2 = h2 + c;
+	c = g2 >> 26;
+	g2 &= 0x3ffffff;
+	g3 = h3 + c;
+	c = g3 >> 26;
+	g3 &= 0x3ffffff;
+	g4 = h4 + c - (1 << 26);
+
+	mask = (g4 >> 31) - 1;
+	g0 &= mask;
+	g1 &= mask;
+	g2 &= mask;
+	g3 &= mask;
+	g4 &= mask;
+	mask = ~mask;
+	h0 = (h0 & mask) | g0;
+	h1 = (h1 & mask) | g1;
+	h2 = (h2 & mask) | g2;
+	h3 = (h3 & mask) | g3;
+	h4 = (h4 & mask) | g4;
+
+	h0 = (h0) | (h1 << 26);
+	h1 = (h1 >> 6) | (h2 << 20);
+	h2 = (h2 >> 12) | (h3 << 14);
+	h3 = (h3 >> 18) | (h4 << 8);
+
+	f = (unsigned long long)h0 + ctx->pad[0];
+	h0 = (unsigned int)f;
+	f = (unsigned long long)h1 + ctx->pad[1] + (f >> 32);
+	h1 = (unsigned int)f;
+	f = (unsigned long long)h2 + ctx->pad[2] + (f >> 32);
+	h2 = (unsigned int)f;
+	f = (unsigned long long)h3 + ctx->pad[3] + (f >> 32);
+	h3 = (unsigned int)f;
+
+	store32_le(tag + 0, h0);
+	store32_le(tag + 4, h1);
+	store32_le(tag + 8, h2);
+	store32_le(tag + 12, h3);
+	memset(ctx, 0, sizeof(*ctx));
+}
+
+static void aead_tag(const unsigned char key[32], const unsigned char nonce[12],
+		     const unsigned char *aad, size_t aadlen,
+		     const unsigned char *ct, size_t len,
+		     unsigned char tag[16]) {
+	POLY1305_CTX ctx;
+	unsigned char block[64];
+	unsigned char lens[16];
+	int i;
+	chacha20_block(key, 0, nonce, block);
+	poly1305_init(&ctx, block);
+	memset(block, 0, sizeof(block));
+	poly1305_update(&ctx, aad, aadlen);
+	poly1305_pad16(&ctx);
+	poly1305_update(&ctx, ct, len);
+	poly1305_pad16(&ctx);
+	for (i = 0; i < 8; i++) {
+		lens[i] = (unsigned long long)aadlen >> (8 * i);
+		lens[8 + i] = (unsigned long long)len >> (8 * i);
+	}
+	poly1305_update(&ctx, lens, 16);
+	poly1305_finish(&ctx, tag);
+}
+
+// encrypts len bytes of in into out, which may be the same buffer, and writes
+// the 16 byte tag authenticating them and aad
+void chacha20poly1305_seal(const unsigned char key[32],
+			   const unsigned char nonce[12],
+			   const unsigned char *aad, size_t aadlen,
+			   const unsigned char *in, size_t len,
+			   unsigned char *out, unsigned char tag[16]) {
+	chacha20_xor(key, 1, nonce, in, out, len);
+	aead_tag(key, nonce, aad, aadlen, out, len, tag);
+}
+
+// checks tag and decrypts len bytes of in into out, which may be the same
+// buffer. returns -1 without writing out if the tag does not match.
+int chacha20poly1305_open(const unsigned char key[32],
+			  const unsigned char nonce[12],
+			  const unsigned char *aad, size_t aadlen,
+			  const unsigned char *in, size_t len,
+			  const unsigned char tag[16], unsigned char *out) {
+	unsigned char expected[16];
+	unsigned char diff = 0;
+	int i;
+	aead_tag(key, nonce, aad, aadlen, in, len, expected);
+	for (i = 0; i < 16; i++) diff |= expected[i] ^ tag[i];
+	if (diff != 0) return -1;
+	chacha20_xor(key, 1, nonce, in, out, len);
+	return 0;
+}
@@ -1,4 +1,6 @@
+#include <errno.h>
 #include <pthread.h>
+#include <time.h>
 
 void _exit(int);
 int perror(const char *msg);
@@ -13,30 +15,65 @@ typedef struct Message {
 typedef struct Channel {
 	pthread_mutex_t lock;
 	pthread_cond_t cond;
+	pthread_cond_t not_full;
 	Message *head;
 	Message *tail;
+	unsigned long long len;
+	unsigned long long capacity;
 } Channel;
 
 _Bool channel_pending(Channel *handle) { return handle->head; }
 
-int channel_init(Channel *handle) {
+unsigned long long channel_len(Channel *handle) {
+	return __atomic_load_n(&handle->len, __ATOMIC_RELAXED);
+}
+
+// a capacity of 0 means the channel is unbounded
+int channel_init(Channel *handle, unsigned long long capacity) {
 	if (pthread_mutex_init(&handle->lock, NULL)) return -1;
 	if (pthread_cond_init(&handle->cond, NULL)) return -1;
+	if (pthread_cond_init(&handle->not_full, NULL)) return -1;
 	handle->head = handle->tail = NULL;
+	handle->len = 0;
+	handle->capacity = capacity;
 	return 0;
 }
-int channel_send(Channel *handle, Message *msg) {
+static _Bool channel_full(Channel *handle) {
+	return handle->capacity && handle->len >= handle->capacity;
+}
+// called with the lock held after a message has been taken off the queue
+static void channel_taken(Channel *handle) {
+	if (handle->capacity && pthread_cond_signal(&handle->not_full)) {
+		perror("pthread_cond_signal");
+		_exit(-1);
+	}
+}
+// when the channel is full, waits for room if block is set and otherwise returns -2
+// leaving msg with the caller
+int channel_send(Channel *handle, Message *msg, _Bool block) {
 	if (pthread_mutex_lock(&handle->lock)) {
 		perror("pthread_mutex_lock");
 		_exit(-1);
 	}
 
+	while (channel_full(handle)) {
+		if (!block) {
+			if (pthread_mutex_unlock(&handle->lock)) {
+				perror("pthread_mutex_unlock");
+				_exit(-1);
+			}
+			return -2;
+		}
+		pthread_cond_wait(&handle->not_full, &handle->lock);
+	}
+
 	msg->next = NULL;
 	if (handle->tail)
 		handle->tail->next = msg;
 	else
 		handle->head = msg;
 	handle->tail = msg;
+	__atomic_add_fetch(&handle->len, 1, __ATOMIC_RELAXED);
 
 	if (pthread_cond_signal(&handle->cond)) {
 		perror("pthread_cond_signal");
@@ -63,6 +100,45 @@ Message *channel_recv(Channel *handle) {
 	Message *ret = handle->head;
 	handle->head = handle->head->next;
 	if (!handle->head) handle->tail = NULL;
+	__atomic_sub_fetch(&handle->len, 1, __ATOMIC_RELAXED);
+	channel_taken(handle);
+
+	if (pthread_mutex_unlock(&handle->lock)) {
+		perror("pthread_mutex_lock");
+		_exit(1);
+	}
+
+	return ret;
+}
+// like channel_recv but gives up after timeout_micros, returning NULL
+Message *channel_recv_timeout(Channel *handle, unsigned long long timeout_micros) {
+	struct timespec deadline;
+	clock_gettime(CLOCK_REALTIME, &deadline);
+	deadline.tv_sec += timeout_micros / 1000000;
+	deadline.tv_nsec += (timeout_micros % 1000000) * 1000;
+	if (deadline.tv_nsec >= 1000000000) {
+		deadline.tv_sec++;
+		deadline.tv_nsec -= 1000000000;
+	}
+
+	if (pthread_mutex_lock(&handle->lock)) {
+		perror("pthread_mutex_lock");
+		_exit(1);
+	}
+
+	while (!handle->head) {
+		if (pthread_cond_timedwait(&handle->cond, &handle->lock, &deadline) ==
+		    ETIMEDOUT)
+			break;
+	}
+
+	Message *ret = handle->head;
+	if (ret) {
+		handle->head = ret->next;
+		if (!handle->head) handle->tail = NULL;
+		__atomic_sub_fetch(&handle->len, 1, __ATOMIC_RELAXED);
+		channel_taken(handle);
+	}
 
 	if (pthread_mutex_unlock(&handle->lock)) {
 		perror("pthread_mutex_lock");
@@ -81,5 +157,9 @@ int channel_destroy(Channel *handle) {
 		perror("pthread_cond_destroy");
 		_exit(-1);
 	}
+	if (pthread_cond_destroy(&handle->not_full)) {
+		perror("pthread_cond_destroy");
+		_exit(-1);
+	}
 	return 0;
 }
@@ -0,0 +1,88 @@
+#include <errno.h>
+#include <pthread.h>
+#include <time.h>
+
+void _exit(int);
+int perror(const char *msg);
+
+// seq is bumped by every notify. A waiter reads it before releasing the caller's lock and
+// only sleeps while it is unchanged, so a notify between the two is never lost.
+typedef struct Condvar {
+	pthread_mutex_t lock;
+	pthread_cond_t cond;
+	unsigned long long seq;
+} Condvar;
+
+int condvar_init(Condvar *handle) {
+	if (pthread_mutex_init(&handle->lock, NULL)) return -1;
+	if (pthread_cond_init(&handle->cond, NULL)) return -1;
+	handle->seq = 0;
+	return 0;
+}
+unsigned long long condvar_seq(Condvar *handle) {
+	return __atomic_load_n(&handle->seq, __ATOMIC_ACQUIRE);
+}
+// waits until seq differs from expected, returning 0, or until timeout_micros have passed,
+// returning -1. A timeout of 0 waits indefinitely.
+int condvar_wait(Condvar *handle, unsigned long long expected,
+		 unsigned long long timeout_micros) {
+	struct timespec deadline;
+	if (timeout_micros) {
+		clock_gettime(CLOCK_REALTIME, &deadline);
+		deadline.tv_sec += timeout_micros / 1000000;
+		deadline.tv_nsec += (timeout_micros % 1000000) * 1000;
+		if (deadline.tv_nsec >= 1000000000) {
+			deadline.tv_sec++;
+			deadline.tv_nsec -= 1000000000;
+		}
+	}
+
+	if (pthread_mutex_lock(&handle->lock)) {
+		perror("pthread_mutex_lock");
+		_exit(-1);
+	}
+
+	int ret = 0;
+	while (handle->seq == expected) {
+		if (!timeout_micros) {
+			pthread_cond_wait(&handle->cond, &handle->lock);
+		} else if (pthread_cond_timedwait(&handle->cond, &handle->lock, &deadline) ==
+			   ETIMEDOUT) {
+			ret = handle->seq == expected ? -1 : 0;
+			break;
+		}
+	}
+
+	if (pthread_mutex_unlock(&handle->lock)) {
+		perror("pthread_mutex_unlock");
+		_exit(-1);
+	}
+	return ret;
+}
+void condvar_notify(Condvar *handle, _Bool all) {
+	if (pthread_mutex_lock(&handle->lock)) {
+		perror("pthread_mutex_lock");
+		_exit(-1);
+	}
+	__atomic_add_fetch(&handle->seq, 1, __ATOMIC_RELEASE);
+	if (all ? pthread_cond_broadcast(&handle->cond) : pthread_cond_signal(&handle->cond)) {
+		perror("pthread_cond_signal");
+		_exit(-1);
+	}
+	if (pthread_mutex_unlock(&handle->lock)) {
+		perror("pthread_mutex_unlock");
+		_exit(-1);
+	}
+}
+unsigned long long condvar_handle_size() { return sizeof(Condvar); }
+int condvar_destroy(Condvar *handle) {
+	if (pthread_mutex_destroy(&handle->lock)) {
+		perror("pthread_mutex_destroy");
+		_exit(-1);
+	}
+	if (pthread_cond_destroy(&handle->cond)) {
+		perror("pthread_cond_destroy");
+		_exit(-1);
+	}
+	return 0;
+}
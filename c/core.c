@@ -1,3 +1,4 @@
+#include <sys/mman.h>
 #include <time.h>
 
 int printf(const char *, ...);
@@ -7,10 +8,26 @@ void free(void *);
 int getentropy(void *buf, unsigned long long length);
 long long __alloc_count = 0;
 void _exit(int);
+#ifdef __APPLE__
+unsigned long malloc_size(const void *ptr);
+#define malloc_usable_size malloc_size
+#else
+unsigned long malloc_usable_size(void *ptr);
+#endif	// __APPLE__
+
+// heap in use through alloc and resize, kept in every build for mem_stats, unlike
+// __alloc_count which only tests track
+unsigned long long __alloc_bytes = 0;
+unsigned long long __alloc_blocks = 0;
 
 void *alloc(unsigned long size) {
 	void *ptr = malloc(size);
 	// printf("malloc %p (%lu) (alloc=%lli)\n", ptr, size, __alloc_count);
+	if (ptr) {
+		__atomic_fetch_add(&__alloc_bytes, malloc_usable_size(ptr),
+				   __ATOMIC_RELAXED);
+		__atomic_fetch_add(&__alloc_blocks, 1, __ATOMIC_RELAXED);
+	}
 #ifdef TEST
 	__atomic_fetch_add(&__alloc_count, 1, __ATOMIC_SEQ_CST);
 #endif	// TEST
@@ -22,12 +39,30 @@ void release(void *ptr) {
 #ifdef TEST
 	__atomic_fetch_sub(&__alloc_count, 1, __ATOMIC_SEQ_CST);
 #endif	// TEST
+	if (ptr) {
+		__atomic_fetch_sub(&__alloc_bytes, malloc_usable_size(ptr),
+				   __ATOMIC_RELAXED);
+		__atomic_fetch_sub(&__alloc_blocks, 1, __ATOMIC_RELAXED);
+	}
 	free(ptr);
 }
 
 void *resize(void *ptr, unsigned long long len) {
+	// realloc may free the block and return NULL, which would leave it counted
+	if (ptr && len == 0) {
+		release(ptr);
+		return NULL;
+	}
+	unsigned long long before = ptr ? malloc_usable_size(ptr) : 0;
 	void *ret = realloc(ptr, len);
 	// printf("realloc size=%llu [%p -> %p]\n", len, ptr, ret);
+	if (ret) {
+		// wraps around when the block shrank, which the subtraction undoes
+		__atomic_fetch_add(&__alloc_bytes,
+				   malloc_usable_size(ret) - before,
+				   __ATOMIC_RELAXED);
+		if (!ptr) __atomic_fetch_add(&__alloc_blocks, 1, __ATOMIC_RELAXED);
+	}
 	return ret;
 }
 
@@ -38,6 +73,13 @@ unsigned long long getmicros() {
 	       (unsigned long long)(now.tv_nsec / 1000);
 }
 
+unsigned long long thread_cpu_micros() {
+	struct timespec now;
+	clock_gettime(CLOCK_THREAD_CPUTIME_ID, &now);
+	return (unsigned long long)now.tv_sec * 1000000 +
+	       (unsigned long long)(now.tv_nsec / 1000);
+}
+
 int sleep_millis(unsigned long long millis) {
 	struct timespec ts;
 	ts.tv_sec = millis / 1000;
@@ -52,3 +94,39 @@ int rand_bytes(unsigned char *buf, unsigned long long length) {
 
 long long getalloccount() { return __alloc_count; }
 
+unsigned long long getallocbytes() {
+	return __atomic_load_n(&__alloc_bytes, __ATOMIC_RELAXED);
+}
+
+unsigned long long getallocblocks() {
+	return __atomic_load_n(&__alloc_blocks, __ATOMIC_RELAXED);
+}
+
+// pages of their own for secrets, locked into memory so they are never written to swap
+// and on linux left out of core dumps. Returns NULL if they could not be mapped or locked.
+void *secure_alloc(unsigned long long size) {
+	void *ptr = mmap(NULL, size, PROT_READ | PROT_WRITE,
+			 MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+	if (ptr == MAP_FAILED) return NULL;
+	if (mlock(ptr, size)) {
+		munmap(ptr, size);
+		return NULL;
+	}
+#ifdef __linux__
+	madvise(ptr, size, MADV_DONTDUMP);
+#endif	// __linux__
+#ifdef TEST
+	__atomic_fetch_add(&__alloc_count, 1, __ATOMIC_SEQ_CST);
+#endif	// TEST
+	return ptr;
+}
+
+// the caller has already wiped the memory
+void secure_release(void *ptr, unsigned long long size) {
+#ifdef TEST
+	__atomic_fetch_sub(&__alloc_count, 1, __ATOMIC_SEQ_CST);
+#endif	// TEST
+	munlock(ptr, size);
+	munmap(ptr, size);
+}
+
@@ -41,6 +41,7 @@ CsprngCtx *cpsrng_context_create() {
 	return ret;
 }
 void cpsrng_context_destroy(CsprngCtx *ctx) { release(ctx); }
+unsigned long long cpsrng_context_size() { return sizeof(CsprngCtx); }
 void cpsrng_rand_bytes_ctx(CsprngCtx *ctx, void *v, unsigned long long size) {
 	AES_CTR_xcrypt_buffer(&ctx->ctx, (byte *)v, size);
 }
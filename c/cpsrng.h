@@ -30,6 +30,7 @@ void cpsrng_rand_bytes(void *v, unsigned long long size);
 
 CsprngCtx *cpsrng_context_create();
 void cpsrng_context_destroy(CsprngCtx *);
+unsigned long long cpsrng_context_size();
 void cpsrng_rand_bytes_ctx(CsprngCtx *, void *v, unsigned long long size);
 
 #ifdef TEST
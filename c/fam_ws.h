@@ -0,0 +1,47 @@
+// Copyright (c) 2024, The MyFamily Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#ifndef _FAM_WS_H__
+#define _FAM_WS_H__
+
+// C embedding API for the WebSocket server. Ownership rules are documented in
+// rust/net/capi.rs. In short: the host owns the server until fam_ws_destroy,
+// ctx is never touched by the library and must stay valid until fam_ws_destroy
+// returns, conn and msg are only valid during the callback unless conn is
+// retained, and the callback may run on several worker threads at once.
+
+#include <stddef.h>
+#include <stdint.h>
+
+typedef struct FamWs FamWs;
+typedef struct FamWsConn FamWsConn;
+
+// return nonzero to close the connection with status 1011. Must not be null.
+typedef int (*FamWsHandler)(void *ctx, FamWsConn *conn, uint8_t op,
+			    const uint8_t *msg, size_t len);
+
+FamWs *fam_ws_create(uint64_t threads);
+int fam_ws_set_handler(FamWs *ws, FamWsHandler handler, void *ctx);
+int fam_ws_start(FamWs *ws);
+int fam_ws_add_server(FamWs *ws, const uint8_t addr[4], uint16_t port,
+		      int backlog);
+int fam_ws_send(FamWsConn *conn, const uint8_t *msg, size_t len, int binary);
+// returns -1 if status is not a close code an endpoint may send
+int fam_ws_close(FamWsConn *conn, uint16_t status);
+FamWsConn *fam_ws_conn_retain(FamWsConn *conn);
+void fam_ws_conn_release(FamWsConn *conn);
+int fam_ws_stop(FamWs *ws);
+void fam_ws_destroy(FamWs *ws);
+
+#endif	// _FAM_WS_H__
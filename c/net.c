@@ -28,14 +28,18 @@
 #define ERROR_MULTIPLEX_INIT -9
 #define ERROR_GETSOCKNAME -10
 #define ERROR_EAGAIN -11
+#define ERROR_GETPEERNAME -12
 
 long long __fd_count = 0;
 
 long long getfdcount() { return __fd_count; }
 
 int close_impl(int fd) {
+	// keep the errno of the call that failed when closing during cleanup
+	int saved = errno;
 	int ret = close(fd);
 	if (ret == 0) {
+		errno = saved;
 #ifdef TEST
 		__atomic_fetch_sub(&__fd_count, 1, __ATOMIC_SEQ_CST);
 #endif	// TEST
@@ -43,6 +47,65 @@ int close_impl(int fd) {
 	return ret;
 }
 
+int socket_errno() { return errno; }
+
+const char *errno_name(int e) {
+	switch (e) {
+		case EACCES:
+			return "EACCES";
+		case EADDRINUSE:
+			return "EADDRINUSE";
+		case EADDRNOTAVAIL:
+			return "EADDRNOTAVAIL";
+		case EAFNOSUPPORT:
+			return "EAFNOSUPPORT";
+		case EAGAIN:
+			return "EAGAIN";
+		case EBADF:
+			return "EBADF";
+		case ECONNABORTED:
+			return "ECONNABORTED";
+		case ECONNREFUSED:
+			return "ECONNREFUSED";
+		case ECONNRESET:
+			return "ECONNRESET";
+		case EEXIST:
+			return "EEXIST";
+		case EHOSTUNREACH:
+			return "EHOSTUNREACH";
+		case EINPROGRESS:
+			return "EINPROGRESS";
+		case EINTR:
+			return "EINTR";
+		case EINVAL:
+			return "EINVAL";
+		case EMFILE:
+			return "EMFILE";
+		case ENETUNREACH:
+			return "ENETUNREACH";
+		case ENFILE:
+			return "ENFILE";
+		case ENOBUFS:
+			return "ENOBUFS";
+		case ENOENT:
+			return "ENOENT";
+		case ENOMEM:
+			return "ENOMEM";
+		case ENOTCONN:
+			return "ENOTCONN";
+		case ENOTSOCK:
+			return "ENOTSOCK";
+		case EPERM:
+			return "EPERM";
+		case EPIPE:
+			return "EPIPE";
+		case ETIMEDOUT:
+			return "ETIMEDOUT";
+		default:
+			return NULL;
+	}
+}
+
 typedef struct SocketHandle {
 	int fd;
 } SocketHandle;
@@ -83,7 +146,9 @@ int socket_connect(SocketHandle *s, unsigned char addr[4], int port) {
 
 	if (connect(s->fd, (struct sockaddr *)&serv_addr, sizeof(serv_addr)) <
 	    0) {
+		int saved = errno;
 		perror("connect");
+		errno = saved;
 		close_impl(s->fd);
 		return ERROR_CONNECT;
 	}
@@ -159,8 +224,39 @@ int open_pipe(int *handles) {
 	return ret;
 }
 
+int socket_pair(SocketHandle *s1, SocketHandle *s2) {
+	int fds[2];
+	if (socketpair(AF_UNIX, SOCK_STREAM, 0, fds) < 0) return ERROR_SOCKET;
+#ifdef TEST
+	__atomic_fetch_add(&__fd_count, 2, __ATOMIC_SEQ_CST);
+#endif	// TEST
+
+	for (int i = 0; i < 2; i++) {
+		int flags = fcntl(fds[i], F_GETFL, 0);
+		if (flags < 0 || fcntl(fds[i], F_SETFL, flags | O_NONBLOCK) < 0) {
+			close_impl(fds[0]);
+			close_impl(fds[1]);
+			return ERROR_FCNTL;
+		}
+	}
+
+	s1->fd = fds[0];
+	s2->fd = fds[1];
+	return 0;
+}
+
 int socket_shutdown(SocketHandle *s) { return shutdown(s->fd, SHUT_RDWR); }
 int socket_close(SocketHandle *s) { return close_impl(s->fd); }
+int socket_set_nonblocking(SocketHandle *s, _Bool nonblocking) {
+	int flags = fcntl(s->fd, F_GETFL, 0);
+	if (flags < 0) return ERROR_FCNTL;
+	if (nonblocking)
+		flags |= O_NONBLOCK;
+	else
+		flags &= ~O_NONBLOCK;
+	if (fcntl(s->fd, F_SETFL, flags) < 0) return ERROR_FCNTL;
+	return 0;
+}
 int socket_listen(SocketHandle *s, unsigned char addr[4], int port,
 		  int backlog) {
 	int opt = 1;
@@ -214,6 +310,83 @@ int socket_listen(SocketHandle *s, unsigned char addr[4], int port,
 	return port;
 }
 
+int socket_udp_bind(SocketHandle *s, unsigned char addr[4], int port) {
+	struct sockaddr_in address;
+
+	s->fd = socket(AF_INET, SOCK_DGRAM, 0);
+	if (s->fd < 0) return ERROR_SOCKET;
+#ifdef TEST
+	__atomic_fetch_add(&__fd_count, 1, __ATOMIC_SEQ_CST);
+#endif	// TEST
+
+	int flags = fcntl(s->fd, F_GETFL, 0);
+	if (flags < 0 || fcntl(s->fd, F_SETFL, flags | O_NONBLOCK) < 0) {
+		close_impl(s->fd);
+		return ERROR_FCNTL;
+	}
+
+	memset(&address, 0, sizeof(address));
+	address.sin_family = AF_INET;
+	address.sin_port = htons(port);
+	memcpy(&address.sin_addr.s_addr, addr, 4);
+
+	if (bind(s->fd, (struct sockaddr *)&address, sizeof(address)) < 0) {
+		close_impl(s->fd);
+		return ERROR_BIND;
+	}
+
+	socklen_t addr_len = sizeof(address);
+	if (getsockname(s->fd, (struct sockaddr *)&address, &addr_len) < 0) {
+		close_impl(s->fd);
+		return ERROR_GETSOCKNAME;
+	}
+	return ntohs(address.sin_port);
+}
+
+long long socket_sendto(SocketHandle *s, const char *buf,
+			unsigned long long len, unsigned char addr[4], int port) {
+	struct sockaddr_in dest;
+	memset(&dest, 0, sizeof(dest));
+	dest.sin_family = AF_INET;
+	dest.sin_port = htons(port);
+	memcpy(&dest.sin_addr.s_addr, addr, 4);
+
+	long long ret =
+	    sendto(s->fd, buf, len, 0, (struct sockaddr *)&dest, sizeof(dest));
+	if (ret < 0 && errno == EAGAIN) return ERROR_EAGAIN;
+	return ret;
+}
+
+long long socket_recvfrom(SocketHandle *s, char *buf,
+			  unsigned long long capacity, unsigned char addr[4],
+			  unsigned short *port) {
+	struct sockaddr_in src;
+	socklen_t src_len = sizeof(src);
+	memset(&src, 0, sizeof(src));
+
+	long long ret = recvfrom(s->fd, buf, capacity, 0,
+				 (struct sockaddr *)&src, &src_len);
+	if (ret < 0) {
+		if (errno == EAGAIN) return ERROR_EAGAIN;
+		return ret;
+	}
+	memcpy(addr, &src.sin_addr.s_addr, 4);
+	*port = ntohs(src.sin_port);
+	return ret;
+}
+
+int socket_peer_addr(SocketHandle *s, unsigned char addr[4],
+		     unsigned short *port) {
+	struct sockaddr_in peer;
+	socklen_t peer_len = sizeof(peer);
+	memset(&peer, 0, sizeof(peer));
+	if (getpeername(s->fd, (struct sockaddr *)&peer, &peer_len) < 0)
+		return ERROR_GETPEERNAME;
+	memcpy(addr, &peer.sin_addr.s_addr, 4);
+	*port = ntohs(peer.sin_port);
+	return 0;
+}
+
 int socket_accept(SocketHandle *s, SocketHandle *accepted) {
 	struct sockaddr_in client_addr;
 	socklen_t client_len = sizeof(client_addr);
@@ -363,6 +536,29 @@ int socket_multiplex_unregister_write(MultiplexHandle *multiplex,
 }
 #endif	// __linux__
 
+#ifdef __APPLE__
+int socket_multiplex_unregister(MultiplexHandle *multiplex, SocketHandle *s) {
+	struct kevent change_event[1];
+
+	EV_SET(&change_event[0], s->fd, EVFILT_READ, EV_DELETE, 0, 0, NULL);
+
+	if (kevent(multiplex->fd, change_event, 1, NULL, 0, NULL) < 0) {
+		return ERROR_REGISTER;
+	}
+	return 0;
+}
+#endif	// __APPLE__
+#ifdef __linux__
+int socket_multiplex_unregister(MultiplexHandle *multiplex, SocketHandle *s) {
+	struct epoll_event event;
+
+	if (epoll_ctl(multiplex->fd, EPOLL_CTL_DEL, s->fd, &event) < 0)
+		return ERROR_REGISTER;
+
+	return 0;
+}
+#endif	// __linux__
+
 /*
 int socket_multiplex_wait(MultiplexHandle *multiplex, void *events,
 			  int max_events, long long timeout_millis) {
@@ -0,0 +1,156 @@
+#include <memory.h>
+#include <stddef.h>
+
+#define ROTL(x, n) (((x) << (n)) | ((x) >> (32 - (n))))
+
+typedef struct {
+	unsigned char data[64];
+	unsigned int datalen;
+	unsigned long long bitlen;
+	unsigned int state[5];
+} RIPEMD160_CTX;
+
+static const unsigned char rl[80] = {
+    0, 1, 2,  3,  4,  5,  6,  7,  8,  9,  10, 11, 12, 13, 14, 15,
+    7, 4, 13, 1,  10, 6,  15, 3,  12, 0,  9,  5,  2,  14, 11, 8,
+    3, 10, 14, 4, 9,  15, 8,  1,  2,  7,  0,  6,  13, 11, 5,  12,
+    1, 9, 11, 10, 0,  8,  12, 4,  13, 3,  7,  15, 14, 5,  6,  2,
+    4, 0, 5,  9,  7,  12, 2,  10, 14, 1,  3,  8,  11, 6,  15, 13};
+static const unsigned char rr[80] = {
+    5,  14, 7,  0, 9, 2,  11, 4,  13, 6,  15, 8,  1,  10, 3,  12,
+    6,  11, 3,  7, 0, 13, 5,  10, 14, 15, 8,  12, 4,  9,  1,  2,
+    15, 5,  1,  3, 7, 14, 6,  9,  11, 8,  12, 2,  10, 0,  4,  13,
+    8,  6,  4,  1, 3, 11, 15, 0,  5,  12, 2,  13, 9,  7,  10, 14,
+    12, 15, 10, 4, 1, 5,  8,  7,  6,  2,  13, 14, 0,  3,  9,  11};
+static const unsigned char sl[80] = {
+    11, 14, 15, 12, 5,  8,  7,  9,  11, 13, 14, 15, 6,  7,  9,  8,
+    7,  6,  8,  13, 11, 9,  7,  15, 7,  12, 15, 9,  11, 7,  13, 12,
+    11, 13, 6,  7,  14, 9,  13, 15, 14, 8,  13, 6,  5,  12, 7,  5,
+    11, 12, 14, 15, 14, 15, 9,  8,  9,  14, 5,  6,  8,  6,  5,  12,
+    9,  15, 5,  11, 6,  8,  13, 12, 5,  12, 13, 14, 11, 8,  5,  6};
+static const unsigned char sr[80] = {
+    8,  9,  9,  11, 13, 15, 15, 5,  7,  7,  8,  11, 14, 14, 12, 6,
+    9,  13, 15, 7,  12, 8,  9,  11, 7,  7,  12, 7,  6,  15, 13, 11,
+    9,  7,  15, 11, 8,  6,  6,  14, 12, 13, 5,  14, 13, 13, 7,  5,
+    15, 5,  8,  11, 14, 14, 6,  14, 6,  9,  12, 9,  12, 5,  15, 8,
+    8,  5,  12, 9,  12, 5,  14, 6,  8,  13, 6,  5,  15, 13, 11, 11};
+static const unsigned int kl[5] = {0x00000000, 0x5a827999, 0x6ed9eba1,
+				   0x8f1bbcdc, 0xa953fd4e};
+static const unsigned int kr[5] = {0x50a28be6, 0x5c4dd124, 0x6d703ef3,
+				   0x7a6d76e9, 0x00000000};
+
+static unsigned int ripemd160_f(int j, unsigned int x, unsigned int y,
+				unsigned int z) {
+	switch (j / 16) {
+		case 0:
+			return x ^ y ^ z;
+		case 1:
+			return (x & y) | (~x & z);
+		case 2:
+			return (x | ~y) ^ z;
+		case 3:
+			return (x & z) | (y & ~z);
+		default:
+			return x ^ (y | ~z);
+	}
+}
+
+void ripemd160_transform(RIPEMD160_CTX *ctx, const unsigned char data[]) {
+	unsigned int al, bl, cl, dl, el, ar, br, cr, dr, er, t, m[16];
+	int j;
+
+	for (j = 0; j < 16; ++j)
+		m[j] = data[j * 4] | (data[j * 4 + 1] << 8) |
+		       (data[j * 4 + 2] << 16) |
+		       ((unsigned int)data[j * 4 + 3] << 24);
+
+	al = ar = ctx->state[0];
+	bl = br = ctx->state[1];
+	cl = cr = ctx->state[2];
+	dl = dr = ctx->state[3];
+	el = er = ctx->state[4];
+
+	for (j = 0; j < 80; ++j) {
+		t = ROTL(al + ripemd160_f(j, bl, cl, dl) + m[rl[j]] + kl[j / 16],
+			 sl[j]) +
+		    el;
+		al = el;
+		el = dl;
+		dl = ROTL(cl, 10);
+		cl = bl;
+		bl = t;
+
+		t = ROTL(ar + ripemd160_f(79 - j, br, cr, dr) + m[rr[j]] +
+			     kr[j / 16],
+			 sr[j]) +
+		    er;
+		ar = er;
+		er = dr;
+		dr = ROTL(cr, 10);
+		cr = br;
+		br = t;
+	}
+
+	t = ctx->state[1] + cl + dr;
+	ctx->state[1] = ctx->state[2] + dl + er;
+	ctx->state[2] = ctx->state[3] + el + ar;
+	ctx->state[3] = ctx->state[4] + al + br;
+	ctx->state[4] = ctx->state[0] + bl + cr;
+	ctx->state[0] = t;
+}
+
+void RIPEMD160_Init(RIPEMD160_CTX *ctx) {
+	ctx->datalen = 0;
+	ctx->bitlen = 0;
+	ctx->state[0] = 0x67452301;
+	ctx->state[1] = 0xefcdab89;
+	ctx->state[2] = 0x98badcfe;
+	ctx->state[3] = 0x10325476;
+	ctx->state[4] = 0xc3d2e1f0;
+}
+
+void RIPEMD160_Update(RIPEMD160_CTX *ctx, const unsigned char data[],
+		      size_t len) {
+	size_t i;
+
+	for (i = 0; i < len; ++i) {
+		ctx->data[ctx->datalen] = data[i];
+		ctx->datalen++;
+		if (ctx->datalen == 64) {
+			ripemd160_transform(ctx, ctx->data);
+			ctx->bitlen += 512;
+			ctx->datalen = 0;
+		}
+	}
+}
+
+void RIPEMD160_Final(RIPEMD160_CTX *ctx, unsigned char hash[]) {
+	unsigned int i;
+
+	i = ctx->datalen;
+
+	if (ctx->datalen < 56) {
+		ctx->data[i++] = 0x80;
+		while (i < 56) ctx->data[i++] = 0x00;
+	} else {
+		ctx->data[i++] = 0x80;
+		while (i < 64) ctx->data[i++] = 0x00;
+		ripemd160_transform(ctx, ctx->data);
+		memset(ctx->data, 0, 56);
+	}
+
+	// unlike SHA, the length and the output are little endian
+	ctx->bitlen += ctx->datalen * 8;
+	for (i = 0; i < 8; ++i) ctx->data[56 + i] = ctx->bitlen >> (i * 8);
+	ripemd160_transform(ctx, ctx->data);
+
+	for (i = 0; i < 20; ++i)
+		hash[i] = (ctx->state[i / 4] >> ((i % 4) * 8)) & 0xff;
+}
+
+void RIPEMD160(const unsigned char *data, size_t size, unsigned char hash[]) {
+	RIPEMD160_CTX ctx;
+	RIPEMD160_Init(&ctx);
+	RIPEMD160_Update(&ctx, data, size);
+	RIPEMD160_Final(&ctx, hash);
+}
@@ -569,8 +569,7 @@ static int secp256k1_aggsig_verify_callback(secp256k1_scalar *sc, secp256k1_ge *
         return 0;
     }
     secp256k1_scalar_negate(sc, sc);
-    secp256k1_pubkey_load(cbdata->ctx, pt, &cbdata->pubkeys[idx]);
-    return 1;
+    return secp256k1_pubkey_load(cbdata->ctx, pt, &cbdata->pubkeys[idx]);
 }
 
 int secp256k1_aggsig_verify(const secp256k1_context* ctx, secp256k1_scratch_space *scratch, const unsigned char *sig64, const unsigned char *msg32, const secp256k1_pubkey *pubkeys, size_t n_pubkeys) {
@@ -635,8 +634,7 @@ int secp256k1_aggsig_build_scratch_and_verify(const secp256k1_context* ctx,
 static int secp256k1_aggsig_verify_callback_single(secp256k1_scalar *sc, secp256k1_ge *pt, size_t idx, void *data) {
     secp256k1_verify_callback_data *cbdata = (secp256k1_verify_callback_data*) data;
     secp256k1_scalar_negate(sc, &cbdata->single_hash);
-    secp256k1_pubkey_load(cbdata->ctx, pt, &cbdata->pubkeys[idx]);
-    return 1;
+    return secp256k1_pubkey_load(cbdata->ctx, pt, &cbdata->pubkeys[idx]);
 }
 
 int secp256k1_aggsig_verify_single(
@@ -683,7 +681,9 @@ int secp256k1_aggsig_verify_single(
     if (pubnonce != NULL) {
         secp256k1_compute_sighash_single(ctx, &sighash, pubnonce, pubkey_total, msg32);
     } else {
-        secp256k1_ge_set_xquad(&tmp_ge, &r_x);
+        if (!secp256k1_ge_set_xquad(&tmp_ge, &r_x)) {
+            return 0;
+        }
         secp256k1_pubkey_save(&tmp_pk, &tmp_ge);
         secp256k1_compute_sighash_single(ctx, &sighash, &tmp_pk, pubkey_total, msg32);
     }
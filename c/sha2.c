@@ -0,0 +1,345 @@
+#include <memory.h>
+#include <stddef.h>
+
+#define SHA256_BLOCK_SIZE 64
+#define SHA512_BLOCK_SIZE 128
+
+typedef struct {
+	unsigned char data[64];
+	unsigned int datalen;
+	unsigned long long bitlen;
+	unsigned int state[8];
+} SHA256_CTX;
+
+typedef struct {
+	unsigned char data[128];
+	unsigned int datalen;
+	unsigned long long bitlen;
+	unsigned long long state[8];
+} SHA512_CTX;
+
+#define ROTR32(a, b) (((a) >> (b)) | ((a) << (32 - (b))))
+#define ROTR64(a, b) (((a) >> (b)) | ((a) << (64 - (b))))
+#define CH(x, y, z) (((x) & (y)) ^ (~(x) & (z)))
+#define MAJ(x, y, z) (((x) & (y)) ^ ((x) & (z)) ^ ((y) & (z)))
+
+static const unsigned int k256[64] = {
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+    0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+    0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+    0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+    0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+    0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+    0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+    0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+    0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2};
+
+static const unsigned long long k512[80] = {
+    0x428a2f98d728ae22ULL, 0x7137449123ef65cdULL, 0xb5c0fbcfec4d3b2fULL,
+    0xe9b5dba58189dbbcULL, 0x3956c25bf348b538ULL, 0x59f111f1b605d019ULL,
+    0x923f82a4af194f9bULL, 0xab1c5ed5da6d8118ULL, 0xd807aa98a3030242ULL,
+    0x12835b0145706fbeULL, 0x243185be4ee4b28cULL, 0x550c7dc3d5ffb4e2ULL,
+    0x72be5d74f27b896fULL, 0x80deb1fe3b1696b1ULL, 0x9bdc06a725c71235ULL,
+    0xc19bf174cf692694ULL, 0xe49b69c19ef14ad2ULL, 0xefbe4786384f25e3ULL,
+    0x0fc19dc68b8cd5b5ULL, 0x240ca1cc77ac9c65ULL, 0x2de92c6f592b0275ULL,
+    0x4a7484aa6ea6e483ULL, 0x5cb0a9dcbd41fbd4ULL, 0x76f988da831153b5ULL,
+    0x983e5152ee66dfabULL, 0xa831c66d2db43210ULL, 0xb00327c898fb213fULL,
+    0xbf597fc7beef0ee4ULL, 0xc6e00bf33da88fc2ULL, 0xd5a79147930aa725ULL,
+    0x06ca6351e003826fULL, 0x142929670a0e6e70ULL, 0x27b70a8546d22ffcULL,
+    0x2e1b21385c26c926ULL, 0x4d2c6dfc5ac42aedULL, 0x53380d139d95b3dfULL,
+    0x650a73548baf63deULL, 0x766a0abb3c77b2a8ULL, 0x81c2c92e47edaee6ULL,
+    0x92722c851482353bULL, 0xa2bfe8a14cf10364ULL, 0xa81a664bbc423001ULL,
+    0xc24b8b70d0f89791ULL, 0xc76c51a30654be30ULL, 0xd192e819d6ef5218ULL,
+    0xd69906245565a910ULL, 0xf40e35855771202aULL, 0x106aa07032bbd1b8ULL,
+    0x19a4c116b8d2d0c8ULL, 0x1e376c085141ab53ULL, 0x2748774cdf8eeb99ULL,
+    0x34b0bcb5e19b48a8ULL, 0x391c0cb3c5c95a63ULL, 0x4ed8aa4ae3418acbULL,
+    0x5b9cca4f7763e373ULL, 0x682e6ff3d6b2b8a3ULL, 0x748f82ee5defb2fcULL,
+    0x78a5636f43172f60ULL, 0x84c87814a1f0ab72ULL, 0x8cc702081a6439ecULL,
+    0x90befffa23631e28ULL, 0xa4506cebde82bde9ULL, 0xbef9a3f7b2c67915ULL,
+    0xc67178f2e372532bULL, 0xca273eceea26619cULL, 0xd186b8c721c0c207ULL,
+    0xeada7dd6cde0eb1eULL, 0xf57d4f7fee6ed178ULL, 0x06f067aa72176fbaULL,
+    0x0a637dc5a2c898a6ULL, 0x113f9804bef90daeULL, 0x1b710b35131c471bULL,
+    0x28db77f523047d84ULL, 0x32caab7b40c72493ULL, 0x3c9ebe0a15c9bebcULL,
+    0x431d67c49c100d4cULL, 0x4cc5d4becb3e42b6ULL, 0x597f299cfc657e2aULL,
+    0x5fcb6fab3ad6faecULL, 0x6c44198c4a475817ULL};
+
+void sha256_transform(SHA256_CTX *ctx, const unsigned char data[]) {
+	unsigned int a, b, c, d, e, f, g, h, i, j, t1, t2, m[64];
+
+	for (i = 0, j = 0; i < 16; ++i, j += 4)
+		m[i] = ((unsigned int)data[j] << 24) | (data[j + 1] << 16) |
+		       (data[j + 2] << 8) | (data[j + 3]);
+	for (; i < 64; ++i)
+		m[i] = (ROTR32(m[i - 2], 17) ^ ROTR32(m[i - 2], 19) ^
+			(m[i - 2] >> 10)) +
+		       m[i - 7] +
+		       (ROTR32(m[i - 15], 7) ^ ROTR32(m[i - 15], 18) ^
+			(m[i - 15] >> 3)) +
+		       m[i - 16];
+
+	a = ctx->state[0];
+	b = ctx->state[1];
+	c = ctx->state[2];
+	d = ctx->state[3];
+	e = ctx->state[4];
+	f = ctx->state[5];
+	g = ctx->state[6];
+	h = ctx->state[7];
+
+	for (i = 0; i < 64; ++i) {
+		t1 = h + (ROTR32(e, 6) ^ ROTR32(e, 11) ^ ROTR32(e, 25)) +
+		     CH(e, f, g) + k256[i] + m[i];
+		t2 = (ROTR32(a, 2) ^ ROTR32(a, 13) ^ ROTR32(a, 22)) +
+		     MAJ(a, b, c);
+		h = g;
+		g = f;
+		f = e;
+		e = d + t1;
+		d = c;
+		c = b;
+		b = a;
+		a = t1 + t2;
+	}
+
+	ctx->state[0] += a;
+	ctx->state[1] += b;
+	ctx->state[2] += c;
+	ctx->state[3] += d;
+	ctx->state[4] += e;
+	ctx->state[5] += f;
+	ctx->state[6] += g;
+	ctx->state[7] += h;
+}
+
+void SHA256_Init(SHA256_CTX *ctx) {
+	ctx->datalen = 0;
+	ctx->bitlen = 0;
+	ctx->state[0] = 0x6a09e667;
+	ctx->state[1] = 0xbb67ae85;
+	ctx->state[2] = 0x3c6ef372;
+	ctx->state[3] = 0xa54ff53a;
+	ctx->state[4] = 0x510e527f;
+	ctx->state[5] = 0x9b05688c;
+	ctx->state[6] = 0x1f83d9ab;
+	ctx->state[7] = 0x5be0cd19;
+}
+
+void SHA256_Update(SHA256_CTX *ctx, const unsigned char data[], size_t len) {
+	size_t i;
+
+	for (i = 0; i < len; ++i) {
+		ctx->data[ctx->datalen] = data[i];
+		ctx->datalen++;
+		if (ctx->datalen == 64) {
+			sha256_transform(ctx, ctx->data);
+			ctx->bitlen += 512;
+			ctx->datalen = 0;
+		}
+	}
+}
+
+void SHA256_Final(SHA256_CTX *ctx, unsigned char hash[]) {
+	unsigned int i;
+
+	i = ctx->datalen;
+
+	if (ctx->datalen < 56) {
+		ctx->data[i++] = 0x80;
+		while (i < 56) ctx->data[i++] = 0x00;
+	} else {
+		ctx->data[i++] = 0x80;
+		while (i < 64) ctx->data[i++] = 0x00;
+		sha256_transform(ctx, ctx->data);
+		memset(ctx->data, 0, 56);
+	}
+
+	ctx->bitlen += ctx->datalen * 8;
+	for (i = 0; i < 8; ++i) ctx->data[63 - i] = ctx->bitlen >> (i * 8);
+	sha256_transform(ctx, ctx->data);
+
+	for (i = 0; i < 32; ++i)
+		hash[i] = (ctx->state[i / 4] >> (24 - (i % 4) * 8)) & 0xff;
+}
+
+void SHA256(const unsigned char *data, size_t size, unsigned char hash[]) {
+	SHA256_CTX ctx;
+	SHA256_Init(&ctx);
+	SHA256_Update(&ctx, data, size);
+	SHA256_Final(&ctx, hash);
+}
+
+void sha512_transform(SHA512_CTX *ctx, const unsigned char data[]) {
+	unsigned long long a, b, c, d, e, f, g, h, t1, t2, m[80];
+	unsigned int i, j, k;
+
+	for (i = 0, j = 0; i < 16; ++i, j += 8) {
+		m[i] = 0;
+		for (k = 0; k < 8; ++k) m[i] = (m[i] << 8) | data[j + k];
+	}
+	for (; i < 80; ++i)
+		m[i] = (ROTR64(m[i - 2], 19) ^ ROTR64(m[i - 2], 61) ^
+			(m[i - 2] >> 6)) +
+		       m[i - 7] +
+		       (ROTR64(m[i - 15], 1) ^ ROTR64(m[i - 15], 8) ^
+			(m[i - 15] >> 7)) +
+		       m[i - 16];
+
+	a = ctx->state[0];
+	b = ctx->state[1];
+	c = ctx->state[2];
+	d = ctx->state[3];
+	e = ctx->state[4];
+	f = ctx->state[5];
+	g = ctx->state[6];
+	h = ctx->state[7];
+
+	for (i = 0; i < 80; ++i) {
+		t1 = h + (ROTR64(e, 14) ^ ROTR64(e, 18) ^ ROTR64(e, 41)) +
+		     CH(e, f, g) + k512[i] + m[i];
+		t2 = (ROTR64(a, 28) ^ ROTR64(a, 34) ^ ROTR64(a, 39)) +
+		     MAJ(a, b, c);
+		h = g;
+		g = f;
+		f = e;
+		e = d + t1;
+		d = c;
+		c = b;
+		b = a;
+		a = t1 + t2;
+	}
+
+	ctx->state[0] += a;
+	ctx->state[1] += b;
+	ctx->state[2] += c;
+	ctx->state[3] += d;
+	ctx->state[4] += e;
+	ctx->state[5] += f;
+	ctx->state[6] += g;
+	ctx->state[7] += h;
+}
+
+void SHA512_Init(SHA512_CTX *ctx) {
+	ctx->datalen = 0;
+	ctx->bitlen = 0;
+	ctx->state[0] = 0x6a09e667f3bcc908ULL;
+	ctx->state[1] = 0xbb67ae8584caa73bULL;
+	ctx->state[2] = 0x3c6ef372fe94f82bULL;
+	ctx->state[3] = 0xa54ff53a5f1d36f1ULL;
+	ctx->state[4] = 0x510e527fade682d1ULL;
+	ctx->state[5] = 0x9b05688c2b3e6c1fULL;
+	ctx->state[6] = 0x1f83d9abfb41bd6bULL;
+	ctx->state[7] = 0x5be0cd19137e2179ULL;
+}
+
+void SHA512_Update(SHA512_CTX *ctx, const unsigned char data[], size_t len) {
+	size_t i;
+
+	for (i = 0; i < len; ++i) {
+		ctx->data[ctx->datalen] = data[i];
+		ctx->datalen++;
+		if (ctx->datalen == 128) {
+			sha512_transform(ctx, ctx->data);
+			ctx->bitlen += 1024;
+			ctx->datalen = 0;
+		}
+	}
+}
+
+void SHA512_Final(SHA512_CTX *ctx, unsigned char hash[]) {
+	unsigned int i;
+
+	i = ctx->datalen;
+
+	// the length is encoded in the final 16 bytes; messages are always
+	// shorter than 2^64 bits so the upper 8 bytes are zero.
+	if (ctx->datalen < 112) {
+		ctx->data[i++] = 0x80;
+		while (i < 112) ctx->data[i++] = 0x00;
+	} else {
+		ctx->data[i++] = 0x80;
+		while (i < 128) ctx->data[i++] = 0x00;
+		sha512_transform(ctx, ctx->data);
+		memset(ctx->data, 0, 112);
+	}
+
+	ctx->bitlen += ctx->datalen * 8;
+	for (i = 0; i < 8; ++i) {
+		ctx->data[127 - i] = ctx->bitlen >> (i * 8);
+		ctx->data[119 - i] = 0;
+	}
+	sha512_transform(ctx, ctx->data);
+
+	for (i = 0; i < 64; ++i)
+		hash[i] = (ctx->state[i / 8] >> (56 - (i % 8) * 8)) & 0xff;
+}
+
+void SHA512(const unsigned char *data, size_t size, unsigned char hash[]) {
+	SHA512_CTX ctx;
+	SHA512_Init(&ctx);
+	SHA512_Update(&ctx, data, size);
+	SHA512_Final(&ctx, hash);
+}
+
+void HMAC_SHA512(const unsigned char *key, size_t keylen,
+		 const unsigned char *data, size_t size, unsigned char hash[]) {
+	unsigned char k[SHA512_BLOCK_SIZE];
+	unsigned char pad[SHA512_BLOCK_SIZE];
+	unsigned char inner[64];
+	SHA512_CTX ctx;
+	size_t i;
+
+	memset(k, 0, sizeof(k));
+	if (keylen > SHA512_BLOCK_SIZE)
+		SHA512(key, keylen, k);
+	else
+		memcpy(k, key, keylen);
+
+	for (i = 0; i < SHA512_BLOCK_SIZE; ++i) pad[i] = k[i] ^ 0x36;
+	SHA512_Init(&ctx);
+	SHA512_Update(&ctx, pad, SHA512_BLOCK_SIZE);
+	SHA512_Update(&ctx, data, size);
+	SHA512_Final(&ctx, inner);
+
+	for (i = 0; i < SHA512_BLOCK_SIZE; ++i) pad[i] = k[i] ^ 0x5c;
+	SHA512_Init(&ctx);
+	SHA512_Update(&ctx, pad, SHA512_BLOCK_SIZE);
+	SHA512_Update(&ctx, inner, sizeof(inner));
+	SHA512_Final(&ctx, hash);
+
+	memset(k, 0, sizeof(k));
+	memset(pad, 0, sizeof(pad));
+	memset(inner, 0, sizeof(inner));
+}
+
+void HMAC_SHA256(const unsigned char *key, size_t keylen,
+		 const unsigned char *data, size_t size, unsigned char hash[]) {
+	unsigned char k[SHA256_BLOCK_SIZE];
+	unsigned char pad[SHA256_BLOCK_SIZE];
+	unsigned char inner[32];
+	SHA256_CTX ctx;
+	size_t i;
+
+	memset(k, 0, sizeof(k));
+	if (keylen > SHA256_BLOCK_SIZE)
+		SHA256(key, keylen, k);
+	else
+		memcpy(k, key, keylen);
+
+	for (i = 0; i < SHA256_BLOCK_SIZE; ++i) pad[i] = k[i] ^ 0x36;
+	SHA256_Init(&ctx);
+	SHA256_Update(&ctx, pad, SHA256_BLOCK_SIZE);
+	SHA256_Update(&ctx, data, size);
+	SHA256_Final(&ctx, inner);
+
+	for (i = 0; i < SHA256_BLOCK_SIZE; ++i) pad[i] = k[i] ^ 0x5c;
+	SHA256_Init(&ctx);
+	SHA256_Update(&ctx, pad, SHA256_BLOCK_SIZE);
+	SHA256_Update(&ctx, inner, sizeof(inner));
+	SHA256_Final(&ctx, hash);
+
+	memset(k, 0, sizeof(k));
+	memset(pad, 0, sizeof(pad));
+	memset(inner, 0, sizeof(inner));
+}
@@ -1,4 +1,15 @@
+#ifdef __linux__
+#define _GNU_SOURCE
+#endif	// __linux__
+
 #include <pthread.h>
+#include <sched.h>
+#include <unistd.h>
+
+#ifdef __linux__
+int pthread_setname_np(pthread_t thread, const char *name);
+int pthread_getname_np(pthread_t thread, char *name, unsigned long len);
+#endif	// __linux__
 
 typedef struct ThreadHandle {
 	pthread_t handle;
@@ -28,3 +39,38 @@ int thread_join(ThreadHandle *handle) {
 int thread_detach(ThreadHandle *handle) {
 	return pthread_detach(handle->handle);
 }
+
+// names the calling thread, truncated to the 15 bytes that Linux allows
+int thread_set_name(const char *name, unsigned long long len) {
+	char buf[16];
+	if (len > sizeof(buf) - 1) len = sizeof(buf) - 1;
+	for (unsigned long long i = 0; i < len; i++) buf[i] = name[i];
+	buf[len] = 0;
+#ifdef __APPLE__
+	return pthread_setname_np(buf);
+#else
+	return pthread_setname_np(pthread_self(), buf);
+#endif	// __APPLE__
+}
+
+int thread_get_name(char *buf, unsigned long long len) {
+	return pthread_getname_np(pthread_self(), buf, len);
+}
+
+// pins the calling thread to cpu. Only Linux lets threads be pinned, elsewhere this fails.
+int thread_set_affinity(unsigned long long cpu) {
+#ifdef __linux__
+	cpu_set_t set;
+	if (cpu >= CPU_SETSIZE) return -1;
+	CPU_ZERO(&set);
+	CPU_SET(cpu, &set);
+	return pthread_setaffinity_np(pthread_self(), sizeof(set), &set);
+#else
+	return -1;
+#endif	// __linux__
+}
+
+unsigned long long thread_cpu_count() {
+	long count = sysconf(_SC_NPROCESSORS_ONLN);
+	return count > 0 ? count : 1;
+}
@@ -130,9 +130,36 @@ int cstring_is_alpha_numeric(const char *X) {
 
 typedef unsigned long size_t;
 int snprintf(char *s, size_t n, const char *format, ...);
+double strtod(const char *s, char **end);
 
-int f64_to_str(double d, char *buf, unsigned long long capacity) {
-	return snprintf(buf, capacity, "%.5f", d);
+int f64_to_str(double d, char *buf, unsigned long long capacity,
+	       int precision) {
+	return snprintf(buf, capacity, "%.*f", precision, d);
+}
+
+// the shortest %g form of d which reads back as d, so that 0.1 is not written as
+// 0.10000000000000001
+int f64_to_str_shortest(double d, char *buf, unsigned long long capacity) {
+	int len = -1;
+	for (int precision = 1; precision <= 17; precision++) {
+		len = snprintf(buf, capacity, "%.*g", precision, d);
+		if (len < 0 || (unsigned long long)len >= capacity) return -1;
+		if (strtod(buf, 0) == d) break;
+	}
+	return len;
+}
+
+// parses the len bytes at s, which need not be terminated, as a double. Returns 0 if
+// all of them are part of the number.
+int str_to_f64(const char *s, unsigned long long len, double *out) {
+	char buf[512];
+	char *end;
+	if (len == 0 || len >= sizeof(buf)) return -1;
+	copy_bytes((unsigned char *)buf, (const unsigned char *)s, len);
+	buf[len] = 0;
+	*out = strtod(buf, &end);
+	if (end != buf + len) return -1;
+	return 0;
 }
 
 void ptr_add(void **p, long long v) { *p = (void *)((char *)*p + v); }
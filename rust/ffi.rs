@@ -11,6 +11,11 @@ extern "C" {
 
 	pub static secp256k1_nonce_function_default: NonceFn;
 
+	pub static secp256k1_generator_const_g: Generator;
+
+	pub static secp256k1_generator_const_h: Generator;
+	pub static secp256k1_context_no_precomp: *const Context;
+
 	// Contexts
 	pub fn secp256k1_context_create(flags: u32) -> *mut Context;
 
@@ -18,6 +23,8 @@ extern "C" {
 
 	pub fn secp256k1_context_destroy(cx: *mut Context);
 
+	pub fn secp256k1_context_preallocated_size(flags: u32) -> usize;
+
 	pub fn secp256k1_context_randomize(cx: *mut Context, seed32: *const u8) -> i32;
 	// Scratch space
 	pub fn secp256k1_scratch_space_create(cx: *mut Context, max_size: usize) -> *mut ScratchSpace;
@@ -31,7 +38,12 @@ extern "C" {
 		seed32: *const u8,
 	) -> i32;
 
-	// TODO secp256k1_context_set_illegal_callback
+	pub fn secp256k1_context_set_illegal_callback(
+		cx: *mut Context,
+		fun: extern "C" fn(message: *const u8, data: *mut u8),
+		data: *const u8,
+	);
+
 	// TODO secp256k1_context_set_error_callback
 	// (Actually, I don't really want these exposed; if either of these
 	// are ever triggered it indicates a bug in rust-secp256k1, since
@@ -522,16 +534,24 @@ extern "C" {
 	pub fn alloc(len: usize) -> *const u8;
 	pub fn resize(ptr: *const u8, len: usize) -> *const u8;
 	pub fn release(ptr: *const u8);
+	pub fn secure_alloc(size: u64) -> *mut u8;
+	pub fn secure_release(ptr: *mut u8, size: u64);
 	pub fn sleep_millis(millis: u64) -> i32;
 	pub fn ptr_add(p: *mut u8, v: i64);
 	pub fn getalloccount() -> i64;
+	pub fn getallocbytes() -> u64;
+	pub fn getallocblocks() -> u64;
 	pub fn getfdcount() -> i64;
 	pub fn atomic_store_u64(ptr: *mut u64, value: u64);
 	pub fn atomic_load_u64(ptr: *const u64) -> u64;
 	pub fn atomic_fetch_add_u64(ptr: *mut u64, value: u64) -> u64;
 	pub fn atomic_fetch_sub_u64(ptr: *mut u64, value: u64) -> u64;
 	pub fn cas_release(ptr: *mut u64, expect: *const u64, desired: u64) -> bool;
-	pub fn f64_to_str(d: f64, buf: *mut u8, capacity: u64) -> i32;
+	pub fn tsan_acquire(addr: *const u8);
+	pub fn tsan_release(addr: *const u8);
+	pub fn f64_to_str(d: f64, buf: *mut u8, capacity: u64, precision: i32) -> i32;
+	pub fn f64_to_str_shortest(d: f64, buf: *mut u8, capacity: u64) -> i32;
+	pub fn str_to_f64(s: *const u8, len: u64, out: *mut f64) -> i32;
 	pub fn sched_yield() -> i32;
 	pub fn cstring_len(s: *const u8) -> usize;
 	pub fn backtrace_ptr(bin: *const u8, len: usize) -> usize;
@@ -539,6 +559,7 @@ extern "C" {
 	pub fn backtrace_size() -> usize;
 	pub fn backtrace_free(bt: *const u8);
 	pub fn getmicros() -> i64;
+	pub fn thread_cpu_micros() -> u64;
 
 	// THREAD
 	pub fn thread_create(start_routine: extern "C" fn(*mut u8), arg: *mut u8) -> i32;
@@ -550,14 +571,28 @@ extern "C" {
 	pub fn thread_join(handle: *const u8) -> i32;
 	pub fn thread_detach(handle: *const u8) -> i32;
 	pub fn thread_handle_size() -> usize;
+	pub fn thread_set_name(name: *const u8, len: usize) -> i32;
+	pub fn thread_get_name(buf: *mut u8, len: usize) -> i32;
+	pub fn thread_set_affinity(cpu: u64) -> i32;
+	pub fn thread_cpu_count() -> u64;
 
 	// CHANNEL
-	pub fn channel_init(channel: *const u8) -> i32;
-	pub fn channel_send(channel: *const u8, ptr: *const u8) -> i32;
+	pub fn channel_init(channel: *const u8, capacity: u64) -> i32;
+	pub fn channel_send(channel: *const u8, ptr: *const u8, block: bool) -> i32;
 	pub fn channel_recv(channel: *const u8) -> *mut u8;
+	pub fn channel_recv_timeout(channel: *const u8, timeout_micros: u64) -> *mut u8;
 	pub fn channel_handle_size() -> usize;
 	pub fn channel_destroy(channel: *const u8) -> i32;
 	pub fn channel_pending(channel: *const u8) -> bool;
+	pub fn channel_len(channel: *const u8) -> u64;
+
+	// CONDVAR
+	pub fn condvar_init(handle: *const u8) -> i32;
+	pub fn condvar_seq(handle: *const u8) -> u64;
+	pub fn condvar_wait(handle: *const u8, expected: u64, timeout_micros: u64) -> i32;
+	pub fn condvar_notify(handle: *const u8, all: bool);
+	pub fn condvar_handle_size() -> usize;
+	pub fn condvar_destroy(handle: *const u8) -> i32;
 
 	// SOCKET
 	pub fn socket_handle_size() -> usize;
@@ -567,11 +602,31 @@ extern "C" {
 	pub fn socket_connect(handle: *mut u8, addr: *const u8, port: i32) -> i32;
 	pub fn socket_shutdown(handle: *const u8) -> i32;
 	pub fn socket_close(handle: *const u8) -> i32;
+	pub fn socket_set_nonblocking(handle: *const u8, nonblocking: bool) -> i32;
 	pub fn socket_listen(handle: *mut u8, addr: *const u8, port: u16, backlog: i32) -> i32;
 	pub fn socket_accept(handle: *const u8, nhandle: *mut u8) -> i32;
+	pub fn socket_peer_addr(handle: *const u8, addr: *mut u8, port: *mut u16) -> i32;
+	pub fn socket_pair(handle1: *mut u8, handle2: *mut u8) -> i32;
 	pub fn socket_send(handle: *const u8, buf: *const u8, len: usize) -> i64;
 	pub fn socket_recv(handle: *const u8, buf: *mut u8, capacity: usize) -> i64;
+	pub fn socket_udp_bind(handle: *mut u8, addr: *const u8, port: i32) -> i32;
+	pub fn socket_sendto(
+		handle: *const u8,
+		buf: *const u8,
+		len: usize,
+		addr: *const u8,
+		port: i32,
+	) -> i64;
+	pub fn socket_recvfrom(
+		handle: *const u8,
+		buf: *mut u8,
+		capacity: usize,
+		addr: *mut u8,
+		port: *mut u16,
+	) -> i64;
 	pub fn socket_clear_pipe(handle: *const u8) -> i32;
+	pub fn socket_errno() -> i32;
+	pub fn errno_name(errno: i32) -> *const u8;
 
 	pub fn socket_multiplex_init(handle: *mut u8) -> i32;
 	pub fn socket_multiplex_register(
@@ -585,6 +640,7 @@ extern "C" {
 		socket: *const u8,
 		connptr: *const u8,
 	) -> i32;
+	pub fn socket_multiplex_unregister(handle: *const u8, socket: *const u8) -> i32;
 	pub fn socket_multiplex_wait(
 		handle: *const u8,
 		events: *mut u8,
@@ -601,10 +657,36 @@ extern "C" {
 	pub fn Base64decode(output: *mut u8, input: *mut u8);
 	pub fn Base64encode(input: *const u8, output: *mut u8, len: usize);
 	pub fn SHA1(data: *const u8, size: usize, hash: *mut u8);
+	pub fn SHA256(data: *const u8, size: usize, hash: *mut u8);
+	pub fn SHA512(data: *const u8, size: usize, hash: *mut u8);
+	pub fn HMAC_SHA256(key: *const u8, keylen: usize, data: *const u8, size: usize, hash: *mut u8);
+	pub fn HMAC_SHA512(key: *const u8, keylen: usize, data: *const u8, size: usize, hash: *mut u8);
+	pub fn RIPEMD160(data: *const u8, size: usize, hash: *mut u8);
+	pub fn chacha20poly1305_seal(
+		key: *const u8,
+		nonce: *const u8,
+		aad: *const u8,
+		aadlen: usize,
+		input: *const u8,
+		len: usize,
+		out: *mut u8,
+		tag: *mut u8,
+	);
+	pub fn chacha20poly1305_open(
+		key: *const u8,
+		nonce: *const u8,
+		aad: *const u8,
+		aadlen: usize,
+		input: *const u8,
+		len: usize,
+		tag: *const u8,
+		out: *mut u8,
+	) -> i32;
 
 	// CPSRNG
 	pub fn cpsrng_rand_bytes(v: *mut u8, len: usize);
 	pub fn cpsrng_context_create() -> *mut u8;
 	pub fn cpsrng_context_destroy(ctx: *mut u8);
+	pub fn cpsrng_context_size() -> u64;
 	pub fn cpsrng_rand_bytes_ctx(ctx: *mut u8, v: *mut u8, len: usize);
 }
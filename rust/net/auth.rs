@@ -0,0 +1,530 @@
+//! # Authentication
+//! An Authenticator registered with WebSocket::register_authenticator inspects each
+//! client's upgrade request and either establishes an Identity, which handlers read with
+//! WsResponse::identity, or rejects the client with a 401 before any handler runs. The
+//! built in providers read the Authorization header:
+//! * TokenAuthenticator: `Bearer <token>` where token is one of a static list.
+//! * HmacAuthenticator: `Bearer <name>.<expires>.<mac>` issued by HmacAuthenticator::issue,
+//!   so any number of clients can be admitted with a single shared key.
+//! * SchnorrAuthenticator: `Schnorr <key>.<timestamp>.<signature>`, a BIP-340 signature
+//!   with one of a list of allowed secp256k1 keys over the uri and a recent timestamp.
+//!
+//! Authenticators run on the worker threads, possibly several at once, and only get
+//! shared access to themselves.
+
+use core::result::Result as CoreResult;
+use core::str::from_utf8;
+use net::ws::Handshake;
+use prelude::*;
+use secp256k1::schnorr::{Keypair, XOnlyPublicKey, XONLY_PUBLIC_KEY_SIZE};
+use secp256k1::types::{ContextFlag, CtEq, Message, Secp256k1, Signature};
+use std::encoding::{hex_decode_array, hex_encode};
+use std::hash::{hmac_sha256, sha256, SHA256_SIZE};
+
+// domain separation for the message signed by SchnorrAuthenticator clients
+const SCHNORR_AUTH_TAG: &[u8; 11] = b"fam-ws-auth";
+const SIGNATURE_SIZE: usize = 64;
+
+/// Who a client was authenticated as
+pub struct Identity {
+	name: String,
+}
+
+/// Called from every worker thread of a WebSocket
+pub trait Authenticator: Send + Sync {
+	/// Returns the identity of the client making the request, or an error to reject it
+	fn authenticate(&self, handshake: &Handshake) -> Result<Identity, Error>;
+}
+
+/// Accepts clients presenting one of a fixed set of bearer tokens
+pub struct TokenAuthenticator {
+	// the sha256 of each token, so tokens of any length are compared in constant time
+	tokens: Vec<([u8; SHA256_SIZE], String)>,
+}
+
+/// Accepts bearer tokens carrying an HMAC-SHA256 of the name and expiry under a shared key
+pub struct HmacAuthenticator {
+	key: Vec<u8>,
+}
+
+/// Accepts clients which sign the uri and the current time with an allowed key. A
+/// signature can be replayed by whoever observes it until it falls outside the allowed
+/// clock skew, so it should only be sent over an encrypted transport.
+pub struct SchnorrAuthenticator {
+	secp: Secp256k1,
+	keys: Vec<(XOnlyPublicKey, String)>,
+	max_skew_micros: u64,
+}
+
+// the value of a decimal part of a token
+fn parse_decimal(s: &[u8]) -> Option<u64> {
+	match String::from_utf8(s) {
+		Ok(s) => match s.parse_u64() {
+			Ok(v) => Some(v),
+			Err(_e) => None,
+		},
+		Err(_e) => None,
+	}
+}
+
+fn push_hex(sb: &mut StringBuilder, bytes: &[u8]) -> Result<(), Error> {
+	match hex_encode(bytes) {
+		Ok(hex) => sb.push_str(hex.to_str()),
+		Err(e) => Err(e),
+	}
+}
+
+fn decode_hex<const N: usize>(s: &[u8]) -> Option<[u8; N]> {
+	match from_utf8(s) {
+		CoreResult::Ok(s) => match hex_decode_array(s) {
+			Ok(v) => Some(v),
+			Err(_e) => None,
+		},
+		CoreResult::Err(_e) => None,
+	}
+}
+
+// splits `a.b.c` into its three parts, none of which may contain a dot
+fn split3(s: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+	let mut dots = [0usize; 2];
+	let mut n = 0;
+	for i in 0..s.len() {
+		if s[i] == b'.' {
+			if n == 2 {
+				return None;
+			}
+			dots[n] = i;
+			n += 1;
+		}
+	}
+	if n != 2 {
+		return None;
+	}
+	Some((&s[0..dots[0]], &s[dots[0] + 1..dots[1]], &s[dots[1] + 1..]))
+}
+
+// names end up inside tokens and log lines
+fn valid_name(name: &str) -> bool {
+	if name.len() == 0 {
+		return false;
+	}
+	for c in name.as_bytes() {
+		if *c <= b' ' || *c == b'.' || *c >= 0x7f {
+			return false;
+		}
+	}
+	true
+}
+
+// the 32 byte message a SchnorrAuthenticator client signs
+fn schnorr_message(uri: &[u8], timestamp_micros: u64) -> Message {
+	let mut buf = [0u8; 11 + SHA256_SIZE + 8];
+	buf[0..11].copy_from_slice(SCHNORR_AUTH_TAG);
+	buf[11..11 + SHA256_SIZE].copy_from_slice(&sha256(uri));
+	to_be_bytes_u64(timestamp_micros, &mut buf[11 + SHA256_SIZE..]);
+	Message::sha256(&buf)
+}
+
+impl Identity {
+	pub fn new(name: &str) -> Result<Self, Error> {
+		match String::new(name) {
+			Ok(name) => Ok(Self { name }),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn name(&self) -> &str {
+		self.name.to_str()
+	}
+}
+
+impl TokenAuthenticator {
+	pub fn new() -> Self {
+		Self { tokens: Vec::new() }
+	}
+
+	/// Admits clients presenting token as the identity name
+	pub fn add(&mut self, token: &str, name: &str) -> Result<(), Error> {
+		if token.len() == 0 || !valid_name(name) {
+			return Err(err!(IllegalArgument));
+		}
+		let token = sha256(token.as_bytes());
+		let name = match String::new(name) {
+			Ok(name) => name,
+			Err(e) => return Err(e),
+		};
+		self.tokens.push((token, name))
+	}
+}
+
+impl Authenticator for TokenAuthenticator {
+	fn authenticate(&self, handshake: &Handshake) -> Result<Identity, Error> {
+		let presented = match handshake.authorization("Bearer") {
+			Some(presented) => presented,
+			None => return Err(err!(Unauthorized)),
+		};
+		let presented = sha256(presented);
+		for (token, name) in &self.tokens {
+			if token.ct_eq(&presented) {
+				return Identity::new(name.to_str());
+			}
+		}
+		Err(err!(Unauthorized))
+	}
+}
+
+impl HmacAuthenticator {
+	pub fn new(key: &[u8]) -> Result<Self, Error> {
+		if key.len() == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut v = Vec::new();
+		match v.append_ptr(key.as_ptr(), key.len()) {
+			Ok(_) => Ok(Self { key: v }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Token admitting its bearer as name until expires_micros, in microseconds since the
+	/// epoch. name may not contain dots, spaces or control characters.
+	pub fn issue(&self, name: &str, expires_micros: u64) -> Result<String, Error> {
+		if !valid_name(name) {
+			return Err(err!(IllegalArgument));
+		}
+		let mut sb = StringBuilder::new();
+		match sb.push_strs(&[name, "."]) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match sb.push(&expires_micros) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mac = hmac_sha256(self.key.as_slice(), sb.as_str().as_bytes());
+		match sb.push_char('.') {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match push_hex(&mut sb, &mac) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		sb.to_string()
+	}
+}
+
+impl Authenticator for HmacAuthenticator {
+	fn authenticate(&self, handshake: &Handshake) -> Result<Identity, Error> {
+		let token = match handshake.authorization("Bearer") {
+			Some(token) => token,
+			None => return Err(err!(Unauthorized)),
+		};
+		let (name, expires, mac) = match split3(token) {
+			Some(parts) => parts,
+			None => return Err(err!(Unauthorized)),
+		};
+		let mac: [u8; SHA256_SIZE] = match decode_hex(mac) {
+			Some(mac) => mac,
+			None => return Err(err!(Unauthorized)),
+		};
+		let signed = &token[0..name.len() + 1 + expires.len()];
+		if !hmac_sha256(self.key.as_slice(), signed).ct_eq(&mac) {
+			return Err(err!(Unauthorized));
+		}
+		match parse_decimal(expires) {
+			Some(expires) if expires >= getmicros!() as u64 => {}
+			_ => return Err(err!(Unauthorized)),
+		}
+		match from_utf8(name) {
+			CoreResult::Ok(name) => Identity::new(name),
+			CoreResult::Err(_e) => Err(err!(Unauthorized)),
+		}
+	}
+}
+
+impl SchnorrAuthenticator {
+	/// Accepts signatures whose timestamp is within max_skew_micros of the server's clock
+	pub fn new(max_skew_micros: u64) -> Result<Self, Error> {
+		match Secp256k1::with_caps(ContextFlag::VerifyOnly) {
+			Ok(secp) => Ok(Self {
+				secp,
+				keys: Vec::new(),
+				max_skew_micros,
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Admits clients holding the secret for key as the identity name
+	pub fn allow(&mut self, key: XOnlyPublicKey, name: &str) -> Result<(), Error> {
+		if !valid_name(name) {
+			return Err(err!(IllegalArgument));
+		}
+		match String::new(name) {
+			Ok(name) => self.keys.push((key, name)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Authorization header value for a client connecting to uri at timestamp_micros,
+	/// suitable for WebSocket::add_client_auth. secp must be able to sign.
+	pub fn authorization(
+		secp: &Secp256k1,
+		keypair: &Keypair,
+		uri: &str,
+		timestamp_micros: u64,
+		aux_rand: &[u8; 32],
+	) -> Result<String, Error> {
+		let msg = schnorr_message(uri.as_bytes(), timestamp_micros);
+		let sig = match secp.schnorr_sign(&msg, keypair, aux_rand) {
+			Ok(sig) => sig,
+			Err(e) => return Err(e),
+		};
+		let mut sb = StringBuilder::new();
+		let mut res = sb.push_str("Schnorr ");
+		if res.is_ok() {
+			res = push_hex(&mut sb, &keypair.public.0);
+		}
+		if res.is_ok() {
+			res = sb.push_char('.');
+		}
+		if res.is_ok() {
+			res = sb.push(&timestamp_micros);
+		}
+		if res.is_ok() {
+			res = sb.push_char('.');
+		}
+		if res.is_ok() {
+			res = push_hex(&mut sb, &sig.0);
+		}
+		match res {
+			Ok(_) => sb.to_string(),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Authenticator for SchnorrAuthenticator {
+	fn authenticate(&self, handshake: &Handshake) -> Result<Identity, Error> {
+		let credentials = match handshake.authorization("Schnorr") {
+			Some(credentials) => credentials,
+			None => return Err(err!(Unauthorized)),
+		};
+		let (key, timestamp, sig) = match split3(credentials) {
+			Some(parts) => parts,
+			None => return Err(err!(Unauthorized)),
+		};
+		let key = match decode_hex::<XONLY_PUBLIC_KEY_SIZE>(key) {
+			Some(key) => XOnlyPublicKey(key),
+			None => return Err(err!(Unauthorized)),
+		};
+		let sig = match decode_hex::<SIGNATURE_SIZE>(sig) {
+			Some(sig) => Signature(sig),
+			None => return Err(err!(Unauthorized)),
+		};
+		let timestamp = match parse_decimal(timestamp) {
+			Some(timestamp) => timestamp,
+			None => return Err(err!(Unauthorized)),
+		};
+		let now = getmicros!() as u64;
+		let skew = if now > timestamp {
+			now - timestamp
+		} else {
+			timestamp - now
+		};
+		if skew > self.max_skew_micros {
+			return Err(err!(Unauthorized));
+		}
+		for (allowed, name) in &self.keys {
+			if *allowed == key {
+				let msg = schnorr_message(handshake.uri(), timestamp);
+				if !self.secp.schnorr_verify(&sig, &msg, &key) {
+					return Err(err!(Unauthorized));
+				}
+				return Identity::new(name.to_str());
+			}
+		}
+		Err(err!(Unauthorized))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::sleep_millis;
+	use net::ws::*;
+	use std::cpsrng::Cpsrng;
+
+	// request headers as the server sees them, from the end of the uri
+	fn authenticate(auth: &dyn Authenticator, uri: &str, value: &str) -> Result<Identity, Error> {
+		let mut sb = StringBuilder::new();
+		sb.push_strs(&[
+			" HTTP/1.1\r\nHost: localhost\r\nAuthorization: ",
+			value,
+			"\r\n\r\n",
+		])
+		.unwrap();
+		auth.authenticate(&Handshake::new(uri.as_bytes(), sb.as_str().as_bytes()))
+	}
+
+	#[test]
+	fn test_token_and_hmac_auth() {
+		let _alloc = AllocGuard::new();
+		let headers = b" HTTP/1.1\r\nAuthorization: Bearer abc\r\nX-Other: 1\r\n\r\n";
+		let handshake = Handshake::new(b"/", headers);
+		assert!(handshake.header("X-Other") == Some(&b"1"[..]));
+		assert!(handshake.header("X-Missing").is_none());
+		assert!(handshake.authorization("Bearer") == Some(&b"abc"[..]));
+		assert!(handshake.authorization("Basic").is_none());
+
+		let mut tokens = TokenAuthenticator::new();
+		assert!(tokens.add("secret1", "bad name").is_err());
+		tokens.add("secret1", "alice").unwrap();
+		tokens.add("secret2", "bob").unwrap();
+		let id = authenticate(&tokens, "/", "Bearer secret2").unwrap();
+		assert_eq!(id.name(), "bob");
+		assert!(authenticate(&tokens, "/", "Bearer secret").is_err());
+		assert!(authenticate(&tokens, "/", "Basic secret1").is_err());
+
+		assert!(HmacAuthenticator::new(&[]).is_err());
+		let hmac = HmacAuthenticator::new(b"shared key").unwrap();
+		assert!(hmac.issue("a.b", 0).is_err());
+		let later = getmicros!() as u64 + 60_000_000;
+		let token = hmac.issue("carol", later).unwrap();
+		let mut value = StringBuilder::new();
+		value.push_strs(&["Bearer ", token.to_str()]).unwrap();
+		let id = authenticate(&hmac, "/", value.as_str()).unwrap();
+		assert_eq!(id.name(), "carol");
+
+		// another key, an expired token and a changed name are all rejected
+		let other = HmacAuthenticator::new(b"other key").unwrap();
+		assert!(authenticate(&other, "/", value.as_str()).is_err());
+		let expired = hmac.issue("carol", getmicros!() as u64 - 1).unwrap();
+		let mut value = StringBuilder::new();
+		value.push_strs(&["Bearer ", expired.to_str()]).unwrap();
+		assert!(authenticate(&hmac, "/", value.as_str()).is_err());
+		let mut value = StringBuilder::new();
+		value
+			.push_strs(&["Bearer carox", &token.to_str()[5..]])
+			.unwrap();
+		assert!(authenticate(&hmac, "/", value.as_str()).is_err());
+		assert!(authenticate(&hmac, "/", "Bearer carol.1.zz").is_err());
+	}
+
+	#[test]
+	fn test_schnorr_auth() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let keypair = Keypair::generate(&secp, &rand).unwrap();
+		let stranger = Keypair::generate(&secp, &rand).unwrap();
+		let aux = [7u8; 32];
+
+		let mut auth = SchnorrAuthenticator::new(5_000_000).unwrap();
+		auth.allow(keypair.public, "dave").unwrap();
+		let now = getmicros!() as u64;
+		let value =
+			SchnorrAuthenticator::authorization(&secp, &keypair, "/feed", now, &aux).unwrap();
+		let id = authenticate(&auth, "/feed", value.to_str()).unwrap();
+		assert_eq!(id.name(), "dave");
+
+		// signed for another uri, too old, or by a key which is not allowed
+		assert!(authenticate(&auth, "/other", value.to_str()).is_err());
+		let old =
+			SchnorrAuthenticator::authorization(&secp, &keypair, "/feed", now - 10_000_000, &aux)
+				.unwrap();
+		assert!(authenticate(&auth, "/feed", old.to_str()).is_err());
+		let value =
+			SchnorrAuthenticator::authorization(&secp, &stranger, "/feed", now, &aux).unwrap();
+		assert!(authenticate(&auth, "/feed", value.to_str()).is_err());
+		assert!(authenticate(&auth, "/feed", "Schnorr 00.1.00").is_err());
+	}
+
+	#[test]
+	fn test_ws_auth() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let mut tokens = TokenAuthenticator::new();
+		tokens.add("letmein", "erin").unwrap();
+		server
+			.register_authenticator(Box::new(tokens).unwrap())
+			.unwrap();
+		// replies with the name the connection was authenticated as
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, mut resp: WsResponse| {
+				let name = match resp.identity() {
+					Some(identity) => String::new(identity.name()).unwrap(),
+					None => String::new("none").unwrap(),
+				};
+				resp.send(name.to_str())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let reply = Arc::new(Mutex::new(String::empty())).unwrap();
+		let reply_clone = reply.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() == 0x1 {
+					*reply.lock() = String::from_utf8(req.msg()).unwrap();
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		client.start().unwrap();
+		let config = WsClientConfig::new(addr, port);
+		assert!(client.add_client_auth(config, "Bearer a\r\nX: b").is_err());
+
+		let mut resp = client.add_client_auth(config, "Bearer letmein").unwrap();
+		resp.send("who").unwrap();
+		let mut replied = false;
+		for _i in 0..5_000 {
+			if reply_clone.lock().to_str() == "erin" {
+				replied = true;
+				break;
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+		assert!(replied);
+
+		// wrong and missing credentials are turned away before the handler
+		let bad = client.add_client_auth(config, "Bearer guess").unwrap();
+		let none = client.add_client(config).unwrap();
+		let mut closed = false;
+		for _i in 0..5_000 {
+			if bad.is_closed() && none.is_closed() {
+				closed = true;
+				break;
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+		assert!(closed);
+		assert_eq!(server.auth_failures(), 2);
+		assert!(server
+			.debug_dump()
+			.unwrap()
+			.find("auth_failures=2")
+			.is_some());
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+}
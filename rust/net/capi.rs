@@ -0,0 +1,373 @@
+//! # C embedding API
+//! Exports the WebSocket server to C and C++ hosts. The declarations are in c/fam_ws.h.
+//!
+//! Ownership rules:
+//! * fam_ws_create returns a server that the host owns until it passes it to fam_ws_destroy.
+//! * The ctx pointer given to fam_ws_set_handler is never read, written or freed by this
+//!   library, only handed back to the callback. The host keeps it valid until
+//!   fam_ws_destroy returns, after which the callback is never called again.
+//! * The conn pointer passed to the callback is borrowed and only valid until the callback
+//!   returns. fam_ws_conn_retain returns an owned copy that stays valid until it is given
+//!   to fam_ws_conn_release, even after the connection closes or the server is destroyed.
+//! * msg is only valid for the duration of the callback.
+//! * The callback runs on the server's worker threads, possibly on several at once, so
+//!   anything it shares through ctx must be synchronized by the host.
+//!
+//! Every function taking a pointer is unsafe: a null ws or conn is rejected, but any other
+//! pointer must be one this library returned, or passed to the callback, and not yet
+//! released. Buffers must be readable for the length given.
+
+use core::ptr::{copy_nonoverlapping, null_mut};
+use core::result::Result as CoreResult;
+use core::slice::from_raw_parts;
+use core::str::from_utf8;
+use net::ws::*;
+use prelude::*;
+
+/// Message callback. A nonzero return closes the connection with status 1011. A null
+/// function pointer from C is None.
+pub type FamWsHandler = Option<
+	extern "C" fn(ctx: *mut u8, conn: *mut WsResponse, op: u8, msg: *const u8, len: usize) -> i32,
+>;
+
+// the host's ctx pointer, which the host synchronizes as the module documentation requires
+struct HostPtr(*mut u8);
+unsafe impl Send for HostPtr {}
+unsafe impl Sync for HostPtr {}
+
+/// Creates a server with the given number of worker threads. Returns null on failure.
+#[no_mangle]
+pub extern "C" fn fam_ws_create(threads: u64) -> *mut WebSocket {
+	if threads == 0 {
+		return null_mut();
+	}
+	let ws = match WebSocket::new(WsConfig {
+		threads,
+		..WsConfig::default()
+	}) {
+		Ok(ws) => ws,
+		Err(_e) => return null_mut(),
+	};
+	match Box::new(ws) {
+		Ok(mut b) => {
+			b.leak();
+			b.as_ptr().raw()
+		}
+		Err(_e) => null_mut(),
+	}
+}
+
+/// Installs handler, replacing any previous one. May be called before or after fam_ws_start.
+/// A null handler is rejected.
+///
+/// # Safety
+/// ws must be null or a live server from fam_ws_create. ctx must stay valid as described
+/// in the module documentation.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_set_handler(
+	ws: *mut WebSocket,
+	handler: FamWsHandler,
+	ctx: *mut u8,
+) -> i32 {
+	let handler = match handler {
+		Some(handler) => handler,
+		None => return -1,
+	};
+	if ws.is_null() {
+		return -1;
+	}
+	let ctx = HostPtr(ctx);
+	let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+		match Box::new(move |req: WsRequest, mut resp: WsResponse| {
+			let msg = req.msg();
+			let conn = &mut resp as *mut WsResponse;
+			if handler(ctx.0, conn, req.op(), msg.as_ptr(), msg.len()) != 0 {
+				resp.close(1011);
+			}
+			Ok(())
+		}) {
+			Ok(b) => b,
+			Err(_e) => return -1,
+		};
+	match (*ws).register_handler(b) {
+		Ok(_) => 0,
+		Err(_e) => -1,
+	}
+}
+
+/// Starts the worker threads
+///
+/// # Safety
+/// ws must be null or a live server from fam_ws_create.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_start(ws: *mut WebSocket) -> i32 {
+	if ws.is_null() {
+		return -1;
+	}
+	match (*ws).start() {
+		Ok(_) => 0,
+		Err(_e) => -1,
+	}
+}
+
+/// Listens on the IPv4 address addr (4 bytes, network order) and port, 0 for an ephemeral
+/// port. Must be called after fam_ws_start. Returns the bound port or -1.
+///
+/// # Safety
+/// ws must be null or a live server from fam_ws_create, and addr null or readable for 4
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_add_server(
+	ws: *mut WebSocket,
+	addr: *const u8,
+	port: u16,
+	backlog: i32,
+) -> i32 {
+	if ws.is_null() || addr.is_null() {
+		return -1;
+	}
+	let mut a = [0u8; 4];
+	copy_nonoverlapping(addr, a.as_mut_ptr(), 4);
+	match (*ws).add_server(WsServerConfig::new(a, port, backlog)) {
+		Ok(port) => port as i32,
+		Err(_e) => -1,
+	}
+}
+
+/// Sends len bytes of msg on conn as a binary frame, or as a text frame if binary is 0 in
+/// which case msg must be valid UTF-8.
+///
+/// # Safety
+/// conn must be null, the conn passed to a running callback or one from fam_ws_conn_retain
+/// not yet released. msg must be readable for len bytes unless len is 0.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_send(
+	conn: *mut WsResponse,
+	msg: *const u8,
+	len: usize,
+	binary: i32,
+) -> i32 {
+	if conn.is_null() || (msg.is_null() && len != 0) {
+		return -1;
+	}
+	let bytes = if len == 0 {
+		&[]
+	} else {
+		from_raw_parts(msg, len)
+	};
+	let res = if binary != 0 {
+		(*conn).sendb(bytes)
+	} else {
+		match from_utf8(bytes) {
+			CoreResult::Ok(s) => (*conn).send(s),
+			CoreResult::Err(_e) => return -1,
+		}
+	};
+	match res {
+		Ok(_) => 0,
+		Err(_e) => -1,
+	}
+}
+
+/// Closes conn with the given status code. Returns -1 without closing if status is not
+/// one an endpoint may send.
+///
+/// # Safety
+/// conn must be null, the conn passed to a running callback or one from fam_ws_conn_retain
+/// not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_close(conn: *mut WsResponse, status: u16) -> i32 {
+	if conn.is_null() || !valid_close_code(status) {
+		return -1;
+	}
+	(*conn).close(status);
+	0
+}
+
+/// Returns an owned copy of conn that outlives the callback, or null on failure
+///
+/// # Safety
+/// conn must be null, the conn passed to a running callback or one from fam_ws_conn_retain
+/// not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_conn_retain(conn: *mut WsResponse) -> *mut WsResponse {
+	if conn.is_null() {
+		return null_mut();
+	}
+	let resp = match (*conn).clone() {
+		Ok(resp) => resp,
+		Err(_e) => return null_mut(),
+	};
+	match Box::new(resp) {
+		Ok(mut b) => {
+			b.leak();
+			b.as_ptr().raw()
+		}
+		Err(_e) => null_mut(),
+	}
+}
+
+/// Releases a copy returned by fam_ws_conn_retain
+///
+/// # Safety
+/// conn must be null or from fam_ws_conn_retain and not already released.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_conn_release(conn: *mut WsResponse) {
+	if !conn.is_null() {
+		let _b = Box::from_raw(Ptr::new(conn));
+	}
+}
+
+/// Stops the worker threads and joins them
+///
+/// # Safety
+/// ws must be null or a live server from fam_ws_create.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_stop(ws: *mut WebSocket) -> i32 {
+	if ws.is_null() {
+		return -1;
+	}
+	match (*ws).stop() {
+		Ok(_) => 0,
+		Err(_e) => -1,
+	}
+}
+
+/// Stops the server if it is still running and frees it
+///
+/// # Safety
+/// ws must be null or a live server from fam_ws_create, and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn fam_ws_destroy(ws: *mut WebSocket) {
+	if !ws.is_null() {
+		let mut b = Box::from_raw(Ptr::new(ws));
+		let _ = b.stop();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::sleep_millis;
+
+	struct HostCtx {
+		count: u64,
+		retained: *mut WsResponse,
+	}
+
+	extern "C" fn echo(
+		ctx: *mut u8,
+		conn: *mut WsResponse,
+		op: u8,
+		msg: *const u8,
+		len: usize,
+	) -> i32 {
+		let ctx = ctx as *mut HostCtx;
+		let bytes = unsafe { from_raw_parts(msg, len) };
+		if op != 0x1 && op != 0x2 {
+			return 0;
+		}
+		if bytes == b"fail" {
+			return 1;
+		}
+		unsafe {
+			if (*ctx).retained.is_null() {
+				(*ctx).retained = fam_ws_conn_retain(conn);
+			}
+		}
+		aadd!(&mut (*ctx).count, 1);
+		unsafe { fam_ws_send(conn, msg, len, (op == 0x2) as i32) }
+	}
+
+	#[test]
+	fn test_capi() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(fam_ws_create(0).is_null());
+		unsafe {
+			assert_eq!(fam_ws_start(null_mut()), -1);
+			assert_eq!(fam_ws_send(null_mut(), null_mut(), 0, 1), -1);
+			assert_eq!(fam_ws_close(null_mut(), 1000), -1);
+		}
+
+		let mut host = HostCtx {
+			count: 0,
+			retained: null_mut(),
+		};
+		let ws = fam_ws_create(1);
+		assert!(!ws.is_null());
+		let ctx = &mut host as *mut HostCtx as *mut u8;
+		unsafe {
+			// a null function pointer
+			assert_eq!(fam_ws_set_handler(ws, None, ctx), -1);
+			assert_eq!(fam_ws_set_handler(ws, Some(echo), ctx), 0);
+			assert_eq!(fam_ws_start(ws), 0);
+		}
+		let addr = [127u8, 0, 0, 1];
+		let port = unsafe { fam_ws_add_server(ws, addr.as_ptr(), 0, 10) };
+		assert!(port > 0);
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let replies = TestCounts::new().unwrap();
+		let replies_clone = replies.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() == 0x1 || req.op() == 0x2 {
+					replies_clone.add(0);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		client.start().unwrap();
+		let mut resp = client
+			.add_client(WsClientConfig::new(addr, port as u16))
+			.unwrap();
+
+		// echoed from inside the callback
+		resp.send("hello").unwrap();
+		assert!(replies.wait(0, 1));
+		// counted before the echo was sent
+		assert_eq!(aload!(&host.count), 1);
+
+		// the retained connection can be used after the callback has returned
+		let retained = host.retained;
+		assert!(!retained.is_null());
+		assert_eq!(unsafe { fam_ws_send(retained, b"later".as_ptr(), 5, 1) }, 0);
+		assert!(replies.wait(0, 2));
+		unsafe {
+			assert_eq!(fam_ws_send(retained, [0xffu8].as_ptr(), 1, 0), -1);
+			// reserved for use inside the protocol, so never sent
+			assert_eq!(fam_ws_close(retained, 1005), -1);
+			assert_eq!(fam_ws_close(retained, 999), -1);
+		}
+		assert!(!resp.is_closed());
+
+		// a failing callback closes the connection
+		resp.send("fail").unwrap();
+		let mut closed = false;
+		for _i in 0..5_000 {
+			if resp.is_closed() {
+				closed = true;
+				break;
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+		assert!(closed);
+		assert_eq!(aload!(&host.count), 1);
+
+		unsafe {
+			fam_ws_conn_release(retained);
+			assert_eq!(fam_ws_stop(ws), 0);
+			assert_eq!(fam_ws_stop(ws), 0);
+			fam_ws_destroy(ws);
+		}
+		assert!(client.stop().is_ok());
+	}
+}
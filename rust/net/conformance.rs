@@ -0,0 +1,249 @@
+//! # Conformance
+//! A checklist of the RFC 6455 rules a strict server enforces, in the spirit of the
+//! Autobahn test suite. Each case speaks the protocol over a plain socket, sends frames a
+//! conforming client never would and checks the status of the close which fails the
+//! connection: 1002 for a protocol error, 1007 for text which is not UTF-8 and 1009 for a
+//! message over WsConfig::max_message_bytes. Every frame is masked with a zero key so that
+//! the payloads can be written as they go over the wire.
+
+use net::selfcheck::echo_handler;
+use net::tcp::TcpStream;
+use net::ws::*;
+use prelude::*;
+
+const MAX_MESSAGE_BYTES: u64 = 1024;
+const PROTOCOL_ERROR: &[u8] = b"\x88\x02\x03\xEA";
+const INVALID_PAYLOAD: &[u8] = b"\x88\x02\x03\xEF";
+const MESSAGE_TOO_BIG: &[u8] = b"\x88\x02\x03\xF1";
+
+fn start_server() -> (WebSocket, u16) {
+	let mut ws = WebSocket::new(WsConfig {
+		threads: 1,
+		strict: true,
+		max_message_bytes: MAX_MESSAGE_BYTES,
+		..WsConfig::default()
+	})
+	.unwrap();
+	ws.register_handler(echo_handler().unwrap()).unwrap();
+	ws.start().unwrap();
+	let port = ws
+		.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+		.unwrap();
+	(ws, port)
+}
+
+fn connect(port: u16) -> TcpStream {
+	let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+	stream.set_nonblocking(false).unwrap();
+	let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+	assert_eq!(stream.write(request).unwrap(), request.len());
+	let mut buf = [0u8; 512];
+	let mut offset = 0;
+	while offset < 4 || &buf[offset - 4..offset] != b"\r\n\r\n" {
+		offset += stream.read(&mut buf[offset..offset + 1]).unwrap();
+	}
+	stream
+}
+
+// a frame masked with a zero key
+fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::new();
+	let len = payload.len();
+	if len < 126 {
+		ret.push(first).unwrap();
+		ret.push(0x80 | len as u8).unwrap();
+	} else {
+		ret.push(first).unwrap();
+		ret.push(0x80 | 126).unwrap();
+		ret.push((len >> 8) as u8).unwrap();
+		ret.push(len as u8).unwrap();
+	}
+	ret.append_ptr([0u8; 4].as_ptr(), 4).unwrap();
+	ret.append_ptr(payload.as_ptr(), len).unwrap();
+	ret
+}
+
+fn read_until_closed(stream: &TcpStream) -> Vec<u8> {
+	let mut ret = Vec::new();
+	let mut buf = [0u8; 256];
+	loop {
+		match stream.read(&mut buf) {
+			Ok(0) | Err(_) => break,
+			Ok(n) => ret.append_ptr(buf.as_ptr(), n).unwrap(),
+		}
+	}
+	ret
+}
+
+// sends frames on a new connection and returns all that came back before it was closed
+fn exchange(port: u16, frames: &[&[u8]]) -> Vec<u8> {
+	let stream = connect(port);
+	for f in frames {
+		assert_eq!(stream.write(f).unwrap(), f.len());
+	}
+	read_until_closed(&stream)
+}
+
+#[test]
+fn test_conformance_protocol_errors() {
+	let _alloc = AllocGuard::new();
+	let _fds = FdGuard::new();
+	let (mut ws, port) = start_server();
+
+	// a client frame must be masked
+	let resp = exchange(port, &[b"\x81\x02hi"]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// no extension is negotiated to give the reserved bits a meaning
+	let resp = exchange(port, &[frame(0xC1, b"hi").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// reserved data and control opcodes
+	let resp = exchange(port, &[frame(0x83, b"hi").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+	let resp = exchange(port, &[frame(0x8B, b"").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// control frames are neither fragmented nor longer than 125 bytes
+	let resp = exchange(port, &[frame(0x09, b"hi").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+	let resp = exchange(port, &[frame(0x89, &[b'x'; 126]).as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// a continuation without a message to continue
+	let resp = exchange(port, &[frame(0x80, b"hi").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// a new message before the last was finished
+	let first = frame(0x01, b"hel");
+	let second = frame(0x81, b"lo");
+	let resp = exchange(port, &[first.as_slice(), second.as_slice()]);
+	assert!(resp.as_slice().starts_with(b"\x01\x03hel"));
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	// 1005 is reserved for a close without a status and may not be sent
+	let resp = exchange(port, &[frame(0x88, b"\x03\xED").as_slice()]);
+	assert!(resp.as_slice().ends_with(PROTOCOL_ERROR));
+
+	ws.stop().unwrap();
+}
+
+#[test]
+fn test_conformance_invalid_payload() {
+	let _alloc = AllocGuard::new();
+	let _fds = FdGuard::new();
+	let (mut ws, port) = start_server();
+
+	// a lone continuation byte
+	let resp = exchange(port, &[frame(0x81, b"h\x80i").as_slice()]);
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+
+	// overlong encodings and surrogates
+	let resp = exchange(port, &[frame(0x81, b"\xC0\xAF").as_slice()]);
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+	let resp = exchange(port, &[frame(0x81, b"\xED\xA0\x80").as_slice()]);
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+
+	// found in the fragment where it goes wrong, before the message is finished
+	let first = frame(0x01, b"ok");
+	let second = frame(0x00, b"\xF5");
+	let resp = exchange(port, &[first.as_slice(), second.as_slice()]);
+	assert!(resp.as_slice().starts_with(b"\x01\x02ok"));
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+
+	// a message which ends part way through a code point
+	let first = frame(0x01, b"ok");
+	let second = frame(0x80, b"\xE2\x82");
+	let resp = exchange(port, &[first.as_slice(), second.as_slice()]);
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+
+	// the reason of a close is text too
+	let resp = exchange(port, &[frame(0x88, b"\x03\xE8\xFF").as_slice()]);
+	assert!(resp.as_slice().ends_with(INVALID_PAYLOAD));
+
+	// binary messages are not checked
+	let binary = frame(0x82, b"\xFF\xFE");
+	let close = frame(0x88, b"\x03\xE8");
+	let resp = exchange(port, &[binary.as_slice(), close.as_slice()]);
+	assert!(resp.as_slice().starts_with(b"\x82\x02\xFF\xFE"));
+	assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xE8"));
+
+	ws.stop().unwrap();
+}
+
+#[test]
+fn test_conformance_message_too_big() {
+	let _alloc = AllocGuard::new();
+	let _fds = FdGuard::new();
+	let (mut ws, port) = start_server();
+
+	// refused on its header, without the payload being sent
+	let mut header = frame(0x82, &[]);
+	header[1] = 0x80 | 126;
+	header
+		.insert(2, ((MAX_MESSAGE_BYTES + 1) >> 8) as u8)
+		.unwrap();
+	header.insert(3, (MAX_MESSAGE_BYTES + 1) as u8).unwrap();
+	let resp = exchange(port, &[header.as_slice()]);
+	assert!(resp.as_slice().ends_with(MESSAGE_TOO_BIG));
+
+	// or once the fragments so far add up to more than the maximum
+	let half = [b'x'; MAX_MESSAGE_BYTES as usize / 2];
+	let first = frame(0x02, &half);
+	let second = frame(0x00, &half);
+	let third = frame(0x80, b"x");
+	let resp = exchange(
+		port,
+		&[first.as_slice(), second.as_slice(), third.as_slice()],
+	);
+	assert!(resp.as_slice().ends_with(MESSAGE_TOO_BIG));
+
+	// a message of exactly the maximum is fine
+	let full = [b'x'; MAX_MESSAGE_BYTES as usize];
+	let message = frame(0x82, &full);
+	let close = frame(0x88, b"\x03\xE8");
+	let resp = exchange(port, &[message.as_slice(), close.as_slice()]);
+	assert_eq!(resp.len(), 4 + MAX_MESSAGE_BYTES as usize + 4);
+	assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xE8"));
+
+	ws.stop().unwrap();
+}
+
+#[test]
+fn test_conformance_valid() {
+	let _alloc = AllocGuard::new();
+	let _fds = FdGuard::new();
+	let (mut ws, port) = start_server();
+
+	// a fragmented message with a ping between its fragments, and a code point split
+	// across them
+	let first = frame(0x01, b"caf\xC3");
+	let ping = frame(0x89, b"p");
+	let second = frame(0x00, b"\xA9 ");
+	let third = frame(0x80, "\u{1F600}".as_bytes());
+	let close = frame(0x88, b"\x03\xE8");
+	let resp = exchange(
+		port,
+		&[
+			first.as_slice(),
+			ping.as_slice(),
+			second.as_slice(),
+			third.as_slice(),
+			close.as_slice(),
+		],
+	);
+	let mut expected = Vec::new();
+	let parts: [&[u8]; 5] = [
+		b"\x01\x04caf\xC3",
+		b"\x8A\x01p",
+		b"\x00\x02\xA9 ",
+		b"\x80\x04\xF0\x9F\x98\x80",
+		b"\x88\x02\x03\xE8",
+	];
+	for part in &parts {
+		expected.append_ptr(part.as_ptr(), part.len()).unwrap();
+	}
+	assert_eq!(resp.as_slice(), expected.as_slice());
+
+	ws.stop().unwrap();
+}
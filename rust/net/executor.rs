@@ -0,0 +1,634 @@
+//! # Executor
+//! A single threaded executor for futures, state machines which are polled until they
+//! complete. A future which cannot go on registers what it is waiting for through its
+//! Context, a socket becoming readable or writable or a point in time, and returns Pending.
+//! The executor polls it again once one of those has happened and waits on its socket
+//! multiplexer in between. A protocol exchange such as a handshake followed by a request
+//! and its response is then written as one future which moves from state to state, instead
+//! of as callbacks which each pick up where the last left off.
+//!
+//! A task is polled again after any of its registrations fires and is not told which, so
+//! a future must check that it can make progress, a read may still find nothing. What a
+//! task waits for is cleared each time it is polled. A socket can only be waited on by one
+//! task at a time. Deadlines are kept with their task and found by a scan of all tasks,
+//! which suits the few long lived tasks this is meant for.
+
+use core::mem::{replace, swap};
+use ffi::getmicros;
+use net::reactor::{Events, Reactor, READ, WAKE_TOKEN, WRITE};
+use prelude::*;
+
+// events taken from the multiplexer per wait
+const MAX_EVENTS: usize = 32;
+
+/// Result of polling a future
+pub enum Poll<T> {
+	Ready(T),
+	Pending,
+}
+
+/// A computation which completes over any number of polls
+pub trait Future {
+	type Output;
+	/// Makes as much progress as possible. Before returning Pending the future registers
+	/// through cx what has to happen for it to continue. A task which returns Pending
+	/// without registering anything is never polled again.
+	fn poll(&mut self, cx: &mut Context) -> Poll<Self::Output>;
+}
+
+/// Handed to a future while it is polled
+pub struct Context<'a> {
+	exec: &'a mut Executor,
+	task: usize,
+	waits: &'a mut Waits,
+}
+
+/// Future built from a closure which is called each time it is polled
+pub struct PollFn<F> {
+	f: F,
+}
+
+/// Completes once getmicros has reached its deadline
+pub struct Sleep {
+	deadline: u64,
+}
+
+pub struct Executor {
+	reactor: Reactor,
+	events: Events,
+	// indexed by task id, None for ids which are free or whose task is being polled
+	slots: Vec<Option<Slot>>,
+	free: Vec<usize>,
+	ready: Vec<usize>,
+	// the tasks being polled this turn, swapped with ready
+	polling: Vec<usize>,
+	now: u64,
+}
+
+// what a task is waiting for
+struct Waits {
+	sockets: Vec<([u8; 4], i32)>,
+	// getmicros value to poll the task again at, 0 for none
+	deadline: u64,
+}
+
+struct Slot {
+	future: Box<dyn Future<Output = ()>>,
+	waits: Waits,
+	// on the ready list
+	queued: bool,
+}
+
+impl<'a> Context<'a> {
+	/// getmicros when this turn started polling the ready tasks
+	pub fn now(&self) -> u64 {
+		self.exec.now
+	}
+
+	/// Polls the task again on the next turn
+	pub fn wake(&mut self) {
+		let now = self.exec.now;
+		self.wake_at(now);
+	}
+
+	/// Polls the task again once getmicros has reached micros. Of several deadlines the
+	/// earliest is kept.
+	pub fn wake_at(&mut self, micros: u64) {
+		let micros = if micros == 0 { 1 } else { micros };
+		if self.waits.deadline == 0 || micros < self.waits.deadline {
+			self.waits.deadline = micros;
+		}
+	}
+
+	/// Polls the task again once the socket has data to read or has been closed
+	pub fn readable(&mut self, handle: [u8; 4]) -> Result<(), Error> {
+		self.register(handle, READ)
+	}
+
+	/// Polls the task again once the socket can take more data
+	pub fn writable(&mut self, handle: [u8; 4]) -> Result<(), Error> {
+		self.register(handle, WRITE)
+	}
+
+	/// Adds a task, which is first polled on the next turn
+	pub fn spawn<F>(&mut self, future: F) -> Result<(), Error>
+	where
+		F: Future<Output = ()> + 'static,
+	{
+		self.exec.spawn(future)
+	}
+
+	fn register(&mut self, handle: [u8; 4], flag: i32) -> Result<(), Error> {
+		let mut flags = flag;
+		let mut index = self.waits.sockets.len();
+		for i in 0..self.waits.sockets.len() {
+			if self.waits.sockets[i].0 == handle {
+				flags |= self.waits.sockets[i].1;
+				index = i;
+			}
+		}
+		// the token is the task id plus one, since the reactor does not take 0
+		match self.exec.reactor.register(handle, flags, self.task + 1) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if index < self.waits.sockets.len() {
+			self.waits.sockets[index].1 = flags;
+			Ok(())
+		} else {
+			self.waits.sockets.push((handle, flags))
+		}
+	}
+}
+
+/// Returns a future which calls f each time it is polled, for state machines too small to
+/// be worth a type of their own
+pub fn poll_fn<T, F>(f: F) -> PollFn<F>
+where
+	F: FnMut(&mut Context) -> Poll<T>,
+{
+	PollFn { f }
+}
+
+impl<T, F> Future for PollFn<F>
+where
+	F: FnMut(&mut Context) -> Poll<T>,
+{
+	type Output = T;
+	fn poll(&mut self, cx: &mut Context) -> Poll<T> {
+		(self.f)(cx)
+	}
+}
+
+impl Sleep {
+	/// Completes micros after it is created
+	pub fn new(micros: u64) -> Self {
+		Self {
+			deadline: now_micros() + micros,
+		}
+	}
+
+	/// Completes once getmicros has reached deadline
+	pub fn until(deadline: u64) -> Self {
+		Self { deadline }
+	}
+}
+
+impl Future for Sleep {
+	type Output = ();
+	fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+		if now_micros() >= self.deadline {
+			Poll::Ready(())
+		} else {
+			cx.wake_at(self.deadline);
+			Poll::Pending
+		}
+	}
+}
+
+impl Executor {
+	pub fn new() -> Result<Self, Error> {
+		let events = match Events::new(MAX_EVENTS) {
+			Ok(events) => events,
+			Err(e) => return Err(e),
+		};
+		// the tasks' registrations go with the reactor when the executor is dropped
+		let reactor = match Reactor::new() {
+			Ok(reactor) => reactor,
+			Err(e) => return Err(e),
+		};
+		Ok(Self {
+			reactor,
+			events,
+			slots: Vec::new(),
+			free: Vec::new(),
+			ready: Vec::new(),
+			polling: Vec::new(),
+			now: now_micros(),
+		})
+	}
+
+	/// Adds a task, which is first polled on the next turn
+	pub fn spawn<F>(&mut self, future: F) -> Result<(), Error>
+	where
+		F: Future<Output = ()> + 'static,
+	{
+		let future = match Box::new(future) {
+			Ok(future) => future,
+			Err(e) => return Err(e),
+		};
+		let slot = Slot {
+			future,
+			waits: Waits {
+				sockets: Vec::new(),
+				deadline: 0,
+			},
+			queued: true,
+		};
+		let id = match self.free.pop() {
+			Some(id) => {
+				self.slots[id] = Some(slot);
+				id
+			}
+			None => match self.slots.push(Some(slot)) {
+				Ok(_) => self.slots.len() - 1,
+				Err(e) => return Err(e),
+			},
+		};
+		match self.ready.push(id) {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				self.slots[id] = None;
+				let _ = self.free.push(id);
+				Err(e)
+			}
+		}
+	}
+
+	/// Runs the tasks until future has completed and returns its output. Fails with
+	/// IllegalState if it is left waiting for nothing, so that it can never complete.
+	pub fn block_on<F>(&mut self, future: F) -> Result<F::Output, Error>
+	where
+		F: Future + 'static,
+	{
+		let mut out = match Rc::new(None) {
+			Ok(out) => out,
+			Err(e) => return Err(e),
+		};
+		// SAFETY: clone always succeeds on rc
+		let mut out_clone = out.clone().unwrap();
+		let mut future = future;
+		match self.spawn(poll_fn(move |cx: &mut Context| match future.poll(cx) {
+			Poll::Ready(v) => {
+				*out_clone = Some(v);
+				Poll::Ready(())
+			}
+			Poll::Pending => Poll::Pending,
+		})) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		loop {
+			let more = match self.turn() {
+				Ok(more) => more,
+				Err(e) => return Err(e),
+			};
+			match replace(&mut *out, None) {
+				Some(v) => return Ok(v),
+				None => {}
+			}
+			if !more {
+				return Err(err!(IllegalState));
+			}
+		}
+	}
+
+	/// Runs until no task is left which is ready or waiting for anything
+	pub fn run(&mut self) -> Result<(), Error> {
+		loop {
+			match self.turn() {
+				Ok(true) => {}
+				Ok(false) => return Ok(()),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Number of tasks which have not completed
+	pub fn tasks(&self) -> usize {
+		self.slots.len() - self.free.len()
+	}
+
+	/// Waits for the next socket event or deadline, without waiting while a task is ready,
+	/// then polls the tasks which are ready. Returns false, without waiting, once no task
+	/// is ready or waiting for anything.
+	pub fn turn(&mut self) -> Result<bool, Error> {
+		let mut deadline = 0;
+		let mut sockets = false;
+		for i in 0..self.slots.len() {
+			match &self.slots[i] {
+				Some(slot) => {
+					if slot.waits.deadline != 0 && (deadline == 0 || slot.waits.deadline < deadline)
+					{
+						deadline = slot.waits.deadline;
+					}
+					if slot.waits.sockets.len() > 0 {
+						sockets = true;
+					}
+				}
+				None => {}
+			}
+		}
+		if self.ready.len() == 0 && deadline == 0 && !sockets {
+			return Ok(false);
+		}
+
+		let now = now_micros();
+		let timeout = if self.ready.len() > 0 || (deadline != 0 && deadline <= now) {
+			0
+		} else if deadline == 0 {
+			-1
+		} else {
+			// rounded up so that the deadline has passed when the wait times out
+			((deadline - now + 999) / 1000) as i64
+		};
+		let count = match self.reactor.poll(&mut self.events, timeout) {
+			Ok(count) => count,
+			Err(e) => return Err(e),
+		};
+		for i in 0..count {
+			let token = self.events.get(i).token();
+			if token != WAKE_TOKEN {
+				self.wake(token - 1);
+			}
+		}
+
+		self.now = now_micros();
+		for i in 0..self.slots.len() {
+			let expired = match &self.slots[i] {
+				Some(slot) => slot.waits.deadline != 0 && slot.waits.deadline <= self.now,
+				None => false,
+			};
+			if expired {
+				self.wake(i);
+			}
+		}
+
+		swap(&mut self.ready, &mut self.polling);
+		for i in 0..self.polling.len() {
+			let id = self.polling[i];
+			self.poll(id);
+		}
+		self.polling.truncate(0);
+		Ok(true)
+	}
+
+	fn poll(&mut self, id: usize) {
+		let mut slot = match replace(&mut self.slots[id], None) {
+			Some(slot) => slot,
+			None => return,
+		};
+		slot.queued = false;
+		let done = {
+			let mut cx = Context {
+				exec: self,
+				task: id,
+				waits: &mut slot.waits,
+			};
+			match slot.future.poll(&mut cx) {
+				Poll::Ready(_) => true,
+				Poll::Pending => false,
+			}
+		};
+		if done {
+			Self::clear(&self.reactor, &mut slot.waits);
+			// on allocation failure the id is not reused
+			let _ = self.free.push(id);
+		} else {
+			self.slots[id] = Some(slot);
+		}
+	}
+
+	fn wake(&mut self, id: usize) {
+		if id >= self.slots.len() {
+			return;
+		}
+		match &mut self.slots[id] {
+			Some(slot) => {
+				if slot.queued {
+					return;
+				}
+				Self::clear(&self.reactor, &mut slot.waits);
+				match self.ready.push(id) {
+					Ok(_) => slot.queued = true,
+					// retried on the next turn as an expired deadline
+					Err(_e) => slot.waits.deadline = 1,
+				}
+			}
+			None => {}
+		}
+	}
+
+	// drops everything the task was waiting for
+	fn clear(reactor: &Reactor, waits: &mut Waits) {
+		for i in 0..waits.sockets.len() {
+			// the socket may already have been closed, which deregistered it
+			let _ = reactor.deregister(waits.sockets[i].0);
+		}
+		waits.sockets.truncate(0);
+		waits.deadline = 0;
+	}
+}
+
+fn now_micros() -> u64 {
+	unsafe { getmicros() as u64 }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::{socket_close, socket_pair, socket_recv, socket_send};
+
+	// answers every 4 byte request with its bytes reversed until the peer closes
+	struct Responder {
+		handle: [u8; 4],
+		buf: [u8; 4],
+		pos: usize,
+		writing: bool,
+	}
+
+	impl Future for Responder {
+		type Output = ();
+		fn poll(&mut self, cx: &mut Context) -> Poll<()> {
+			loop {
+				if self.writing {
+					let n = unsafe {
+						socket_send(
+							&self.handle as *const u8,
+							self.buf.as_ptr().add(self.pos),
+							4 - self.pos,
+						)
+					};
+					if n < 0 {
+						cx.writable(self.handle).unwrap();
+						return Poll::Pending;
+					}
+					self.pos += n as usize;
+					if self.pos == 4 {
+						self.pos = 0;
+						self.writing = false;
+					}
+				} else {
+					let n = unsafe {
+						socket_recv(
+							&self.handle as *const u8,
+							self.buf.as_mut_ptr().add(self.pos),
+							4 - self.pos,
+						)
+					};
+					if n == 0 {
+						return Poll::Ready(());
+					}
+					if n < 0 {
+						cx.readable(self.handle).unwrap();
+						return Poll::Pending;
+					}
+					self.pos += n as usize;
+					if self.pos == 4 {
+						self.buf.reverse();
+						self.pos = 0;
+						self.writing = true;
+					}
+				}
+			}
+		}
+	}
+
+	// sends msg, then waits for a 4 byte reply until the deadline
+	struct Request {
+		handle: [u8; 4],
+		msg: &'static [u8],
+		sent: bool,
+		reply: [u8; 4],
+		pos: usize,
+		deadline: u64,
+	}
+
+	impl Request {
+		fn new(handle: [u8; 4], msg: &'static [u8], micros: u64) -> Self {
+			Self {
+				handle,
+				msg,
+				sent: false,
+				reply: [0; 4],
+				pos: 0,
+				deadline: now_micros() + micros,
+			}
+		}
+	}
+
+	impl Future for Request {
+		type Output = Result<[u8; 4], Error>;
+		fn poll(&mut self, cx: &mut Context) -> Poll<Self::Output> {
+			if !self.sent {
+				let n = unsafe {
+					socket_send(&self.handle as *const u8, self.msg.as_ptr(), self.msg.len())
+				};
+				assert_eq!(n, self.msg.len() as i64);
+				self.sent = true;
+			}
+			while self.pos < 4 {
+				let n = unsafe {
+					socket_recv(
+						&self.handle as *const u8,
+						self.reply.as_mut_ptr().add(self.pos),
+						4 - self.pos,
+					)
+				};
+				if n <= 0 {
+					break;
+				}
+				self.pos += n as usize;
+			}
+			if self.pos == 4 {
+				Poll::Ready(Ok(self.reply))
+			} else if now_micros() >= self.deadline {
+				Poll::Ready(Err(err!(Timeout)))
+			} else {
+				cx.readable(self.handle).unwrap();
+				cx.wake_at(self.deadline);
+				Poll::Pending
+			}
+		}
+	}
+
+	#[test]
+	fn test_executor_timers() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut exec = Executor::new().unwrap();
+		let order = Rc::new(Vec::new()).unwrap();
+		for i in 0..3 {
+			let mut order = order.clone().unwrap();
+			let mut sleep = Sleep::new((3 - i) * 10_000);
+			exec.spawn(poll_fn(move |cx: &mut Context| match sleep.poll(cx) {
+				Poll::Ready(_) => {
+					order.push(i).unwrap();
+					Poll::Ready(())
+				}
+				Poll::Pending => Poll::Pending,
+			}))
+			.unwrap();
+		}
+		assert_eq!(exec.tasks(), 3);
+		let start = now_micros();
+		exec.run().unwrap();
+		assert!(now_micros() - start >= 30_000);
+		assert_eq!(order.as_slice(), &[2, 1, 0]);
+		assert_eq!(exec.tasks(), 0);
+
+		// wake polls again on the next turn
+		let mut polls = 0;
+		let ret = exec.block_on(poll_fn(move |cx: &mut Context| {
+			polls += 1;
+			if polls == 3 {
+				Poll::Ready(polls)
+			} else {
+				cx.wake();
+				Poll::Pending
+			}
+		}));
+		assert_eq!(ret.unwrap(), 3);
+
+		// a future which waits for nothing can never complete
+		let e = exec
+			.block_on(poll_fn(|_cx: &mut Context| Poll::<()>::Pending))
+			.unwrap_err();
+		assert!(e.kind == IllegalState);
+	}
+
+	#[test]
+	fn test_executor_sockets() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = [0u8; 4];
+		let mut client = [0u8; 4];
+		assert_eq!(
+			unsafe { socket_pair(&mut server as *mut u8, &mut client as *mut u8) },
+			0
+		);
+		let mut exec = Executor::new().unwrap();
+		exec.spawn(Responder {
+			handle: server,
+			buf: [0; 4],
+			pos: 0,
+			writing: false,
+		})
+		.unwrap();
+
+		let reply = exec.block_on(Request::new(client, b"ping", 10_000_000));
+		assert_eq!(&reply.unwrap().unwrap(), b"gnip");
+
+		// half a request gets no reply before the deadline, the rest completes it
+		let start = now_micros();
+		let e = exec
+			.block_on(Request::new(client, b"ab", 20_000))
+			.unwrap()
+			.unwrap_err();
+		assert!(e.kind == Timeout);
+		assert!(now_micros() - start >= 20_000);
+		let reply = exec.block_on(Request::new(client, b"cd", 10_000_000));
+		assert_eq!(&reply.unwrap().unwrap(), b"dcba");
+
+		// the responder completes once the client has closed
+		assert_eq!(exec.tasks(), 1);
+		unsafe {
+			socket_close(&client as *const u8);
+		}
+		exec.run().unwrap();
+		assert_eq!(exec.tasks(), 0);
+		unsafe {
+			socket_close(&server as *const u8);
+		}
+	}
+}
@@ -1 +1,15 @@
+pub mod auth;
+pub mod capi;
+#[cfg(test)]
+mod conformance;
+pub mod executor;
+pub mod p2p;
+pub mod pool;
+pub mod proxy;
+pub mod reactor;
+pub mod secure;
+pub mod selfcheck;
+pub mod tcp;
+pub mod transport;
+pub mod udp;
 pub mod ws;
@@ -0,0 +1,945 @@
+//! # P2P
+//! The node to node wire protocol. Peers exchange frames over TCP, each a header of the
+//! network's magic value, a message type and the payload length, all big endian, followed
+//! by the payload serialized with std::ser. A frame with the wrong magic or a payload over
+//! max_message_size ends the connection, so nodes of different networks or a peer which is
+//! not speaking the protocol are dropped at the first frame.
+//!
+//! Both sides send a hello with their protocol version as soon as they are connected. No
+//! other message is accepted before it, and a peer whose version is below min_version or
+//! which turns out to be this node is disconnected. Pings go out every ping_interval and a
+//! peer which has not answered the last one when the next is due is disconnected as well.
+//!
+//! Message types below MSG_USER belong to the protocol. Applications register a handler for
+//! each of their types, which receives the message already deserialized along with the peer
+//! to reply to. Messages of a type without a handler are ignored so that older nodes keep
+//! working alongside newer ones. A handler which fails disconnects the peer.
+//!
+//! With a secure_identity in its config a node runs every connection through a
+//! SecureStream, the side which connected taking the initiator's part of the handshake.
+//! Frames, the hello included, are queued until the handshake completes. A peer which does
+//! not complete it is disconnected, whatever key it presents is accepted and
+//! Peer::remote_public_key returns it for the application to check.
+//!
+//! P2pNode is single threaded: turn waits on its Reactor and processes what is ready.
+
+use core::mem::replace;
+use core::ops::FnMut;
+use net::reactor::{Events, Reactor, READ, WAKE_TOKEN, WRITE};
+use net::secure::{Identity, SecureStream};
+use net::tcp::{TcpListener, TcpStream};
+use prelude::*;
+use secp256k1::types::COMPRESSED_PUBLIC_KEY_SIZE;
+use std::cpsrng::Cpsrng;
+use std::ser::{deserialize, Readable, Writeable};
+
+/// Version handshake, the first message on every connection
+pub const MSG_HELLO: u8 = 0;
+pub const MSG_PING: u8 = 1;
+pub const MSG_PONG: u8 = 2;
+/// Smallest message type available to applications
+pub const MSG_USER: u8 = 16;
+
+// magic, message type and payload length
+const HEADER_SIZE: usize = 9;
+const READ_SIZE: usize = 4096;
+const MAX_EVENTS: usize = 64;
+// peer ids start above it
+const LISTENER_TOKEN: usize = 1;
+
+pub struct P2pConfig {
+	/// Identifies the network, frames with another magic are rejected
+	pub magic: u32,
+	/// Protocol version sent in the hello
+	pub version: u32,
+	/// Lowest version a peer may have
+	pub min_version: u32,
+	pub max_message_size: usize,
+	/// 0 disables pings
+	pub ping_interval_micros: i64,
+	pub backlog: i32,
+	/// Encrypt connections with a SecureStream under this identity. Peers must do the
+	/// same, those which do not are disconnected.
+	pub secure_identity: Option<Arc<Identity>>,
+}
+
+impl Default for P2pConfig {
+	fn default() -> Self {
+		Self {
+			magic: 0x4641_4d31,
+			version: 1,
+			min_version: 1,
+			max_message_size: 4 * 1024 * 1024,
+			ping_interval_micros: 30_000_000,
+			backlog: 128,
+			secure_identity: None,
+		}
+	}
+}
+
+struct Hello {
+	version: u32,
+	// random per node, a hello carrying our own nonce means we connected to ourselves
+	nonce: u64,
+}
+
+impl_ser!(Hello { version, nonce });
+
+// the socket of a peer, encrypted if the node has a secure_identity
+enum PeerStream {
+	Plain(TcpStream),
+	Secure(SecureStream),
+}
+
+impl PeerStream {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		match self {
+			PeerStream::Plain(stream) => stream.read(buf),
+			PeerStream::Secure(stream) => stream.read(buf),
+		}
+	}
+
+	fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		match self {
+			PeerStream::Plain(stream) => stream.write(buf),
+			PeerStream::Secure(stream) => stream.write(buf),
+		}
+	}
+
+	// sends what a SecureStream has encrypted or queued for the handshake
+	fn flush(&mut self) -> Result<(), Error> {
+		match self {
+			PeerStream::Plain(_) => Ok(()),
+			PeerStream::Secure(stream) => stream.flush(),
+		}
+	}
+
+	fn pending(&self) -> usize {
+		match self {
+			PeerStream::Plain(_) => 0,
+			PeerStream::Secure(stream) => stream.pending(),
+		}
+	}
+
+	fn handle(&self) -> [u8; 4] {
+		match self {
+			PeerStream::Plain(stream) => stream.handle(),
+			PeerStream::Secure(stream) => stream.handle(),
+		}
+	}
+}
+
+/// A connection to another node, handed to message handlers so they can reply
+pub struct Peer {
+	id: u64,
+	stream: PeerStream,
+	outbound: bool,
+	// negotiated version, 0 until the peer's hello has arrived
+	version: u32,
+	magic: u32,
+	max_message_size: usize,
+	rbuf: Vec<u8>,
+	wbuf: Deque<u8>,
+	write_registered: bool,
+	ping_nonce: u64,
+	ping_sent: i64,
+	rtt_micros: i64,
+	closing: bool,
+}
+
+type Handler = Box<dyn FnMut(&mut Peer, &[u8]) -> Result<(), Error>>;
+
+pub struct P2pNode {
+	config: P2pConfig,
+	reactor: Reactor,
+	events: Events,
+	listener: Option<TcpListener>,
+	peers: HashMap<u64, Peer>,
+	handlers: HashMap<u8, Handler>,
+	connect_handler: Option<Box<dyn FnMut(&mut Peer)>>,
+	disconnect_handler: Option<Box<dyn FnMut(u64)>>,
+	next_id: u64,
+	nonce: u64,
+	rand: Cpsrng,
+}
+
+impl Peer {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	/// True if this node made the connection
+	pub fn is_outbound(&self) -> bool {
+		self.outbound
+	}
+
+	/// The lower of the two nodes' versions, 0 until the handshake has completed
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	/// Round trip time of the last answered ping, 0 before the first
+	pub fn rtt_micros(&self) -> i64 {
+		self.rtt_micros
+	}
+
+	/// The peer's static key once the secure handshake has completed, None before and on
+	/// nodes without a secure_identity
+	pub fn remote_public_key(&self) -> Option<[u8; COMPRESSED_PUBLIC_KEY_SIZE]> {
+		match &self.stream {
+			PeerStream::Plain(_) => None,
+			PeerStream::Secure(stream) => stream.remote_public_key(),
+		}
+	}
+
+	/// Queues msg as a message of msg_type and sends as much as the socket takes. The rest
+	/// goes out as the peer reads. IllegalArgument if it is larger than max_message_size.
+	pub fn send<M: Writeable>(&mut self, msg_type: u8, msg: &M) -> Result<(), Error> {
+		let mut frame = Vec::new();
+		match frame.resize(HEADER_SIZE) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match msg.write(&mut frame) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let len = frame.len() - HEADER_SIZE;
+		if len > self.max_message_size {
+			return Err(err!(IllegalArgument));
+		}
+		frame[0..4].copy_from_slice(&self.magic.to_be_bytes());
+		frame[4] = msg_type;
+		frame[5..9].copy_from_slice(&(len as u32).to_be_bytes());
+		match self.wbuf.extend_from_slice(frame.as_slice()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.flush()
+	}
+
+	/// Disconnects once the current turn is over
+	pub fn close(&mut self) {
+		self.closing = true;
+	}
+
+	fn flush(&mut self) -> Result<(), Error> {
+		while self.wbuf.len() > 0 {
+			match self.stream.write(self.wbuf.front_slice()) {
+				Ok(n) => {
+					// cannot be an error, n is at most the length of the front slice
+					let _ = self.wbuf.shift(n);
+				}
+				Err(e) => {
+					if e.kind == WouldBlock {
+						break;
+					}
+					return Err(e);
+				}
+			}
+		}
+		match self.stream.flush() {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				if e.kind == WouldBlock {
+					Ok(())
+				} else {
+					Err(e)
+				}
+			}
+		}
+	}
+
+	// reads until the socket is drained, false once the peer has closed its side
+	fn fill(&mut self) -> Result<bool, Error> {
+		let mut buf = [0u8; READ_SIZE];
+		loop {
+			match self.stream.read(&mut buf) {
+				Ok(0) => return Ok(false),
+				Ok(n) => match self.rbuf.append_ptr(buf.as_ptr(), n) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => {
+					if e.kind == WouldBlock {
+						return Ok(true);
+					}
+					return Err(e);
+				}
+			}
+		}
+	}
+}
+
+impl P2pNode {
+	pub fn new(config: P2pConfig) -> Result<Self, Error> {
+		let reactor = match Reactor::new() {
+			Ok(reactor) => reactor,
+			Err(e) => return Err(e),
+		};
+		let events = match Events::new(MAX_EVENTS) {
+			Ok(events) => events,
+			Err(e) => return Err(e),
+		};
+		let peers = match HashMap::new() {
+			Ok(peers) => peers,
+			Err(e) => return Err(e),
+		};
+		let handlers = match HashMap::new() {
+			Ok(handlers) => handlers,
+			Err(e) => return Err(e),
+		};
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		Ok(Self {
+			config,
+			reactor,
+			events,
+			listener: None,
+			peers,
+			handlers,
+			connect_handler: None,
+			disconnect_handler: None,
+			next_id: LISTENER_TOKEN as u64 + 1,
+			nonce: rand.gen_u64(),
+			rand,
+		})
+	}
+
+	/// Accepts peers on addr and port, or a port chosen by the system if port is 0, which
+	/// is returned. IllegalState if the node is already listening.
+	pub fn listen(&mut self, addr: [u8; 4], port: u16) -> Result<u16, Error> {
+		if self.listener.is_some() {
+			return Err(err!(IllegalState));
+		}
+		let listener = match TcpListener::bind(addr, port, self.config.backlog) {
+			Ok(listener) => listener,
+			Err(e) => return Err(e),
+		};
+		match self
+			.reactor
+			.register(listener.handle(), READ, LISTENER_TOKEN)
+		{
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let port = listener.port();
+		self.listener = Some(listener);
+		Ok(port)
+	}
+
+	/// Connects to the node at addr and port and sends it our hello, returning the id of
+	/// the new peer. Messages can be sent to it before its hello has arrived.
+	pub fn connect(&mut self, addr: [u8; 4], port: u16) -> Result<u64, Error> {
+		match TcpStream::connect(addr, port) {
+			Ok(stream) => self.add_peer(stream, true),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Calls handler with each message of msg_type, deserialized as M. IllegalArgument if
+	/// msg_type is below MSG_USER. A handler registered earlier for it is replaced.
+	pub fn register<M, F>(&mut self, msg_type: u8, mut handler: F) -> Result<(), Error>
+	where
+		M: Readable + 'static,
+		F: FnMut(&mut Peer, M) -> Result<(), Error> + 'static,
+	{
+		if msg_type < MSG_USER {
+			return Err(err!(IllegalArgument));
+		}
+		let handler: Handler =
+			match Box::new(
+				move |peer: &mut Peer, payload: &[u8]| match deserialize::<M>(payload) {
+					Ok(msg) => handler(peer, msg),
+					Err(e) => Err(e),
+				},
+			) {
+				Ok(handler) => handler,
+				Err(e) => return Err(e),
+			};
+		match self.handlers.insert(msg_type, handler) {
+			Ok(_) => Ok(()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Called with each peer once its hello has been accepted
+	pub fn set_connect_handler(&mut self, handler: Box<dyn FnMut(&mut Peer)>) {
+		self.connect_handler = Some(handler);
+	}
+
+	/// Called with the id of each peer which was disconnected, for whatever reason
+	pub fn set_disconnect_handler(&mut self, handler: Box<dyn FnMut(u64)>) {
+		self.disconnect_handler = Some(handler);
+	}
+
+	/// Sends msg to peer. ConnectionClosed if there is no such peer.
+	pub fn send<M: Writeable>(&mut self, peer: u64, msg_type: u8, msg: &M) -> Result<(), Error> {
+		let res = match self.peers.get_mut(&peer) {
+			Some(p) => p.send(msg_type, msg),
+			None => return Err(err!(ConnectionClosed)),
+		};
+		self.update_interest(peer);
+		res
+	}
+
+	/// Pings peer now rather than at the next interval
+	pub fn ping(&mut self, peer: u64) -> Result<(), Error> {
+		let nonce = self.rand.gen_u64() | 1;
+		let res = match self.peers.get_mut(&peer) {
+			Some(p) => {
+				p.ping_nonce = nonce;
+				p.ping_sent = getmicros!();
+				p.send(MSG_PING, &nonce)
+			}
+			None => return Err(err!(ConnectionClosed)),
+		};
+		self.update_interest(peer);
+		res
+	}
+
+	pub fn peer(&self, id: u64) -> Option<&Peer> {
+		self.peers.get(&id)
+	}
+
+	/// Number of connected peers, including those still handshaking
+	pub fn peers(&self) -> usize {
+		self.peers.len()
+	}
+
+	/// Disconnects peer, returning false if there was no such peer
+	pub fn disconnect(&mut self, peer: u64) -> bool {
+		match self.peers.remove(&peer) {
+			Some(_) => {
+				match &mut self.disconnect_handler {
+					Some(handler) => handler(peer),
+					None => {}
+				}
+				true
+			}
+			None => false,
+		}
+	}
+
+	/// Waits up to timeout_millis, or without limit if it is negative, for sockets to
+	/// become ready and processes them: accepting peers, reading and dispatching messages
+	/// and writing what was queued. Also sends the pings which are due.
+	pub fn turn(&mut self, timeout_millis: i64) -> Result<(), Error> {
+		let count = match self.reactor.poll(&mut self.events, timeout_millis) {
+			Ok(count) => count,
+			Err(e) => return Err(e),
+		};
+		for i in 0..count {
+			let evt = self.events.get(i);
+			if evt.token() == WAKE_TOKEN {
+				continue;
+			}
+			if evt.token() == LISTENER_TOKEN {
+				self.accept();
+				continue;
+			}
+			let id = evt.token() as u64;
+			if evt.is_writable() {
+				match self.peers.get_mut(&id) {
+					Some(peer) => {
+						if peer.flush().is_err() {
+							peer.closing = true;
+						}
+					}
+					None => {}
+				}
+			}
+			if evt.is_readable() {
+				self.proc_read(id);
+			}
+		}
+		self.check_pings();
+
+		let mut closed = Vec::new();
+		for (id, peer) in self.peers.iter() {
+			if peer.closing {
+				match closed.push(*id) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+			}
+		}
+		for id in &closed {
+			self.disconnect(*id);
+		}
+		let mut ids = Vec::new();
+		for (id, _) in self.peers.iter() {
+			match ids.push(*id) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		for id in &ids {
+			self.update_interest(*id);
+		}
+		Ok(())
+	}
+
+	fn accept(&mut self) {
+		loop {
+			let stream = match &self.listener {
+				Some(listener) => match listener.accept() {
+					Ok(stream) => stream,
+					Err(_e) => return,
+				},
+				None => return,
+			};
+			// a peer which cannot be set up is dropped, which closes its socket
+			let _ = self.add_peer(stream, false);
+		}
+	}
+
+	fn add_peer(&mut self, stream: TcpStream, outbound: bool) -> Result<u64, Error> {
+		let stream = match &self.config.secure_identity {
+			Some(identity) => {
+				let identity = match identity.clone() {
+					Ok(identity) => identity,
+					Err(e) => return Err(e),
+				};
+				match SecureStream::new(stream, identity, outbound) {
+					Ok(stream) => PeerStream::Secure(stream),
+					Err(e) => return Err(e),
+				}
+			}
+			None => PeerStream::Plain(stream),
+		};
+		let id = self.next_id;
+		self.next_id += 1;
+		let mut peer = Peer {
+			id,
+			stream,
+			outbound,
+			version: 0,
+			magic: self.config.magic,
+			max_message_size: self.config.max_message_size,
+			rbuf: Vec::new(),
+			wbuf: Deque::new(),
+			write_registered: false,
+			ping_nonce: 0,
+			ping_sent: getmicros!(),
+			rtt_micros: 0,
+			closing: false,
+		};
+		let hello = Hello {
+			version: self.config.version,
+			nonce: self.nonce,
+		};
+		match peer.send(MSG_HELLO, &hello) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self
+			.reactor
+			.register(peer.stream.handle(), READ, id as usize)
+		{
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self.peers.insert(id, peer) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.update_interest(id);
+		Ok(id)
+	}
+
+	fn proc_read(&mut self, id: u64) {
+		let open = match self.peers.get_mut(&id) {
+			Some(peer) => match peer.fill() {
+				Ok(open) => open,
+				Err(_e) => false,
+			},
+			None => return,
+		};
+		let res = self.proc_frames(id);
+		match self.peers.get_mut(&id) {
+			Some(peer) => {
+				if !open || res.is_err() {
+					peer.closing = true;
+				}
+			}
+			None => {}
+		}
+	}
+
+	// dispatches every complete frame in the peer's read buffer
+	fn proc_frames(&mut self, id: u64) -> Result<(), Error> {
+		let peer = match self.peers.get_mut(&id) {
+			Some(peer) => peer,
+			None => return Ok(()),
+		};
+		let rbuf = replace(&mut peer.rbuf, Vec::new());
+		let mut offset = 0;
+		let mut res = Ok(());
+		while rbuf.len() - offset >= HEADER_SIZE && !peer.closing {
+			let header = &rbuf[offset..offset + HEADER_SIZE];
+			let magic = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+			let msg_type = header[4];
+			let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+			if magic != self.config.magic || len > self.config.max_message_size {
+				res = Err(err!(CorruptedData));
+				break;
+			}
+			if rbuf.len() - offset - HEADER_SIZE < len {
+				break;
+			}
+			let start = offset + HEADER_SIZE;
+			offset = start + len;
+			res = Self::dispatch(
+				peer,
+				msg_type,
+				&rbuf[start..offset],
+				&self.config,
+				self.nonce,
+				&mut self.handlers,
+				&mut self.connect_handler,
+			);
+			if res.is_err() {
+				break;
+			}
+		}
+		let mut rbuf = rbuf;
+		// cannot be an error, offset is at most the length
+		let _ = rbuf.shift(offset);
+		peer.rbuf = rbuf;
+		res
+	}
+
+	fn dispatch(
+		peer: &mut Peer,
+		msg_type: u8,
+		payload: &[u8],
+		config: &P2pConfig,
+		nonce: u64,
+		handlers: &mut HashMap<u8, Handler>,
+		connect_handler: &mut Option<Box<dyn FnMut(&mut Peer)>>,
+	) -> Result<(), Error> {
+		if peer.version == 0 {
+			if msg_type != MSG_HELLO {
+				return Err(err!(IllegalState));
+			}
+			let hello: Hello = match deserialize(payload) {
+				Ok(hello) => hello,
+				Err(e) => return Err(e),
+			};
+			if hello.version < config.min_version || hello.version == 0 {
+				return Err(err!(IllegalState));
+			}
+			if hello.nonce == nonce {
+				return Err(err!(IllegalState));
+			}
+			peer.version = if hello.version < config.version {
+				hello.version
+			} else {
+				config.version
+			};
+			match connect_handler {
+				Some(handler) => handler(peer),
+				None => {}
+			}
+			return Ok(());
+		}
+		match msg_type {
+			MSG_HELLO => Err(err!(IllegalState)),
+			MSG_PING => match deserialize::<u64>(payload) {
+				Ok(n) => peer.send(MSG_PONG, &n),
+				Err(e) => Err(e),
+			},
+			MSG_PONG => match deserialize::<u64>(payload) {
+				Ok(n) => {
+					if n == peer.ping_nonce && n != 0 {
+						peer.rtt_micros = getmicros!() - peer.ping_sent;
+						peer.ping_nonce = 0;
+					}
+					Ok(())
+				}
+				Err(e) => Err(e),
+			},
+			_ => {
+				if msg_type < MSG_USER {
+					return Err(err!(CorruptedData));
+				}
+				match handlers.get_mut(&msg_type) {
+					Some(handler) => handler(peer, payload),
+					None => Ok(()),
+				}
+			}
+		}
+	}
+
+	// sends the pings which are due and closes peers which did not answer the last one
+	fn check_pings(&mut self) {
+		if self.config.ping_interval_micros <= 0 {
+			return;
+		}
+		let now = getmicros!();
+		for (_, peer) in self.peers.iter_mut() {
+			if peer.version == 0 || now - peer.ping_sent < self.config.ping_interval_micros {
+				continue;
+			}
+			if peer.ping_nonce != 0 {
+				peer.closing = true;
+				continue;
+			}
+			peer.ping_nonce = self.rand.gen_u64() | 1;
+			peer.ping_sent = now;
+			let nonce = peer.ping_nonce;
+			if peer.send(MSG_PING, &nonce).is_err() {
+				peer.closing = true;
+			}
+		}
+	}
+
+	// asks for write events while the peer has bytes queued and stops once they are sent
+	fn update_interest(&mut self, id: u64) {
+		let peer = match self.peers.get_mut(&id) {
+			Some(peer) => peer,
+			None => return,
+		};
+		let pending = peer.wbuf.len() > 0 || peer.stream.pending() > 0;
+		if pending == peer.write_registered {
+			return;
+		}
+		let handle = peer.stream.handle();
+		let res = if pending {
+			self.reactor.register(handle, READ | WRITE, id as usize)
+		} else {
+			self.reactor.deregister_write(handle, id as usize)
+		};
+		match res {
+			Ok(_) => peer.write_registered = pending,
+			Err(_e) => peer.closing = true,
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct Note {
+		text: String,
+		n: u64,
+	}
+
+	impl_ser!(Note { text, n });
+
+	const MSG_NOTE: u8 = MSG_USER;
+
+	fn turn_until<F: FnMut(&P2pNode, &P2pNode) -> bool>(
+		a: &mut P2pNode,
+		b: &mut P2pNode,
+		mut done: F,
+	) {
+		for _ in 0..1_000 {
+			if done(a, b) {
+				return;
+			}
+			a.turn(1).unwrap();
+			b.turn(1).unwrap();
+		}
+		panic!("condition not reached");
+	}
+
+	#[test]
+	fn test_p2p() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = P2pNode::new(P2pConfig::default()).unwrap();
+		let mut client = P2pNode::new(P2pConfig {
+			version: 2,
+			..P2pConfig::default()
+		})
+		.unwrap();
+		assert!(server
+			.register(MSG_PING, |_p: &mut Peer, _m: u64| Ok(()))
+			.is_err());
+
+		// the server answers each note with n doubled
+		server
+			.register(MSG_NOTE, |peer: &mut Peer, note: Note| {
+				let reply = Note {
+					text: note.text,
+					n: note.n * 2,
+				};
+				peer.send(MSG_NOTE, &reply)
+			})
+			.unwrap();
+		let received = Rc::new(0u64).unwrap();
+		let mut received_clone = received.clone().unwrap();
+		client
+			.register(MSG_NOTE, move |_peer: &mut Peer, note: Note| {
+				assert_eq!(note.text.to_str(), "hi");
+				*received_clone += note.n;
+				Ok(())
+			})
+			.unwrap();
+		let connected = Rc::new(0u64).unwrap();
+		let mut connected_clone = connected.clone().unwrap();
+		server.set_connect_handler(
+			Box::new(move |peer: &mut Peer| {
+				assert!(!peer.is_outbound());
+				*connected_clone += 1;
+			})
+			.unwrap(),
+		);
+
+		let port = server.listen([127, 0, 0, 1], 0).unwrap();
+		assert!(server.listen([127, 0, 0, 1], 0).is_err());
+		let id = client.connect([127, 0, 0, 1], port).unwrap();
+		let note = Note {
+			text: String::new("hi").unwrap(),
+			n: 21,
+		};
+		client.send(id, MSG_NOTE, &note).unwrap();
+		turn_until(&mut server, &mut client, |_, _| *received != 0);
+		assert_eq!(*received, 42);
+		assert_eq!(*connected, 1);
+
+		// the lower version is used by both
+		assert_eq!(client.peer(id).unwrap().version(), 1);
+		assert!(client.peer(id).unwrap().is_outbound());
+		assert_eq!(server.peers(), 1);
+
+		client.ping(id).unwrap();
+		turn_until(&mut server, &mut client, |_, c| {
+			c.peer(id).unwrap().rtt_micros() > 0
+		});
+
+		// a message too large to send, then one which is sent in many pieces
+		let mut big: Vec<u8> = Vec::new();
+		big.resize(5 * 1024 * 1024).unwrap();
+		let e = client.send(id, MSG_NOTE + 1, &big).unwrap_err();
+		assert!(e.kind == IllegalArgument);
+		big.resize(3 * 1024 * 1024).unwrap();
+		client.send(id, MSG_NOTE + 1, &big).unwrap();
+		client.send(id, MSG_NOTE, &note).unwrap();
+		turn_until(&mut server, &mut client, |_, _| *received == 84);
+
+		let disconnected = Rc::new(0u64).unwrap();
+		let mut disconnected_clone = disconnected.clone().unwrap();
+		server.set_disconnect_handler(
+			Box::new(move |_id: u64| {
+				*disconnected_clone += 1;
+			})
+			.unwrap(),
+		);
+		assert!(client.disconnect(id));
+		assert!(!client.disconnect(id));
+		turn_until(&mut server, &mut client, |s, _| s.peers() == 0);
+		assert_eq!(*disconnected, 1);
+	}
+
+	#[test]
+	fn test_p2p_secure() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let server_identity = Arc::new(Identity::generate().unwrap()).unwrap();
+		let client_identity = Arc::new(Identity::generate().unwrap()).unwrap();
+		let mut server = P2pNode::new(P2pConfig {
+			secure_identity: Some(server_identity.clone().unwrap()),
+			..P2pConfig::default()
+		})
+		.unwrap();
+		let mut client = P2pNode::new(P2pConfig {
+			secure_identity: Some(client_identity.clone().unwrap()),
+			..P2pConfig::default()
+		})
+		.unwrap();
+		let client_key = client_identity.public_key();
+		server
+			.register(MSG_NOTE, move |peer: &mut Peer, note: Note| {
+				assert_eq!(peer.remote_public_key().unwrap(), client_key);
+				let reply = Note {
+					text: note.text,
+					n: note.n + 1,
+				};
+				peer.send(MSG_NOTE, &reply)
+			})
+			.unwrap();
+		let received = Rc::new(0u64).unwrap();
+		let mut received_clone = received.clone().unwrap();
+		client
+			.register(MSG_NOTE, move |_peer: &mut Peer, note: Note| {
+				assert_eq!(note.text.to_str(), "secret");
+				*received_clone = note.n;
+				Ok(())
+			})
+			.unwrap();
+
+		// sent before the handshake, so it waits for it along with the hello
+		let port = server.listen([127, 0, 0, 1], 0).unwrap();
+		let id = client.connect([127, 0, 0, 1], port).unwrap();
+		assert!(client.peer(id).unwrap().remote_public_key().is_none());
+		let note = Note {
+			text: String::new("secret").unwrap(),
+			n: 7,
+		};
+		client.send(id, MSG_NOTE, &note).unwrap();
+		turn_until(&mut server, &mut client, |_, _| *received != 0);
+		assert_eq!(*received, 8);
+		assert_eq!(
+			client.peer(id).unwrap().remote_public_key().unwrap(),
+			server_identity.public_key()
+		);
+
+		// a node without an identity reads the first handshake message as a frame of
+		// another network
+		let mut plain = P2pNode::new(P2pConfig::default()).unwrap();
+		let port = plain.listen([127, 0, 0, 1], 0).unwrap();
+		client.connect([127, 0, 0, 1], port).unwrap();
+		turn_until(&mut plain, &mut client, |p, c| {
+			p.peers() == 0 && c.peers() == 1
+		});
+	}
+
+	#[test]
+	fn test_p2p_rejected() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = P2pNode::new(P2pConfig {
+			min_version: 2,
+			..P2pConfig::default()
+		})
+		.unwrap();
+		let port = server.listen([127, 0, 0, 1], 0).unwrap();
+
+		// too old
+		let mut old = P2pNode::new(P2pConfig::default()).unwrap();
+		old.connect([127, 0, 0, 1], port).unwrap();
+		turn_until(&mut server, &mut old, |s, o| {
+			s.peers() == 0 && o.peers() == 0
+		});
+
+		// another network
+		let mut other = P2pNode::new(P2pConfig {
+			magic: 1,
+			version: 2,
+			..P2pConfig::default()
+		})
+		.unwrap();
+		other.connect([127, 0, 0, 1], port).unwrap();
+		turn_until(&mut server, &mut other, |s, o| {
+			s.peers() == 0 && o.peers() == 0
+		});
+
+		// ourselves
+		let mut ring = P2pNode::new(P2pConfig::default()).unwrap();
+		let port = ring.listen([127, 0, 0, 1], 0).unwrap();
+		ring.connect([127, 0, 0, 1], port).unwrap();
+		for _ in 0..100 {
+			if ring.peers() == 0 {
+				break;
+			}
+			ring.turn(1).unwrap();
+		}
+		assert_eq!(ring.peers(), 0);
+	}
+}
@@ -0,0 +1,519 @@
+//! # Client pool
+//! Maintains a fixed number of WebSocket client connections spread over a set of servers.
+//! Outgoing messages are routed by key with jump consistent hashing so that a key always
+//! uses the same connection (and server), replies from every connection are delivered to a
+//! single handler and closed connections are re-established on the next send, resuming
+//! their server side session when the server issued a resumption token.
+//!
+//! start waits for every connection to complete its handshake, so that the first messages
+//! are not held up by connection setup. A background thread then pings each connection at
+//! WsPoolConfig::ping_interval_micros and replaces those which have closed. A peer which
+//! has gone away without closing stops answering pings, so the client closes the connection
+//! once it has seen no traffic for WsConfig::timeout_micros and it is replaced on the next
+//! round. Pongs are delivered to the handler like any other frame.
+
+use ffi::sleep_millis;
+use net::ws::*;
+use prelude::*;
+use std::murmur128::murmur3_x64_128_of_slice;
+
+// fixed so that routing is stable across processes
+const ROUTE_SEED: u32 = 0;
+// how often the health checker looks for stop while waiting for the next round
+const HEALTH_POLL_MILLIS: u64 = 10;
+
+/// Size, warm-up and health check settings for a WsClientPool
+#[derive(Clone, Copy)]
+pub struct WsPoolConfig {
+	pub connections: usize,
+	/// start waits this long for every connection to complete its handshake. 0 returns as
+	/// soon as the connections have been made.
+	pub warmup_micros: i64,
+	/// Interval at which open connections are pinged and closed ones replaced. 0 disables
+	/// the health checker, leaving send to replace closed connections.
+	pub ping_interval_micros: i64,
+}
+
+struct PoolState {
+	ws: WebSocket,
+	servers: Vec<WsClientConfig>,
+	conns: Vec<WsResponse>,
+	halt: bool,
+	next: u64,
+	replaced: u64,
+}
+
+pub struct WsClientPool {
+	// shared with the health checker
+	state: Arc<Mutex<PoolState>>,
+	config: WsPoolConfig,
+	health: Option<JoinHandle>,
+}
+
+impl Default for WsPoolConfig {
+	fn default() -> Self {
+		Self {
+			connections: 4,
+			warmup_micros: 5_000_000,
+			ping_interval_micros: 10_000_000,
+		}
+	}
+}
+
+/// Jump consistent hash (Lamping and Veach). Maps key to a bucket in [0, buckets) such that
+/// growing the number of buckets only moves keys to the new buckets.
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+	let mut b: i64 = -1;
+	let mut j: i64 = 0;
+	while j < buckets as i64 {
+		b = j;
+		key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+		j = ((b + 1) as f64 * ((1i64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+	}
+	b as usize
+}
+
+impl PoolState {
+	fn reconnect(&mut self, index: usize) -> Result<(), Error> {
+		let server = self.servers[index % self.servers.len()];
+		let conn = match self.conns[index].resume_token() {
+			Some(token) => self.ws.resume_client(server, &token),
+			None => self.ws.add_client(server),
+		};
+		match conn {
+			Ok(conn) => {
+				self.conns[index] = conn;
+				self.replaced += 1;
+				Ok(())
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	// the connection at index, reconnected first if it has been closed
+	fn conn(&mut self, index: usize) -> Result<WsResponse, Error> {
+		if self.conns[index].is_closed() {
+			match self.reconnect(index) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		self.conns[index].clone()
+	}
+
+	// one round of the health checker: ping the open connections and replace the closed
+	// ones and any which can no longer be written to. Connections still handshaking are
+	// left alone.
+	fn check(&mut self) {
+		for i in 0..self.conns.len() {
+			let healthy = if self.conns[i].is_closed() {
+				false
+			} else if self.conns[i].is_open() {
+				self.conns[i].send_frame(0x9, true, &[]).is_ok()
+			} else {
+				true
+			};
+			if !healthy {
+				match self.reconnect(i) {
+					Ok(_) => {}
+					Err(e) => println!("WARN: could not replace pool connection {}: {}", i, e),
+				}
+			}
+		}
+	}
+}
+
+impl WsClientPool {
+	/// Creates a pool of connections to servers. Connection i goes to server i modulo the
+	/// number of servers. No connections are made until start is called.
+	pub fn new(
+		config: WsConfig,
+		servers: &[WsClientConfig],
+		pool: WsPoolConfig,
+	) -> Result<Self, Error> {
+		if servers.len() == 0
+			|| pool.connections == 0
+			|| pool.warmup_micros < 0
+			|| pool.ping_interval_micros < 0
+		{
+			return Err(err!(IllegalArgument));
+		}
+		let ws = match WebSocket::new(config) {
+			Ok(ws) => ws,
+			Err(e) => return Err(e),
+		};
+		let mut v = Vec::new();
+		for server in servers {
+			match v.push(*server) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		let state = match Arc::new(Mutex::new(PoolState {
+			ws,
+			servers: v,
+			conns: Vec::new(),
+			halt: false,
+			next: 0,
+			replaced: 0,
+		})) {
+			Ok(state) => state,
+			Err(e) => return Err(e),
+		};
+		Ok(Self {
+			state,
+			config: pool,
+			health: None,
+		})
+	}
+
+	/// Handler for messages received on any connection in the pool
+	pub fn register_handler(&mut self, handler: Handler) -> Result<(), Error> {
+		self.state.lock().ws.register_handler(handler)
+	}
+
+	/// Connects the pool, waits for the handshakes to complete and starts the health
+	/// checker. Returns Timeout if some connection was not ready within
+	/// WsPoolConfig::warmup_micros, in which case the pool must still be stopped.
+	pub fn start(&mut self) -> Result<(), Error> {
+		{
+			let mut state = self.state.lock();
+			match state.ws.start() {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			for i in 0..self.config.connections {
+				let server = state.servers[i % state.servers.len()];
+				let conn = match state.ws.add_client(server) {
+					Ok(conn) => conn,
+					Err(e) => return Err(e),
+				};
+				match state.conns.push(conn) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+			}
+		}
+
+		if self.config.ping_interval_micros > 0 {
+			let state = self.state.clone().unwrap();
+			let interval = self.config.ping_interval_micros;
+			let jh = match spawnj(move || {
+				let mut last = getmicros!();
+				loop {
+					unsafe {
+						sleep_millis(HEALTH_POLL_MILLIS);
+					}
+					let mut state = state.lock();
+					if state.halt {
+						break;
+					}
+					let now = getmicros!();
+					if now - last >= interval {
+						last = now;
+						state.check();
+					}
+				}
+			}) {
+				Ok(jh) => jh,
+				Err(e) => return Err(e),
+			};
+			self.health = Some(jh);
+		}
+
+		self.warm_up()
+	}
+
+	// wait for every connection to complete its handshake
+	fn warm_up(&self) -> Result<(), Error> {
+		if self.config.warmup_micros == 0 {
+			return Ok(());
+		}
+		let deadline = getmicros!() + self.config.warmup_micros;
+		loop {
+			let ready = {
+				let state = self.state.lock();
+				let mut ready = true;
+				for conn in &state.conns {
+					if !conn.is_open() {
+						ready = false;
+						break;
+					}
+				}
+				ready
+			};
+			if ready {
+				return Ok(());
+			}
+			if getmicros!() >= deadline {
+				return Err(err!(Timeout));
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+	}
+
+	pub fn stop(&mut self) -> Result<(), Error> {
+		self.state.lock().halt = true;
+		match &mut self.health {
+			Some(jh) => match jh.join() {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			},
+			None => {}
+		}
+		self.health = None;
+		self.state.lock().ws.stop()
+	}
+
+	/// Index of the connection used for key
+	pub fn route(&self, key: &[u8]) -> usize {
+		let hash = murmur3_x64_128_of_slice(key, ROUTE_SEED) as u64;
+		jump_hash(hash, self.config.connections)
+	}
+
+	/// Handle to the connection for key, reconnecting first if it has been closed
+	pub fn get(&mut self, key: &[u8]) -> Result<WsResponse, Error> {
+		let index = self.route(key);
+		let mut state = self.state.lock();
+		if state.conns.len() != self.config.connections {
+			return Err(err!(NotInitialized));
+		}
+		state.conn(index)
+	}
+
+	/// Handle to the next connection in turn, reconnecting first if it has been closed
+	pub fn next(&mut self) -> Result<WsResponse, Error> {
+		let mut state = self.state.lock();
+		if state.conns.len() != self.config.connections {
+			return Err(err!(NotInitialized));
+		}
+		let index = (state.next % self.config.connections as u64) as usize;
+		state.next += 1;
+		state.conn(index)
+	}
+
+	/// Sends msg on the connection for key, reconnecting first if it has been closed.
+	/// A failed send is retried once on a fresh connection to the same server.
+	pub fn send(&mut self, key: &[u8], msg: &[u8]) -> Result<(), Error> {
+		let index = self.route(key);
+		let mut state = self.state.lock();
+		if state.conns.len() != self.config.connections {
+			return Err(err!(NotInitialized));
+		}
+		let mut conn = match state.conn(index) {
+			Ok(conn) => conn,
+			Err(e) => return Err(e),
+		};
+		match conn.sendb(msg) {
+			Ok(_) => Ok(()),
+			Err(_e) => match state.reconnect(index) {
+				Ok(_) => state.conns[index].sendb(msg),
+				Err(e) => Err(e),
+			},
+		}
+	}
+
+	/// Number of connections replaced since start, by send or by the health checker
+	pub fn replaced(&self) -> u64 {
+		self.state.lock().replaced
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::murmur128::murmur3_128_of_u64;
+
+	#[test]
+	fn test_jump_hash() {
+		// keys only move to the new bucket when a bucket is added
+		for key in 0..1_000u64 {
+			let hash = murmur3_128_of_u64(key, 0) as u64;
+			assert_eq!(jump_hash(hash, 1), 0);
+			for buckets in 1..16 {
+				let before = jump_hash(hash, buckets);
+				let after = jump_hash(hash, buckets + 1);
+				assert!(before < buckets);
+				assert!(after == before || after == buckets);
+			}
+		}
+	}
+
+	#[test]
+	fn test_client_pool() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+
+		// echo server on two ports, closing the connection when asked to
+		let mut server = WebSocket::new(WsConfig {
+			threads: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		server.start().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.msg() == b"close" {
+					resp.close(1000);
+					Ok(())
+				} else {
+					resp.sendb(req.msg())
+				}
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let addr = [127, 0, 0, 1];
+		let port1 = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+		let port2 = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let servers = [
+			WsClientConfig::new(addr, port1),
+			WsClientConfig::new(addr, port2),
+		];
+		assert!(WsClientPool::new(WsConfig::default(), &[], WsPoolConfig::default()).is_err());
+		let mut pool = WsClientPool::new(
+			WsConfig {
+				threads: 2,
+				..WsConfig::default()
+			},
+			&servers,
+			WsPoolConfig {
+				connections: 4,
+				ping_interval_micros: 0,
+				..WsPoolConfig::default()
+			},
+		)
+		.unwrap();
+		assert!(pool.send(b"key", b"too early").is_err());
+
+		// replies from every connection arrive at the one handler
+		let counts = TestCounts::new().unwrap();
+		let counts_clone = counts.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, _resp: WsResponse| {
+				counts_clone.add(0);
+				Ok(())
+			})
+			.unwrap();
+		pool.register_handler(b).unwrap();
+		pool.start().unwrap();
+
+		let mut used = [false; 4];
+		for i in 0..32u64 {
+			let mut key = [0u8; 8];
+			to_be_bytes_u64(i, &mut key);
+			let index = pool.route(&key);
+			assert_eq!(index, pool.route(&key));
+			used[index] = true;
+			assert!(pool.send(&key, b"hello").is_ok());
+		}
+		assert!(used[0] && used[1] && used[2] && used[3]);
+		assert!(counts.wait(0, 32));
+
+		// the server closes the connection for this key, the next send reconnects
+		let index = pool.route(b"sticky");
+		assert!(pool.send(b"sticky", b"close").is_ok());
+		let mut closed = false;
+		for _i in 0..5_000 {
+			if pool.state.lock().conns[index].is_closed() {
+				closed = true;
+				break;
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+		assert!(closed);
+		assert!(pool.send(b"sticky", b"hello again").is_ok());
+		assert!(!pool.state.lock().conns[index].is_closed());
+		assert!(counts.wait(0, 33));
+
+		assert!(pool.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+
+	#[test]
+	fn test_client_pool_health() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+
+		// answers pings itself, counting them, and closes the connection when asked to
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			auto_control_frames: false,
+			..WsConfig::default()
+		})
+		.unwrap();
+		server.start().unwrap();
+		let pings = TestCounts::new().unwrap();
+		let pings_clone = pings.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() == 0x9 {
+					pings_clone.add(0);
+					resp.send_frame(0xA, true, req.msg())
+				} else if req.msg() == b"close" {
+					resp.close(1000);
+					Ok(())
+				} else {
+					resp.sendb(req.msg())
+				}
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut pool = WsClientPool::new(
+			WsConfig {
+				threads: 1,
+				..WsConfig::default()
+			},
+			&[WsClientConfig::new(addr, port)],
+			WsPoolConfig {
+				connections: 3,
+				ping_interval_micros: 20_000,
+				..WsPoolConfig::default()
+			},
+		)
+		.unwrap();
+		assert!(pool.next().is_err());
+		pool.start().unwrap();
+
+		// warmed up: every connection is open once start returns
+		for i in 0..3 {
+			assert!(pool.state.lock().conns[i].is_open());
+		}
+		assert!(pings.wait(0, 3));
+
+		// handles are handed out in turn, and by key
+		let index = pool.route(b"key");
+		let mut resp = pool.get(b"key").unwrap();
+		assert!(resp.sendb(b"hello").is_ok());
+		let _ = pool.next().unwrap();
+		let _ = pool.next().unwrap();
+		let _ = pool.next().unwrap();
+		assert_eq!(pool.state.lock().next, 3);
+
+		// the health checker replaces a connection the server closed without any send
+		assert!(resp.sendb(b"close").is_ok());
+		let mut replaced = false;
+		for _i in 0..5_000 {
+			if pool.replaced() == 1 && pool.get(b"key").unwrap().is_open() {
+				replaced = true;
+				break;
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+		assert!(replaced);
+		assert!(resp.is_closed());
+		assert!(!pool.state.lock().conns[index].is_closed());
+
+		assert!(pool.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+}
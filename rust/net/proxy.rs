@@ -0,0 +1,472 @@
+//! # Proxy
+//! Outgoing connections through a SOCKS5 (RFC 1928, with the username and password
+//! method of RFC 1929) or HTTP CONNECT proxy, such as Tor's SOCKS port. ProxyConfig::connect
+//! opens a TcpStream to the proxy, asks it for a tunnel to the target and returns the
+//! stream once the tunnel is up, so the caller speaks its own protocol as if connected
+//! directly. A direct ProxyConfig connects to the target itself.
+//!
+//! The exchange with the proxy is done blocking, like TcpStream::connect, and the stream
+//! is nonblocking when returned. Targets are IPv4 addresses.
+
+use core::marker::Copy;
+use core::str::from_utf8_unchecked;
+use ffi::Base64encode;
+use net::tcp::TcpStream;
+use prelude::*;
+
+// the longest username or password RFC 1929 can carry
+pub const MAX_CREDENTIAL_LEN: usize = 255;
+// an HTTP proxy response head longer than this is not a proxy speaking HTTP
+const MAX_RESPONSE_HEAD: usize = 8192;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_AUTH_VERSION: u8 = 1;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_USER_PASS: u8 = 2;
+const SOCKS_NO_ACCEPTABLE: u8 = 0xFF;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_IPV4: u8 = 1;
+const SOCKS_DOMAIN: u8 = 3;
+const SOCKS_IPV6: u8 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProxyKind {
+	Direct,
+	Socks5,
+	HttpConnect,
+}
+
+#[derive(Clone, Copy)]
+pub struct ProxyConfig {
+	kind: ProxyKind,
+	addr: [u8; 4],
+	port: u16,
+	user: [u8; MAX_CREDENTIAL_LEN],
+	user_len: usize,
+	password: [u8; MAX_CREDENTIAL_LEN],
+	password_len: usize,
+}
+
+impl ProxyConfig {
+	/// No proxy, connect to the target directly
+	pub fn direct() -> Self {
+		Self::new(ProxyKind::Direct, [0u8; 4], 0)
+	}
+
+	pub fn socks5(addr: [u8; 4], port: u16) -> Self {
+		Self::new(ProxyKind::Socks5, addr, port)
+	}
+
+	pub fn http_connect(addr: [u8; 4], port: u16) -> Self {
+		Self::new(ProxyKind::HttpConnect, addr, port)
+	}
+
+	/// Authenticates to the proxy with user and password, by the SOCKS5 username and
+	/// password method or HTTP Basic. IllegalArgument if either is longer than
+	/// MAX_CREDENTIAL_LEN, user is empty, or they cannot be sent in an HTTP header.
+	pub fn with_auth(self, user: &str, password: &str) -> Result<Self, Error> {
+		let (u, p) = (user.as_bytes(), password.as_bytes());
+		if u.len() == 0 || u.len() > MAX_CREDENTIAL_LEN || p.len() > MAX_CREDENTIAL_LEN {
+			return Err(err!(IllegalArgument));
+		}
+		// Basic joins them with a colon
+		for b in u {
+			if *b == b':' {
+				return Err(err!(IllegalArgument));
+			}
+		}
+		let mut ret = self;
+		ret.user[0..u.len()].copy_from_slice(u);
+		ret.user_len = u.len();
+		ret.password[0..p.len()].copy_from_slice(p);
+		ret.password_len = p.len();
+		Ok(ret)
+	}
+
+	pub fn kind(&self) -> ProxyKind {
+		self.kind
+	}
+
+	/// A stream to addr and port, through the proxy if there is one. Unauthorized if the
+	/// proxy rejected the credentials or asked for some, ConnectionRefused if the target
+	/// refused the connection, Connect if the proxy could not reach it for another reason
+	/// and CorruptedData if the proxy did not follow the protocol.
+	pub fn connect(&self, addr: [u8; 4], port: u16) -> Result<TcpStream, Error> {
+		if self.kind == ProxyKind::Direct {
+			return TcpStream::connect(addr, port);
+		}
+		let stream = match TcpStream::connect(self.addr, self.port) {
+			Ok(stream) => stream,
+			Err(e) => return Err(e),
+		};
+		match stream.set_nonblocking(false) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let res = match self.kind {
+			ProxyKind::Socks5 => self.socks5_connect(&stream, addr, port),
+			_ => self.http_connect_tunnel(&stream, addr, port),
+		};
+		match res {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match stream.set_nonblocking(true) {
+			Ok(_) => Ok(stream),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn new(kind: ProxyKind, addr: [u8; 4], port: u16) -> Self {
+		Self {
+			kind,
+			addr,
+			port,
+			user: [0u8; MAX_CREDENTIAL_LEN],
+			user_len: 0,
+			password: [0u8; MAX_CREDENTIAL_LEN],
+			password_len: 0,
+		}
+	}
+
+	fn socks5_connect(&self, stream: &TcpStream, addr: [u8; 4], port: u16) -> Result<(), Error> {
+		let greeting: &[u8] = if self.user_len > 0 {
+			&[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASS]
+		} else {
+			&[SOCKS_VERSION, 1, SOCKS_NO_AUTH]
+		};
+		match write_all(stream, greeting) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut reply = [0u8; 2];
+		match read_exact(stream, &mut reply) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if reply[0] != SOCKS_VERSION {
+			return Err(err!(CorruptedData));
+		}
+		match reply[1] {
+			SOCKS_NO_AUTH => {}
+			SOCKS_USER_PASS if self.user_len > 0 => match self.socks5_auth(stream) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			},
+			SOCKS_NO_ACCEPTABLE => return Err(err!(Unauthorized)),
+			_ => return Err(err!(CorruptedData)),
+		}
+
+		let mut request = [
+			SOCKS_VERSION,
+			SOCKS_CONNECT,
+			0,
+			SOCKS_IPV4,
+			0,
+			0,
+			0,
+			0,
+			0,
+			0,
+		];
+		request[4..8].copy_from_slice(&addr);
+		request[8..10].copy_from_slice(&port.to_be_bytes());
+		match write_all(stream, &request) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut head = [0u8; 5];
+		match read_exact(stream, &mut head) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if head[0] != SOCKS_VERSION {
+			return Err(err!(CorruptedData));
+		}
+		match head[1] {
+			0 => {}
+			2 => return Err(err!(Unauthorized)),
+			5 => return Err(err!(ConnectionRefused)),
+			6 => return Err(err!(Timeout)),
+			_ => return Err(err!(Connect)),
+		}
+		// the bound address, of which head has read the first byte, and the port
+		let rest = match head[3] {
+			SOCKS_IPV4 => 3 + 2,
+			SOCKS_DOMAIN => head[4] as usize + 2,
+			SOCKS_IPV6 => 15 + 2,
+			_ => return Err(err!(CorruptedData)),
+		};
+		let mut bound = [0u8; 257];
+		read_exact(stream, &mut bound[0..rest])
+	}
+
+	fn socks5_auth(&self, stream: &TcpStream) -> Result<(), Error> {
+		let mut request = [0u8; 3 + 2 * MAX_CREDENTIAL_LEN];
+		let (ulen, plen) = (self.user_len, self.password_len);
+		request[0] = SOCKS_AUTH_VERSION;
+		request[1] = ulen as u8;
+		request[2..2 + ulen].copy_from_slice(&self.user[0..ulen]);
+		request[2 + ulen] = plen as u8;
+		request[3 + ulen..3 + ulen + plen].copy_from_slice(&self.password[0..plen]);
+		match write_all(stream, &request[0..3 + ulen + plen]) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut reply = [0u8; 2];
+		match read_exact(stream, &mut reply) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if reply[0] != SOCKS_AUTH_VERSION {
+			return Err(err!(CorruptedData));
+		}
+		if reply[1] != 0 {
+			return Err(err!(Unauthorized));
+		}
+		Ok(())
+	}
+
+	fn http_connect_tunnel(
+		&self,
+		stream: &TcpStream,
+		addr: [u8; 4],
+		port: u16,
+	) -> Result<(), Error> {
+		let target = match format!("{}.{}.{}.{}:{}", addr[0], addr[1], addr[2], addr[3], port) {
+			Ok(target) => target,
+			Err(e) => return Err(e),
+		};
+		let mut request = StringBuilder::new();
+		let mut res = request.push_strs(&[
+			"CONNECT ",
+			target.to_str(),
+			" HTTP/1.1\r\nHost: ",
+			target.to_str(),
+			"\r\n",
+		]);
+		if res.is_ok() && self.user_len > 0 {
+			let mut credentials = [0u8; 2 * MAX_CREDENTIAL_LEN + 1];
+			let (ulen, plen) = (self.user_len, self.password_len);
+			credentials[0..ulen].copy_from_slice(&self.user[0..ulen]);
+			credentials[ulen] = b':';
+			credentials[ulen + 1..ulen + 1 + plen].copy_from_slice(&self.password[0..plen]);
+			let mut encoded = [0u8; (2 * MAX_CREDENTIAL_LEN + 3) / 3 * 4];
+			let len = (ulen + 1 + plen + 2) / 3 * 4;
+			unsafe {
+				Base64encode(
+					encoded.as_mut_ptr(),
+					credentials.as_mut_ptr(),
+					ulen + 1 + plen,
+				);
+			}
+			// base64 output is ascii
+			res = request.push_strs(&[
+				"Proxy-Authorization: Basic ",
+				unsafe { from_utf8_unchecked(&encoded[0..len]) },
+				"\r\n",
+			]);
+		}
+		if res.is_ok() {
+			res = request.push_str("\r\n");
+		}
+		match res {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let request = match request.to_string() {
+			Ok(request) => request,
+			Err(e) => return Err(e),
+		};
+		match write_all(stream, request.to_str().as_bytes()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+
+		// read a byte at a time so that nothing past the head is taken from the tunnel
+		let mut head = Vec::new();
+		let mut b = [0u8; 1];
+		loop {
+			match read_exact(stream, &mut b) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			match head.push(b[0]) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			let len = head.len();
+			if len >= 4 && &head[len - 4..len] == b"\r\n\r\n" {
+				break;
+			}
+			if len >= MAX_RESPONSE_HEAD {
+				return Err(err!(CorruptedData));
+			}
+		}
+		// HTTP/1.x NNN
+		if head.len() < 12 || &head[0..7] != b"HTTP/1." || head[8] != b' ' {
+			return Err(err!(CorruptedData));
+		}
+		let mut status = 0u32;
+		for d in &head[9..12] {
+			if !d.is_ascii_digit() {
+				return Err(err!(CorruptedData));
+			}
+			status = status * 10 + (d - b'0') as u32;
+		}
+		match status {
+			200..=299 => Ok(()),
+			407 => Err(err!(Unauthorized)),
+			_ => Err(err!(Connect)),
+		}
+	}
+}
+
+fn write_all(stream: &TcpStream, data: &[u8]) -> Result<(), Error> {
+	let mut offset = 0;
+	while offset < data.len() {
+		match stream.write(&data[offset..]) {
+			Ok(n) => offset += n,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+fn read_exact(stream: &TcpStream, buf: &mut [u8]) -> Result<(), Error> {
+	let mut offset = 0;
+	while offset < buf.len() {
+		match stream.read(&mut buf[offset..]) {
+			Ok(0) => return Err(err!(ConnectionClosed)),
+			Ok(n) => offset += n,
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use net::tcp::TcpListener;
+
+	type Step = (&'static [u8], &'static [u8]);
+
+	// accepts one connection and for each step checks that the client sends the first part
+	// and answers with the second, then echoes what follows
+	fn fake_proxy(steps: &'static [Step]) -> (u16, JoinHandle) {
+		let listener = TcpListener::bind([127, 0, 0, 1], 0, 10).unwrap();
+		let port = listener.port();
+		let jh = spawnj(move || {
+			listener.set_nonblocking(false).unwrap();
+			let stream = listener.accept().unwrap();
+			stream.set_nonblocking(false).unwrap();
+			let mut buf = [0u8; 512];
+			for (expect, reply) in steps {
+				if read_exact(&stream, &mut buf[0..expect.len()]).is_err() {
+					return;
+				}
+				assert_eq!(&buf[0..expect.len()], *expect);
+				write_all(&stream, reply).unwrap();
+			}
+			loop {
+				match stream.read(&mut buf) {
+					Ok(0) | Err(_) => break,
+					Ok(n) => write_all(&stream, &buf[0..n]).unwrap(),
+				}
+			}
+		})
+		.unwrap();
+		(port, jh)
+	}
+
+	fn echo(stream: &TcpStream) {
+		stream.set_nonblocking(false).unwrap();
+		write_all(stream, b"tunnel").unwrap();
+		let mut buf = [0u8; 6];
+		read_exact(stream, &mut buf).unwrap();
+		assert_eq!(&buf, b"tunnel");
+		stream.shutdown().unwrap();
+	}
+
+	#[test]
+	fn test_proxy_socks5() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let (port, mut jh) = fake_proxy(&[
+			(b"\x05\x01\x00", b"\x05\x00"),
+			(
+				b"\x05\x01\x00\x01\x0a\x00\x00\x07\x1f\x90",
+				b"\x05\x00\x00\x01\x7f\x00\x00\x01\x04\xd2",
+			),
+		]);
+		let proxy = ProxyConfig::socks5([127, 0, 0, 1], port);
+		assert!(proxy.kind() == ProxyKind::Socks5);
+		let stream = proxy.connect([10, 0, 0, 7], 8080).unwrap();
+		echo(&stream);
+		jh.join().unwrap();
+
+		// with credentials, then a target the proxy could not reach
+		let (port, mut jh) = fake_proxy(&[
+			(b"\x05\x02\x00\x02", b"\x05\x02"),
+			(b"\x01\x03bob\x02pw", b"\x01\x00"),
+			(
+				b"\x05\x01\x00\x01\x0a\x00\x00\x07\x1f\x90",
+				b"\x05\x05\x00\x01\x00\x00\x00\x00\x00\x00",
+			),
+		]);
+		let proxy = ProxyConfig::socks5([127, 0, 0, 1], port)
+			.with_auth("bob", "pw")
+			.unwrap();
+		let e = proxy.connect([10, 0, 0, 7], 8080).unwrap_err();
+		assert!(e.kind == ConnectionRefused);
+		jh.join().unwrap();
+
+		// rejected credentials
+		let (port, mut jh) = fake_proxy(&[
+			(b"\x05\x02\x00\x02", b"\x05\x02"),
+			(b"\x01\x03bob\x01x", b"\x01\x01"),
+		]);
+		let proxy = ProxyConfig::socks5([127, 0, 0, 1], port)
+			.with_auth("bob", "x")
+			.unwrap();
+		assert!(proxy.connect([10, 0, 0, 7], 8080).unwrap_err().kind == Unauthorized);
+		jh.join().unwrap();
+
+		assert!(ProxyConfig::direct().with_auth("", "pw").is_err());
+		assert!(ProxyConfig::direct().with_auth("a:b", "pw").is_err());
+	}
+
+	#[test]
+	fn test_proxy_http_connect() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let (port, mut jh) = fake_proxy(&[(
+			b"CONNECT 10.0.0.7:8080 HTTP/1.1\r\nHost: 10.0.0.7:8080\r\n\
+			  Proxy-Authorization: Basic Ym9iOnB3\r\n\r\n",
+			b"HTTP/1.1 200 Connection established\r\nVia: test\r\n\r\n",
+		)]);
+		let proxy = ProxyConfig::http_connect([127, 0, 0, 1], port)
+			.with_auth("bob", "pw")
+			.unwrap();
+		let stream = proxy.connect([10, 0, 0, 7], 8080).unwrap();
+		echo(&stream);
+		jh.join().unwrap();
+
+		let (port, mut jh) = fake_proxy(&[(
+			b"CONNECT 10.0.0.7:8080 HTTP/1.1\r\nHost: 10.0.0.7:8080\r\n\r\n",
+			b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+		)]);
+		let proxy = ProxyConfig::http_connect([127, 0, 0, 1], port);
+		assert!(proxy.connect([10, 0, 0, 7], 8080).unwrap_err().kind == Unauthorized);
+		jh.join().unwrap();
+
+		let (port, mut jh) = fake_proxy(&[(
+			b"CONNECT 10.0.0.7:8080 HTTP/1.1\r\nHost: 10.0.0.7:8080\r\n\r\n",
+			b"SSH-2.0-OpenSSH\r\n\r\n",
+		)]);
+		let proxy = ProxyConfig::http_connect([127, 0, 0, 1], port);
+		assert!(proxy.connect([10, 0, 0, 7], 8080).unwrap_err().kind == CorruptedData);
+		jh.join().unwrap();
+	}
+}
@@ -0,0 +1,319 @@
+//! # Reactor
+//! The epoll or kqueue multiplexer of c/net.c behind a safe interface. Sockets are
+//! registered with an interest, READ, WRITE or both, and a token, and poll fills Events
+//! with the tokens of the sockets which became ready. Registrations are edge triggered,
+//! so a socket is only reported again once more has happened on it and its owner has to
+//! read or write until WouldBlock before waiting again.
+//!
+//! Each reactor owns a pipe which Reactor::wake and its Wakers write to from any thread.
+//! poll drains it and reports an event with WAKE_TOKEN, so other threads can hand work to
+//! the thread polling. The WebSocket workers and the Executor are both built on this.
+
+use core::ptr::null;
+use ffi::{
+	open_pipe, socket_clear_pipe, socket_close, socket_event_is_read, socket_event_is_write,
+	socket_event_ptr, socket_event_size, socket_multiplex_init, socket_multiplex_register,
+	socket_multiplex_unregister, socket_multiplex_unregister_write, socket_multiplex_wait,
+	socket_send,
+};
+use net::tcp::{socket_error_kind, EAGAIN};
+use prelude::*;
+
+/// Interest in a socket having data to read or having been closed
+pub const READ: i32 = 0x1;
+/// Interest in a socket being able to take more data
+pub const WRITE: i32 = 0x2;
+/// Token of the event reported after the reactor was woken
+pub const WAKE_TOKEN: usize = usize::MAX;
+
+/// A ready socket as reported by Reactor::poll
+pub struct Event {
+	token: usize,
+	readable: bool,
+	writable: bool,
+}
+
+/// Space for the events of one Reactor::poll
+pub struct Events {
+	buf: Vec<u8>,
+	capacity: usize,
+	len: usize,
+}
+
+/// Wakes a reactor from any thread. It is only valid as long as its reactor.
+#[derive(Clone, Copy)]
+pub struct Waker {
+	// write end of the reactor's pipe
+	handle: [u8; 4],
+}
+
+pub struct Reactor {
+	mplex: [u8; 4],
+	// read end followed by write end
+	wakeup: [u8; 8],
+}
+
+impl Event {
+	pub fn token(&self) -> usize {
+		self.token
+	}
+
+	pub fn is_readable(&self) -> bool {
+		self.readable
+	}
+
+	pub fn is_writable(&self) -> bool {
+		self.writable
+	}
+}
+
+impl Events {
+	/// Room for at least one and at most capacity events per poll
+	pub fn new(capacity: usize) -> Result<Self, Error> {
+		let capacity = if capacity == 0 { 1 } else { capacity };
+		let mut buf = Vec::new();
+		match buf.resize(capacity * unsafe { socket_event_size() }) {
+			Ok(_) => Ok(Self {
+				buf,
+				capacity,
+				len: 0,
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Number of events filled in by the last poll
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn get(&self, i: usize) -> Event {
+		if i >= self.len {
+			panic!("event index out of bounds!");
+		}
+		let evt = unsafe { self.buf.as_ptr().add(i * socket_event_size()) };
+		unsafe {
+			Event {
+				token: socket_event_ptr(evt) as usize,
+				readable: socket_event_is_read(evt),
+				writable: socket_event_is_write(evt),
+			}
+		}
+	}
+}
+
+impl Waker {
+	/// Makes the reactor's current or next poll return with a WAKE_TOKEN event
+	pub fn wake(&self) -> Result<(), Error> {
+		let ret = unsafe { socket_send(&self.handle as *const u8, &b'0', 1) };
+		// a full pipe already has a wakeup pending
+		if ret == 1 || ret == EAGAIN as i64 {
+			Ok(())
+		} else {
+			Err(oserr!(IO))
+		}
+	}
+}
+
+impl Drop for Reactor {
+	fn drop(&mut self) {
+		// registrations go with the multiplexer
+		unsafe {
+			socket_close(&self.wakeup as *const u8);
+			socket_close((&self.wakeup as *const u8).add(4));
+			socket_close(&self.mplex as *const u8);
+		}
+	}
+}
+
+impl Reactor {
+	pub fn new() -> Result<Self, Error> {
+		let mut mplex = [0u8; 4];
+		let code = unsafe { socket_multiplex_init(&mut mplex as *mut u8) };
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		let mut wakeup = [0u8; 8];
+		if unsafe { open_pipe(&mut wakeup as *mut u8) } < 0 {
+			let e = oserr!(Pipe);
+			unsafe {
+				socket_close(&mplex as *const u8);
+			}
+			return Err(e);
+		}
+		let reactor = Self { mplex, wakeup };
+		let code = unsafe {
+			socket_multiplex_register(
+				&mplex as *const u8,
+				&wakeup as *const u8,
+				READ,
+				WAKE_TOKEN as *const u8,
+			)
+		};
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(reactor)
+	}
+
+	/// Reports events for handle with token. Registering a handle again adds interest
+	/// and replaces its token. IllegalArgument if interest is empty or token is 0 or
+	/// WAKE_TOKEN.
+	pub fn register(&self, handle: [u8; 4], interest: i32, token: usize) -> Result<(), Error> {
+		if interest & (READ | WRITE) == 0 || token == 0 || token == WAKE_TOKEN {
+			return Err(err!(IllegalArgument));
+		}
+		let code = unsafe {
+			socket_multiplex_register(
+				&self.mplex as *const u8,
+				&handle as *const u8,
+				interest,
+				token as *const u8,
+			)
+		};
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(())
+	}
+
+	/// Drops the WRITE interest of a handle registered with token, keeping READ
+	pub fn deregister_write(&self, handle: [u8; 4], token: usize) -> Result<(), Error> {
+		let code = unsafe {
+			socket_multiplex_unregister_write(
+				&self.mplex as *const u8,
+				&handle as *const u8,
+				token as *const u8,
+			)
+		};
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(())
+	}
+
+	/// Stops reporting events for handle. Closing a socket deregisters it as well.
+	pub fn deregister(&self, handle: [u8; 4]) -> Result<(), Error> {
+		// kqueue keeps a filter per interest, the write filter may not exist
+		unsafe {
+			socket_multiplex_unregister_write(
+				&self.mplex as *const u8,
+				&handle as *const u8,
+				null(),
+			);
+		}
+		let code =
+			unsafe { socket_multiplex_unregister(&self.mplex as *const u8, &handle as *const u8) };
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(())
+	}
+
+	/// Waits up to timeout_millis, or without limit if it is negative, for events and
+	/// returns how many were put in events. A wait interrupted by a signal returns 0.
+	pub fn poll(&self, events: &mut Events, timeout_millis: i64) -> Result<usize, Error> {
+		events.len = 0;
+		let count = unsafe {
+			socket_multiplex_wait(
+				&self.mplex as *const u8,
+				events.buf.as_mut_ptr(),
+				events.capacity as i32,
+				timeout_millis,
+			)
+		};
+		if count < 0 {
+			let e = oserr!(IO);
+			return if e.kind == Interrupted { Ok(0) } else { Err(e) };
+		}
+		events.len = count as usize;
+		for i in 0..events.len {
+			if events.get(i).token == WAKE_TOKEN {
+				unsafe {
+					socket_clear_pipe(&self.wakeup as *const u8);
+				}
+			}
+		}
+		Ok(events.len)
+	}
+
+	/// A handle which wakes this reactor from another thread
+	pub fn waker(&self) -> Waker {
+		let mut handle = [0u8; 4];
+		handle.copy_from_slice(&self.wakeup[4..8]);
+		Waker { handle }
+	}
+
+	pub fn wake(&self) -> Result<(), Error> {
+		self.waker().wake()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::{sleep_millis, socket_pair, socket_recv};
+
+	#[test]
+	fn test_reactor() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let reactor = Reactor::new().unwrap();
+		let mut events = Events::new(8).unwrap();
+		assert_eq!(reactor.poll(&mut events, 0).unwrap(), 0);
+
+		let mut a = [0u8; 4];
+		let mut b = [0u8; 4];
+		assert_eq!(
+			unsafe { socket_pair(&mut a as *mut u8, &mut b as *mut u8) },
+			0
+		);
+		assert!(reactor.register(a, READ, 0).unwrap_err().kind == IllegalArgument);
+		assert!(reactor.register(a, READ, WAKE_TOKEN).unwrap_err().kind == IllegalArgument);
+		assert!(reactor.register(a, 0, 7).unwrap_err().kind == IllegalArgument);
+		reactor.register(a, READ, 7).unwrap();
+		unsafe {
+			socket_send(&b as *const u8, b"x".as_ptr(), 1);
+		}
+		assert_eq!(reactor.poll(&mut events, 1_000).unwrap(), 1);
+		let evt = events.get(0);
+		assert_eq!(evt.token(), 7);
+		assert!(evt.is_readable() && !evt.is_writable());
+		let mut buf = [0u8; 4];
+		assert_eq!(
+			unsafe { socket_recv(&a as *const u8, buf.as_mut_ptr(), 4) },
+			1
+		);
+
+		// adding write interest reports the socket as writable at once
+		reactor.register(a, READ | WRITE, 7).unwrap();
+		assert_eq!(reactor.poll(&mut events, 1_000).unwrap(), 1);
+		assert!(events.get(0).is_writable());
+		reactor.deregister_write(a, 7).unwrap();
+		reactor.deregister(a).unwrap();
+		unsafe {
+			socket_send(&b as *const u8, b"y".as_ptr(), 1);
+		}
+		assert_eq!(reactor.poll(&mut events, 0).unwrap(), 0);
+
+		// a wake from another thread ends a poll without a timeout
+		let waker = reactor.waker();
+		let mut jh = spawnj(move || {
+			unsafe {
+				sleep_millis(10);
+			}
+			waker.wake().unwrap();
+		})
+		.unwrap();
+		assert_eq!(reactor.poll(&mut events, -1).unwrap(), 1);
+		assert_eq!(events.get(0).token(), WAKE_TOKEN);
+		jh.join().unwrap();
+		// the pipe was drained
+		assert_eq!(reactor.poll(&mut events, 0).unwrap(), 0);
+
+		unsafe {
+			socket_close(&a as *const u8);
+			socket_close(&b as *const u8);
+		}
+	}
+}
@@ -0,0 +1,761 @@
+//! # Secure
+//! SecureStream encrypts and authenticates a TcpStream. The handshake follows the Noise XX
+//! pattern with secp256k1 for the key exchanges, ChaCha20-Poly1305 as the cipher and
+//! SHA-256 as the hash: each side sends an ephemeral key, then both send their static
+//! Identity key encrypted under the secret agreed so far. Every exchange is mixed into the
+//! keys, so once the handshake completes each side knows the other holds the secret key of
+//! the public key it presented, and nothing sent before or after reveals either identity to
+//! an observer. Deciding whether that key is welcome is up to the caller, through
+//! remote_public_key.
+//!
+//! After the handshake data travels in frames of a two byte big endian length followed by
+//! the ciphertext and its tag, each with the next nonce of its direction. A frame which
+//! fails to authenticate is reported as CorruptedData and the stream should be dropped.
+//!
+//! Like TcpStream it works in either mode. A nonblocking stream returns WouldBlock from
+//! handshake, read and write until the socket is ready, so it can be driven by a Reactor or
+//! an Executor through handle() as the P2P and WebSocket layers do with plain streams.
+//! Writes are encrypted and queued in full, whatever the socket takes now is sent and
+//! flush sends the rest.
+
+use core::cmp::min;
+use net::tcp::TcpStream;
+use prelude::*;
+use secp256k1::types::{
+	ContextFlag, PublicKey, Secp256k1, SecretKey, SharedSecret, COMPRESSED_PUBLIC_KEY_SIZE,
+};
+use std::aead::{open, seal, KEY_SIZE, NONCE_SIZE, TAG_SIZE};
+use std::cpsrng::Cpsrng;
+use std::hash::{hmac_sha256, sha256, SHA256_SIZE};
+
+pub const PROTOCOL_NAME: &[u8] = b"Noise_XX_secp256k1_ChaChaPoly_SHA256";
+/// Largest payload of one transport frame, longer writes are split
+pub const MAX_PAYLOAD: usize = 65535 - TAG_SIZE;
+
+// -> e
+const MSG1_SIZE: usize = COMPRESSED_PUBLIC_KEY_SIZE;
+// <- e, ee, s, es
+const MSG2_SIZE: usize = COMPRESSED_PUBLIC_KEY_SIZE * 2 + TAG_SIZE;
+// -> s, se
+const MSG3_SIZE: usize = COMPRESSED_PUBLIC_KEY_SIZE + TAG_SIZE;
+// handshake messages sent or received
+const ESTABLISHED: u8 = 3;
+const LEN_SIZE: usize = 2;
+const READ_SIZE: usize = 4096;
+
+/// The long term key pair a node is known by. Shared between streams, typically in an Arc.
+pub struct Identity {
+	secp: Secp256k1,
+	secret: SecretKey,
+	public: [u8; COMPRESSED_PUBLIC_KEY_SIZE],
+}
+
+pub struct SecureStream {
+	stream: TcpStream,
+	identity: Arc<Identity>,
+	initiator: bool,
+	step: u8,
+	ephemeral: SecretKey,
+	remote_ephemeral: PublicKey,
+	remote_static: [u8; COMPRESSED_PUBLIC_KEY_SIZE],
+	// handshake state, the chaining key, transcript hash and current cipher key
+	ck: [u8; SHA256_SIZE],
+	h: [u8; SHA256_SIZE],
+	k: [u8; KEY_SIZE],
+	n: u64,
+	send_key: [u8; KEY_SIZE],
+	recv_key: [u8; KEY_SIZE],
+	send_nonce: u64,
+	recv_nonce: u64,
+	// ciphertext received and not yet decrypted
+	rbuf: Vec<u8>,
+	// plaintext decrypted and not yet read
+	plain: Deque<u8>,
+	// ciphertext not yet sent
+	wbuf: Deque<u8>,
+	eof: bool,
+}
+
+impl Identity {
+	/// A new random identity
+	pub fn generate() -> Result<Self, Error> {
+		let secp = match Secp256k1::with_caps(ContextFlag::SignOnly) {
+			Ok(secp) => secp,
+			Err(e) => return Err(e),
+		};
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		let (secret, _) = match secp.generate_keypair(&rand) {
+			Ok(pair) => pair,
+			Err(e) => return Err(e),
+		};
+		Self::from_secret(secp, secret)
+	}
+
+	/// The identity of a stored secret key. InvalidPublicKey if it is not a valid key.
+	pub fn from_secret(secp: Secp256k1, secret: SecretKey) -> Result<Self, Error> {
+		let public = match PublicKey::from_secret_key(&secp, &secret) {
+			Ok(pk) => match pk.serialize_compressed(&secp) {
+				Ok(public) => public,
+				Err(e) => return Err(e),
+			},
+			Err(e) => return Err(e),
+		};
+		Ok(Self {
+			secp,
+			secret,
+			public,
+		})
+	}
+
+	/// The compressed public key peers see
+	pub fn public_key(&self) -> [u8; COMPRESSED_PUBLIC_KEY_SIZE] {
+		self.public
+	}
+}
+
+impl SecureStream {
+	/// Wraps stream, taking the initiator's side of the handshake if initiator is true and
+	/// the responder's otherwise. The handshake runs in handshake, or the first read or
+	/// write.
+	pub fn new(stream: TcpStream, identity: Arc<Identity>, initiator: bool) -> Result<Self, Error> {
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		let ephemeral = match identity.secp.generate_keypair(&rand) {
+			Ok((sk, _)) => sk,
+			Err(e) => return Err(e),
+		};
+		// the name is longer than a hash, so it is hashed, followed by an empty prologue
+		let h = sha256(PROTOCOL_NAME);
+		let mut ret = Self {
+			stream,
+			identity,
+			initiator,
+			step: 0,
+			ephemeral,
+			remote_ephemeral: PublicKey::new(),
+			remote_static: [0u8; COMPRESSED_PUBLIC_KEY_SIZE],
+			ck: h,
+			h,
+			k: [0u8; KEY_SIZE],
+			n: 0,
+			send_key: [0u8; KEY_SIZE],
+			recv_key: [0u8; KEY_SIZE],
+			send_nonce: 0,
+			recv_nonce: 0,
+			rbuf: Vec::new(),
+			plain: Deque::new(),
+			wbuf: Deque::new(),
+			eof: false,
+		};
+		ret.mix_hash(&[]);
+		if initiator {
+			let e = match ret.ephemeral_public() {
+				Ok(e) => e,
+				Err(e) => return Err(e),
+			};
+			ret.mix_hash(&e);
+			match ret.wbuf.extend_from_slice(&e) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			ret.step = 1;
+		}
+		Ok(ret)
+	}
+
+	/// Runs the handshake until it completes. WouldBlock if a nonblocking stream has to
+	/// wait for the peer, ConnectionClosed if the peer closed the connection first and
+	/// CorruptedData or InvalidPublicKey if it sent something else than the protocol.
+	pub fn handshake(&mut self) -> Result<(), Error> {
+		loop {
+			match self.flush() {
+				Ok(_) => {}
+				Err(e) => {
+					if e.kind != WouldBlock {
+						return Err(e);
+					}
+				}
+			}
+			if self.step == ESTABLISHED {
+				return Ok(());
+			}
+			let expected = match self.step {
+				0 => MSG1_SIZE,
+				1 => MSG2_SIZE,
+				_ => MSG3_SIZE,
+			};
+			if self.rbuf.len() < expected {
+				match self.fill() {
+					Ok(true) => continue,
+					Ok(false) => return Err(err!(ConnectionClosed)),
+					Err(e) => return Err(e),
+				}
+			}
+			let mut msg = [0u8; MSG2_SIZE];
+			msg[0..expected].copy_from_slice(&self.rbuf[0..expected]);
+			// cannot be an error, the buffer holds at least expected bytes
+			let _ = self.rbuf.shift(expected);
+			let res = match self.step {
+				0 => self.read_msg1(&msg[0..expected]),
+				1 => self.read_msg2(&msg[0..expected]),
+				_ => self.read_msg3(&msg[0..expected]),
+			};
+			match res {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	pub fn is_established(&self) -> bool {
+		self.step == ESTABLISHED
+	}
+
+	/// The peer's static public key, once the handshake has completed
+	pub fn remote_public_key(&self) -> Option<[u8; COMPRESSED_PUBLIC_KEY_SIZE]> {
+		if self.step == ESTABLISHED {
+			Some(self.remote_static)
+		} else {
+			None
+		}
+	}
+
+	/// Reads decrypted data into buf, completing the handshake first if needed. Returns
+	/// the number of bytes read, 0 once the peer has shut down its side, WouldBlock if a
+	/// nonblocking stream has nothing to read and CorruptedData if a frame was forged or
+	/// damaged.
+	pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+		match self.handshake() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		loop {
+			if self.plain.len() > 0 {
+				let n = min(buf.len(), self.plain.front_slice().len());
+				buf[0..n].copy_from_slice(&self.plain.front_slice()[0..n]);
+				// cannot be an error, n is at most the length of the front slice
+				let _ = self.plain.shift(n);
+				return Ok(n);
+			}
+			match self.decrypt_frames() {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			if self.plain.len() > 0 {
+				continue;
+			}
+			if self.eof {
+				// a partial frame means the connection was cut
+				return if self.rbuf.len() == 0 {
+					Ok(0)
+				} else {
+					Err(err!(ConnectionClosed))
+				};
+			}
+			match self.fill() {
+				Ok(true) => {}
+				Ok(false) => self.eof = true,
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	/// Encrypts and queues all of buf, completing the handshake first if needed, and sends
+	/// what the socket takes. Returns buf.len(). WouldBlock only while the handshake is
+	/// waiting on the peer.
+	pub fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+		match self.handshake() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		for chunk in buf.chunks(MAX_PAYLOAD) {
+			match self.encrypt_frame(chunk) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		match self.flush() {
+			Ok(_) => Ok(buf.len()),
+			Err(e) => {
+				if e.kind == WouldBlock {
+					Ok(buf.len())
+				} else {
+					Err(e)
+				}
+			}
+		}
+	}
+
+	/// Sends queued data. WouldBlock if a nonblocking stream could not take all of it.
+	pub fn flush(&mut self) -> Result<(), Error> {
+		while self.wbuf.len() > 0 {
+			match self.stream.write(self.wbuf.front_slice()) {
+				Ok(n) => {
+					// cannot be an error, n is at most the length of the front slice
+					let _ = self.wbuf.shift(n);
+				}
+				Err(e) => return Err(e),
+			}
+		}
+		// start the next write at the front of a buffer so it goes out in one send
+		self.wbuf.clear();
+		Ok(())
+	}
+
+	/// Number of encrypted bytes waiting to be sent
+	pub fn pending(&self) -> usize {
+		self.wbuf.len()
+	}
+
+	pub fn shutdown(&self) -> Result<(), Error> {
+		self.stream.shutdown()
+	}
+
+	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+		self.stream.set_nonblocking(nonblocking)
+	}
+
+	/// The socket handle, for Reactor::register and Context::readable
+	pub fn handle(&self) -> [u8; 4] {
+		self.stream.handle()
+	}
+
+	/// The underlying stream, with whatever is buffered on either side dropped
+	pub fn into_inner(self) -> TcpStream {
+		self.stream
+	}
+
+	// responder: -> e
+	fn read_msg1(&mut self, msg: &[u8]) -> Result<(), Error> {
+		match self.read_ephemeral(msg) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		// <- e, ee, s, es
+		let e = match self.ephemeral_public() {
+			Ok(e) => e,
+			Err(e) => return Err(e),
+		};
+		self.mix_hash(&e);
+		let ee = match self.dh(&self.ephemeral, &self.remote_ephemeral) {
+			Ok(ee) => ee,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(ee.as_bytes());
+		let public = self.identity.public;
+		let s = self.encrypt_and_hash(&public);
+		let es = match self.dh(&self.identity.secret, &self.remote_ephemeral) {
+			Ok(es) => es,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(es.as_bytes());
+		match self.wbuf.extend_from_slice(&e) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self.wbuf.extend_from_slice(&s) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.step = 2;
+		Ok(())
+	}
+
+	// initiator: <- e, ee, s, es
+	fn read_msg2(&mut self, msg: &[u8]) -> Result<(), Error> {
+		match self.read_ephemeral(&msg[0..COMPRESSED_PUBLIC_KEY_SIZE]) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let ee = match self.dh(&self.ephemeral, &self.remote_ephemeral) {
+			Ok(ee) => ee,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(ee.as_bytes());
+		let remote = match self.read_static(&msg[COMPRESSED_PUBLIC_KEY_SIZE..]) {
+			Ok(remote) => remote,
+			Err(e) => return Err(e),
+		};
+		let es = match self.dh(&self.ephemeral, &remote) {
+			Ok(es) => es,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(es.as_bytes());
+
+		// -> s, se
+		let public = self.identity.public;
+		let s = self.encrypt_and_hash(&public);
+		let se = match self.dh(&self.identity.secret, &self.remote_ephemeral) {
+			Ok(se) => se,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(se.as_bytes());
+		match self.wbuf.extend_from_slice(&s) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.split();
+		Ok(())
+	}
+
+	// responder: -> s, se
+	fn read_msg3(&mut self, msg: &[u8]) -> Result<(), Error> {
+		let remote = match self.read_static(msg) {
+			Ok(remote) => remote,
+			Err(e) => return Err(e),
+		};
+		let se = match self.dh(&self.ephemeral, &remote) {
+			Ok(se) => se,
+			Err(e) => return Err(e),
+		};
+		self.mix_key(se.as_bytes());
+		self.split();
+		Ok(())
+	}
+
+	fn read_ephemeral(&mut self, data: &[u8]) -> Result<(), Error> {
+		let mut e = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		e.copy_from_slice(data);
+		self.remote_ephemeral = match PublicKey::from_compressed(&self.identity.secp, &e) {
+			Ok(pk) => pk,
+			Err(e) => return Err(e),
+		};
+		self.mix_hash(&e);
+		Ok(())
+	}
+
+	fn read_static(&mut self, data: &[u8]) -> Result<PublicKey, Error> {
+		let s = match self.decrypt_and_hash(data) {
+			Ok(s) => s,
+			Err(e) => return Err(e),
+		};
+		let pk = match PublicKey::from_compressed(&self.identity.secp, &s) {
+			Ok(pk) => pk,
+			Err(e) => return Err(e),
+		};
+		self.remote_static = s;
+		Ok(pk)
+	}
+
+	fn ephemeral_public(&self) -> Result<[u8; COMPRESSED_PUBLIC_KEY_SIZE], Error> {
+		match PublicKey::from_secret_key(&self.identity.secp, &self.ephemeral) {
+			Ok(pk) => pk.serialize_compressed(&self.identity.secp),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn dh(&self, sk: &SecretKey, pk: &PublicKey) -> Result<SharedSecret, Error> {
+		SharedSecret::ecdh(&self.identity.secp, pk, sk)
+	}
+
+	fn mix_hash(&mut self, data: &[u8]) {
+		// at most a static key and its tag are mixed in at once
+		let mut buf = [0u8; SHA256_SIZE + MSG3_SIZE];
+		buf[0..SHA256_SIZE].copy_from_slice(&self.h);
+		buf[SHA256_SIZE..SHA256_SIZE + data.len()].copy_from_slice(data);
+		self.h = sha256(&buf[0..SHA256_SIZE + data.len()]);
+	}
+
+	fn mix_key(&mut self, ikm: &[u8]) {
+		let (ck, k) = hkdf(&self.ck, ikm);
+		self.ck = ck;
+		self.k = k;
+		self.n = 0;
+	}
+
+	fn encrypt_and_hash(&mut self, s: &[u8; COMPRESSED_PUBLIC_KEY_SIZE]) -> [u8; MSG3_SIZE] {
+		let mut ret = [0u8; MSG3_SIZE];
+		ret[0..COMPRESSED_PUBLIC_KEY_SIZE].copy_from_slice(s);
+		let tag = seal(
+			&self.k,
+			&nonce(self.n),
+			&self.h,
+			&mut ret[0..COMPRESSED_PUBLIC_KEY_SIZE],
+		);
+		ret[COMPRESSED_PUBLIC_KEY_SIZE..].copy_from_slice(&tag);
+		self.n += 1;
+		self.mix_hash(&ret);
+		ret
+	}
+
+	fn decrypt_and_hash(&mut self, data: &[u8]) -> Result<[u8; COMPRESSED_PUBLIC_KEY_SIZE], Error> {
+		let mut ret = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		ret.copy_from_slice(&data[0..COMPRESSED_PUBLIC_KEY_SIZE]);
+		let mut tag = [0u8; TAG_SIZE];
+		tag.copy_from_slice(&data[COMPRESSED_PUBLIC_KEY_SIZE..]);
+		match open(&self.k, &nonce(self.n), &self.h, &mut ret, &tag) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.n += 1;
+		self.mix_hash(data);
+		Ok(ret)
+	}
+
+	// derives the transport keys, the initiator sends with the first
+	fn split(&mut self) {
+		let (k1, k2) = hkdf(&self.ck, &[]);
+		if self.initiator {
+			self.send_key = k1;
+			self.recv_key = k2;
+		} else {
+			self.send_key = k2;
+			self.recv_key = k1;
+		}
+		self.ck = [0u8; SHA256_SIZE];
+		self.k = [0u8; KEY_SIZE];
+		self.step = ESTABLISHED;
+	}
+
+	fn encrypt_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+		let len = data.len() + TAG_SIZE;
+		let mut frame = Vec::new();
+		match frame.resize(LEN_SIZE + len) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		frame[0..LEN_SIZE].copy_from_slice(&(len as u16).to_be_bytes());
+		frame[LEN_SIZE..LEN_SIZE + data.len()].copy_from_slice(data);
+		let tag = seal(
+			&self.send_key,
+			&nonce(self.send_nonce),
+			&[],
+			&mut frame[LEN_SIZE..LEN_SIZE + data.len()],
+		);
+		frame[LEN_SIZE + data.len()..LEN_SIZE + len].copy_from_slice(&tag);
+		self.send_nonce += 1;
+		self.wbuf.extend_from_slice(frame.as_slice())
+	}
+
+	// moves every complete frame of rbuf to plain
+	fn decrypt_frames(&mut self) -> Result<(), Error> {
+		while self.rbuf.len() >= LEN_SIZE {
+			let len = u16::from_be_bytes([self.rbuf[0], self.rbuf[1]]) as usize;
+			if len < TAG_SIZE {
+				return Err(err!(CorruptedData));
+			}
+			if self.rbuf.len() < LEN_SIZE + len {
+				break;
+			}
+			let end = LEN_SIZE + len - TAG_SIZE;
+			let mut tag = [0u8; TAG_SIZE];
+			tag.copy_from_slice(&self.rbuf[end..end + TAG_SIZE]);
+			match open(
+				&self.recv_key,
+				&nonce(self.recv_nonce),
+				&[],
+				&mut self.rbuf[LEN_SIZE..end],
+				&tag,
+			) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			self.recv_nonce += 1;
+			match self.plain.extend_from_slice(&self.rbuf[LEN_SIZE..end]) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			// cannot be an error, the frame is in the buffer
+			let _ = self.rbuf.shift(LEN_SIZE + len);
+		}
+		Ok(())
+	}
+
+	// one read from the socket, false once the peer has closed its side
+	fn fill(&mut self) -> Result<bool, Error> {
+		let mut buf = [0u8; READ_SIZE];
+		match self.stream.read(&mut buf) {
+			Ok(0) => Ok(false),
+			Ok(n) => match self.rbuf.append_ptr(buf.as_ptr(), n) {
+				Ok(_) => Ok(true),
+				Err(e) => Err(e),
+			},
+			Err(e) => Err(e),
+		}
+	}
+}
+
+// 32 bits of zeros followed by the little endian counter
+fn nonce(n: u64) -> [u8; NONCE_SIZE] {
+	let mut ret = [0u8; NONCE_SIZE];
+	ret[4..].copy_from_slice(&n.to_le_bytes());
+	ret
+}
+
+// HKDF with the chaining key as salt and two outputs
+fn hkdf(ck: &[u8; SHA256_SIZE], ikm: &[u8]) -> ([u8; SHA256_SIZE], [u8; SHA256_SIZE]) {
+	let prk = hmac_sha256(ck, ikm);
+	let out1 = hmac_sha256(&prk, &[1]);
+	let mut buf = [0u8; SHA256_SIZE + 1];
+	buf[0..SHA256_SIZE].copy_from_slice(&out1);
+	buf[SHA256_SIZE] = 2;
+	(out1, hmac_sha256(&prk, &buf))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::sleep_millis;
+	use net::tcp::TcpListener;
+
+	fn pair() -> (TcpStream, TcpStream) {
+		let listener = TcpListener::bind([127, 0, 0, 1], 0, 10).unwrap();
+		let client = TcpStream::connect([127, 0, 0, 1], listener.port()).unwrap();
+		listener.set_nonblocking(false).unwrap();
+		let server = listener.accept().unwrap();
+		(client, server)
+	}
+
+	fn handshake(a: &mut SecureStream, b: &mut SecureStream) {
+		for _ in 0..100 {
+			unsafe {
+				sleep_millis(1);
+			}
+			let ra = a.handshake();
+			let rb = b.handshake();
+			if ra.is_ok() && rb.is_ok() {
+				return;
+			}
+			assert!(ra.is_ok() || ra.unwrap_err().kind == WouldBlock);
+			assert!(rb.is_ok() || rb.unwrap_err().kind == WouldBlock);
+		}
+		panic!("handshake did not complete");
+	}
+
+	// moves what is waiting on from to to, flipping a bit of the byte at offset corrupt
+	fn relay(from: &TcpStream, to: &TcpStream, corrupt: Option<usize>) -> usize {
+		let mut buf = [0u8; 1024];
+		match from.read(&mut buf) {
+			Ok(n) => {
+				match corrupt {
+					Some(i) if i < n => buf[i] ^= 1,
+					_ => {}
+				}
+				assert_eq!(to.write(&buf[0..n]).unwrap(), n);
+				n
+			}
+			Err(_) => 0,
+		}
+	}
+
+	#[test]
+	fn test_secure_stream() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let alice = Arc::new(Identity::generate().unwrap()).unwrap();
+		let bob = Arc::new(Identity::generate().unwrap()).unwrap();
+		assert!(alice.public_key() != bob.public_key());
+		let (c, s) = pair();
+		let mut client = SecureStream::new(c, alice.clone().unwrap(), true).unwrap();
+		let mut server = SecureStream::new(s, bob.clone().unwrap(), false).unwrap();
+		assert!(!client.is_established() && client.remote_public_key().is_none());
+		let mut buf = [0u8; 64];
+		assert!(server.read(&mut buf).unwrap_err().kind == WouldBlock);
+
+		handshake(&mut client, &mut server);
+		assert!(client.is_established() && server.is_established());
+		assert_eq!(client.remote_public_key().unwrap(), bob.public_key());
+		assert_eq!(server.remote_public_key().unwrap(), alice.public_key());
+
+		assert_eq!(client.write(b"hello").unwrap(), 5);
+		server.set_nonblocking(false).unwrap();
+		assert_eq!(server.read(&mut buf).unwrap(), 5);
+		assert_eq!(&buf[0..5], b"hello");
+		server.write(b"world").unwrap();
+		client.set_nonblocking(false).unwrap();
+		assert_eq!(client.read(&mut buf).unwrap(), 5);
+		assert_eq!(&buf[0..5], b"world");
+
+		// a write longer than a frame arrives in order
+		let mut big = Vec::new();
+		for i in 0..200_000u32 {
+			big.push((i % 251) as u8).unwrap();
+		}
+		client.set_nonblocking(true).unwrap();
+		server.set_nonblocking(true).unwrap();
+		assert_eq!(client.write(big.as_slice()).unwrap(), big.len());
+		let mut received = 0;
+		let mut rbuf = [0u8; 10_000];
+		while received < big.len() {
+			let _ = client.flush();
+			match server.read(&mut rbuf) {
+				Ok(n) => {
+					assert!(n > 0);
+					assert_eq!(&rbuf[0..n], &big[received..received + n]);
+					received += n;
+				}
+				Err(e) => assert!(e.kind == WouldBlock),
+			}
+		}
+		assert_eq!(client.pending(), 0);
+
+		client.shutdown().unwrap();
+		server.set_nonblocking(false).unwrap();
+		assert_eq!(server.read(&mut buf).unwrap(), 0);
+	}
+
+	#[test]
+	fn test_secure_stream_tampered() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let alice = Arc::new(Identity::generate().unwrap()).unwrap();
+		let bob = Arc::new(Identity::generate().unwrap()).unwrap();
+
+		// a peer which is not speaking the protocol
+		let (c, s) = pair();
+		let mut server = SecureStream::new(s, bob.clone().unwrap(), false).unwrap();
+		c.write(&[5u8; MSG1_SIZE]).unwrap();
+		server.set_nonblocking(false).unwrap();
+		assert!(server.handshake().unwrap_err().kind == InvalidPublicKey);
+
+		// a relay which changes a bit of the responder's encrypted static key
+		let (c, p1) = pair();
+		let (p2, s) = pair();
+		let mut client = SecureStream::new(c, alice.clone().unwrap(), true).unwrap();
+		let mut server = SecureStream::new(s, bob.clone().unwrap(), false).unwrap();
+		let mut kind = WouldBlock;
+		for _ in 0..100 {
+			unsafe {
+				sleep_millis(1);
+			}
+			let _ = server.handshake();
+			relay(&p1, &p2, None);
+			relay(&p2, &p1, Some(MSG1_SIZE + 1));
+			kind = client.handshake().unwrap_err().kind;
+			if kind != WouldBlock {
+				break;
+			}
+		}
+		assert!(kind == CorruptedData);
+
+		// and one which changes a frame after the handshake
+		let (c, p1) = pair();
+		let (p2, s) = pair();
+		let mut client = SecureStream::new(c, alice.clone().unwrap(), true).unwrap();
+		let mut server = SecureStream::new(s, bob.clone().unwrap(), false).unwrap();
+		for _ in 0..100 {
+			unsafe {
+				sleep_millis(1);
+			}
+			let _ = client.handshake();
+			let _ = server.handshake();
+			relay(&p1, &p2, None);
+			relay(&p2, &p1, None);
+			if client.is_established() && server.is_established() {
+				break;
+			}
+		}
+		assert!(client.is_established() && server.is_established());
+		client.write(b"transfer 10 coins").unwrap();
+		p1.set_nonblocking(false).unwrap();
+		assert!(relay(&p1, &p2, Some(LEN_SIZE + 9)) > 0);
+		server.set_nonblocking(false).unwrap();
+		let mut buf = [0u8; 64];
+		assert!(server.read(&mut buf).unwrap_err().kind == CorruptedData);
+	}
+}
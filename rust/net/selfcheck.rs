@@ -0,0 +1,503 @@
+//! # Self check
+//! A synchronous probe of a running echo server for smoke testing a deployment. The probe
+//! speaks the protocol over a plain socket rather than through a WebSocket client so that
+//! it can send the frames our client never produces: masked, fragmented and control frames.
+//! The server is expected to run echo_handler, which returns every data frame with the same
+//! opcode, fin bit and payload, answers a ping with a pong and a close with a close.
+
+use core::str::from_utf8_unchecked;
+use ffi::{sleep_millis, socket_close, socket_connect, socket_recv, socket_send, Base64encode};
+use net::ws::*;
+use prelude::*;
+use std::cpsrng::Cpsrng;
+
+// a step which has not completed after this long fails
+const STEP_TIMEOUT_MICROS: i64 = 5_000_000;
+// return code of socket_send and socket_recv when the socket would block
+const EAGAIN: i64 = -11;
+const ACCEPT_PREFIX: &[u8] = "Sec-WebSocket-Accept: ".as_bytes();
+const PING_PAYLOAD: &[u8] = "fam self check".as_bytes();
+const SKIPPED: &str = "skipped after an earlier failure";
+
+// the sizes straddle the boundaries between the 7 bit, 16 bit and 64 bit length encodings
+const STEPS: [&str; 9] = [
+	"handshake",
+	"text 0",
+	"text 125",
+	"text 126",
+	"binary 65535",
+	"binary 65536",
+	"fragmented",
+	"ping",
+	"close",
+];
+
+/// Outcome of one step of WebSocket::self_check
+pub struct SelfCheckStep {
+	pub name: &'static str,
+	pub passed: bool,
+	/// Why the step failed, empty if it passed
+	pub detail: &'static str,
+	/// Time from sending the first frame of the step to receiving the last reply
+	pub micros: i64,
+}
+
+/// The steps of a self check in the order they ran
+pub struct SelfCheckReport {
+	pub steps: Vec<SelfCheckStep>,
+}
+
+// an empty Vec has no buffer to take a slice of
+fn as_bytes(v: &Vec<u8>) -> &[u8] {
+	if v.len() == 0 {
+		&[]
+	} else {
+		v.as_slice()
+	}
+}
+
+struct Frame {
+	fin: bool,
+	op: u8,
+	payload: Vec<u8>,
+}
+
+struct Probe {
+	handle: [u8; 4],
+	rand: Cpsrng,
+	rbuf: Vec<u8>,
+}
+
+impl Display for SelfCheckReport {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		for step in &self.steps {
+			let res = if step.passed {
+				writeb!(*f, "{}: ok ({}us)\n", step.name, step.micros)
+			} else {
+				writeb!(
+					*f,
+					"{}: FAILED {} ({}us)\n",
+					step.name,
+					step.detail,
+					step.micros
+				)
+			};
+			match res {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		writeb!(
+			*f,
+			"passed {}/{}",
+			self.steps.len() - self.failures(),
+			self.steps.len()
+		)
+	}
+}
+
+impl SelfCheckReport {
+	pub fn passed(&self) -> bool {
+		self.failures() == 0
+	}
+
+	pub fn failures(&self) -> usize {
+		let mut failures = 0;
+		for step in &self.steps {
+			if !step.passed {
+				failures += 1;
+			}
+		}
+		failures
+	}
+}
+
+impl Drop for Probe {
+	fn drop(&mut self) {
+		unsafe {
+			socket_close(&self.handle as *const u8);
+		}
+	}
+}
+
+impl Probe {
+	fn connect(addr: [u8; 4], port: u16) -> Result<Self, Error> {
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		let mut handle = [0u8; 4];
+		if unsafe { socket_connect(&mut handle as *mut u8, &addr as *const u8, port as i32) } < 0 {
+			return Err(oserr!(Connect));
+		}
+		Ok(Self {
+			handle,
+			rand,
+			rbuf: Vec::new(),
+		})
+	}
+
+	fn write_all(&mut self, bytes: &[u8], deadline: i64) -> Result<(), &'static str> {
+		let mut offset = 0;
+		while offset < bytes.len() {
+			let n = unsafe {
+				socket_send(
+					&self.handle as *const u8,
+					bytes.as_ptr().add(offset),
+					bytes.len() - offset,
+				)
+			};
+			if n > 0 {
+				offset += n as usize;
+			} else if n != EAGAIN {
+				return Err("send failed");
+			} else if getmicros!() >= deadline {
+				return Err("timed out");
+			} else {
+				unsafe {
+					sleep_millis(1);
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// appends whatever the server has sent to rbuf, waiting for at least one byte
+	fn fill(&mut self, deadline: i64) -> Result<(), &'static str> {
+		let mut buf = [0u8; 16 * 1024];
+		loop {
+			let n = unsafe { socket_recv(&self.handle as *const u8, buf.as_mut_ptr(), buf.len()) };
+			if n > 0 {
+				return match self.rbuf.append_ptr(buf.as_ptr(), n as usize) {
+					Ok(_) => Ok(()),
+					Err(_e) => Err("out of memory"),
+				};
+			} else if n == 0 {
+				return Err("connection closed");
+			} else if n != EAGAIN {
+				return Err("receive failed");
+			} else if getmicros!() >= deadline {
+				return Err("timed out");
+			}
+			unsafe {
+				sleep_millis(1);
+			}
+		}
+	}
+
+	fn handshake(&mut self, deadline: i64) -> Result<(), &'static str> {
+		let mut nonce = [0u8; 16];
+		self.rand.fill(&mut nonce);
+		let mut key = [0u8; 24];
+		unsafe {
+			Base64encode(key.as_mut_ptr(), nonce.as_mut_ptr(), nonce.len());
+		}
+		let mut request = StringBuilder::new();
+		// base64 output is ascii
+		if request
+			.push_strs(&[
+				"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: ",
+				unsafe { from_utf8_unchecked(&key) },
+				"\r\nSec-WebSocket-Version: 13\r\n\r\n",
+			])
+			.is_err()
+		{
+			return Err("out of memory");
+		}
+		match self.write_all(request.as_str().as_bytes(), deadline) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+
+		loop {
+			let len = self.rbuf.len();
+			for i in 3..len {
+				if &self.rbuf[i - 3..i + 1] == b"\r\n\r\n" {
+					let response = &self.rbuf[0..i + 1];
+					if response.len() < 12 || &response[0..12] != b"HTTP/1.1 101" {
+						return Err("upgrade refused");
+					}
+					let expected = WebSocket::handle_websocket_handshake(&key);
+					match WebSocket::header_value(response, ACCEPT_PREFIX) {
+						Some(accept) if accept == &expected => {}
+						_ => return Err("wrong Sec-WebSocket-Accept"),
+					}
+					let _ = self.rbuf.shift(i + 1);
+					return Ok(());
+				}
+			}
+			match self.fill(deadline) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	// sends payload in a single frame masked with a fresh key, as clients must
+	fn send_frame(&mut self, b1: u8, payload: &[u8], deadline: i64) -> Result<(), &'static str> {
+		let mut header = [0u8; 10];
+		let header_len = encode_frame_header(b1, payload.len(), &mut header);
+		header[1] |= 0x80;
+		let mut masking_key = [0u8; 4];
+		self.rand.fill(&mut masking_key);
+
+		let mut frame = Vec::new();
+		let res = match frame.append_ptr(header.as_ptr(), header_len) {
+			Ok(_) => match frame.append_ptr(masking_key.as_ptr(), 4) {
+				Ok(_) => frame.append_ptr(payload.as_ptr(), payload.len()),
+				Err(e) => Err(e),
+			},
+			Err(e) => Err(e),
+		};
+		if res.is_err() {
+			return Err("out of memory");
+		}
+		let offset = header_len + 4;
+		unmask(&mut frame.as_mut_slice()[offset..], &masking_key);
+		self.write_all(frame.as_slice(), deadline)
+	}
+
+	fn recv_frame(&mut self, deadline: i64) -> Result<Frame, &'static str> {
+		loop {
+			let header = match decode_frame_header(self.rbuf.as_slice()) {
+				Ok(header) => header,
+				Err(_e) => return Err("malformed frame"),
+			};
+			match header {
+				Some(header) if self.rbuf.len() >= header.offset + header.payload_len => {
+					let mut payload = Vec::new();
+					let end = header.offset + header.payload_len;
+					let start = &self.rbuf[header.offset..end];
+					if payload.append_ptr(start.as_ptr(), start.len()).is_err() {
+						return Err("out of memory");
+					}
+					match header.masking_key {
+						Some(masking_key) if start.len() > 0 => {
+							unmask(payload.as_mut_slice(), &masking_key)
+						}
+						_ => {}
+					}
+					let _ = self.rbuf.shift(end);
+					return Ok(Frame {
+						fin: header.fin,
+						op: header.op,
+						payload,
+					});
+				}
+				_ => {}
+			}
+			match self.fill(deadline) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	// sends each frame then expects them back in order with the same opcode, fin bit and
+	// payload
+	fn echo(&mut self, frames: &[(u8, &[u8])], deadline: i64) -> Result<(), &'static str> {
+		for (b1, payload) in frames {
+			match self.send_frame(*b1, payload, deadline) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		for (b1, payload) in frames {
+			let frame = match self.recv_frame(deadline) {
+				Ok(frame) => frame,
+				Err(e) => return Err(e),
+			};
+			if frame.op == 0x8 {
+				return Err("closed by server");
+			} else if frame.op != *b1 & 0x0F || frame.fin != (*b1 & 0x80 != 0) {
+				return Err("unexpected frame type");
+			} else if as_bytes(&frame.payload) != *payload {
+				return Err("payload mismatch");
+			}
+		}
+		Ok(())
+	}
+
+	fn echo_sized(&mut self, op: u8, len: usize, deadline: i64) -> Result<(), &'static str> {
+		let mut payload = Vec::new();
+		if payload.resize(len).is_err() {
+			return Err("out of memory");
+		}
+		// printable so that text frames carry valid utf8
+		for i in 0..len {
+			payload[i] = b'a' + (i % 26) as u8;
+		}
+		self.echo(&[(0x80 | op, as_bytes(&payload))], deadline)
+	}
+
+	fn ping(&mut self, deadline: i64) -> Result<(), &'static str> {
+		match self.send_frame(0x89, PING_PAYLOAD, deadline) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self.recv_frame(deadline) {
+			Ok(frame) => {
+				if frame.op != 0xA {
+					Err("no pong")
+				} else if as_bytes(&frame.payload) != PING_PAYLOAD {
+					Err("payload mismatch")
+				} else {
+					Ok(())
+				}
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	// the server must answer with a close frame and then disconnect
+	fn close(&mut self, deadline: i64) -> Result<(), &'static str> {
+		match self.send_frame(0x88, &[0x03, 0xE8], deadline) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self.recv_frame(deadline) {
+			Ok(frame) if frame.op == 0x8 => {}
+			Ok(_) => return Err("no close"),
+			Err(e) => return Err(e),
+		}
+		loop {
+			self.rbuf.clear();
+			match self.fill(deadline) {
+				Ok(_) => {}
+				Err("connection closed") => return Ok(()),
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	fn run_step(&mut self, step: usize, deadline: i64) -> Result<(), &'static str> {
+		match step {
+			0 => self.handshake(deadline),
+			1 => self.echo_sized(0x1, 0, deadline),
+			2 => self.echo_sized(0x1, 125, deadline),
+			3 => self.echo_sized(0x1, 126, deadline),
+			4 => self.echo_sized(0x2, 65535, deadline),
+			5 => self.echo_sized(0x2, 65536, deadline),
+			6 => self.echo(
+				&[
+					(0x01, "frag".as_bytes()),
+					(0x00, "ment".as_bytes()),
+					(0x80, "ed".as_bytes()),
+				],
+				deadline,
+			),
+			7 => self.ping(deadline),
+			_ => self.close(deadline),
+		}
+	}
+}
+
+/// A handler which serves self_check: data frames are returned as they arrived so that
+/// fragments are relayed one by one. Pings and closes are answered by the server itself,
+/// see WsConfig::auto_control_frames.
+pub fn echo_handler() -> Result<Handler, Error> {
+	match Box::new(
+		move |req: WsRequest, mut resp: WsResponse| match req.opcode() {
+			WsOpcode::Continuation | WsOpcode::Text | WsOpcode::Binary => {
+				resp.send_frame(req.op(), req.fin(), req.msg())
+			}
+			_ => Ok(()),
+		},
+	) {
+		Ok(b) => Ok(b),
+		Err(e) => Err(e),
+	}
+}
+
+impl WebSocket {
+	/// Connects to the echo server at addr and port and runs each step of the check in turn:
+	/// the handshake, text and binary messages on either side of the 125 and 65535 byte
+	/// length encoding boundaries, a fragmented message, a ping and a close. Every frame is
+	/// masked. Steps after the first failure are reported as skipped since the connection
+	/// can no longer be trusted. Only failing to connect is returned as an error.
+	pub fn self_check(addr: [u8; 4], port: u16) -> Result<SelfCheckReport, Error> {
+		let mut probe = match Probe::connect(addr, port) {
+			Ok(probe) => probe,
+			Err(e) => return Err(e),
+		};
+		let mut report = SelfCheckReport { steps: Vec::new() };
+		let mut failed = false;
+		for i in 0..STEPS.len() {
+			let start = getmicros!();
+			let res = if failed {
+				Err(SKIPPED)
+			} else {
+				probe.run_step(i, start + STEP_TIMEOUT_MICROS)
+			};
+			let step = SelfCheckStep {
+				name: STEPS[i],
+				passed: res.is_ok(),
+				detail: match res {
+					Ok(_) => "",
+					Err(detail) => detail,
+				},
+				micros: getmicros!() - start,
+			};
+			failed = !step.passed;
+			match report.steps.push(step) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(report)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn start_server(handler: Handler) -> (WebSocket, u16) {
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.register_handler(handler).unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+		(ws, port)
+	}
+
+	#[test]
+	fn test_self_check() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let (mut ws, port) = start_server(echo_handler().unwrap());
+		let report = WebSocket::self_check([127, 0, 0, 1], port).unwrap();
+		assert_eq!(report.steps.len(), STEPS.len());
+		for step in &report.steps {
+			assert!(step.passed);
+			assert!(step.micros < STEP_TIMEOUT_MICROS);
+		}
+		assert!(report.passed());
+		let mut f = Formatter::new();
+		writeb!(f, "{}", report).unwrap();
+		let text = String::new(f.as_str()).unwrap();
+		assert!(text.find("fragmented: ok").is_some());
+		assert!(text.ends_with("passed 9/9"));
+
+		// a server which answers everything with the same binary message
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, mut resp: WsResponse| resp.sendb(b"x")).unwrap();
+		let (mut bad, bad_port) = start_server(b);
+		let report = WebSocket::self_check([127, 0, 0, 1], bad_port).unwrap();
+		assert!(!report.passed());
+		assert_eq!(report.failures(), STEPS.len() - 1);
+		assert!(report.steps[0].passed);
+		assert_eq!(report.steps[1].detail, "unexpected frame type");
+		assert_eq!(report.steps[2].detail, SKIPPED);
+		assert!(bad.stop().is_ok());
+		assert!(ws.stop().is_ok());
+
+		// nothing listens on port 1
+		assert!(WebSocket::self_check([127, 0, 0, 1], 1).is_err());
+	}
+}
@@ -0,0 +1,302 @@
+//! # TCP
+//! TcpListener and TcpStream own a socket from c/net.c and close it when dropped. Both
+//! start out nonblocking, as the WebSocket workers use them, so accept, read and write
+//! return WouldBlock instead of waiting. handle() gives the socket to an Executor through
+//! Context::readable and Context::writable, which lets other protocols than WebSocket run
+//! on the same event loop. set_nonblocking(false) makes them wait instead, for simple
+//! clients and tests.
+
+use core::mem::forget;
+use ffi::{
+	socket_accept, socket_close, socket_connect, socket_listen, socket_peer_addr, socket_recv,
+	socket_send, socket_set_nonblocking, socket_shutdown,
+};
+use prelude::*;
+
+// return codes of the socket functions in c/net.c
+pub(crate) const ERROR_SOCKET: i32 = -1;
+pub(crate) const ERROR_CONNECT: i32 = -2;
+pub(crate) const ERROR_SETSOCKOPT: i32 = -3;
+pub(crate) const ERROR_BIND: i32 = -4;
+pub(crate) const ERROR_LISTEN: i32 = -5;
+pub(crate) const ERROR_ACCEPT: i32 = -6;
+pub(crate) const ERROR_FCNTL: i32 = -7;
+pub(crate) const ERROR_REGISTER: i32 = -8;
+pub(crate) const ERROR_MULTIPLEX_INIT: i32 = -9;
+pub(crate) const ERROR_GETSOCKNAME: i32 = -10;
+pub(crate) const EAGAIN: i32 = -11;
+pub(crate) const ERROR_GETPEERNAME: i32 = -12;
+
+/// Error kind for a negative return code of the socket functions in c/net.c
+pub(crate) fn socket_error_kind(code: i32) -> ErrorKind {
+	match code {
+		ERROR_SOCKET | ERROR_MULTIPLEX_INIT => CreateFileDescriptor,
+		ERROR_CONNECT => Connect,
+		ERROR_BIND | ERROR_LISTEN => Bind,
+		ERROR_REGISTER => MultiplexRegister,
+		EAGAIN => WouldBlock,
+		_ => IO,
+	}
+}
+
+/// Name of the step that failed for a negative return code of the socket functions
+pub(crate) fn socket_error_name(code: i32) -> &'static str {
+	match code {
+		ERROR_SOCKET => "socket",
+		ERROR_CONNECT => "connect",
+		ERROR_SETSOCKOPT => "setsockopt",
+		ERROR_BIND => "bind",
+		ERROR_LISTEN => "listen",
+		ERROR_ACCEPT => "accept",
+		ERROR_FCNTL => "fcntl",
+		ERROR_REGISTER => "register",
+		ERROR_MULTIPLEX_INIT => "multiplex init",
+		ERROR_GETSOCKNAME => "getsockname",
+		EAGAIN => "would block",
+		ERROR_GETPEERNAME => "getpeername",
+		_ => "unknown",
+	}
+}
+
+pub struct TcpListener {
+	handle: [u8; 4],
+	port: u16,
+}
+
+pub struct TcpStream {
+	handle: [u8; 4],
+}
+
+impl Drop for TcpListener {
+	fn drop(&mut self) {
+		unsafe {
+			socket_close(&self.handle as *const u8);
+		}
+	}
+}
+
+impl Drop for TcpStream {
+	fn drop(&mut self) {
+		unsafe {
+			socket_close(&self.handle as *const u8);
+		}
+	}
+}
+
+impl TcpListener {
+	/// Listens on port, or on a port chosen by the system if port is 0, with room for
+	/// backlog connections waiting to be accepted
+	pub fn bind(addr: [u8; 4], port: u16, backlog: i32) -> Result<Self, Error> {
+		let mut handle = [0u8; 4];
+		let port = unsafe { socket_listen(&mut handle as *mut u8, addr.as_ptr(), port, backlog) };
+		if port < 0 {
+			return Err(oserr!(socket_error_kind(port)));
+		}
+		Ok(Self {
+			handle,
+			port: port as u16,
+		})
+	}
+
+	/// The port listened on
+	pub fn port(&self) -> u16 {
+		self.port
+	}
+
+	/// The next pending connection, which starts out nonblocking. WouldBlock if there is
+	/// none and the listener is nonblocking.
+	pub fn accept(&self) -> Result<TcpStream, Error> {
+		let mut handle = [0u8; 4];
+		let code = unsafe { socket_accept(&self.handle as *const u8, &mut handle as *mut u8) };
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(TcpStream { handle })
+	}
+
+	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+		set_nonblocking(&self.handle, nonblocking)
+	}
+
+	/// The socket handle, for Context::readable
+	pub fn handle(&self) -> [u8; 4] {
+		self.handle
+	}
+}
+
+impl TcpStream {
+	/// Connects to addr and port. The connection is made before returning, after which
+	/// the stream is nonblocking.
+	pub fn connect(addr: [u8; 4], port: u16) -> Result<Self, Error> {
+		let mut handle = [0u8; 4];
+		let code = unsafe { socket_connect(&mut handle as *mut u8, addr.as_ptr(), port as i32) };
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
+		}
+		Ok(Self { handle })
+	}
+
+	/// Reads into buf, returning the number of bytes read, 0 once the peer has shut down its
+	/// side. WouldBlock if a nonblocking stream has nothing to read.
+	pub fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+		let ret = unsafe { socket_recv(&self.handle as *const u8, buf.as_mut_ptr(), buf.len()) };
+		if ret < 0 {
+			Err(oserr!(socket_error_kind(ret as i32)))
+		} else {
+			Ok(ret as usize)
+		}
+	}
+
+	/// Writes from buf, returning the number of bytes written, which may be fewer than
+	/// buf.len(). WouldBlock if a nonblocking stream cannot take any.
+	pub fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+		let ret = unsafe { socket_send(&self.handle as *const u8, buf.as_ptr(), buf.len()) };
+		if ret < 0 {
+			Err(oserr!(socket_error_kind(ret as i32)))
+		} else {
+			Ok(ret as usize)
+		}
+	}
+
+	/// Shuts down both directions. The peer reads end of stream and a read on this side
+	/// returns 0. The socket stays open until the stream is dropped.
+	pub fn shutdown(&self) -> Result<(), Error> {
+		if unsafe { socket_shutdown(&self.handle as *const u8) } < 0 {
+			return Err(oserr!(IO));
+		}
+		Ok(())
+	}
+
+	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+		set_nonblocking(&self.handle, nonblocking)
+	}
+
+	/// Address and port of the other end of the connection
+	pub fn peer_addr(&self) -> Result<([u8; 4], u16), Error> {
+		peer_addr(&self.handle)
+	}
+
+	/// The socket handle, for Context::readable and Context::writable
+	pub fn handle(&self) -> [u8; 4] {
+		self.handle
+	}
+
+	/// Takes over a socket opened elsewhere, which is closed when the stream is dropped
+	pub(crate) fn from_handle(handle: [u8; 4]) -> Self {
+		Self { handle }
+	}
+
+	/// Gives up the socket without closing it, for code which manages handles itself
+	pub(crate) fn into_handle(self) -> [u8; 4] {
+		let handle = self.handle;
+		forget(self);
+		handle
+	}
+}
+
+pub(crate) fn set_nonblocking(handle: &[u8; 4], nonblocking: bool) -> Result<(), Error> {
+	let code = unsafe { socket_set_nonblocking(handle as *const u8, nonblocking) };
+	if code < 0 {
+		return Err(oserr!(socket_error_kind(code)));
+	}
+	Ok(())
+}
+
+pub(crate) fn peer_addr(handle: &[u8; 4]) -> Result<([u8; 4], u16), Error> {
+	let mut addr = [0u8; 4];
+	let mut port = 0u16;
+	let code = unsafe { socket_peer_addr(handle as *const u8, addr.as_mut_ptr(), &mut port) };
+	if code < 0 {
+		return Err(oserr!(socket_error_kind(code)));
+	}
+	Ok((addr, port))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use net::executor::{poll_fn, Context, Executor, Poll};
+
+	#[test]
+	fn test_tcp() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let listener = TcpListener::bind([127, 0, 0, 1], 0, 10).unwrap();
+		assert!(listener.port() != 0);
+		assert!(listener.accept().unwrap_err().kind == WouldBlock);
+
+		let client = TcpStream::connect([127, 0, 0, 1], listener.port()).unwrap();
+		listener.set_nonblocking(false).unwrap();
+		let server = listener.accept().unwrap();
+		assert_eq!(server.peer_addr().unwrap().0, [127, 0, 0, 1]);
+		assert_eq!(
+			client.peer_addr().unwrap(),
+			([127, 0, 0, 1], listener.port())
+		);
+		let mut buf = [0u8; 16];
+		assert!(server.read(&mut buf).unwrap_err().kind == WouldBlock);
+		assert_eq!(client.write(b"ping").unwrap(), 4);
+
+		// a blocking read waits for the data
+		server.set_nonblocking(false).unwrap();
+		assert_eq!(server.read(&mut buf).unwrap(), 4);
+		assert_eq!(&buf[0..4], b"ping");
+		assert_eq!(server.write(b"pong").unwrap(), 4);
+
+		client.set_nonblocking(false).unwrap();
+		assert_eq!(client.read(&mut buf).unwrap(), 4);
+		assert_eq!(&buf[0..4], b"pong");
+
+		client.shutdown().unwrap();
+		assert_eq!(server.read(&mut buf).unwrap(), 0);
+		assert_eq!(client.read(&mut buf).unwrap(), 0);
+	}
+
+	#[test]
+	fn test_tcp_executor() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let listener = TcpListener::bind([127, 0, 0, 1], 0, 10).unwrap();
+		let client = TcpStream::connect([127, 0, 0, 1], listener.port()).unwrap();
+		assert_eq!(client.write(b"hello").unwrap(), 5);
+		let mut exec = Executor::new().unwrap();
+
+		// accept when the listener is readable, then echo once the request arrives
+		let mut server: Option<TcpStream> = None;
+		let echoed = exec
+			.block_on(poll_fn(move |cx: &mut Context| {
+				if server.is_none() {
+					match listener.accept() {
+						Ok(stream) => server = Some(stream),
+						Err(e) if e.kind == WouldBlock => {
+							return match cx.readable(listener.handle()) {
+								Ok(_) => Poll::Pending,
+								Err(e) => Poll::Ready(Err(e)),
+							};
+						}
+						Err(e) => return Poll::Ready(Err(e)),
+					}
+				}
+				let stream = match &server {
+					Some(stream) => stream,
+					None => return Poll::Ready(Err(err!(IllegalState))),
+				};
+				let mut buf = [0u8; 8];
+				match stream.read(&mut buf) {
+					Ok(n) => Poll::Ready(stream.write(&buf[0..n])),
+					Err(e) if e.kind == WouldBlock => match cx.readable(stream.handle()) {
+						Ok(_) => Poll::Pending,
+						Err(e) => Poll::Ready(Err(e)),
+					},
+					Err(e) => Poll::Ready(Err(e)),
+				}
+			}))
+			.unwrap()
+			.unwrap();
+		assert_eq!(echoed, 5);
+		client.set_nonblocking(false).unwrap();
+		let mut buf = [0u8; 8];
+		assert_eq!(client.read(&mut buf).unwrap(), 5);
+		assert_eq!(&buf[0..5], b"hello");
+	}
+}
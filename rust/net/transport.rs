@@ -0,0 +1,636 @@
+//! # Transport
+//! The socket calls a WebSocket worker makes on its connections, send, recv, shutdown,
+//! close and the registrations with its reactor, behind a trait. SocketTransport makes
+//! them on the sockets of c/net.c and SecureTransport on the same sockets through a
+//! SecureStream for each connection. In tests MockTransport keeps each connection as a pair
+//! of in-memory buffers instead, which a test fills and drains in place of the peer, so
+//! that handshakes, frames, backpressure and closes can be driven one step at a time
+//! without opening a socket or waiting on one.
+//!
+//! Return codes follow c/net.c: send and recv return a byte count, EAGAIN when they
+//! would block or another negative code once the socket has failed.
+
+use core::cmp::min;
+use core::mem::replace;
+use ffi::{socket_close, socket_recv, socket_send, socket_shutdown};
+use net::reactor::Reactor;
+#[cfg(test)]
+use net::reactor::{READ, WRITE};
+use net::secure::{Identity, SecureStream, MAX_PAYLOAD};
+use net::tcp::{TcpStream, EAGAIN, ERROR_SOCKET};
+use prelude::*;
+
+// most bytes SecureTransport accepts from a connection before its handshake completes
+const MAX_EARLY_BYTES: usize = 4096;
+
+/// Called from every worker thread of a WebSocket
+pub trait Transport: Send + Sync {
+	/// Called once for each new connection before anything else is done with its handle.
+	/// initiator is true for connections this side opened.
+	fn attach(&self, _handle: [u8; 4], _initiator: bool) -> Result<(), Error> {
+		Ok(())
+	}
+	/// Bytes of buf sent, EAGAIN if none could be
+	fn send(&self, handle: [u8; 4], buf: &[u8]) -> i64;
+	/// Bytes received into buf, 0 once the peer closed or the socket was shut down
+	fn recv(&self, handle: [u8; 4], buf: &mut [u8]) -> i64;
+	/// Ends the connection in both directions. The handle stays open until close.
+	fn shutdown(&self, handle: [u8; 4]);
+	fn close(&self, handle: [u8; 4]);
+	fn register(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		interest: i32,
+		token: usize,
+	) -> Result<(), Error>;
+	fn deregister_write(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		token: usize,
+	) -> Result<(), Error>;
+	fn deregister(&self, reactor: &Reactor, handle: [u8; 4]) -> Result<(), Error>;
+}
+
+/// The sockets of c/net.c, registered with the reactor they are passed with
+pub struct SocketTransport;
+
+impl Transport for SocketTransport {
+	fn send(&self, handle: [u8; 4], buf: &[u8]) -> i64 {
+		unsafe { socket_send(&handle as *const u8, buf.as_ptr(), buf.len()) }
+	}
+
+	fn recv(&self, handle: [u8; 4], buf: &mut [u8]) -> i64 {
+		unsafe { socket_recv(&handle as *const u8, buf.as_mut_ptr(), buf.len()) }
+	}
+
+	fn shutdown(&self, handle: [u8; 4]) {
+		unsafe {
+			socket_shutdown(&handle as *const u8);
+		}
+	}
+
+	fn close(&self, handle: [u8; 4]) {
+		unsafe {
+			socket_close(&handle as *const u8);
+		}
+	}
+
+	fn register(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		interest: i32,
+		token: usize,
+	) -> Result<(), Error> {
+		reactor.register(handle, interest, token)
+	}
+
+	fn deregister_write(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		token: usize,
+	) -> Result<(), Error> {
+		reactor.deregister_write(handle, token)
+	}
+
+	fn deregister(&self, reactor: &Reactor, handle: [u8; 4]) -> Result<(), Error> {
+		reactor.deregister(handle)
+	}
+}
+
+/// The sockets of c/net.c with everything sent and received encrypted by a SecureStream
+/// under identity, which takes the initiator's side of the handshake on connections this
+/// side opened. Bytes sent before the handshake completes are held until it does, up to
+/// MAX_EARLY_BYTES, and the handshake only reads from the socket in recv, so that nothing
+/// received is left unread. Any peer key is accepted. The sockets stay owned by the
+/// WebSocket and are only closed by close.
+pub struct SecureTransport {
+	identity: Arc<Identity>,
+	conns: RwLock<HashMap<u32, Mutex<SecureConn>>>,
+}
+
+struct SecureConn {
+	stream: SecureStream,
+	// plaintext sent before the handshake completed
+	early: Vec<u8>,
+	// bytes encrypted into frames still waiting in the stream, reported as sent once they
+	// have gone out. The caller offers them again until then, as with a short send.
+	unreported: usize,
+}
+
+impl SecureTransport {
+	pub fn new(identity: Arc<Identity>) -> Result<Self, Error> {
+		match HashMap::new() {
+			Ok(conns) => Ok(Self {
+				identity,
+				conns: RwLock::new(conns),
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn key(handle: [u8; 4]) -> u32 {
+		u32::from_ne_bytes(handle)
+	}
+
+	// the socket of a connection which is gone is closed by its owner, not the stream
+	fn release(conn: Mutex<SecureConn>) {
+		let _ = conn.into_inner().stream.into_inner().into_handle();
+	}
+
+	fn send_secure(conn: &mut SecureConn, buf: &[u8]) -> i64 {
+		if !conn.stream.is_established() {
+			match conn.stream.flush() {
+				Ok(_) => {}
+				Err(e) => {
+					if e.kind != WouldBlock {
+						return ERROR_SOCKET as i64;
+					}
+				}
+			}
+			if conn.early.len() + buf.len() > MAX_EARLY_BYTES {
+				return EAGAIN as i64;
+			}
+			return match conn.early.append_ptr(buf.as_ptr(), buf.len()) {
+				Ok(_) => buf.len() as i64,
+				Err(_e) => EAGAIN as i64,
+			};
+		}
+		match conn.stream.flush() {
+			Ok(_) => {}
+			Err(e) => {
+				return if e.kind == WouldBlock {
+					EAGAIN as i64
+				} else {
+					ERROR_SOCKET as i64
+				};
+			}
+		}
+		if conn.unreported > 0 {
+			let n = min(conn.unreported, buf.len());
+			conn.unreported -= n;
+			return n as i64;
+		}
+		let n = min(buf.len(), MAX_PAYLOAD);
+		if n == 0 {
+			return 0;
+		}
+		match conn.stream.write(&buf[0..n]) {
+			Ok(_) => {}
+			Err(_e) => return ERROR_SOCKET as i64,
+		}
+		if conn.stream.pending() > 0 {
+			conn.unreported = n;
+			EAGAIN as i64
+		} else {
+			n as i64
+		}
+	}
+
+	fn recv_secure(conn: &mut SecureConn, buf: &mut [u8]) -> i64 {
+		match conn.stream.handshake() {
+			Ok(_) => {}
+			Err(e) => {
+				return if e.kind == WouldBlock {
+					EAGAIN as i64
+				} else {
+					ERROR_SOCKET as i64
+				};
+			}
+		}
+		if conn.early.len() > 0 {
+			let early = replace(&mut conn.early, Vec::new());
+			match conn.stream.write(early.as_slice()) {
+				Ok(_) => {}
+				Err(_e) => return ERROR_SOCKET as i64,
+			}
+		}
+		match conn.stream.read(buf) {
+			Ok(n) => n as i64,
+			Err(e) => {
+				if e.kind == WouldBlock {
+					EAGAIN as i64
+				} else {
+					ERROR_SOCKET as i64
+				}
+			}
+		}
+	}
+}
+
+impl Drop for SecureTransport {
+	fn drop(&mut self) {
+		let mut conns = self.conns.write();
+		let mut keys = Vec::new();
+		for (key, _) in conns.iter() {
+			// on allocation failure the remaining streams are freed with the map
+			if keys.push(*key).is_err() {
+				break;
+			}
+		}
+		for key in &keys {
+			match conns.remove(key) {
+				Some(conn) => Self::release(conn),
+				None => {}
+			}
+		}
+	}
+}
+
+impl Transport for SecureTransport {
+	fn attach(&self, handle: [u8; 4], initiator: bool) -> Result<(), Error> {
+		let identity = match self.identity.clone() {
+			Ok(identity) => identity,
+			Err(e) => return Err(e),
+		};
+		let mut stream =
+			match SecureStream::new(TcpStream::from_handle(handle), identity, initiator) {
+				Ok(stream) => stream,
+				Err(e) => return Err(e),
+			};
+		// the initiator's first message goes out without waiting for a send
+		let _ = stream.flush();
+		let conn = Mutex::new(SecureConn {
+			stream,
+			early: Vec::new(),
+			unreported: 0,
+		});
+		// a handle left behind by a connection which never reached close is reused
+		match self.conns.write().insert(Self::key(handle), conn) {
+			Ok(Some(old)) => {
+				Self::release(old);
+				Ok(())
+			}
+			Ok(None) => Ok(()),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn send(&self, handle: [u8; 4], buf: &[u8]) -> i64 {
+		let conns = self.conns.read();
+		match conns.get(&Self::key(handle)) {
+			Some(conn) => Self::send_secure(&mut conn.lock(), buf),
+			None => ERROR_SOCKET as i64,
+		}
+	}
+
+	fn recv(&self, handle: [u8; 4], buf: &mut [u8]) -> i64 {
+		let conns = self.conns.read();
+		match conns.get(&Self::key(handle)) {
+			Some(conn) => Self::recv_secure(&mut conn.lock(), buf),
+			None => ERROR_SOCKET as i64,
+		}
+	}
+
+	fn shutdown(&self, handle: [u8; 4]) {
+		unsafe {
+			socket_shutdown(&handle as *const u8);
+		}
+	}
+
+	fn close(&self, handle: [u8; 4]) {
+		let conn = self.conns.write().remove(&Self::key(handle));
+		match conn {
+			Some(conn) => Self::release(conn),
+			None => {}
+		}
+		unsafe {
+			socket_close(&handle as *const u8);
+		}
+	}
+
+	fn register(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		interest: i32,
+		token: usize,
+	) -> Result<(), Error> {
+		reactor.register(handle, interest, token)
+	}
+
+	fn deregister_write(
+		&self,
+		reactor: &Reactor,
+		handle: [u8; 4],
+		token: usize,
+	) -> Result<(), Error> {
+		reactor.deregister_write(handle, token)
+	}
+
+	fn deregister(&self, reactor: &Reactor, handle: [u8; 4]) -> Result<(), Error> {
+		reactor.deregister(handle)
+	}
+}
+
+// handles of mock sockets start here so that they are never mistaken for descriptors
+#[cfg(test)]
+const MOCK_HANDLE_BASE: u32 = 0x4000_0000;
+
+#[cfg(test)]
+struct MockSocket {
+	// sent by the peer and not yet received
+	input: Deque<u8>,
+	// sent and not yet taken by the peer
+	output: Deque<u8>,
+	// most bytes output holds before send returns EAGAIN
+	room: usize,
+	peer_closed: bool,
+	shutdown: bool,
+	closed: bool,
+	interest: i32,
+	token: usize,
+}
+
+/// In-memory sockets. Registrations are recorded on the socket and reported by ready
+/// while they hold, as a level triggered multiplexer would, and the reactor passed with
+/// them is left alone. Clones share the sockets.
+#[cfg(test)]
+pub struct MockTransport {
+	sockets: Arc<Mutex<Vec<MockSocket>>>,
+}
+
+#[cfg(test)]
+impl Clone for MockTransport {
+	fn clone(&self) -> Result<Self, Error> {
+		match self.sockets.clone() {
+			Ok(sockets) => Ok(Self { sockets }),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+#[cfg(test)]
+impl MockTransport {
+	pub fn new() -> Result<Self, Error> {
+		match Arc::new(Mutex::new(Vec::new())) {
+			Ok(sockets) => Ok(Self { sockets }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// A new connected socket with unlimited room for output
+	pub fn socket(&self) -> Result<[u8; 4], Error> {
+		let mut sockets = self.sockets.lock();
+		let id = MOCK_HANDLE_BASE + sockets.len() as u32;
+		match sockets.push(MockSocket {
+			input: Deque::new(),
+			output: Deque::new(),
+			room: usize::MAX,
+			peer_closed: false,
+			shutdown: false,
+			closed: false,
+			interest: 0,
+			token: 0,
+		}) {
+			Ok(_) => Ok(id.to_le_bytes()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Sends data from the peer
+	pub fn push(&self, handle: [u8; 4], data: &[u8]) -> Result<(), Error> {
+		let mut sockets = self.sockets.lock();
+		sockets[Self::index(handle)].input.extend_from_slice(data)
+	}
+
+	/// Receives everything sent to the peer so far
+	pub fn take(&self, handle: [u8; 4]) -> Result<Vec<u8>, Error> {
+		let mut sockets = self.sockets.lock();
+		let socket = &mut sockets[Self::index(handle)];
+		let mut ret = Vec::new();
+		let (front, back) = socket.output.as_slices();
+		match ret.append_ptr(front.as_ptr(), front.len()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match ret.append_ptr(back.as_ptr(), back.len()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		socket.output.clear();
+		Ok(ret)
+	}
+
+	/// Limits how many bytes sent and not yet taken the socket holds, as a full send
+	/// buffer would
+	pub fn set_room(&self, handle: [u8; 4], room: usize) {
+		let mut sockets = self.sockets.lock();
+		sockets[Self::index(handle)].room = room;
+	}
+
+	/// Closes the peer's end, recv returns 0 once the input is read
+	pub fn close_peer(&self, handle: [u8; 4]) {
+		let mut sockets = self.sockets.lock();
+		sockets[Self::index(handle)].peer_closed = true;
+	}
+
+	/// Whether the socket was shut down or closed
+	pub fn is_shutdown(&self, handle: [u8; 4]) -> bool {
+		let sockets = self.sockets.lock();
+		let socket = &sockets[Self::index(handle)];
+		socket.shutdown || socket.closed
+	}
+
+	pub fn is_closed(&self, handle: [u8; 4]) -> bool {
+		let sockets = self.sockets.lock();
+		sockets[Self::index(handle)].closed
+	}
+
+	/// Interest the socket is registered with, 0 if none
+	pub fn interest(&self, handle: [u8; 4]) -> i32 {
+		let sockets = self.sockets.lock();
+		sockets[Self::index(handle)].interest
+	}
+
+	/// Sockets not closed yet
+	pub fn open(&self) -> usize {
+		let sockets = self.sockets.lock();
+		let mut count = 0;
+		for socket in &*sockets {
+			if !socket.closed {
+				count += 1;
+			}
+		}
+		count
+	}
+
+	/// Token of each registered socket which has something to read or room to write for
+	/// its interest, and whether it is readable
+	pub fn ready(&self) -> Result<Vec<(usize, bool)>, Error> {
+		let sockets = self.sockets.lock();
+		let mut ret = Vec::new();
+		for socket in &*sockets {
+			if socket.closed {
+				continue;
+			}
+			let readable = socket.interest & READ != 0
+				&& (socket.input.len() > 0 || socket.peer_closed || socket.shutdown);
+			let writable = socket.interest & WRITE != 0
+				&& socket.output.len() < socket.room
+				&& !socket.shutdown;
+			if readable || writable {
+				match ret.push((socket.token, readable)) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+			}
+		}
+		Ok(ret)
+	}
+
+	fn index(handle: [u8; 4]) -> usize {
+		(u32::from_le_bytes(handle) - MOCK_HANDLE_BASE) as usize
+	}
+
+	fn set_interest(&self, handle: [u8; 4], interest: i32, token: usize) {
+		let mut sockets = self.sockets.lock();
+		let socket = &mut sockets[Self::index(handle)];
+		socket.interest = interest;
+		socket.token = token;
+	}
+}
+
+#[cfg(test)]
+impl Transport for MockTransport {
+	fn send(&self, handle: [u8; 4], buf: &[u8]) -> i64 {
+		let mut sockets = self.sockets.lock();
+		let socket = &mut sockets[Self::index(handle)];
+		if socket.shutdown || socket.closed || socket.peer_closed {
+			return ERROR_SOCKET as i64;
+		}
+		let room = socket.room.saturating_sub(socket.output.len());
+		if room == 0 && buf.len() > 0 {
+			return EAGAIN as i64;
+		}
+		let len = if buf.len() < room { buf.len() } else { room };
+		match socket.output.extend_from_slice(&buf[..len]) {
+			Ok(_) => len as i64,
+			Err(_e) => EAGAIN as i64,
+		}
+	}
+
+	fn recv(&self, handle: [u8; 4], buf: &mut [u8]) -> i64 {
+		let mut sockets = self.sockets.lock();
+		let socket = &mut sockets[Self::index(handle)];
+		if socket.shutdown || socket.closed {
+			return 0;
+		}
+		let mut len = 0;
+		while len < buf.len() {
+			match socket.input.pop_front() {
+				Some(b) => {
+					buf[len] = b;
+					len += 1;
+				}
+				None => break,
+			}
+		}
+		if len == 0 && !socket.peer_closed {
+			EAGAIN as i64
+		} else {
+			len as i64
+		}
+	}
+
+	fn shutdown(&self, handle: [u8; 4]) {
+		let mut sockets = self.sockets.lock();
+		sockets[Self::index(handle)].shutdown = true;
+	}
+
+	fn close(&self, handle: [u8; 4]) {
+		let mut sockets = self.sockets.lock();
+		let socket = &mut sockets[Self::index(handle)];
+		socket.closed = true;
+		socket.interest = 0;
+	}
+
+	fn register(
+		&self,
+		_reactor: &Reactor,
+		handle: [u8; 4],
+		interest: i32,
+		token: usize,
+	) -> Result<(), Error> {
+		if interest & (READ | WRITE) == 0 || self.is_closed(handle) {
+			return Err(err!(IllegalArgument));
+		}
+		self.set_interest(handle, interest, token);
+		Ok(())
+	}
+
+	fn deregister_write(
+		&self,
+		_reactor: &Reactor,
+		handle: [u8; 4],
+		token: usize,
+	) -> Result<(), Error> {
+		self.set_interest(handle, READ, token);
+		Ok(())
+	}
+
+	fn deregister(&self, _reactor: &Reactor, handle: [u8; 4]) -> Result<(), Error> {
+		self.set_interest(handle, 0, 0);
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_mock_transport() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let reactor = Reactor::new().unwrap();
+		let mock = MockTransport::new().unwrap();
+		let transport: Box<dyn Transport> = Box::new(mock.clone().unwrap()).unwrap();
+		let a = mock.socket().unwrap();
+		let mut buf = [0u8; 8];
+		assert_eq!(transport.recv(a, &mut buf), EAGAIN as i64);
+
+		// readiness follows the registration and what the peer has done
+		transport.register(&reactor, a, READ, 7).unwrap();
+		assert_eq!(mock.ready().unwrap().len(), 0);
+		mock.push(a, b"hello").unwrap();
+		let ready = mock.ready().unwrap();
+		assert_eq!(ready.len(), 1);
+		assert_eq!(ready[0], (7, true));
+		assert_eq!(transport.recv(a, &mut buf[..3]), 3);
+		assert_eq!(transport.recv(a, &mut buf[3..]), 2);
+		assert_eq!(&buf[..5], b"hello");
+		assert_eq!(mock.ready().unwrap().len(), 0);
+
+		// a full send buffer takes what fits and then blocks until the peer takes some
+		mock.set_room(a, 4);
+		assert_eq!(transport.send(a, b"abcdef"), 4);
+		assert_eq!(transport.send(a, b"ef"), EAGAIN as i64);
+		transport.register(&reactor, a, READ | WRITE, 7).unwrap();
+		assert_eq!(mock.ready().unwrap().len(), 0);
+		assert_eq!(mock.take(a).unwrap().as_slice(), b"abcd");
+		assert_eq!(mock.ready().unwrap()[0], (7, false));
+		transport.deregister_write(&reactor, a, 7).unwrap();
+		assert_eq!(mock.interest(a), READ);
+
+		// a closed peer reads as end of stream, as does shutting down
+		mock.push(a, b"x").unwrap();
+		mock.close_peer(a);
+		assert_eq!(transport.recv(a, &mut buf), 1);
+		assert_eq!(transport.recv(a, &mut buf), 0);
+		assert!(transport.send(a, b"y") < 0);
+		let b = mock.socket().unwrap();
+		transport.shutdown(b);
+		assert!(mock.is_shutdown(b) && !mock.is_closed(b));
+		assert_eq!(transport.recv(b, &mut buf), 0);
+
+		assert_eq!(mock.open(), 2);
+		transport.close(a);
+		transport.close(b);
+		assert_eq!(mock.open(), 0);
+		assert!(transport.register(&reactor, a, READ, 7).is_err());
+	}
+}
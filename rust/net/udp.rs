@@ -0,0 +1,132 @@
+//! # UDP
+//! UdpSocket sends and receives datagrams of IPv4 peers. Like the TCP sockets it starts
+//! out nonblocking and closes its socket when dropped, and its handle can be registered
+//! with a Reactor or waited on through Context::readable. A datagram longer than the
+//! buffer given to recv_from is cut short, the rest of it is lost.
+
+use ffi::{socket_close, socket_recvfrom, socket_sendto, socket_udp_bind};
+use net::tcp::{set_nonblocking, socket_error_kind};
+use prelude::*;
+
+pub struct UdpSocket {
+	handle: [u8; 4],
+	port: u16,
+}
+
+impl Drop for UdpSocket {
+	fn drop(&mut self) {
+		unsafe {
+			socket_close(&self.handle as *const u8);
+		}
+	}
+}
+
+impl UdpSocket {
+	/// Binds to addr and port, or a port chosen by the system if port is 0. [0, 0, 0, 0]
+	/// receives on every interface.
+	pub fn bind(addr: [u8; 4], port: u16) -> Result<Self, Error> {
+		let mut handle = [0u8; 4];
+		let port = unsafe { socket_udp_bind(&mut handle as *mut u8, addr.as_ptr(), port as i32) };
+		if port < 0 {
+			return Err(oserr!(socket_error_kind(port)));
+		}
+		Ok(Self {
+			handle,
+			port: port as u16,
+		})
+	}
+
+	/// The port bound to
+	pub fn port(&self) -> u16 {
+		self.port
+	}
+
+	/// Sends buf as one datagram to addr and port, returning the number of bytes sent.
+	/// WouldBlock if a nonblocking socket's send buffer is full.
+	pub fn send_to(&self, buf: &[u8], addr: [u8; 4], port: u16) -> Result<usize, Error> {
+		let ret = unsafe {
+			socket_sendto(
+				&self.handle as *const u8,
+				buf.as_ptr(),
+				buf.len(),
+				addr.as_ptr(),
+				port as i32,
+			)
+		};
+		if ret < 0 {
+			Err(oserr!(socket_error_kind(ret as i32)))
+		} else {
+			Ok(ret as usize)
+		}
+	}
+
+	/// Receives the next datagram into buf, returning its length and the address and port
+	/// it came from. WouldBlock if a nonblocking socket has none waiting.
+	pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, [u8; 4], u16), Error> {
+		let mut addr = [0u8; 4];
+		let mut port = 0u16;
+		let ret = unsafe {
+			socket_recvfrom(
+				&self.handle as *const u8,
+				buf.as_mut_ptr(),
+				buf.len(),
+				&mut addr as *mut u8,
+				&mut port as *mut u16,
+			)
+		};
+		if ret < 0 {
+			Err(oserr!(socket_error_kind(ret as i32)))
+		} else {
+			Ok((ret as usize, addr, port))
+		}
+	}
+
+	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<(), Error> {
+		set_nonblocking(&self.handle, nonblocking)
+	}
+
+	/// The socket handle, for Reactor::register and Context::readable
+	pub fn handle(&self) -> [u8; 4] {
+		self.handle
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use net::reactor::{Events, Reactor, READ};
+
+	#[test]
+	fn test_udp() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let a = UdpSocket::bind([127, 0, 0, 1], 0).unwrap();
+		let b = UdpSocket::bind([127, 0, 0, 1], 0).unwrap();
+		assert!(a.port() != 0 && a.port() != b.port());
+		let mut buf = [0u8; 16];
+		assert!(a.recv_from(&mut buf).unwrap_err().kind == WouldBlock);
+
+		// the reactor reports the datagram, which comes with its sender's address
+		let reactor = Reactor::new().unwrap();
+		let mut events = Events::new(4).unwrap();
+		reactor.register(a.handle(), READ, 1).unwrap();
+		assert_eq!(b.send_to(b"hello", [127, 0, 0, 1], a.port()).unwrap(), 5);
+		assert_eq!(reactor.poll(&mut events, 1_000).unwrap(), 1);
+		assert_eq!(events.get(0).token(), 1);
+		let (len, addr, port) = a.recv_from(&mut buf).unwrap();
+		assert_eq!(&buf[0..len], b"hello");
+		assert_eq!(addr, [127, 0, 0, 1]);
+		assert_eq!(port, b.port());
+
+		// datagrams keep their boundaries, and a short buffer truncates
+		a.send_to(b"one", addr, port).unwrap();
+		a.send_to(b"three", addr, port).unwrap();
+		b.set_nonblocking(false).unwrap();
+		assert_eq!(b.recv_from(&mut buf).unwrap().0, 3);
+		let mut short = [0u8; 2];
+		assert_eq!(b.recv_from(&mut short).unwrap().0, 2);
+		assert_eq!(&short, b"th");
+		b.set_nonblocking(true).unwrap();
+		assert!(b.recv_from(&mut buf).unwrap_err().kind == WouldBlock);
+	}
+}
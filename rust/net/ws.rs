@@ -1,11 +1,35 @@
-use core::ptr::{copy_nonoverlapping, null_mut};
+use core::mem::{drop, replace, size_of};
+use core::ptr::copy_nonoverlapping;
+use core::result::Result as CoreResult;
+use core::str::from_utf8;
 use ffi::*;
+use net::auth::{Authenticator, Identity};
+use net::proxy::ProxyConfig;
+use net::reactor::{Events, Reactor, Waker, READ, WAKE_TOKEN, WRITE};
+use net::secure::Identity as SecureIdentity;
+use net::tcp::*;
+use net::transport::{SecureTransport, SocketTransport, Transport};
 use prelude::*;
+use secp256k1::types::CtEq;
+use std::arc::ArcInner;
+use std::cpsrng::Cpsrng;
+use std::error::{errno_name, last_errno};
+use std::hash::{crc32c, hmac_sha256, sha1, SHA256_SIZE};
+use std::mem::{MemCharge, MemTag};
+use util::arena::{Arena, ARENA_CHUNK_SIZE};
+use util::bufpool::BufferPool;
+use util::rope::{Rope, ROPE_CHUNK_SIZE};
 
 const MAGIC_STRING: &[u8; 36] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\n\
 Content-Type: text/plain\r\n\
 Connection: close\r\n\r\n";
+const UNAUTHORIZED: &str = "HTTP/1.1 401 Unauthorized\r\n\
+Content-Type: text/plain\r\n\
+Connection: close\r\n\r\n";
+const REQUEST_TIMEOUT: &str = "HTTP/1.1 408 Request Timeout\r\n\
+Content-Type: text/plain\r\n\
+Connection: close\r\n\r\n";
 const SWITCH_PROTOCOL: &str = "HTTP/1.1 101 Switching Protocols\r\n\
 Upgrade: websocket\r\n\
 Connection: Upgrade\r\n\
@@ -16,12 +40,30 @@ Sec-WebSocket-Key: ";
 
 const GET_PREFIX: &[u8] = "GET /".as_bytes();
 const SEC_KEY_PREFIX: &[u8] = "Sec-WebSocket-Key: ".as_bytes();
+const CHECKSUM_HEADER: &str = "X-Frame-Checksum: crc32c";
+const CHECKSUM_SIZE: usize = 4;
+const RESUME_TOKEN_PREFIX: &str = "X-Resume-Token: ";
+const AUTHORIZATION_PREFIX: &str = "Authorization: ";
+const SESSION_ID_SIZE: usize = 16;
+// session id followed by its HMAC under the server's session key
+const RESUME_TOKEN_RAW: usize = SESSION_ID_SIZE + SHA256_SIZE;
+/// Length of a base64 encoded resumption token
+pub const RESUME_TOKEN_LEN: usize = RESUME_TOKEN_RAW / 3 * 4;
+
+const MAX_FRAME_HEADER: usize = 10;
+const DEBUG_DUMP_TIMEOUT_MILLIS: u64 = 1_000;
+// how long to wait for a worker to pick up a new connection before reporting a timeout
+const WORKER_REPLY_TIMEOUT_MICROS: u64 = 10_000_000;
+// closed connections whose allocations are kept for the next ones accepted
+const CONNECTION_POOL_SIZE: usize = 64;
+// read buffers of each size kept by a worker for its connections
+const READ_BUFFER_POOL_SIZE: usize = 64;
+
+/// Called with each message received. It runs on the worker threads, possibly on several
+/// at once.
+pub type Handler = Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync>;
 
-const EAGAIN: i32 = -11;
-const REG_READ_FLAG: i32 = 0x1;
-const REG_WRITE_FLAG: i32 = 0x2;
-
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum ConnectionState {
 	NeedHandshake,
 	HandshakeComplete,
@@ -35,42 +77,368 @@ enum ConnectionType {
 	ClientConnection,
 }
 
+#[cfg(test)]
+#[derive(PartialEq, Clone, Copy)]
+enum WsTestEvent {
+	HandshakeComplete(ConnectionType),
+	MessageProcessed(ConnectionType),
+	ConnectionClosed(ConnectionType),
+	DataRead(ConnectionType),
+}
+
 pub struct WsConfig {
-	threads: u64,
-	max_events: i32,
-	timeout_micros: i64,
-	debug_pending: bool,
+	pub threads: u64,
+	pub max_events: i32,
+	/// Connections with no traffic for this long are closed with WsClosePolicy::idle_timeout,
+	/// or dealt with as the handler registered with WebSocket::register_idle_handler decides
+	pub timeout_micros: i64,
+	/// Longest a worker waits between passes over its connections looking for those which
+	/// have been idle or handshaking too long, so a connection may outlive its timeout by
+	/// up to this much. Must be greater than 0.
+	pub stale_check_micros: i64,
+	/// Connections which have not completed the handshake this long after they were opened
+	/// are closed whether or not bytes are still arriving, server connections with a 408.
+	/// 0 leaves them to timeout_micros.
+	pub handshake_timeout_micros: i64,
+	/// Most bytes a connection may buffer before its handshake is complete. A server
+	/// connection which sends more without finishing its request gets a 400.
+	pub max_handshake_bytes: usize,
+	pub debug_pending: bool,
+	/// Stop accepting connections while buffered bytes across all connections exceed this
+	pub max_buffer_bytes: u64,
+	/// Resume accepting once buffered bytes drop below this
+	pub buffer_low_water: u64,
+	/// Negotiate a CRC32C trailer on binary frames with peers that also enable it
+	pub frame_checksum: bool,
+	/// Most bytes try_send and try_sendb will leave buffered on a single connection, and the
+	/// most that will be queued for a session while its client is away
+	pub try_send_limit: u64,
+	/// Keep the session of a closed server connection this long so that a client presenting
+	/// its resumption token can take it over. 0 disables sessions.
+	pub session_ttl_micros: i64,
+	/// Handler invocations running longer than this are counted and logged. 0 disables.
+	pub slow_handler_micros: i64,
+	/// Status sent when the server closes a connection, by reason
+	pub close_policy: WsClosePolicy,
+	/// Status of the response to handshakes turned away by the accept handler
+	pub reject_status: u16,
+	/// Name of the runtime the event loops run on, shown in their thread names and log lines
+	pub name: &'static str,
+	/// Frames a worker processes from one connection before the other connections with
+	/// frames waiting get their turn. 0 processes everything a connection has buffered.
+	pub message_budget: u64,
+	/// Call the handler from a pool of this many threads rather than from the event loops,
+	/// so that a slow handler holds up only its own connection. Each connection's messages
+	/// are still handled one at a time and in order. 0 calls it from the event loops.
+	pub dispatch_threads: u64,
+	/// Messages a connection may have waiting for a dispatch thread. A connection whose
+	/// inbox is full is not read from until the handler has worked through half of it.
+	pub inbox_capacity: usize,
+	/// Give each worker a listening socket of its own on every server port, bound with
+	/// SO_REUSEPORT, so that the kernel spreads new connections over the workers instead of
+	/// the workers taking turns accepting from one shared socket. Only Linux balances
+	/// connections between such sockets, elsewhere this is ignored.
+	pub reuse_port: bool,
+	/// Pin each worker's event loop to a CPU of its own, worker n to CPU n modulo the number
+	/// online, so that its connections stay in one core's caches. Only Linux pins threads,
+	/// elsewhere a warning is logged and the workers run unpinned.
+	pub pin_threads: bool,
+	/// Answer pings with a pong and a close with a close of the same status, so that the
+	/// handler only sees closes and none of the pings or pongs. A malformed close fails the
+	/// connection instead. Turn off for handlers which answer control frames themselves.
+	pub auto_control_frames: bool,
+	/// Hold peers to RFC 6455: frames from clients must be masked and those from servers
+	/// not, reserved opcodes, fragmented or oversized control frames and continuations out of
+	/// sequence fail the connection with a protocol error and text messages which are not
+	/// UTF-8 with an invalid payload error, as soon as the offending byte arrives. The
+	/// clients of a WebSocket send unmasked frames, so they cannot connect to a strict one.
+	pub strict: bool,
+	/// Longest message, counting all of its fragments, before the connection is closed with
+	/// a message too big error. Control frames are not counted. 0 for no limit.
+	pub max_message_bytes: u64,
+	/// Most bytes a connection may have waiting to be written to a peer which is not
+	/// reading them. A frame which would take it past this is not sent, the send fails with
+	/// ConnectionClosed and the connection is closed with a try again later status. The
+	/// close is queued past the limit, behind the bytes already waiting, and the connection
+	/// is shut down once they are written, or after timeout_micros if the peer never reads
+	/// them. Frames are not coalesced or compressed to make room. 0 for no limit.
+	pub max_write_buffer_bytes: u64,
+	/// Size of the buffers connections receive into. A connection takes another from its
+	/// worker's pool each time one fills and returns it once its frames are processed.
+	pub read_chunk_size: usize,
+	/// Encrypt every connection, servers' and clients', with a SecureStream under this
+	/// identity. The other end must do the same, a peer speaking plain WebSocket fails the
+	/// handshake and is disconnected. Any peer key is accepted.
+	pub secure_identity: Option<Arc<SecureIdentity>>,
+}
+
+/// Reasons for the server to close a connection on its own account
+#[derive(PartialEq, Clone, Copy)]
+pub enum CloseReason {
+	/// A frame with reserved bits set, or one WsConfig::strict rejects
+	ProtocolError,
+	/// A binary frame whose checksum trailer did not match, or with WsConfig::strict a text
+	/// message which is not UTF-8
+	InvalidPayload,
+	/// A message longer than WsConfig::max_message_bytes
+	MessageTooBig,
+	/// More than WsConfig::max_write_buffer_bytes waiting to be written
+	TryAgainLater,
+	/// The server did not agree to the checksum extension the client requires
+	MissingExtension,
+	/// No traffic for WsConfig::timeout_micros
+	IdleTimeout,
+	/// A write could not be buffered or a deferred reply was dropped
+	InternalError,
+	/// The server is draining
+	GoingAway,
+	/// The handler returned an error
+	HandlerError,
+}
+
+/// Close status for each CloseReason. Every code must be one that RFC 6455 allows in a
+/// close frame, which WebSocket::new checks.
+#[derive(Clone, Copy)]
+pub struct WsClosePolicy {
+	pub protocol_error: u16,
+	pub invalid_payload: u16,
+	pub message_too_big: u16,
+	pub try_again_later: u16,
+	pub missing_extension: u16,
+	pub idle_timeout: u16,
+	pub internal_error: u16,
+	pub going_away: u16,
+	/// 0 leaves the connection open after the error has been logged
+	pub handler_error: u16,
+}
+
+/// Parameters for WebSocket::drain
+pub struct WsDrainConfig {
+	/// Existing connections are closed in this many batches, each connection being assigned
+	/// to one at random
+	pub batches: u64,
+	/// The batches start at even intervals over this window
+	pub window_micros: i64,
+	/// Status of the response to handshakes which arrive while draining
+	pub status: u16,
+	/// Sent in the Retry-After header of that response
+	pub retry_after_secs: u64,
 }
 
 enum ConnectionMessage {
 	Read(Box<Connection>),
-	Write(Ptr<Connection>),
+	Write(Connection),
+	// the connection's inbox has room again
+	Resume(Connection),
+	Dump(Sender<WorkerStats>),
+}
+
+/// Snapshot of a worker's connection list, taken by the worker itself
+#[derive(Clone, Copy, Default)]
+struct WorkerStats {
+	connections: u64,
+	servers: u64,
+	server_connections: u64,
+	clients: u64,
+	handshaking: u64,
+	pending_writes: u64,
+	accept_paused: bool,
 }
 
 struct ConnectionInner {
-	next: Ptr<Connection>,
-	prev: Ptr<Connection>,
-	connptr: Ptr<Connection>,
 	ctype: ConnectionType,
-	cstate: ConnectionState,
-	rbuf: Vec<u8>,
-	wbuf: Vec<u8>,
 	handle: [u8; 4],
-	lock: Lock,
+	// what handle is sent, received and registered through
+	transport: Arc<Box<dyn Transport>>,
+	io: Mutex<ConnectionIo>,
+	// only ever locked by the connection's worker
+	recv: Mutex<ConnectionRecv>,
+	// written by the worker at handshake and read from any thread
+	negotiated: RwLock<Negotiated>,
 	send: Sender<ConnectionMessage>,
 	debug_pending: bool,
-	wakeup: [u8; 8],
+	waker: Waker,
+	// when the connection was accepted or connected, for the handshake timeout
+	opened: i64,
+	clock: Arc<Clock>,
+	buffer_bytes: Arc<AtomicU64>,
+	try_send_limit: u64,
+	max_write_buffer: u64,
+	resume_token: RwLock<Option<[u8; RESUME_TOKEN_LEN]>>,
+	// start of the handler invocation in progress on this connection, 0 between invocations
+	handler_start: AtomicU64,
+	slow_handler_micros: i64,
+	// 1 once yield_now has logged the invocation in progress
+	slow_reported: AtomicU64,
+	close_policy: WsClosePolicy,
+	// this state, for mem_stats
+	_mem: MemCharge,
+}
+
+// the part of a connection which handlers write to from other threads as well as its worker
+struct ConnectionIo {
+	cstate: ConnectionState,
+	wbuf: Deque<u8>,
+	// the capacity of wbuf, for mem_stats
+	wbuf_mem: MemCharge,
 	last: i64,
+	// when a close was queued behind a full write buffer, 0 otherwise. Nothing more is
+	// written and the worker shuts the connection down once the buffer drains.
+	close_pending: i64,
+	// frames waiting for a dispatch thread
+	inbox: Deque<InboxMessage>,
+	// a dispatch task is working through the inbox
+	inbox_running: bool,
+	// not read from until the dispatch task has made room in the inbox
+	inbox_paused: bool,
+	// the worker's boxed handle, which the reactor's events carry
+	connptr: Ptr<Connection>,
+}
+
+// what the worker has read from a connection and not yet processed
+struct ConnectionRecv {
+	rbuf: Rope,
+	// the capacity of rbuf, for mem_stats
+	rbuf_mem: MemCharge,
+	// on its worker's ready ring, waiting for a turn to process the rest of its frames
+	ready: bool,
+	// when a draining worker closes this connection, 0 until it is assigned a batch and
+	// i64::MAX once the close has been sent
+	drain_at: i64,
+	// kept apart from rbuf, which the frame being checked may point into
+	message: MessageProgress,
+}
+
+// the data message being received
+#[derive(Default)]
+struct MessageProgress {
+	// opcode of the fragmented message being received, 0 between messages
+	op: u8,
+	// payload bytes received so far of the message, for WsConfig::max_message_bytes
+	bytes: u64,
+	// WsConfig::strict validation of the text message being received
+	utf8: Utf8Check,
+}
+
+// what the handshake settled
+struct Negotiated {
+	checksum: bool,
+	session: Option<Arc<Session>>,
+	session_generation: u64,
+	// set on server connections when an Authenticator accepted the handshake
+	identity: Option<Arc<Identity>>,
+}
+
+// the transport and the rope are only used under the locks above
+unsafe impl Send for ConnectionInner {}
+unsafe impl Sync for ConnectionInner {}
+
+// a frame on its connection's inbox, copied out of the receive buffer
+struct InboxMessage {
+	fin: bool,
+	op: u8,
+	msg: Vec<u8>,
+}
+
+// what a dispatch task needs to work through one connection's inbox. It holds no reference
+// to the State, which owns the dispatch runtime and so the tasks queued on it.
+struct Dispatch {
+	conn: Connection,
+	handler: Option<Arc<Handler>>,
+	name: &'static str,
+	slow_handlers: Arc<AtomicU64>,
+	auto_control: bool,
+	resume_at: usize,
+}
+
+// server side state which outlives a connection so that a reconnecting client can resume it
+struct Session {
+	id: [u8; SESSION_ID_SIZE],
+	state: RwLock<SessionState>,
+}
+
+// generation is bumped on each resume so that a stale connection closing late does not
+// detach the session from its replacement
+struct SessionState {
+	bound: bool,
+	generation: u64,
+	expires: i64,
+	data: u64,
+	pending: Vec<u8>,
 }
 
+// each clone is a handle of its own on the shared state, only the worker's is ever linked
 struct Connection {
-	inner: Rc<ConnectionInner>,
+	inner: Arc<ConnectionInner>,
+	links: ListLinks<Connection>,
+}
+
+// the links are only followed by the worker whose list the connection is on
+unsafe impl Send for Connection {}
+unsafe impl Sync for Connection {}
+
+/// Frame opcodes of RFC 6455
+#[derive(PartialEq, Clone, Copy)]
+pub enum WsOpcode {
+	Continuation,
+	Text,
+	Binary,
+	Close,
+	Ping,
+	Pong,
+	/// One of the opcodes RFC 6455 leaves unassigned
+	Reserved(u8),
+}
+
+/// The status and reason carried by a close frame, see WsRequest::close_frame and
+/// WebSocket::register_close_handler
+pub struct WsClose<'a> {
+	status: Option<u16>,
+	reason: &'a str,
+}
+
+/// Called with the close frames the peer sends, in place of the handler
+pub type CloseHandler = Box<dyn Fn(WsClose, WsResponse) + Send + Sync>;
+
+/// Called with each open connection found idle for longer than WsConfig::timeout_micros and
+/// how long it has been idle, in microseconds
+pub type IdleHandler = Box<dyn Fn(WsResponse, i64) -> WsIdleAction + Send + Sync>;
+
+/// What to do with a connection which has been idle too long, see
+/// WebSocket::register_idle_handler
+#[derive(Clone, Copy, PartialEq)]
+pub enum WsIdleAction {
+	/// Close with WsClosePolicy::idle_timeout, as is done without an idle handler
+	Timeout,
+	/// Close with this status
+	Close(u16),
+	/// Send a ping and leave the connection open. Sending counts as traffic, so the handler
+	/// is asked again once the connection has been idle for timeout_micros after the ping.
+	Ping,
+	/// Leave the connection open and ask again once it has been idle this many more
+	/// microseconds
+	Extend(i64),
 }
 
 pub struct WsRequest<'a> {
 	msg: &'a [u8],
 	fin: bool,
 	op: u8,
+	arena: &'a Arena,
+}
+
+/// The client's upgrade request as seen by an Authenticator
+pub struct Handshake<'a> {
+	uri: &'a [u8],
+	headers: &'a [u8],
+}
+
+/// What the server knows of a client once its upgrade request has been read, as seen by
+/// the handler registered with WebSocket::register_accept_handler
+pub struct WsHandshakeInfo<'a> {
+	handshake: Handshake<'a>,
+	peer_addr: [u8; 4],
+	peer_port: u16,
 }
 
 enum MessageType {
@@ -82,46 +450,136 @@ pub struct WsResponse {
 	conn: Connection,
 }
 
+/// A reply that is sent after the handler has returned, from any thread. Obtained with
+/// WsResponse::defer_reply. Dropping it without replying closes the connection with
+/// WsClosePolicy::internal_error so that the client is not left waiting for an answer that
+/// will never come.
+pub struct DeferredReply {
+	resp: WsResponse,
+	completed: bool,
+}
+
 pub struct WsServerConfig {
 	addr: [u8; 4],
 	port: u16,
 	backlog: i32,
 }
 
+#[derive(Clone, Copy)]
 pub struct WsClientConfig {
 	addr: [u8; 4],
 	port: u16,
+	proxy: ProxyConfig,
 }
 
 struct WorkerState {
-	head: *mut Connection,
-	wakeup: [u8; 8],
-	mplex: [u8; 4],
+	reactor: Reactor,
 	recv: Receiver<ConnectionMessage>,
 	send: Sender<ConnectionMessage>,
 	comp_recv: Receiver<()>,
 	comp_send: Sender<()>,
+	bufs: BufferPool,
+}
+
+// other threads reach a worker only through send and the reactor's registrations
+unsafe impl Send for WorkerState {}
+unsafe impl Sync for WorkerState {}
+
+// the time connections are stamped and judged idle by. A test holds it and moves it on by
+// hand rather than sleeping past a timeout.
+struct Clock {
+	// the held time in microseconds, 0 while the clock follows getmicros
+	held: AtomicU64,
+}
+
+impl Clock {
+	fn new() -> Self {
+		Self {
+			held: AtomicU64::new(0),
+		}
+	}
+
+	fn now(&self) -> i64 {
+		match self.held.load() {
+			0 => unsafe { getmicros() },
+			held => held as i64,
+		}
+	}
 }
 
 struct State {
 	wstate: Vec<WorkerState>,
-	runtime: Option<Runtime<()>>,
-	handler: Option<Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>>>,
+	// shared by the connections on sockets rather than allocated for each
+	transport: Arc<Box<dyn Transport>>,
+	// the workers, None until start
+	runtime: Mutex<Option<Runtime<()>>>,
+	// runs the handler when WsConfig::dispatch_threads is set, locked by the workers to queue
+	// their tasks
+	dispatch: Mutex<Option<Runtime<()>>>,
+	handler: RwLock<Option<Arc<Handler>>>,
+	// bumped each time the handler is replaced, so that workers know to pick it up
+	handler_generation: AtomicU64,
+	accept_handler: Option<Box<dyn Fn(&WsHandshakeInfo) -> bool + Send + Sync>>,
+	close_handler: Option<CloseHandler>,
+	idle_handler: Option<IdleHandler>,
+	authenticator: Option<Box<dyn Authenticator>>,
 	config: WsConfig,
-	itt: u64,
-	lock: LockBox,
+	itt: AtomicU64,
+	control: RwLock<Control>,
+	buffer_bytes: Arc<AtomicU64>,
+	conn_pool: Pool<ArcInner<ConnectionInner>>,
+	checksum_failures: AtomicU64,
+	// shared with the dispatch tasks
+	slow_handlers: Arc<AtomicU64>,
+	auth_failures: AtomicU64,
+	handshakes_rejected: AtomicU64,
+	drained_workers: AtomicU64,
+	// filled from the workers and from add_client, and the keystream is not thread safe
+	rand: Mutex<Cpsrng>,
+	session_key: [u8; 32],
+	// shared with the connections, which stamp their activity with it
+	clock: Arc<Clock>,
+	#[cfg(test)]
+	test_events: Option<Sender<WsTestEvent>>,
+}
+
+// the part of State which the WebSocket and its workers change after start
+struct Control {
 	halt: bool,
+	drain: Option<WsDrainConfig>,
+	drain_start: i64,
+	drain_complete: Option<Box<dyn FnMut() + Send + Sync>>,
+	// set by shutdown_graceful, when it gives up waiting. 0 otherwise.
+	closing_deadline: i64,
+	// each worker sends on this once it has no connections left during a graceful shutdown
+	workers_closed: Option<Sender<()>>,
+	// keyed by the session id read as a little endian u128
+	sessions: HashMap<u128, Arc<Session>>,
 }
 
 pub struct WsContext {
-	state: Rc<State>,
+	state: Arc<State>,
 	tid: usize,
-	events: *mut u8,
+	conns: IntrusiveList<Connection>,
+	events: Events,
 	last_check: i64,
+	accept_paused: bool,
+	drained: bool,
+	closed: bool,
+	// the handler this worker calls and the generation it was registered as
+	handler: Option<Arc<Handler>>,
+	handler_generation: u64,
+	// connections with frames left over when their budget ran out, in the order of their turns
+	ready: Vec<Ptr<Connection>>,
+	// reset on every iteration of the event loop
+	arena: Arena,
 }
 
+// moved to the worker thread which it belongs to
+unsafe impl Send for WsContext {}
+
 pub struct WebSocket {
-	state: Rc<State>,
+	state: Arc<State>,
 }
 
 impl Clone for WsResponse {
@@ -132,82 +590,357 @@ impl Clone for WsResponse {
 	}
 }
 
+/// Parsed frame header. offset is the length of the header including the masking key.
+pub(crate) struct FrameHeader {
+	pub(crate) fin: bool,
+	pub(crate) op: u8,
+	pub(crate) masking_key: Option<[u8; 4]>,
+	pub(crate) payload_len: usize,
+	pub(crate) offset: usize,
+}
+
+/// Writes the header of an unmasked frame with first byte b1 into buf, returning its length
+pub(crate) fn encode_frame_header(
+	b1: u8,
+	payload_len: usize,
+	buf: &mut [u8; MAX_FRAME_HEADER],
+) -> usize {
+	buf[0] = b1;
+	if payload_len <= 125 {
+		buf[1] = payload_len as u8;
+		2
+	} else if payload_len <= 65535 {
+		buf[1] = 126;
+		to_be_bytes_u16(payload_len as u16, &mut buf[2..4]);
+		4
+	} else {
+		buf[1] = 127;
+		to_be_bytes_u64(payload_len as u64, &mut buf[2..10]);
+		10
+	}
+}
+
+/// Parses the frame header at the start of buf. Returns None if buf does not hold the
+/// complete header yet, the payload itself may still be incomplete.
+pub(crate) fn decode_frame_header(buf: &[u8]) -> Result<Option<FrameHeader>, StaticError> {
+	let len = buf.len();
+	if len < 2 {
+		return Ok(None);
+	}
+	if buf[0] & 0x70 != 0 {
+		return Err(serr!(CorruptedData));
+	}
+
+	let fin = buf[0] & 0x80 != 0;
+	let op = buf[0] & !0x80;
+	let mask = buf[1] & 0x80 != 0;
+
+	// determine variable payload len
+	let payload_len = buf[1] & 0x7F;
+	let (payload_len, mut offset) = if payload_len == 126 {
+		if len < 4 {
+			return Ok(None);
+		}
+		((buf[2] as usize) << 8 | buf[3] as usize, 4)
+	} else if payload_len == 127 {
+		if len < 10 {
+			return Ok(None);
+		}
+		let mut payload_len = 0usize;
+		for i in 2..10 {
+			payload_len = payload_len << 8 | buf[i] as usize;
+		}
+		(payload_len, 10)
+	} else {
+		(payload_len as usize, 2)
+	};
+
+	// if masking set we add 4 bytes for the masking key
+	let masking_key = if mask {
+		offset += 4;
+		if len < offset {
+			return Ok(None);
+		}
+		Some([
+			buf[offset - 4],
+			buf[offset - 3],
+			buf[offset - 2],
+			buf[offset - 1],
+		])
+	} else {
+		None
+	};
+
+	Ok(Some(FrameHeader {
+		fin,
+		op,
+		masking_key,
+		payload_len,
+		offset,
+	}))
+}
+
+pub(crate) fn unmask(payload: &mut [u8], masking_key: &[u8; 4]) {
+	for i in 0..payload.len() {
+		payload[i] ^= masking_key[i % 4];
+	}
+}
+
 impl WsResponse {
 	pub fn send(&mut self, msg: &str) -> Result<(), Error> {
-		self.send_impl(MessageType::Text, msg.as_bytes())
+		self.send_impl(MessageType::Text, msg.as_bytes(), false)
 	}
 
 	pub fn sendb(&mut self, msg: &[u8]) -> Result<(), Error> {
-		self.send_impl(MessageType::Binary, msg)
+		self.send_impl(MessageType::Binary, msg, false)
+	}
+
+	/// Like send, but returns WouldBlock instead of buffering when the frame would leave more
+	/// than WsConfig::try_send_limit bytes queued on the connection. Nothing is written in
+	/// that case so the caller may drop the message.
+	pub fn try_send(&mut self, msg: &str) -> Result<(), Error> {
+		self.send_impl(MessageType::Text, msg.as_bytes(), true)
+	}
+
+	/// Binary version of try_send
+	pub fn try_sendb(&mut self, msg: &[u8]) -> Result<(), Error> {
+		self.send_impl(MessageType::Binary, msg, true)
+	}
+
+	/// Sends a single frame with opcode op, setting the fin bit if fin is true. For handlers
+	/// which relay fragments as they arrive or answer control frames such as ping.
+	pub fn send_frame(&mut self, op: u8, fin: bool, msg: &[u8]) -> Result<(), Error> {
+		if op > 0xF {
+			return Err(err!(IllegalArgument));
+		}
+		let b1 = if fin { 0x80 | op } else { op };
+		self.send_frame_impl(b1, msg, false)
 	}
 
 	pub fn close(&self, status: u16) {
 		self.conn.close(status);
 	}
 
-	fn send_impl(&mut self, mtype: MessageType, bytes: &[u8]) -> Result<(), Error> {
-		let _l = self.conn.inner.lock.write();
-		let b1 = match mtype {
-			MessageType::Text => 0x81,
-			MessageType::Binary => 0x82,
-		};
+	/// Token for replying to the current message once asynchronous work has completed
+	pub fn defer_reply(&self) -> Result<DeferredReply, Error> {
+		match self.clone() {
+			Ok(resp) => Ok(DeferredReply {
+				resp,
+				completed: false,
+			}),
+			Err(e) => Err(e),
+		}
+	}
 
-		if bytes.len() <= 125 {
-			match self.conn.writeb(&[b1, bytes.len() as u8]) {
-				Ok(_) => {}
-				Err(e) => {
-					self.conn.close(1011);
-					return Err(e);
-				}
-			}
-		} else if bytes.len() <= 65535 {
-			match self.conn.writeb(&[b1, 126]) {
-				Ok(_) => {}
-				Err(e) => {
-					self.conn.close(1011);
-					return Err(e);
+	/// True once the underlying socket has been closed by either side
+	pub fn is_closed(&self) -> bool {
+		self.conn.inner.io.lock().cstate == ConnectionState::Closed
+	}
+
+	/// True from the completion of the opening handshake until the connection closes
+	pub fn is_open(&self) -> bool {
+		self.conn.inner.io.lock().cstate == ConnectionState::HandshakeComplete
+	}
+
+	/// Yield point for handlers which do long running work on the event loop thread. Gives
+	/// up the CPU and, the first time it is called after the current invocation has run for
+	/// longer than WsConfig::slow_handler_micros, logs a warning with a backtrace of the
+	/// caller, which points at the code that follows the slow call.
+	pub fn yield_now(&self) {
+		let start = self.conn.inner.handler_start.load();
+		let limit = self.conn.inner.slow_handler_micros;
+		if start != 0 && limit > 0 && self.conn.inner.slow_reported.load() == 0 {
+			let elapsed = getmicros!() - start as i64;
+			if elapsed > limit {
+				self.conn.inner.slow_reported.store(1);
+				match Backtrace::new() {
+					Ok(bt) => match bt.to_string() {
+						Ok(s) => println!("WARN: handler has run for {}us:\n{}", elapsed, s),
+						Err(_) => println!("WARN: handler has run for {}us", elapsed),
+					},
+					Err(_) => println!("WARN: handler has run for {}us", elapsed),
 				}
 			}
-			let mut len = [0u8; 2];
-			to_be_bytes_u16(bytes.len() as u16, &mut len);
-			match self.conn.writeb(&len) {
+		}
+		sched_yield!();
+	}
+
+	/// Token issued by the server at handshake which can be passed to
+	/// WebSocket::resume_client to take over this session after a reconnect
+	pub fn resume_token(&self) -> Option<[u8; RESUME_TOKEN_LEN]> {
+		match *self.conn.inner.resume_token.read() {
+			Some(token) => Some(token),
+			None => None,
+		}
+	}
+
+	/// Id of the server side session, which is kept when a client resumes on a new socket.
+	/// Applications can use it to key their own per client state.
+	pub fn session_id(&self) -> Option<[u8; SESSION_ID_SIZE]> {
+		match &self.conn.inner.negotiated.read().session {
+			Some(session) => Some(session.id),
+			None => None,
+		}
+	}
+
+	/// Identity the server's Authenticator established for this connection, if any
+	pub fn identity(&self) -> Option<Arc<Identity>> {
+		match &self.conn.inner.negotiated.read().identity {
+			// clone always succeeds on arc
+			Some(identity) => Some(identity.clone().unwrap()),
+			None => None,
+		}
+	}
+
+	/// Value stored with set_session_data, 0 if none was stored or there is no session
+	pub fn session_data(&self) -> u64 {
+		match &self.conn.inner.negotiated.read().session {
+			Some(session) => session.state.read().data,
+			None => 0,
+		}
+	}
+
+	/// Stores a value with the session so that it is available after the client resumes
+	pub fn set_session_data(&mut self, data: u64) {
+		match &self.conn.inner.negotiated.read().session {
+			Some(session) => session.state.write().data = data,
+			None => {}
+		}
+	}
+
+	// append a frame to the pending queue of a detached session so that it is delivered
+	// when the client resumes
+	fn queue_detached(&self, parts: &[&[u8]]) -> Result<(), Error> {
+		let negotiated = self.conn.inner.negotiated.read();
+		let session = match &negotiated.session {
+			Some(session) => session,
+			None => return Err(err!(ConnectionClosed)),
+		};
+		let mut session = session.state.write();
+		if session.bound
+			|| session.generation != negotiated.session_generation
+			|| self.conn.inner.clock.now() > session.expires
+		{
+			return Err(err!(ConnectionClosed));
+		}
+		let mut len = session.pending.len();
+		for part in parts {
+			len += part.len();
+		}
+		if len as u64 > self.conn.inner.try_send_limit {
+			return Err(err!(WouldBlock));
+		}
+		for part in parts {
+			match session.pending.append_ptr(part.as_ptr(), part.len()) {
 				Ok(_) => {}
-				Err(e) => {
-					self.conn.close(1011);
-					return Err(e);
-				}
+				Err(e) => return Err(e),
 			}
+		}
+		Ok(())
+	}
+
+	fn send_impl(&mut self, mtype: MessageType, bytes: &[u8], strict: bool) -> Result<(), Error> {
+		let b1 = match mtype {
+			MessageType::Text => 0x81,
+			MessageType::Binary => 0x82,
+		};
+		self.send_frame_impl(b1, bytes, strict)
+	}
+
+	fn send_frame_impl(&mut self, b1: u8, bytes: &[u8], strict: bool) -> Result<(), Error> {
+		let mut io = self.conn.inner.io.lock();
+		// the receiver expects a trailer on every binary frame, fragments included
+		let checksum = b1 & 0x0F == 0x2 && self.conn.inner.negotiated.read().checksum;
+		let payload_len = if checksum {
+			bytes.len() + CHECKSUM_SIZE
 		} else {
-			match self.conn.writeb(&[b1, 127]) {
-				Ok(_) => {}
-				Err(e) => {
-					self.conn.close(1011);
-					return Err(e);
-				}
+			bytes.len()
+		};
+
+		let mut header = [0u8; MAX_FRAME_HEADER];
+		let header_len = encode_frame_header(b1, payload_len, &mut header);
+		if io.cstate == ConnectionState::Closed {
+			let mut trailer = [0u8; CHECKSUM_SIZE];
+			let trailer_len = if checksum {
+				to_be_bytes_u32(crc32c(bytes), &mut trailer);
+				CHECKSUM_SIZE
+			} else {
+				0
+			};
+			return self.queue_detached(&[&header[0..header_len], bytes, &trailer[0..trailer_len]]);
+		}
+		if io.close_pending != 0 {
+			return Err(err!(ConnectionClosed));
+		}
+		let queued = (io.wbuf.len() + header_len + payload_len) as u64;
+		if strict && queued > self.conn.inner.try_send_limit {
+			return Err(err!(WouldBlock));
+		}
+		let max = self.conn.inner.max_write_buffer;
+		if max > 0 && queued > max {
+			drop(io);
+			self.conn.close_after_flush(CloseReason::TryAgainLater);
+			return Err(err!(ConnectionClosed));
+		}
+
+		let mut trailer = [0u8; CHECKSUM_SIZE];
+		let trailer_len = if checksum {
+			to_be_bytes_u32(crc32c(bytes), &mut trailer);
+			CHECKSUM_SIZE
+		} else {
+			0
+		};
+		// the frame goes out whole before another thread's frame can be written
+		for part in [&header[0..header_len], bytes, &trailer[0..trailer_len]] {
+			if part.len() == 0 {
+				continue;
 			}
-			let mut len = [0u8; 8];
-			to_be_bytes_u64(bytes.len() as u64, &mut len);
-			match self.conn.writeb(&len) {
+			match self.conn.writeb_locked(&mut io, part) {
 				Ok(_) => {}
 				Err(e) => {
-					self.conn.close(1011);
-					return Err(e);
+					drop(io);
+					self.conn.close_for(CloseReason::InternalError);
+					return Err(e.into());
 				}
 			}
 		}
+		Ok(())
+	}
+}
 
-		match self.conn.writeb(bytes) {
-			Ok(_) => {}
-			Err(e) => {
-				self.conn.close(1011);
-				return Err(e);
-			}
+impl Drop for DeferredReply {
+	fn drop(&mut self) {
+		if !self.completed && !self.resp.is_closed() {
+			self.resp.conn.close_for(CloseReason::InternalError);
 		}
-		Ok(())
 	}
 }
 
-impl WsRequest<'_> {
+impl DeferredReply {
+	pub fn reply(mut self, msg: &str) -> Result<(), Error> {
+		self.complete(MessageType::Text, msg.as_bytes())
+	}
+
+	pub fn replyb(mut self, msg: &[u8]) -> Result<(), Error> {
+		self.complete(MessageType::Binary, msg)
+	}
+
+	/// True if the connection closed before a reply was sent, in which case reply returns
+	/// ConnectionClosed
+	pub fn is_closed(&self) -> bool {
+		self.resp.is_closed()
+	}
+
+	fn complete(&mut self, mtype: MessageType, msg: &[u8]) -> Result<(), Error> {
+		self.completed = true;
+		if self.resp.is_closed() {
+			return Err(err!(ConnectionClosed));
+		}
+		self.resp.send_impl(mtype, msg, false)
+	}
+}
+
+impl<'a> WsRequest<'a> {
 	pub fn msg(&self) -> &[u8] {
 		self.msg
 	}
@@ -219,130 +952,699 @@ impl WsRequest<'_> {
 	pub fn op(&self) -> u8 {
 		self.op
 	}
-}
 
-impl Default for WsConfig {
-	fn default() -> Self {
-		Self {
-			threads: 4,
-			max_events: 32,
-			debug_pending: false,
-			timeout_micros: 1_000_000 * 60,
+	pub fn opcode(&self) -> WsOpcode {
+		WsOpcode::from_u8(self.op)
+	}
+
+	/// True for close, ping, pong and the reserved control opcodes
+	pub fn is_control(&self) -> bool {
+		self.opcode().is_control()
+	}
+
+	/// The status and reason of a close frame. None for other frames and for a close whose
+	/// payload is malformed: a single byte, a status which may not be sent or a reason which
+	/// is not UTF-8.
+	pub fn close_frame(&self) -> Option<WsClose<'a>> {
+		if self.opcode() != WsOpcode::Close {
+			return None;
+		}
+		match parse_close(self.msg) {
+			Ok(close) => Some(close),
+			Err(_) => None,
 		}
 	}
-}
 
-impl Clone for Connection {
-	fn clone(&self) -> Result<Self, Error> {
-		Ok(Self {
-			inner: self.inner.clone().unwrap(),
-		})
+	/// Scratch memory for the handler, which the worker reclaims after the current turn of
+	/// its event loop. Nothing allocated here may be kept past the handler's return.
+	pub fn arena(&self) -> &'a Arena {
+		self.arena
 	}
 }
 
-impl Connection {
-	fn new(
-		ctype: ConnectionType,
-		handle: [u8; 4],
-		send: Sender<ConnectionMessage>,
-		debug_pending: bool,
-		wakeup: [u8; 8],
-	) -> Result<Self, Error> {
-		let mut rbuf = Vec::new();
-		rbuf.set_min(0);
-		match Rc::new(ConnectionInner {
-			next: Ptr::null(),
-			prev: Ptr::null(),
-			connptr: Ptr::null(),
-			ctype,
-			rbuf,
-			wbuf: Vec::new(),
-			handle,
-			lock: lock!(),
-			cstate: ConnectionState::NeedHandshake,
-			send,
-			debug_pending,
-			wakeup,
-			last: unsafe { getmicros() },
-		}) {
-			Ok(inner) => Ok(Self { inner }),
-			Err(e) => Err(e),
+impl<'a> WsClose<'a> {
+	/// None if the close frame had no payload
+	pub fn status(&self) -> Option<u16> {
+		match self.status {
+			Some(status) => Some(status),
+			None => None,
 		}
 	}
 
-	fn writeb(&self, msg: &[u8]) -> Result<(), Error> {
-		let mut inner = self.inner.clone().unwrap();
-		inner.last = unsafe { getmicros() };
-		if self.inner.cstate == ConnectionState::Closed {
-			return Err(err!(ConnectionClosed));
-		}
-		let mut res = if inner.wbuf.len() == 0 && !self.inner.debug_pending {
-			unsafe { socket_send(&inner.handle as *const u8, msg.as_ptr(), msg.len()) }
-		} else {
-			0
-		};
-		if res == EAGAIN.into() || (res >= 0 && (res as usize) < msg.len()) {
-			if res < 0 {
-				res = 0;
-			}
-			unsafe {
-				match inner
-					.wbuf
-					.append_ptr(msg.as_ptr().add(res as usize), msg.len() - (res as usize))
+	/// Empty if the peer gave no reason
+	pub fn reason(&self) -> &'a str {
+		self.reason
+	}
+}
+
+impl Dispatch {
+	// works through the inbox until it is empty, letting the worker read from the connection
+	// again once a full inbox is down to resume_at
+	fn run(&mut self) {
+		let mut arena = match Arena::new(ARENA_CHUNK_SIZE) {
+			Ok(arena) => arena,
+			Err(_e) => {
+				println!(
+					"WARN: [{}] Could not allocate dispatch arena! Closing connection.",
+					self.name
+				);
 				{
-					Ok(_) => {}
-					Err(_e) => {
-						// could not allocate space to append data to buffer. Close socket.
-						println!(
-							"WARN: Could not allocate space to write buffer. Dropping connection!"
-						);
-						let _ = self.close(1011);
-						return Err(err!(IO));
-					}
+					let mut io = self.conn.inner.io.lock();
+					io.inbox.clear();
+					io.inbox_running = false;
 				}
+				self.conn.close_for(CloseReason::InternalError);
+				return;
 			}
-
-			match self
-				.inner
-				.send
-				.send(ConnectionMessage::Write(self.inner.connptr))
-			{
-				Ok(_) => {}
-				Err(e) => return Err(e),
+		};
+		loop {
+			let (message, resume) = {
+				let mut io = self.conn.inner.io.lock();
+				let message = match io.inbox.pop_front() {
+					Some(message) => message,
+					None => {
+						io.inbox_running = false;
+						return;
+					}
+				};
+				let resume = io.inbox_paused && io.inbox.len() <= self.resume_at;
+				if resume {
+					io.inbox_paused = false;
+				}
+				(message, resume)
+			};
+			if resume {
+				self.conn.resume();
 			}
-
-			unsafe {
-				socket_send((&self.inner.wakeup as *const u8).add(4), &b'0', 1);
+			arena.reset();
+			let req = WsRequest {
+				fin: message.fin,
+				op: message.op,
+				msg: message.msg.as_slice(),
+				arena: &arena,
+			};
+			match &self.handler {
+				Some(handler) => WebSocket::call_handler(
+					handler,
+					req,
+					self.conn.share(),
+					self.name,
+					&self.slow_handlers,
+				),
+				None => {}
 			}
-		} else if res < 0 {
-			unsafe {
-				socket_shutdown(&self.inner.handle as *const u8);
+			// closes reach the inbox parsed, see proc_hs_complete
+			if self.auto_control && WsOpcode::from_u8(message.op) == WsOpcode::Close {
+				match parse_close(message.msg.as_slice()) {
+					Ok(close) => self.conn.close_echo(close.status()),
+					Err(_) => {}
+				}
 			}
 		}
-
-		Ok(())
 	}
+}
 
-	fn write(&self, msg: &str) -> Result<(), Error> {
-		self.writeb(msg.as_bytes())
+impl WsOpcode {
+	/// The opcode in the low four bits of op
+	pub fn from_u8(op: u8) -> Self {
+		match op & 0x0F {
+			0x0 => WsOpcode::Continuation,
+			0x1 => WsOpcode::Text,
+			0x2 => WsOpcode::Binary,
+			0x8 => WsOpcode::Close,
+			0x9 => WsOpcode::Ping,
+			0xA => WsOpcode::Pong,
+			op => WsOpcode::Reserved(op),
+		}
 	}
 
-	pub fn close(&self, v: u16) {
-		if self.inner.cstate != ConnectionState::NeedHandshake {
-			let mut status_code = [0u8; 2];
-			to_be_bytes_u16(v, &mut status_code);
-			let _ = self.writeb(&[0x88, 0]);
-			let _ = self.writeb(&[0x88, 2]);
-			let _ = self.writeb(&status_code);
+	pub fn to_u8(&self) -> u8 {
+		match self {
+			WsOpcode::Continuation => 0x0,
+			WsOpcode::Text => 0x1,
+			WsOpcode::Binary => 0x2,
+			WsOpcode::Close => 0x8,
+			WsOpcode::Ping => 0x9,
+			WsOpcode::Pong => 0xA,
+			WsOpcode::Reserved(op) => *op,
 		}
-		unsafe {
-			socket_shutdown(&self.inner.handle as *const u8);
+	}
+
+	/// Control frames, the opcodes from 0x8, may not be fragmented
+	pub fn is_control(&self) -> bool {
+		self.to_u8() >= 0x8
+	}
+}
+
+impl<'a> WsHandshakeInfo<'a> {
+	/// Requested path, for example `/chat`
+	pub fn uri(&self) -> &[u8] {
+		self.handshake.uri()
+	}
+
+	/// Value of the first header called name, matched case-sensitively
+	pub fn header(&self, name: &str) -> Option<&[u8]> {
+		self.handshake.header(name)
+	}
+
+	/// The Origin header, which browsers send with the page the connection was opened from
+	pub fn origin(&self) -> Option<&[u8]> {
+		self.handshake.header("Origin")
+	}
+
+	/// Address and port the client connected from
+	pub fn peer_addr(&self) -> ([u8; 4], u16) {
+		(self.peer_addr, self.peer_port)
+	}
+}
+
+impl<'a> Handshake<'a> {
+	/// uri is the requested path and headers the rest of the request from the end of the
+	/// path, with each header on a line of its own
+	pub fn new(uri: &'a [u8], headers: &'a [u8]) -> Self {
+		Self { uri, headers }
+	}
+
+	/// Requested path, for example `/chat`
+	pub fn uri(&self) -> &[u8] {
+		self.uri
+	}
+
+	/// Value of the first header called name, matched case-sensitively
+	pub fn header(&self, name: &str) -> Option<&[u8]> {
+		let name = name.as_bytes();
+		let buf = self.headers;
+		for i in 0..buf.len() {
+			let start = i + 1 + name.len() + 2;
+			if buf[i] == b'\n'
+				&& buf.len() > start
+				&& &buf[i + 1..i + 1 + name.len()] == name
+				&& &buf[start - 2..start] == b": "
+			{
+				for j in start..buf.len() {
+					if buf[j] == b'\r' || buf[j] == b'\n' {
+						return Some(&buf[start..j]);
+					}
+				}
+			}
+		}
+		None
+	}
+
+	/// Credentials of the Authorization header if it uses scheme, e.g. `Bearer`
+	pub fn authorization(&self, scheme: &str) -> Option<&[u8]> {
+		let scheme = scheme.as_bytes();
+		match self.header("Authorization") {
+			Some(value) => {
+				if value.len() > scheme.len()
+					&& &value[0..scheme.len()] == scheme
+					&& value[scheme.len()] == b' '
+				{
+					Some(&value[scheme.len() + 1..])
+				} else {
+					None
+				}
+			}
+			None => None,
+		}
+	}
+}
+
+/// True if code may be sent in a close frame: 1000 to 1003 and 1007 to 1011 from RFC 6455,
+/// 1012 to 1014 which IANA registered since and 3000 to 4999 for libraries and applications.
+/// 1004 to 1006 and 1015 are reserved and the rest of 1000 to 2999 is unassigned.
+pub fn valid_close_code(code: u16) -> bool {
+	match code {
+		1000..=1003 | 1007..=1014 | 3000..=4999 => true,
+		_ => false,
+	}
+}
+
+// the status and reason of a close frame's payload, or the reason to fail the connection for
+// a malformed one
+fn parse_close(payload: &[u8]) -> Result<WsClose<'_>, CloseReason> {
+	if payload.len() == 0 {
+		return Ok(WsClose {
+			status: None,
+			reason: "",
+		});
+	}
+	if payload.len() < 2 {
+		return Err(CloseReason::ProtocolError);
+	}
+	let status = from_be_bytes_u16(payload);
+	if !valid_close_code(status) {
+		return Err(CloseReason::ProtocolError);
+	}
+	match from_utf8(&payload[2..]) {
+		CoreResult::Ok(reason) => Ok(WsClose {
+			status: Some(status),
+			reason,
+		}),
+		CoreResult::Err(_e) => Err(CloseReason::InvalidPayload),
+	}
+}
+
+// validates a text message a fragment at a time, so that a bad byte is found as soon as it
+// arrives and a code point may be split between fragments
+#[derive(Clone, Copy, Default)]
+struct Utf8Check {
+	// continuation bytes still to come of the current code point
+	need: u8,
+	// range of the next continuation byte, narrower after some lead bytes to rule out
+	// overlong encodings, surrogates and code points past U+10FFFF
+	lo: u8,
+	hi: u8,
+}
+
+impl Utf8Check {
+	fn update(&mut self, bytes: &[u8]) -> bool {
+		for i in 0..bytes.len() {
+			let b = bytes[i];
+			if self.need > 0 {
+				if b < self.lo || b > self.hi {
+					return false;
+				}
+				self.need -= 1;
+				self.lo = 0x80;
+				self.hi = 0xBF;
+				continue;
+			}
+			let (need, lo, hi) = match b {
+				0x00..=0x7F => continue,
+				0xC2..=0xDF => (1, 0x80, 0xBF),
+				0xE0 => (2, 0xA0, 0xBF),
+				0xED => (2, 0x80, 0x9F),
+				0xE1..=0xEF => (2, 0x80, 0xBF),
+				0xF0 => (3, 0x90, 0xBF),
+				0xF1..=0xF3 => (3, 0x80, 0xBF),
+				0xF4 => (3, 0x80, 0x8F),
+				_ => return false,
+			};
+			self.need = need;
+			self.lo = lo;
+			self.hi = hi;
+		}
+		true
+	}
+
+	// whether the bytes so far end on a code point boundary
+	fn is_complete(&self) -> bool {
+		self.need == 0
+	}
+}
+
+/// Reason phrase for the status sent to handshakes rejected by the accept handler or while
+/// draining
+fn reason_phrase(status: u16) -> &'static str {
+	match status {
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		429 => "Too Many Requests",
+		500 => "Internal Server Error",
+		502 => "Bad Gateway",
+		503 => "Service Unavailable",
+		504 => "Gateway Timeout",
+		_ => "Unavailable",
+	}
+}
+
+impl Default for WsConfig {
+	fn default() -> Self {
+		Self {
+			threads: 4,
+			max_events: 32,
+			debug_pending: false,
+			timeout_micros: 1_000_000 * 60,
+			stale_check_micros: 5_000_000,
+			handshake_timeout_micros: 1_000_000 * 10,
+			max_handshake_bytes: 8192,
+			max_buffer_bytes: 256 * 1024 * 1024,
+			buffer_low_water: 192 * 1024 * 1024,
+			frame_checksum: false,
+			try_send_limit: 1024 * 1024,
+			session_ttl_micros: 0,
+			slow_handler_micros: 0,
+			close_policy: WsClosePolicy::default(),
+			reject_status: 403,
+			message_budget: 16,
+			dispatch_threads: 0,
+			inbox_capacity: 64,
+			read_chunk_size: ROPE_CHUNK_SIZE,
+			reuse_port: false,
+			pin_threads: false,
+			auto_control_frames: true,
+			strict: false,
+			max_message_bytes: 0,
+			max_write_buffer_bytes: 0,
+			name: "ws",
+			secure_identity: None,
+		}
+	}
+}
+
+impl Default for WsClosePolicy {
+	fn default() -> Self {
+		Self {
+			protocol_error: 1002,
+			invalid_payload: 1007,
+			message_too_big: 1009,
+			try_again_later: 1013,
+			missing_extension: 1010,
+			idle_timeout: 1001,
+			internal_error: 1011,
+			going_away: 1001,
+			handler_error: 0,
+		}
+	}
+}
+
+impl WsClosePolicy {
+	/// Status to send when closing for reason, 0 if the connection is to stay open
+	pub fn code(&self, reason: CloseReason) -> u16 {
+		match reason {
+			CloseReason::ProtocolError => self.protocol_error,
+			CloseReason::InvalidPayload => self.invalid_payload,
+			CloseReason::MessageTooBig => self.message_too_big,
+			CloseReason::TryAgainLater => self.try_again_later,
+			CloseReason::MissingExtension => self.missing_extension,
+			CloseReason::IdleTimeout => self.idle_timeout,
+			CloseReason::InternalError => self.internal_error,
+			CloseReason::GoingAway => self.going_away,
+			CloseReason::HandlerError => self.handler_error,
+		}
+	}
+
+	fn is_valid(&self) -> bool {
+		valid_close_code(self.protocol_error)
+			&& valid_close_code(self.invalid_payload)
+			&& valid_close_code(self.message_too_big)
+			&& valid_close_code(self.try_again_later)
+			&& valid_close_code(self.missing_extension)
+			&& valid_close_code(self.idle_timeout)
+			&& valid_close_code(self.internal_error)
+			&& valid_close_code(self.going_away)
+			&& (self.handler_error == 0 || valid_close_code(self.handler_error))
+	}
+}
+
+impl Default for WsDrainConfig {
+	fn default() -> Self {
+		Self {
+			batches: 10,
+			window_micros: 1_000_000 * 30,
+			status: 503,
+			retry_after_secs: 30,
+		}
+	}
+}
+
+impl WsServerConfig {
+	pub fn new(addr: [u8; 4], port: u16, backlog: i32) -> Self {
+		Self {
+			addr,
+			port,
+			backlog,
+		}
+	}
+}
+
+impl WsClientConfig {
+	pub fn new(addr: [u8; 4], port: u16) -> Self {
+		Self {
+			addr,
+			port,
+			proxy: ProxyConfig::direct(),
+		}
+	}
+
+	/// Connects through proxy, which opens the tunnel before the upgrade request is sent
+	pub fn with_proxy(self, proxy: ProxyConfig) -> Self {
+		Self { proxy, ..self }
+	}
+
+	// the socket of a new connection to the server
+	fn connect(&self) -> Result<[u8; 4], Error> {
+		match self.proxy.connect(self.addr, self.port) {
+			Ok(stream) => Ok(stream.into_handle()),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Clone for Connection {
+	fn clone(&self) -> Result<Self, Error> {
+		Ok(self.share())
+	}
+}
+
+impl Linked for Connection {
+	fn links(&self) -> &ListLinks<Self> {
+		&self.links
+	}
+	fn links_mut(&mut self) -> &mut ListLinks<Self> {
+		&mut self.links
+	}
+}
+
+impl Connection {
+	// a connection of worker tid, its settings taken from the WebSocket's state
+	fn new(
+		ctype: ConnectionType,
+		handle: [u8; 4],
+		transport: Arc<Box<dyn Transport>>,
+		state: &State,
+		tid: usize,
+	) -> Result<Self, Error> {
+		if ctype != ConnectionType::Server {
+			match transport.attach(handle, ctype == ConnectionType::ClientConnection) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		let wstate = &state.wstate[tid];
+		let send = match wstate.send.clone() {
+			Ok(send) => send,
+			Err(e) => return Err(e),
+		};
+		let bufs = match wstate.bufs.clone() {
+			Ok(bufs) => bufs,
+			Err(e) => return Err(e),
+		};
+		let buffer_bytes = match state.buffer_bytes.clone() {
+			Ok(buffer_bytes) => buffer_bytes,
+			Err(e) => return Err(e),
+		};
+		let rbuf = match Rope::with_pool(state.config.read_chunk_size, bufs) {
+			Ok(rbuf) => rbuf,
+			Err(e) => return Err(e),
+		};
+		let clock = match state.clock.clone() {
+			Ok(clock) => clock,
+			Err(e) => return Err(e),
+		};
+		let now = clock.now();
+		match Arc::new_pooled(
+			ConnectionInner {
+				ctype,
+				handle,
+				transport,
+				io: Mutex::new(ConnectionIo {
+					cstate: ConnectionState::NeedHandshake,
+					wbuf: Deque::new(),
+					wbuf_mem: MemCharge::new(MemTag::Buffer, 0),
+					last: now,
+					close_pending: 0,
+					inbox: Deque::new(),
+					inbox_running: false,
+					inbox_paused: false,
+					connptr: Ptr::null(),
+				}),
+				recv: Mutex::new(ConnectionRecv {
+					rbuf,
+					rbuf_mem: MemCharge::new(MemTag::Buffer, 0),
+					ready: false,
+					drain_at: 0,
+					message: MessageProgress::default(),
+				}),
+				// a client asks for checksums in its upgrade request, see init_client
+				negotiated: RwLock::new(Negotiated {
+					checksum: ctype == ConnectionType::ClientConnection
+						&& state.config.frame_checksum,
+					session: None,
+					session_generation: 0,
+					identity: None,
+				}),
+				send,
+				debug_pending: state.config.debug_pending,
+				waker: wstate.reactor.waker(),
+				opened: now,
+				clock,
+				buffer_bytes,
+				try_send_limit: state.config.try_send_limit,
+				max_write_buffer: state.config.max_write_buffer_bytes,
+				resume_token: RwLock::new(None),
+				handler_start: AtomicU64::new(0),
+				slow_handler_micros: state.config.slow_handler_micros,
+				slow_reported: AtomicU64::new(0),
+				close_policy: state.config.close_policy,
+				_mem: MemCharge::new(MemTag::Connection, size_of::<ConnectionInner>() as u64),
+			},
+			&state.conn_pool,
+		) {
+			Ok(inner) => Ok(Self {
+				inner,
+				links: ListLinks::new(),
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	// another handle on the same connection, which is not on any list
+	fn share(&self) -> Self {
+		Self {
+			// clone always succeeds on arc
+			inner: self.inner.clone().unwrap(),
+			links: ListLinks::new(),
+		}
+	}
+
+	// called for every frame so errors are StaticError, converted by the public senders
+	fn writeb(&self, msg: &[u8]) -> Result<(), StaticError> {
+		let mut io = self.inner.io.lock();
+		self.writeb_locked(&mut io, msg)
+	}
+
+	// writeb for a caller which already holds io
+	fn writeb_locked(&self, io: &mut ConnectionIo, msg: &[u8]) -> Result<(), StaticError> {
+		io.last = self.inner.clock.now();
+		if io.cstate == ConnectionState::Closed || io.close_pending != 0 {
+			return Err(serr!(ConnectionClosed));
+		}
+		let mut res = if io.wbuf.len() == 0 && !self.inner.debug_pending {
+			self.inner.transport.send(self.inner.handle, msg)
+		} else {
+			0
+		};
+		if res == EAGAIN.into() || (res >= 0 && (res as usize) < msg.len()) {
+			if res < 0 {
+				res = 0;
+			}
+			match io.wbuf.extend_from_slice(&msg[res as usize..]) {
+				Ok(_) => {
+					let appended = (msg.len() - (res as usize)) as u64;
+					self.inner.buffer_bytes.fetch_add(appended);
+					let capacity = io.wbuf.capacity() as u64;
+					io.wbuf_mem.set(capacity);
+				}
+				Err(_e) => {
+					// could not allocate space to append data to buffer. Close socket, there
+					// is no room for a close frame either.
+					println!(
+						"WARN: Could not allocate space to write buffer. Dropping connection!"
+					);
+					self.shutdown();
+					return Err(serr!(IO));
+				}
+			}
+
+			// the worker checks whether the connection is still open before registering it
+			let conn = match self.clone() {
+				Ok(conn) => conn,
+				Err(e) => return Err(serr!(e.kind)),
+			};
+			match self.inner.send.send(ConnectionMessage::Write(conn)) {
+				Ok(_) => {}
+				Err(e) => return Err(serr!(e.kind)),
+			}
+
+			let _ = self.inner.waker.wake();
+		} else if res < 0 {
+			self.shutdown();
+		}
+
+		Ok(())
+	}
+
+	fn write(&self, msg: &str) -> Result<(), StaticError> {
+		self.writeb(msg.as_bytes())
+	}
+
+	fn close_for(&self, reason: CloseReason) {
+		self.close(self.inner.close_policy.code(reason));
+	}
+
+	// the close frame may take the write buffer past its limit, so a peer which reads
+	// what is waiting sees why the connection went away
+	fn close_after_flush(&self, reason: CloseReason) {
+		self.write_close(self.inner.close_policy.code(reason));
+		let mut io = self.inner.io.lock();
+		io.close_pending = self.inner.clock.now();
+		// the worker may have drained the buffer before close_pending was set
+		if io.wbuf.len() == 0 {
+			self.shutdown();
+		}
+	}
+
+	// answers a close with the same status, or a normal closure for one without
+	fn close_echo(&self, status: Option<u16>) {
+		match status {
+			Some(status) => self.close(status),
+			None => self.close(1000),
+		}
+	}
+
+	// asks the worker to read from the connection again once its inbox has room
+	fn resume(&self) {
+		let conn = self.share();
+		if self
+			.inner
+			.send
+			.send(ConnectionMessage::Resume(conn))
+			.is_ok()
+		{
+			let _ = self.inner.waker.wake();
+		}
+	}
+
+	pub fn close(&self, v: u16) {
+		self.write_close(v);
+		self.shutdown();
+	}
+
+	fn write_close(&self, v: u16) {
+		if self.inner.io.lock().cstate != ConnectionState::NeedHandshake {
+			// a status the peer would have to treat as a protocol error is left out
+			if valid_close_code(v) {
+				let mut frame = [0x88, 2, 0, 0];
+				to_be_bytes_u16(v, &mut frame[2..4]);
+				let _ = self.writeb(&frame);
+			} else {
+				println!(
+					"WARN: not sending close status {} which RFC 6455 forbids",
+					v
+				);
+				let _ = self.writeb(&[0x88, 0]);
+			}
 		}
 	}
+
+	fn shutdown(&self) {
+		self.inner.transport.shutdown(self.inner.handle);
+	}
+
+	fn register(&self, reactor: &Reactor, interest: i32) -> Result<(), Error> {
+		let connptr = self.inner.io.lock().connptr;
+		self.inner
+			.transport
+			.register(reactor, self.inner.handle, interest, connptr.raw() as usize)
+	}
 }
 
 impl WorkerState {
-	fn new(wakeup: [u8; 8], mplex: [u8; 4]) -> Result<Self, Error> {
+	fn new(reactor: Reactor) -> Result<Self, Error> {
 		let (send, recv) = match channel() {
 			Ok((send, recv)) => (send, recv),
 			Err(e) => return Err(e),
@@ -351,108 +1653,315 @@ impl WorkerState {
 			Ok((send, recv)) => (send, recv),
 			Err(e) => return Err(e),
 		};
+		let bufs = match BufferPool::new(READ_BUFFER_POOL_SIZE) {
+			Ok(bufs) => bufs,
+			Err(e) => return Err(e),
+		};
 		Ok(Self {
-			mplex,
-			wakeup,
-			head: null_mut(),
+			reactor,
 			send,
 			recv,
 			comp_send,
 			comp_recv,
+			bufs,
 		})
 	}
 }
 
 impl State {
 	fn new(config: WsConfig) -> Result<Self, Error> {
-		let lock = match lock_box!() {
-			Ok(lock) => lock,
+		let buffer_bytes = match Arc::new(AtomicU64::new(0)) {
+			Ok(buffer_bytes) => buffer_bytes,
+			Err(e) => return Err(e),
+		};
+		let slow_handlers = match Arc::new(AtomicU64::new(0)) {
+			Ok(slow_handlers) => slow_handlers,
+			Err(e) => return Err(e),
+		};
+		let clock = match Arc::new(Clock::new()) {
+			Ok(clock) => clock,
+			Err(e) => return Err(e),
+		};
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		let mut session_key = [0u8; 32];
+		rand.fill(&mut session_key);
+		let conn_pool = match Pool::new(CONNECTION_POOL_SIZE) {
+			Ok(conn_pool) => conn_pool,
+			Err(e) => return Err(e),
+		};
+		let sessions = match HashMap::new() {
+			Ok(sessions) => sessions,
+			Err(e) => return Err(e),
+		};
+		let transport: Box<dyn Transport> = match &config.secure_identity {
+			Some(identity) => {
+				let secure = match identity.clone() {
+					Ok(identity) => match SecureTransport::new(identity) {
+						Ok(secure) => secure,
+						Err(e) => return Err(e),
+					},
+					Err(e) => return Err(e),
+				};
+				match Box::new(secure) {
+					Ok(transport) => transport,
+					Err(e) => return Err(e),
+				}
+			}
+			None => match Box::new(SocketTransport) {
+				Ok(transport) => transport,
+				Err(e) => return Err(e),
+			},
+		};
+		let transport = match Arc::new(transport) {
+			Ok(transport) => transport,
 			Err(e) => return Err(e),
 		};
 
 		Ok(Self {
-			runtime: None,
+			runtime: Mutex::new(None),
+			dispatch: Mutex::new(None),
 			wstate: Vec::new(),
+			transport,
 			config,
-			handler: None,
-			itt: 0,
-			lock,
-			halt: false,
+			handler: RwLock::new(None),
+			handler_generation: AtomicU64::new(0),
+			accept_handler: None,
+			close_handler: None,
+			idle_handler: None,
+			authenticator: None,
+			itt: AtomicU64::new(0),
+			control: RwLock::new(Control {
+				halt: false,
+				drain: None,
+				drain_start: 0,
+				drain_complete: None,
+				closing_deadline: 0,
+				workers_closed: None,
+				sessions,
+			}),
+			buffer_bytes,
+			conn_pool,
+			checksum_failures: AtomicU64::new(0),
+			slow_handlers,
+			auth_failures: AtomicU64::new(0),
+			handshakes_rejected: AtomicU64::new(0),
+			drained_workers: AtomicU64::new(0),
+			rand: Mutex::new(rand),
+			session_key,
+			clock,
+			#[cfg(test)]
+			test_events: None,
 		})
 	}
+
+	#[cfg(test)]
+	fn emit(&self, event: WsTestEvent) {
+		match &self.test_events {
+			Some(send) => {
+				let _ = send.send(event);
+			}
+			None => {}
+		}
+	}
 }
 
 impl WebSocket {
-	pub fn new(config: WsConfig) -> Result<Self, Error> {
+	pub fn new(mut config: WsConfig) -> Result<Self, Error> {
+		config.reuse_port = config.reuse_port && cfg!(target_os = "linux");
+		if !config.close_policy.is_valid()
+			|| config.reject_status < 400
+			|| config.reject_status > 599
+			|| config.read_chunk_size == 0
+			|| config.max_handshake_bytes == 0
+			|| config.stale_check_micros <= 0
+			|| (config.dispatch_threads > 0 && config.inbox_capacity == 0)
+		{
+			return Err(err!(IllegalArgument));
+		}
 		let state = match State::new(config) {
 			Ok(state) => state,
 			Err(e) => return Err(e),
 		};
 		Ok(Self {
-			state: Rc::new(state).unwrap(),
+			state: Arc::new(state).unwrap(),
 		})
 	}
 
 	pub fn add_client(&mut self, config: WsClientConfig) -> Result<WsResponse, Error> {
+		let client = match config.connect() {
+			Ok(client) => client,
+			Err(e) => return Err(e),
+		};
+		self.init_client(client, None, None)
+	}
+
+	/// Connects like add_client, sending authorization as the value of the Authorization
+	/// header of the upgrade request, for example `Bearer <token>`
+	pub fn add_client_auth(
+		&mut self,
+		config: WsClientConfig,
+		authorization: &str,
+	) -> Result<WsResponse, Error> {
+		for b in authorization.as_bytes() {
+			if *b == b'\r' || *b == b'\n' {
+				return Err(err!(IllegalArgument));
+			}
+		}
+		let client = match config.connect() {
+			Ok(client) => client,
+			Err(e) => return Err(e),
+		};
+		self.init_client(client, None, Some(authorization))
+	}
+
+	/// Connects like add_client and presents token, obtained from WsResponse::resume_token
+	/// of an earlier connection, so that the server rebinds the new socket to that session.
+	/// Unknown or expired tokens get a new session.
+	pub fn resume_client(
+		&mut self,
+		config: WsClientConfig,
+		token: &[u8; RESUME_TOKEN_LEN],
+	) -> Result<WsResponse, Error> {
+		let client = match config.connect() {
+			Ok(client) => client,
+			Err(e) => return Err(e),
+		};
+		self.init_client(client, Some(token), None)
+	}
+
+	// connect a client to this WebSocket over an in-process socket pair instead of TCP.
+	pub fn add_loopback(&mut self) -> Result<WsResponse, Error> {
+		let mut server = [0u8; 4];
 		let mut client = [0u8; 4];
+		let server_ptr = &mut server as *mut u8;
 		let client_ptr = &mut client as *mut u8;
-		if unsafe { socket_connect(client_ptr, config.addr.as_ptr(), config.port as i32) } < 0 {
-			return Err(err!(Connect));
+		let code = unsafe { socket_pair(server_ptr, client_ptr) };
+		if code < 0 {
+			return Err(oserr!(socket_error_kind(code)));
 		}
-		let threads = self.state.config.threads;
-		let itt = if threads > 0 {
-			(aadd!(&mut self.state.itt, 1) % threads) as usize
-		} else {
-			1
-		};
+
+		let itt = self.next_worker();
 		let conn = match Connection::new(
-			ConnectionType::ClientConnection,
-			client,
-			self.state.wstate[itt].send.clone().unwrap(),
-			self.state.config.debug_pending,
-			self.state.wstate[itt].wakeup,
+			ConnectionType::ServerConnection,
+			server,
+			self.state.transport.clone().unwrap(),
+			&self.state,
+			itt,
 		) {
 			Ok(conn) => conn,
 			Err(e) => {
 				unsafe {
+					socket_close(server_ptr);
 					socket_close(client_ptr);
 				}
 				return Err(e);
 			}
 		};
-
-		let mut boxed_conn = match Box::new(conn.clone().unwrap()) {
+		let mut boxed_conn = match Box::new(conn) {
 			Ok(conn) => conn,
 			Err(e) => {
 				unsafe {
+					socket_close(server_ptr);
 					socket_close(client_ptr);
 				}
 				return Err(e);
 			}
 		};
+		let conn_ptr = boxed_conn.as_ptr();
 		boxed_conn.leak();
-		// note: we simplify here and return an error if the full message is not sent.
-		// these are short and should generally succeed. Re-try logic can be used by
-		// caller.
-		if unsafe {
-			socket_send(
-				client_ptr,
-				CONNECT_MESSAGE_PREFIX.as_ptr(),
-				CONNECT_MESSAGE_PREFIX.len(),
-			)
-		} < CONNECT_MESSAGE_PREFIX.len() as i64
+		match self.state.wstate[itt]
+			.send
+			.send(ConnectionMessage::Read(boxed_conn))
 		{
-			unsafe {
-				socket_close(client_ptr);
-			}
-			return Err(err!(IO));
-		}
+			Ok(_) => {}
+			Err(e) => {
+				// the message was dropped without reaching the worker, and dropping the
+				// leaked box did not free it
+				let _conn = Box::from_raw(conn_ptr);
+				unsafe {
+					socket_close(server_ptr);
+					socket_close(client_ptr);
+				}
+				return Err(e);
+			}
+		}
+		// from here the worker owns the server end. It registers the connection once woken,
+		// or closes it with the other queued messages when it stops.
+		match self.state.wstate[itt].reactor.wake() {
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
+			}
+		}
+		match self.state.wstate[itt]
+			.comp_recv
+			.recv_timeout(WORKER_REPLY_TIMEOUT_MICROS)
+		{
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
+			}
+		}
+
+		self.init_client(client, None, None)
+	}
+
+	fn next_worker(&mut self) -> usize {
+		let threads = self.state.config.threads;
+		if threads > 0 {
+			(self.state.itt.fetch_add(1) % threads) as usize
+		} else {
+			1
+		}
+	}
+
+	fn init_client(
+		&mut self,
+		client: [u8; 4],
+		token: Option<&[u8; RESUME_TOKEN_LEN]>,
+		authorization: Option<&str>,
+	) -> Result<WsResponse, Error> {
+		let mut client = client;
+		let client_ptr = &mut client as *mut u8;
+		let itt = self.next_worker();
+		let conn = match Connection::new(
+			ConnectionType::ClientConnection,
+			client,
+			self.state.transport.clone().unwrap(),
+			&self.state,
+			itt,
+		) {
+			Ok(conn) => conn,
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
+			}
+		};
+
+		let mut boxed_conn = match Box::new(conn.clone().unwrap()) {
+			Ok(conn) => conn,
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
+			}
+		};
+		boxed_conn.leak();
 		let mut accept_key: [u8; 24] = [0; 24];
 		let mut rand_bytes_v: [u8; 16] = [0; 16];
-		// TODO: switch to secure psrng
-		unsafe {
-			rand_bytes(&mut rand_bytes_v as *mut u8, rand_bytes_v.len());
-		}
+		self.state.rand.lock().fill(&mut rand_bytes_v);
 		unsafe {
 			Base64encode(
 				accept_key.as_mut_ptr(),
@@ -461,19 +1970,61 @@ impl WebSocket {
 			);
 		}
 
-		if unsafe { socket_send(client_ptr, accept_key.as_ptr(), accept_key.len()) }
-			< accept_key.len() as i64
-		{
-			unsafe {
-				socket_close(client_ptr);
+		let mut request = StringBuilder::new();
+		// base64 output is ascii
+		let mut res = request.push_strs(&[CONNECT_MESSAGE_PREFIX, unsafe {
+			from_utf8_unchecked(&accept_key)
+		}]);
+		if res.is_ok() && self.state.config.frame_checksum {
+			res = request.push_strs(&["\r\n", CHECKSUM_HEADER]);
+		}
+		match token {
+			Some(token) => {
+				if res.is_ok() {
+					res = request.push_strs(&["\r\n", RESUME_TOKEN_PREFIX, unsafe {
+						from_utf8_unchecked(token)
+					}]);
+				}
+			}
+			None => {}
+		}
+		match authorization {
+			Some(authorization) => {
+				if res.is_ok() {
+					res = request.push_strs(&["\r\n", AUTHORIZATION_PREFIX, authorization]);
+				}
+			}
+			None => {}
+		}
+		if res.is_ok() {
+			res = request.push_str("\r\n\r\n");
+		}
+		match res {
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
 			}
-			return Err(err!(IO));
 		}
-		if unsafe { socket_send(client_ptr, "\r\n\r\n".as_ptr(), 4) } < 4 {
+
+		// note: we simplify here and return an error if the full message is not sent.
+		// these are short and should generally succeed. Re-try logic can be used by
+		// caller.
+		let request = request.as_str();
+		let sent = self.state.transport.send(client, request.as_bytes());
+		if sent < request.len() as i64 {
+			// a short write leaves errno as it was, so it only describes a failed one
+			let e = if sent < 0 {
+				oserr!(IO)
+			} else {
+				err!(IO, "short write of the upgrade request")
+			};
 			unsafe {
 				socket_close(client_ptr);
 			}
-			return Err(err!(IO));
+			return Err(e);
 		}
 
 		match self.state.wstate[itt]
@@ -484,47 +2035,46 @@ impl WebSocket {
 			Err(e) => return Err(e),
 		}
 
-		if unsafe {
-			socket_send(
-				(&self.state.wstate[itt].wakeup as *const u8).add(4),
-				&b'0',
-				1,
-			)
-		} < 1
-		{
-			unsafe {
-				socket_close(client_ptr);
+		match self.state.wstate[itt].reactor.wake() {
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					socket_close(client_ptr);
+				}
+				return Err(e);
 			}
-			return Err(err!(IO));
 		}
-		self.state.wstate[itt].comp_recv.recv();
-
-		Ok(WsResponse { conn })
+		// the worker owns the socket from here, even if it is too slow to confirm
+		match self.state.wstate[itt]
+			.comp_recv
+			.recv_timeout(WORKER_REPLY_TIMEOUT_MICROS)
+		{
+			Ok(_) => Ok(WsResponse { conn }),
+			Err(e) => Err(e),
+		}
 	}
 
 	pub fn add_server(&mut self, config: WsServerConfig) -> Result<u16, Error> {
-		let mut server = [0u8; 4];
-		let server_ptr = &mut server as *mut u8;
-		let port = unsafe {
-			socket_listen(
-				server_ptr,
-				config.addr.as_ptr(),
-				config.port,
-				config.backlog,
-			)
+		let (mut server, port) = match Self::listen(&config, config.port) {
+			Ok((server, port)) => (server, port),
+			Err(e) => return Err(e),
 		};
-		if port < 0 {
-			return Err(err!(Bind));
-		}
 
 		let mut i = 0;
 		for wstate in &self.state.wstate {
+			// the other workers' sockets share the port the first one was given
+			if i > 0 && self.state.config.reuse_port {
+				server = match Self::listen(&config, port) {
+					Ok((server, _)) => server,
+					Err(e) => return Err(e),
+				};
+			}
 			let connection = match Connection::new(
 				ConnectionType::Server,
 				server,
-				self.state.wstate[i].send.clone().unwrap(),
-				self.state.config.debug_pending,
-				self.state.wstate[i].wakeup,
+				self.state.transport.clone().unwrap(),
+				&self.state,
+				i,
 			) {
 				Ok(connection) => connection,
 				Err(e) => return Err(e),
@@ -540,221 +2090,820 @@ impl WebSocket {
 				Ok(_) => {}
 				Err(e) => return Err(e),
 			}
-			if unsafe { socket_send((&wstate.wakeup as *const u8).add(4), &b'0', 1) } < 1 {
+			if wstate.reactor.wake().is_err() {
 				return Err(err!(WsStop));
 			}
 
-			wstate.comp_recv.recv();
+			match wstate.comp_recv.recv_timeout(WORKER_REPLY_TIMEOUT_MICROS) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
 			i += 1;
 		}
 
-		Ok(port as u16)
+		Ok(port)
 	}
 
+	fn listen(config: &WsServerConfig, port: u16) -> Result<([u8; 4], u16), Error> {
+		let mut server = [0u8; 4];
+		let server_ptr = &mut server as *mut u8;
+		let port = unsafe { socket_listen(server_ptr, config.addr.as_ptr(), port, config.backlog) };
+		if port < 0 {
+			return Err(oserr!(socket_error_kind(port)));
+		}
+		Ok((server, port as u16))
+	}
+
+	/// Stops the worker threads. Calling stop again has no effect.
 	pub fn stop(&mut self) -> Result<(), Error> {
-		let lock = self.state.lock.clone().unwrap();
 		{
-			let _l = lock.write();
-			self.state.halt = true;
+			let mut control = self.state.control.write();
+			if control.halt {
+				return Ok(());
+			}
+			control.halt = true;
 		}
 		match self.wakeup_threads() {
 			Ok(_) => {}
 			Err(_e) => {}
 		}
-		match &mut self.state.runtime {
+		let res = match &mut *self.state.runtime.lock() {
 			Some(ref mut rt) => rt.stop(),
 			None => Ok(()),
+		};
+		// after the event loops, which queue messages on it
+		match &mut *self.state.dispatch.lock() {
+			Some(ref mut rt) => {
+				let _ = rt.stop();
+			}
+			None => {}
 		}
+		res
 	}
 
 	fn wakeup_threads(&self) -> Result<(), Error> {
 		for wstate in &self.state.wstate {
-			if unsafe { socket_send((&wstate.wakeup as *const u8).add(4), &b'0', 1) } < 1 {
-				println!("WARN: could not wakeup fd {}", unsafe {
-					socket_fd((&wstate.wakeup as *const u8).add(4) as *const u8)
-				});
+			match wstate.reactor.wake() {
+				Ok(_) => {}
+				Err(e) => println!("WARN: could not wake up worker: {}", e),
 			}
 		}
 		Ok(())
 	}
 
-	pub fn register_handler(
-		&mut self,
-		handler: Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>>,
-	) {
-		self.state.handler = Some(handler);
+	/// Number of binary frames received whose CRC32C trailer did not match
+	pub fn checksum_failures(&self) -> u64 {
+		self.state.checksum_failures.load()
 	}
 
-	pub fn start(&mut self) -> Result<(), Error> {
-		let runtime_config = RuntimeConfig {
-			max_threads: self.state.config.threads,
-			min_threads: self.state.config.threads,
-		};
+	/// Number of handler invocations which ran longer than WsConfig::slow_handler_micros
+	pub fn slow_handlers(&self) -> u64 {
+		self.state.slow_handlers.load()
+	}
 
-		let mut runtime: Runtime<()> = match Runtime::new(runtime_config) {
-			Ok(runtime) => runtime,
+	/// Number of handshakes rejected by the registered Authenticator
+	pub fn auth_failures(&self) -> u64 {
+		self.state.auth_failures.load()
+	}
+
+	/// Number of handshakes turned away by the registered accept handler
+	pub fn handshakes_rejected(&self) -> u64 {
+		self.state.handshakes_rejected.load()
+	}
+
+	/// Puts the server into maintenance mode ahead of a rolling deploy. From now on
+	/// handshakes are answered with config.status and a Retry-After header, and connected
+	/// clients are sent a going away close (WsClosePolicy::going_away) in randomly assigned
+	/// batches spread over
+	/// config.window_micros so that they do not all reconnect elsewhere at once. on_complete
+	/// is called on a worker thread once no server connections remain. Draining cannot be
+	/// undone, stop the server when on_complete has been called.
+	pub fn drain(
+		&mut self,
+		config: WsDrainConfig,
+		on_complete: Box<dyn FnMut() + Send + Sync>,
+	) -> Result<(), Error> {
+		if config.batches == 0 || config.window_micros < 0 {
+			return Err(err!(IllegalArgument));
+		}
+		if self.state.runtime.lock().is_none() {
+			return Err(err!(NotInitialized));
+		}
+		{
+			let mut control = self.state.control.write();
+			if control.halt || control.drain.is_some() {
+				return Err(err!(IllegalState));
+			}
+			control.drain_start = getmicros!();
+			control.drain_complete = Some(on_complete);
+			control.drain = Some(config);
+		}
+		self.wakeup_threads()
+	}
+
+	/// Stops the server without cutting off what it is sending. The workers stop accepting,
+	/// send each connection a going away close (WsClosePolicy::going_away) once everything
+	/// queued for it has been written and wait for the peers to hang up. The event loops are
+	/// torn down when no connections remain or deadline_micros from now, whichever comes
+	/// first. Timeout if connections were left at the deadline, the server is stopped either
+	/// way.
+	pub fn shutdown_graceful(&mut self, deadline_micros: i64) -> Result<(), Error> {
+		if deadline_micros < 0 {
+			return Err(err!(IllegalArgument));
+		}
+		if self.state.runtime.lock().is_none() {
+			return Err(err!(NotInitialized));
+		}
+		let (send, recv) = match channel() {
+			Ok((send, recv)) => (send, recv),
 			Err(e) => return Err(e),
 		};
-		match runtime.start() {
+		let deadline = getmicros!() + deadline_micros;
+		{
+			let mut control = self.state.control.write();
+			if control.halt {
+				return Ok(());
+			}
+			if control.closing_deadline != 0 {
+				return Err(err!(IllegalState));
+			}
+			control.closing_deadline = deadline;
+			control.workers_closed = Some(send);
+		}
+		match self.wakeup_threads() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let threads = self.state.config.threads;
+		let mut closed = 0;
+		while closed < threads {
+			let now = getmicros!();
+			if now >= deadline {
+				break;
+			}
+			match recv.recv_timeout((deadline - now) as u64) {
+				Ok(_) => closed += 1,
+				Err(_) => break,
+			}
+		}
+		match self.stop() {
 			Ok(_) => {}
 			Err(e) => return Err(e),
 		}
+		if closed == threads {
+			Ok(())
+		} else {
+			Err(err!(Timeout))
+		}
+	}
 
-		for tid in 0..self.state.config.threads as usize {
-			let mut state = self.state.clone().unwrap();
-			let mut mplex = [0u8; 4];
+	/// True once drain has been called
+	pub fn is_draining(&self) -> bool {
+		self.state.control.read().drain.is_some()
+	}
 
-			if unsafe { socket_multiplex_init(&mut mplex as *mut u8) } < 0 {
-				return Err(err!(CreateFileDescriptor));
-			}
+	/// Returns a human readable snapshot of the server state. Each worker reports on its own
+	/// connection list; workers which do not respond within a second are reported as
+	/// unresponsive rather than blocking the caller.
+	pub fn debug_dump(&mut self) -> Result<String, Error> {
+		let mut f = Formatter::new();
+		let started = self.state.runtime.lock().is_some();
+		let (halt, draining, sessions) = {
+			let control = self.state.control.read();
+			(
+				control.halt,
+				control.drain.is_some(),
+				control.sessions.len(),
+			)
+		};
+		match writeb!(
+			f,
+			"WebSocket: name={},threads={},started={},halt={},handler={},buffered={},checksum_failures={},slow_handlers={},auth_failures={},handshakes_rejected={},sessions={},draining={}\n",
+			self.state.config.name,
+			self.state.config.threads,
+			started,
+			halt,
+			self.state.handler.read().is_some(),
+			self.state.buffer_bytes.load(),
+			self.state.checksum_failures.load(),
+			self.state.slow_handlers.load(),
+			self.state.auth_failures.load(),
+			self.state.handshakes_rejected.load(),
+			sessions,
+			draining
+		) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
 
-			let mut wakeup = [0u8; 8];
-			if unsafe { open_pipe(&mut wakeup as *mut u8) } < 0 {
-				return Err(err!(Pipe));
+		// queue depths are sampled before our own Dump request is enqueued
+		let mut replies = Vec::new();
+		let mut depths = Vec::new();
+		for i in 0..self.state.wstate.len() {
+			let depth = (
+				self.state.wstate[i].recv.len(),
+				self.state.wstate[i].comp_recv.len(),
+			);
+			match depths.push(depth) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
 			}
-
-			let wstate = match WorkerState::new(wakeup, mplex) {
-				Ok(wstate) => wstate,
+			let (send, recv) = match channel() {
+				Ok((send, recv)) => (send, recv),
 				Err(e) => return Err(e),
 			};
-
-			match state.wstate.push(wstate) {
+			// only running workers can answer
+			if started && !halt {
+				let wstate = &self.state.wstate[i];
+				if wstate.send.send(ConnectionMessage::Dump(send)).is_ok() {
+					let _ = wstate.reactor.wake();
+				}
+			}
+			match replies.push(recv) {
 				Ok(_) => {}
 				Err(e) => return Err(e),
 			}
+		}
 
-			if unsafe {
-				socket_multiplex_register(
-					&mplex as *const u8,
-					&wakeup as *const u8,
-					REG_READ_FLAG,
-					null_mut(),
-				)
-			} < 0
-			{
-				return Err(err!(MultiplexRegister));
+		let start = unsafe { getmicros() };
+		for i in 0..self.state.wstate.len() {
+			match writeb!(
+				f,
+				"worker[{}]: queue={},completions={}",
+				i,
+				depths[i].0,
+				depths[i].1
+			) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
 			}
-			let events = unsafe {
-				alloc(socket_event_size() * self.state.config.max_events as usize) as *mut u8
+			let elapsed = (unsafe { getmicros() } - start) as u64;
+			let remaining = if elapsed < DEBUG_DUMP_TIMEOUT_MILLIS * 1_000 {
+				DEBUG_DUMP_TIMEOUT_MILLIS * 1_000 - elapsed
+			} else {
+				0
 			};
-
-			let mut ctx = WsContext {
-				state,
-				tid,
-				events,
-				last_check: 0,
+			let stats = if !started || halt {
+				None
+			} else {
+				match replies[i].recv_timeout(remaining) {
+					Ok(stats) => Some(stats),
+					Err(_e) => None,
+				}
 			};
-
-			let _ = runtime.execute(move || match Self::event_loop(&mut ctx) {
+			let res = if !started || halt {
+				writeb!(f, ",stopped\n")
+			} else if let Some(stats) = stats {
+				writeb!(
+					f,
+					",connections={} (servers={},server_connections={},clients={},handshaking={},pending_writes={},accept_paused={})\n",
+					stats.connections,
+					stats.servers,
+					stats.server_connections,
+					stats.clients,
+					stats.handshaking,
+					stats.pending_writes,
+					stats.accept_paused
+				)
+			} else {
+				writeb!(f, ",unresponsive\n")
+			};
+			match res {
 				Ok(_) => {}
-				Err(e) => println!("FATAL: unexpected error in event_loop: {}", e),
-			});
+				Err(e) => return Err(e),
+			}
 		}
-
-		self.state.runtime = Some(runtime);
-
-		Ok(())
+		String::new(f.as_str())
 	}
 
-	fn remove_from_list(ctx: &mut WsContext, conn: &mut Box<Connection>) {
-		if !conn.inner.prev.is_null() {
-			conn.inner.prev.inner.next = conn.inner.next;
-		} else {
-			// update head
-			ctx.state.wstate[ctx.tid].head = conn.inner.next.raw();
+	fn worker_stats(ctx: &WsContext) -> WorkerStats {
+		let mut stats = WorkerStats::default();
+		stats.accept_paused = ctx.accept_paused;
+		for conn in ctx.conns.iter() {
+			let inner = &conn.inner;
+			stats.connections += 1;
+			match inner.ctype {
+				ConnectionType::Server => stats.servers += 1,
+				ConnectionType::ServerConnection => stats.server_connections += 1,
+				ConnectionType::ClientConnection => stats.clients += 1,
+			}
+			let io = inner.io.lock();
+			if io.cstate == ConnectionState::NeedHandshake {
+				stats.handshaking += 1;
+			}
+			if io.wbuf.len() > 0 {
+				stats.pending_writes += 1;
+			}
 		}
-		if !conn.inner.next.is_null() {
-			conn.inner.next.inner.prev = conn.inner.prev;
+		stats
+	}
+
+	#[cfg(test)]
+	fn test_events(&mut self) -> Result<Receiver<WsTestEvent>, Error> {
+		let (send, recv) = match channel() {
+			Ok((send, recv)) => (send, recv),
+			Err(e) => return Err(e),
+		};
+		match self.state.get_mut() {
+			Some(state) => state.test_events = Some(send),
+			None => return Err(err!(IllegalState)),
 		}
+		Ok(recv)
 	}
 
-	fn update_head(ctx: &mut WsContext, conn: &mut Box<Connection>) {
-		let mut state_clone1 = ctx.state.clone().unwrap();
-		let mut state_clone2 = ctx.state.clone().unwrap();
-		conn.inner.next = Ptr::new(ctx.state.wstate[ctx.tid].head);
-		conn.inner.prev = Ptr::null();
-		if !ctx.state.wstate[ctx.tid].head.is_null() {
-			unsafe {
-				(*state_clone1.wstate[ctx.tid].head).inner.prev = Ptr::new(conn.as_ptr().raw());
+	/// Sets the handler for received messages, replacing any previous one. May be called
+	/// while the server is running: each worker switches to the new handler on the next turn
+	/// of its event loop, and the previous one is dropped once the last worker has let go of
+	/// it.
+	pub fn register_handler(&mut self, handler: Handler) -> Result<(), Error> {
+		let handler = match Arc::new(handler) {
+			Ok(handler) => handler,
+			Err(e) => return Err(e),
+		};
+		let previous = replace(&mut *self.state.handler.write(), Some(handler));
+		self.state.handler_generation.fetch_add(1);
+		// dropped outside the lock
+		drop(previous);
+		Ok(())
+	}
+
+	/// Lets handler turn clients away once their upgrade request has been read, for example
+	/// by Origin, path or address, by returning false. Rejected clients get a
+	/// WsConfig::reject_status response and are disconnected before any Authenticator sees
+	/// them. IllegalState once the server is started, as the worker threads call the handler
+	/// without a lock.
+	pub fn register_accept_handler(
+		&mut self,
+		handler: Box<dyn Fn(&WsHandshakeInfo) -> bool + Send + Sync>,
+	) -> Result<(), Error> {
+		match self.state.get_mut() {
+			Some(state) => {
+				state.accept_handler = Some(handler);
+				Ok(())
 			}
+			None => Err(err!(IllegalState)),
 		}
-		state_clone2.wstate[ctx.tid].head = conn.as_ptr().raw();
 	}
 
-	fn check_stale(ctx: &mut WsContext) {
-		let mut cur = ctx.state.wstate[ctx.tid].head;
-		let now = unsafe { getmicros() };
-		if now.saturating_sub(ctx.last_check) < 5000_000 {
-			return;
+	/// Routes the close frames the peer sends to handler, with their status and reason,
+	/// instead of to the handler set by register_handler. A malformed close fails the
+	/// connection with WsClosePolicy::protocol_error, or invalid_payload if the reason is not
+	/// UTF-8, without reaching either. IllegalState once the server is started.
+	pub fn register_close_handler(&mut self, handler: CloseHandler) -> Result<(), Error> {
+		match self.state.get_mut() {
+			Some(state) => {
+				state.close_handler = Some(handler);
+				Ok(())
+			}
+			None => Err(err!(IllegalState)),
 		}
-		ctx.last_check = now;
-		while !cur.is_null() {
-			let v = cur;
-			cur = unsafe { (*cur).inner.next.raw() };
-
-			let mut b = Box::from_raw(Ptr::new(v));
-			b.leak();
+	}
 
-			let diff = now.saturating_sub(b.inner.last);
-			if diff > ctx.state.config.timeout_micros && b.inner.ctype != ConnectionType::Server {
-				Self::close_cleanly(&mut b, 1016);
+	/// Lets handler choose what happens to connections idle for longer than
+	/// WsConfig::timeout_micros, instead of closing them with WsClosePolicy::idle_timeout.
+	/// Connections still handshaking are closed as before. IllegalState once the server is
+	/// started.
+	pub fn register_idle_handler(&mut self, handler: IdleHandler) -> Result<(), Error> {
+		match self.state.get_mut() {
+			Some(state) => {
+				state.idle_handler = Some(handler);
+				Ok(())
 			}
+			None => Err(err!(IllegalState)),
 		}
 	}
 
-	fn proc_wakeup(ctx: &mut WsContext) {
-		let mplex = &ctx.state.wstate[ctx.tid].mplex as *const u8;
-		while ctx.state.wstate[ctx.tid].recv.pending() {
-			match ctx.state.wstate[ctx.tid].recv.recv() {
-				ConnectionMessage::Read(mut conn) => {
-					let _ = ctx.state.wstate[ctx.tid].comp_send.send(());
-					conn.inner.connptr = conn.as_ptr();
-					if unsafe {
-						socket_multiplex_register(
-							mplex as *const u8,
-							&conn.inner.handle as *const u8,
-							REG_READ_FLAG,
-							conn.as_ptr().raw() as *const u8,
-						)
-					} < 0
-					{
-						unsafe {
-							socket_close(&conn.inner.handle as *const u8);
-						}
-					} else {
-						Self::update_head(ctx, &mut conn);
-					}
-				}
-				ConnectionMessage::Write(conn) => {
-					if unsafe {
-						socket_multiplex_register(
-							mplex as *const u8,
-							&conn.inner.handle as *const u8,
-							REG_READ_FLAG | REG_WRITE_FLAG,
-							conn.raw() as *const u8,
-						)
-					} < 0
-					{
-						unsafe { socket_close(&conn.inner.handle as *const u8) };
-					}
-				}
+	/// Requires every client to pass authenticator during the handshake. Rejected clients
+	/// get a 401 response and are disconnected before the handler sees them. IllegalState
+	/// once the server is started.
+	pub fn register_authenticator(
+		&mut self,
+		authenticator: Box<dyn Authenticator>,
+	) -> Result<(), Error> {
+		match self.state.get_mut() {
+			Some(state) => {
+				state.authenticator = Some(authenticator);
+				Ok(())
 			}
+			None => Err(err!(IllegalState)),
 		}
 	}
 
-	fn handle_websocket_handshake(sec_key: &[u8]) -> [u8; 28] {
-		let mut sha1_result: [u8; 20] = [0; 20];
-		let mut combined: [u8; 60] = [0; 60];
-
-		unsafe {
-			copy_nonoverlapping(sec_key.as_ptr(), combined.as_mut_ptr(), sec_key.len());
-
-			copy_nonoverlapping(
+	pub fn start(&mut self) -> Result<(), Error> {
+		// every worker's state is in place before the first event loop can look at it
+		let state = match self.state.get_mut() {
+			Some(state) => state,
+			None => return Err(err!(IllegalState)),
+		};
+		for _ in 0..state.config.threads {
+			let reactor = match Reactor::new() {
+				Ok(reactor) => reactor,
+				Err(e) => return Err(e),
+			};
+			let wstate = match WorkerState::new(reactor) {
+				Ok(wstate) => wstate,
+				Err(e) => return Err(e),
+			};
+			match state.wstate.push(wstate) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		// in place before the event loops, which queue messages on it
+		let dispatch_threads = state.config.dispatch_threads;
+		if dispatch_threads > 0 {
+			let mut dispatch: Runtime<()> = match Runtime::new(RuntimeConfig {
+				name: state.config.name,
+				max_threads: dispatch_threads,
+				min_threads: dispatch_threads,
+				..RuntimeConfig::default()
+			}) {
+				Ok(dispatch) => dispatch,
+				Err(e) => return Err(e),
+			};
+			match dispatch.start() {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			*state.dispatch.get_mut() = Some(dispatch);
+		}
+
+		let runtime_config = RuntimeConfig {
+			name: self.state.config.name,
+			max_threads: self.state.config.threads,
+			min_threads: self.state.config.threads,
+			..RuntimeConfig::default()
+		};
+
+		let mut runtime: Runtime<()> = match Runtime::new(runtime_config) {
+			Ok(runtime) => runtime,
+			Err(e) => return Err(e),
+		};
+		match runtime.start() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+
+		for tid in 0..self.state.config.threads as usize {
+			// clone always succeeds on arc
+			let state = self.state.clone().unwrap();
+			let arena = match Arena::new(ARENA_CHUNK_SIZE) {
+				Ok(arena) => arena,
+				Err(e) => return Err(e),
+			};
+			let events = match Events::new(self.state.config.max_events as usize) {
+				Ok(events) => events,
+				Err(e) => return Err(e),
+			};
+
+			let mut ctx = WsContext {
+				state,
+				tid,
+				conns: IntrusiveList::new(),
+				events,
+				last_check: 0,
+				accept_paused: false,
+				drained: false,
+				closed: false,
+				handler: None,
+				handler_generation: 0,
+				ready: Vec::new(),
+				arena,
+			};
+
+			let _ = runtime.execute(move || match Self::event_loop(&mut ctx) {
+				Ok(_) => {}
+				Err(e) => println!(
+					"FATAL: [{}] unexpected error in event_loop: {}",
+					ctx.state.config.name, e
+				),
+			});
+		}
+
+		*self.state.runtime.lock() = Some(runtime);
+
+		Ok(())
+	}
+
+	fn check_stale(ctx: &mut WsContext) {
+		let now = ctx.state.clock.now();
+		let handshake_timeout = ctx.state.config.handshake_timeout_micros;
+		let stale_check = ctx.state.config.stale_check_micros;
+		let interval = if handshake_timeout > 0 && handshake_timeout < stale_check {
+			handshake_timeout
+		} else {
+			stale_check
+		};
+		if now.saturating_sub(ctx.last_check) < interval {
+			return;
+		}
+		ctx.last_check = now;
+		debug_assert!(ctx.conns.validate());
+		for v in ctx.conns.iter() {
+			let mut b = Box::from_raw(v);
+			b.leak();
+			let (cstate, close_pending, last) = {
+				let io = b.inner.io.lock();
+				(io.cstate, io.close_pending, io.last)
+			};
+
+			// bytes trickling in keep last fresh, so the handshake is timed from the start
+			if handshake_timeout > 0
+				&& cstate == ConnectionState::NeedHandshake
+				&& b.inner.ctype != ConnectionType::Server
+				&& now.saturating_sub(b.inner.opened) > handshake_timeout
+			{
+				if b.inner.ctype == ConnectionType::ServerConnection {
+					Self::request_timeout(&mut b);
+				} else {
+					Self::close_cleanly(&mut b, CloseReason::IdleTimeout);
+				}
+				continue;
+			}
+
+			// a peer which never reads the bytes ahead of a queued close is not waited on
+			if close_pending != 0 {
+				if now.saturating_sub(close_pending) > ctx.state.config.timeout_micros {
+					b.shutdown();
+				}
+				continue;
+			}
+
+			let diff = now.saturating_sub(last);
+			if diff > ctx.state.config.timeout_micros && b.inner.ctype != ConnectionType::Server {
+				Self::proc_idle(&ctx.state, &mut b, now, diff);
+			}
+		}
+
+		// the sessions are shared, so one worker is enough to expire them
+		if ctx.tid == 0 {
+			Self::expire_sessions(&ctx.state, now);
+		}
+	}
+
+	fn live_session(session: &Session, now: i64) -> bool {
+		let session = session.state.read();
+		session.bound || session.expires >= now
+	}
+
+	// drops the detached sessions which were not resumed in time
+	fn expire_sessions(state: &State, now: i64) {
+		let mut control = state.control.write();
+		let mut expired = Vec::new();
+		for (key, session) in control.sessions.iter() {
+			if !Self::live_session(session, now) {
+				match expired.push(*key) {
+					Ok(_) => {}
+					// the rest are dropped on a later sweep
+					Err(_e) => break,
+				}
+			}
+		}
+		for key in &expired {
+			let _ = control.sessions.remove(key);
+		}
+	}
+
+	// does what the idle handler chooses for a connection idle for idle microseconds, or
+	// closes it if there is no handler
+	fn proc_idle(state: &State, b: &mut Box<Connection>, now: i64, idle: i64) {
+		let open = b.inner.io.lock().cstate == ConnectionState::HandshakeComplete;
+		let action = match &state.idle_handler {
+			Some(idle_handler) if open => idle_handler(WsResponse { conn: b.share() }, idle),
+			_ => WsIdleAction::Timeout,
+		};
+		match action {
+			WsIdleAction::Timeout => Self::close_cleanly(b, CloseReason::IdleTimeout),
+			WsIdleAction::Close(status) => b.close(status),
+			WsIdleAction::Ping => {
+				let _ = WsResponse { conn: b.share() }.send_frame(0x9, true, &[]);
+			}
+			WsIdleAction::Extend(micros) => {
+				// judged idle again once now plus micros has passed
+				let timeout = state.config.timeout_micros;
+				b.inner.io.lock().last = now.saturating_add(micros).saturating_sub(timeout);
+			}
+		}
+	}
+
+	// sends each of this worker's server connections a going away close once the time for its
+	// batch has come. When none are left the worker counts itself as drained and the last
+	// worker to do so runs the completion callback.
+	fn check_drain(ctx: &mut WsContext) {
+		if ctx.drained {
+			return;
+		}
+		let (start, window, batches) = {
+			let control = ctx.state.control.read();
+			match &control.drain {
+				Some(drain) => (control.drain_start, drain.window_micros, drain.batches),
+				None => return,
+			}
+		};
+		let now = getmicros!();
+		let mut remaining = 0;
+		for v in ctx.conns.iter() {
+			let mut b = Box::from_raw(v);
+			b.leak();
+
+			if b.inner.ctype != ConnectionType::ServerConnection
+				|| b.inner.io.lock().cstate != ConnectionState::HandshakeComplete
+			{
+				continue;
+			}
+			remaining += 1;
+			let mut recv = b.inner.recv.lock();
+			if recv.drain_at == 0 {
+				let mut r = [0u8; 8];
+				ctx.state.rand.lock().fill(&mut r);
+				let batch = from_be_bytes_u64(&r) % batches;
+				recv.drain_at = start + window / batches as i64 * batch as i64;
+			}
+			if recv.drain_at <= now {
+				// the connection stays in the list until the peer has gone
+				recv.drain_at = i64::MAX;
+				drop(recv);
+				Self::close_cleanly(&mut b, CloseReason::GoingAway);
+			}
+		}
+		if remaining > 0 {
+			return;
+		}
+		ctx.drained = true;
+		if ctx.state.drained_workers.fetch_add(1) + 1 == ctx.state.config.threads {
+			let on_complete = replace(&mut ctx.state.control.write().drain_complete, None);
+			match on_complete {
+				Some(mut on_complete) => on_complete(),
+				None => {}
+			}
+		}
+	}
+
+	// stop listening on this worker's servers while buffered bytes are over the ceiling
+	// and start again once they fall below the low-water mark
+	fn check_backpressure(ctx: &mut WsContext) {
+		let buffered = ctx.state.buffer_bytes.load();
+		// accepting stays paused for good once a graceful shutdown has begun
+		let closing = ctx.closed || ctx.state.control.read().closing_deadline != 0;
+		let pause = if closing {
+			true
+		} else if ctx.accept_paused {
+			buffered >= ctx.state.config.buffer_low_water
+		} else {
+			buffered > ctx.state.config.max_buffer_bytes
+		};
+		if pause == ctx.accept_paused {
+			return;
+		}
+		if pause && !closing {
+			println!(
+				"WARN: [{}] {} bytes buffered, pausing accept on worker {}",
+				ctx.state.config.name, buffered, ctx.tid
+			);
+		}
+
+		let reactor = &ctx.state.wstate[ctx.tid].reactor;
+		for conn in ctx.conns.iter() {
+			if conn.inner.ctype != ConnectionType::Server {
+				continue;
+			}
+			if pause {
+				let _ = conn.inner.transport.deregister(reactor, conn.inner.handle);
+			} else if conn.register(reactor, READ).is_err() {
+				println!(
+					"WARN: [{}] could not re-register server on worker {}",
+					ctx.state.config.name, ctx.tid
+				);
+			}
+		}
+		ctx.accept_paused = pause;
+	}
+
+	// during a graceful shutdown, sends each connection of this worker a going away close once
+	// its write buffer is empty. When none are left the worker counts itself as closed.
+	fn check_closing(ctx: &mut WsContext) {
+		if ctx.closed || ctx.state.control.read().closing_deadline == 0 {
+			return;
+		}
+		let mut remaining = 0;
+		for v in ctx.conns.iter() {
+			let mut b = Box::from_raw(v);
+			b.leak();
+
+			if b.inner.ctype == ConnectionType::Server {
+				continue;
+			}
+			remaining += 1;
+			// the connection stays in the list until the peer has gone
+			{
+				let mut recv = b.inner.recv.lock();
+				if recv.drain_at == i64::MAX || b.inner.io.lock().wbuf.len() > 0 {
+					continue;
+				}
+				recv.drain_at = i64::MAX;
+			}
+			Self::close_cleanly(&mut b, CloseReason::GoingAway);
+		}
+		if remaining == 0 {
+			ctx.closed = true;
+			match &ctx.state.control.read().workers_closed {
+				Some(send) => {
+					let _ = send.send(());
+				}
+				None => {}
+			}
+		}
+	}
+
+	fn proc_wakeup(ctx: &mut WsContext) {
+		while ctx.state.wstate[ctx.tid].recv.pending() {
+			match ctx.state.wstate[ctx.tid].recv.recv() {
+				ConnectionMessage::Read(conn) => {
+					conn.inner.io.lock().connptr = conn.as_ptr();
+					if conn.inner.ctype == ConnectionType::Server && ctx.accept_paused {
+						// registered by check_backpressure when accepting resumes
+						ctx.conns.push_front(Ptr::new(conn.as_ptr().raw()));
+					} else if conn
+						.register(&ctx.state.wstate[ctx.tid].reactor, READ)
+						.is_err()
+					{
+						if Self::owns_handle(ctx, &conn) {
+							conn.inner.transport.close(conn.inner.handle);
+						}
+						// the box was leaked for its trip through the channel
+						let _conn = Box::from_raw(Ptr::new(conn.as_ptr().raw()));
+					} else {
+						ctx.conns.push_front(Ptr::new(conn.as_ptr().raw()));
+					}
+					// only once the connection is in place, so that a listener is not closed by
+					// a stop which follows add_server while another worker is registering it
+					let _ = ctx.state.wstate[ctx.tid].comp_send.send(());
+				}
+				ConnectionMessage::Write(conn) => {
+					// closed connections have been freed by this worker, only the shared
+					// inner state from the message remains
+					let closed = {
+						let io = conn.inner.io.lock();
+						io.cstate == ConnectionState::Closed || io.connptr.is_null()
+					};
+					if closed {
+						continue;
+					}
+					if conn
+						.register(&ctx.state.wstate[ctx.tid].reactor, READ | WRITE)
+						.is_err()
+					{
+						conn.inner.transport.close(conn.inner.handle);
+					}
+				}
+				ConnectionMessage::Resume(conn) => {
+					let (pending, connptr) = {
+						let io = conn.inner.io.lock();
+						if io.cstate == ConnectionState::Closed || io.connptr.is_null() {
+							continue;
+						}
+						(io.wbuf.len() > 0, io.connptr)
+					};
+					let interest = if pending { READ | WRITE } else { READ };
+					if conn
+						.register(&ctx.state.wstate[ctx.tid].reactor, interest)
+						.is_err()
+					{
+						conn.inner.transport.close(conn.inner.handle);
+						continue;
+					}
+					// the frames left unread when the inbox filled up
+					let mut b = Box::from_raw(Ptr::new(connptr.raw()));
+					b.leak();
+					let ready = b.inner.recv.lock().ready;
+					if !ready {
+						let mut budget = Self::message_budget(ctx);
+						if Self::proc_messages(ctx, &mut b, &mut budget) {
+							Self::make_ready(ctx, &mut b);
+						}
+					}
+				}
+				ConnectionMessage::Dump(send) => {
+					// the buffered bytes may have changed since the top of this iteration
+					Self::check_backpressure(ctx);
+					let _ = send.send(Self::worker_stats(ctx));
+				}
+			}
+		}
+	}
+
+	pub(crate) fn handle_websocket_handshake(sec_key: &[u8]) -> [u8; 28] {
+		let mut combined: [u8; 60] = [0; 60];
+
+		unsafe {
+			copy_nonoverlapping(sec_key.as_ptr(), combined.as_mut_ptr(), sec_key.len());
+
+			copy_nonoverlapping(
 				MAGIC_STRING.as_ptr(),
 				combined.as_mut_ptr().add(sec_key.len()),
 				MAGIC_STRING.len(),
 			);
-			SHA1(combined.as_ptr(), combined.len(), sha1_result.as_mut_ptr());
+			let mut sha1_result = sha1(&combined);
 
 			let mut accept_key: [u8; 28] = [0; 28];
 			Base64encode(
@@ -767,110 +2916,457 @@ impl WebSocket {
 		}
 	}
 
-	fn switch_protocol(handle: &mut Box<Connection>, accept_key: &[u8; 28]) {
-		match handle.write(SWITCH_PROTOCOL) {
-			Ok(_) => {}
-			Err(_e) => handle.close(1011),
+	fn switch_protocol(
+		handle: &mut Box<Connection>,
+		accept_key: &[u8; 28],
+		checksum: bool,
+		token: Option<[u8; RESUME_TOKEN_LEN]>,
+	) {
+		let mut response = StringBuilder::new();
+		// base64 output is ascii
+		let mut res =
+			response.push_strs(&[SWITCH_PROTOCOL, unsafe { from_utf8_unchecked(accept_key) }]);
+		if res.is_ok() && checksum {
+			res = response.push_strs(&["\r\n", CHECKSUM_HEADER]);
 		}
-		match handle.writeb(accept_key) {
-			Ok(_) => {}
-			Err(_e) => handle.close(1011),
+		match token {
+			Some(token) => {
+				if res.is_ok() {
+					res = response.push_strs(&["\r\n", RESUME_TOKEN_PREFIX, unsafe {
+						from_utf8_unchecked(&token)
+					}]);
+				}
+			}
+			None => {}
+		}
+		if res.is_ok() {
+			res = response.push_str("\r\n\r\n");
 		}
 
-		match handle.write("\r\n\r\n") {
-			Ok(_) => {}
-			Err(_e) => handle.close(1011),
+		match res {
+			Ok(_) => match handle.write(response.as_str()) {
+				Ok(_) => {}
+				Err(_e) => handle.close_for(CloseReason::InternalError),
+			},
+			Err(_e) => handle.close_for(CloseReason::InternalError),
 		}
 	}
 
 	fn bad_request(handle: &mut Box<Connection>) {
 		let _ = handle.write(BAD_REQUEST);
-		unsafe {
-			socket_shutdown(&mut handle.inner.handle as *const u8);
+		handle.shutdown();
+	}
+
+	fn request_timeout(handle: &mut Box<Connection>) {
+		let _ = handle.write(REQUEST_TIMEOUT);
+		handle.shutdown();
+	}
+
+	fn rejected(handle: &mut Box<Connection>, status: u16) {
+		let mut f = Formatter::new();
+		if writeb!(
+			f,
+			"HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n",
+			status,
+			reason_phrase(status)
+		)
+		.is_ok()
+		{
+			let _ = handle.write(f.as_str());
 		}
+		handle.shutdown();
 	}
 
-	fn proc_hs_client(handle: &mut Box<Connection>) {
-		let mut handle_clone = handle.clone().unwrap();
-		let rvec = &handle.inner.rbuf;
-		for i in 3..rvec.len() {
-			if rvec[i] == b'\n'
-				&& rvec[i - 1] == b'\r'
-				&& rvec[i - 2] == b'\n'
-				&& rvec[i - 3] == b'\r'
-			{
-				// end of response just check if this is a 101
-				if i >= SWITCHING_PROTOCOL_PREFIX.len()
-					&& &rvec[0..SWITCHING_PROTOCOL_PREFIX.len()]
-						== SWITCHING_PROTOCOL_PREFIX.as_bytes()
-				{
-					handle_clone.inner.cstate = ConnectionState::HandshakeComplete;
-					if rvec.len() == i + 1 {
-						handle_clone.inner.rbuf.clear();
-					} else {
-						let _ = handle_clone.inner.rbuf.shift(i + 1);
-					}
-					break;
-				}
-			}
+	fn unauthorized(handle: &mut Box<Connection>) {
+		let _ = handle.write(UNAUTHORIZED);
+		handle.shutdown();
+	}
+
+	fn unavailable(handle: &mut Box<Connection>, status: u16, retry_after_secs: u64) {
+		let mut f = Formatter::new();
+		if writeb!(
+			f,
+			"HTTP/1.1 {} {}\r\nRetry-After: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n",
+			status,
+			reason_phrase(status),
+			retry_after_secs
+		)
+		.is_ok()
+		{
+			let _ = handle.write(f.as_str());
 		}
+		handle.shutdown();
 	}
 
-	fn proc_hs(handle: &mut Box<Connection>) {
-		let mut handle_clone = handle.clone().unwrap();
-		let len = handle.inner.rbuf.len();
-		let rvec = &handle.inner.rbuf;
-		let mut uri_end = 0;
-		if len >= 5 && &rvec[0..5] == GET_PREFIX {
-			for i in 5..len {
-				if rvec[i] == b' ' || rvec[i] == b'?' || rvec[i] == b'\r' || rvec[i] == b'\n' {
-					uri_end = i;
-					break;
+	// status and Retry-After of the response to a handshake while draining, None otherwise
+	fn drain_status(ctx: &WsContext) -> Option<(u16, u64)> {
+		match &ctx.state.control.read().drain {
+			Some(drain) => Some((drain.status, drain.retry_after_secs)),
+			None => None,
+		}
+	}
+
+	// runs the registered accept handler, if any. Returns false if the client was rejected.
+	fn admit(handle: &Box<Connection>, ctx: &mut WsContext, uri: &[u8], headers: &[u8]) -> bool {
+		let accept_handler = match &ctx.state.accept_handler {
+			Some(accept_handler) => accept_handler,
+			None => return true,
+		};
+		// a client which has already gone is noticed by the next read
+		let (peer_addr, peer_port) = match peer_addr(&handle.inner.handle) {
+			Ok((addr, port)) => (addr, port),
+			Err(_e) => ([0u8; 4], 0),
+		};
+		let info = WsHandshakeInfo {
+			handshake: Handshake { uri, headers },
+			peer_addr,
+			peer_port,
+		};
+		if accept_handler(&info) {
+			true
+		} else {
+			ctx.state.handshakes_rejected.fetch_add(1);
+			false
+		}
+	}
+
+	// runs the registered Authenticator, if any. Returns false if the client was rejected.
+	fn authenticate(conn: &Connection, ctx: &mut WsContext, uri: &[u8], headers: &[u8]) -> bool {
+		let authenticator = match &ctx.state.authenticator {
+			Some(authenticator) => authenticator,
+			None => return true,
+		};
+		match authenticator.authenticate(&Handshake { uri, headers }) {
+			Ok(identity) => match Arc::new(identity) {
+				Ok(identity) => {
+					conn.inner.negotiated.write().identity = Some(identity);
+					true
 				}
+				// turned away like a client which failed, but not counted as one
+				Err(_e) => false,
+			},
+			Err(_e) => {
+				ctx.state.auth_failures.fetch_add(1);
+				false
 			}
-			if uri_end == 0 {
-				Self::bad_request(handle);
-				return;
+		}
+	}
+
+	// true if buf contains the complete header line at the start of a line
+	fn has_header(buf: &[u8], header: &str) -> bool {
+		let header = header.as_bytes();
+		for i in 0..buf.len() {
+			let end = i + 1 + header.len();
+			if buf[i] == b'\n' && buf.len() > end && &buf[i + 1..end] == header && buf[end] == b'\r'
+			{
+				return true;
 			}
+		}
+		false
+	}
 
-			let uri = &rvec[4..uri_end];
-			for i in 0..uri.len() {
-				if !((uri[i] >= b'a' && uri[i] <= b'z')
-					|| (uri[i] >= b'A' && uri[i] <= b'Z')
-					|| (uri[i] >= b'0' && uri[i] <= b'9')
-					|| uri[i] == b'-'
-					|| uri[i] == b'.'
-					|| uri[i] == b'_'
-					|| uri[i] == b'~'
-					|| uri[i] == b'/')
-				{
-					Self::bad_request(handle);
-					return;
+	// value of the header starting with prefix, up to the end of its line
+	pub(crate) fn header_value<'a>(buf: &'a [u8], prefix: &[u8]) -> Option<&'a [u8]> {
+		for i in 0..buf.len() {
+			let start = i + 1 + prefix.len();
+			if buf[i] == b'\n' && buf.len() > start && &buf[i + 1..start] == prefix {
+				for j in start..buf.len() {
+					if buf[j] == b'\r' || buf[j] == b'\n' {
+						return Some(&buf[start..j]);
+					}
 				}
 			}
+		}
+		None
+	}
 
-			let mut sec_key: &[u8] = &[];
+	fn resume_token(key: &[u8; 32], id: &[u8; SESSION_ID_SIZE]) -> [u8; RESUME_TOKEN_LEN] {
+		let mac = hmac_sha256(key, id);
+		let mut raw = [0u8; RESUME_TOKEN_RAW];
+		raw[0..SESSION_ID_SIZE].copy_from_slice(id);
+		raw[SESSION_ID_SIZE..].copy_from_slice(&mac);
+		let mut token = [0u8; RESUME_TOKEN_LEN];
+		unsafe {
+			Base64encode(token.as_mut_ptr(), raw.as_mut_ptr(), raw.len());
+		}
+		token
+	}
 
-			for i in uri_end..len {
-				if rvec[i] == b'\n'
-					&& rvec[i - 1] == b'\r'
-					&& rvec[i - 2] == b'\n'
-					&& rvec[i - 3] == b'\r'
-				{
-					if sec_key == &[] || sec_key.len() > 24 {
+	// returns the session id if token carries a valid MAC under key
+	fn verify_token(key: &[u8; 32], token: &[u8]) -> Option<[u8; SESSION_ID_SIZE]> {
+		if token.len() != RESUME_TOKEN_LEN {
+			return None;
+		}
+		// the decoder stops at the first byte outside the alphabet, so reject those here
+		let mut encoded = [0u8; RESUME_TOKEN_LEN + 1];
+		for i in 0..RESUME_TOKEN_LEN {
+			let c = token[i];
+			if !(c.is_ascii_alphanumeric() || c == b'+' || c == b'/') {
+				return None;
+			}
+			encoded[i] = c;
+		}
+		let mut raw = [0u8; RESUME_TOKEN_RAW];
+		unsafe {
+			Base64decode(raw.as_mut_ptr(), encoded.as_mut_ptr());
+		}
+		let mut id = [0u8; SESSION_ID_SIZE];
+		id.copy_from_slice(&raw[0..SESSION_ID_SIZE]);
+		let mut mac = [0u8; SHA256_SIZE];
+		mac.copy_from_slice(&raw[SESSION_ID_SIZE..]);
+		if hmac_sha256(key, &id).ct_eq(&mac) {
+			Some(id)
+		} else {
+			None
+		}
+	}
+
+	// binds conn to the session named by a valid token in the request, or to a new session.
+	// Returns the token to hand back to the client.
+	fn bind_session(
+		ctx: &mut WsContext,
+		conn: &Connection,
+		request: &[u8],
+	) -> Result<[u8; RESUME_TOKEN_LEN], Error> {
+		let now = ctx.state.clock.now();
+		let state = &ctx.state;
+		let mut control = state.control.write();
+
+		let id = match Self::header_value(request, RESUME_TOKEN_PREFIX.as_bytes()) {
+			Some(token) => Self::verify_token(&state.session_key, token),
+			None => None,
+		};
+		// a session which has expired but is not yet swept is not resumed
+		let found = match id {
+			Some(id) => match control.sessions.get(&u128::from_le_bytes(id)) {
+				Some(session) if Self::live_session(session, now) => Some(session.clone().unwrap()),
+				_ => None,
+			},
+			None => None,
+		};
+
+		let session = match found {
+			Some(session) => session,
+			None => {
+				let mut id = [0u8; SESSION_ID_SIZE];
+				state.rand.lock().fill(&mut id);
+				let session = match Arc::new(Session {
+					id,
+					state: RwLock::new(SessionState {
+						bound: true,
+						generation: 0,
+						expires: 0,
+						data: 0,
+						pending: Vec::new(),
+					}),
+				}) {
+					Ok(session) => session,
+					Err(e) => return Err(e),
+				};
+				let key = u128::from_le_bytes(id);
+				match control.sessions.insert(key, session.clone().unwrap()) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+				session
+			}
+		};
+
+		// a client may resume before the server has noticed the old socket close, the
+		// new generation keeps the old connection from detaching the session later
+		let generation = {
+			let mut session = session.state.write();
+			session.bound = true;
+			session.generation += 1;
+			session.generation
+		};
+		let token = Self::resume_token(&state.session_key, &session.id);
+		// only once the session's lock is released, which is always taken after negotiated
+		let mut negotiated = conn.inner.negotiated.write();
+		negotiated.session_generation = generation;
+		negotiated.session = Some(session);
+		Ok(token)
+	}
+
+	// deliver frames queued while the session was detached
+	fn flush_session(handle: &mut Box<Connection>) {
+		let pending = match &handle.inner.negotiated.read().session {
+			Some(session) => replace(&mut session.state.write().pending, Vec::new()),
+			None => return,
+		};
+		// written without the session's lock, which is only ever taken after io
+		let len = pending.len();
+		if len > 0 {
+			match handle.writeb(&pending[0..len]) {
+				Ok(_) => {}
+				Err(_e) => handle.close_for(CloseReason::InternalError),
+			}
+		}
+	}
+
+	// a closed server connection leaves its session for session_ttl_micros
+	fn detach_session(conn: &Connection, ttl_micros: i64) {
+		let negotiated = conn.inner.negotiated.read();
+		match &negotiated.session {
+			Some(session) => {
+				let mut session = session.state.write();
+				if session.generation == negotiated.session_generation {
+					session.bound = false;
+					session.expires = conn.inner.clock.now() + ttl_micros;
+				}
+			}
+			None => {}
+		}
+	}
+
+	fn proc_hs_client(handle: &mut Box<Connection>, max_handshake_bytes: usize) {
+		let mut rejected = false;
+		// clone always succeeds on arc
+		let inner = handle.inner.clone().unwrap();
+		let mut recv = inner.recv.lock();
+		let len = recv.rbuf.len();
+		let rvec: &[u8] = match recv.rbuf.contiguous(0, len) {
+			Ok(rvec) => rvec,
+			Err(_e) => {
+				Self::close_cleanly(handle, CloseReason::InternalError);
+				return;
+			}
+		};
+		let mut complete = false;
+		for i in 3..len {
+			if rvec[i] == b'\n'
+				&& rvec[i - 1] == b'\r'
+				&& rvec[i - 2] == b'\n'
+				&& rvec[i - 3] == b'\r'
+			{
+				// end of response just check if this is a 101
+				if i >= SWITCHING_PROTOCOL_PREFIX.len()
+					&& &rvec[0..SWITCHING_PROTOCOL_PREFIX.len()]
+						== SWITCHING_PROTOCOL_PREFIX.as_bytes()
+				{
+					// the server did not agree to the checksum extension we require
+					rejected = handle.inner.negotiated.read().checksum
+						&& !Self::has_header(&rvec[0..i + 1], CHECKSUM_HEADER);
+					match Self::header_value(&rvec[0..i + 1], RESUME_TOKEN_PREFIX.as_bytes()) {
+						Some(value) => {
+							if value.len() == RESUME_TOKEN_LEN {
+								let mut token = [0u8; RESUME_TOKEN_LEN];
+								token.copy_from_slice(value);
+								*handle.inner.resume_token.write() = Some(token);
+							}
+						}
+						None => {}
+					}
+					handle.inner.io.lock().cstate = ConnectionState::HandshakeComplete;
+					// rvec may point into the consumed bytes, it is not used again
+					recv.rbuf.consume(i + 1);
+					complete = true;
+					break;
+				}
+			}
+		}
+		if rejected {
+			Self::close_cleanly(handle, CloseReason::MissingExtension);
+		} else if !complete && len > max_handshake_bytes {
+			Self::close_cleanly(handle, CloseReason::ProtocolError);
+		}
+	}
+
+	fn proc_hs(handle: &mut Box<Connection>, ctx: &mut WsContext) {
+		// clone always succeeds on arc
+		let inner = handle.inner.clone().unwrap();
+		let mut recv = inner.recv.lock();
+		let len = recv.rbuf.len();
+		let rvec: &[u8] = match recv.rbuf.contiguous(0, len) {
+			Ok(rvec) => rvec,
+			Err(_e) => {
+				Self::bad_request(handle);
+				return;
+			}
+		};
+		let max_handshake_bytes = ctx.state.config.max_handshake_bytes;
+		let mut uri_end = 0;
+		if len < GET_PREFIX.len() && rvec == &GET_PREFIX[0..len] {
+			// the rest of the request line is still to come
+		} else if len >= 5 && &rvec[0..5] == GET_PREFIX {
+			for i in 5..len {
+				if rvec[i] == b' ' || rvec[i] == b'?' || rvec[i] == b'\r' || rvec[i] == b'\n' {
+					uri_end = i;
+					break;
+				}
+			}
+			if uri_end == 0 {
+				if len > max_handshake_bytes {
+					Self::bad_request(handle);
+				}
+				return;
+			}
+
+			let uri = &rvec[4..uri_end];
+			for i in 0..uri.len() {
+				if !((uri[i] >= b'a' && uri[i] <= b'z')
+					|| (uri[i] >= b'A' && uri[i] <= b'Z')
+					|| (uri[i] >= b'0' && uri[i] <= b'9')
+					|| uri[i] == b'-'
+					|| uri[i] == b'.'
+					|| uri[i] == b'_'
+					|| uri[i] == b'~'
+					|| uri[i] == b'/')
+				{
+					Self::bad_request(handle);
+					return;
+				}
+			}
+
+			let mut sec_key: &[u8] = &[];
+			let mut complete = false;
+
+			for i in uri_end..len {
+				if rvec[i] == b'\n'
+					&& rvec[i - 1] == b'\r'
+					&& rvec[i - 2] == b'\n'
+					&& rvec[i - 3] == b'\r'
+				{
+					if sec_key == &[] || sec_key.len() > 24 {
 						Self::bad_request(handle);
+					} else if let Some((status, retry_after_secs)) = Self::drain_status(ctx) {
+						Self::unavailable(handle, status, retry_after_secs);
+					} else if !Self::admit(handle, ctx, &rvec[4..uri_end], &rvec[uri_end..i + 1]) {
+						Self::rejected(handle, ctx.state.config.reject_status);
+					} else if !Self::authenticate(
+						handle,
+						ctx,
+						&rvec[4..uri_end],
+						&rvec[uri_end..i + 1],
+					) {
+						Self::unauthorized(handle);
 					} else {
 						let accept_key = Self::handle_websocket_handshake(sec_key);
-						Self::switch_protocol(handle, &accept_key);
-						handle.inner.cstate = ConnectionState::HandshakeComplete;
-
-						let rbuflen = handle_clone.inner.rbuf.len();
-						if rbuflen == i + 1 {
-							handle_clone.inner.rbuf.clear();
+						let checksum = ctx.state.config.frame_checksum
+							&& Self::has_header(&rvec[uri_end..i + 1], CHECKSUM_HEADER);
+						let token = if ctx.state.config.session_ttl_micros > 0 {
+							match Self::bind_session(ctx, handle, &rvec[uri_end..i + 1]) {
+								Ok(token) => Some(token),
+								Err(e) => {
+									println!(
+										"WARN: [{}] could not bind session: {}",
+										ctx.state.config.name, e
+									);
+									None
+								}
+							}
 						} else {
-							let _ = handle_clone.inner.rbuf.shift(i + 1);
-						}
+							None
+						};
+						Self::switch_protocol(handle, &accept_key, checksum, token);
+						Self::flush_session(handle);
+						handle.inner.negotiated.write().checksum = checksum;
+						handle.inner.io.lock().cstate = ConnectionState::HandshakeComplete;
+
+						// rvec may point into the consumed bytes, it is not used again
+						recv.rbuf.consume(i + 1);
 					}
+					complete = true;
 					break;
 				} else if rvec[i] == b'\n'
 					&& len > i + 1 + SEC_KEY_PREFIX.len()
@@ -884,689 +3380,3408 @@ impl WebSocket {
 					}
 				}
 			}
-		} else {
-			Self::bad_request(handle);
-			return;
+			if !complete && len > max_handshake_bytes {
+				Self::bad_request(handle);
+			}
+		} else {
+			Self::bad_request(handle);
+			return;
+		}
+	}
+
+	fn proc_hs_complete(handle: &mut Box<Connection>, ctx: &mut WsContext) {
+		let conn = handle.share();
+		// clone always succeeds on arc
+		let inner = handle.inner.clone().unwrap();
+		let mut guard = inner.recv.lock();
+		let recv = &mut *guard;
+
+		let len = recv.rbuf.len();
+
+		// min length to try to process
+		if len < 2 {
+			return;
+		}
+
+		let header_len = if len < MAX_FRAME_HEADER + 4 {
+			len
+		} else {
+			MAX_FRAME_HEADER + 4
+		};
+		let header = match recv.rbuf.contiguous(0, header_len) {
+			Ok(bytes) => decode_frame_header(bytes),
+			Err(e) => Err(serr!(e.kind)),
+		};
+		let header = match header {
+			Ok(Some(header)) => header,
+			Ok(None) => return,
+			Err(_) => {
+				// reserved bits not 0
+				Self::close_cleanly(handle, CloseReason::ProtocolError);
+				return;
+			}
+		};
+		let (payload_len, offset) = (header.payload_len, header.offset);
+		// judged on the header alone so that an oversized frame is refused before it arrives
+		match Self::frame_error(inner.ctype, &recv.message, &header, &ctx.state.config) {
+			Some(reason) => {
+				Self::close_cleanly(handle, reason);
+				return;
+			}
+			None => {}
+		}
+		if payload_len > len - offset {
+			return;
+		}
+
+		let checksum = header.op == 0x2 && inner.negotiated.read().checksum;
+		// the only copy of a frame which arrived in several chunks, made in the worker's arena
+		let payload = match recv.rbuf.contiguous_in(offset, payload_len, &ctx.arena) {
+			Ok(payload) => payload,
+			Err(_e) => {
+				println!(
+					"WARN: [{}] Could not allocate frame buffer! Closing connection.",
+					ctx.state.config.name
+				);
+				Self::close_cleanly(handle, CloseReason::InternalError);
+				recv.rbuf.clear();
+				return;
+			}
+		};
+		match header.masking_key {
+			Some(masking_key) => unmask(payload, &masking_key),
+			None => {}
+		}
+		let mut payload: &[u8] = payload;
+
+		let mut corrupt = false;
+		if checksum {
+			if payload_len < CHECKSUM_SIZE {
+				corrupt = true;
+			} else {
+				let data_len = payload_len - CHECKSUM_SIZE;
+				let expected = from_be_bytes_u32(&payload[data_len..]);
+				payload = &payload[0..data_len];
+				corrupt = crc32c(payload) != expected;
+			}
+		}
+
+		let opcode = WsOpcode::from_u8(header.op);
+		if !opcode.is_control() {
+			let strict = ctx.state.config.strict;
+			match Self::track_message(&mut recv.message, opcode, header.fin, payload, strict) {
+				Some(reason) => {
+					Self::close_cleanly(handle, reason);
+					return;
+				}
+				None => {}
+			}
+		}
+		let auto_control = ctx.state.config.auto_control_frames;
+		// closes are parsed whenever the server, not the handler, deals with them
+		let close =
+			if opcode == WsOpcode::Close && (auto_control || ctx.state.close_handler.is_some()) {
+				Some(parse_close(payload))
+			} else {
+				None
+			};
+		if corrupt {
+			ctx.state.checksum_failures.fetch_add(1);
+			conn.close_for(CloseReason::InvalidPayload);
+		} else if let Some(Err(reason)) = &close {
+			conn.close_for(*reason);
+		} else if close.is_some() && ctx.state.close_handler.is_some() {
+			if let (Some(Ok(close)), Some(close_handler)) = (close, &ctx.state.close_handler) {
+				let status = close.status();
+				close_handler(close, WsResponse { conn });
+				if auto_control {
+					handle.close_echo(status);
+				}
+			}
+		} else if auto_control && opcode == WsOpcode::Ping {
+			let mut resp = WsResponse { conn };
+			let _ = resp.send_frame(WsOpcode::Pong.to_u8(), true, payload);
+		} else if auto_control && opcode == WsOpcode::Pong {
+			// a pong needs no answer
+		} else if ctx.state.dispatch.lock().is_some() {
+			if !Self::dispatch(
+				handle,
+				&ctx.state,
+				&ctx.handler,
+				ctx.tid,
+				header.fin,
+				header.op,
+				payload,
+			) {
+				// left unread until the inbox has room
+				return;
+			}
+		} else {
+			let req = WsRequest {
+				fin: header.fin,
+				op: header.op,
+				msg: payload,
+				arena: &ctx.arena,
+			};
+			match &ctx.handler {
+				Some(handler) => Self::call_handler(
+					handler,
+					req,
+					conn,
+					ctx.state.config.name,
+					&ctx.state.slow_handlers,
+				),
+				None => {}
+			}
+			// the handler has seen the close, which is now returned
+			if let Some(Ok(close)) = &close {
+				handle.close_echo(close.status());
+			}
+		}
+		#[cfg(test)]
+		ctx.state
+			.emit(WsTestEvent::MessageProcessed(handle.inner.ctype));
+
+		recv.rbuf.consume(payload_len + offset);
+	}
+
+	// the reason to fail the connection over the frame with header, if there is one which
+	// does not depend on its payload
+	fn frame_error(
+		ctype: ConnectionType,
+		message: &MessageProgress,
+		header: &FrameHeader,
+		config: &WsConfig,
+	) -> Option<CloseReason> {
+		let opcode = WsOpcode::from_u8(header.op);
+		if config.strict {
+			// clients mask every frame and servers none
+			let masked = header.masking_key.is_some();
+			if masked != (ctype == ConnectionType::ServerConnection) {
+				return Some(CloseReason::ProtocolError);
+			}
+			let error = match opcode {
+				WsOpcode::Reserved(_) => true,
+				WsOpcode::Continuation => message.op == 0,
+				WsOpcode::Text | WsOpcode::Binary => message.op != 0,
+				_ => !header.fin || header.payload_len > 125,
+			};
+			if error {
+				return Some(CloseReason::ProtocolError);
+			}
+		}
+		let max = config.max_message_bytes;
+		if max > 0 && !opcode.is_control() {
+			let received = if opcode == WsOpcode::Continuation {
+				message.bytes
+			} else {
+				0
+			};
+			if received + header.payload_len as u64 > max {
+				return Some(CloseReason::MessageTooBig);
+			}
+		}
+		None
+	}
+
+	// follows the data frames of each message, validating its text when strict. Returns the
+	// reason to fail the connection if the payload is not UTF-8.
+	fn track_message(
+		message: &mut MessageProgress,
+		opcode: WsOpcode,
+		fin: bool,
+		payload: &[u8],
+		strict: bool,
+	) -> Option<CloseReason> {
+		let text = opcode == WsOpcode::Text
+			|| (opcode == WsOpcode::Continuation && message.op == WsOpcode::Text.to_u8());
+		if strict && text && (!message.utf8.update(payload) || (fin && !message.utf8.is_complete()))
+		{
+			return Some(CloseReason::InvalidPayload);
+		}
+		if fin {
+			*message = MessageProgress::default();
+		} else {
+			if opcode != WsOpcode::Continuation {
+				message.op = opcode.to_u8();
+				message.bytes = 0;
+			}
+			message.bytes += payload.len() as u64;
+		}
+		None
+	}
+
+	// calls handler with a frame, counting and logging the invocation if it runs longer than
+	// limit. An error closes the connection unless the close policy leaves it open.
+	fn call_handler(
+		handler: &Handler,
+		req: WsRequest,
+		conn: Connection,
+		name: &str,
+		slow_handlers: &AtomicU64,
+	) {
+		let limit = conn.inner.slow_handler_micros;
+		let resp = WsResponse { conn: conn.share() };
+		let start = getmicros!();
+		if limit > 0 {
+			conn.inner.slow_reported.store(0);
+			conn.inner.handler_start.store(start as u64);
+		}
+		match handler(req, resp) {
+			Ok(_) => {}
+			Err(e) => {
+				println!("WARN: [{}] handler generated error: {}", name, e);
+				if conn.inner.close_policy.handler_error != 0 {
+					conn.close_for(CloseReason::HandlerError);
+				}
+			}
+		}
+		if limit > 0 {
+			conn.inner.handler_start.store(0);
+			let elapsed = getmicros!() - start;
+			if elapsed > limit {
+				slow_handlers.fetch_add(1);
+				if conn.inner.slow_reported.load() == 0 {
+					println!(
+						"WARN: [{}] handler took {}us, call WsResponse::yield_now to locate the slow call",
+						name, elapsed
+					);
+				}
+			}
+		}
+	}
+
+	// queues a frame on the connection's inbox, starting a dispatch task to work through the
+	// inbox unless one is already running. Returns false, leaving the frame where it is, if
+	// the inbox is full, in which case the connection is not read from until the task has
+	// made room.
+	fn dispatch(
+		handle: &mut Box<Connection>,
+		state: &State,
+		handler: &Option<Arc<Handler>>,
+		tid: usize,
+		fin: bool,
+		op: u8,
+		payload: &[u8],
+	) -> bool {
+		// the frame's bytes are reclaimed once the worker moves past them
+		let mut msg = Vec::new();
+		if msg.append_ptr(payload.as_ptr(), payload.len()).is_err() {
+			println!(
+				"WARN: [{}] Could not allocate inbox message! Closing connection.",
+				state.config.name
+			);
+			handle.close_for(CloseReason::InternalError);
+			return true;
+		}
+		let start = {
+			let mut io = handle.inner.io.lock();
+			if io.inbox.len() >= state.config.inbox_capacity {
+				io.inbox_paused = true;
+				let _ = handle
+					.inner
+					.transport
+					.deregister(&state.wstate[tid].reactor, handle.inner.handle);
+				return false;
+			}
+			if io.inbox.push_back(InboxMessage { fin, op, msg }).is_err() {
+				drop(io);
+				println!(
+					"WARN: [{}] Could not allocate inbox message! Closing connection.",
+					state.config.name
+				);
+				handle.close_for(CloseReason::InternalError);
+				return true;
+			}
+			!replace(&mut io.inbox_running, true)
+		};
+		if !start {
+			return true;
+		}
+
+		let handler = match handler {
+			Some(handler) => match handler.clone() {
+				Ok(handler) => Some(handler),
+				Err(_e) => None,
+			},
+			None => None,
+		};
+		let mut dispatch = Dispatch {
+			conn: handle.share(),
+			handler,
+			name: state.config.name,
+			slow_handlers: state.slow_handlers.clone().unwrap(),
+			auto_control: state.config.auto_control_frames,
+			resume_at: state.config.inbox_capacity / 2,
+		};
+		let res = match &mut *state.dispatch.lock() {
+			Some(dispatch_runtime) => dispatch_runtime.execute(move || dispatch.run()),
+			None => Err(err!(NotInitialized)),
+		};
+		match res {
+			Ok(_) => {}
+			Err(e) => {
+				println!(
+					"WARN: [{}] Could not dispatch message: {}. Closing connection.",
+					state.config.name, e
+				);
+				{
+					let mut io = handle.inner.io.lock();
+					io.inbox.clear();
+					io.inbox_running = false;
+				}
+				handle.close_for(CloseReason::InternalError);
+			}
+		}
+		true
+	}
+
+	// picks up the handler if register_handler has replaced it since this worker last looked.
+	// Messages already being processed in this turn of the event loop keep the old one.
+	fn refresh_handler(ctx: &mut WsContext) {
+		let generation = ctx.state.handler_generation.load();
+		if generation == ctx.handler_generation {
+			return;
+		}
+		ctx.handler = match &*ctx.state.handler.read() {
+			Some(handler) => match handler.clone() {
+				Ok(handler) => Some(handler),
+				Err(_e) => None,
+			},
+			None => None,
+		};
+		ctx.handler_generation = generation;
+	}
+
+	fn close_cleanly(handle: &mut Box<Connection>, reason: CloseReason) {
+		handle.close_for(reason);
+	}
+
+	// processes complete frames until none are left or budget is used up. Returns true if the
+	// budget ran out with bytes still buffered, which may hold more frames.
+	fn proc_messages(ctx: &mut WsContext, conn: &mut Box<Connection>, budget: &mut u64) -> bool {
+		loop {
+			if *budget == 0 {
+				return !conn.inner.recv.lock().rbuf.is_empty();
+			}
+			let slen = conn.inner.recv.lock().rbuf.len();
+			let cstate = conn.inner.io.lock().cstate;
+			match cstate {
+				ConnectionState::NeedHandshake => {
+					if conn.inner.ctype == ConnectionType::ClientConnection {
+						Self::proc_hs_client(conn, ctx.state.config.max_handshake_bytes)
+					} else {
+						Self::proc_hs(conn, ctx)
+					}
+					#[cfg(test)]
+					if conn.inner.io.lock().cstate == ConnectionState::HandshakeComplete {
+						ctx.state
+							.emit(WsTestEvent::HandshakeComplete(conn.inner.ctype));
+					}
+				}
+				_ => Self::proc_hs_complete(conn, ctx),
+			}
+			let elen = conn.inner.recv.lock().rbuf.len();
+			if elen == 0 || elen == slen {
+				return false;
+			}
+			*budget -= 1;
+		}
+	}
+
+	fn message_budget(ctx: &WsContext) -> u64 {
+		match ctx.state.config.message_budget {
+			0 => u64::MAX,
+			budget => budget,
+		}
+	}
+
+	// puts a connection with frames left over at the back of the ready ring
+	fn make_ready(ctx: &mut WsContext, conn: &mut Box<Connection>) {
+		match ctx.ready.push(Ptr::new(conn.as_ptr().raw())) {
+			Ok(_) => conn.inner.recv.lock().ready = true,
+			Err(_e) => {
+				// without room on the ring the frames are processed now
+				let mut budget = u64::MAX;
+				Self::proc_messages(ctx, conn, &mut budget);
+			}
+		}
+	}
+
+	// gives each connection on the ready ring one turn, so that a connection with a deep
+	// backlog of frames cannot hold up the others on this worker. Connections which still
+	// have frames after their turn go to the back of the ring.
+	fn proc_ready(ctx: &mut WsContext) {
+		let turns = replace(&mut ctx.ready, Vec::new());
+		for i in 0..turns.len() {
+			let mut conn = Box::from_raw(turns[i]);
+			conn.leak();
+			conn.inner.recv.lock().ready = false;
+			let mut budget = Self::message_budget(ctx);
+			if Self::proc_messages(ctx, &mut conn, &mut budget) {
+				Self::make_ready(ctx, &mut conn);
+			}
+		}
+	}
+
+	fn proc_write(ctx: &mut WsContext, conn: &mut Box<Connection>) {
+		let mut io = conn.inner.io.lock();
+		loop {
+			let ret = conn
+				.inner
+				.transport
+				.send(conn.inner.handle, io.wbuf.front_slice());
+			if ret < 0 {
+				if ret != EAGAIN.into() {
+					conn.shutdown();
+				}
+				break;
+			} else {
+				if ret > 0 {
+					// cannot be an error
+					let _ = io.wbuf.shift(ret as usize);
+					conn.inner.buffer_bytes.fetch_sub(ret as u64);
+				} else {
+					break;
+				}
+			}
+		}
+
+		if io.wbuf.len() == 0 {
+			// a drained buffer is released rather than kept for the connection's lifetime
+			io.wbuf.clear();
+			io.wbuf_mem.set(0);
+			if io.close_pending != 0 {
+				conn.shutdown();
+			}
+			// cancel loop
+			let _ = conn.inner.transport.deregister_write(
+				&ctx.state.wstate[ctx.tid].reactor,
+				conn.inner.handle,
+				io.connptr.raw() as usize,
+			);
+		}
+	}
+
+	fn proc_read(ctx: &mut WsContext, conn: &mut Box<Connection>) {
+		conn.inner.io.lock().last = conn.inner.clock.now();
+
+		let (accounted, ready) = {
+			let recv = conn.inner.recv.lock();
+			(recv.rbuf.len() as u64, recv.ready)
+		};
+		// a connection waiting on the ready ring only buffers until its turn comes
+		let mut budget = if ready { 0 } else { Self::message_budget(ctx) };
+		loop {
+			// released before the frames are processed, which lock it again
+			let mut recv = conn.inner.recv.lock();
+			// received data is never moved, a new chunk is added when the last one is full
+			let buf = match recv.rbuf.spare() {
+				Ok(buf) => buf,
+				Err(_e) => {
+					println!(
+						"WARN: [{}] Could not allocate read buffer! Closing connection.",
+						ctx.state.config.name
+					);
+					conn.shutdown();
+					break;
+				}
+			};
+			let len = conn.inner.transport.recv(conn.inner.handle, buf);
+
+			if len == 0 || (len < 0 && len != EAGAIN as i64) {
+				let ready = recv.ready;
+				drop(recv);
+				// frames still waiting for a turn arrived ahead of the close, so are processed
+				if ready {
+					let mut rest = u64::MAX;
+					Self::proc_messages(ctx, conn, &mut rest);
+					let ptr = Ptr::new(conn.as_ptr().raw());
+					ctx.ready.retain(|v| *v != ptr);
+				}
+				{
+					let mut io = conn.inner.io.lock();
+					io.cstate = ConnectionState::Closed;
+					let buffered = accounted + io.wbuf.len() as u64;
+					conn.inner.buffer_bytes.fetch_sub(buffered);
+					// a handler sending now queues on the session, see send_frame_impl
+					Self::detach_session(conn, ctx.state.config.session_ttl_micros);
+				}
+				conn.inner.transport.close(conn.inner.handle);
+				ctx.conns.remove(conn.as_ptr());
+				#[cfg(test)]
+				ctx.state
+					.emit(WsTestEvent::ConnectionClosed(conn.inner.ctype));
+				conn.unleak();
+
+				return;
+			} else if len < 0 {
+				// EAGAIN, an idle connection keeps no buffer
+				if recv.rbuf.is_empty() {
+					recv.rbuf.clear();
+				}
+				break;
+			}
+
+			recv.rbuf.commit(len as usize);
+			drop(recv);
+			if budget > 0 && Self::proc_messages(ctx, conn, &mut budget) {
+				Self::make_ready(ctx, conn);
+			}
+			#[cfg(test)]
+			ctx.state.emit(WsTestEvent::DataRead(conn.inner.ctype));
+		}
+
+		let mut recv = conn.inner.recv.lock();
+		let buffered = recv.rbuf.len() as u64;
+		if buffered > accounted {
+			conn.inner.buffer_bytes.fetch_add(buffered - accounted);
+		} else {
+			conn.inner.buffer_bytes.fetch_sub(accounted - buffered);
+		}
+		let capacity = recv.rbuf.capacity() as u64;
+		recv.rbuf_mem.set(capacity);
+	}
+
+	fn proc_accept(ctx: &mut WsContext, _conn: &mut Box<Connection>, ehandle: *const u8) {
+		loop {
+			// leave the rest in the backlog, check_backpressure stops further events
+			if ctx.state.buffer_bytes.load() > ctx.state.config.max_buffer_bytes {
+				break;
+			}
+			let mut handle = [0u8; 4];
+			let nhandle: *mut u8 = &mut handle as *mut u8;
+			let res = unsafe { socket_accept(ehandle, nhandle) };
+			if res < 0 {
+				if res == EAGAIN {
+					break;
+				} else {
+					let errno = last_errno();
+					println!(
+						"WARN: [{}] Error accepting socket: {} ({})",
+						ctx.state.config.name,
+						socket_error_name(res),
+						errno_name(errno)
+					);
+					break;
+				}
+			}
+			let connection = match Connection::new(
+				ConnectionType::ServerConnection,
+				handle,
+				ctx.state.transport.clone().unwrap(),
+				&ctx.state,
+				ctx.tid,
+			) {
+				Ok(connection) => connection,
+				Err(_e) => {
+					continue;
+				}
+			};
+			let mut boxed_conn = match Box::new(connection) {
+				Ok(b) => b,
+				Err(_e) => {
+					continue;
+				}
+			};
+			boxed_conn.inner.io.lock().connptr = boxed_conn.as_ptr();
+			boxed_conn.leak();
+
+			if boxed_conn
+				.register(&ctx.state.wstate[ctx.tid].reactor, READ)
+				.is_err()
+			{
+				println!(
+					"WARN: [{}] could not register accepted connection!",
+					ctx.state.config.name
+				);
+				boxed_conn.inner.transport.close(handle);
+			}
+
+			ctx.conns.push_front(Ptr::new(boxed_conn.as_ptr().raw()));
+		}
+	}
+
+	fn proc_connection(
+		ctx: &mut WsContext,
+		conn: &mut Box<Connection>,
+		ehandle: *const u8,
+		readable: bool,
+	) {
+		match &conn.inner.ctype {
+			ConnectionType::Server if ctx.state.config.reuse_port => {
+				// the socket is this worker's alone
+				Self::proc_accept(ctx, conn, ehandle);
+			}
+			ConnectionType::Server => {
+				// since we are edge triggered, no other events
+				// can fire until we accept the connections, so
+				// we know this can only happen in each thread once
+				let cur = ctx.state.itt.load();
+				let rem = rem_usize(cur as usize, ctx.state.config.threads as usize);
+				if ctx.state.config.threads != 0 && rem == ctx.tid as usize {
+					Self::proc_accept(ctx, conn, ehandle);
+					ctx.state.itt.fetch_add(1);
+				}
+			}
+			_ => {
+				if readable {
+					Self::proc_read(ctx, conn);
+				} else {
+					Self::proc_write(ctx, conn);
+				}
+			}
+		}
+	}
+
+	// whether this worker closes the socket of conn when it stops. A listening socket shared
+	// by all workers is closed by the first.
+	fn owns_handle(ctx: &WsContext, conn: &Connection) -> bool {
+		conn.inner.ctype != ConnectionType::Server || ctx.tid == 0 || ctx.state.config.reuse_port
+	}
+
+	fn event_loop(ctx: &mut WsContext) -> Result<(), Error> {
+		if ctx.state.config.pin_threads {
+			let cpu = ctx.tid % cpu_count();
+			match set_thread_affinity(cpu) {
+				Ok(_) => {}
+				Err(e) => println!(
+					"WARN: [{}] could not pin worker {} to cpu {}: {}",
+					ctx.state.config.name, ctx.tid, cpu, e
+				),
+			}
+		}
+		loop {
+			// connections with frames waiting for their turn are not left until the timeout
+			let timeout = if ctx.ready.len() == 0 { 1000 } else { 0 };
+			// a failed wait is retried on the next iteration
+			let count = match ctx.state.wstate[ctx.tid]
+				.reactor
+				.poll(&mut ctx.events, timeout)
+			{
+				Ok(count) => count,
+				Err(_e) => 0,
+			};
+			if ctx.state.control.read().halt {
+				break;
+			}
+			Self::start_iteration(ctx);
+			for i in 0..count {
+				let evt = ctx.events.get(i);
+				if evt.token() == WAKE_TOKEN {
+					Self::proc_wakeup(ctx);
+				} else {
+					Self::proc_event(ctx, evt.token(), evt.is_readable());
+				}
+			}
+			Self::end_iteration(ctx);
+		}
+		Self::cleanup(ctx);
+		Ok(())
+	}
+
+	// the steps of each event loop iteration before and after its events are processed, also
+	// taken by the tests which step a worker over a MockTransport
+	fn start_iteration(ctx: &mut WsContext) {
+		// frames and handler scratch from the last iteration are no longer referenced
+		ctx.arena.reset();
+		Self::refresh_handler(ctx);
+		Self::check_backpressure(ctx);
+	}
+
+	fn proc_event(ctx: &mut WsContext, token: usize, readable: bool) {
+		let ptr = token as *mut Connection;
+		let mut connection = Box::from_raw(Ptr::new(ptr));
+		connection.leak();
+		let ehandle = &connection.inner.handle as *const u8;
+		Self::proc_connection(ctx, &mut connection, ehandle, readable);
+	}
+
+	fn end_iteration(ctx: &mut WsContext) {
+		Self::proc_ready(ctx);
+		Self::check_stale(ctx);
+		Self::check_drain(ctx);
+		Self::check_closing(ctx);
+	}
+
+	fn cleanup(ctx: &mut WsContext) {
+		// each connection is freed after the iterator has moved past it. The list holds
+		// untagged pointers so these boxes are not marked as leaked.
+		for v in ctx.conns.iter() {
+			let b = Box::from_raw(v);
+			if Self::owns_handle(ctx, &b) {
+				b.inner.transport.close(b.inner.handle);
+			}
+		}
+
+		// messages which arrived after the last wakeup was processed. Each connection holds a
+		// sender of this channel, so one left in it would keep both alive.
+		while ctx.state.wstate[ctx.tid].recv.pending() {
+			match ctx.state.wstate[ctx.tid].recv.recv() {
+				ConnectionMessage::Read(conn) => {
+					if Self::owns_handle(ctx, &conn) {
+						conn.inner.transport.close(conn.inner.handle);
+					}
+					let _conn = Box::from_raw(Ptr::new(conn.as_ptr().raw()));
+				}
+				ConnectionMessage::Write(_conn) => {}
+				ConnectionMessage::Resume(_conn) => {}
+				ConnectionMessage::Dump(_send) => {}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::str::from_utf8_unchecked;
+	use net::transport::MockTransport;
+	use util::proptest::*;
+
+	// block until each of the specified events has been emitted, ignoring others. Events
+	// may be listed more than once to wait for that many of them. Panics if they have not
+	// all arrived within 10 seconds.
+	fn await_events(events: &Receiver<WsTestEvent>, expected: &[WsTestEvent]) {
+		let mut remaining = Vec::new();
+		for event in expected {
+			remaining.push(*event).unwrap();
+		}
+		let deadline = getmicros!() + 10_000_000;
+		while remaining.len() > 0 {
+			let now = getmicros!();
+			let wait = if now < deadline { deadline - now } else { 0 };
+			let event = match events.recv_timeout(wait as u64) {
+				Ok(event) => event,
+				Err(_) => panic!("{} test events did not arrive", remaining.len()),
+			};
+			for i in 0..remaining.len() {
+				if remaining[i] == event {
+					remaining.swap_remove(i);
+					break;
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn test_ws1() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let threads = if cfg!(target_os = "linux") {
+			4 // 4 threads for Linux
+		} else {
+			1 // 1 thread for macOS or other OS
+		};
+
+		let config = WsConfig {
+			threads,
+			timeout_micros: 5_000_000,
+			..WsConfig::default()
+		};
+
+		let mut ws = WebSocket::new(config).unwrap();
+		let conf = Arc::new(Mutex::new(false)).unwrap();
+		ws.start().unwrap();
+
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
+				if s == "this is a test" {
+					let _ = resp.send("got it!");
+				} else if s == "got it!" {
+					*conf.lock() = true;
+				}
+				Ok(())
+			})
+			.unwrap();
+		ws.register_handler(b).unwrap();
+
+		let _port = ws
+			.add_server(WsServerConfig {
+				addr: [127, 0, 0, 1],
+				port: 9999,
+				backlog: 10,
+			})
+			.unwrap();
+		assert!(ws.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_proxy_refused() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.start().unwrap();
+
+		// a proxy which wants credentials the client does not have
+		let listener = TcpListener::bind([127, 0, 0, 1], 0, 10).unwrap();
+		let proxy_port = listener.port();
+		let mut jh = spawnj(move || {
+			listener.set_nonblocking(false).unwrap();
+			let stream = listener.accept().unwrap();
+			stream.set_nonblocking(false).unwrap();
+			let reply = b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n";
+			assert_eq!(stream.write(reply).unwrap(), reply.len());
+			let mut buf = [0u8; 512];
+			loop {
+				match stream.read(&mut buf) {
+					Ok(0) | Err(_) => break,
+					Ok(_) => {}
+				}
+			}
+		})
+		.unwrap();
+
+		let proxy = ProxyConfig::http_connect([127, 0, 0, 1], proxy_port);
+		let config = WsClientConfig::new([127, 0, 0, 1], 9998).with_proxy(proxy);
+		let e = ws.add_client(config).unwrap_err();
+		assert!(e.kind == Unauthorized);
+		jh.join().unwrap();
+		assert!(ws.stop().is_ok());
+	}
+
+	// everything the server sends until it closes the connection
+	fn read_until_closed(stream: &TcpStream) -> Vec<u8> {
+		stream.set_nonblocking(false).unwrap();
+		let mut ret = Vec::new();
+		let mut buf = [0u8; 256];
+		loop {
+			match stream.read(&mut buf) {
+				Ok(0) | Err(_) => break,
+				Ok(n) => ret.append_ptr(buf.as_ptr(), n).unwrap(),
+			}
+		}
+		ret
+	}
+
+	#[test]
+	fn test_ws_handshake_limits() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(WebSocket::new(WsConfig {
+			max_handshake_bytes: 0,
+			..WsConfig::default()
+		})
+		.is_err());
+
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			handshake_timeout_micros: 200_000,
+			max_handshake_bytes: 64 * 1024,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		// a request which trickles in a byte at a time is cut off with a 408 while its bytes
+		// are still arriving. Each byte is sent once the server has read the one before.
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		let start = unsafe { getmicros() };
+		let header = b"GET / HTTP/1.1\r\nX: ";
+		let mut sent = 0;
+		loop {
+			let b = if sent < header.len() {
+				header[sent]
+			} else {
+				b'a'
+			};
+			if stream.write(&[b]).is_err() {
+				break;
+			}
+			sent += 1;
+			let closed = loop {
+				match events.recv_timeout(10_000_000).unwrap() {
+					WsTestEvent::DataRead(ConnectionType::ServerConnection) => break false,
+					WsTestEvent::ConnectionClosed(ConnectionType::ServerConnection) => break true,
+					_ => {}
+				}
+			};
+			if closed {
+				break;
+			}
+		}
+		let resp = read_until_closed(&stream);
+		assert!(resp.as_slice().starts_with(b"HTTP/1.1 408 "));
+		assert!(unsafe { getmicros() } - start >= 200_000);
+
+		// headers which never end are cut off with a 400 once they pass the limit
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let mut request = Vec::new();
+		request.resize(128 * 1024).unwrap();
+		request.as_mut_slice().fill(b'a');
+		request[0..18].copy_from_slice(b"GET / HTTP/1.1\r\nX:");
+		let mut offset = 0;
+		while offset < request.len() {
+			match stream.write(&request.as_slice()[offset..]) {
+				Ok(n) => offset += n,
+				Err(_) => break,
+			}
+		}
+		let resp = read_until_closed(&stream);
+		assert!(resp.as_slice().starts_with(b"HTTP/1.1 400 "));
+
+		// a complete request sent slowly but within the timeout still succeeds
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		for part in [&request[0..3], &request[3..30], &request[30..request.len()]] {
+			stream.write(part).unwrap();
+			await_events(
+				&events,
+				&[WsTestEvent::DataRead(ConnectionType::ServerConnection)],
+			);
+		}
+		let mut buf = [0u8; 12];
+		let mut offset = 0;
+		while offset < buf.len() {
+			offset += stream.read(&mut buf[offset..]).unwrap();
 		}
+		assert_eq!(&buf, b"HTTP/1.1 101");
+		stream.shutdown().unwrap();
+
+		assert!(ws.stop().is_ok());
 	}
 
-	fn proc_hs_complete(handle: &mut Box<Connection>, ctx: &mut WsContext) {
-		let conn = Connection {
-			inner: handle.inner.clone().unwrap(),
-		};
+	// sends an upgrade request with the given extra header lines and returns the status line
+	fn upgrade_status(port: u16, headers: &str) -> [u8; 12] {
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let request = format!(
+			"GET /chat HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+			headers
+		)
+		.unwrap();
+		let request = request.to_str().as_bytes();
+		let mut offset = 0;
+		while offset < request.len() {
+			offset += stream.write(&request[offset..]).unwrap();
+		}
+		let mut status = [0u8; 12];
+		let mut offset = 0;
+		while offset < status.len() {
+			offset += stream.read(&mut status[offset..]).unwrap();
+		}
+		stream.shutdown().unwrap();
+		status
+	}
 
-		let len = handle.inner.rbuf.len();
+	#[test]
+	fn test_ws_accept_handler() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(WebSocket::new(WsConfig {
+			reject_status: 200,
+			..WsConfig::default()
+		})
+		.is_err());
 
-		// min length to try to process
-		if len < 2 {
-			return;
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.register_accept_handler(
+			Box::new(|info: &WsHandshakeInfo| {
+				assert_eq!(info.uri(), b"/chat");
+				assert_eq!(info.peer_addr().0, [127, 0, 0, 1]);
+				info.origin() == Some(b"https://example.com")
+			})
+			.unwrap(),
+		)
+		.unwrap();
+		ws.start().unwrap();
+		// the workers call the handler without a lock, so it cannot be replaced once started
+		let e = ws
+			.register_accept_handler(Box::new(|_info: &WsHandshakeInfo| true).unwrap())
+			.unwrap_err();
+		assert!(e.kind == IllegalState);
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		assert_eq!(
+			&upgrade_status(port, "Origin: https://example.com\r\n"),
+			b"HTTP/1.1 101"
+		);
+		assert_eq!(
+			&upgrade_status(port, "Origin: https://evil.example\r\n"),
+			b"HTTP/1.1 403"
+		);
+		assert_eq!(&upgrade_status(port, ""), b"HTTP/1.1 403");
+		assert_eq!(ws.handshakes_rejected(), 2);
+		assert!(ws.stop().is_ok());
+
+		// the status is configurable
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			reject_status: 404,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.register_accept_handler(Box::new(|_info: &WsHandshakeInfo| false).unwrap())
+			.unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+		assert_eq!(&upgrade_status(port, ""), b"HTTP/1.1 404");
+		assert!(ws.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_reuse_port() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 4,
+			reuse_port: true,
+			pin_threads: true,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+		// the kernel picks a worker's socket for each of these
+		for _ in 0..16 {
+			assert_eq!(&upgrade_status(port, ""), b"HTTP/1.1 101");
 		}
+		// each worker closes its own socket, which FdGuard checks
+		assert!(ws.stop().is_ok());
+	}
 
-		let rvec = &mut handle.inner.rbuf;
-		let fin = rvec[0] & 0x80 != 0;
+	#[test]
+	fn test_ws2() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let threads = if cfg!(target_os = "linux") {
+			4 // 4 threads for Linux
+		} else {
+			1 // 1 thread for macOS or other OS
+		};
 
-		// reserved bits not 0
-		if rvec[0] & 0x70 != 0 {
-			Self::close_cleanly(handle, 1002);
-			return;
+		let config = WsConfig {
+			threads,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let conf = Arc::new(Mutex::new(false)).unwrap();
+		let conf_clone = conf.clone().unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
+				if s == "this is a test" {
+					let _ = resp.send("got it!");
+				} else if s == "got it!" {
+					*conf.lock() = true;
+				}
+				Ok(())
+			})
+			.unwrap();
+		let _ = ws.register_handler(b);
+
+		let port = ws
+			.add_server(WsServerConfig {
+				addr: [127, 0, 0, 1],
+				port: 0,
+				backlog: 10,
+			})
+			.unwrap();
+
+		let mut req = ws
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+
+		assert!(req.send("this is a test").is_ok());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		{
+			assert!(*conf_clone.lock());
 		}
 
-		let op = rvec[0] & !0x80;
-		let mask = rvec[1] & 0x80 != 0;
+		req.close(1000);
+		await_events(
+			&events,
+			&[
+				WsTestEvent::ConnectionClosed(ConnectionType::ServerConnection),
+				WsTestEvent::ConnectionClosed(ConnectionType::ClientConnection),
+			],
+		);
 
-		// determine variable payload len
-		let payload_len = rvec[1] & 0x7F;
-		let (payload_len, mut offset) = if payload_len == 126 {
-			if len < 4 {
-				return;
-			}
-			((rvec[2] as usize) << 8 | rvec[3] as usize, 4)
-		} else if payload_len == 127 {
-			if len < 10 {
-				return;
-			}
-			(
-				(rvec[2] as usize) << 56
-					| (rvec[3] as usize) << 48
-					| (rvec[4] as usize) << 40
-					| (rvec[5] as usize) << 32
-					| (rvec[6] as usize) << 24
-					| (rvec[7] as usize) << 16
-					| (rvec[8] as usize) << 8
-					| (rvec[9] as usize),
-				10,
-			)
+		assert!(ws.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_perf() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let threads = if cfg!(target_os = "linux") {
+			8 // 8 threads for Linux
 		} else {
-			(payload_len as usize, 2)
+			1 // 1 thread for macOS or other OS
 		};
 
-		// if masking set we add 4 bytes for the masking key
-		if mask {
-			offset += 4;
-			if offset + payload_len > len {
-				return;
-			}
-			let masking_key = [
-				rvec[offset - 4],
-				rvec[offset - 3],
-				rvec[offset - 2],
-				rvec[offset - 1],
-			];
+		let config = WsConfig {
+			threads,
+			..WsConfig::default()
+		};
 
-			for i in 0..payload_len {
-				if i % 4 < masking_key.len() && offset + i < rvec.len() {
-					rvec[offset + i] ^= masking_key[i % 4];
-				}
-			}
+		let threads = 4;
+		let target = 1_000;
+
+		let mut ws = WebSocket::new(config).unwrap();
+		ws.start().unwrap();
+		let count = Arc::new(Mutex::new([0u64; 256])).unwrap();
+		let count_clone = count.clone().unwrap();
+		let mut sends = Vec::new();
+		let mut recvs = Vec::new();
+		for _i in 0..threads {
+			let (send, recv) = channel().unwrap();
+			let _ = sends.push(send);
+			let _ = recvs.push(recv);
 		}
 
-		if offset + payload_len > len {
-			return;
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				let msg = req.msg();
+				let item = from_be_bytes_u64(&msg[1..9]);
+
+				let index = msg[0] as usize;
+				let mut count = count.lock();
+				assert_eq!(count[index], item);
+				count[index] += 1;
+				if count[index] == target {
+					let _ = sends[index].send(());
+				}
+
+				Ok(())
+			})
+			.unwrap();
+		let _ = ws.register_handler(b);
+
+		let port = ws
+			.add_server(WsServerConfig {
+				addr: [127, 0, 0, 1],
+				port: 0,
+				backlog: 10,
+			})
+			.unwrap();
+		let mut resps = Vec::new();
+		for _i in 0..threads {
+			let resp = ws
+				.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+				.unwrap();
+			let _ = resps.push(resp);
 		}
-		let payload = &rvec[offset..payload_len + offset];
 
-		let req = WsRequest {
-			fin,
-			op,
-			msg: payload,
+		let config = RuntimeConfig {
+			min_threads: threads * 2,
+			max_threads: threads * 2,
+			..RuntimeConfig::default()
 		};
-		let resp = WsResponse { conn };
-		match &mut ctx.state.handler {
-			Some(handler) => match handler(req, resp) {
-				Ok(_) => {}
-				Err(e) => println!("WARN: handler generated error: {}", e),
-			},
-			None => {}
+		let mut runtime = Runtime::<()>::new(config).unwrap();
+		assert!(runtime.start().is_ok());
+
+		let mut jhs = Vec::new();
+
+		for v in 0..threads {
+			let mut resp = resps[v as usize].clone().unwrap();
+			let h = runtime
+				.execute(move || {
+					let mut bytes = [b'm'; 10];
+					bytes[0] = v as u8;
+					for i in 0..target {
+						to_be_bytes_u64(i as u64, &mut bytes[1..9]);
+						assert!(resp.sendb(&bytes).is_ok());
+					}
+				})
+				.unwrap();
+			let _ = jhs.push(h);
 		}
 
-		if payload_len + offset == len {
-			handle.inner.rbuf.clear();
-		} else {
-			// SAFETY: we know that n < len so there will be no error here
-			let _ = handle.inner.rbuf.shift(payload_len + offset);
+		for i in 0..jhs.len() {
+			jhs[i].block_on().unwrap();
 		}
+		for i in 0..threads {
+			recvs[i as usize].recv();
+			assert_eq!(count_clone.lock()[i as usize], target);
+		}
+		assert!(ws.stop().is_ok());
 	}
 
-	fn close_cleanly(handle: &mut Box<Connection>, status: u16) {
-		let conn = Connection {
-			inner: handle.inner.clone().unwrap(),
+	#[test]
+	fn test_ws_pending() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let threads = if cfg!(target_os = "linux") {
+			4 // 4 threads for Linux
+		} else {
+			1 // 1 thread for macOS or other OS
 		};
-		let resp = WsResponse { conn };
-		resp.close(status);
-	}
 
-	fn proc_messages(ctx: &mut WsContext, conn: &mut Box<Connection>) {
-		loop {
-			let slen = conn.inner.rbuf.len();
-			match conn.inner.cstate {
-				ConnectionState::NeedHandshake => {
-					if conn.inner.ctype == ConnectionType::ClientConnection {
-						Self::proc_hs_client(conn)
-					} else {
-						Self::proc_hs(conn)
-					}
+		let config = WsConfig {
+			threads,
+			debug_pending: true,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let conf = Arc::new(Mutex::new(false)).unwrap();
+		let conf_clone = conf.clone().unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
+				if s == "this is a test" {
+					let _ = resp.send("got it!");
+				} else if s == "got it!" {
+					*conf.lock() = true;
 				}
-				_ => Self::proc_hs_complete(conn, ctx),
-			}
-			let elen = conn.inner.rbuf.len();
-			if elen == 0 || elen == slen {
-				break;
-			}
+				Ok(())
+			})
+			.unwrap();
+		let _ = ws.register_handler(b);
+		let port = ws
+			.add_server(WsServerConfig {
+				addr: [127, 0, 0, 1],
+				port: 0,
+				backlog: 10,
+			})
+			.unwrap();
+
+		let mut req = ws
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+
+		assert!(req.send("this is a test").is_ok());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		{
+			assert!(*conf_clone.lock());
 		}
+
+		assert!(ws.stop().is_ok());
 	}
 
-	fn proc_write(ctx: &mut WsContext, conn: &mut Box<Connection>, ehandle: *const u8) {
-		loop {
-			let ret = unsafe {
-				socket_send(
-					&conn.inner.handle as *const u8,
-					conn.inner.wbuf[0..conn.inner.wbuf.len()].as_ptr(),
-					conn.inner.wbuf.len(),
-				)
-			};
-			if ret < 0 {
-				if ret != EAGAIN.into() {
-					unsafe {
-						socket_shutdown(&conn.inner.handle as *const u8);
-					}
-				}
-				break;
-			} else {
-				if ret > 0 {
-					// cannot be an error
-					let _ = conn.inner.wbuf.shift(ret as usize);
-					let nlen = conn.inner.wbuf.len();
-					// downward resize cannot be an error
-					let _ = conn.inner.wbuf.resize(nlen);
-				} else {
-					break;
+	#[test]
+	fn test_ws_loopback() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let threads = if cfg!(target_os = "linux") {
+			4 // 4 threads for Linux
+		} else {
+			1 // 1 thread for macOS or other OS
+		};
+
+		let config = WsConfig {
+			threads,
+			debug_pending: true,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let conf = Arc::new(Mutex::new(false)).unwrap();
+		let conf_clone = conf.clone().unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
+				if s == "this is a test" {
+					let _ = resp.send("got it!");
+				} else if s == "got it!" {
+					*conf.lock() = true;
 				}
-			}
+				Ok(())
+			})
+			.unwrap();
+		let _ = ws.register_handler(b);
+		let mut req = ws.add_loopback().unwrap();
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+
+		assert!(req.send("this is a test").is_ok());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		{
+			assert!(*conf_clone.lock());
 		}
 
-		if conn.inner.wbuf.len() == 0 {
-			// cancel loop
-			unsafe {
-				socket_multiplex_unregister_write(
-					&ctx.state.wstate[ctx.tid].mplex as *const u8,
-					ehandle,
-					conn.inner.connptr.raw() as *const u8,
-				)
-			};
-		}
+		assert!(ws.stop().is_ok());
 	}
 
-	fn proc_read(ctx: &mut WsContext, conn: &mut Box<Connection>, ehandle: *const u8) {
-		conn.inner.last = unsafe { getmicros() };
-		loop {
-			let rlen = conn.inner.rbuf.len();
-			match conn.inner.rbuf.resize(rlen + 256) {
+	#[test]
+	fn test_ws_debug_dump() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let config = WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let events = ws.test_events().unwrap();
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump.find("WebSocket: name=ws,").is_some());
+		assert!(dump.find("started=false").is_some());
+		assert!(dump.find("worker[").is_none());
+
+		ws.start().unwrap();
+		let _client = ws.add_loopback().unwrap();
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump.find("started=true,halt=false,handler=false").is_some());
+		assert!(dump
+			.find("worker[0]: queue=0,completions=0,connections=2 (servers=0,server_connections=1,clients=1,handshaking=0,pending_writes=0,accept_paused=false)")
+			.is_some());
+
+		assert!(ws.stop().is_ok());
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump.find("halt=true").is_some());
+		assert!(dump
+			.find("worker[0]: queue=0,completions=0,stopped")
+			.is_some());
+	}
+
+	#[test]
+	fn test_ws_accept_backpressure() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let config = WsConfig {
+			threads: 1,
+			max_buffer_bytes: 1024,
+			buffer_low_water: 512,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig {
+				addr: [127, 0, 0, 1],
+				port: 0,
+				backlog: 10,
+			})
+			.unwrap();
+
+		// simulate buffered data over the ceiling, the dump wakes the worker to notice
+		ws.state.buffer_bytes.fetch_add(4096);
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump.find("accept_paused=true").is_some());
+
+		// the connection waits in the listen backlog. The listener is out of the reactor while
+		// accepting is paused so the worker, which registers the client before it handles the
+		// dump, has nothing to accept and neither end can complete its handshake.
+		let _client = ws
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump
+			.find("servers=1,server_connections=0,clients=1,handshaking=2,pending_writes=0,accept_paused=true")
+			.is_some());
+		assert!(events.try_recv().is_none());
+
+		// below the low-water mark the backlog is accepted
+		ws.state.buffer_bytes.fetch_sub(4096);
+		let dump = ws.debug_dump().unwrap();
+		assert!(dump.find("accept_paused=false").is_some());
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+
+		assert!(ws.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_try_send() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		// the server never accepts so nothing the client writes is read
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			max_buffer_bytes: 1024,
+			buffer_low_water: 512,
+			..WsConfig::default()
+		})
+		.unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+		server.state.buffer_bytes.fetch_add(4096);
+		assert!(server
+			.debug_dump()
+			.unwrap()
+			.find("accept_paused=true")
+			.is_some());
+
+		let limit = 256 * 1024;
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			try_send_limit: limit,
+			..WsConfig::default()
+		})
+		.unwrap();
+		client.start().unwrap();
+		let mut resp = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+
+		// a frame larger than the limit is never queued
+		let big = [1u8; 300 * 1024];
+		match resp.try_sendb(&big) {
+			Ok(_) => panic!("expected WouldBlock"),
+			Err(e) => assert!(e.kind == ErrorKind::WouldBlock),
+		}
+
+		// fill the socket buffers until try_sendb refuses to queue more
+		let msg = [2u8; 64 * 1024];
+		let mut blocked = false;
+		for _i in 0..10_000 {
+			match resp.try_sendb(&msg) {
 				Ok(_) => {}
-				Err(_e) => {
-					println!("WARN: Could not allocate read buffer! Closing connection.");
-					unsafe {
-						socket_shutdown(ehandle);
-					}
+				Err(e) => {
+					assert!(e.kind == ErrorKind::WouldBlock);
+					blocked = true;
 					break;
 				}
 			}
-			let buf = &mut conn.inner.rbuf[rlen..rlen + 256];
-			let len = unsafe { socket_recv(ehandle, buf.as_mut_ptr(), 256) };
+		}
+		assert!(blocked);
+		let queued = { resp.conn.inner.io.lock().wbuf.len() as u64 };
+		assert!(queued > 0 && queued <= limit);
 
-			if len == 0 || (len < 0 && len != EAGAIN as i64) {
-				{
-					let mut conn_inner = conn.inner.clone().unwrap();
-					let _l = conn.inner.lock.write();
-					conn_inner.cstate = ConnectionState::Closed;
+		// send still buffers past the limit
+		assert!(resp.sendb(&msg).is_ok());
+		assert!(resp.try_send("x").is_err());
+
+		assert!(client.stop().is_ok());
+		server.state.buffer_bytes.fetch_sub(4096);
+		assert!(server.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_session_resume() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			session_ttl_micros: 10_000_000,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let events = server.test_events().unwrap();
+		let stash: Arc<Mutex<Option<WsResponse>>> = Arc::new(Mutex::new(None)).unwrap();
+		let stash_clone = stash.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() != 0x1 {
+					return Ok(());
 				}
-				unsafe {
-					socket_close(ehandle);
+				{
+					*stash.lock() = Some(resp.clone().unwrap());
 				}
-				Self::remove_from_list(ctx, conn);
-				conn.unleak();
-
-				break;
-			} else if len < 0 {
-				if rlen == 0 {
-					conn.inner.rbuf.clear();
+				if req.msg() == b"hello" {
+					resp.set_session_data(42);
+					resp.send("hi")
+				} else if resp.session_data() == 42 {
+					resp.send("42")
 				} else {
-					conn.inner.rbuf.resize(rlen).unwrap();
+					resp.send("none")
 				}
-				// EAGAIN
-				break;
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let counts = TestCounts::new().unwrap();
+		let counts_clone = counts.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				match req.msg() {
+					b"hi" => counts_clone.add(0),
+					b"queued" => counts_clone.add(1),
+					b"42" => counts_clone.add(2),
+					b"none" => counts_clone.add(3),
+					_ => {}
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		client.start().unwrap();
+		let config = WsClientConfig::new([127, 0, 0, 1], port);
+
+		let mut resp1 = client.add_client(config).unwrap();
+		assert!(resp1.send("hello").is_ok());
+		assert!(counts.wait(0, 1));
+		let token = resp1.resume_token().unwrap();
+		let old = {
+			match &*stash_clone.lock() {
+				Some(resp) => resp.clone().unwrap(),
+				None => panic!("no server connection"),
 			}
+		};
+		let id = old.session_id().unwrap();
 
-			conn.inner.rbuf.resize(len as usize + rlen).unwrap();
-			if len <= 0 {
-				break;
-			} else {
-				Self::proc_messages(ctx, conn);
+		// messages sent while the client is away are queued for the session
+		resp1.close(1000);
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(
+				ConnectionType::ServerConnection,
+			)],
+		);
+		assert!(old.is_closed());
+		let mut old_clone = old.clone().unwrap();
+		assert!(old_clone.send("queued").is_ok());
+
+		// resuming delivers the queue and keeps the session id and data
+		let mut resp2 = client.resume_client(config, &token).unwrap();
+		assert!(counts.wait(1, 1));
+		assert!(resp2.send("data").is_ok());
+		assert!(counts.wait(2, 1));
+		{
+			match &*stash_clone.lock() {
+				Some(resp) => assert!(resp.session_id() == Some(id)),
+				None => panic!("no server connection"),
 			}
 		}
+		assert!(old_clone.send("too late").is_err());
+
+		// a token which does not verify starts a new session
+		let mut forged = token;
+		forged[0] = if forged[0] == b'A' { b'B' } else { b'A' };
+		let mut resp3 = client.resume_client(config, &forged).unwrap();
+		assert!(resp3.send("data").is_ok());
+		assert!(counts.wait(3, 1));
+		assert!(server.debug_dump().unwrap().find("sessions=2").is_some());
+
+		// the sweep keeps a detached session until its ttl passes, and never a bound one
+		let token3 = resp3.resume_token().unwrap();
+		resp3.close(1000);
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(
+				ConnectionType::ServerConnection,
+			)],
+		);
+		WebSocket::expire_sessions(&server.state, unsafe { getmicros() });
+		assert!(server.debug_dump().unwrap().find("sessions=2").is_some());
+		WebSocket::expire_sessions(&server.state, i64::MAX);
+		assert!(server.debug_dump().unwrap().find("sessions=1").is_some());
+		let mut resp4 = client.resume_client(config, &token3).unwrap();
+		assert!(resp4.send("data").is_ok());
+		assert!(counts.wait(3, 2));
+		assert!(server.debug_dump().unwrap().find("sessions=2").is_some());
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
 	}
 
-	fn proc_accept(ctx: &mut WsContext, _conn: &mut Box<Connection>, ehandle: *const u8) {
-		let mplex = ctx.state.wstate[ctx.tid].mplex;
-		loop {
-			let mut handle = [0u8; 4];
-			let nhandle: *mut u8 = &mut handle as *mut u8;
-			let res = unsafe { socket_accept(ehandle, nhandle) };
-			if res < 0 {
-				if res == EAGAIN {
-					break;
-				} else {
-					println!("WARN: Error accepting socket code: {}", res);
-					break;
+	#[test]
+	fn test_ws_deferred_reply() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let server_events = server.test_events().unwrap();
+		let (deferred_send, deferred_recv) = channel().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, resp: WsResponse| {
+				if req.op() != 0x1 {
+					return Ok(());
 				}
-			}
-			let connection = match Connection::new(
-				ConnectionType::ServerConnection,
-				handle,
-				ctx.state.wstate[ctx.tid].send.clone().unwrap(),
-				ctx.state.config.debug_pending,
-				ctx.state.wstate[ctx.tid].wakeup,
-			) {
-				Ok(connection) => connection,
-				Err(_e) => {
-					continue;
+				let deferred = match resp.defer_reply() {
+					Ok(deferred) => deferred,
+					Err(e) => return Err(e),
+				};
+				// "drop" lets the token go out of scope without a reply
+				if req.msg() == b"later" {
+					deferred_send.send(deferred).unwrap();
 				}
-			};
-			let mut boxed_conn = match Box::new(connection) {
-				Ok(b) => b,
-				Err(_e) => {
-					continue;
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let counts = TestCounts::new().unwrap();
+		let counts_clone = counts.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.msg() == b"done" {
+					counts_clone.add(0);
 				}
-			};
-			boxed_conn.inner.connptr = boxed_conn.as_ptr();
-			boxed_conn.leak();
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let client_events = client.test_events().unwrap();
+		client.start().unwrap();
+		let config = WsClientConfig::new([127, 0, 0, 1], port);
+
+		// completed from another thread after the handler has returned
+		let mut resp1 = client.add_client(config).unwrap();
+		assert!(resp1.send("later").is_ok());
+		let deferred = deferred_recv.recv_timeout(10_000_000).unwrap();
+		assert!(!deferred.is_closed());
+		let mut jh = spawnj(move || {
+			assert!(deferred.reply("done").is_ok());
+		})
+		.unwrap();
+		assert!(jh.join().is_ok());
+		assert!(counts.wait(0, 1));
+		assert!(!resp1.is_closed());
+
+		// a token dropped without a reply closes the connection
+		assert!(resp1.send("drop").is_ok());
+		await_events(
+			&client_events,
+			&[WsTestEvent::ConnectionClosed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		assert!(resp1.is_closed());
+
+		// replying after the client has gone is an error
+		let resp2 = client.add_client(config).unwrap();
+		let mut resp2_clone = resp2.clone().unwrap();
+		assert!(resp2_clone.send("later").is_ok());
+		let deferred = deferred_recv.recv_timeout(10_000_000).unwrap();
+		resp2.close(1000);
+		// the server end of the first connection closed earlier
+		await_events(
+			&server_events,
+			&[WsTestEvent::ConnectionClosed(ConnectionType::ServerConnection); 2],
+		);
+		assert!(deferred.is_closed());
+		match deferred.reply("done") {
+			Ok(_) => panic!("expected ConnectionClosed"),
+			Err(e) => assert!(e.kind == ErrorKind::ConnectionClosed),
+		}
 
-			if unsafe {
-				socket_multiplex_register(
-					&mplex as *const u8,
-					nhandle,
-					REG_READ_FLAG,
-					boxed_conn.as_ptr().raw() as *const u8,
-				)
-			} < 0
-			{
-				println!("WARN: could not register accepted connection!");
-				unsafe {
-					socket_close(nhandle);
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+
+	#[test]
+	fn test_ws_secure() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			secure_identity: Some(Arc::new(SecureIdentity::generate().unwrap()).unwrap()),
+			..WsConfig::default()
+		})
+		.unwrap();
+		let events = server.test_events().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() == 0x2 {
+					resp.sendb(req.msg())
+				} else {
+					Ok(())
 				}
-			}
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			secure_identity: Some(Arc::new(SecureIdentity::generate().unwrap()).unwrap()),
+			..WsConfig::default()
+		})
+		.unwrap();
+		let counts = TestCounts::new().unwrap();
+		let counts_clone = counts.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				let msg = req.msg();
+				let mut intact = true;
+				for i in 0..msg.len() {
+					if msg[i] != (i % 251) as u8 {
+						intact = false;
+					}
+				}
+				counts_clone.add(if intact { 0 } else { 1 });
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		client.start().unwrap();
+
+		// the upgrade request waits for the handshake, then a message over many frames
+		let mut resp = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		let mut msg = Vec::new();
+		msg.resize(300_000).unwrap();
+		for i in 0..msg.len() {
+			msg[i] = (i % 251) as u8;
+		}
+		assert!(resp.sendb(&msg[0..100]).is_ok());
+		assert!(resp.sendb(msg.as_slice()).is_ok());
+		assert!(counts.wait(0, 2));
+		assert_eq!(counts.get(1), 0);
+
+		// a plain client fails the handshake and is dropped before its request is answered
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n";
+		assert_eq!(stream.write(request).unwrap(), request.len());
+		assert_eq!(read_until_closed(&stream).len(), 0);
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(
+				ConnectionType::ServerConnection,
+			)],
+		);
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
 
-			Self::update_head(ctx, &mut boxed_conn);
+	#[test]
+	fn test_ws_frame_checksum() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let config = WsConfig {
+			threads: 1,
+			frame_checksum: true,
+			..WsConfig::default()
+		};
+		let mut ws = WebSocket::new(config).unwrap();
+		let lock = lock_box!().unwrap();
+		let received = Arc::new(AtomicU64::new(0)).unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let received_clone = received.clone().unwrap();
+		let events = ws.test_events().unwrap();
+		ws.start().unwrap();
+
+		// the trailer is stripped before the handler sees the message
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() != 0x2 {
+					// close frames
+				} else if req.msg() == b"checked" {
+					let _ = resp.sendb(b"checked back");
+				} else {
+					let _l = lock.write();
+					if req.msg() == b"checked back" {
+						received.fetch_add(1);
+					} else {
+						received.fetch_add(100);
+					}
+				}
+				Ok(())
+			})
+			.unwrap();
+		ws.register_handler(b).unwrap();
+		let mut req = ws.add_loopback().unwrap();
+		await_events(
+			&events,
+			&[
+				WsTestEvent::HandshakeComplete(ConnectionType::ServerConnection),
+				WsTestEvent::HandshakeComplete(ConnectionType::ClientConnection),
+			],
+		);
+		assert!(req.conn.inner.negotiated.read().checksum);
+
+		assert!(req.sendb(b"checked").is_ok());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		{
+			let _l = lock_clone.read();
+			assert_eq!(received_clone.load(), 1);
+		}
+		assert_eq!(ws.checksum_failures(), 0);
+
+		// a frame with a bad trailer is counted and closes the connection
+		let mut header = [0u8; MAX_FRAME_HEADER];
+		let header_len = encode_frame_header(0x82, 7 + CHECKSUM_SIZE, &mut header);
+		assert!(req.conn.writeb(&header[0..header_len]).is_ok());
+		assert!(req.conn.writeb(b"corrupt").is_ok());
+		assert!(req.conn.writeb(&[0u8; CHECKSUM_SIZE]).is_ok());
+		await_events(
+			&events,
+			&[
+				WsTestEvent::ConnectionClosed(ConnectionType::ServerConnection),
+				WsTestEvent::ConnectionClosed(ConnectionType::ClientConnection),
+			],
+		);
+		assert_eq!(ws.checksum_failures(), 1);
+		assert!(req.is_closed());
+		{
+			let _l = lock_clone.read();
+			assert_eq!(received_clone.load(), 1);
 		}
+		assert!(ws
+			.debug_dump()
+			.unwrap()
+			.find("checksum_failures=1")
+			.is_some());
+
+		assert!(ws.stop().is_ok());
 	}
 
-	fn proc_connection(
-		ctx: &mut WsContext,
-		conn: &mut Box<Connection>,
-		ehandle: *const u8,
-		evt: *const u8,
-	) {
-		match &conn.inner.ctype {
-			ConnectionType::Server => {
-				// since we are edge triggered, no other events
-				// can fire until we accept the connections, so
-				// we know this can only happen in each thread once
-				let cur = aload!(&ctx.state.itt);
-				let rem = rem_usize(cur as usize, ctx.state.config.threads as usize);
-				if ctx.state.config.threads != 0 && rem == ctx.tid as usize {
-					Self::proc_accept(ctx, conn, ehandle);
-					aadd!(&mut ctx.state.itt, 1);
+	#[test]
+	fn test_ws_large_message() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(WebSocket::new(WsConfig {
+			read_chunk_size: 0,
+			..WsConfig::default()
+		})
+		.is_err());
+
+		// receive buffers of the default size and of one which is not a power of two
+		for read_chunk_size in [ROPE_CHUNK_SIZE, 1000] {
+			let mut ws = WebSocket::new(WsConfig {
+				threads: 1,
+				read_chunk_size,
+				..WsConfig::default()
+			})
+			.unwrap();
+			let lock = lock_box!().unwrap();
+			let replies = Arc::new(AtomicU64::new(0)).unwrap();
+			let lock_clone = lock.clone().unwrap();
+			let replies_clone = replies.clone().unwrap();
+			let events = ws.test_events().unwrap();
+			ws.start().unwrap();
+
+			// the server checks each binary message arrived intact and answers with text
+			let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+				Box::new(move |req: WsRequest, mut resp: WsResponse| {
+					if req.op() == 0x2 {
+						let msg = req.msg();
+						let mut intact = true;
+						for i in 0..msg.len() {
+							if msg[i] != (i % 251) as u8 {
+								intact = false;
+								break;
+							}
+						}
+						let _ = resp.send(if intact { "ok" } else { "bad" });
+					} else if req.op() == 0x1 {
+						let _l = lock.write();
+						replies.fetch_add(if req.msg() == b"ok" { 1 } else { 1_000 });
+					}
+					Ok(())
+				})
+				.unwrap();
+			ws.register_handler(b).unwrap();
+			let mut client = ws.add_loopback().unwrap();
+
+			// a frame over many receive chunks, then frames which share chunks with its tail
+			let mut msg = Vec::new();
+			msg.resize(2 * 1024 * 1024 + 7).unwrap();
+			for i in 0..msg.len() {
+				msg[i] = (i % 251) as u8;
+			}
+			assert!(client.sendb(msg.as_slice()).is_ok());
+			assert!(client.sendb(&msg[0..10]).is_ok());
+			assert!(client.sendb(&msg[0..40_000]).is_ok());
+
+			await_events(
+				&events,
+				&[WsTestEvent::MessageProcessed(ConnectionType::ClientConnection); 3],
+			);
+			{
+				let _l = lock_clone.read();
+				assert_eq!(replies_clone.load(), 3);
+			}
+
+			assert!(ws.stop().is_ok());
+		}
+	}
+
+	#[test]
+	fn test_ws_frame_checksum_rejected() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		// a server without the extension does not echo the header so the client closes
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			frame_checksum: true,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let resp = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		assert!(resp.is_closed());
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+
+	#[test]
+	fn test_frame_header_prop() {
+		// every payload length round trips and no strict prefix of a header decodes
+		check(PropConfig::default(), |len: &u64| {
+			let len = *len as usize;
+			let mut buf = [0u8; MAX_FRAME_HEADER];
+			let header_len = encode_frame_header(0x82, len, &mut buf);
+			for i in 0..header_len {
+				match decode_frame_header(&buf[0..i]) {
+					Ok(None) => {}
+					_ => return false,
 				}
 			}
-			_ => {
-				if unsafe { socket_event_is_read(evt) } {
-					Self::proc_read(ctx, conn, ehandle);
-				} else {
-					let conn2 = conn.clone().unwrap();
-					let _l = conn2.inner.lock.write();
-					Self::proc_write(ctx, conn, ehandle);
+			match decode_frame_header(&buf[0..header_len]) {
+				Ok(Some(header)) => {
+					header.fin
+						&& header.op == 0x2
+						&& header.masking_key.is_none()
+						&& header.payload_len == len
+						&& header.offset == header_len
 				}
+				_ => false,
 			}
-		}
+		});
 	}
 
-	fn event_loop(ctx: &mut WsContext) -> Result<(), Error> {
-		let mut ehandle = [0u8; 4];
-		let ehandle: *mut u8 = &mut ehandle as *mut u8;
-		let wakeup = &ctx.state.wstate[ctx.tid].wakeup as *const u8;
-		let mplex = &ctx.state.wstate[ctx.tid].mplex as *const u8;
-
-		loop {
-			let count = unsafe {
-				socket_multiplex_wait(mplex, ctx.events, ctx.state.config.max_events, 1000)
-			};
+	#[test]
+	fn test_frame_prop() {
+		let _alloc = AllocGuard::new();
+		let config = PropConfig {
+			max_len: 300,
+			..PropConfig::default()
+		};
+		// masked frames (as sent by clients) decode and unmask to the original payload
+		check(config, |payload: &Vec<u8>| {
+			let len = payload.len();
+			let masking_key = [len as u8, 0x5a, 0xa5, (len >> 8) as u8];
+			let mut buf = [0u8; MAX_FRAME_HEADER];
+			let header_len = encode_frame_header(0x81, len, &mut buf);
+			buf[1] |= 0x80;
+			let mut frame = Vec::new();
+			if frame.append_ptr(buf.as_ptr(), header_len).is_err()
+				|| frame.append_ptr(masking_key.as_ptr(), 4).is_err()
 			{
-				let _l = ctx.state.lock.read();
-				if ctx.state.halt {
-					break;
-				}
+				return false;
 			}
-			for i in 0..count {
-				let evt = unsafe { ctx.events.add(i as usize * socket_event_size() as usize) };
-				unsafe {
-					socket_event_handle(ehandle, evt);
+			for i in 0..len {
+				if frame.push(payload[i] ^ masking_key[i % 4]).is_err() {
+					return false;
 				}
+			}
 
-				if unsafe { socket_handle_eq(ehandle, wakeup) } {
-					unsafe {
-						socket_clear_pipe(ehandle);
-					}
-					Self::proc_wakeup(ctx);
+			let header = match decode_frame_header(&frame[0..frame.len()]) {
+				Ok(Some(header)) => header,
+				_ => return false,
+			};
+			if header.offset != header_len + 4 || header.payload_len != len || header.op != 0x1 {
+				return false;
+			}
+			let end = frame.len();
+			match header.masking_key {
+				Some(key) => unmask(&mut frame[header.offset..end], &key),
+				None => return false,
+			}
+			&frame[header.offset..end] == &payload[0..len]
+		});
+
+		// reserved bits are rejected
+		assert!(decode_frame_header(&[0x91, 0]).is_err());
+	}
+
+	#[test]
+	fn test_ws_slow_handler() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			slow_handler_micros: 5_000,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() != 0x1 {
+					return Ok(());
+				}
+				if req.msg() == b"slow" {
+					// busy for well over the limit
+					let start = getmicros!();
+					while getmicros!() - start < 20_000 {}
+					// reports the first time only
+					resp.yield_now();
+					resp.yield_now();
 				} else {
-					let ptr = unsafe { socket_event_ptr(evt) } as *const ConnectionInner;
-					let mut connection = Box::from_raw(Ptr::new(ptr as *mut Connection));
-					connection.leak();
-					let ehandle = &connection.inner.handle as *const u8;
-					Self::proc_connection(ctx, &mut connection, ehandle, evt);
+					resp.yield_now();
 				}
-			}
-			Self::check_stale(ctx);
+				resp.send("done")
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let server_events = server.test_events().unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let count = Arc::new(AtomicU64::new(0)).unwrap();
+		let count_clone = count.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				let _l = lock.write();
+				if req.msg() == b"done" {
+					count.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let mut resp = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+
+		// outside of a handler invocation yield_now only yields
+		resp.yield_now();
+		for msg in ["fast", "slow", "fast", "slow"] {
+			assert!(resp.send(msg).is_ok());
+		}
+		// the server counts a slow handler once it has returned, after its reply is sent
+		await_events(
+			&server_events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ServerConnection); 4],
+		);
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ClientConnection); 4],
+		);
+		{
+			let _l = lock_clone.read();
+			assert_eq!(count_clone.load(), 4);
 		}
+		assert_eq!(server.slow_handlers(), 2);
+		assert!(server
+			.debug_dump()
+			.unwrap()
+			.find("slow_handlers=2")
+			.is_some());
+		assert_eq!(client.slow_handlers(), 0);
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
 
-		// cleanup connections
-		let mut cur = ctx.state.wstate[ctx.tid].head;
-		while !cur.is_null() {
-			let v = cur;
-			cur = unsafe { (*cur).inner.next.raw() };
-			let b = Box::from_raw(Ptr::new(v));
-			if b.inner.ctype != ConnectionType::Server || ctx.tid == 0 {
-				unsafe {
-					socket_close(&b.inner.handle as *const u8);
+	#[test]
+	fn test_ws_fair_scheduling() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			message_budget: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let order = Arc::new(Mutex::new(Vec::new())).unwrap();
+		let order_clone = order.clone().unwrap();
+		let (started_send, started_recv) = channel().unwrap();
+		let (release_send, release_recv) = channel().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() != 0x1 {
+					return Ok(());
 				}
-			}
+				// hold the worker so that both clients have frames buffered when it resumes
+				if req.msg() == b"x" {
+					started_send.send(()).unwrap();
+					release_recv.recv();
+				}
+				order_clone.lock().push(req.msg()[0])
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let events = server.test_events().unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		client.start().unwrap();
+		let mut chatty = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		let mut quiet = client
+			.add_client(WsClientConfig::new([127, 0, 0, 1], port))
+			.unwrap();
+		assert!(chatty.send("w").is_ok());
+		assert!(quiet.send("w").is_ok());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ServerConnection); 2],
+		);
+		assert_eq!(order.lock().len(), 2);
+
+		assert!(chatty.send("x").is_ok());
+		started_recv.recv();
+		for _i in 0..50 {
+			assert!(chatty.send("a").is_ok());
 		}
-
-		unsafe {
-			socket_close(&ctx.state.wstate[ctx.tid].wakeup as *const u8);
-			socket_close((&ctx.state.wstate[ctx.tid].wakeup as *const u8).add(4));
-			socket_close(&ctx.state.wstate[ctx.tid].mplex as *const u8);
-			release(ctx.events);
+		assert!(quiet.send("b").is_ok());
+		// nothing was left queued, so the frames are in the server's socket buffers
+		for resp in [&chatty, &quiet] {
+			assert_eq!(resp.conn.inner.io.lock().wbuf.len(), 0);
 		}
+		release_send.send(()).unwrap();
+
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ServerConnection); 52],
+		);
+		let order = order.lock();
+		assert_eq!(order.len(), 54);
+		assert_eq!(order[2], b'x');
+		// the quiet connection is served after a few turns, not after the chatty backlog
+		let mut quiet_at = 0;
+		for i in 0..order.len() {
+			if order[i] == b'b' {
+				quiet_at = i;
+			}
+		}
+		assert!(quiet_at > 2 && quiet_at < 12);
 
-		Ok(())
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
 	}
-}
-
-#[cfg(test)]
-mod test {
-	use super::*;
-	use core::str::from_utf8_unchecked;
 
 	#[test]
-	fn test_ws1() {
-		let initial = unsafe { crate::ffi::getalloccount() };
-		let initial_fds = unsafe { crate::ffi::getfdcount() };
-		{
-			let threads = if cfg!(target_os = "linux") {
-				4 // 4 threads for Linux
-			} else {
-				1 // 1 thread for macOS or other OS
-			};
-
-			let config = WsConfig {
-				threads,
-				timeout_micros: 5_000_000,
-				..WsConfig::default()
-			};
+	fn test_ws_socket_errors() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(socket_error_kind(ERROR_CONNECT) == Connect);
+		assert!(socket_error_kind(ERROR_LISTEN) == Bind);
+		assert!(socket_error_kind(ERROR_FCNTL) == IO);
+		assert_eq!(socket_error_name(ERROR_ACCEPT), "accept");
+		assert_eq!(socket_error_name(0), "unknown");
+
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		ws.start().unwrap();
+		let port = ws
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+		assert!(port > 0);
+		// nothing listens on port 1 so the connection is refused
+		match ws.add_client(WsClientConfig::new([127, 0, 0, 1], 1)) {
+			Ok(_) => panic!("connected to port 1"),
+			Err(e) => {
+				assert!(e.kind == ConnectionRefused);
+				assert!(e.is_retryable());
+				assert_eq!(errno_name(e.errno), "ECONNREFUSED");
+			}
+		}
+		assert!(ws.stop().is_ok());
+	}
 
-			let mut ws = WebSocket::new(config).unwrap();
-			let lock = lock_box!().unwrap();
-			let mut conf = Rc::new(false).unwrap();
-			ws.start().unwrap();
+	#[test]
+	fn test_ws_drain() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert_eq!(reason_phrase(503), "Service Unavailable");
+		assert_eq!(reason_phrase(599), "Unavailable");
+
+		let mut server = WebSocket::new(WsConfig {
+			threads: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.op() == 0x1 {
+					let _ = resp.send("pong");
+				}
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let on_complete: Box<dyn FnMut() + Send + Sync> = Box::new(|| {}).unwrap();
+		assert!(server.drain(WsDrainConfig::default(), on_complete).is_err());
+		server.start().unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let replies = Arc::new(AtomicU64::new(0)).unwrap();
+		let going_away = Arc::new(AtomicU64::new(0)).unwrap();
+		let replies_clone = replies.clone().unwrap();
+		let going_away_clone = going_away.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() == 0x1 {
+					replies.fetch_add(1);
+				} else if req.op() == 0x8 && req.msg() == &[0x03, 0xE9] {
+					going_away.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let config = WsClientConfig::new(addr, port);
+		let mut conns = Vec::new();
+		for _i in 0..6 {
+			let mut resp = client.add_client(config).unwrap();
+			resp.send("ping").unwrap();
+			conns.push(resp).unwrap();
+		}
+		// every connection has completed its handshake once it has been answered
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ClientConnection); 6],
+		);
+		assert_eq!(replies_clone.load(), 6);
+
+		let (completed_send, completed_recv) = channel().unwrap();
+		let on_complete: Box<dyn FnMut() + Send + Sync> = Box::new(move || {
+			completed_send.send(()).unwrap();
+		})
+		.unwrap();
+		let drain = WsDrainConfig {
+			batches: 3,
+			window_micros: 300_000,
+			..WsDrainConfig::default()
+		};
+		let bad = WsDrainConfig {
+			batches: 0,
+			..WsDrainConfig::default()
+		};
+		let on_bad: Box<dyn FnMut() + Send + Sync> = Box::new(|| {}).unwrap();
+		assert!(server.drain(bad, on_bad).is_err());
+		assert!(!server.is_draining());
+		server.drain(drain, on_complete).unwrap();
+		assert!(server.is_draining());
+		let on_again: Box<dyn FnMut() + Send + Sync> = Box::new(|| {}).unwrap();
+		assert!(server.drain(WsDrainConfig::default(), on_again).is_err());
+		assert!(server.debug_dump().unwrap().find("draining=true").is_some());
+
+		// new clients are turned away with a 503 while existing ones are asked to leave
+		let rejected = client.add_client(config).unwrap();
+		// the drain completes once the server has sent its closes, which the client sees later
+		completed_recv.recv_timeout(10_000_000).unwrap();
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(ConnectionType::ClientConnection); 7],
+		);
+		assert!(completed_recv.try_recv().is_none());
+		assert!(rejected.is_closed());
+		for i in 0..conns.len() {
+			assert!(conns[i].is_closed());
+		}
+		assert_eq!(going_away_clone.load(), 6);
+		assert_eq!(replies_clone.load(), 6);
 
-			let b: Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>> =
-				Box::new(move |req: WsRequest, mut resp: WsResponse| {
-					let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
-					if s == "this is a test" {
-						let _ = resp.send("got it!");
-					} else if s == "got it!" {
-						let _l = lock.write();
-						*conf = true;
-					}
-					Ok(())
-				})
-				.unwrap();
-			ws.register_handler(b);
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
 
-			let _port = ws
-				.add_server(WsServerConfig {
-					addr: [127, 0, 0, 1],
-					port: 9999,
-					backlog: 10,
-				})
-				.unwrap();
-			match ws.stop() {
-				Ok(_) => {}
-				Err(_) => unsafe {
-					crate::ffi::sleep_millis(200);
-				},
-			}
+	#[test]
+	fn test_ws_shutdown_graceful() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.msg() == b"ping" {
+					let _ = resp.send("pong");
+				}
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		assert!(server.shutdown_graceful(1_000_000).unwrap_err().kind == NotInitialized);
+		server.start().unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let replies = Arc::new(AtomicU64::new(0)).unwrap();
+		let going_away = Arc::new(AtomicU64::new(0)).unwrap();
+		let replies_clone = replies.clone().unwrap();
+		let going_away_clone = going_away.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() == 0x1 {
+					replies.fetch_add(1);
+				} else if req.op() == 0x8 && req.msg() == &[0x03, 0xE9] {
+					going_away.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let mut conns = Vec::new();
+		for _i in 0..4 {
+			let mut resp = client.add_client(WsClientConfig::new(addr, port)).unwrap();
+			resp.send("ping").unwrap();
+			conns.push(resp).unwrap();
+		}
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(ConnectionType::ClientConnection); 4],
+		);
+		assert_eq!(replies_clone.load(), 4);
+
+		// every client is told the server is going away before it stops
+		let start = getmicros!();
+		server.shutdown_graceful(5_000_000).unwrap();
+		assert!(getmicros!() - start < 5_000_000);
+		await_events(
+			&events,
+			&[WsTestEvent::ConnectionClosed(ConnectionType::ClientConnection); 4],
+		);
+		assert_eq!(going_away_clone.load(), 4);
+		for i in 0..conns.len() {
+			assert!(conns[i].is_closed());
+		}
+		// stopped already
+		assert!(server.shutdown_graceful(1_000_000).is_ok());
+		assert!(client.stop().is_ok());
+
+		// a client which never reads what is queued for it holds the close back until the
+		// deadline
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.msg() == b"flood" {
+					// far more than the socket buffers hold, for a client which never reads
+					let mut big = Vec::new();
+					big.resize(16 * 1024 * 1024).unwrap();
+					let _ = resp.sendb(big.as_slice());
+				}
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let events = server.test_events().unwrap();
+		server.start().unwrap();
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+		let stream = TcpStream::connect(addr, port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		assert_eq!(stream.write(request).unwrap(), request.len());
+		let mut status = [0u8; 12];
+		let mut offset = 0;
+		while offset < status.len() {
+			offset += stream.read(&mut status[offset..]).unwrap();
 		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
-		assert_eq!(initial_fds, unsafe { crate::ffi::getfdcount() });
+		assert_eq!(&status, b"HTTP/1.1 101");
+		// a masked text frame with a zero key
+		let frame = b"\x81\x85\x00\x00\x00\x00flood";
+		assert_eq!(stream.write(frame).unwrap(), frame.len());
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ServerConnection,
+			)],
+		);
+		assert!(server.state.buffer_bytes.load() > 0);
+		let start = getmicros!();
+		assert!(server.shutdown_graceful(200_000).unwrap_err().kind == Timeout);
+		assert!(getmicros!() - start >= 200_000);
 	}
 
 	#[test]
-	fn test_ws2() {
-		let initial = unsafe { crate::ffi::getalloccount() };
-		let initial_fds = unsafe { crate::ffi::getfdcount() };
-		{
-			let threads = if cfg!(target_os = "linux") {
-				4 // 4 threads for Linux
-			} else {
-				1 // 1 thread for macOS or other OS
-			};
+	fn test_ws_replace_handler() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut server = WebSocket::new(WsConfig {
+			threads: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, mut resp: WsResponse| resp.send("one")).unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let ones = Arc::new(AtomicU64::new(0)).unwrap();
+		let twos = Arc::new(AtomicU64::new(0)).unwrap();
+		let ones_clone = ones.clone().unwrap();
+		let twos_clone = twos.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.msg() == b"one" {
+					ones.fetch_add(1);
+				} else if req.msg() == b"two" {
+					twos.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let mut resp = client.add_client(WsClientConfig::new(addr, port)).unwrap();
+		resp.send("hi").unwrap();
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		assert_eq!(ones_clone.load(), 1);
+
+		// swapped while the workers are running, the old handler is no longer called
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, mut resp: WsResponse| resp.send("two")).unwrap();
+		server.register_handler(b).unwrap();
+		resp.send("hi").unwrap();
+		await_events(
+			&events,
+			&[WsTestEvent::MessageProcessed(
+				ConnectionType::ClientConnection,
+			)],
+		);
+		assert_eq!(twos_clone.load(), 1);
+		assert_eq!(ones_clone.load(), 1);
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
 
-			let config = WsConfig {
-				threads,
-				..WsConfig::default()
-			};
-			let mut ws = WebSocket::new(config).unwrap();
-			let lock = lock_box!().unwrap();
-			let mut conf = Rc::new(false).unwrap();
-			let lock_clone = lock.clone().unwrap();
-			let conf_clone = conf.clone().unwrap();
-			ws.start().unwrap();
+	#[test]
+	fn test_ws_control_frames() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(WsOpcode::from_u8(0x89) == WsOpcode::Ping);
+		assert!(WsOpcode::from_u8(0x3) == WsOpcode::Reserved(0x3));
+		assert_eq!(WsOpcode::Pong.to_u8(), 0xA);
+		assert!(WsOpcode::Close.is_control());
+		assert!(WsOpcode::Reserved(0xB).is_control());
+		assert!(!WsOpcode::Reserved(0x3).is_control());
+		assert!(!WsOpcode::Continuation.is_control());
+
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let seen = Arc::new(AtomicU64::new(0)).unwrap();
+		let closes = Arc::new(AtomicU64::new(0)).unwrap();
+		let seen_clone = seen.clone().unwrap();
+		let closes_clone = closes.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				seen.fetch_add(1);
+				if req.opcode() == WsOpcode::Close && req.is_control() {
+					closes.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+		stream.set_nonblocking(false).unwrap();
+		let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		assert_eq!(stream.write(request).unwrap(), request.len());
+		let mut buf = [0u8; 512];
+		let mut offset = 0;
+		while offset < 4 || &buf[offset - 4..offset] != b"\r\n\r\n" {
+			offset += stream.read(&mut buf[offset..offset + 1]).unwrap();
+		}
 
-			let b: Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>> =
-				Box::new(move |req: WsRequest, mut resp: WsResponse| {
-					let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
-					if s == "this is a test" {
-						let _ = resp.send("got it!");
-					} else if s == "got it!" {
-						let _l = lock.write();
-						*conf = true;
-					}
-					Ok(())
-				})
-				.unwrap();
-			let _ = ws.register_handler(b);
+		// a ping is answered with a pong carrying its payload, without the handler
+		let ping = b"\x89\x82\x00\x00\x00\x00hi";
+		assert_eq!(stream.write(ping).unwrap(), ping.len());
+		let mut pong = [0u8; 4];
+		let mut offset = 0;
+		while offset < pong.len() {
+			offset += stream.read(&mut pong[offset..]).unwrap();
+		}
+		assert_eq!(&pong, b"\x8A\x02hi");
 
-			let port = ws
-				.add_server(WsServerConfig {
-					addr: [127, 0, 0, 1],
-					port: 0,
-					backlog: 10,
-				})
-				.unwrap();
+		// a close reaches the handler and is returned with its status
+		let close = b"\x88\x82\x00\x00\x00\x00\x03\xE9";
+		assert_eq!(stream.write(close).unwrap(), close.len());
+		let resp = read_until_closed(&stream);
+		assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xE9"));
+		assert_eq!(seen_clone.load(), 1);
+		assert_eq!(closes_clone.load(), 1);
 
-			let mut req = ws
-				.add_client(WsClientConfig {
-					addr: [127, 0, 0, 1],
-					port,
-				})
-				.unwrap();
+		assert!(server.stop().is_ok());
+	}
 
-			assert!(req.send("this is a test").is_ok());
+	#[test]
+	fn test_ws_dispatch() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(WebSocket::new(WsConfig {
+			dispatch_threads: 2,
+			inbox_capacity: 0,
+			..WsConfig::default()
+		})
+		.is_err());
 
-			loop {
-				{
-					let _l = lock_clone.read();
-					if *conf_clone {
-						break;
-					}
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			dispatch_threads: 2,
+			inbox_capacity: 2,
+			..WsConfig::default()
+		})
+		.unwrap();
+		// "block" waits for release, digits are echoed and must arrive in order
+		let (release_send, release_recv) = channel().unwrap();
+		let last = Arc::new(AtomicU64::new(0)).unwrap();
+		let disorder = Arc::new(AtomicU64::new(0)).unwrap();
+		let last_clone = last.clone().unwrap();
+		let disorder_clone = disorder.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, mut resp: WsResponse| {
+				if req.msg() == b"block" {
+					release_recv.recv();
+					return Ok(());
 				}
-				unsafe {
-					crate::ffi::sleep_millis(1);
+				let digit = (req.msg()[0] - b'0') as u64;
+				if digit != last.load() + 1 {
+					disorder.fetch_add(1);
 				}
+				last.store(digit);
+				resp.sendb(req.msg())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		let events = server.test_events().unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let connect = || {
+			let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+			stream.set_nonblocking(false).unwrap();
+			let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+			assert_eq!(stream.write(request).unwrap(), request.len());
+			let mut buf = [0u8; 512];
+			let mut offset = 0;
+			while offset < 4 || &buf[offset - 4..offset] != b"\r\n\r\n" {
+				offset += stream.read(&mut buf[offset..offset + 1]).unwrap();
 			}
-
-			match ws.stop() {
-				Ok(_) => {}
-				Err(_) => unsafe {
-					crate::ffi::sleep_millis(200);
-				},
+			stream
+		};
+		let send = |stream: &TcpStream, msg: &[u8]| {
+			let mut frame = [0x81, 0x80 | msg.len() as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+			frame[6..6 + msg.len()].copy_from_slice(msg);
+			let len = 6 + msg.len();
+			assert_eq!(stream.write(&frame[0..len]).unwrap(), len);
+		};
+		// the server's echoes of the digits
+		let recv = |stream: &TcpStream, buf: &mut [u8]| {
+			let mut offset = 0;
+			while offset < buf.len() {
+				match stream.read(&mut buf[offset..]) {
+					Ok(0) | Err(_) => break,
+					Ok(n) => offset += n,
+				}
 			}
+			assert_eq!(offset, buf.len());
+		};
+
+		// one connection's handler blocking does not hold up another on the same worker
+		let blocked = connect();
+		send(&blocked, b"block");
+		let other = connect();
+		send(&other, b"1");
+		let mut echo = [0u8; 3];
+		recv(&other, &mut echo);
+		assert_eq!(&echo, b"\x82\x011");
+
+		// more messages than the inbox holds wait in order behind the blocked handler
+		while events.try_recv().is_some() {}
+		for msg in [b"2", b"3", b"4", b"5", b"6", b"7", b"8", b"9"] {
+			send(&blocked, msg);
+		}
+		await_events(
+			&events,
+			&[WsTestEvent::DataRead(ConnectionType::ServerConnection)],
+		);
+		assert_eq!(last_clone.load(), 1);
+		release_send.send(()).unwrap();
+		let mut echoes = [0u8; 24];
+		recv(&blocked, &mut echoes);
+		for i in 0..8 {
+			assert_eq!(&echoes[i * 3..i * 3 + 3], &[0x82, 1, b'2' + i as u8]);
 		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
-		assert_eq!(initial_fds, unsafe { crate::ffi::getfdcount() });
+		assert_eq!(disorder_clone.load(), 0);
+
+		assert!(server.stop().is_ok());
 	}
 
 	#[test]
-	fn test_ws_perf() {
-		let initial = unsafe { crate::ffi::getalloccount() };
-		let initial_fds = unsafe { crate::ffi::getfdcount() };
-		{
-			let threads = if cfg!(target_os = "linux") {
-				8 // 8 threads for Linux
-			} else {
-				1 // 1 thread for macOS or other OS
-			};
+	fn test_ws_close_handler() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let arena = Arena::new(ARENA_CHUNK_SIZE).unwrap();
+		let req = WsRequest {
+			msg: b"\x03\xE9bye",
+			fin: true,
+			op: 0x8,
+			arena: &arena,
+		};
+		let close = req.close_frame().unwrap();
+		assert!(close.status() == Some(1001));
+		assert_eq!(close.reason(), "bye");
+		let failure = |payload: &[u8]| match parse_close(payload) {
+			Ok(_) => None,
+			Err(reason) => Some(reason),
+		};
+		assert!(failure(b"").is_none());
+		assert!(failure(b"\x03") == Some(CloseReason::ProtocolError));
+		assert!(failure(b"\x03\xED") == Some(CloseReason::ProtocolError));
+		assert!(failure(b"\x03\xE8\xFF") == Some(CloseReason::InvalidPayload));
+		let req = WsRequest {
+			msg: b"\x03\xE9",
+			fin: true,
+			op: 0x1,
+			arena: &arena,
+		};
+		assert!(req.close_frame().is_none());
 
-			let config = WsConfig {
-				threads,
-				..WsConfig::default()
-			};
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		let seen = Arc::new(AtomicU64::new(0)).unwrap();
+		let seen_clone = seen.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |_req: WsRequest, _resp: WsResponse| {
+				seen.fetch_add(1);
+				Ok(())
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		// the last status seen, 0 for none, and the number of closes giving "bye" as reason
+		let status = Arc::new(AtomicU64::new(0)).unwrap();
+		let byes = Arc::new(AtomicU64::new(0)).unwrap();
+		let status_clone = status.clone().unwrap();
+		let byes_clone = byes.clone().unwrap();
+		let c: CloseHandler = Box::new(move |close: WsClose, _resp: WsResponse| {
+			match close.status() {
+				Some(code) => status.store(code as u64),
+				None => status.store(0),
+			}
+			if close.reason() == "bye" {
+				byes.fetch_add(1);
+			}
+		})
+		.unwrap();
+		server.register_close_handler(c).unwrap();
+		server.start().unwrap();
+		let port = server
+			.add_server(WsServerConfig::new([127, 0, 0, 1], 0, 10))
+			.unwrap();
+
+		let close_with = |frame: &[u8]| {
+			let stream = TcpStream::connect([127, 0, 0, 1], port).unwrap();
+			stream.set_nonblocking(false).unwrap();
+			let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+			assert_eq!(stream.write(request).unwrap(), request.len());
+			assert_eq!(stream.write(frame).unwrap(), frame.len());
+			read_until_closed(&stream)
+		};
 
-			let threads = 4;
-			let target = 1_000;
+		// the close handler gets the status and reason, the handler nothing
+		let resp = close_with(b"\x88\x85\x00\x00\x00\x00\x03\xE9bye");
+		assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xE9"));
+		assert_eq!(status_clone.load(), 1001);
+		assert_eq!(byes_clone.load(), 1);
+
+		// an empty close has no status and is answered with a normal closure
+		let resp = close_with(b"\x88\x80\x00\x00\x00\x00");
+		assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xE8"));
+		assert_eq!(status_clone.load(), 0);
+
+		// a malformed close fails the connection without reaching either handler
+		status_clone.store(1);
+		let resp = close_with(b"\x88\x83\x00\x00\x00\x00\x03\xE8\xFF");
+		assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xEF"));
+		let resp = close_with(b"\x88\x81\x00\x00\x00\x00\x03");
+		assert!(resp.as_slice().ends_with(b"\x88\x02\x03\xEA"));
+		assert_eq!(status_clone.load(), 1);
+		assert_eq!(seen_clone.load(), 0);
+
+		assert!(server.stop().is_ok());
+	}
 
-			let mut ws = WebSocket::new(config).unwrap();
-			ws.start().unwrap();
-			let mut count = Rc::new([0u64; 256]).unwrap();
-			let count_clone = count.clone().unwrap();
-			let mut sends = Vec::new();
-			let mut recvs = Vec::new();
-			for _i in 0..threads {
-				let (send, recv) = channel().unwrap();
-				let _ = sends.push(send);
-				let _ = recvs.push(recv);
-			}
-
-			let b: Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>> =
-				Box::new(move |req: WsRequest, _resp: WsResponse| {
-					let msg = req.msg();
-					let item = from_be_bytes_u64(&msg[1..9]);
-
-					let index = msg[0];
-					assert_eq!((*count)[index as usize], item);
-					(*count)[index as usize] += 1;
-					if (*count)[index as usize] == target {
-						let _ = sends[index as usize].send(());
-					}
+	#[test]
+	fn test_ws_close_policy() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(valid_close_code(1000));
+		assert!(valid_close_code(1011));
+		assert!(valid_close_code(4999));
+		assert!(!valid_close_code(999));
+		assert!(!valid_close_code(1005));
+		assert!(!valid_close_code(1016));
+		assert!(!valid_close_code(5000));
+		let policy = WsClosePolicy::default();
+		assert_eq!(policy.code(CloseReason::IdleTimeout), 1001);
+		assert_eq!(policy.code(CloseReason::HandlerError), 0);
+		assert!(WebSocket::new(WsConfig {
+			close_policy: WsClosePolicy {
+				idle_timeout: 1016,
+				..WsClosePolicy::default()
+			},
+			..WsConfig::default()
+		})
+		.is_err());
 
+		let mut server = WebSocket::new(WsConfig {
+			threads: 1,
+			close_policy: WsClosePolicy {
+				handler_error: 4000,
+				..WsClosePolicy::default()
+			},
+			..WsConfig::default()
+		})
+		.unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, resp: WsResponse| {
+				if req.msg() == b"bad status" {
+					resp.close(1016);
 					Ok(())
-				})
-				.unwrap();
-			let _ = ws.register_handler(b);
+				} else {
+					Err(err!(IllegalState))
+				}
+			})
+			.unwrap();
+		server.register_handler(b).unwrap();
+		server.start().unwrap();
+		let addr = [127, 0, 0, 1];
+		let port = server.add_server(WsServerConfig::new(addr, 0, 10)).unwrap();
+
+		let mut client = WebSocket::new(WsConfig {
+			threads: 1,
+			..WsConfig::default()
+		})
+		.unwrap();
+		// the status of the last close frame which carried one, and the number without
+		let status = Arc::new(AtomicU64::new(0)).unwrap();
+		let empty = Arc::new(AtomicU64::new(0)).unwrap();
+		let status_clone = status.clone().unwrap();
+		let empty_clone = empty.clone().unwrap();
+		let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+			Box::new(move |req: WsRequest, _resp: WsResponse| {
+				if req.op() == 0x8 && req.msg().len() == 2 {
+					status.store(from_be_bytes_u16(req.msg()) as u64);
+				} else if req.op() == 0x8 {
+					empty.fetch_add(1);
+				}
+				Ok(())
+			})
+			.unwrap();
+		client.register_handler(b).unwrap();
+		let events = client.test_events().unwrap();
+		client.start().unwrap();
+		let config = WsClientConfig::new(addr, port);
+
+		let await_closed = |resp: &WsResponse| {
+			await_events(
+				&events,
+				&[WsTestEvent::ConnectionClosed(
+					ConnectionType::ClientConnection,
+				)],
+			);
+			assert!(resp.is_closed());
+		};
+
+		// a failing handler closes with the configured status, in a single close frame
+		let mut resp = client.add_client(config).unwrap();
+		resp.send("fail").unwrap();
+		await_closed(&resp);
+		assert_eq!(status_clone.load(), 4000);
+		assert_eq!(empty_clone.load(), 0);
+
+		// a forbidden status is not put on the wire
+		status_clone.store(0);
+		let mut resp = client.add_client(config).unwrap();
+		resp.send("bad status").unwrap();
+		await_closed(&resp);
+		assert_eq!(status_clone.load(), 0);
+		assert_eq!(empty_clone.load(), 1);
+
+		assert!(client.stop().is_ok());
+		assert!(server.stop().is_ok());
+	}
+
+	// one worker stepped by the test over a MockTransport. Its event loop is never started,
+	// so each step does exactly the work the test has set up and no test waits on a socket.
+	struct MockWorker {
+		ws: WebSocket,
+		ctx: WsContext,
+		mock: MockTransport,
+		transport: Arc<Box<dyn Transport>>,
+	}
+
+	impl Drop for MockWorker {
+		fn drop(&mut self) {
+			WebSocket::cleanup(&mut self.ctx);
+		}
+	}
 
-			let port = ws
-				.add_server(WsServerConfig {
-					addr: [127, 0, 0, 1],
-					port: 0,
-					backlog: 10,
+	impl MockWorker {
+		fn new(config: WsConfig) -> Self {
+			Self::with(
+				WebSocket::new(WsConfig {
+					threads: 1,
+					..config
 				})
-				.unwrap();
-			let mut resps = Vec::new();
-			for _i in 0..threads {
-				let resp = ws
-					.add_client(WsClientConfig {
-						addr: [127, 0, 0, 1],
-						port,
-					})
-					.unwrap();
-				let _ = resps.push(resp);
-			}
-
-			let config = RuntimeConfig {
-				min_threads: threads * 2,
-				max_threads: threads * 2,
+				.unwrap(),
+			)
+		}
+
+		// for handlers which can only be registered before the worker shares the state
+		fn with(mut ws: WebSocket) -> Self {
+			let wstate = WorkerState::new(Reactor::new().unwrap()).unwrap();
+			ws.state.get_mut().unwrap().wstate.push(wstate).unwrap();
+			let mock = MockTransport::new().unwrap();
+			let transport: Box<dyn Transport> = Box::new(mock.clone().unwrap()).unwrap();
+			let ctx = WsContext {
+				state: ws.state.clone().unwrap(),
+				tid: 0,
+				conns: IntrusiveList::new(),
+				events: Events::new(1).unwrap(),
+				last_check: 0,
+				accept_paused: false,
+				drained: false,
+				closed: false,
+				handler: None,
+				handler_generation: 0,
+				ready: Vec::new(),
+				arena: Arena::new(ARENA_CHUNK_SIZE).unwrap(),
 			};
-			let mut runtime = Runtime::<()>::new(config).unwrap();
-			assert!(runtime.start().is_ok());
-
-			let mut jhs = Vec::new();
-
-			for v in 0..threads {
-				let mut resp = resps[v as usize].clone().unwrap();
-				let h = runtime
-					.execute(move || {
-						let mut bytes = [b'm'; 10];
-						bytes[0] = v as u8;
-						for i in 0..target {
-							to_be_bytes_u64(i as u64, &mut bytes[1..9]);
-							assert!(resp.sendb(&bytes).is_ok());
-						}
-					})
-					.unwrap();
-				let _ = jhs.push(h);
+			Self {
+				ws,
+				ctx,
+				mock,
+				transport: Arc::new(transport).unwrap(),
 			}
+		}
 
-			for i in 0..jhs.len() {
-				jhs[i].block_on();
-			}
-			for i in 0..threads {
-				let _ = recvs[i as usize].recv();
-				assert_eq!((*count_clone)[i as usize], target);
-			}
-			match ws.stop() {
-				Ok(_) => {}
-				Err(_) => unsafe {
-					crate::ffi::sleep_millis(200);
-				},
-			}
+		// a server connection on a new mock socket, handed to the worker as add_loopback does
+		fn accept(&mut self) -> [u8; 4] {
+			let handle = self.mock.socket().unwrap();
+			let state = &self.ctx.state;
+			let conn = Connection::new(
+				ConnectionType::ServerConnection,
+				handle,
+				self.transport.clone().unwrap(),
+				state,
+				0,
+			)
+			.unwrap();
+			let mut conn = Box::new(conn).unwrap();
+			conn.leak();
+			state.wstate[0]
+				.send
+				.send(ConnectionMessage::Read(conn))
+				.unwrap();
+			self.step();
+			handle
 		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
-		assert_eq!(initial_fds, unsafe { crate::ffi::getfdcount() });
-	}
 
-	#[test]
-	fn test_ws_pending() {
-		let initial = unsafe { crate::ffi::getalloccount() };
-		let initial_fds = unsafe { crate::ffi::getfdcount() };
-		{
-			let threads = if cfg!(target_os = "linux") {
-				4 // 4 threads for Linux
-			} else {
-				1 // 1 thread for macOS or other OS
-			};
+		// accepts a connection and completes its handshake
+		fn upgrade(&mut self) -> [u8; 4] {
+			let conn = self.accept();
+			let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+			self.mock.push(conn, request).unwrap();
+			self.step();
+			let resp = self.mock.take(conn).unwrap();
+			assert!(resp.as_slice().starts_with(b"HTTP/1.1 101"));
+			conn
+		}
 
-			let config = WsConfig {
-				threads,
-				debug_pending: true,
-				..WsConfig::default()
-			};
-			let mut ws = WebSocket::new(config).unwrap();
-			let lock = lock_box!().unwrap();
-			let mut conf = Rc::new(false).unwrap();
-			let lock_clone = lock.clone().unwrap();
-			let conf_clone = conf.clone().unwrap();
-			ws.start().unwrap();
+		// stops the clock connections are judged idle by, so that only advance moves it on
+		fn hold_clock(&mut self) {
+			let now = getmicros!();
+			self.ctx.state.clock.held.store(now as u64);
+		}
 
-			let b: Box<dyn FnMut(WsRequest, WsResponse) -> Result<(), Error>> =
+		fn advance(&mut self, micros: i64) {
+			self.ctx.state.clock.held.fetch_add(micros as u64);
+		}
+
+		// one iteration of the event loop with the readiness of the mock sockets as its events
+		fn step(&mut self) {
+			WebSocket::start_iteration(&mut self.ctx);
+			WebSocket::proc_wakeup(&mut self.ctx);
+			let ready = self.mock.ready().unwrap();
+			for i in 0..ready.len() {
+				let (token, readable) = ready[i];
+				WebSocket::proc_event(&mut self.ctx, token, readable);
+			}
+			WebSocket::end_iteration(&mut self.ctx);
+		}
+
+		fn echo(&mut self) {
+			let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
 				Box::new(move |req: WsRequest, mut resp: WsResponse| {
-					let s = unsafe { from_utf8_unchecked(&req.msg()[0..req.msg().len()]) };
-					if s == "this is a test" {
-						let _ = resp.send("got it!");
-					} else if s == "got it!" {
-						let _l = lock.write();
-						*conf = true;
+					if !req.is_control() {
+						let _ = resp.sendb(req.msg());
 					}
 					Ok(())
 				})
 				.unwrap();
-			let _ = ws.register_handler(b);
-			let port = ws
-				.add_server(WsServerConfig {
-					addr: [127, 0, 0, 1],
-					port: 0,
-					backlog: 10,
-				})
-				.unwrap();
+			self.ws.register_handler(b).unwrap();
+		}
+	}
 
-			let mut req = ws
-				.add_client(WsClientConfig {
-					addr: [127, 0, 0, 1],
-					port,
-				})
-				.unwrap();
+	#[test]
+	fn test_ws_mock_handshake() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut worker = MockWorker::new(WsConfig::default());
+		worker.echo();
+		let conn = worker.accept();
+
+		// the request arrives a byte at a time and is answered once it is complete
+		let request = b"GET / HTTP/1.1\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+		for i in 0..request.len() {
+			assert_eq!(worker.mock.take(conn).unwrap().len(), 0);
+			worker.mock.push(conn, &request[i..i + 1]).unwrap();
+			worker.step();
+		}
+		let resp = worker.mock.take(conn).unwrap();
+		assert!(resp.as_slice().starts_with(b"HTTP/1.1 101"));
+		assert!(resp
+			.as_slice()
+			.windows(28)
+			.any(|w| w == b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+		// two frames in one read, the second of them cut short, then its remainder
+		worker
+			.mock
+			.push(conn, b"\x81\x82\x00\x00\x00\x00hi\x81\x83\x00\x00")
+			.unwrap();
+		worker.step();
+		assert_eq!(worker.mock.take(conn).unwrap().as_slice(), b"\x82\x02hi");
+		worker.mock.push(conn, b"\x00\x00abc").unwrap();
+		worker.step();
+		assert_eq!(worker.mock.take(conn).unwrap().as_slice(), b"\x82\x03abc");
+
+		// a frame with reserved bits set fails the connection
+		worker
+			.mock
+			.push(conn, b"\xC1\x82\x00\x00\x00\x00hi")
+			.unwrap();
+		worker.step();
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x03\xEA"
+		);
+		assert!(worker.mock.is_shutdown(conn));
+		worker.step();
+		assert!(worker.mock.is_closed(conn));
+
+		// a request which is not an upgrade is refused
+		let conn = worker.accept();
+		worker.mock.push(conn, b"POST / HTTP/1.1\r\n\r\n").unwrap();
+		worker.step();
+		assert!(worker
+			.mock
+			.take(conn)
+			.unwrap()
+			.as_slice()
+			.starts_with(b"HTTP/1.1 400"));
+		assert!(worker.mock.is_shutdown(conn));
+		worker.step();
+		assert_eq!(worker.mock.open(), 0);
+	}
 
-			assert!(req.send("this is a test").is_ok());
+	#[test]
+	fn test_ws_mock_backpressure() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut worker = MockWorker::new(WsConfig::default());
+		worker.echo();
+		let conn = worker.upgrade();
+
+		// the peer takes 10 bytes at a time, the rest of the echo waits in the write buffer
+		worker.mock.set_room(conn, 10);
+		let mut frame = [0u8; 106];
+		frame[0] = 0x81;
+		frame[1] = 0x80 | 100;
+		for i in 6..frame.len() {
+			frame[i] = i as u8;
+		}
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		let mut echoed = worker.mock.take(conn).unwrap();
+		assert_eq!(echoed.len(), 10);
+		assert_eq!(worker.ctx.state.buffer_bytes.load(), 92);
+		// the worker only asks for writability once the write buffer has data in it
+		worker.step();
+		assert_eq!(worker.mock.interest(conn), READ | WRITE);
+		while echoed.len() < 102 {
+			worker.step();
+			let more = worker.mock.take(conn).unwrap();
+			assert!(more.len() > 0 && more.len() <= 10);
+			echoed.append(&more).unwrap();
+		}
+		assert_eq!(&echoed.as_slice()[..2], b"\x82\x64");
+		assert_eq!(&echoed.as_slice()[2..], &frame[6..]);
+		assert_eq!(worker.ctx.state.buffer_bytes.load(), 0);
+		worker.step();
+		assert_eq!(worker.mock.interest(conn), READ);
+	}
 
-			loop {
-				{
-					let _l = lock_clone.read();
-					if *conf_clone {
-						break;
-					}
-				}
-				unsafe {
-					crate::ffi::sleep_millis(1);
-				}
-			}
+	#[test]
+	fn test_ws_mock_write_buffer_limit() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut worker = MockWorker::new(WsConfig {
+			max_write_buffer_bytes: 150,
+			..WsConfig::default()
+		});
+		worker.echo();
+
+		// a frame too big to ever fit is refused and the connection closed at once
+		let conn = worker.upgrade();
+		let mut frame = [0u8; 208];
+		frame[0] = 0x81;
+		frame[1] = 0x80 | 126;
+		frame[3] = 200;
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x03\xF5"
+		);
+		assert!(worker.mock.is_shutdown(conn));
+
+		// a peer which stops reading may have one echo waiting, but not two
+		let conn = worker.upgrade();
+		worker.mock.set_room(conn, 10);
+		let mut frame = [0u8; 106];
+		frame[0] = 0x81;
+		frame[1] = 0x80 | 100;
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		assert_eq!(worker.mock.take(conn).unwrap().len(), 10);
+		assert!(!worker.mock.is_shutdown(conn));
+		worker.mock.set_room(conn, 0);
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		// the close waits behind the rest of the first echo instead of being dropped with it
+		assert!(!worker.mock.is_shutdown(conn));
+		worker.mock.set_room(conn, 1000);
+		worker.step();
+		let rest = worker.mock.take(conn).unwrap();
+		assert_eq!(rest.len(), 96);
+		assert_eq!(&rest.as_slice()[92..], b"\x88\x02\x03\xF5");
+		assert!(worker.mock.is_shutdown(conn));
+		worker.step();
+		assert!(worker.mock.is_closed(conn));
+		assert_eq!(worker.ctx.state.buffer_bytes.load(), 0);
+
+		// nor is a peer which never reads them waited on for longer than the idle timeout
+		let mut worker = MockWorker::new(WsConfig {
+			max_write_buffer_bytes: 150,
+			timeout_micros: 5_000,
+			stale_check_micros: 1_000,
+			..WsConfig::default()
+		});
+		worker.hold_clock();
+		worker.echo();
+		let conn = worker.upgrade();
+		worker.mock.set_room(conn, 0);
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		worker.mock.push(conn, &frame).unwrap();
+		worker.step();
+		worker.advance(5_000);
+		worker.step();
+		assert!(!worker.mock.is_shutdown(conn));
+		worker.advance(1_000);
+		worker.step();
+
+		assert!(worker.mock.is_shutdown(conn));
+		assert_eq!(worker.mock.take(conn).unwrap().len(), 0);
+
+		assert!(WebSocket::new(WsConfig {
+			close_policy: WsClosePolicy {
+				try_again_later: 1015,
+				..WsClosePolicy::default()
+			},
+			..WsConfig::default()
+		})
+		.is_err());
+	}
 
-			match ws.stop() {
-				Ok(_) => {}
-				Err(_) => unsafe {
-					crate::ffi::sleep_millis(200);
-				},
-			}
-		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
-		assert_eq!(initial_fds, unsafe { crate::ffi::getfdcount() });
+	#[test]
+	fn test_ws_mock_idle_handler() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut ws = WebSocket::new(WsConfig {
+			threads: 1,
+			timeout_micros: 5_000,
+			stale_check_micros: 1_000,
+			..WsConfig::default()
+		})
+		.unwrap();
+		assert!(WebSocket::new(WsConfig {
+			stale_check_micros: 0,
+			..WsConfig::default()
+		})
+		.is_err());
+		let calls = Arc::new(AtomicU64::new(0)).unwrap();
+		let calls_clone = calls.clone().unwrap();
+		let idle = Arc::new(AtomicU64::new(0)).unwrap();
+		let idle_clone = idle.clone().unwrap();
+		let actions = [
+			WsIdleAction::Ping,
+			WsIdleAction::Extend(60_000_000),
+			WsIdleAction::Close(4000),
+			WsIdleAction::Timeout,
+		];
+		let h: IdleHandler = Box::new(move |_resp: WsResponse, micros: i64| {
+			idle_clone.store(micros as u64);
+			let n = calls_clone.fetch_add(1);
+			actions[n as usize]
+		})
+		.unwrap();
+		ws.register_idle_handler(h).unwrap();
+		let mut worker = MockWorker::with(ws);
+		worker.hold_clock();
+		worker.echo();
+
+		// a ping leaves the connection open and restarts the idle time
+		let conn = worker.upgrade();
+		worker.step();
+		assert_eq!(calls.load(), 0);
+		worker.advance(10_000);
+		worker.step();
+		assert_eq!(calls.load(), 1);
+		assert_eq!(idle.load(), 10_000);
+		assert_eq!(worker.mock.take(conn).unwrap().as_slice(), b"\x89\x00");
+		assert!(!worker.mock.is_shutdown(conn));
+		worker.advance(10_000);
+		worker.step();
+		assert_eq!(calls.load(), 2);
+		worker.advance(10_000);
+		worker.step();
+		assert_eq!(calls.load(), 2);
+		assert_eq!(worker.mock.take(conn).unwrap().len(), 0);
+		assert!(!worker.mock.is_shutdown(conn));
+
+		// closes with the status the handler chose, or the idle timeout status
+		let conn = worker.upgrade();
+		worker.advance(10_000);
+		worker.step();
+		assert_eq!(calls.load(), 3);
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x0F\xA0"
+		);
+		assert!(worker.mock.is_shutdown(conn));
+		let conn = worker.upgrade();
+		worker.advance(10_000);
+		worker.step();
+		assert_eq!(calls.load(), 4);
+
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x03\xE9"
+		);
+		assert!(worker.mock.is_shutdown(conn));
+	}
+
+	#[test]
+	fn test_ws_mock_close() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let mut worker = MockWorker::new(WsConfig::default());
+		worker.echo();
+
+		// a close is answered with the same status, and the socket shut down and closed
+		let conn = worker.upgrade();
+		worker
+			.mock
+			.push(conn, b"\x88\x82\x00\x00\x00\x00\x03\xE8")
+			.unwrap();
+		worker.step();
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x03\xE8"
+		);
+		assert!(worker.mock.is_closed(conn));
+
+		// a malformed close gets a protocol error
+		let conn = worker.upgrade();
+		worker
+			.mock
+			.push(conn, b"\x88\x81\x00\x00\x00\x00\x03")
+			.unwrap();
+		worker.step();
+		assert_eq!(
+			worker.mock.take(conn).unwrap().as_slice(),
+			b"\x88\x02\x03\xEA"
+		);
+
+		// a peer which goes away is closed after the frames it sent first
+		let conn = worker.upgrade();
+		worker
+			.mock
+			.push(conn, b"\x81\x82\x00\x00\x00\x00hi")
+			.unwrap();
+		worker.mock.close_peer(conn);
+		worker.step();
+		assert!(worker.mock.is_closed(conn));
+		worker.step();
+		assert_eq!(worker.mock.open(), 0);
+		assert_eq!(worker.ctx.conns.iter().count(), 0);
 	}
 }
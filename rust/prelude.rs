@@ -1,22 +1,32 @@
 // Internal
+pub use std::arc::Arc;
+pub use std::atomic::AtomicU64;
 pub use std::backtrace::Backtrace;
 pub use std::boxed::Box;
 pub use std::channel::*;
 pub use std::clone::Clone;
-pub use std::error::{Error, ErrorKind, ErrorKind::*};
+pub use std::deque::Deque;
+pub use std::error::{Error, ErrorKind, ErrorKind::*, StaticError};
 pub use std::format::Formatter;
-pub use std::lock::{Lock, LockBox};
+pub use std::lock::{Condvar, Lock, LockBox, Mutex, RwLock};
 pub use std::murmur32::*;
 pub use std::option::{Option, Option::None, Option::Some};
 pub use std::ptr::Ptr;
-pub use std::rc::Rc;
+pub use std::rc::{Rc, Weak};
 pub use std::result::{Result, Result::Err, Result::Ok};
-pub use std::string::String;
+pub use std::string::{String, StringBuilder};
+pub use std::test_support::{AllocGuard, FdGuard, TestCounts};
 pub use std::thread::*;
 pub use std::traits::*;
 pub use std::util::*;
 pub use std::vec::Vec;
+pub use util::hashmap::*;
+pub use util::hashset::*;
 pub use util::hashtable::*;
+pub use util::list::*;
+pub use util::mpmc::*;
+pub use util::orderedmap::*;
+pub use util::pool::*;
 pub use util::rbtree::*;
 pub use util::runtime::*;
 
@@ -26,5 +36,6 @@ pub use core::convert::{AsMut, AsRef};
 pub use core::convert::{From, Into, TryFrom, TryInto};
 pub use core::default::Default;
 pub use core::iter::Iterator;
-pub use core::ops::{Drop, FnMut};
+pub use core::marker::{Send, Sync};
+pub use core::ops::{Drop, Fn, FnMut};
 pub use core::str::from_utf8_unchecked;
@@ -1,17 +1,622 @@
+//! # Command line
+//! `fam <mode> [options]` runs one of:
+//! * `server`: an echo server which runs until it is killed.
+//! * `autobahn`: the echo server with WsConfig::strict set, for running a conformance suite
+//!   such as Autobahn's fuzzing client against.
+//! * `client`: a load generator which sends timestamped messages to a running server and
+//!   reports the round trip latency and throughput.
+//! * `bench`: an echo server and a load generator in the same process, on an ephemeral port.
+//! * `check`: runs WebSocket::self_check against a running server, exiting with 1 if any
+//!   step fails.
+
+use core::result::Result as CoreResult;
+use core::slice::from_raw_parts;
+use core::str::from_utf8;
+use ffi::{cstring_len, sleep_millis};
+use net::pool::{WsClientPool, WsPoolConfig};
+use net::selfcheck::echo_handler;
+use net::ws::*;
 use prelude::*;
 
-fn test1() {
-	let bt = Backtrace::new().unwrap();
-	match bt.print() {
+// values below 16 have their own bucket, above that each power of two is split into 8
+// buckets so a recorded value is within 12.5% of the true one
+const HISTOGRAM_BUCKETS: usize = 496;
+// how long to wait for outstanding replies once every message has been sent
+const DRAIN_MICROS: u64 = 10_000_000;
+// largest message the autobahn echo server accepts, above the 16 MiB the suite sends
+const AUTOBAHN_MAX_MESSAGE_BYTES: u64 = 32 * 1024 * 1024;
+
+const USAGE: &str = "usage: fam <server|autobahn|client|bench|check> [options]
+modes:
+  server             echo server, runs until killed
+  autobahn           echo server enforcing RFC 6455 strictly, runs until killed
+  client             load generator for a running server
+  bench              server and load generator in one process
+  check              protocol self check of a running server
+options:
+  --host A.B.C.D     address to listen on or connect to (default 127.0.0.1)
+  --port N           port (default 9090, bench uses an ephemeral port)
+  --threads N        worker threads for the server and for the clients (default 4)
+  --connections N    client connections (default 4)
+  --messages N       messages to send (default 10000)
+  --rate R           messages per second, 0 to send as fast as possible (default 0)
+  --size S           message size in bytes, at least 8 (default 64)";
+
+#[derive(PartialEq, Clone, Copy)]
+enum Mode {
+	Server,
+	Autobahn,
+	Client,
+	Bench,
+	Check,
+}
+
+#[derive(Clone, Copy)]
+struct Options {
+	mode: Mode,
+	host: [u8; 4],
+	port: u16,
+	threads: u64,
+	connections: u64,
+	messages: u64,
+	rate: u64,
+	size: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Histogram {
+	counts: [u64; HISTOGRAM_BUCKETS],
+	count: u64,
+	sum: u64,
+	min: u64,
+	max: u64,
+}
+
+struct Report {
+	sent: u64,
+	elapsed_micros: u64,
+	latency: Histogram,
+}
+
+fn bucket(v: u64) -> usize {
+	if v < 16 {
+		return v as usize;
+	}
+	let shift = 60 - v.leading_zeros() as usize;
+	shift * 8 + (v >> shift) as usize
+}
+
+// smallest value which falls in bucket i
+fn bucket_floor(i: usize) -> u64 {
+	if i < 16 {
+		return i as u64;
+	}
+	let shift = i / 8 - 1;
+	((i % 8 + 8) as u64) << shift
+}
+
+impl Histogram {
+	fn new() -> Self {
+		Self {
+			counts: [0; HISTOGRAM_BUCKETS],
+			count: 0,
+			sum: 0,
+			min: 0,
+			max: 0,
+		}
+	}
+
+	fn record(&mut self, v: u64) {
+		self.counts[bucket(v)] += 1;
+		if self.count == 0 || v < self.min {
+			self.min = v;
+		}
+		if v > self.max {
+			self.max = v;
+		}
+		self.count += 1;
+		self.sum += v;
+	}
+
+	fn mean(&self) -> u64 {
+		if self.count == 0 {
+			0
+		} else {
+			self.sum / self.count
+		}
+	}
+
+	/// Upper bound of the bucket holding the given per mille rank, capped at the maximum
+	fn percentile(&self, per_mille: u64) -> u64 {
+		let mut target = (self.count * per_mille + 999) / 1_000;
+		if target == 0 {
+			target = 1;
+		}
+		let mut seen = 0;
+		for i in 0..HISTOGRAM_BUCKETS {
+			seen += self.counts[i];
+			if seen >= target {
+				let upper = if i + 1 < HISTOGRAM_BUCKETS {
+					bucket_floor(i + 1) - 1
+				} else {
+					self.max
+				};
+				return if upper < self.max { upper } else { self.max };
+			}
+		}
+		self.max
+	}
+}
+
+fn parse_u64(value: &str) -> Result<u64, Error> {
+	match String::new(value) {
+		Ok(s) => s.parse_u64(),
+		Err(e) => Err(e),
+	}
+}
+
+fn parse_host(value: &str) -> Result<[u8; 4], Error> {
+	let s = match String::new(value) {
+		Ok(s) => s,
+		Err(e) => return Err(e),
+	};
+	let mut host = [0u8; 4];
+	let mut i = 0;
+	for part in s.split('.') {
+		if i == 4 {
+			return Err(err!(IllegalArgument));
+		}
+		match part.parse_u64() {
+			Ok(v) if v <= 255 => host[i] = v as u8,
+			Ok(_) => return Err(err!(IllegalArgument)),
+			Err(e) => return Err(e),
+		}
+		i += 1;
+	}
+	if i != 4 {
+		return Err(err!(IllegalArgument));
+	}
+	Ok(host)
+}
+
+/// Parses the arguments following the program name: the mode, then `--name value` pairs
+fn parse_options(args: &[&str]) -> Result<Options, Error> {
+	if args.len() == 0 {
+		return Err(err!(IllegalArgument));
+	}
+	let mode = match args[0] {
+		"server" => Mode::Server,
+		"autobahn" => Mode::Autobahn,
+		"client" => Mode::Client,
+		"bench" => Mode::Bench,
+		"check" => Mode::Check,
+		_ => return Err(err!(IllegalArgument)),
+	};
+	let mut opts = Options {
+		mode,
+		host: [127, 0, 0, 1],
+		port: 9090,
+		threads: 4,
+		connections: 4,
+		messages: 10_000,
+		rate: 0,
+		size: 64,
+	};
+	let mut i = 1;
+	while i < args.len() {
+		if i + 1 == args.len() {
+			return Err(err!(IllegalArgument));
+		}
+		let value = args[i + 1];
+		if args[i] == "--host" {
+			opts.host = match parse_host(value) {
+				Ok(host) => host,
+				Err(e) => return Err(e),
+			};
+		} else {
+			let v = match parse_u64(value) {
+				Ok(v) => v,
+				Err(e) => return Err(e),
+			};
+			match args[i] {
+				"--port" if v <= 65535 => opts.port = v as u16,
+				"--threads" if v > 0 => opts.threads = v,
+				"--connections" if v > 0 => opts.connections = v,
+				"--messages" if v > 0 => opts.messages = v,
+				"--rate" => opts.rate = v,
+				"--size" if v >= 8 => opts.size = v,
+				_ => return Err(err!(IllegalArgument)),
+			}
+		}
+		i += 2;
+	}
+	Ok(opts)
+}
+
+fn start_echo_server(opts: &Options, port: u16) -> Result<(WebSocket, u16), Error> {
+	let strict = opts.mode == Mode::Autobahn;
+	let mut ws = match WebSocket::new(WsConfig {
+		name: "echo",
+		threads: opts.threads,
+		strict,
+		max_message_bytes: if strict {
+			AUTOBAHN_MAX_MESSAGE_BYTES
+		} else {
+			0
+		},
+		..WsConfig::default()
+	}) {
+		Ok(ws) => ws,
+		Err(e) => return Err(e),
+	};
+	let b = match echo_handler() {
+		Ok(b) => b,
+		Err(e) => return Err(e),
+	};
+	match ws.register_handler(b) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	match ws.start() {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	match ws.add_server(WsServerConfig::new(opts.host, port, 128)) {
+		Ok(port) => Ok((ws, port)),
+		Err(e) => {
+			let _ = ws.stop();
+			Err(e)
+		}
+	}
+}
+
+/// Sends opts.messages messages to port, each carrying its send time in the first 8 bytes,
+/// and records the round trip time of every echoed reply
+fn run_load(opts: &Options, port: u16) -> Result<Report, Error> {
+	let mut pool = match WsClientPool::new(
+		WsConfig {
+			name: "load",
+			threads: opts.threads,
+			..WsConfig::default()
+		},
+		&[WsClientConfig::new(opts.host, port)],
+		WsPoolConfig {
+			connections: opts.connections as usize,
+			..WsPoolConfig::default()
+		},
+	) {
+		Ok(pool) => pool,
+		Err(e) => return Err(e),
+	};
+	let latency = match Arc::new(Mutex::new(Histogram::new())) {
+		Ok(latency) => latency,
+		Err(e) => return Err(e),
+	};
+	let latency_clone = match latency.clone() {
+		Ok(latency) => latency,
+		Err(e) => return Err(e),
+	};
+	let b: Box<dyn Fn(WsRequest, WsResponse) -> Result<(), Error> + Send + Sync> =
+		match Box::new(move |req: WsRequest, _resp: WsResponse| {
+			let msg = req.msg();
+			if req.op() == 0x2 && msg.len() >= 8 {
+				let now = getmicros!() as u64;
+				let sent = from_be_bytes_u64(&msg[0..8]);
+				latency.lock().record(now.saturating_sub(sent));
+			}
+			Ok(())
+		}) {
+			Ok(b) => b,
+			Err(e) => return Err(e),
+		};
+	match pool.register_handler(b) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	match pool.start() {
 		Ok(_) => {}
 		Err(e) => {
-			println!("Err={}", e);
+			let _ = pool.stop();
+			return Err(e);
+		}
+	}
+
+	let mut msg = Vec::new();
+	match msg.resize(opts.size as usize) {
+		Ok(_) => {}
+		Err(e) => {
+			let _ = pool.stop();
+			return Err(e);
+		}
+	}
+	let interval = if opts.rate == 0 {
+		0
+	} else {
+		1_000_000 / opts.rate
+	};
+	let mut key = [0u8; 8];
+	let start = getmicros!() as u64;
+	let mut sent = 0;
+	while sent < opts.messages {
+		if interval > 0 {
+			let due = start + sent * interval;
+			loop {
+				let now = getmicros!() as u64;
+				if now >= due {
+					break;
+				} else if due - now >= 1_000 {
+					unsafe {
+						sleep_millis(1);
+					}
+				} else {
+					sched_yield!();
+				}
+			}
+		}
+		to_be_bytes_u64(sent, &mut key);
+		to_be_bytes_u64(getmicros!() as u64, &mut msg.as_mut_slice()[0..8]);
+		match pool.send(&key, msg.as_slice()) {
+			Ok(_) => sent += 1,
+			Err(e) => {
+				println!("WARN: send failed after {} messages: {}", sent, e);
+				break;
+			}
+		}
+	}
+
+	let mut end = getmicros!() as u64;
+	let deadline = end + DRAIN_MICROS;
+	while end < deadline {
+		if latency_clone.lock().count >= sent {
+			break;
+		}
+		unsafe {
+			sleep_millis(1);
+		}
+		end = getmicros!() as u64;
+	}
+	let latency = *latency_clone.lock();
+	let _ = pool.stop();
+	Ok(Report {
+		sent,
+		elapsed_micros: end - start,
+		latency,
+	})
+}
+
+fn print_report(opts: &Options, report: &Report) {
+	let received = report.latency.count;
+	let secs = if report.elapsed_micros == 0 {
+		1.0 / 1_000_000.0
+	} else {
+		report.elapsed_micros as f64 / 1_000_000.0
+	};
+	println!(
+		"connections={} size={} rate={}",
+		opts.connections, opts.size, opts.rate
+	);
+	println!(
+		"sent={} received={} lost={} elapsed_micros={}",
+		report.sent,
+		received,
+		report.sent - received,
+		report.elapsed_micros
+	);
+	println!(
+		"throughput: {} msgs/s {} MB/s",
+		received as f64 / secs,
+		(received * opts.size) as f64 / secs / 1_000_000.0
+	);
+	let l = &report.latency;
+	println!(
+		"latency_micros: min={} mean={} p50={} p90={} p99={} p999={} max={}",
+		l.min,
+		l.mean(),
+		l.percentile(500),
+		l.percentile(900),
+		l.percentile(990),
+		l.percentile(999),
+		l.max
+	);
+}
+
+fn run(opts: &Options) -> Result<(), Error> {
+	match opts.mode {
+		Mode::Server | Mode::Autobahn => {
+			let (_ws, port) = match start_echo_server(opts, opts.port) {
+				Ok(server) => server,
+				Err(e) => return Err(e),
+			};
+			println!(
+				"echo server listening on {}.{}.{}.{}:{}",
+				opts.host[0], opts.host[1], opts.host[2], opts.host[3], port
+			);
+			loop {
+				unsafe {
+					sleep_millis(1_000);
+				}
+			}
+		}
+		Mode::Client => match run_load(opts, opts.port) {
+			Ok(report) => {
+				print_report(opts, &report);
+				Ok(())
+			}
+			Err(e) => Err(e),
+		},
+		Mode::Bench => {
+			let (mut ws, port) = match start_echo_server(opts, 0) {
+				Ok(server) => server,
+				Err(e) => return Err(e),
+			};
+			let res = run_load(opts, port);
+			let _ = ws.stop();
+			match res {
+				Ok(report) => {
+					print_report(opts, &report);
+					Ok(())
+				}
+				Err(e) => Err(e),
+			}
+		}
+		Mode::Check => {
+			let report = match WebSocket::self_check(opts.host, opts.port) {
+				Ok(report) => report,
+				Err(e) => return Err(e),
+			};
+			println!("{}", report);
+			if report.passed() {
+				Ok(())
+			} else {
+				Err(err!(IllegalState))
+			}
 		}
 	}
 }
 
 #[no_mangle]
-pub extern "C" fn real_main(_argc: i32, _argv: *const *const u8) -> i32 {
-	test1();
-	0
+pub extern "C" fn real_main(argc: i32, argv: *const *const u8) -> i32 {
+	let mut args = Vec::new();
+	for i in 1..argc as usize {
+		let arg = unsafe {
+			let p = *argv.add(i);
+			from_raw_parts(p, cstring_len(p))
+		};
+		match from_utf8(arg) {
+			CoreResult::Ok(arg) => {
+				if args.push(arg).is_err() {
+					return 1;
+				}
+			}
+			CoreResult::Err(_e) => {
+				println!("{}", USAGE);
+				return 2;
+			}
+		}
+	}
+	let opts = match parse_options(args.as_slice()) {
+		Ok(opts) => opts,
+		Err(_e) => {
+			println!("{}", USAGE);
+			return 2;
+		}
+	};
+	match run(&opts) {
+		Ok(_) => 0,
+		Err(e) => {
+			println!("Err={}", e);
+			1
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_parse_options() {
+		assert!(parse_options(&[]).is_err());
+		assert!(parse_options(&["other"]).is_err());
+		let opts = parse_options(&["bench"]).unwrap();
+		assert!(opts.mode == Mode::Bench);
+		assert_eq!(opts.port, 9090);
+		assert_eq!(opts.host, [127, 0, 0, 1]);
+		assert!(parse_options(&["check"]).unwrap().mode == Mode::Check);
+		assert!(parse_options(&["autobahn"]).unwrap().mode == Mode::Autobahn);
+
+		let opts = parse_options(&[
+			"client",
+			"--host",
+			"10.0.0.2",
+			"--port",
+			"8080",
+			"--connections",
+			"16",
+			"--rate",
+			"500",
+			"--size",
+			"1024",
+		])
+		.unwrap();
+		assert!(opts.mode == Mode::Client);
+		assert_eq!(opts.host, [10, 0, 0, 2]);
+		assert_eq!(opts.port, 8080);
+		assert_eq!(opts.connections, 16);
+		assert_eq!(opts.rate, 500);
+		assert_eq!(opts.size, 1024);
+
+		// missing values, unknown names and out of range values
+		assert!(parse_options(&["client", "--rate"]).is_err());
+		assert!(parse_options(&["client", "--speed", "1"]).is_err());
+		assert!(parse_options(&["client", "--port", "65536"]).is_err());
+		assert!(parse_options(&["client", "--size", "7"]).is_err());
+		assert!(parse_options(&["client", "--connections", "0"]).is_err());
+		assert!(parse_options(&["client", "--host", "1.2.3"]).is_err());
+		assert!(parse_options(&["client", "--host", "1.2.3.256"]).is_err());
+		assert!(parse_options(&["client", "--host", "1.2.3.4.5"]).is_err());
+	}
+
+	#[test]
+	fn test_histogram() {
+		// buckets are contiguous and each value is at or above its bucket's floor
+		for v in 0..100_000u64 {
+			let i = bucket(v);
+			assert!(bucket_floor(i) <= v);
+			assert!(bucket_floor(i + 1) > v);
+		}
+		assert!(bucket(u64::MAX) < HISTOGRAM_BUCKETS);
+
+		let mut h = Histogram::new();
+		assert_eq!(h.percentile(500), 0);
+		for v in 1..=1_000u64 {
+			h.record(v);
+		}
+		assert_eq!(h.count, 1_000);
+		assert_eq!(h.min, 1);
+		assert_eq!(h.max, 1_000);
+		assert_eq!(h.mean(), 500);
+		let p50 = h.percentile(500);
+		assert!(p50 >= 500 && p50 <= 500 + 500 / 8);
+		let p99 = h.percentile(990);
+		assert!(p99 >= 990 && p99 <= 1_000);
+		assert_eq!(h.percentile(1_000), 1_000);
+	}
+
+	#[test]
+	fn test_bench() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		let opts = Options {
+			threads: 2,
+			connections: 2,
+			messages: 200,
+			..parse_options(&["bench"]).unwrap()
+		};
+		let (mut ws, port) = start_echo_server(&opts, 0).unwrap();
+		let report = run_load(&opts, port).unwrap();
+		assert_eq!(report.sent, 200);
+		assert_eq!(report.latency.count, 200);
+		assert!(report.latency.max >= report.latency.min);
+		// the echo server also passes the self check
+		let check = Options {
+			mode: Mode::Check,
+			port,
+			..opts
+		};
+		assert!(run(&check).is_ok());
+		assert!(ws.stop().is_ok());
+
+		// paced sends take at least messages / rate seconds
+		let opts = Options {
+			messages: 20,
+			rate: 1_000,
+			..opts
+		};
+		let (mut ws, port) = start_echo_server(&opts, 0).unwrap();
+		let report = run_load(&opts, port).unwrap();
+		assert_eq!(report.latency.count, 20);
+		assert!(report.elapsed_micros >= 19_000);
+		assert!(ws.stop().is_ok());
+	}
 }
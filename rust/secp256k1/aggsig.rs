@@ -15,9 +15,10 @@
 
 use core::ptr;
 use ffi;
-use ffi::cpsrng_rand_bytes_ctx;
 use prelude::*;
 use secp256k1::types::*;
+use std::cpsrng::Cpsrng;
+use std::zeroize::SecureBox;
 
 const SCRATCH_SPACE_SIZE: usize = 1024 * 1024;
 
@@ -26,10 +27,13 @@ const SCRATCH_SPACE_SIZE: usize = 1024 * 1024;
 /// In:
 /// msg: the message to sign
 /// seckey: the secret key
-pub fn export_secnonce_single(secp: &Secp256k1, rand: *mut u8) -> Result<SecretKey, Error> {
+pub fn export_secnonce_single(secp: &Secp256k1, rand: &Cpsrng) -> Result<SecretKey, Error> {
 	let mut return_key = SecretKey::generate(rand);
-	let mut seed = [0u8; 32];
-	unsafe { cpsrng_rand_bytes_ctx(rand, &mut seed as *mut u8, 32) };
+	let mut seed = match SecureBox::new([0u8; 32]) {
+		Ok(seed) => seed,
+		Err(e) => return Err(e),
+	};
+	rand.fill(&mut *seed);
 	let retval = unsafe {
 		ffi::secp256k1_aggsig_export_secnonce_single(
 			secp.ctx,
@@ -48,13 +52,7 @@ macro_rules! is_zero_pubkey {
 	(reterr => $e:expr) => {
 		match $e {
 			Some(n) => {
-				let mut is_ok = false;
-				for i in 0..n.0.len() {
-					if n.0[i] != 0 {
-						is_ok = true;
-					}
-				}
-				if !is_ok {
+				if n.0.ct_eq(&[0u8; 64]) {
 					return Err(err!(InvalidPublicKey));
 				}
 				n.as_ptr()
@@ -65,13 +63,7 @@ macro_rules! is_zero_pubkey {
 	(retfalse => $e:expr) => {
 		match $e {
 			Some(n) => {
-				let mut is_ok = false;
-				for i in 0..n.0.len() {
-					if n.0[i] != 0 {
-						is_ok = true;
-					}
-				}
-				if !is_ok {
+				if n.0.ct_eq(&[0u8; 64]) {
 					return false;
 				}
 				n.as_ptr()
@@ -99,11 +91,14 @@ pub fn sign_single(
 	pubnonce: Option<&PublicKey>,
 	pubkey_for_e: Option<&PublicKey>,
 	final_nonce_sum: Option<&PublicKey>,
-	rand: *mut u8,
+	rand: &Cpsrng,
 ) -> Result<Signature, Error> {
 	let mut retsig = Signature::from(Signature::new());
-	let mut seed = [0u8; 32];
-	unsafe { cpsrng_rand_bytes_ctx(rand, &mut seed as *mut u8, 32) };
+	let mut seed = match SecureBox::new([0u8; 32]) {
+		Ok(seed) => seed,
+		Err(e) => return Err(e),
+	};
+	rand.fill(&mut *seed);
 
 	let secnonce = match secnonce {
 		Some(n) => n.0.as_ptr(),
@@ -166,18 +161,14 @@ pub fn verify_single(
 
 	let extra = is_zero_pubkey!(retfalse => extra_pubkey);
 
+	let pubkey = is_zero_pubkey!(retfalse => Some(pubkey));
+
 	let is_partial = match is_partial {
 		true => 1,
 		false => 0,
 	};
 
-	let mut is_ok = false;
-	for i in 0..sig.0.len() {
-		if sig.0[i] != 0 {
-			is_ok = true;
-		}
-	}
-	if !is_ok {
+	if sig.0.ct_eq(&[0u8; 64]) {
 		return false;
 	}
 
@@ -187,7 +178,7 @@ pub fn verify_single(
 			sig.as_ptr(),
 			msg.as_ptr(),
 			pubnonce,
-			pubkey.as_ptr(),
+			pubkey,
 			pe,
 			extra,
 			is_partial,
@@ -217,13 +208,7 @@ pub fn verify_batch(
 	}
 
 	for i in 0..pub_keys.len() {
-		let mut is_ok = false;
-		for j in 0..pub_keys[i].0.len() {
-			if pub_keys[i].0[j] != 0 {
-				is_ok = true;
-			}
-		}
-		if !is_ok {
+		if pub_keys[i].0.ct_eq(&[0u8; 64]) {
 			return false;
 		}
 	}
@@ -345,10 +330,13 @@ impl AggSigContext {
 	pub fn new(
 		secp: &Secp256k1,
 		pubkeys_vec: &Vec<PublicKey>,
-		rand: *mut u8,
+		rand: &Cpsrng,
 	) -> Result<AggSigContext, Error> {
-		let mut seed = [0u8; 32];
-		unsafe { cpsrng_rand_bytes_ctx(rand, &mut seed as *mut u8, 32) };
+		let mut seed = match SecureBox::new([0u8; 32]) {
+			Ok(seed) => seed,
+			Err(e) => return Err(e),
+		};
+		rand.fill(&mut *seed);
 		let mut pubkeys: Vec<*const PublicKey> = Vec::new();
 		for pubkey in pubkeys_vec {
 			match pubkeys.push(pubkey.as_ptr()) {
@@ -493,250 +481,168 @@ impl Drop for AggSigContext {
 	}
 }
 
-/*
-
 #[cfg(test)]
-mod tests {
-	use super::{
-		add_signatures_single, export_secnonce_single, sign_single, verify_single, verify_batch,
-		AggSigContext, Secp256k1,
-	};
-	use crate::aggsig::subtract_partial_signature;
-use crate::ffi;
-	use crate::key::{PublicKey, SecretKey};
-	use rand::{thread_rng, Rng};
-	use crate::ContextFlag;
-	use crate::{AggSigPartialSignature, Message, Signature};
+mod test {
+	use super::*;
+
+	fn random_msg(rand: &Cpsrng) -> Message {
+		let mut msg = [0u8; 32];
+		rand.fill(&mut msg);
+		Message(msg)
+	}
 
 	#[test]
 	fn test_aggsig_multisig() {
+		let _alloc = AllocGuard::new();
 		let numkeys = 5;
-		let secp = Secp256k1::with_caps(ContextFlag::Full);
-		let mut keypairs: Vec<(SecretKey, PublicKey)> = vec![];
+		let rand = Cpsrng::new().unwrap();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let mut sks: Vec<SecretKey> = Vec::new();
+		let mut pks: Vec<PublicKey> = Vec::new();
 		for _ in 0..numkeys {
-			keypairs.push(secp.generate_keypair(&mut thread_rng()).unwrap());
+			let (sk, pk) = secp.generate_keypair(&rand).unwrap();
+			sks.push(sk).unwrap();
+			pks.push(pk).unwrap();
 		}
-		let pks: Vec<PublicKey> = keypairs.clone().into_iter().map(|(_, p)| p).collect();
-		println!(
-			"Creating aggsig context with {} pubkeys: {:?}",
-			pks.len(),
-			pks
-		);
-		let aggsig = AggSigContext::new(&secp, &pks);
-		println!("Generating nonces for each index");
+		let aggsig = AggSigContext::new(&secp, &pks, &rand).unwrap();
 		for i in 0..numkeys {
-			let retval = aggsig.generate_nonce(i);
-			println!("{} returned {}", i, retval);
-			assert!(retval == true);
+			assert!(aggsig.generate_nonce(i));
 		}
+		// nonce already generated
+		assert!(!aggsig.generate_nonce(0));
 
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		let mut partial_sigs: Vec<AggSigPartialSignature> = vec![];
+		let msg = random_msg(&rand);
+		let mut partial_sigs: Vec<AggSigPartialSignature> = Vec::new();
 		for i in 0..numkeys {
-			println!(
-				"Partial sign message: {:?} at index {}, SK:{:?}",
-				msg, i, keypairs[i].0
-			);
-
-			let result = aggsig.partial_sign(msg, keypairs[i].0.clone(), i);
-			match result {
-				Ok(ps) => {
-					println!("Partial sig: {:?}", ps);
-					partial_sigs.push(ps);
-				}
-				Err(e) => panic!("Partial sig failed: {}", e),
-			}
+			let ps = aggsig.partial_sign(msg, SecretKey(sks[i].0), i).unwrap();
+			partial_sigs.push(ps).unwrap();
 		}
 
-		let result = aggsig.combine_signatures(&partial_sigs);
-
-		let combined_sig = match result {
-			Ok(cs) => {
-				println!("Combined sig: {:?}", cs);
-				cs
-			}
-			Err(e) => panic!("Combining partial sig failed: {}", e),
-		};
-
-		println!(
-			"Verifying Combined sig: {:?}, msg: {:?}, pks:{:?}",
-			combined_sig, msg, pks
-		);
-		let result = aggsig.verify(combined_sig, msg, &pks);
-		println!("Signature verification: {}", result);
+		let combined_sig = aggsig.combine_signatures(&partial_sigs).unwrap();
+		assert!(aggsig.verify(combined_sig, msg, &pks));
+		assert!(!aggsig.verify(combined_sig, random_msg(&rand), &pks));
+		assert!(aggsig.combine_signatures(&Vec::new()).is_err());
 	}
 
 	#[test]
 	fn test_aggsig_single() {
-		let secp = Secp256k1::with_caps(ContextFlag::Full);
-		let (sk, pk) = secp.generate_keypair(&mut thread_rng()).unwrap();
-
-		println!(
-			"Performing aggsig single context with seckey, pubkey: {:?},{:?}",
-			sk, pk
-		);
+		let _alloc = AllocGuard::new();
+		let rand = Cpsrng::new().unwrap();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let (sk, pk) = secp.generate_keypair(&rand).unwrap();
 
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		let sig = sign_single(&secp, &msg, &sk, None, None, None, None, None).unwrap();
-
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			sig, msg, pk
-		);
+		let msg = random_msg(&rand);
+		let sig = sign_single(&secp, &msg, &sk, None, None, None, None, None, &rand).unwrap();
 		let result = verify_single(&secp, &sig, &msg, None, &pk, None, None, false);
-		println!("Signature verification single (correct): {}", result);
 		assert!(result == true);
 
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			sig, msg, pk
-		);
+		// wrong message
+		let msg = random_msg(&rand);
 		let result = verify_single(&secp, &sig, &msg, None, &pk, None, None, false);
-		println!("Signature verification single (wrong message): {}", result);
 		assert!(result == false);
 
 		// test optional extra key
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		let (sk_extra, pk_extra) = secp.generate_keypair(&mut thread_rng()).unwrap();
-		let sig = sign_single(&secp, &msg, &sk, None, Some(&sk_extra), None, None, None).unwrap();
+		let msg = random_msg(&rand);
+		let (sk_extra, pk_extra) = secp.generate_keypair(&rand).unwrap();
+		let sig = sign_single(
+			&secp,
+			&msg,
+			&sk,
+			None,
+			Some(&sk_extra),
+			None,
+			None,
+			None,
+			&rand,
+		)
+		.unwrap();
 		let result = verify_single(&secp, &sig, &msg, None, &pk, None, Some(&pk_extra), false);
 		assert!(result == true);
 	}
 
 	#[test]
 	fn test_aggsig_batch() {
-		let secp = Secp256k1::with_caps(ContextFlag::Full);
+		let _alloc = AllocGuard::new();
+		let rand = Cpsrng::new().unwrap();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
 
-		let mut sigs: Vec<Signature> = vec![];
-		let mut msgs: Vec<Message> = vec![];
-		let mut pub_keys: Vec<PublicKey> = vec![];
+		let mut sigs: Vec<Signature> = Vec::new();
+		let mut msgs: Vec<Message> = Vec::new();
+		let mut pub_keys: Vec<PublicKey> = Vec::new();
 
 		for _ in 0..100 {
-			let (sk, pk) = secp.generate_keypair(&mut thread_rng()).unwrap();
-			let mut msg = [0u8; 32];
-			thread_rng().fill(&mut msg);
-
-			let msg = Message::from_slice(&msg).unwrap();
-			let sig = sign_single(&secp, &msg, &sk, None, None, None, Some(&pk), None).unwrap();
+			let (sk, pk) = secp.generate_keypair(&rand).unwrap();
+			let msg = random_msg(&rand);
+			let sig =
+				sign_single(&secp, &msg, &sk, None, None, None, Some(&pk), None, &rand).unwrap();
 
 			let result_single = verify_single(&secp, &sig, &msg, None, &pk, Some(&pk), None, false);
 			assert!(result_single == true);
 
-			pub_keys.push(pk);
-			msgs.push(msg);
-			sigs.push(sig);
+			pub_keys.push(pk).unwrap();
+			msgs.push(msg).unwrap();
+			sigs.push(sig).unwrap();
 		}
 
-		println!("Verifying aggsig batch of 100");
-		let result = verify_batch(&secp, &sigs, &msgs, &pub_keys);
-		assert!(result == true);
+		assert!(verify_batch(&secp, &sigs, &msgs, &pub_keys));
+
+		// mismatched lengths
+		msgs.push(random_msg(&rand)).unwrap();
+		assert!(!verify_batch(&secp, &sigs, &msgs, &pub_keys));
 	}
 
 	#[test]
 	fn test_aggsig_fuzz() {
-		let secp = Secp256k1::with_caps(ContextFlag::Full);
-		let (sk, pk) = secp.generate_keypair(&mut thread_rng()).unwrap();
-
-		println!(
-			"Performing aggsig single context with seckey, pubkey: {:?},{:?}",
-			sk, pk
-		);
+		let _alloc = AllocGuard::new();
+		let rand = Cpsrng::new().unwrap();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let (sk, pk) = secp.generate_keypair(&rand).unwrap();
 
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		let sig = sign_single(&secp, &msg, &sk, None, None, None, None, None).unwrap();
+		let msg = random_msg(&rand);
+		let sig = sign_single(&secp, &msg, &sk, None, None, None, None, None, &rand).unwrap();
 
 		// force sig[32..] as 0 to simulate Fuzz test
-		let corrupted = &mut [0u8; 64];
-		let mut i = 0;
-		for elem in corrupted[..32].iter_mut() {
-			*elem = sig.0[i];
-			i += 1;
+		let mut corrupted = [0u8; 64];
+		for i in 0..32 {
+			corrupted[i] = sig.0[i];
 		}
-		let corrupted_sig: Signature = Signature {
-			0: ffi::Signature(*corrupted),
-		};
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			corrupted_sig, msg, pk
-		);
+		let corrupted_sig = Signature::from_data(corrupted);
 		let result = verify_single(&secp, &corrupted_sig, &msg, None, &pk, None, None, false);
-		println!("Signature verification single (correct): {}", result);
 		assert!(result == false);
 
 		// force sig[0..32] as 0 to simulate Fuzz test
-		let corrupted = &mut [0u8; 64];
-		let mut i = 32;
-		for elem in corrupted[32..].iter_mut() {
-			*elem = sig.0[i];
-			i += 1;
+		let mut corrupted = [0u8; 64];
+		for i in 32..64 {
+			corrupted[i] = sig.0[i];
 		}
-		let corrupted_sig: Signature = Signature {
-			0: ffi::Signature(*corrupted),
-		};
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			corrupted_sig, msg, pk
-		);
+		let corrupted_sig = Signature::from_data(corrupted);
 		let result = verify_single(&secp, &corrupted_sig, &msg, None, &pk, None, None, false);
-		println!("Signature verification single (correct): {}", result);
 		assert!(result == false);
 
 		// force pk as 0 to simulate Fuzz test
 		let zero_pk = PublicKey::new();
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			sig, msg, zero_pk
-		);
 		let result = verify_single(&secp, &sig, &msg, None, &zero_pk, None, None, false);
-		println!("Signature verification single (correct): {}", result);
 		assert!(result == false);
 
-		let mut sigs: Vec<Signature> = vec![];
-		sigs.push(sig);
-		let mut msgs: Vec<Message> = vec![];
-		msgs.push(msg);
-		let mut pub_keys: Vec<PublicKey> = vec![];
-		pub_keys.push(zero_pk);
-		println!(
-			"Verifying aggsig batch: {:?}, msg: {:?}, pk:{:?}",
-			sig, msg, zero_pk
-		);
+		let mut sigs: Vec<Signature> = Vec::new();
+		sigs.push(sig).unwrap();
+		let mut msgs: Vec<Message> = Vec::new();
+		msgs.push(msg).unwrap();
+		let mut pub_keys: Vec<PublicKey> = Vec::new();
+		pub_keys.push(zero_pk).unwrap();
 		let result = verify_batch(&secp, &sigs, &msgs, &pub_keys);
-		println!("Signature verification batch: {}", result);
 		assert!(result == false);
 
-
 		// force pk[0..32] as 0 to simulate Fuzz test
-		let corrupted = &mut [0u8; 64];
-		let mut i = 32;
-		for elem in corrupted[32..].iter_mut() {
-			*elem = pk.0[i];
-			i += 1;
+		let mut corrupted = [0u8; 64];
+		for i in 32..64 {
+			corrupted[i] = pk.0[i];
 		}
-		let corrupted_pk: PublicKey = PublicKey {
-			0: ffi::PublicKey(*corrupted),
-		};
-		println!(
-			"Verifying aggsig single: {:?}, msg: {:?}, pk:{:?}",
-			sig, msg, corrupted_pk
-		);
+		let corrupted_pk = PublicKey(corrupted);
 		let result = verify_single(&secp, &sig, &msg, None, &corrupted_pk, None, None, false);
-		println!("Signature verification single (correct): {}", result);
 		assert!(result == false);
 
 		// more tests on other parameters
-		let zero_pk = PublicKey::new();
 		let result = verify_single(
 			&secp,
 			&sig,
@@ -749,10 +655,8 @@ use crate::ffi;
 		);
 		assert!(result == false);
 
-		let mut msg = [0u8; 32];
-		thread_rng().fill(&mut msg);
-		let msg = Message::from_slice(&msg).unwrap();
-		if sign_single(
+		let msg = random_msg(&rand);
+		assert!(sign_single(
 			&secp,
 			&msg,
 			&sk,
@@ -761,40 +665,39 @@ use crate::ffi;
 			Some(&zero_pk),
 			Some(&zero_pk),
 			Some(&zero_pk),
-		).is_ok()
-		{
-			panic!("sign_single should fail on zero public key, but not!");
-		}
+			&rand,
+		)
+		.is_err());
 	}
 
 	#[test]
 	fn test_aggsig_exchange() {
+		let _alloc = AllocGuard::new();
+		let rand = Cpsrng::new().unwrap();
 		for _ in 0..20 {
-			let secp = Secp256k1::with_caps(ContextFlag::Full);
+			let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
 			// Generate keys for sender, receiver
-			let (sk1, pk1) = secp.generate_keypair(&mut thread_rng()).unwrap();
-			let (sk2, pk2) = secp.generate_keypair(&mut thread_rng()).unwrap();
+			let (sk1, pk1) = secp.generate_keypair(&rand).unwrap();
+			let (sk2, pk2) = secp.generate_keypair(&rand).unwrap();
 
 			// Generate nonces for sender, receiver
-			let secnonce_1 = export_secnonce_single(&secp).unwrap();
-			let secnonce_2 = export_secnonce_single(&secp).unwrap();
+			let secnonce_1 = export_secnonce_single(&secp, &rand).unwrap();
+			let secnonce_2 = export_secnonce_single(&secp, &rand).unwrap();
 
 			// Calculate public nonces
 			let _ = PublicKey::from_secret_key(&secp, &secnonce_1).unwrap();
 			let pubnonce_2 = PublicKey::from_secret_key(&secp, &secnonce_2).unwrap();
 
 			// And get the total
-			let mut nonce_sum = pubnonce_2.clone();
-			let _ = nonce_sum.add_exp_assign(&secp, &secnonce_1);
+			let mut nonce_sum = pubnonce_2;
+			nonce_sum.add_exp_assign(&secp, &secnonce_1).unwrap();
 
 			// Random message
-			let mut msg = [0u8; 32];
-			thread_rng().fill(&mut msg);
-			let msg = Message::from_slice(&msg).unwrap();
+			let msg = random_msg(&rand);
 
 			// Add public keys (for storing in e)
-			let mut pk_sum = pk2.clone();
-			let _ = pk_sum.add_exp_assign(&secp, &sk1);
+			let mut pk_sum = pk2;
+			pk_sum.add_exp_assign(&secp, &sk1).unwrap();
 
 			// Receiver signs
 			let sig1 = sign_single(
@@ -806,7 +709,9 @@ use crate::ffi;
 				Some(&nonce_sum),
 				Some(&pk_sum),
 				Some(&nonce_sum),
-			).unwrap();
+				&rand,
+			)
+			.unwrap();
 
 			// Sender verifies receivers sig
 			let result = verify_single(
@@ -831,7 +736,9 @@ use crate::ffi;
 				Some(&nonce_sum),
 				Some(&pk_sum),
 				Some(&nonce_sum),
-			).unwrap();
+				&rand,
+			)
+			.unwrap();
 
 			// Receiver verifies sender's sig
 			let result = verify_single(
@@ -846,7 +753,9 @@ use crate::ffi;
 			);
 			assert!(result == true);
 
-			let sig_vec = vec![&sig1, &sig2];
+			let mut sig_vec = Vec::new();
+			sig_vec.push(&sig1).unwrap();
+			sig_vec.push(&sig2).unwrap();
 			// Receiver calculates final sig
 			let final_sig = add_signatures_single(&secp, sig_vec, &nonce_sum).unwrap();
 
@@ -864,13 +773,14 @@ use crate::ffi;
 			assert!(result == true);
 
 			// Subtract sig1 from final sig
-			let (res_sig, res_sig_opt) = subtract_partial_signature(&secp, &final_sig, &sig1).unwrap();
+			let (res_sig, res_sig_opt) =
+				subtract_partial_signature(&secp, &final_sig, &sig1).unwrap();
 			assert!(res_sig == sig2 || res_sig_opt == Some(sig2));
 
 			// Subtract sig2 from final sig for good measure
-			let (res_sig, res_sig_opt) = subtract_partial_signature(&secp, &final_sig, &sig2).unwrap();
+			let (res_sig, res_sig_opt) =
+				subtract_partial_signature(&secp, &final_sig, &sig2).unwrap();
 			assert!(res_sig == sig1 || res_sig_opt == Some(sig1));
 		}
 	}
 }
-*/
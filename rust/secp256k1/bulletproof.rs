@@ -0,0 +1,540 @@
+// Rust secp256k1 bindings for bulletproof rangeproofs
+// 2018 The Grin developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Bulletproof Rangeproofs
+
+use core::ptr;
+use ffi;
+use prelude::*;
+use secp256k1::pedersen::INTERNAL_COMMITMENT_SIZE;
+use secp256k1::types::*;
+
+/// The size (in bytes) of a bulletproof over a single 64 bit value
+pub const MAX_PROOF_SIZE: usize = 675;
+/// The size (in bytes) of the message which may be embedded in a proof
+pub const PROOF_MSG_SIZE: usize = 20;
+/// Number of bits proven by each rangeproof
+const NBITS: u64 = 64;
+/// Number of generators created (2 * NBITS for a single commitment)
+const MAX_GENERATORS: u64 = 256;
+/// Upper bound of the scratch space (memory is allocated lazily)
+const SCRATCH_SPACE_SIZE: usize = 256 * 1024 * 1024;
+
+/// A bulletproof rangeproof
+pub struct RangeProof {
+	pub proof: [u8; MAX_PROOF_SIZE],
+	pub plen: usize,
+}
+
+impl Clone for RangeProof {
+	fn clone(&self) -> Result<Self, Error> {
+		Ok(Self {
+			proof: self.proof,
+			plen: self.plen,
+		})
+	}
+}
+
+impl RangeProof {
+	/// Create a rangeproof from a slice
+	pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+		if data.len() > MAX_PROOF_SIZE {
+			return Err(err!(IllegalArgument));
+		}
+		let mut proof = [0u8; MAX_PROOF_SIZE];
+		proof[0..data.len()].clone_from_slice(data);
+		Ok(Self {
+			proof,
+			plen: data.len(),
+		})
+	}
+
+	/// The serialized bytes of this proof
+	pub fn bytes(&self) -> &[u8] {
+		&self.proof[0..self.plen]
+	}
+
+	pub fn len(&self) -> usize {
+		self.plen
+	}
+}
+
+/// Information recovered by rewinding a rangeproof
+pub struct ProofInfo {
+	pub value: u64,
+	pub blind: SecretKey,
+	pub message: [u8; PROOF_MSG_SIZE],
+}
+
+struct BulletproofsInner {
+	secp: Secp256k1,
+	gens: *mut BulletproofGenerators,
+	value_gen: Generator,
+}
+
+// the generators are only read once created, and the context is itself Send and Sync
+unsafe impl Send for BulletproofsInner {}
+unsafe impl Sync for BulletproofsInner {}
+
+impl Drop for BulletproofsInner {
+	fn drop(&mut self) {
+		unsafe {
+			ffi::secp256k1_bulletproof_generators_destroy(self.secp.ctx, self.gens);
+		}
+	}
+}
+
+/// A commit capable context with the bulletproof generators cached. Creating the
+/// generators is expensive so this should be created once and cloned (which is
+/// cheap and shares the context and generators).
+pub struct Bulletproofs {
+	inner: Arc<BulletproofsInner>,
+}
+
+impl Clone for Bulletproofs {
+	fn clone(&self) -> Result<Self, Error> {
+		// SAFETY: clone always succeeds on arc
+		Ok(Self {
+			inner: self.inner.clone().unwrap(),
+		})
+	}
+}
+
+struct Scratch {
+	ptr: *mut ScratchSpace,
+}
+
+impl Drop for Scratch {
+	fn drop(&mut self) {
+		unsafe {
+			ffi::secp256k1_scratch_space_destroy(self.ptr);
+		}
+	}
+}
+
+impl Scratch {
+	fn new(secp: &Secp256k1) -> Result<Self, Error> {
+		let ptr = unsafe { ffi::secp256k1_scratch_space_create(secp.ctx, SCRATCH_SPACE_SIZE) };
+		if ptr.is_null() {
+			Err(err!(Alloc))
+		} else {
+			Ok(Self { ptr })
+		}
+	}
+}
+
+impl Bulletproofs {
+	/// Create a new context and the bulletproof generators
+	pub fn new() -> Result<Self, Error> {
+		Self::with_generator(&GENERATOR_H)
+	}
+
+	/// As new, for the asset whose value generator is value_gen. Its commitments and
+	/// proofs are only valid with contexts for the same asset.
+	pub fn with_generator(value_gen: &Generator) -> Result<Self, Error> {
+		let secp = match Secp256k1::with_caps(ContextFlag::Commit) {
+			Ok(secp) => secp,
+			Err(e) => return Err(e),
+		};
+		let gens = unsafe {
+			ffi::secp256k1_bulletproof_generators_create(
+				secp.ctx,
+				ffi::secp256k1_generator_const_g.0.as_ptr(),
+				MAX_GENERATORS,
+			)
+		};
+		if gens.is_null() {
+			return Err(err!(Alloc));
+		}
+		let inner = match Arc::new(BulletproofsInner {
+			secp,
+			gens,
+			value_gen: *value_gen,
+		}) {
+			Ok(inner) => inner,
+			Err(e) => return Err(e),
+		};
+		Ok(Self { inner })
+	}
+
+	/// The underlying (commit capable) context
+	pub fn secp(&self) -> &Secp256k1 {
+		&self.inner.secp
+	}
+
+	/// Create a pedersen commitment to the value with the specified blinding factor
+	pub fn commit(&self, value: u64, blind: &SecretKey) -> Result<Commitment, Error> {
+		self.inner
+			.secp
+			.commit_with_generator(value, blind, &self.inner.value_gen)
+	}
+
+	/// Create a rangeproof proving that the commitment to value with blinding factor
+	/// blind is in the range [0, 2^64). The nonce is required to rewind the proof.
+	pub fn prove(
+		&self,
+		value: u64,
+		blind: &SecretKey,
+		nonce: &SecretKey,
+		extra_data: Option<&[u8]>,
+		message: Option<&[u8; PROOF_MSG_SIZE]>,
+	) -> Result<RangeProof, Error> {
+		let scratch = match Scratch::new(&self.inner.secp) {
+			Ok(scratch) => scratch,
+			Err(e) => return Err(e),
+		};
+		let (extra_data, extra_data_len) = Self::extra_ptr(extra_data);
+		let message = match message {
+			Some(message) => message.as_ptr(),
+			None => ptr::null(),
+		};
+		let mut proof = [0u8; MAX_PROOF_SIZE];
+		let mut plen = MAX_PROOF_SIZE as u64;
+		let blind_ptr = blind.0.as_ptr();
+		let ret = unsafe {
+			ffi::secp256k1_bulletproof_rangeproof_prove(
+				self.inner.secp.ctx,
+				scratch.ptr,
+				self.inner.gens,
+				proof.as_mut_ptr(),
+				&mut plen,
+				ptr::null_mut(),
+				ptr::null_mut(),
+				ptr::null_mut(),
+				&value,
+				ptr::null(),
+				&blind_ptr,
+				ptr::null(),
+				1,
+				self.inner.value_gen.0.as_ptr(),
+				NBITS,
+				nonce.0.as_ptr(),
+				ptr::null(),
+				extra_data,
+				extra_data_len,
+				message,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(SecpErr));
+		}
+		Ok(RangeProof {
+			proof,
+			plen: plen as usize,
+		})
+	}
+
+	/// Verify the rangeproof for the specified commitment
+	pub fn verify(
+		&self,
+		commit: &Commitment,
+		proof: &RangeProof,
+		extra_data: Option<&[u8]>,
+	) -> Result<(), Error> {
+		let commit_i = match self.inner.secp.commit_parse(commit) {
+			Ok(commit_i) => commit_i,
+			Err(e) => return Err(e),
+		};
+		let scratch = match Scratch::new(&self.inner.secp) {
+			Ok(scratch) => scratch,
+			Err(e) => return Err(e),
+		};
+		let (extra_data, extra_data_len) = Self::extra_ptr(extra_data);
+		let ret = unsafe {
+			ffi::secp256k1_bulletproof_rangeproof_verify(
+				self.inner.secp.ctx,
+				scratch.ptr,
+				self.inner.gens,
+				proof.proof.as_ptr(),
+				proof.plen as u64,
+				ptr::null(),
+				commit_i.as_ptr(),
+				1,
+				NBITS,
+				self.inner.value_gen.0.as_ptr(),
+				extra_data,
+				extra_data_len,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidSignature));
+		}
+		Ok(())
+	}
+
+	/// Batch verify a set of rangeproofs. commits[i] is the commitment for
+	/// proofs[i]. All proofs must be the same length. If specified, extra_data
+	/// must contain one entry per proof.
+	pub fn verify_multi(
+		&self,
+		commits: &Vec<Commitment>,
+		proofs: &Vec<RangeProof>,
+		extra_data: Option<&Vec<Vec<u8>>>,
+	) -> Result<(), Error> {
+		let n = proofs.len();
+		if n == 0 || commits.len() != n {
+			return Err(err!(IllegalArgument));
+		}
+		let plen = proofs[0].plen;
+		let mut commits_i = Vec::new();
+		let mut proof_ptrs = Vec::new();
+		let mut value_gens = Vec::new();
+		for i in 0..n {
+			if proofs[i].plen != plen {
+				return Err(err!(IllegalArgument));
+			}
+			match self.inner.secp.commit_parse(&commits[i]) {
+				Ok(commit_i) => match commits_i.push(commit_i) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => return Err(e),
+			}
+			match proof_ptrs.push(proofs[i].proof.as_ptr()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			match value_gens.push(self.inner.value_gen) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		let mut commit_ptrs = Vec::new();
+		for i in 0..n {
+			let commit_i: &[u8; INTERNAL_COMMITMENT_SIZE] = &commits_i[i];
+			match commit_ptrs.push(commit_i.as_ptr()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+
+		let mut extra_ptrs = Vec::new();
+		let mut extra_lens = Vec::new();
+		match extra_data {
+			Some(extra_data) => {
+				if extra_data.len() != n {
+					return Err(err!(IllegalArgument));
+				}
+				for i in 0..n {
+					let (p, l) = Self::extra_ptr(Some(extra_data[i].as_slice()));
+					match extra_ptrs.push(p) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+					match extra_lens.push(l) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+				}
+			}
+			None => {}
+		}
+		let (extra_ptr, extra_len_ptr) = if extra_data.is_some() {
+			(
+				extra_ptrs.as_ptr() as *const *const u8,
+				extra_lens.as_ptr() as *const u64,
+			)
+		} else {
+			(ptr::null(), ptr::null())
+		};
+
+		let scratch = match Scratch::new(&self.inner.secp) {
+			Ok(scratch) => scratch,
+			Err(e) => return Err(e),
+		};
+		let ret = unsafe {
+			ffi::secp256k1_bulletproof_rangeproof_verify_multi(
+				self.inner.secp.ctx,
+				scratch.ptr,
+				self.inner.gens,
+				proof_ptrs.as_ptr() as *const *const u8,
+				n as u64,
+				plen as u64,
+				ptr::null(),
+				commit_ptrs.as_ptr() as *const *const u8,
+				1,
+				NBITS,
+				value_gens.as_ptr(),
+				extra_ptr,
+				extra_len_ptr,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidSignature));
+		}
+		Ok(())
+	}
+
+	/// Rewind a rangeproof with the nonce it was created with, recovering the value,
+	/// blinding factor and message.
+	pub fn rewind(
+		&self,
+		commit: &Commitment,
+		proof: &RangeProof,
+		nonce: &SecretKey,
+		extra_data: Option<&[u8]>,
+	) -> Result<ProofInfo, Error> {
+		let commit_i = match self.inner.secp.commit_parse(commit) {
+			Ok(commit_i) => commit_i,
+			Err(e) => return Err(e),
+		};
+		let (extra_data, extra_data_len) = Self::extra_ptr(extra_data);
+		let mut info = ProofInfo {
+			value: 0,
+			blind: SecretKey([0u8; SECRET_KEY_SIZE]),
+			message: [0u8; PROOF_MSG_SIZE],
+		};
+		let ret = unsafe {
+			ffi::secp256k1_bulletproof_rangeproof_rewind(
+				self.inner.secp.ctx,
+				&mut info.value,
+				info.blind.0.as_mut_ptr(),
+				proof.proof.as_ptr(),
+				proof.plen as u64,
+				0,
+				commit_i.as_ptr(),
+				self.inner.value_gen.0.as_ptr(),
+				nonce.0.as_ptr(),
+				extra_data,
+				extra_data_len,
+				info.message.as_mut_ptr(),
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidSignature));
+		}
+		Ok(info)
+	}
+
+	fn extra_ptr(extra_data: Option<&[u8]>) -> (*const u8, u64) {
+		match extra_data {
+			Some(extra_data) => (extra_data.as_ptr(), extra_data.len() as u64),
+			None => (ptr::null(), 0),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cpsrng::Cpsrng;
+
+	#[test]
+	fn test_bulletproof_prove_verify() {
+		let _alloc = AllocGuard::new();
+		let bp = Bulletproofs::new().unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let blind = SecretKey::generate(&rand);
+		let nonce = SecretKey::generate(&rand);
+		let commit = bp.commit(1234, &blind).unwrap();
+		let proof = bp.prove(1234, &blind, &nonce, None, None).unwrap();
+		assert_eq!(proof.len(), MAX_PROOF_SIZE);
+		assert!(bp.verify(&commit, &proof, None).is_ok());
+
+		let other = bp.commit(1235, &blind).unwrap();
+		assert!(bp.verify(&other, &proof, None).is_err());
+
+		let extra = [7u8; 10];
+		let proof2 = bp.prove(1234, &blind, &nonce, Some(&extra), None).unwrap();
+		assert!(bp.verify(&commit, &proof2, Some(&extra)).is_ok());
+		assert!(bp.verify(&commit, &proof2, None).is_err());
+
+		let mut bad = proof.clone().unwrap();
+		bad.proof[100] ^= 1;
+		assert!(bp.verify(&commit, &bad, None).is_err());
+
+		let copy = RangeProof::from_slice(proof.bytes()).unwrap();
+		assert!(bp.verify(&commit, &copy, None).is_ok());
+		assert!(RangeProof::from_slice(&[0u8; MAX_PROOF_SIZE + 1]).is_err());
+	}
+
+	#[test]
+	fn test_bulletproof_rewind() {
+		let _alloc = AllocGuard::new();
+		let bp = Bulletproofs::new().unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let blind = SecretKey::generate(&rand);
+		let nonce = SecretKey::generate(&rand);
+		let wrong = SecretKey::generate(&rand);
+		let message = [9u8; PROOF_MSG_SIZE];
+		let commit = bp.commit(999, &blind).unwrap();
+		let proof = bp.prove(999, &blind, &nonce, None, Some(&message)).unwrap();
+
+		let info = bp.rewind(&commit, &proof, &nonce, None).unwrap();
+		assert_eq!(info.value, 999);
+		assert_eq!(info.blind.0, blind.0);
+		assert_eq!(info.message, message);
+		assert!(bp.rewind(&commit, &proof, &wrong, None).is_err());
+	}
+
+	#[test]
+	fn test_bulletproof_asset() {
+		let _alloc = AllocGuard::new();
+		let gold = Generator::from_seed(&[7u8; 32]).unwrap();
+		let bp = Bulletproofs::with_generator(&gold).unwrap();
+		let standard = Bulletproofs::new().unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let blind = SecretKey::generate(&rand);
+		let nonce = SecretKey::generate(&rand);
+		let commit = bp.commit(50, &blind).unwrap();
+		assert!(commit.0 != standard.commit(50, &blind).unwrap().0);
+		let asset_commit = bp.secp().commit_with_generator(50, &blind, &gold).unwrap();
+		assert_eq!(asset_commit.0, commit.0);
+
+		// a proof holds only for the generator it was made with
+		let proof = bp.prove(50, &blind, &nonce, None, None).unwrap();
+		assert!(bp.verify(&commit, &proof, None).is_ok());
+		assert!(standard.verify(&commit, &proof, None).is_err());
+		let mut commits = Vec::new();
+		commits.push(commit).unwrap();
+		let mut proofs = Vec::new();
+		proofs.push(proof.clone().unwrap()).unwrap();
+		assert!(bp.verify_multi(&commits, &proofs, None).is_ok());
+		assert!(standard.verify_multi(&commits, &proofs, None).is_err());
+		assert_eq!(bp.rewind(&commit, &proof, &nonce, None).unwrap().value, 50);
+	}
+
+	#[test]
+	fn test_bulletproof_verify_multi() {
+		let _alloc = AllocGuard::new();
+		let bp = Bulletproofs::new().unwrap();
+		let bp2 = bp.clone().unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let mut commits = Vec::new();
+		let mut proofs = Vec::new();
+		let mut extras = Vec::new();
+		for i in 0..4 {
+			let blind = SecretKey::generate(&rand);
+			let nonce = SecretKey::generate(&rand);
+			let mut extra = Vec::new();
+			extra.push(i as u8).unwrap();
+			commits.push(bp.commit(i * 100, &blind).unwrap()).unwrap();
+			proofs
+				.push(
+					bp2.prove(i * 100, &blind, &nonce, Some(extra.as_slice()), None)
+						.unwrap(),
+				)
+				.unwrap();
+			extras.push(extra).unwrap();
+		}
+		assert!(bp.verify_multi(&commits, &proofs, Some(&extras)).is_ok());
+		assert!(bp.verify_multi(&commits, &proofs, None).is_err());
+
+		let tmp = commits[0];
+		commits[0] = commits[1];
+		commits[1] = tmp;
+		assert!(bp.verify_multi(&commits, &proofs, Some(&extras)).is_err());
+
+		assert!(bp.verify_multi(&Vec::new(), &Vec::new(), None).is_err());
+	}
+}
@@ -0,0 +1,553 @@
+//! # BIP-32 hierarchical deterministic keys
+//! Extended keys pair a key with a chain code so that a whole tree of child keys can be
+//! derived from a single seed. Hardened children (index >= [`HARDENED_KEY_START`]) can
+//! only be derived from the extended secret key, normal children can also be derived
+//! from the extended public key. Extended keys serialize to the standard 78 byte
+//! xprv/xpub layout, and to the familiar xprv.../xpub... strings with base58check.
+
+use core::marker::Copy;
+use ffi;
+use prelude::*;
+use secp256k1::types::*;
+use std::encoding::{base58check_decode, base58check_encode, hex_format};
+use std::hash::{hash160, hmac_sha512, SHA512_SIZE};
+use std::zeroize::{zeroize, SecureBox, SecureVec};
+
+/// The size (in bytes) of a chain code
+pub const CHAIN_CODE_SIZE: usize = 32;
+/// The size (in bytes) of a serialized extended key
+pub const EXTENDED_KEY_SIZE: usize = 78;
+/// Child indices at or above this value derive hardened children
+pub const HARDENED_KEY_START: u32 = 0x8000_0000;
+/// Mainnet version bytes of a serialized extended secret key (xprv)
+pub const VERSION_XPRV: [u8; 4] = [0x04, 0x88, 0xAD, 0xE4];
+/// Mainnet version bytes of a serialized extended public key (xpub)
+pub const VERSION_XPUB: [u8; 4] = [0x04, 0x88, 0xB2, 0x1E];
+
+const MASTER_KEY_SALT: &[u8] = b"Bitcoin seed";
+
+/// Chain code used to derive child keys
+#[derive(Clone, PartialEq)]
+pub struct ChainCode(pub [u8; CHAIN_CODE_SIZE]);
+impl Copy for ChainCode {}
+
+impl Display for ChainCode {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(ChainCode([u8; CHAIN_CODE_SIZE]));
+
+/// First four bytes of the hash160 of a public key, used to identify the parent of a key
+#[derive(Clone, PartialEq)]
+pub struct Fingerprint(pub [u8; 4]);
+impl Copy for Fingerprint {}
+
+impl Display for Fingerprint {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(Fingerprint([u8; 4]));
+
+/// An extended secret key
+pub struct ExtendedSecretKey {
+	pub depth: u8,
+	pub parent_fingerprint: Fingerprint,
+	pub child_number: u32,
+	pub chain_code: ChainCode,
+	pub secret_key: SecretKey,
+}
+
+/// An extended public key
+#[derive(Clone)]
+pub struct ExtendedPublicKey {
+	pub depth: u8,
+	pub parent_fingerprint: Fingerprint,
+	pub child_number: u32,
+	pub chain_code: ChainCode,
+	pub public_key: PublicKey,
+}
+impl Copy for ExtendedPublicKey {}
+
+impl ExtendedSecretKey {
+	/// Creates the master key of a tree from a seed (BIP-32 recommends 16 to 64 bytes)
+	pub fn new_master(secp: &Secp256k1, seed: &[u8]) -> Result<ExtendedSecretKey, Error> {
+		if seed.len() < 16 || seed.len() > 64 {
+			return Err(err!(IllegalArgument));
+		}
+		let i = match hmac_sha512_parts(MASTER_KEY_SALT, &[seed]) {
+			Ok(i) => i,
+			Err(e) => return Err(e),
+		};
+		let (secret_key, chain_code) = split_hmac(&i);
+		if unsafe { ffi::secp256k1_ec_seckey_verify(secp.ctx, secret_key.0.as_ptr()) } != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(ExtendedSecretKey {
+			depth: 0,
+			parent_fingerprint: Fingerprint([0u8; 4]),
+			child_number: 0,
+			chain_code,
+			secret_key,
+		})
+	}
+
+	/// Derives the child key at the specified index. Indices at or above
+	/// [`HARDENED_KEY_START`] derive hardened children.
+	pub fn derive_child(&self, secp: &Secp256k1, index: u32) -> Result<ExtendedSecretKey, Error> {
+		if self.depth == u8::MAX {
+			return Err(err!(Overflow));
+		}
+		let public_key = match PublicKey::from_secret_key(secp, &self.secret_key) {
+			Ok(pk) => pk,
+			Err(e) => return Err(e),
+		};
+		let ser_pk = match public_key.serialize_compressed(secp) {
+			Ok(ser_pk) => ser_pk,
+			Err(e) => return Err(e),
+		};
+		let ser_index = index.to_be_bytes();
+		let i = if index >= HARDENED_KEY_START {
+			hmac_sha512_parts(
+				&self.chain_code.0,
+				&[&[0u8], &self.secret_key.0, &ser_index],
+			)
+		} else {
+			hmac_sha512_parts(&self.chain_code.0, &[&ser_pk, &ser_index])
+		};
+		let i = match i {
+			Ok(i) => i,
+			Err(e) => return Err(e),
+		};
+		let (tweak, chain_code) = split_hmac(&i);
+		let mut secret_key = SecretKey(self.secret_key.0);
+		// fails if the tweak is not below the curve order or the sum is zero
+		match secret_key.add_assign(secp, &tweak) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		Ok(ExtendedSecretKey {
+			depth: self.depth + 1,
+			parent_fingerprint: fingerprint(&ser_pk),
+			child_number: index,
+			chain_code,
+			secret_key,
+		})
+	}
+
+	/// Derives the descendant at the end of path, one child index per level
+	pub fn derive_path(&self, secp: &Secp256k1, path: &[u32]) -> Result<ExtendedSecretKey, Error> {
+		let mut ret = ExtendedSecretKey {
+			depth: self.depth,
+			parent_fingerprint: self.parent_fingerprint,
+			child_number: self.child_number,
+			chain_code: self.chain_code,
+			secret_key: SecretKey(self.secret_key.0),
+		};
+		for index in path {
+			ret = match ret.derive_child(secp, *index) {
+				Ok(child) => child,
+				Err(e) => return Err(e),
+			};
+		}
+		Ok(ret)
+	}
+
+	/// Returns the extended public key corresponding to this key
+	pub fn public_key(&self, secp: &Secp256k1) -> Result<ExtendedPublicKey, Error> {
+		match PublicKey::from_secret_key(secp, &self.secret_key) {
+			Ok(public_key) => Ok(ExtendedPublicKey {
+				depth: self.depth,
+				parent_fingerprint: self.parent_fingerprint,
+				child_number: self.child_number,
+				chain_code: self.chain_code,
+				public_key,
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Returns the fingerprint of this key, which is the parent fingerprint of its children
+	pub fn fingerprint(&self, secp: &Secp256k1) -> Result<Fingerprint, Error> {
+		match self.public_key(secp) {
+			Ok(xpub) => xpub.fingerprint(secp),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Serializes this key in the 78 byte xprv format
+	pub fn encode(&self) -> [u8; EXTENDED_KEY_SIZE] {
+		let mut key = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		copy_from_slice(&mut key[1..], &self.secret_key.0);
+		let ret = encode_parts(
+			&VERSION_XPRV,
+			self.depth,
+			&self.parent_fingerprint,
+			self.child_number,
+			&self.chain_code,
+			&key,
+		);
+		zeroize(&mut key);
+		ret
+	}
+
+	/// Parses a key in the 78 byte xprv format
+	pub fn decode(
+		secp: &Secp256k1,
+		data: &[u8; EXTENDED_KEY_SIZE],
+	) -> Result<ExtendedSecretKey, Error> {
+		if data[0..4] != VERSION_XPRV || data[45] != 0 {
+			return Err(err!(CorruptedData));
+		}
+		let mut secret_key = SecretKey([0u8; SECRET_KEY_SIZE]);
+		copy_from_slice(&mut secret_key.0, &data[46..78]);
+		if unsafe { ffi::secp256k1_ec_seckey_verify(secp.ctx, secret_key.0.as_ptr()) } != 1 {
+			return Err(err!(CorruptedData));
+		}
+		let (depth, parent_fingerprint, child_number, chain_code) = decode_parts(data);
+		Ok(ExtendedSecretKey {
+			depth,
+			parent_fingerprint,
+			child_number,
+			chain_code,
+			secret_key,
+		})
+	}
+
+	/// The xprv... string of this key
+	pub fn to_base58(&self) -> Result<String, Error> {
+		let mut data = self.encode();
+		let ret = base58check_encode(&data);
+		zeroize(&mut data);
+		ret
+	}
+
+	/// Parses an xprv... string
+	pub fn from_base58(secp: &Secp256k1, s: &str) -> Result<ExtendedSecretKey, Error> {
+		let mut decoded = match base58check_decode(s) {
+			Ok(decoded) => decoded,
+			Err(e) => return Err(e),
+		};
+		let mut data = [0u8; EXTENDED_KEY_SIZE];
+		let ret = if decoded.len() == EXTENDED_KEY_SIZE {
+			copy_from_slice(&mut data, decoded.as_slice());
+			Self::decode(secp, &data)
+		} else {
+			Err(err!(CorruptedData))
+		};
+		zeroize(&mut data);
+		zeroize(decoded.as_mut_slice());
+		ret
+	}
+}
+
+impl ExtendedPublicKey {
+	/// Derives the (non-hardened) child key at the specified index
+	pub fn derive_child(&self, secp: &Secp256k1, index: u32) -> Result<ExtendedPublicKey, Error> {
+		if index >= HARDENED_KEY_START {
+			return Err(err!(IllegalArgument));
+		}
+		if self.depth == u8::MAX {
+			return Err(err!(Overflow));
+		}
+		let ser_pk = match self.public_key.serialize_compressed(secp) {
+			Ok(ser_pk) => ser_pk,
+			Err(e) => return Err(e),
+		};
+		let i = match hmac_sha512_parts(&self.chain_code.0, &[&ser_pk, &index.to_be_bytes()]) {
+			Ok(i) => i,
+			Err(e) => return Err(e),
+		};
+		let (tweak, chain_code) = split_hmac(&i);
+		let mut public_key = self.public_key;
+		match public_key.add_exp_assign(secp, &tweak) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		Ok(ExtendedPublicKey {
+			depth: self.depth + 1,
+			parent_fingerprint: fingerprint(&ser_pk),
+			child_number: index,
+			chain_code,
+			public_key,
+		})
+	}
+
+	/// Derives the descendant at the end of path, one child index per level
+	pub fn derive_path(&self, secp: &Secp256k1, path: &[u32]) -> Result<ExtendedPublicKey, Error> {
+		let mut ret = *self;
+		for index in path {
+			ret = match ret.derive_child(secp, *index) {
+				Ok(child) => child,
+				Err(e) => return Err(e),
+			};
+		}
+		Ok(ret)
+	}
+
+	/// Returns the fingerprint of this key, which is the parent fingerprint of its children
+	pub fn fingerprint(&self, secp: &Secp256k1) -> Result<Fingerprint, Error> {
+		match self.public_key.serialize_compressed(secp) {
+			Ok(ser_pk) => Ok(fingerprint(&ser_pk)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Serializes this key in the 78 byte xpub format
+	pub fn encode(&self, secp: &Secp256k1) -> Result<[u8; EXTENDED_KEY_SIZE], Error> {
+		match self.public_key.serialize_compressed(secp) {
+			Ok(key) => Ok(encode_parts(
+				&VERSION_XPUB,
+				self.depth,
+				&self.parent_fingerprint,
+				self.child_number,
+				&self.chain_code,
+				&key,
+			)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Parses a key in the 78 byte xpub format
+	pub fn decode(
+		secp: &Secp256k1,
+		data: &[u8; EXTENDED_KEY_SIZE],
+	) -> Result<ExtendedPublicKey, Error> {
+		if data[0..4] != VERSION_XPUB || (data[45] != 2 && data[45] != 3) {
+			return Err(err!(CorruptedData));
+		}
+		let mut key = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		copy_from_slice(&mut key, &data[45..]);
+		let public_key = match PublicKey::from_compressed(secp, &key) {
+			Ok(public_key) => public_key,
+			Err(e) => return Err(e),
+		};
+		let (depth, parent_fingerprint, child_number, chain_code) = decode_parts(data);
+		Ok(ExtendedPublicKey {
+			depth,
+			parent_fingerprint,
+			child_number,
+			chain_code,
+			public_key,
+		})
+	}
+
+	/// The xpub... string of this key
+	pub fn to_base58(&self, secp: &Secp256k1) -> Result<String, Error> {
+		match self.encode(secp) {
+			Ok(data) => base58check_encode(&data),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Parses an xpub... string
+	pub fn from_base58(secp: &Secp256k1, s: &str) -> Result<ExtendedPublicKey, Error> {
+		let decoded = match base58check_decode(s) {
+			Ok(decoded) => decoded,
+			Err(e) => return Err(e),
+		};
+		if decoded.len() != EXTENDED_KEY_SIZE {
+			return Err(err!(CorruptedData));
+		}
+		let mut data = [0u8; EXTENDED_KEY_SIZE];
+		copy_from_slice(&mut data, decoded.as_slice());
+		Self::decode(secp, &data)
+	}
+}
+
+fn hmac_sha512_parts(key: &[u8], parts: &[&[u8]]) -> Result<SecureBox<[u8; SHA512_SIZE]>, Error> {
+	// child derivation data is at most 37 bytes, seeds at most 64
+	let mut data = match SecureVec::with_capacity(64) {
+		Ok(data) => data,
+		Err(e) => return Err(e),
+	};
+	for part in parts {
+		match data.extend_from_slice(part) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	let mut ret = match SecureBox::new([0u8; SHA512_SIZE]) {
+		Ok(ret) => ret,
+		Err(e) => return Err(e),
+	};
+	*ret = hmac_sha512(key, data.as_slice());
+	Ok(ret)
+}
+
+fn split_hmac(i: &[u8; SHA512_SIZE]) -> (SecretKey, ChainCode) {
+	let mut left = SecretKey([0u8; SECRET_KEY_SIZE]);
+	let mut right = ChainCode([0u8; CHAIN_CODE_SIZE]);
+	copy_from_slice(&mut left.0, &i[0..32]);
+	copy_from_slice(&mut right.0, &i[32..64]);
+	(left, right)
+}
+
+fn fingerprint(ser_pk: &[u8; COMPRESSED_PUBLIC_KEY_SIZE]) -> Fingerprint {
+	let hash = hash160(ser_pk);
+	Fingerprint([hash[0], hash[1], hash[2], hash[3]])
+}
+
+fn encode_parts(
+	version: &[u8; 4],
+	depth: u8,
+	parent_fingerprint: &Fingerprint,
+	child_number: u32,
+	chain_code: &ChainCode,
+	key: &[u8; COMPRESSED_PUBLIC_KEY_SIZE],
+) -> [u8; EXTENDED_KEY_SIZE] {
+	let mut ret = [0u8; EXTENDED_KEY_SIZE];
+	copy_from_slice(&mut ret[0..4], version);
+	ret[4] = depth;
+	copy_from_slice(&mut ret[5..9], &parent_fingerprint.0);
+	copy_from_slice(&mut ret[9..13], &child_number.to_be_bytes());
+	copy_from_slice(&mut ret[13..45], &chain_code.0);
+	copy_from_slice(&mut ret[45..78], key);
+	ret
+}
+
+fn decode_parts(data: &[u8; EXTENDED_KEY_SIZE]) -> (u8, Fingerprint, u32, ChainCode) {
+	let mut parent_fingerprint = Fingerprint([0u8; 4]);
+	let mut chain_code = ChainCode([0u8; CHAIN_CODE_SIZE]);
+	copy_from_slice(&mut parent_fingerprint.0, &data[5..9]);
+	copy_from_slice(&mut chain_code.0, &data[45 - CHAIN_CODE_SIZE..45]);
+	let child_number = u32::from_be_bytes([data[9], data[10], data[11], data[12]]);
+	(data[4], parent_fingerprint, child_number, chain_code)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cpsrng::Cpsrng;
+	use std::encoding::hex_decode_array;
+
+	#[test]
+	fn test_hd_vectors() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		// BIP-32 test vector 1: (path, xprv, xpub) with the base58check encoding removed
+		let vectors: [(&[u32], &str, &str); 3] = [
+			(
+				&[],
+				"0488ade4000000000000000000873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508\
+				 00e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+				"0488b21e000000000000000000873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508\
+				 0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2",
+			),
+			(
+				&[HARDENED_KEY_START],
+				"0488ade4013442193e8000000047fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141\
+				 00edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+				"0488b21e013442193e8000000047fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141\
+				 035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56",
+			),
+			(
+				&[HARDENED_KEY_START, 1],
+				"0488ade4025c1bd648000000012a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19\
+				 003c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368",
+				"0488b21e025c1bd648000000012a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19\
+				 03501e454bf00751f24b1b489aa925215d66af2234e3891c3b21a52bedb3cd711c",
+			),
+		];
+		let seed: [u8; 16] = hex_decode_array("000102030405060708090a0b0c0d0e0f").unwrap();
+		let master = ExtendedSecretKey::new_master(&secp, &seed).unwrap();
+		for (path, xprv, xpub) in vectors {
+			let xprv: [u8; EXTENDED_KEY_SIZE] = hex_decode_array(xprv).unwrap();
+			let xpub: [u8; EXTENDED_KEY_SIZE] = hex_decode_array(xpub).unwrap();
+			let key = master.derive_path(&secp, path).unwrap();
+			assert_eq!(key.encode(), xprv);
+			assert_eq!(key.public_key(&secp).unwrap().encode(&secp).unwrap(), xpub);
+
+			let decoded = ExtendedSecretKey::decode(&secp, &xprv).unwrap();
+			assert!(decoded.secret_key == key.secret_key);
+			assert_eq!(decoded.encode(), xprv);
+			let decoded = ExtendedPublicKey::decode(&secp, &xpub).unwrap();
+			assert_eq!(decoded.encode(&secp).unwrap(), xpub);
+		}
+
+		// the master key of vector 1 as the strings given in BIP-32
+		let xprv = "xprv9s21ZrQH143K3QTDL4LXw2F7HEK3wJUD2nW2nRk4stbPy6cq3jPPqjiChkVvvNKmPGJxWUtg6\
+		            LnF5kejMRNNU3TGtRBeJgk33yuGBxrMPHi";
+		let xpub = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupj\
+		            e8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+		assert_eq!(master.to_base58().unwrap().to_str(), xprv);
+		let public = master.public_key(&secp).unwrap();
+		assert_eq!(public.to_base58(&secp).unwrap().to_str(), xpub);
+		let decoded = ExtendedSecretKey::from_base58(&secp, xprv).unwrap();
+		assert!(decoded.secret_key == master.secret_key);
+		let decoded = ExtendedPublicKey::from_base58(&secp, xpub).unwrap();
+		assert_eq!(
+			decoded.encode(&secp).unwrap(),
+			public.encode(&secp).unwrap()
+		);
+		assert!(
+			ExtendedPublicKey::from_base58(&secp, xprv)
+				.unwrap_err()
+				.kind == CorruptedData
+		);
+		assert!(
+			ExtendedSecretKey::from_base58(&secp, "2g")
+				.unwrap_err()
+				.kind == CorruptedData
+		);
+		let s = format!(
+			"{} {}",
+			master.chain_code,
+			public.fingerprint(&secp).unwrap()
+		)
+		.unwrap();
+		assert_eq!(
+			s.to_str(),
+			"873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508 3442193e"
+		);
+
+		// the public derivation of m/0H/1 matches the private derivation
+		let parent = master.derive_child(&secp, HARDENED_KEY_START).unwrap();
+		let xpub = parent
+			.public_key(&secp)
+			.unwrap()
+			.derive_child(&secp, 1)
+			.unwrap();
+		assert_eq!(
+			xpub.encode(&secp).unwrap(),
+			hex_decode_array(vectors[2].2).unwrap()
+		);
+		assert!(xpub.parent_fingerprint == parent.fingerprint(&secp).unwrap());
+	}
+
+	#[test]
+	fn test_hd_derivation() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let mut seed = [0u8; 32];
+		rand.fill(&mut seed);
+		let master = ExtendedSecretKey::new_master(&secp, &seed).unwrap();
+		let xpub = master.public_key(&secp).unwrap();
+
+		let path = [0, 7, 1_000_000, HARDENED_KEY_START - 1];
+		let child = master.derive_path(&secp, &path).unwrap();
+		let child_pub = xpub.derive_path(&secp, &path).unwrap();
+		assert_eq!(child.depth, 4);
+		assert_eq!(child.child_number, HARDENED_KEY_START - 1);
+		assert_eq!(
+			child.public_key(&secp).unwrap().encode(&secp).unwrap(),
+			child_pub.encode(&secp).unwrap()
+		);
+
+		// hardened children can't be derived from a public key
+		assert!(xpub.derive_child(&secp, HARDENED_KEY_START).is_err());
+		let hardened = master.derive_child(&secp, HARDENED_KEY_START).unwrap();
+		assert!(hardened.secret_key != master.derive_child(&secp, 0).unwrap().secret_key);
+
+		// seeds outside of 16 to 64 bytes and mismatched versions are rejected
+		assert!(ExtendedSecretKey::new_master(&secp, &seed[0..15]).is_err());
+		assert!(ExtendedSecretKey::new_master(&secp, &[0u8; 65]).is_err());
+		assert!(ExtendedSecretKey::decode(&secp, &xpub.encode(&secp).unwrap()).is_err());
+		assert!(ExtendedPublicKey::decode(&secp, &master.encode()).is_err());
+
+		let verify_only = Secp256k1::with_caps(ContextFlag::VerifyOnly).unwrap();
+		assert!(master.derive_child(&verify_only, 0).is_err());
+	}
+}
@@ -14,4 +14,11 @@
 //
 
 pub mod aggsig;
+pub mod bulletproof;
+pub mod hd;
+pub mod pedersen;
+pub mod pool;
+pub mod rangeproof;
+pub mod scanner;
+pub mod schnorr;
 pub mod types;
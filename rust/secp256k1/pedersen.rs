@@ -0,0 +1,321 @@
+// Rust secp256k1 bindings for pedersen commitments
+// 2018 The Grin developers
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Pedersen commitments
+
+use ffi;
+use prelude::*;
+use secp256k1::types::*;
+use std::zeroize::SecureVec;
+
+/// Size (in bytes) of the internal (parsed) representation of a commitment
+pub(crate) const INTERNAL_COMMITMENT_SIZE: usize = 64;
+
+/// Adds and subtracts blinding factors into the one which balances a transaction, as
+/// BlindSum::new().add(&input).sub(&change).switch(value, &output).sum(). The blinds are
+/// held in memory which is wiped when the builder is dropped. An error along the way,
+/// such as running out of memory, is returned by sum.
+pub struct BlindSum {
+	positive: SecureVec,
+	negative: SecureVec,
+	switch_blinds: SecureVec,
+	switch_values: Vec<u64>,
+	err: Option<Error>,
+}
+
+impl Secp256k1 {
+	/// Creates a pedersen commitment from a value and a blinding factor
+	pub fn commit(&self, value: u64, blind: &SecretKey) -> Result<Commitment, Error> {
+		self.commit_with_generator(value, blind, &GENERATOR_H)
+	}
+
+	/// Creates a pedersen commitment to a value of the asset whose value generator is gen
+	pub fn commit_with_generator(
+		&self,
+		value: u64,
+		blind: &SecretKey,
+		gen: &Generator,
+	) -> Result<Commitment, Error> {
+		if self.caps != ContextFlag::Commit {
+			return Err(err!(IllegalState));
+		}
+		let mut commit_i = [0u8; INTERNAL_COMMITMENT_SIZE];
+		let ret = unsafe {
+			ffi::secp256k1_pedersen_commit(
+				self.ctx,
+				commit_i.as_mut_ptr(),
+				blind.0.as_ptr(),
+				value,
+				gen.0.as_ptr(),
+				GENERATOR_G.0.as_ptr(),
+			)
+		};
+		if ret != 1 {
+			return Err(err!(SecpErr));
+		}
+		self.commit_ser(&commit_i)
+	}
+
+	/// Parses a serialized commitment into its internal representation
+	pub(crate) fn commit_parse(
+		&self,
+		commit: &Commitment,
+	) -> Result<[u8; INTERNAL_COMMITMENT_SIZE], Error> {
+		let mut commit_i = [0u8; INTERNAL_COMMITMENT_SIZE];
+		let ret = unsafe {
+			ffi::secp256k1_pedersen_commitment_parse(
+				self.ctx,
+				commit_i.as_mut_ptr(),
+				commit.as_ptr(),
+			)
+		};
+		if ret != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(commit_i)
+	}
+
+	/// Serializes the internal representation of a commitment
+	pub(crate) fn commit_ser(
+		&self,
+		commit_i: &[u8; INTERNAL_COMMITMENT_SIZE],
+	) -> Result<Commitment, Error> {
+		let mut commit = Commitment([0u8; PEDERSEN_COMMITMENT_SIZE]);
+		let ret = unsafe {
+			ffi::secp256k1_pedersen_commitment_serialize(
+				self.ctx,
+				commit.0.as_mut_ptr(),
+				commit_i.as_ptr(),
+			)
+		};
+		if ret != 1 {
+			return Err(err!(SecpErr));
+		}
+		Ok(commit)
+	}
+}
+
+impl BlindSum {
+	pub fn new() -> Self {
+		Self {
+			positive: SecureVec::new(),
+			negative: SecureVec::new(),
+			switch_blinds: SecureVec::new(),
+			switch_values: Vec::new(),
+			err: None,
+		}
+	}
+
+	/// Adds blind to the sum
+	pub fn add(mut self, blind: &SecretKey) -> Self {
+		let r = self.positive.extend_from_slice(&blind.0);
+		self.record(r)
+	}
+
+	/// Subtracts blind from the sum
+	pub fn sub(mut self, blind: &SecretKey) -> Self {
+		let r = self.negative.extend_from_slice(&blind.0);
+		self.record(r)
+	}
+
+	/// Adds the switch commitment blind of an output of value blinded by blind,
+	/// x + SHA256(xG+vH | xJ), so that the output may later be proven with a commitment
+	/// which is binding even if the discrete log problem is broken
+	pub fn switch(mut self, value: u64, blind: &SecretKey) -> Self {
+		let r = self.switch_blinds.extend_from_slice(&blind.0);
+		let r = match r {
+			Ok(_) => self.switch_values.push(value),
+			Err(e) => Err(e),
+		};
+		self.record(r)
+	}
+
+	/// The blinds added less those subtracted, computed with the global context.
+	/// IllegalArgument if there are none, or if one is not a valid secret key.
+	pub fn sum(self) -> Result<SecretKey, Error> {
+		match self.err {
+			Some(e) => return Err(e),
+			None => {}
+		}
+		let secp = match Secp256k1::global() {
+			Ok(secp) => secp,
+			Err(e) => return Err(e),
+		};
+		let switched = match self.switched(secp) {
+			Ok(switched) => switched,
+			Err(e) => return Err(e),
+		};
+		let mut blinds: Vec<*const u8> = Vec::new();
+		let npositive;
+		match push_blinds(&mut blinds, &self.positive) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match push_blinds(&mut blinds, &switched) {
+			Ok(_) => npositive = blinds.len(),
+			Err(e) => return Err(e),
+		}
+		match push_blinds(&mut blinds, &self.negative) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if blinds.len() == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut ret = SecretKey([0u8; SECRET_KEY_SIZE]);
+		let r = unsafe {
+			ffi::secp256k1_pedersen_blind_sum(
+				secp.ctx,
+				ret.0.as_mut_ptr(),
+				blinds.as_slice().as_ptr(),
+				blinds.len() as u64,
+				npositive as u64,
+			)
+		};
+		if r != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(ret)
+	}
+
+	// the switch commitment blinds of the outputs passed to switch
+	fn switched(&self, secp: &Secp256k1) -> Result<SecureVec, Error> {
+		let mut ret = match SecureVec::with_capacity(self.switch_blinds.len()) {
+			Ok(ret) => ret,
+			Err(e) => return Err(e),
+		};
+		if self.switch_values.len() == 0 {
+			return Ok(ret);
+		}
+		let blinds = self.switch_blinds.as_slice();
+		for i in 0..self.switch_values.len() {
+			let mut out = SecretKey([0u8; SECRET_KEY_SIZE]);
+			let r = unsafe {
+				ffi::secp256k1_blind_switch(
+					secp.ctx,
+					out.0.as_mut_ptr(),
+					blinds[i * SECRET_KEY_SIZE..].as_ptr(),
+					self.switch_values[i],
+					ffi::secp256k1_generator_const_h.0.as_ptr(),
+					ffi::secp256k1_generator_const_g.0.as_ptr(),
+					GENERATOR_J.0.as_ptr(),
+				)
+			};
+			if r != 1 {
+				return Err(err!(IllegalArgument));
+			}
+			match ret.extend_from_slice(&out.0) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(ret)
+	}
+
+	// keeps the first error for sum to return
+	fn record(mut self, r: Result<(), Error>) -> Self {
+		match r {
+			Ok(_) => {}
+			Err(e) => {
+				if self.err.is_none() {
+					self.err = Some(e);
+				}
+			}
+		}
+		self
+	}
+}
+
+// a pointer to each blind in v
+fn push_blinds(blinds: &mut Vec<*const u8>, v: &SecureVec) -> Result<(), Error> {
+	let data = v.as_slice();
+	let mut i = 0;
+	while i < data.len() {
+		match blinds.push(data[i..].as_ptr()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		i += SECRET_KEY_SIZE;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cpsrng::Cpsrng;
+
+	#[test]
+	fn test_commit() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Commit).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let blind = SecretKey::generate(&rand);
+		let c1 = secp.commit(5, &blind).unwrap();
+		let c2 = secp.commit(5, &blind).unwrap();
+		let c3 = secp.commit(6, &blind).unwrap();
+		assert_eq!(c1.0, c2.0);
+		assert!(c1.0 != c3.0);
+		let parsed = secp.commit_parse(&c1).unwrap();
+		assert_eq!(secp.commit_ser(&parsed).unwrap().0, c1.0);
+		assert!(secp
+			.commit_parse(&Commitment([0u8; PEDERSEN_COMMITMENT_SIZE]))
+			.is_err());
+
+		let secp = Secp256k1::with_caps(ContextFlag::VerifyOnly).unwrap();
+		assert!(secp.commit(5, &blind).is_err());
+	}
+
+	#[test]
+	fn test_blind_sum() {
+		// the global context leaks on purpose, so it is created before the guard
+		Secp256k1::global().unwrap();
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Commit).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let a = SecretKey::generate(&rand);
+		let b = SecretKey::generate(&rand);
+		let sum = BlindSum::new().add(&a).add(&b).sub(&b).sum().unwrap();
+		assert!(sum == a);
+		assert!(BlindSum::new().sum().is_err());
+
+		// an input of 5 spent to outputs of 3 and 2, the second blinded to balance
+		let change = BlindSum::new().add(&a).sub(&b).sum().unwrap();
+		let input = secp.commit_parse(&secp.commit(5, &a).unwrap()).unwrap();
+		let output1 = secp.commit_parse(&secp.commit(3, &b).unwrap()).unwrap();
+		let output2 = secp
+			.commit_parse(&secp.commit(2, &change).unwrap())
+			.unwrap();
+		let positive = [input.as_ptr()];
+		let negative = [output1.as_ptr(), output2.as_ptr()];
+		let tally = |n: u64| unsafe {
+			ffi::secp256k1_pedersen_verify_tally(
+				secp.ctx,
+				positive.as_ptr(),
+				1,
+				negative.as_ptr(),
+				n,
+			)
+		};
+		assert_eq!(tally(2), 1);
+		assert_eq!(tally(1), 0);
+
+		// switch blinds depend on the value and are the same each time
+		let s1 = BlindSum::new().switch(5, &a).sum().unwrap();
+		assert!(s1 != a);
+		assert!(BlindSum::new().switch(5, &a).sum().unwrap() == s1);
+		assert!(BlindSum::new().switch(6, &a).sum().unwrap() != s1);
+		let both = BlindSum::new().add(&b).switch(5, &a).sub(&b).sum().unwrap();
+		assert!(both == s1);
+	}
+}
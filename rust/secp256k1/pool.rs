@@ -0,0 +1,233 @@
+//! # Context pool
+//! Creating a secp256k1 context builds its precomputed tables, which costs far more than
+//! most of the operations done with it. Secp256k1::global is one context for the whole
+//! process, created and randomized on first use and never destroyed, which the library
+//! only reads from, so it is shared freely for verification. Signing wants its own
+//! context so that each may be randomized again without a lock, so sign_context checks one
+//! out of a pool for as long as it is held, to be reused by the next signer.
+
+use core::mem::replace;
+use core::ops::{Deref, FnOnce};
+use core::ptr::drop_in_place;
+use prelude::*;
+use secp256k1::types::{ContextFlag, Secp256k1};
+use std::cpsrng::Cpsrng;
+
+/// Contexts the global pool keeps once they are put back, about one per signing thread
+pub const GLOBAL_POOL_MAX_FREE: usize = 16;
+
+static mut GLOBAL_CONTEXT: u64 = 0;
+static mut GLOBAL_POOL: u64 = 0;
+
+pub struct SecpPool {
+	caps: ContextFlag,
+	max_free: usize,
+	free: Mutex<Vec<Secp256k1>>,
+}
+
+/// A context checked out of a pool, which goes back to it when dropped
+pub struct PooledSecp<'a> {
+	pool: &'a SecpPool,
+	secp: Option<Secp256k1>,
+}
+
+impl Deref for PooledSecp<'_> {
+	type Target = Secp256k1;
+
+	fn deref(&self) -> &Self::Target {
+		match &self.secp {
+			Some(secp) => secp,
+			None => exit!("PooledSecp used after it was put back"),
+		}
+	}
+}
+
+impl Drop for PooledSecp<'_> {
+	fn drop(&mut self) {
+		match replace(&mut self.secp, None) {
+			Some(secp) => self.pool.put(secp),
+			None => {}
+		}
+	}
+}
+
+impl SecpPool {
+	/// A pool of contexts with caps, keeping at most max_free of those put back
+	pub fn new(caps: ContextFlag, max_free: usize) -> Self {
+		Self {
+			caps,
+			max_free,
+			free: Mutex::new(Vec::new()),
+		}
+	}
+
+	/// Checks out a free context, or creates and randomizes a new one if there is none
+	pub fn get(&self) -> Result<PooledSecp<'_>, Error> {
+		let secp = {
+			let mut free = self.free.lock();
+			free.pop()
+		};
+		let secp = match secp {
+			Some(secp) => secp,
+			None => match randomized(self.caps) {
+				Ok(secp) => secp,
+				Err(e) => return Err(e),
+			},
+		};
+		Ok(PooledSecp {
+			pool: self,
+			secp: Some(secp),
+		})
+	}
+
+	/// Contexts waiting to be checked out
+	pub fn free_len(&self) -> usize {
+		self.free.lock().len()
+	}
+
+	fn put(&self, secp: Secp256k1) {
+		let mut free = self.free.lock();
+		if free.len() < self.max_free {
+			// on failure secp is dropped, as it would be over max_free
+			let _ = free.push(secp);
+		}
+	}
+}
+
+impl Secp256k1 {
+	/// The process wide context, able to do everything but commitments. The first call
+	/// creates it, which is slow. It is only read from, so it may be used by any number of
+	/// threads at once.
+	#[allow(static_mut_refs)]
+	pub fn global() -> Result<&'static Secp256k1, Error> {
+		match global_ptr(unsafe { &mut GLOBAL_CONTEXT }, || {
+			randomized(ContextFlag::Full)
+		}) {
+			Ok(ptr) => Ok(unsafe { &*ptr }),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Checks out a randomized signing context from the process wide pool
+#[allow(static_mut_refs)]
+pub fn sign_context() -> Result<PooledSecp<'static>, Error> {
+	let pool = match global_ptr(unsafe { &mut GLOBAL_POOL }, || {
+		Ok(SecpPool::new(ContextFlag::SignOnly, GLOBAL_POOL_MAX_FREE))
+	}) {
+		Ok(ptr) => unsafe { &*ptr },
+		Err(e) => return Err(e),
+	};
+	pool.get()
+}
+
+fn randomized(caps: ContextFlag) -> Result<Secp256k1, Error> {
+	let mut secp = match Secp256k1::with_caps(caps) {
+		Ok(secp) => secp,
+		Err(e) => return Err(e),
+	};
+	// only contexts which sign have tables to blind
+	if caps != ContextFlag::None && caps != ContextFlag::VerifyOnly {
+		let rand = match Cpsrng::new() {
+			Ok(rand) => rand,
+			Err(e) => return Err(e),
+		};
+		match secp.randomize(&rand) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(secp)
+}
+
+// the value behind slot, which the first caller to get there creates and leaks. A thread
+// which loses the race to publish its value drops it and uses the winner's.
+fn global_ptr<T, F>(slot: &'static mut u64, create: F) -> Result<*const T, Error>
+where
+	F: FnOnce() -> Result<T, Error>,
+{
+	let cur = aload!(slot);
+	if cur != 0 {
+		return Ok(cur as *const T);
+	}
+	let value = match create() {
+		Ok(value) => value,
+		Err(e) => return Err(e),
+	};
+	let ptr = match Ptr::alloc(value) {
+		Ok(ptr) => ptr,
+		Err(e) => return Err(e),
+	};
+	if cas!(slot, &cur, ptr.raw() as u64) {
+		return Ok(ptr.raw());
+	}
+	unsafe {
+		drop_in_place(ptr.raw());
+	}
+	ptr.release();
+	Ok(aload!(slot) as *const T)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+	use secp256k1::types::PublicKey;
+
+	#[test]
+	fn test_global_context() {
+		// the global context leaks on purpose, so it is created before the guard
+		let global = Secp256k1::global().unwrap();
+		let _alloc = AllocGuard::new();
+		assert!(global.caps() == ContextFlag::Full);
+		assert_eq!(
+			Secp256k1::global().unwrap() as *const Secp256k1,
+			global as *const Secp256k1
+		);
+
+		let rand = Cpsrng::new().unwrap();
+		let (sk, pk) = global.generate_keypair(&rand).unwrap();
+		assert_eq!(PublicKey::from_secret_key(global, &sk).unwrap().0, pk.0);
+	}
+
+	#[test]
+	fn test_secp_pool() {
+		let _alloc = AllocGuard::new();
+		let pool = SecpPool::new(ContextFlag::SignOnly, 1);
+		assert_eq!(pool.free_len(), 0);
+		let first = pool.get().unwrap();
+		let second = pool.get().unwrap();
+		assert!(first.caps() == ContextFlag::SignOnly);
+		let ctx = first.ctx;
+		assert!(ctx != second.ctx);
+		drop(first);
+		assert_eq!(pool.free_len(), 1);
+		// over max_free, so destroyed rather than kept
+		drop(second);
+		assert_eq!(pool.free_len(), 1);
+
+		// the context put back is the next checked out
+		let again = pool.get().unwrap();
+		assert_eq!(again.ctx, ctx);
+		assert_eq!(pool.free_len(), 0);
+		drop(again);
+
+		// a verify only context has nothing to randomize
+		let verify = SecpPool::new(ContextFlag::VerifyOnly, 1);
+		assert!(verify.get().unwrap().caps() == ContextFlag::VerifyOnly);
+		let mut none = Secp256k1::with_caps(ContextFlag::None).unwrap();
+		assert!(none.randomize(&Cpsrng::new().unwrap()).is_err());
+	}
+
+	#[test]
+	fn test_sign_context() {
+		// as with the global context, the pool is created before the guard
+		drop(sign_context().unwrap());
+		let _alloc = AllocGuard::new();
+		let rand = Cpsrng::new().unwrap();
+		let secp = sign_context().unwrap();
+		assert!(secp.caps() == ContextFlag::SignOnly);
+		let (sk, pk) = secp.generate_keypair(&rand).unwrap();
+		assert_eq!(PublicKey::from_secret_key(&secp, &sk).unwrap().0, pk.0);
+	}
+}
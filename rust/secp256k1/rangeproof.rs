@@ -0,0 +1,237 @@
+//! # Legacy rangeproofs
+//! The Borromean ring rangeproofs which came before bulletproofs. They are larger and
+//! slower to verify, but outputs created with them are still found in old transactions,
+//! so a wallet may need to read what they prove and rewind them with the nonce they were
+//! created with to recover an output it owns.
+
+use core::ptr;
+use ffi;
+use prelude::*;
+use secp256k1::types::*;
+
+/// The size (in bytes) of the largest legacy rangeproof, over a full 64 bit value
+pub const MAX_LEGACY_PROOF_SIZE: usize = 5134;
+/// The size (in bytes) of the largest message which may be embedded in a legacy proof
+pub const MAX_LEGACY_MSG_SIZE: usize = 4096;
+
+/// The range a legacy rangeproof covers, which anyone may read from it
+#[derive(Clone, Copy, PartialEq)]
+pub struct LegacyRangeInfo {
+	/// The value is a multiple of 10^exp, or -1 if the value is not private
+	pub exp: i32,
+	/// Number of bits proven
+	pub mantissa: i32,
+	pub min_value: u64,
+	pub max_value: u64,
+}
+
+/// Information recovered by rewinding a legacy rangeproof
+pub struct LegacyProofInfo {
+	pub range: LegacyRangeInfo,
+	pub value: u64,
+	pub blind: SecretKey,
+	/// Everything the proof can carry, so a short message is followed by zeros
+	pub message: Vec<u8>,
+}
+
+impl Secp256k1 {
+	/// Reads the range proven by a legacy rangeproof without verifying it. IllegalArgument
+	/// if proof cannot be decoded.
+	pub fn legacy_range_info(&self, proof: &[u8]) -> Result<LegacyRangeInfo, Error> {
+		let mut info = LegacyRangeInfo {
+			exp: 0,
+			mantissa: 0,
+			min_value: 0,
+			max_value: 0,
+		};
+		let ret = unsafe {
+			ffi::secp256k1_rangeproof_info(
+				self.ctx,
+				&mut info.exp,
+				&mut info.mantissa,
+				&mut info.min_value,
+				&mut info.max_value,
+				proof.as_ptr(),
+				proof.len() as u64,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(info)
+	}
+
+	/// Verifies a legacy rangeproof for commit and rewinds it with the nonce it was
+	/// created with, recovering the value, blinding factor and message. The context must
+	/// be able to both sign and verify. InvalidSignature if the proof does not verify or
+	/// the nonce is not the one it was created with.
+	pub fn legacy_rewind(
+		&self,
+		commit: &Commitment,
+		proof: &[u8],
+		nonce: &SecretKey,
+		extra_data: Option<&[u8]>,
+	) -> Result<LegacyProofInfo, Error> {
+		self.legacy_rewind_with_generator(commit, proof, nonce, extra_data, &GENERATOR_H)
+	}
+
+	/// As legacy_rewind, for a commitment to an asset whose value generator is gen
+	pub fn legacy_rewind_with_generator(
+		&self,
+		commit: &Commitment,
+		proof: &[u8],
+		nonce: &SecretKey,
+		extra_data: Option<&[u8]>,
+		gen: &Generator,
+	) -> Result<LegacyProofInfo, Error> {
+		if self.caps != ContextFlag::Full && self.caps != ContextFlag::Commit {
+			return Err(err!(IllegalState));
+		}
+		let range = match self.legacy_range_info(proof) {
+			Ok(range) => range,
+			Err(e) => return Err(e),
+		};
+		let commit_i = match self.commit_parse(commit) {
+			Ok(commit_i) => commit_i,
+			Err(e) => return Err(e),
+		};
+		let (extra_data, extra_data_len) = match extra_data {
+			Some(extra_data) => (extra_data.as_ptr(), extra_data.len() as u64),
+			None => (ptr::null(), 0),
+		};
+		let mut message = Vec::new();
+		match message.resize(MAX_LEGACY_MSG_SIZE) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut info = LegacyProofInfo {
+			range,
+			value: 0,
+			blind: SecretKey([0u8; SECRET_KEY_SIZE]),
+			message: Vec::new(),
+		};
+		let mut outlen = MAX_LEGACY_MSG_SIZE as u64;
+		let ret = unsafe {
+			ffi::secp256k1_rangeproof_rewind(
+				self.ctx,
+				info.blind.0.as_mut_ptr(),
+				&mut info.value,
+				message.as_mut_ptr(),
+				&mut outlen,
+				nonce.0.as_ptr(),
+				&mut info.range.min_value,
+				&mut info.range.max_value,
+				commit_i.as_ptr(),
+				proof.as_ptr(),
+				proof.len() as u64,
+				extra_data,
+				extra_data_len,
+				gen.0.as_ptr(),
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidSignature));
+		}
+		message.truncate(outlen as usize);
+		info.message = message;
+		Ok(info)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cpsrng::Cpsrng;
+
+	fn sign(
+		secp: &Secp256k1,
+		value: u64,
+		blind: &SecretKey,
+		nonce: &SecretKey,
+		message: &[u8],
+		gen: &Generator,
+	) -> Vec<u8> {
+		let commit = secp
+			.commit_parse(&secp.commit_with_generator(value, blind, gen).unwrap())
+			.unwrap();
+		let mut proof = Vec::new();
+		proof.resize(MAX_LEGACY_PROOF_SIZE).unwrap();
+		let mut plen = MAX_LEGACY_PROOF_SIZE as u64;
+		let ret = unsafe {
+			ffi::secp256k1_rangeproof_sign(
+				secp.ctx,
+				proof.as_mut_ptr(),
+				&mut plen,
+				0,
+				commit.as_ptr(),
+				blind.0.as_ptr(),
+				nonce.0.as_ptr(),
+				0,
+				0,
+				value,
+				message.as_ptr(),
+				message.len() as u64,
+				ptr::null(),
+				0,
+				gen.0.as_ptr(),
+			)
+		};
+		assert_eq!(ret, 1);
+		proof.truncate(plen as usize);
+		proof
+	}
+
+	#[test]
+	fn test_legacy_rangeproof() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Commit).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let blind = SecretKey::generate(&rand);
+		let nonce = SecretKey::generate(&rand);
+		let proof = sign(&secp, 1000, &blind, &nonce, b"legacy output", &GENERATOR_H);
+		assert!(proof.len() <= MAX_LEGACY_PROOF_SIZE);
+
+		let range = secp.legacy_range_info(proof.as_slice()).unwrap();
+		assert_eq!(range.exp, 0);
+		assert!(range.mantissa > 0);
+		assert!(range.min_value <= 1000 && 1000 <= range.max_value);
+		assert!(secp.legacy_range_info(&[0u8; 10]).is_err());
+
+		let commit = secp.commit(1000, &blind).unwrap();
+		let info = secp
+			.legacy_rewind(&commit, proof.as_slice(), &nonce, None)
+			.unwrap();
+		assert!(info.range == range);
+		assert_eq!(info.value, 1000);
+		assert!(info.blind == blind);
+		assert!(info.message.as_slice().starts_with(b"legacy output"));
+
+		// a proof rewinds only with its own nonce and commitment
+		let wrong = SecretKey::generate(&rand);
+		assert!(secp
+			.legacy_rewind(&commit, proof.as_slice(), &wrong, None)
+			.is_err());
+		let other = secp.commit(1001, &blind).unwrap();
+		assert!(secp
+			.legacy_rewind(&other, proof.as_slice(), &nonce, None)
+			.is_err());
+
+		let verify = Secp256k1::with_caps(ContextFlag::VerifyOnly).unwrap();
+		assert!(verify.legacy_range_info(proof.as_slice()).is_ok());
+		assert!(verify
+			.legacy_rewind(&commit, proof.as_slice(), &nonce, None)
+			.is_err());
+
+		// an asset's proof rewinds with its own generator
+		let gold = Generator::from_seed(&[3u8; 32]).unwrap();
+		let proof = sign(&secp, 7, &blind, &nonce, b"gold", &gold);
+		let commit = secp.commit_with_generator(7, &blind, &gold).unwrap();
+		let info = secp
+			.legacy_rewind_with_generator(&commit, proof.as_slice(), &nonce, None, &gold)
+			.unwrap();
+		assert_eq!(info.value, 7);
+		assert!(secp
+			.legacy_rewind(&commit, proof.as_slice(), &nonce, None)
+			.is_err());
+	}
+}
@@ -0,0 +1,197 @@
+//! # Watch-only output scanning
+//! A background worker which rewinds the bulletproofs of a stream of outputs
+//! using a set of view keys in order to detect owned outputs.
+
+use core::mem::replace;
+use prelude::*;
+use secp256k1::bulletproof::*;
+use secp256k1::types::*;
+
+/// An output (commitment and its rangeproof) to be scanned
+pub struct ScanOutput {
+	pub commit: Commitment,
+	pub proof: RangeProof,
+}
+
+pub enum ScanMessage {
+	Output(ScanOutput),
+	Stop,
+}
+
+/// A detected owned output
+pub struct ScanMatch {
+	/// Sequence number of the output in the scanned stream
+	pub index: u64,
+	/// Index into the view keys of the key which rewound the proof
+	pub key_index: usize,
+	pub commit: Commitment,
+	pub info: ProofInfo,
+}
+
+type MatchHandler = Box<dyn FnMut(ScanMatch) -> Result<(), Error> + Send>;
+
+pub struct WatchScanner {
+	send: Sender<ScanMessage>,
+	handle: Option<Handle<()>>,
+}
+
+impl Drop for WatchScanner {
+	fn drop(&mut self) {
+		let _ = self.stop();
+	}
+}
+
+impl WatchScanner {
+	/// Start a scanner on the specified runtime. Each output sent to the scanner
+	/// is rewound with every view key and matches are reported to the handler.
+	pub fn start(
+		runtime: &mut Runtime<()>,
+		bp: &Bulletproofs,
+		view_keys: Vec<SecretKey>,
+		handler: MatchHandler,
+	) -> Result<Self, Error> {
+		let bp = match bp.clone() {
+			Ok(bp) => bp,
+			Err(e) => return Err(e),
+		};
+		let (send, recv) = match channel() {
+			Ok((send, recv)) => (send, recv),
+			Err(e) => return Err(e),
+		};
+		let mut handler = handler;
+		let handle = match runtime.execute(move || {
+			Self::scan_loop(&bp, &view_keys, &recv, &mut handler);
+		}) {
+			Ok(handle) => handle,
+			Err(e) => return Err(e),
+		};
+		Ok(Self {
+			send,
+			handle: Some(handle),
+		})
+	}
+
+	/// Queue an output to be scanned
+	pub fn scan(&self, output: ScanOutput) -> Result<(), Error> {
+		if self.handle.is_none() {
+			return Err(err!(IllegalState));
+		}
+		self.send.send(ScanMessage::Output(output))
+	}
+
+	/// Returns a sender which may be used to feed outputs from another thread
+	pub fn sender(&self) -> Result<Sender<ScanMessage>, Error> {
+		self.send.clone()
+	}
+
+	/// Stop the scanner, waiting for all queued outputs to be processed
+	pub fn stop(&mut self) -> Result<(), Error> {
+		match replace(&mut self.handle, None) {
+			Some(handle) => {
+				match self.send.send(ScanMessage::Stop) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+				handle.block_on()
+			}
+			None => Err(err!(IllegalState)),
+		}
+	}
+
+	fn scan_loop(
+		bp: &Bulletproofs,
+		view_keys: &Vec<SecretKey>,
+		recv: &Receiver<ScanMessage>,
+		handler: &mut MatchHandler,
+	) {
+		let mut index = 0;
+		loop {
+			match recv.recv() {
+				ScanMessage::Output(output) => {
+					for key_index in 0..view_keys.len() {
+						match bp.rewind(&output.commit, &output.proof, &view_keys[key_index], None)
+						{
+							Ok(info) => {
+								let m = ScanMatch {
+									index,
+									key_index,
+									commit: output.commit,
+									info,
+								};
+								match handler(m) {
+									Ok(_) => {}
+									Err(e) => println!("WARN: scan handler returned error: {}", e),
+								}
+								break;
+							}
+							Err(_) => {}
+						}
+					}
+					index += 1;
+				}
+				ScanMessage::Stop => break,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cpsrng::Cpsrng;
+
+	#[test]
+	fn test_watch_scanner() {
+		let _alloc = AllocGuard::new();
+		let mut r = Runtime::new(RuntimeConfig::default()).unwrap();
+		r.start().unwrap();
+		let bp = Bulletproofs::new().unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let view_key1 = SecretKey::generate(&rand);
+		let view_key2 = SecretKey::generate(&rand);
+		let other_key = SecretKey::generate(&rand);
+		let mut view_keys = Vec::new();
+		view_keys.push(SecretKey(view_key1.0)).unwrap();
+		view_keys.push(SecretKey(view_key2.0)).unwrap();
+
+		let found = Arc::new(Mutex::new(Vec::new())).unwrap();
+		let found_clone = found.clone().unwrap();
+		let handler = Box::new(move |m: ScanMatch| -> Result<(), Error> {
+			found_clone
+				.lock()
+				.push((m.index, m.key_index, m.info.value, m.info.message[0]))
+		})
+		.unwrap();
+		let mut scanner = WatchScanner::start(&mut r, &bp, view_keys, handler).unwrap();
+
+		let keys = [&other_key, &view_key2, &other_key, &view_key1];
+		for i in 0..keys.len() {
+			let blind = SecretKey::generate(&rand);
+			let value = 100 + i as u64;
+			let commit = bp.commit(value, &blind).unwrap();
+			let message = [i as u8; PROOF_MSG_SIZE];
+			let proof = bp
+				.prove(value, &blind, keys[i], None, Some(&message))
+				.unwrap();
+			scanner.scan(ScanOutput { commit, proof }).unwrap();
+		}
+		// junk is skipped
+		let proof = RangeProof::from_slice(&[1u8; MAX_PROOF_SIZE]).unwrap();
+		let commit = Commitment([2u8; PEDERSEN_COMMITMENT_SIZE]);
+		scanner
+			.sender()
+			.unwrap()
+			.send(ScanMessage::Output(ScanOutput { commit, proof }))
+			.unwrap();
+
+		scanner.stop().unwrap();
+		assert!(scanner.stop().is_err());
+		{
+			let found = found.lock();
+			assert_eq!(found.len(), 2);
+			assert_eq!(found[0], (1, 1, 101, 1));
+			assert_eq!(found[1], (3, 0, 103, 3));
+		}
+		r.stop().unwrap();
+	}
+}
@@ -0,0 +1,518 @@
+//! # BIP-340 Schnorr signatures
+//! Standard x-only Schnorr signatures as used by taproot. These are distinct from the
+//! grin-style signatures in `aggsig`, which commit to the full public key and use
+//! untagged hashes. The vendored library predates BIP-340, so the scheme is built here
+//! from its key and tweak functions and the tagged hashes of the BIP.
+
+use core::marker::Copy;
+use core::ptr;
+use ffi;
+use prelude::*;
+use secp256k1::types::*;
+use std::cpsrng::Cpsrng;
+use std::encoding::hex_format;
+use std::hash::{sha256, SHA256_SIZE};
+
+/// The size (in bytes) of an x-only public key
+pub const XONLY_PUBLIC_KEY_SIZE: usize = 32;
+
+// the compressed encoding of the generator
+const GENERATOR_COMPRESSED: [u8; COMPRESSED_PUBLIC_KEY_SIZE] = [
+	0x02, 0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b,
+	0x07, 0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17,
+	0x98,
+];
+// the order of the group, big endian
+const CURVE_ORDER: [u8; 32] = [
+	0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+	0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+// the longest message tagged_hash is given, the nonce's t || P.x || m
+const TAGGED_MAX: usize = 96;
+
+/// A BIP-340 public key: the x-coordinate of a point with an implicitly even y-coordinate
+#[derive(Clone, PartialEq)]
+#[repr(C)]
+pub struct XOnlyPublicKey(pub [u8; XONLY_PUBLIC_KEY_SIZE]);
+impl Copy for XOnlyPublicKey {}
+
+impl Display for XOnlyPublicKey {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(XOnlyPublicKey([u8; XONLY_PUBLIC_KEY_SIZE]));
+
+impl XOnlyPublicKey {
+	pub fn from_secret_key(secp: &Secp256k1, sk: &SecretKey) -> Result<XOnlyPublicKey, Error> {
+		match Self::from_secret_key_parity(secp, sk) {
+			Ok((pk, _)) => Ok(pk),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Returns the x-only key along with whether the full public key has an odd y-coordinate
+	pub fn from_secret_key_parity(
+		secp: &Secp256k1,
+		sk: &SecretKey,
+	) -> Result<(XOnlyPublicKey, bool), Error> {
+		if secp.caps == ContextFlag::None || secp.caps == ContextFlag::VerifyOnly {
+			return Err(err!(IllegalState));
+		}
+		let full = match PublicKey::from_secret_key(secp, sk) {
+			Ok(full) => full,
+			Err(e) => return Err(e),
+		};
+		match full.serialize_compressed(secp) {
+			Ok(ser) => Ok(split_compressed(&ser)),
+			Err(e) => Err(e),
+		}
+	}
+
+	// the point with this x-coordinate and an even y-coordinate. An error if x is not that
+	// of a point, which includes any x not below the field size.
+	fn lift_x(&self, secp: &Secp256k1) -> Result<PublicKey, Error> {
+		let mut ser = [2u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		ser[1..].copy_from_slice(&self.0);
+		PublicKey::from_compressed(secp, &ser)
+	}
+}
+
+/// A secret key together with its x-only public key
+pub struct Keypair {
+	pub secret: SecretKey,
+	pub public: XOnlyPublicKey,
+}
+
+impl Keypair {
+	pub fn from_secret_key(secp: &Secp256k1, secret: SecretKey) -> Result<Keypair, Error> {
+		match XOnlyPublicKey::from_secret_key(secp, &secret) {
+			Ok(public) => Ok(Keypair { secret, public }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Generates a random keypair using the specified cpsrng context
+	pub fn generate(secp: &Secp256k1, rand: &Cpsrng) -> Result<Keypair, Error> {
+		match secp.generate_keypair(rand) {
+			Ok((secret, _)) => Self::from_secret_key(secp, secret),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Secp256k1 {
+	/// Creates a BIP-340 signature of msg. aux_rand should be fresh randomness; it is
+	/// mixed into the nonce derivation as a countermeasure against side channels.
+	pub fn schnorr_sign(
+		&self,
+		msg: &Message,
+		keypair: &Keypair,
+		aux_rand: &[u8; 32],
+	) -> Result<Signature, Error> {
+		if self.caps == ContextFlag::None || self.caps == ContextFlag::VerifyOnly {
+			return Err(err!(IllegalState));
+		}
+		// d is negated if need be so that its public key has an even y-coordinate
+		let (px, odd) = match XOnlyPublicKey::from_secret_key_parity(self, &keypair.secret) {
+			Ok(pk) => pk,
+			Err(e) => return Err(e),
+		};
+		let mut d = SecretKey(keypair.secret.0);
+		if odd && !self.scalar_negate(&mut d) {
+			return Err(err!(InvalidSignature));
+		}
+
+		// t = bytes(d) xor hash_BIP0340/aux(a)
+		let mut t = SecretKey(tagged_hash(b"BIP0340/aux", &[aux_rand]));
+		for i in 0..32 {
+			t.0[i] ^= d.0[i];
+		}
+		// k' = int(hash_BIP0340/nonce(t || bytes(P) || m)) mod n, failing if it is 0
+		let mut k = SecretKey(tagged_hash(b"BIP0340/nonce", &[&t.0, &px.0, &msg.0]));
+		if !self.scalar_reduce(&mut k) {
+			return Err(err!(InvalidSignature));
+		}
+		let (rx, odd) = match XOnlyPublicKey::from_secret_key_parity(self, &k) {
+			Ok(r) => r,
+			Err(e) => return Err(e),
+		};
+		if odd && !self.scalar_negate(&mut k) {
+			return Err(err!(InvalidSignature));
+		}
+
+		// s = (k + e * d) mod n. The unreduced e is reduced as it is multiplied.
+		let mut s = SecretKey(self.challenge(&rx, &px, msg));
+		let ok = unsafe {
+			ffi::secp256k1_ec_privkey_tweak_mul(self.ctx, s.0.as_mut_ptr(), d.0.as_ptr()) == 1
+				&& ffi::secp256k1_ec_privkey_tweak_add(self.ctx, s.0.as_mut_ptr(), k.0.as_ptr())
+					== 1
+		};
+		if !ok {
+			return Err(err!(InvalidSignature));
+		}
+		let mut sig = Signature::new();
+		sig.0[0..32].copy_from_slice(&rx.0);
+		sig.0[32..64].copy_from_slice(&s.0);
+		Ok(sig)
+	}
+
+	/// Verifies a BIP-340 signature
+	pub fn schnorr_verify(&self, sig: &Signature, msg: &Message, pk: &XOnlyPublicKey) -> bool {
+		if self.caps == ContextFlag::None || self.caps == ContextFlag::SignOnly {
+			return false;
+		}
+		let mut s = [0u8; 32];
+		s.copy_from_slice(&sig.0[32..64]);
+		if !less_than(&s, &CURVE_ORDER) {
+			return false;
+		}
+		let p = match pk.lift_x(self) {
+			Ok(p) => p,
+			Err(_) => return false,
+		};
+		let mut rx = XOnlyPublicKey([0u8; XONLY_PUBLIC_KEY_SIZE]);
+		rx.0.copy_from_slice(&sig.0[0..32]);
+
+		// R = s*G - e*P, either term of which may be zero
+		let mut e = SecretKey(self.challenge(&rx, pk, msg));
+		let mut terms = [PublicKey::new(); 2];
+		let mut n = 0;
+		if s != [0u8; 32] {
+			terms[n] = match self.point_mul(&GENERATOR_COMPRESSED, &s) {
+				Some(sg) => sg,
+				None => return false,
+			};
+			n += 1;
+		}
+		if self.scalar_reduce(&mut e) {
+			if !self.scalar_negate(&mut e) {
+				return false;
+			}
+			terms[n] = p;
+			let ret = unsafe {
+				ffi::secp256k1_ec_pubkey_tweak_mul(self.ctx, terms[n].as_mut_ptr(), e.0.as_ptr())
+			};
+			if ret != 1 {
+				return false;
+			}
+			n += 1;
+		}
+		if n == 0 {
+			return false;
+		}
+		let ins = [terms[0].as_ptr(), terms[1].as_ptr()];
+		let mut r = PublicKey::new();
+		// combining fails when the sum is the point at infinity
+		if unsafe {
+			ffi::secp256k1_ec_pubkey_combine(self.ctx, r.as_mut_ptr(), ins.as_ptr(), n as i32)
+		} != 1
+		{
+			return false;
+		}
+		match r.serialize_compressed(self) {
+			Ok(ser) => {
+				let (x, odd) = split_compressed(&ser);
+				!odd && x.0 == rx.0
+			}
+			Err(_) => false,
+		}
+	}
+
+	/// Verifies a batch of BIP-340 signatures. Returns true only if every signature is valid.
+	/// The library's batch verifier, secp256k1_schnorrsig_verify_batch, is for its older
+	/// signature format whose challenge is not tagged and whose R has a square y-coordinate,
+	/// so each signature is checked in turn.
+	pub fn schnorr_verify_batch(
+		&self,
+		sigs: &Vec<Signature>,
+		msgs: &Vec<Message>,
+		pks: &Vec<XOnlyPublicKey>,
+	) -> bool {
+		if self.caps == ContextFlag::None || self.caps == ContextFlag::SignOnly {
+			return false;
+		}
+		if sigs.len() != msgs.len() || sigs.len() != pks.len() {
+			return false;
+		}
+		for i in 0..sigs.len() {
+			if !self.schnorr_verify(&sigs[i], &msgs[i], &pks[i]) {
+				return false;
+			}
+		}
+		true
+	}
+
+	// e = int(hash_BIP0340/challenge(R.x || P.x || m)), not yet reduced mod n
+	fn challenge(&self, rx: &XOnlyPublicKey, px: &XOnlyPublicKey, msg: &Message) -> [u8; 32] {
+		tagged_hash(b"BIP0340/challenge", &[&rx.0, &px.0, &msg.0])
+	}
+
+	// reduces k mod n in place, false if the result is zero
+	fn scalar_reduce(&self, k: &mut SecretKey) -> bool {
+		// the key being tweaked is read mod n, and adding zero writes it back reduced
+		let zero = [0u8; 32];
+		unsafe {
+			ffi::secp256k1_ec_privkey_tweak_add(self.ctx, k.0.as_mut_ptr(), zero.as_ptr()) == 1
+		}
+	}
+
+	fn scalar_negate(&self, k: &mut SecretKey) -> bool {
+		unsafe { ffi::secp256k1_ec_privkey_tweak_neg(self.ctx, k.0.as_mut_ptr()) == 1 }
+	}
+
+	// k times the point with compressed encoding ser, where 0 < k < n
+	fn point_mul(&self, ser: &[u8; COMPRESSED_PUBLIC_KEY_SIZE], k: &[u8; 32]) -> Option<PublicKey> {
+		let mut p = match PublicKey::from_compressed(self, ser) {
+			Ok(p) => p,
+			Err(_) => return None,
+		};
+		match unsafe { ffi::secp256k1_ec_pubkey_tweak_mul(self.ctx, p.as_mut_ptr(), k.as_ptr()) } {
+			1 => Some(p),
+			_ => None,
+		}
+	}
+}
+
+// hash_tag(x) = SHA256(SHA256(tag) || SHA256(tag) || x) where x is the parts concatenated
+fn tagged_hash(tag: &[u8], parts: &[&[u8]]) -> [u8; SHA256_SIZE] {
+	let tag_hash = sha256(tag);
+	let mut buf = [0u8; 2 * SHA256_SIZE + TAGGED_MAX];
+	buf[0..SHA256_SIZE].copy_from_slice(&tag_hash);
+	buf[SHA256_SIZE..2 * SHA256_SIZE].copy_from_slice(&tag_hash);
+	let mut len = 2 * SHA256_SIZE;
+	for part in parts {
+		buf[len..len + part.len()].copy_from_slice(part);
+		len += part.len();
+	}
+	let ret = sha256(&buf[0..len]);
+	// the nonce's input includes secret bytes
+	for i in 0..len {
+		unsafe {
+			ptr::write_volatile(&mut buf[i], 0);
+		}
+	}
+	ret
+}
+
+// the x-coordinate of a compressed point and whether its y-coordinate is odd
+fn split_compressed(ser: &[u8; COMPRESSED_PUBLIC_KEY_SIZE]) -> (XOnlyPublicKey, bool) {
+	let mut x = XOnlyPublicKey([0u8; XONLY_PUBLIC_KEY_SIZE]);
+	x.0.copy_from_slice(&ser[1..]);
+	(x, ser[0] == 3)
+}
+
+// big endian comparison of two 256 bit numbers
+fn less_than(a: &[u8; 32], b: &[u8; 32]) -> bool {
+	for i in 0..32 {
+		if a[i] != b[i] {
+			return a[i] < b[i];
+		}
+	}
+	false
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::encoding::hex_decode_array;
+	use std::ser::{deserialize, serialize};
+
+	#[test]
+	fn test_schnorr_vectors() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		// (secret key, public key, aux_rand, message, signature) from the BIP-340 test vectors
+		let vectors = [
+			(
+				"0000000000000000000000000000000000000000000000000000000000000003",
+				"F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+				"0000000000000000000000000000000000000000000000000000000000000000",
+				"0000000000000000000000000000000000000000000000000000000000000000",
+				"E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA8215\
+				 25F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0",
+			),
+			(
+				"B7E151628AED2A6ABF7158809CF4F3C762E7160F38B4DA56A784D9045190CFEF",
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"0000000000000000000000000000000000000000000000000000000000000001",
+				"243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+				"6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+				 8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A",
+			),
+			(
+				"C90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B14E5C9",
+				"DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+				"C87AA53824B4D7AE2EB035A2B5BBBCCC080E76CDC6D1692C4B0B62D798E6D906",
+				"7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+				"5831AAEED7B44BB74E5EAB94BA9D4294C49BCF2A60728D8B4C200F50DD313C1B\
+				 AB745879A5AD954A72C45A91C3A51D3C7ADEA98D82F8481E0E1E03674A6F3FB7",
+			),
+			(
+				"0B432B2677937381AEF05BB02A66ECD012773062CF3FA2549E44F58ED2401710",
+				"25D1DFF95105F5253C4022F628A996AD3A0D95FBF21D468A1B33F8C160D8F517",
+				"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+				"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+				"7EB0509757E246F19449885651611CB965ECC1A187DD51B64FDA1EDC9637D5EC\
+				 97582B9CB13DB3933705B32BA982AF5AF25FD78881EBB32771FC5922EFC66EA3",
+			),
+		];
+		for (sk, pk, aux, msg, sig) in vectors {
+			let sk = SecretKey(hex_decode_array(sk).unwrap());
+			let keypair = Keypair::from_secret_key(&secp, sk).unwrap();
+			assert!(keypair.public == XOnlyPublicKey(hex_decode_array(pk).unwrap()));
+			let msg = Message(hex_decode_array(msg).unwrap());
+			let aux = hex_decode_array(aux).unwrap();
+			let s = secp.schnorr_sign(&msg, &keypair, &aux).unwrap();
+			assert!(s == Signature(hex_decode_array(sig).unwrap()));
+			assert!(secp.schnorr_verify(&s, &msg, &keypair.public));
+			assert_eq!(format!("{:X}", keypair.public).unwrap().to_str(), pk);
+			assert_eq!(format!("{:X}", s).unwrap().to_str(), sig);
+
+			// the signature, key and message round trip as bytes
+			let bytes = serialize(&s).unwrap();
+			assert_eq!(bytes.len(), 64);
+			let read: Signature = deserialize(bytes.as_slice()).unwrap();
+			let bytes = serialize(&keypair.public).unwrap();
+			let pk: XOnlyPublicKey = deserialize(bytes.as_slice()).unwrap();
+			let m: Message = deserialize(serialize(&msg).unwrap().as_slice()).unwrap();
+			assert!(read == s && pk == keypair.public);
+			assert!(secp.schnorr_verify(&read, &m, &pk));
+		}
+	}
+
+	#[test]
+	fn test_schnorr_verify_vectors() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::VerifyOnly).unwrap();
+		// a valid signature whose R.x has many leading zero bytes
+		let pk = "D69C3509BB99E412E68B0FE8544E72837DFA30746D8BE2AA65975F29D22DC7B9";
+		let msg = "4DF3C3F68FCC83B27E9D42C90431A72499F17875C81A599B566C9889B9696703";
+		let sig = "00000000000000000000003B78CE563F89A0ED9414F5AA28AD0D96D6795F9C63\
+			 76AFB1548AF603B3EB45C9F8207DEE1060CB71C04E80F593060B07D28308D7F4";
+		let pk = XOnlyPublicKey(hex_decode_array(pk).unwrap());
+		let sig = Signature(hex_decode_array(sig).unwrap());
+		assert!(secp.schnorr_verify(&sig, &Message(hex_decode_array(msg).unwrap()), &pk));
+
+		// (public key, signature) of the BIP-340 vectors that must fail, all over one message
+		let msg = Message(
+			hex_decode_array("243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89")
+				.unwrap(),
+		);
+		let vectors = [
+			// public key not on the curve
+			(
+				"EEFDEA4CDB677750A420FEE807EACF21EB9898AE79B9768766E4FAA04A2D4A34",
+				"6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+				 69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+			),
+			// R has an odd y-coordinate
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"FFF97BD5755EEEA420453A14355235D382F6472F8568A18B2F057A1460297556\
+				 3CC27944640AC607CD107AE10923D9EF7A73C643E166BE5EBEAFA34B1AC553E2",
+			),
+			// negated message
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"1FA62E331EDBC21C394792D2AB1100A7B432B013DF3F6FF4F99FCB33E0E1515F\
+				 28890B3EDB6E7189B630448B515CE4F8622A954CFE545735AAEA5134FCCDB2BD",
+			),
+			// negated s
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+				 961764B3AA9B2FFCB6EF947B6887A226E8D7C93E00C5ED0C1834FF0D0C2E6DA6",
+			),
+			// R is infinite (sG - eP with e = 0)
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"0000000000000000000000000000000000000000000000000000000000000000\
+				 123DDA8328AF9C23A94C1FEECFD123BA4FB73476F0D594DCB65C6425BD186051",
+			),
+			// R is infinite (sG - eP with e = 1)
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"0000000000000000000000000000000000000000000000000000000000000001\
+				 7615FBAF5AE28864013C099742DEADB4DBA87F11AC6754F93780D5A1837CF197",
+			),
+			// R.x is not on the curve
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"4A298DACAE57395A15D0795DDBFD1DCB564DA82B0F269BC70A74F8220429BA1D\
+				 69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+			),
+			// R.x is the field size
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F\
+				 69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+			),
+			// s is the curve order
+			(
+				"DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+				"6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+				 FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141",
+			),
+			// public key exceeds the field size
+			(
+				"FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30",
+				"6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+				 69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B",
+			),
+		];
+		let mut sigs = Vec::new();
+		let mut msgs = Vec::new();
+		let mut pks = Vec::new();
+		for (pk, sig) in vectors {
+			let pk = XOnlyPublicKey(hex_decode_array(pk).unwrap());
+			let sig = Signature(hex_decode_array(sig).unwrap());
+			assert!(!secp.schnorr_verify(&sig, &msg, &pk));
+			sigs.push(sig).unwrap();
+			msgs.push(msg).unwrap();
+			pks.push(pk).unwrap();
+			assert!(!secp.schnorr_verify_batch(&sigs, &msgs, &pks));
+		}
+	}
+
+	#[test]
+	fn test_schnorr_sign_verify() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::Full).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let mut sigs = Vec::new();
+		let mut msgs = Vec::new();
+		let mut pks = Vec::new();
+		for _i in 0..10 {
+			let keypair = Keypair::generate(&secp, &rand).unwrap();
+			let mut msg = Message([0u8; 32]);
+			let mut aux = [0u8; 32];
+			rand.fill(&mut msg.0);
+			rand.fill(&mut aux);
+			let sig = secp.schnorr_sign(&msg, &keypair, &aux).unwrap();
+			assert!(secp.schnorr_verify(&sig, &msg, &keypair.public));
+
+			let mut bad = sig;
+			bad.0[63] ^= 1;
+			assert!(!secp.schnorr_verify(&bad, &msg, &keypair.public));
+			let mut bad_msg = msg;
+			bad_msg.0[0] ^= 1;
+			assert!(!secp.schnorr_verify(&sig, &bad_msg, &keypair.public));
+
+			sigs.push(sig).unwrap();
+			msgs.push(msg).unwrap();
+			pks.push(keypair.public).unwrap();
+		}
+		assert!(secp.schnorr_verify_batch(&sigs, &msgs, &pks));
+		assert!(secp.schnorr_verify_batch(&Vec::new(), &Vec::new(), &Vec::new()));
+		sigs[3].0[40] ^= 1;
+		assert!(!secp.schnorr_verify_batch(&sigs, &msgs, &pks));
+		sigs[3].0[40] ^= 1;
+		pks.push(pks[0]).unwrap();
+		assert!(!secp.schnorr_verify_batch(&sigs, &msgs, &pks));
+
+		let verify_only = Secp256k1::with_caps(ContextFlag::VerifyOnly).unwrap();
+		assert!(Keypair::generate(&verify_only, &rand).is_err());
+		assert!(verify_only.schnorr_verify(&sigs[0], &msgs[0], &pks[0]));
+	}
+}
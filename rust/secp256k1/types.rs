@@ -1,7 +1,20 @@
+use core::hint::black_box;
 use core::marker::{Copy, Send, Sync};
-use core::ptr::write_volatile;
-use ffi::cpsrng_rand_bytes_ctx;
+use core::ptr::{null, write_volatile};
+use ffi::{
+	secp256k1_context_create, secp256k1_context_destroy, secp256k1_context_no_precomp,
+	secp256k1_context_preallocated_size, secp256k1_context_randomize,
+	secp256k1_context_set_illegal_callback, secp256k1_ec_privkey_tweak_add,
+	secp256k1_ec_pubkey_create, secp256k1_ec_pubkey_parse, secp256k1_ec_pubkey_serialize,
+	secp256k1_ec_pubkey_tweak_add, secp256k1_ec_seckey_verify, secp256k1_ecdh,
+	secp256k1_generator_generate,
+};
 use prelude::*;
+use std::cpsrng::Cpsrng;
+use std::encoding::{hex_decode_array, hex_format};
+use std::hash::{sha256, SHA256_SIZE};
+use std::mem::{MemCharge, MemTag};
+use std::zeroize::SecureBox;
 
 /// Flag for context to enable no precomputation
 pub const SECP256K1_START_NONE: u32 = (1 << 0) | 0;
@@ -13,6 +26,8 @@ pub const SECP256K1_START_SIGN: u32 = (1 << 0) | (1 << 9);
 pub const SECP256K1_SER_UNCOMPRESSED: u32 = (1 << 1) | 0;
 /// Flag for keys to indicate compressed serialization format
 pub const SECP256K1_SER_COMPRESSED: u32 = (1 << 1) | (1 << 8);
+/// The size (in bytes) of a public key in compressed serialization format
+pub const COMPRESSED_PUBLIC_KEY_SIZE: usize = 33;
 
 /// A nonce generation function. Ordinary users of the library
 /// never need to see this type; only if you need to control
@@ -54,12 +69,63 @@ pub struct ScratchSpace(i32);
 #[repr(C)]
 pub struct BulletproofGenerators(i32);
 
-/// Generator
+/// A generator of the curve in the library's internal format, its x and y coordinates.
+/// Commitments to a value v with blinding factor x are xG+vH, where H is the value
+/// generator. Each asset may have a value generator of its own.
 #[repr(C)]
 #[derive(Clone)]
 pub struct Generator(pub [u8; 64]);
 impl Copy for Generator {}
 
+/// The standard generator G, by which blinding factors are multiplied
+pub const GENERATOR_G: Generator = Generator([
+	0x79, 0xbe, 0x66, 0x7e, 0xf9, 0xdc, 0xbb, 0xac, 0x55, 0xa0, 0x62, 0x95, 0xce, 0x87, 0x0b, 0x07,
+	0x02, 0x9b, 0xfc, 0xdb, 0x2d, 0xce, 0x28, 0xd9, 0x59, 0xf2, 0x81, 0x5b, 0x16, 0xf8, 0x17, 0x98,
+	0x48, 0x3a, 0xda, 0x77, 0x26, 0xa3, 0xc4, 0x65, 0x5d, 0xa4, 0xfb, 0xfc, 0x0e, 0x11, 0x08, 0xa8,
+	0xfd, 0x17, 0xb4, 0x48, 0xa6, 0x85, 0x54, 0x19, 0x9c, 0x47, 0xd0, 0x8f, 0xfb, 0x10, 0xd4, 0xb8,
+]);
+
+/// The standard value generator H, the hash of G lifted onto the curve
+pub const GENERATOR_H: Generator = Generator([
+	0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+	0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+	0x31, 0xd3, 0xc6, 0x86, 0x39, 0x73, 0x92, 0x6e, 0x04, 0x9e, 0x63, 0x7c, 0xb1, 0xb5, 0xf4, 0x0a,
+	0x36, 0xda, 0xc2, 0x8a, 0xf1, 0x76, 0x69, 0x68, 0xc3, 0x0c, 0x23, 0x13, 0xf3, 0xa3, 0x89, 0x04,
+]);
+
+/// The generator J of switch commitments, as a PublicKey. The vendored secp256k1-zkp does
+/// not export it, these are the bytes its commitment tests pass to secp256k1_blind_switch.
+pub const GENERATOR_J: PublicKey = PublicKey([
+	0x5f, 0x15, 0x21, 0x36, 0x93, 0x93, 0x01, 0x2a, 0x8d, 0x8b, 0x39, 0x7e, 0x9b, 0xf4, 0x54, 0x29,
+	0x2f, 0x5a, 0x1b, 0x3d, 0x38, 0x85, 0x16, 0xc2, 0xf3, 0x03, 0xfc, 0x95, 0x67, 0xf5, 0x60, 0xb8,
+	0x3a, 0xc4, 0xc5, 0xa6, 0xdc, 0xa2, 0x01, 0x59, 0xfc, 0x56, 0xcf, 0x74, 0x9a, 0xa6, 0xa5, 0x65,
+	0x31, 0x6a, 0xa5, 0x03, 0x74, 0x42, 0x3f, 0x42, 0x53, 0x8f, 0xaa, 0x2c, 0xd3, 0x09, 0x3f, 0xa4,
+]);
+
+impl PartialEq for Generator {
+	fn eq(&self, other: &Self) -> bool {
+		self.0 == other.0
+	}
+}
+
+impl Generator {
+	/// A value generator hashed from seed, such as the hash of an asset's name, whose
+	/// discrete log relative to G and H is unknown. IllegalArgument in the rare case that
+	/// the seed does not make one.
+	pub fn from_seed(seed: &[u8; 32]) -> Result<Generator, Error> {
+		let mut gen = Generator([0u8; 64]);
+		// hashing to the curve needs none of a context's tables, so the library's static
+		// one is used
+		let ret = unsafe {
+			secp256k1_generator_generate(secp256k1_context_no_precomp, &mut gen, seed.as_ptr())
+		};
+		if ret != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(gen)
+	}
+}
+
 /// Library-internal representation of a Secp256k1 public key
 #[repr(C)]
 #[derive(Clone)]
@@ -82,6 +148,144 @@ impl PublicKey {
 	pub fn as_ptr(&self) -> *const Self {
 		&self.0 as *const u8 as *const Self
 	}
+
+	/// Creates a new public key from a secret key
+	pub fn from_secret_key(secp: &Secp256k1, sk: &SecretKey) -> Result<PublicKey, Error> {
+		if secp.caps == ContextFlag::None || secp.caps == ContextFlag::VerifyOnly {
+			return Err(err!(IllegalState));
+		}
+		let mut pk = PublicKey::new();
+		let ret = unsafe { secp256k1_ec_pubkey_create(secp.ctx, pk.as_mut_ptr(), sk.0.as_ptr()) };
+		if ret != 1 {
+			return Err(err!(InvalidPublicKey));
+		}
+		Ok(pk)
+	}
+
+	/// Adds the secret key times the generator to this public key
+	pub fn add_exp_assign(&mut self, secp: &Secp256k1, other: &SecretKey) -> Result<(), Error> {
+		if secp.caps == ContextFlag::None || secp.caps == ContextFlag::SignOnly {
+			return Err(err!(IllegalState));
+		}
+		let ret =
+			unsafe { secp256k1_ec_pubkey_tweak_add(secp.ctx, self.as_mut_ptr(), other.0.as_ptr()) };
+		if ret != 1 {
+			return Err(err!(InvalidPublicKey));
+		}
+		Ok(())
+	}
+
+	/// Parses a public key in the 33 byte compressed format
+	pub fn from_compressed(
+		secp: &Secp256k1,
+		data: &[u8; COMPRESSED_PUBLIC_KEY_SIZE],
+	) -> Result<PublicKey, Error> {
+		if data[0] != 2 && data[0] != 3 {
+			return Err(err!(InvalidPublicKey));
+		}
+		let mut pk = PublicKey::new();
+		let ret = unsafe {
+			secp256k1_ec_pubkey_parse(
+				secp.ctx,
+				pk.as_mut_ptr(),
+				data.as_ptr(),
+				COMPRESSED_PUBLIC_KEY_SIZE as u64,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidPublicKey));
+		}
+		Ok(pk)
+	}
+
+	/// Parses a public key from the 66 hex digits of its compressed format.
+	/// IllegalArgument if s is not hex of that length, InvalidPublicKey if it is not a point.
+	pub fn from_hex(s: &str) -> Result<PublicKey, Error> {
+		let data: [u8; COMPRESSED_PUBLIC_KEY_SIZE] = match hex_decode_array(s) {
+			Ok(data) => data,
+			Err(e) => return Err(e),
+		};
+		if data[0] != 2 && data[0] != 3 {
+			return Err(err!(InvalidPublicKey));
+		}
+		let mut pk = PublicKey::new();
+		// parsing needs none of a context's tables, so the library's static one is used
+		let ret = unsafe {
+			secp256k1_ec_pubkey_parse(
+				secp256k1_context_no_precomp,
+				pk.as_mut_ptr(),
+				data.as_ptr(),
+				COMPRESSED_PUBLIC_KEY_SIZE as u64,
+			)
+		};
+		if ret != 1 {
+			return Err(err!(InvalidPublicKey));
+		}
+		Ok(pk)
+	}
+
+	/// Serializes this key in the 33 byte compressed format
+	pub fn serialize_compressed(
+		&self,
+		secp: &Secp256k1,
+	) -> Result<[u8; COMPRESSED_PUBLIC_KEY_SIZE], Error> {
+		let mut ret = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		let mut len = COMPRESSED_PUBLIC_KEY_SIZE as u64;
+		let res = unsafe {
+			secp256k1_ec_pubkey_serialize(
+				secp.ctx,
+				ret.as_mut_ptr(),
+				&mut len,
+				self.as_ptr(),
+				SECP256K1_SER_COMPRESSED,
+			)
+		};
+		if res != 1 || len != COMPRESSED_PUBLIC_KEY_SIZE as u64 {
+			return Err(err!(InvalidPublicKey));
+		}
+		Ok(ret)
+	}
+}
+
+// the compressed format, which from_hex parses
+impl Display for PublicKey {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		// the static context aborts on a blank key rather than reporting it
+		if self.0 == [0u8; 64] {
+			return Err(err!(InvalidPublicKey));
+		}
+		let mut ser = [0u8; COMPRESSED_PUBLIC_KEY_SIZE];
+		let mut len = COMPRESSED_PUBLIC_KEY_SIZE as u64;
+		let res = unsafe {
+			secp256k1_ec_pubkey_serialize(
+				secp256k1_context_no_precomp,
+				ser.as_mut_ptr(),
+				&mut len,
+				self.as_ptr(),
+				SECP256K1_SER_COMPRESSED,
+			)
+		};
+		if res != 1 {
+			return Err(err!(InvalidPublicKey));
+		}
+		hex_format(&ser, f)
+	}
+}
+
+/// Constant-time equality. Every byte is compared regardless of where the first
+/// difference occurs so comparisons of secret material do not leak timing.
+pub trait CtEq {
+	fn ct_eq(&self, other: &Self) -> bool;
+}
+
+impl<const N: usize> CtEq for [u8; N] {
+	fn ct_eq(&self, other: &Self) -> bool {
+		let mut diff = 0u8;
+		for i in 0..N {
+			diff |= self[i] ^ other[i];
+		}
+		black_box(diff) == 0
+	}
 }
 
 pub const SECRET_KEY_SIZE: usize = 32;
@@ -98,13 +302,36 @@ impl Drop for SecretKey {
 	}
 }
 
+impl CtEq for SecretKey {
+	fn ct_eq(&self, other: &Self) -> bool {
+		self.0.ct_eq(&other.0)
+	}
+}
+
+impl PartialEq for SecretKey {
+	fn eq(&self, other: &Self) -> bool {
+		self.ct_eq(other)
+	}
+}
+
 impl SecretKey {
-	pub fn generate(rand: *mut u8) -> Self {
+	pub fn generate(rand: &Cpsrng) -> Self {
 		let mut r = [0u8; 32];
-		unsafe { cpsrng_rand_bytes_ctx(rand, &mut r as *mut u8, 32) };
+		rand.fill(&mut r);
 		SecretKey(r)
 	}
 
+	/// Adds another secret key to this one, modulo the curve order
+	pub fn add_assign(&mut self, secp: &Secp256k1, other: &SecretKey) -> Result<(), Error> {
+		let ret = unsafe {
+			secp256k1_ec_privkey_tweak_add(secp.ctx, self.0.as_mut_ptr(), other.0.as_ptr())
+		};
+		if ret != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(())
+	}
+
 	pub fn as_mut_ptr(&mut self) -> *mut Self {
 		self.0.as_mut_ptr() as *mut Self
 	}
@@ -119,6 +346,16 @@ impl SecretKey {
 #[derive(Clone)]
 pub struct Signature(pub [u8; 64]);
 impl Copy for Signature {}
+impl CtEq for Signature {
+	fn ct_eq(&self, other: &Self) -> bool {
+		self.0.ct_eq(&other.0)
+	}
+}
+impl PartialEq for Signature {
+	fn eq(&self, other: &Self) -> bool {
+		self.ct_eq(other)
+	}
+}
 impl Signature {
 	pub fn as_mut_ptr(&mut self) -> *mut Self {
 		&mut self.0 as *mut u8 as *mut Self
@@ -126,7 +363,23 @@ impl Signature {
 	pub fn as_ptr(&self) -> *const Self {
 		self.0.as_ptr() as *const Self
 	}
+
+	/// Parses the 128 hex digits Display writes. IllegalArgument if s is not hex of that
+	/// length.
+	pub fn from_hex(s: &str) -> Result<Signature, Error> {
+		match hex_decode_array(s) {
+			Ok(data) => Ok(Signature(data)),
+			Err(e) => Err(e),
+		}
+	}
 }
+// the 64 bytes are the serialized form of schnorr and aggsig signatures
+impl Display for Signature {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(Signature([u8; 64]));
 
 /// Library-internal representation of a Secp256k1 signature + recovery ID
 #[repr(C)]
@@ -189,11 +442,45 @@ impl AggSigPartialSignature {
 /// Library-internal representation of an ECDH shared secret
 #[repr(C)]
 pub struct SharedSecret([u8; 32]);
+impl CtEq for SharedSecret {
+	fn ct_eq(&self, other: &Self) -> bool {
+		self.0.ct_eq(&other.0)
+	}
+}
+impl PartialEq for SharedSecret {
+	fn eq(&self, other: &Self) -> bool {
+		self.ct_eq(other)
+	}
+}
+impl Drop for SharedSecret {
+	fn drop(&mut self) {
+		for i in 0..32 {
+			unsafe {
+				write_volatile(&mut self.0[i], 0);
+			}
+		}
+	}
+}
 impl SharedSecret {
 	/// Create a new (zeroed) signature usable for the FFI interface
 	pub fn new() -> SharedSecret {
 		SharedSecret([0; 32])
 	}
+
+	/// The SHA-256 of the compressed point pk multiplied by sk. Both sides of a key
+	/// exchange arrive at the same secret from their own secret key and the other's
+	/// public key.
+	pub fn ecdh(secp: &Secp256k1, pk: &PublicKey, sk: &SecretKey) -> Result<SharedSecret, Error> {
+		let mut ret = SharedSecret::new();
+		if unsafe { secp256k1_ecdh(secp.ctx, &mut ret, pk.as_ptr(), sk.0.as_ptr()) } != 1 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(ret)
+	}
+
+	pub fn as_bytes(&self) -> &[u8; 32] {
+		&self.0
+	}
 	/// Create a new (uninitialized) signature usable for the FFI interface
 	pub unsafe fn blank() -> Self {
 		Self::new()
@@ -203,11 +490,88 @@ impl SharedSecret {
 pub struct Secp256k1 {
 	pub(crate) ctx: *mut Context,
 	pub(crate) caps: ContextFlag,
+	// the library allocates the context itself, so the allocator does not count it
+	_mem: MemCharge,
 }
 
 unsafe impl Send for Secp256k1 {}
 unsafe impl Sync for Secp256k1 {}
 
+extern "C" fn illegal_callback(_message: *const u8, _data: *mut u8) {}
+
+impl Drop for Secp256k1 {
+	fn drop(&mut self) {
+		unsafe {
+			secp256k1_context_destroy(self.ctx);
+		}
+	}
+}
+
+impl Secp256k1 {
+	/// Creates a new Secp256k1 context with the specified capabilities
+	pub fn with_caps(caps: ContextFlag) -> Result<Secp256k1, Error> {
+		let flag = match caps {
+			ContextFlag::None => SECP256K1_START_NONE,
+			ContextFlag::SignOnly => SECP256K1_START_SIGN,
+			ContextFlag::VerifyOnly => SECP256K1_START_VERIFY,
+			ContextFlag::Full | ContextFlag::Commit => {
+				SECP256K1_START_SIGN | SECP256K1_START_VERIFY
+			}
+		};
+		let ctx = unsafe { secp256k1_context_create(flag) };
+		if ctx.is_null() {
+			Err(err!(SecpInit))
+		} else {
+			// illegal arguments are reported through the return value of each
+			// function instead of aborting the process
+			unsafe {
+				secp256k1_context_set_illegal_callback(ctx, illegal_callback, null());
+			}
+			let size = unsafe { secp256k1_context_preallocated_size(flag) };
+			let mem = MemCharge::new(MemTag::Crypto, size as u64);
+			Ok(Secp256k1 {
+				ctx,
+				caps,
+				_mem: mem,
+			})
+		}
+	}
+
+	/// Returns the capabilities of this context
+	pub fn caps(&self) -> ContextFlag {
+		self.caps
+	}
+
+	/// Blinds the signing tables with a fresh random seed, so that the timing and power
+	/// use of signing reveal less about secret keys. IllegalState for a context which
+	/// cannot sign.
+	pub fn randomize(&mut self, rand: &Cpsrng) -> Result<(), Error> {
+		let mut seed = match SecureBox::new([0u8; 32]) {
+			Ok(seed) => seed,
+			Err(e) => return Err(e),
+		};
+		rand.fill(&mut *seed);
+		if unsafe { secp256k1_context_randomize(self.ctx, seed.as_ptr()) } != 1 {
+			return Err(err!(IllegalState));
+		}
+		Ok(())
+	}
+
+	/// Generates a random keypair using the specified cpsrng context
+	pub fn generate_keypair(&self, rand: &Cpsrng) -> Result<(SecretKey, PublicKey), Error> {
+		loop {
+			let sk = SecretKey::generate(rand);
+			if unsafe { secp256k1_ec_seckey_verify(self.ctx, sk.0.as_ptr()) } != 1 {
+				continue;
+			}
+			return match PublicKey::from_secret_key(self, &sk) {
+				Ok(pk) => Ok((sk, pk)),
+				Err(e) => Err(e),
+			};
+		}
+	}
+}
+
 /// Flags used to determine the capabilities of a `Secp256k1` object;
 /// the more capabilities, the more expensive it is to create.
 #[derive(PartialEq, Eq, Copy, Clone)]
@@ -237,4 +601,205 @@ impl Message {
 	pub fn as_ptr(&self) -> *const Self {
 		self.0.as_ptr() as *const Self
 	}
+
+	/// A message of the 32 bytes in data, which should already be a hash.
+	/// InvalidMessage if data is any other length.
+	pub fn from_slice(data: &[u8]) -> Result<Message, Error> {
+		if data.len() != MESSAGE_SIZE {
+			return Err(err!(InvalidMessage));
+		}
+		let mut ret = [0u8; MESSAGE_SIZE];
+		ret.copy_from_slice(data);
+		Ok(Message(ret))
+	}
+
+	/// A message of a SHA-256 digest
+	pub fn from_hash(hash: [u8; SHA256_SIZE]) -> Message {
+		Message(hash)
+	}
+
+	/// The message signing data commits to: its SHA-256
+	pub fn sha256(data: &[u8]) -> Message {
+		Message(sha256(data))
+	}
+
+	/// Parses the 64 hex digits Display writes. IllegalArgument if s is not hex of that
+	/// length.
+	pub fn from_hex(s: &str) -> Result<Message, Error> {
+		match hex_decode_array(s) {
+			Ok(data) => Ok(Message(data)),
+			Err(e) => Err(e),
+		}
+	}
+}
+impl Display for Message {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(Message([u8; MESSAGE_SIZE]));
+
+/// The size (in bytes) of a serialized pedersen commitment
+pub const PEDERSEN_COMMITMENT_SIZE: usize = 33;
+
+/// A serialized pedersen commitment
+#[derive(Clone)]
+#[repr(C)]
+pub struct Commitment(pub [u8; PEDERSEN_COMMITMENT_SIZE]);
+impl Copy for Commitment {}
+impl Commitment {
+	/// Create a commitment from raw data
+	pub fn from_data(data: [u8; PEDERSEN_COMMITMENT_SIZE]) -> Commitment {
+		Commitment(data)
+	}
+
+	pub fn as_ptr(&self) -> *const u8 {
+		self.0.as_ptr()
+	}
+
+	/// Parses the 66 hex digits Display writes. IllegalArgument if s is not hex of that
+	/// length. Like from_data, this does not check the commitment is a valid point.
+	pub fn from_hex(s: &str) -> Result<Commitment, Error> {
+		match hex_decode_array(s) {
+			Ok(data) => Ok(Commitment(data)),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Display for Commitment {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		hex_format(&self.0, f)
+	}
+}
+impl_ser!(Commitment([u8; PEDERSEN_COMMITMENT_SIZE]));
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use ffi::{secp256k1_generator_const_g, secp256k1_generator_const_h};
+
+	#[test]
+	fn test_ct_eq() {
+		let a = [1u8; 32];
+		let mut b = [1u8; 32];
+		assert!(a.ct_eq(&b));
+		b[31] = 2;
+		assert!(!a.ct_eq(&b));
+		b[31] = 1;
+		b[0] = 0;
+		assert!(!a.ct_eq(&b));
+
+		assert!(SecretKey([3u8; 32]) == SecretKey([3u8; 32]));
+		assert!(SecretKey([3u8; 32]) != SecretKey([4u8; 32]));
+		assert!(Signature([5u8; 64]).ct_eq(&Signature([5u8; 64])));
+		assert!(!Signature([5u8; 64]).ct_eq(&Signature::new()));
+		assert!(SharedSecret::new() == SharedSecret([0u8; 32]));
+		assert!(SharedSecret::new() != SharedSecret([9u8; 32]));
+	}
+
+	#[test]
+	fn test_ecdh() {
+		let _alloc = AllocGuard::new();
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly).unwrap();
+		let rand = Cpsrng::new().unwrap();
+		let (sk1, pk1) = secp.generate_keypair(&rand).unwrap();
+		let (sk2, pk2) = secp.generate_keypair(&rand).unwrap();
+		let s1 = SharedSecret::ecdh(&secp, &pk2, &sk1).unwrap();
+		let s2 = SharedSecret::ecdh(&secp, &pk1, &sk2).unwrap();
+		assert!(s1 == s2);
+		assert!(s1 != SharedSecret::new());
+		assert!(SharedSecret::ecdh(&secp, &pk1, &SecretKey([0u8; 32])).is_err());
+
+		let ser = pk1.serialize_compressed(&secp).unwrap();
+		assert!(ser[0] == 2 || ser[0] == 3);
+		let parsed = PublicKey::from_compressed(&secp, &ser).unwrap();
+		assert!(parsed.0[..] == pk1.0[..]);
+		let mut bad = ser;
+		bad[0] = 4;
+		assert!(PublicKey::from_compressed(&secp, &bad).is_err());
+	}
+
+	#[test]
+	fn test_generator() {
+		let _alloc = AllocGuard::new();
+		assert!(GENERATOR_G == unsafe { secp256k1_generator_const_g });
+		assert!(GENERATOR_H == unsafe { secp256k1_generator_const_h });
+		let gold = Generator::from_seed(&sha256(b"gold")).unwrap();
+		assert!(Generator::from_seed(&sha256(b"gold")).unwrap() == gold);
+		assert!(Generator::from_seed(&sha256(b"silver")).unwrap() != gold);
+		assert!(gold != GENERATOR_H && gold != GENERATOR_G);
+
+		// the compressed form of J from the same tests
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly).unwrap();
+		let j =
+			hex_decode_array("02b860f56795fc03f3c21685383d1b5a2f2954f49b7e398b8d2a0193933621155f")
+				.unwrap();
+		let parsed = PublicKey::from_compressed(&secp, &j).unwrap();
+		assert!(parsed.0[..] == GENERATOR_J.0[..]);
+	}
+
+	#[test]
+	fn test_message() {
+		let _alloc = AllocGuard::new();
+		let abc = Message::sha256(b"abc");
+		assert_eq!(
+			format!("{}", abc).unwrap().to_str(),
+			"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+		);
+		assert!(Message::from_hash(sha256(b"abc")).0 == abc.0);
+		assert!(Message::from_slice(&abc.0).unwrap().0 == abc.0);
+		assert!(Message::from_slice(&abc.0[1..]).unwrap_err().kind == InvalidMessage);
+		assert!(Message::from_slice(&[0u8; 33]).unwrap_err().kind == InvalidMessage);
+		assert!(Message::from_slice(&[]).is_err());
+	}
+
+	#[test]
+	fn test_hex() {
+		let _alloc = AllocGuard::new();
+		// the generator point
+		let g = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+		let pk = PublicKey::from_hex(g).unwrap();
+		assert_eq!(format!("{}", pk).unwrap().to_str(), g);
+		let secp = Secp256k1::with_caps(ContextFlag::SignOnly).unwrap();
+		let mut one = SecretKey([0u8; 32]);
+		one.0[31] = 1;
+		assert!(pk.0 == PublicKey::from_secret_key(&secp, &one).unwrap().0);
+		assert!(PublicKey::from_hex(&g[2..]).unwrap_err().kind == IllegalArgument);
+		let mut bad = [0u8; 66];
+		bad.copy_from_slice(g.as_bytes());
+		bad[1] = b'4';
+		let bad = unsafe { from_utf8_unchecked(&bad) };
+		assert!(PublicKey::from_hex(bad).unwrap_err().kind == InvalidPublicKey);
+		// x = 5 is not on the curve
+		let off = "020000000000000000000000000000000000000000000000000000000000000005";
+		assert!(PublicKey::from_hex(off).unwrap_err().kind == InvalidPublicKey);
+		assert!(format!("{}", PublicKey::new()).is_err());
+
+		let mut sig = Signature::new();
+		for i in 0..64 {
+			sig.0[i] = i as u8;
+		}
+		let s = format!("{}", sig).unwrap();
+		assert_eq!(s.len(), 128);
+		assert!(Signature::from_hex(s.to_str()).unwrap() == sig);
+		assert!(Signature::from_hex(&s.to_str()[1..]).is_err());
+
+		let msg =
+			Message::from_hex("00112233445566778899AABBCCDDEEFF00112233445566778899aabbccddeeff")
+				.unwrap();
+		assert_eq!(msg.0[10], 0xaa);
+		assert_eq!(
+			format!("{}", msg).unwrap().to_str(),
+			"00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff"
+		);
+		assert!(Message::from_hex("zz").is_err());
+
+		let commit = Commitment::from_hex(&format!("09{}", &g[2..]).unwrap().to_str()).unwrap();
+		assert_eq!(commit.0[0], 9);
+		assert_eq!(
+			format!("{}", commit).unwrap().to_str(),
+			format!("09{}", &g[2..]).unwrap().to_str()
+		);
+	}
 }
@@ -0,0 +1,139 @@
+//! # Authenticated encryption
+//! ChaCha20-Poly1305 (RFC 8439) from the C layer. seal encrypts a buffer in place and
+//! returns the tag which authenticates it together with the associated data, open checks
+//! the tag before decrypting in place. A key must never be used twice with the same nonce.
+
+use ffi::{chacha20poly1305_open, chacha20poly1305_seal};
+use prelude::*;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+/// Encrypts data in place and returns the tag over it and aad
+pub fn seal(
+	key: &[u8; KEY_SIZE],
+	nonce: &[u8; NONCE_SIZE],
+	aad: &[u8],
+	data: &mut [u8],
+) -> [u8; TAG_SIZE] {
+	let mut tag = [0u8; TAG_SIZE];
+	unsafe {
+		chacha20poly1305_seal(
+			key.as_ptr(),
+			nonce.as_ptr(),
+			aad.as_ptr(),
+			aad.len(),
+			data.as_ptr(),
+			data.len(),
+			data.as_mut_ptr(),
+			tag.as_mut_ptr(),
+		);
+	}
+	tag
+}
+
+/// Decrypts data in place if tag matches it and aad. CorruptedData otherwise, in which
+/// case data is left as it was.
+pub fn open(
+	key: &[u8; KEY_SIZE],
+	nonce: &[u8; NONCE_SIZE],
+	aad: &[u8],
+	data: &mut [u8],
+	tag: &[u8; TAG_SIZE],
+) -> Result<(), Error> {
+	let ret = unsafe {
+		chacha20poly1305_open(
+			key.as_ptr(),
+			nonce.as_ptr(),
+			aad.as_ptr(),
+			aad.len(),
+			data.as_ptr(),
+			data.len(),
+			tag.as_ptr(),
+			data.as_mut_ptr(),
+		)
+	};
+	if ret != 0 {
+		return Err(err!(CorruptedData));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::encoding::hex_decode;
+
+	#[test]
+	fn test_aead_vector() {
+		let _alloc = AllocGuard::new();
+		// RFC 8439 2.8.2
+		let key =
+			hex_decode("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").unwrap();
+		let mut k = [0u8; KEY_SIZE];
+		k.copy_from_slice(key.as_slice());
+		let nonce = [
+			0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+		];
+		let aad = hex_decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+		let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer \
+			you only one tip for the future, sunscreen would be it.";
+		let mut data = Vec::new();
+		data.append_ptr(plaintext.as_ptr(), plaintext.len())
+			.unwrap();
+		let tag = seal(&k, &nonce, aad.as_slice(), data.as_mut_slice());
+		let expected = hex_decode(
+			"d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+			 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+			 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+			 3ff4def08e4b7a9de576d26586cec64b6116",
+		)
+		.unwrap();
+		assert_eq!(data.as_slice(), expected.as_slice());
+		assert_eq!(
+			&tag[..],
+			hex_decode("1ae10b594f09e26a7e902ecbd0600691")
+				.unwrap()
+				.as_slice()
+		);
+
+		open(&k, &nonce, aad.as_slice(), data.as_mut_slice(), &tag).unwrap();
+		assert_eq!(data.as_slice(), plaintext);
+	}
+
+	#[test]
+	fn test_aead_tamper() {
+		let _alloc = AllocGuard::new();
+		let key = [9u8; KEY_SIZE];
+		let nonce = [1u8; NONCE_SIZE];
+		let mut data = [0u8; 100];
+		for i in 0..data.len() {
+			data[i] = i as u8;
+		}
+		let tag = seal(&key, &nonce, b"header", &mut data);
+
+		// any change to the data, the associated data, the tag or the nonce is detected
+		data[50] ^= 1;
+		let tampered = data;
+		assert!(
+			open(&key, &nonce, b"header", &mut data, &tag)
+				.unwrap_err()
+				.kind == CorruptedData
+		);
+		assert_eq!(data, tampered);
+		data[50] ^= 1;
+		assert!(open(&key, &nonce, b"headex", &mut data, &tag).is_err());
+		let mut bad_tag = tag;
+		bad_tag[15] ^= 0x80;
+		assert!(open(&key, &nonce, b"header", &mut data, &bad_tag).is_err());
+		assert!(open(&key, &[2u8; NONCE_SIZE], b"header", &mut data, &tag).is_err());
+		open(&key, &nonce, b"header", &mut data, &tag).unwrap();
+		assert_eq!(data[99], 99);
+
+		// an empty message still carries a tag
+		let tag = seal(&key, &nonce, b"", &mut []);
+		assert!(open(&key, &nonce, b"", &mut [], &tag).is_ok());
+		assert!(open(&key, &nonce, b"x", &mut [], &tag).is_err());
+	}
+}
@@ -0,0 +1,345 @@
+//! # Arc
+//! A reference counted pointer whose counts are updated atomically, so clones may be moved
+//! to and dropped on other threads. Use Rc for values which stay on one thread.
+
+use core::marker::Sized;
+use core::ops::{Deref, Drop};
+use core::ptr::{drop_in_place, read};
+#[cfg(tsan)]
+use ffi;
+use ffi::release;
+use prelude::*;
+
+/// The allocation behind an Arc, named so that a Pool of them can be passed to new_pooled.
+/// weak counts the Weak references plus one held jointly by all the Arc references. The
+/// value is dropped with the last Arc and the allocation released when weak reaches zero.
+pub struct ArcInner<T: ?Sized> {
+	count: u64,
+	weak: u64,
+	// the pool the allocation came from and goes back to
+	pool: Option<Pool<ArcInner<T>>>,
+	value: T,
+}
+
+pub struct Arc<T: ?Sized> {
+	inner: Box<ArcInner<T>>,
+}
+
+/// A non-owning reference to the value of an Arc, which may be upgraded from any thread
+/// while an Arc to the value still exists.
+pub struct Weak<T: ?Sized> {
+	inner: Box<ArcInner<T>>,
+}
+
+// the value is reached from every thread holding a clone, so it must be Sync as well
+unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Send for Weak<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Weak<T> {}
+
+impl<T: ?Sized> Clone for Arc<T> {
+	fn clone(&self) -> Result<Self, Error> {
+		let ptr = self.inner.as_ptr();
+		let mut inner: Box<ArcInner<T>> = Box::from_raw(ptr);
+		inner.leak();
+		aadd!(&mut inner.count, 1);
+		Ok(Arc { inner })
+	}
+}
+
+impl<T: ?Sized> Drop for Arc<T> {
+	fn drop(&mut self) {
+		let rci = self.inner.as_mut();
+		#[cfg(tsan)]
+		unsafe {
+			ffi::tsan_release(&rci.count as *const u64 as *const u8);
+		}
+		if asub!(&mut rci.count, 1) == 1 {
+			#[cfg(tsan)]
+			unsafe {
+				ffi::tsan_acquire(&rci.count as *const u64 as *const u8);
+			}
+			unsafe {
+				drop_in_place(&mut rci.value);
+			}
+			release_weak(&mut self.inner);
+		}
+	}
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+	fn clone(&self) -> Result<Self, Error> {
+		let mut inner: Box<ArcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		aadd!(&mut inner.weak, 1);
+		Ok(Weak { inner })
+	}
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+	fn drop(&mut self) {
+		release_weak(&mut self.inner);
+	}
+}
+
+// frees the allocation, but not the value which the last Arc has already dropped, once the
+// final weak reference goes
+fn release_weak<T: ?Sized>(inner: &mut Box<ArcInner<T>>) {
+	let block = inner.as_ptr().raw() as *mut u8;
+	let rci = inner.as_mut();
+	#[cfg(tsan)]
+	unsafe {
+		ffi::tsan_release(&rci.weak as *const u64 as *const u8);
+	}
+	if asub!(&mut rci.weak, 1) == 1 {
+		#[cfg(tsan)]
+		unsafe {
+			ffi::tsan_acquire(&rci.weak as *const u64 as *const u8);
+		}
+		match unsafe { read(&rci.pool) } {
+			Some(pool) => unsafe { pool.recycle(block) },
+			None => unsafe { release(block) },
+		}
+	}
+}
+
+impl<T: ?Sized> Deref for Arc<T> {
+	type Target = T;
+	fn deref(&self) -> &Self::Target {
+		&self.inner.value
+	}
+}
+
+impl<T: ?Sized> Arc<T> {
+	pub fn get(&self) -> &T {
+		&self.inner.value
+	}
+
+	pub fn get_mut(&mut self) -> Option<&mut T> {
+		// a Weak could otherwise be upgraded while the reference is held
+		if aload!(&mut (*self.inner).count) == 1 && aload!(&mut (*self.inner).weak) == 1 {
+			Some(&mut self.inner.value)
+		} else {
+			None
+		}
+	}
+
+	/// Creates a Weak reference to this value
+	pub fn downgrade(&self) -> Weak<T> {
+		let mut inner: Box<ArcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		aadd!(&mut inner.weak, 1);
+		Weak { inner }
+	}
+
+	pub fn strong_count(&self) -> u64 {
+		aload!(&self.inner.count)
+	}
+
+	pub fn weak_count(&self) -> u64 {
+		aload!(&self.inner.weak) - 1
+	}
+}
+
+impl<T: ?Sized> Weak<T> {
+	/// Returns an Arc to the value, or None if every Arc has been dropped
+	pub fn upgrade(&self) -> Option<Arc<T>> {
+		let mut inner: Box<ArcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		loop {
+			let count = aload!(&inner.count);
+			if count == 0 {
+				return None;
+			}
+			if cas!(&mut inner.count, &count, count + 1) {
+				return Some(Arc { inner });
+			}
+		}
+	}
+
+	pub fn strong_count(&self) -> u64 {
+		aload!(&self.inner.count)
+	}
+}
+
+impl<T> Arc<T> {
+	pub fn new(value: T) -> Result<Self, Error> {
+		match Box::new(ArcInner {
+			value,
+			count: 1,
+			weak: 1,
+			pool: None,
+		}) {
+			Ok(mut inner) => {
+				inner.leak();
+				Ok(Self { inner })
+			}
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Like new but takes the allocation from pool, which it is returned to once the last
+	/// Arc and Weak are gone
+	pub fn new_pooled(value: T, pool: &Pool<ArcInner<T>>) -> Result<Self, Error> {
+		let handle = match pool.clone() {
+			Ok(handle) => handle,
+			Err(e) => return Err(e),
+		};
+		match pool.alloc(ArcInner {
+			value,
+			count: 1,
+			weak: 1,
+			pool: Some(handle),
+		}) {
+			Ok(ptr) => {
+				let mut inner = Box::from_raw(ptr);
+				inner.leak();
+				Ok(Self { inner })
+			}
+			Err(e) => Err(e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	#![allow(static_mut_refs)]
+	use super::*;
+	use core::mem::drop;
+
+	#[test]
+	fn test_arc1() {
+		let _alloc = AllocGuard::new();
+		let mut x1 = Arc::new(1).unwrap();
+		*x1.get_mut().unwrap() += 1;
+		let mut x2 = x1.clone().unwrap();
+		assert!(x1.get_mut().is_none());
+		assert!(x2.get_mut().is_none());
+		assert_eq!(*x1.get(), 2);
+		assert_eq!(*x2.get(), 2);
+
+		// the remaining owner may change the value again
+		drop(x2);
+		*x1.get_mut().unwrap() += 1;
+		assert_eq!(*x1.get(), 3);
+	}
+
+	static mut VTEST: usize = 0;
+
+	struct MyType {
+		v: usize,
+	}
+
+	impl Drop for MyType {
+		fn drop(&mut self) {
+			unsafe {
+				VTEST += 1;
+			}
+		}
+	}
+
+	#[test]
+	fn test_arc2() {
+		let _alloc = AllocGuard::new();
+		{
+			let x = Arc::new(MyType { v: 1 }).unwrap();
+			assert_eq!(x.get().v, 1);
+			{
+				let _y = x.clone();
+				let _z = MyType { v: 2 };
+				unsafe {
+					assert_eq!(VTEST, 0);
+				}
+			}
+			unsafe {
+				assert_eq!(VTEST, 1);
+			}
+		}
+		unsafe {
+			assert_eq!(VTEST, 2);
+		}
+	}
+
+	struct Node {
+		parent: Option<Weak<Node>>,
+		children: Mutex<Vec<Arc<Node>>>,
+	}
+
+	impl Drop for Node {
+		fn drop(&mut self) {
+			unsafe {
+				WTEST += 1;
+			}
+		}
+	}
+
+	static mut WTEST: usize = 0;
+
+	#[test]
+	fn test_arc_weak() {
+		let _alloc = AllocGuard::new();
+		let mut x = Arc::new(7u64).unwrap();
+		let w = x.downgrade();
+		assert_eq!(x.strong_count(), 1);
+		assert_eq!(x.weak_count(), 1);
+		// a live Weak could alias the mutable reference
+		assert!(x.get_mut().is_none());
+		{
+			let w2 = w.clone().unwrap();
+			let y = w2.upgrade().unwrap();
+			assert_eq!(*y, 7);
+			assert_eq!(x.strong_count(), 2);
+			assert_eq!(x.weak_count(), 2);
+		}
+		assert_eq!(x.strong_count(), 1);
+		assert_eq!(x.weak_count(), 1);
+		drop(x);
+		assert_eq!(w.strong_count(), 0);
+		assert!(w.upgrade().is_none());
+
+		// a weak back-reference lets a parent and child free each other
+		{
+			let parent = Arc::new(Node {
+				parent: None,
+				children: Mutex::new(Vec::new()),
+			})
+			.unwrap();
+			let child = Arc::new(Node {
+				parent: Some(parent.downgrade()),
+				children: Mutex::new(Vec::new()),
+			})
+			.unwrap();
+			parent.children.lock().push(child).unwrap();
+			let p = match &parent.children.lock()[0].parent {
+				Some(p) => p.upgrade().unwrap(),
+				None => panic!("the child has no parent"),
+			};
+			assert_eq!(p.children.lock().len(), 1);
+		}
+		unsafe {
+			assert_eq!(WTEST, 2);
+		}
+	}
+
+	#[test]
+	fn test_arc_pooled() {
+		let _alloc = AllocGuard::new();
+		let pool = Pool::new(4).unwrap();
+		let x = Arc::new_pooled(1u64, &pool).unwrap();
+		let y = x.clone().unwrap();
+		let w = x.downgrade();
+		drop(x);
+		drop(y);
+		assert!(w.upgrade().is_none());
+		assert_eq!(pool.free_len(), 0);
+		// the block goes back with the last Weak
+		drop(w);
+		assert_eq!(pool.free_len(), 1);
+
+		// and is reused, even after the pool handle is dropped
+		let x = Arc::new_pooled(2u64, &pool).unwrap();
+		assert_eq!(pool.free_len(), 0);
+		drop(pool);
+		assert_eq!(*x, 2);
+	}
+}
@@ -0,0 +1,98 @@
+//! # Atomic
+//! A u64 which is only read and written through the atomic ffi, so that it can be shared
+//! between threads, in an Arc or a shared struct, without a lock.
+
+use core::cell::UnsafeCell;
+use prelude::*;
+
+pub struct AtomicU64 {
+	value: UnsafeCell<u64>,
+}
+
+// the value is only read and written atomically
+unsafe impl Send for AtomicU64 {}
+unsafe impl Sync for AtomicU64 {}
+
+impl Default for AtomicU64 {
+	fn default() -> Self {
+		Self::new(0)
+	}
+}
+
+impl AtomicU64 {
+	pub const fn new(value: u64) -> Self {
+		Self {
+			value: UnsafeCell::new(value),
+		}
+	}
+
+	pub fn load(&self) -> u64 {
+		aload!(self.value.get())
+	}
+
+	pub fn store(&self, value: u64) {
+		astore!(self.value.get(), value)
+	}
+
+	/// Adds value and returns what was stored before
+	pub fn fetch_add(&self, value: u64) -> u64 {
+		aadd!(self.value.get(), value)
+	}
+
+	/// Subtracts value and returns what was stored before
+	pub fn fetch_sub(&self, value: u64) -> u64 {
+		asub!(self.value.get(), value)
+	}
+
+	/// Stores desired if the value is expect. Returns whether it was stored.
+	pub fn compare_exchange(&self, expect: u64, desired: u64) -> bool {
+		cas!(self.value.get(), &expect, desired)
+	}
+
+	/// The value without atomics, which the exclusive borrow makes safe
+	pub fn get_mut(&mut self) -> &mut u64 {
+		self.value.get_mut()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use prelude::*;
+	use std::atomic::AtomicU64;
+
+	#[test]
+	fn test_atomic_u64() {
+		let mut x = AtomicU64::new(5);
+		assert_eq!(x.fetch_add(3), 5);
+		assert_eq!(x.fetch_sub(1), 8);
+		assert_eq!(x.load(), 7);
+		assert!(!x.compare_exchange(6, 10));
+		assert!(x.compare_exchange(7, 10));
+		x.store(2);
+		*x.get_mut() += 1;
+		assert_eq!(x.load(), 3);
+		assert_eq!(AtomicU64::default().load(), 0);
+	}
+
+	#[test]
+	fn test_atomic_u64_threads() {
+		let count = Arc::new(AtomicU64::new(0)).unwrap();
+		let mut jhs = Vec::new();
+		for _ in 0..4 {
+			let count = count.clone().unwrap();
+			jhs.push(
+				spawnj(move || {
+					for _ in 0..1000 {
+						count.fetch_add(1);
+					}
+				})
+				.unwrap(),
+			)
+			.unwrap();
+		}
+		for i in 0..jhs.len() {
+			assert!(jhs[i].join().is_ok());
+		}
+		assert_eq!(count.load(), 4000);
+	}
+}
@@ -11,6 +11,10 @@ pub struct Backtrace {
 	pub(crate) bt: *const u8,
 }
 
+// bt is owned, and only read after it is captured
+unsafe impl Send for Backtrace {}
+unsafe impl Sync for Backtrace {}
+
 impl Drop for Backtrace {
 	fn drop(&mut self) {
 		unsafe {
@@ -64,16 +68,20 @@ impl Backtrace {
 				Ok(String::empty())
 			} else {
 				let len = unsafe { cstring_len(txt) };
-				if len == 0 {
-					Ok(String::empty())
+				let ret = if len == 0 {
+					String::empty()
 				} else {
 					let bt_slice = unsafe { from_raw_parts(txt, len) };
 					let bt_str = unsafe { from_utf8_unchecked(bt_slice) };
 					match String::new(bt_str) {
-						Ok(backtrace) => Ok(backtrace),
-						Err(_) => Ok(String::empty()),
+						Ok(backtrace) => backtrace,
+						Err(_) => String::empty(),
 					}
+				};
+				unsafe {
+					release(txt);
 				}
+				Ok(ret)
 			}
 		}
 		#[cfg(not(test))]
@@ -10,6 +10,10 @@ pub struct Box<T: ?Sized> {
 	ptr: Ptr<T>,
 }
 
+// a Box owns its value, so it may cross threads whenever the value may
+unsafe impl<T: ?Sized + Send> Send for Box<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for Box<T> {}
+
 impl<T: ?Sized> Drop for Box<T> {
 	fn drop(&mut self) {
 		if !self.ptr.get_bit() {
@@ -33,6 +37,12 @@ impl<T: ?Sized + Clone> Clone for Box<T> {
 	}
 }
 
+impl<T: ?Sized + PartialEq> PartialEq for Box<T> {
+	fn eq(&self, other: &Self) -> bool {
+		self.as_ref() == other.as_ref()
+	}
+}
+
 impl<T> Deref for Box<T>
 where
 	T: ?Sized,
@@ -151,24 +161,20 @@ where
 mod test {
 	use super::*;
 	use core::ops::Fn;
-	use ffi::getalloccount;
 
 	#[test]
 	fn test_box1() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut x = Box::new(4).unwrap();
-			let y = x.as_ref();
-			assert_eq!(*y, 4);
-
-			let z = x.as_mut();
-			*z = 10;
-			assert_eq!(*z, 10);
-			let a = x.clone().unwrap();
-			let b = a.as_ref();
-			assert_eq!(*b, 10);
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		let _alloc = AllocGuard::new();
+		let mut x = Box::new(4).unwrap();
+		let y = x.as_ref();
+		assert_eq!(*y, 4);
+
+		let z = x.as_mut();
+		*z = 10;
+		assert_eq!(*z, 10);
+		let a = x.clone().unwrap();
+		let b = a.as_ref();
+		assert_eq!(*b, 10);
 	}
 
 	trait GetData {
@@ -187,21 +193,17 @@ mod test {
 
 	#[test]
 	fn test_box2() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut b1: Box<TestSample> = Box::new(TestSample { data: 1 }).unwrap();
-			b1.leak();
-			let b2: Box<dyn GetData> = Box::from_raw(Ptr::new(b1.as_ptr().raw()));
-			assert_eq!(b2.get_data(), 1);
+		let _alloc = AllocGuard::new();
+		let mut b1: Box<TestSample> = Box::new(TestSample { data: 1 }).unwrap();
+		b1.leak();
+		let b2: Box<dyn GetData> = Box::from_raw(Ptr::new(b1.as_ptr().raw()));
+		assert_eq!(b2.get_data(), 1);
 
-			let b3: Box<dyn GetData> = Box::new(TestSample { data: 2 }).unwrap();
-			assert_eq!(b3.get_data(), 2);
+		let b3: Box<dyn GetData> = Box::new(TestSample { data: 2 }).unwrap();
+		assert_eq!(b3.get_data(), 2);
 
-			let b4 = Box::new(|x| 5 + x).unwrap();
-			assert_eq!(b4(5), 10);
-		}
-
-		assert_eq!(initial, unsafe { getalloccount() });
+		let b4 = Box::new(|x| 5 + x).unwrap();
+		assert_eq!(b4(5), 10);
 	}
 
 	struct BoxTest<CLOSURE>
@@ -219,52 +221,46 @@ mod test {
 
 	#[test]
 	fn test_box3() {
-		let initial = unsafe { getalloccount() };
-		{
-			let x = BoxTest {
-				x: Box::new(TestSample { data: 8 }).unwrap(),
-				y: Box::new(|x| x + 4).unwrap(),
-				z: Box::new([3u8; 32]).unwrap(),
-			};
+		let _alloc = AllocGuard::new();
+		let x = BoxTest {
+			x: Box::new(TestSample { data: 8 }).unwrap(),
+			y: Box::new(|x| x + 4).unwrap(),
+			z: Box::new([3u8; 32]).unwrap(),
+		};
 
-			assert_eq!(x.x.get_data(), 8);
-			assert_eq!((x.y)(14), 18);
-			assert_eq!(x.z[5], 3u8);
+		assert_eq!(x.x.get_data(), 8);
+		assert_eq!((x.y)(14), 18);
+		assert_eq!(x.z[5], 3u8);
 
-			let y = BoxTest2 {
-				v: Box::new([5u64; 40]).unwrap(),
-			};
+		let y = BoxTest2 {
+			v: Box::new([5u64; 40]).unwrap(),
+		};
 
-			assert_eq!(y.v[9], 5);
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(y.v[9], 5);
 	}
 	#[test]
 	fn test_box4() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut box1 = Box::new([9u8; 992]).unwrap();
-			for i in 0..992 {
-				assert_eq!(9u8, box1.as_ref()[i]);
-			}
-			let box1_mut = box1.as_mut();
-			for i in 0..992 {
-				box1_mut[i] = 8;
-			}
-			for i in 0..992 {
-				assert_eq!(8u8, box1.as_ref()[i]);
-			}
+		let _alloc = AllocGuard::new();
+		let mut box1 = Box::new([9u8; 992]).unwrap();
+		for i in 0..992 {
+			assert_eq!(9u8, box1.as_ref()[i]);
+		}
+		let box1_mut = box1.as_mut();
+		for i in 0..992 {
+			box1_mut[i] = 8;
+		}
+		for i in 0..992 {
+			assert_eq!(8u8, box1.as_ref()[i]);
+		}
 
-			let mut box2 = Box::new_zeroed_byte_slice(20000).unwrap();
-			for i in 0..20000 {
-				box2.as_mut()[i] = 10;
-			}
+		let mut box2 = Box::new_zeroed_byte_slice(20000).unwrap();
+		for i in 0..20000 {
+			box2.as_mut()[i] = 10;
+		}
 
-			for i in 0..20000 {
-				assert_eq!(box2.as_ref()[i], 10);
-			}
+		for i in 0..20000 {
+			assert_eq!(box2.as_ref()[i], 10);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
 	}
 
 	static mut COUNT: i32 = 0;
@@ -284,15 +280,13 @@ mod test {
 
 	#[test]
 	fn test_drop_box() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 		{
 			let _big = Box::<[u8]>::new_zeroed_byte_slice(100000);
 			let _v = Box::new(DropBox { x: 1 }).unwrap();
 			assert_eq!(unsafe { COUNT }, 0);
 		}
 		assert_eq!(unsafe { COUNT }, 1);
-
-		assert_eq!(initial, unsafe { getalloccount() });
 	}
 
 	static mut CLONE_DROP_COUNT: i32 = 0;
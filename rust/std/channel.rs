@@ -1,11 +1,15 @@
 use core::marker::PhantomData;
-use core::ptr;
 use ffi::{
-	channel_destroy, channel_handle_size, channel_init, channel_pending, channel_recv,
-	channel_send, release,
+	channel_destroy, channel_handle_size, channel_init, channel_len, channel_pending, channel_recv,
+	channel_recv_timeout, channel_send,
 };
 use prelude::*;
 
+// return code of channel_send when the channel is full and it was asked not to block
+const CHANNEL_FULL: i32 = -2;
+// released messages kept by each channel for later sends
+const MESSAGE_POOL_SIZE: usize = 64;
+
 #[repr(C)]
 struct ChannelMessage<T> {
 	_reserved: u64,
@@ -13,39 +17,63 @@ struct ChannelMessage<T> {
 }
 
 struct ChannelInner<T> {
-	handle: [u8; 128],
+	handle: [u8; 256],
+	pool: Pool<ChannelMessage<T>>,
 	_marker: PhantomData<T>,
 }
 
 pub struct Sender<T> {
-	inner: Rc<ChannelInner<T>>,
+	inner: Arc<ChannelInner<T>>,
 }
 
 pub struct Receiver<T> {
-	inner: Rc<ChannelInner<T>>,
+	inner: Arc<ChannelInner<T>>,
 }
 
 pub fn channel<T>() -> Result<(Sender<T>, Receiver<T>), Error> {
-	if unsafe { channel_handle_size() } > 128 {
-		exit!("channel_handle_size() > 128");
+	channel_impl(0)
+}
+
+/// A channel holding at most capacity messages. Once it is full send waits for the receiver
+/// to take a message and try_send fails with ChannelFull, so producers can choose between
+/// backpressure and shedding load.
+pub fn channel_bounded<T>(capacity: u64) -> Result<(Sender<T>, Receiver<T>), Error> {
+	if capacity == 0 {
+		return Err(err!(IllegalArgument));
 	}
-	let handle = [0u8; 128];
-	let send_inner = match Rc::new(ChannelInner {
+	channel_impl(capacity)
+}
+
+fn channel_impl<T>(capacity: u64) -> Result<(Sender<T>, Receiver<T>), Error> {
+	if unsafe { channel_handle_size() } > 256 {
+		exit!("channel_handle_size() > 256");
+	}
+	let handle = [0u8; 256];
+	let pool = match Pool::new(MESSAGE_POOL_SIZE) {
+		Ok(pool) => pool,
+		Err(e) => return Err(e),
+	};
+	let mut send_inner = match Arc::new(ChannelInner {
 		handle,
+		pool,
 		_marker: PhantomData,
 	}) {
 		Ok(inner) => inner,
 		Err(e) => return Err(e),
 	};
 
-	// SAFETY: rc.clone does not fail
-	let mut recv_inner = send_inner.clone().unwrap();
-
-	if unsafe { channel_init(&mut recv_inner.handle as *mut u8) } < 0 {
-		Err(err!(ChannelInit))
-	} else {
-		Ok((Sender { inner: send_inner }, Receiver { inner: recv_inner }))
+	// initialized in place while this is the only reference
+	let handle = match send_inner.get_mut() {
+		Some(inner) => &mut inner.handle as *mut u8,
+		None => return Err(err!(IllegalState)),
+	};
+	if unsafe { channel_init(handle, capacity) } < 0 {
+		return Err(err!(ChannelInit));
 	}
+
+	// SAFETY: arc.clone does not fail
+	let recv_inner = send_inner.clone().unwrap();
+	Ok((Sender { inner: send_inner }, Receiver { inner: recv_inner }))
 }
 
 impl<T> Drop for ChannelInner<T> {
@@ -64,29 +92,43 @@ impl<T> ChannelInner<T> {
 	pub fn recv(&self) -> T {
 		let handle = &self.handle;
 		let recv = unsafe { channel_recv(handle as *const u8) } as *mut ChannelMessage<T>;
-		let ptr = Ptr::new(recv);
-		let mut nbox = Box::from_raw(ptr);
-		nbox.leak();
-		let v = unsafe { ptr::read(nbox.as_ptr().raw()) };
-		unsafe {
-			release(recv as *mut u8);
+		self.take(recv)
+	}
+
+	pub fn recv_timeout(&self, micros: u64) -> Option<T> {
+		let handle = &self.handle;
+		let recv =
+			unsafe { channel_recv_timeout(handle as *const u8, micros) } as *mut ChannelMessage<T>;
+		if recv.is_null() {
+			None
+		} else {
+			Some(self.take(recv))
 		}
-		v.value
 	}
 
-	pub fn send(&self, value: T) -> Result<(), Error> {
+	// moves the value out of a message taken from the queue and returns the message to the
+	// pool
+	fn take(&self, recv: *mut ChannelMessage<T>) -> T {
+		self.pool.take(Ptr::new(recv)).value
+	}
+
+	pub fn send(&self, value: T, block: bool) -> Result<(), Error> {
 		let msg = ChannelMessage {
 			_reserved: 0,
 			value,
 		};
-		match Box::new(msg) {
-			Ok(mut b) => {
-				b.leak();
+		match self.pool.alloc(msg) {
+			Ok(ptr) => {
 				let handle = &self.handle;
-				if unsafe { channel_send(handle as *const u8, b.as_ptr().raw() as *mut u8) } < 0 {
-					Err(err!(ChannelSend))
-				} else {
-					Ok(())
+				let ptr = ptr.raw();
+				match unsafe { channel_send(handle as *const u8, ptr as *mut u8, block) } {
+					0 => Ok(()),
+					CHANNEL_FULL => {
+						// the message was not queued, return it and drop the value
+						let _v = self.take(ptr);
+						Err(err!(ChannelFull))
+					}
+					_ => Err(err!(ChannelSend)),
 				}
 			}
 			Err(e) => Err(e),
@@ -96,11 +138,21 @@ impl<T> ChannelInner<T> {
 	pub fn pending(&self) -> bool {
 		unsafe { channel_pending(&self.handle as *const u8) }
 	}
+
+	pub fn len(&self) -> usize {
+		unsafe { channel_len(&self.handle as *const u8) as usize }
+	}
 }
 
+// the C channel does its own locking, so any thread may send or receive
+unsafe impl<T: Send> Send for Sender<T> {}
+unsafe impl<T: Send> Sync for Sender<T> {}
+unsafe impl<T: Send> Send for Receiver<T> {}
+unsafe impl<T: Send> Sync for Receiver<T> {}
+
 impl<T> Clone for Sender<T> {
 	fn clone(&self) -> Result<Self, Error> {
-		// SAFETY: clone always succeeds on rc
+		// SAFETY: clone always succeeds on arc
 		Ok(Self {
 			inner: self.inner.clone().unwrap(),
 		})
@@ -109,7 +161,7 @@ impl<T> Clone for Sender<T> {
 
 impl<T> Clone for Receiver<T> {
 	fn clone(&self) -> Result<Self, Error> {
-		// SAFETY: clone always succeeds on rc
+		// SAFETY: clone always succeeds on arc
 		Ok(Self {
 			inner: self.inner.clone().unwrap(),
 		})
@@ -117,8 +169,15 @@ impl<T> Clone for Receiver<T> {
 }
 
 impl<T> Sender<T> {
+	/// Queues value, waiting for room first if the channel is bounded and full
 	pub fn send(&self, value: T) -> Result<(), Error> {
-		self.inner.send(value)
+		self.inner.send(value, true)
+	}
+
+	/// Like send but fails with ChannelFull instead of waiting, dropping value. The same as
+	/// send on unbounded channels.
+	pub fn try_send(&self, value: T) -> Result<(), Error> {
+		self.inner.send(value, false)
 	}
 }
 
@@ -127,134 +186,156 @@ impl<T> Receiver<T> {
 		self.inner.recv()
 	}
 
+	/// Returns the next message if one is waiting, without blocking
+	pub fn try_recv(&self) -> Option<T> {
+		if self.inner.pending() {
+			// another clone of this receiver may take the message first
+			self.inner.recv_timeout(0)
+		} else {
+			None
+		}
+	}
+
+	/// Like recv but gives up with a Timeout error if no message arrives within micros
+	pub fn recv_timeout(&self, micros: u64) -> Result<T, Error> {
+		match self.inner.recv_timeout(micros) {
+			Some(v) => Ok(v),
+			None => Err(err!(Timeout)),
+		}
+	}
+
 	pub fn pending(&self) -> bool {
 		self.inner.pending()
 	}
+
+	/// Number of messages waiting to be received
+	pub fn len(&self) -> usize {
+		self.inner.len()
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
-	use ffi::getalloccount;
 
 	#[test]
 	fn test_channel_std() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (sender, receiver) = channel().unwrap();
-			let lock = lock!();
-			let rc = Rc::new(1).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
-			let mut jh = spawnj(|| {
-				let v = receiver.recv();
-				assert_eq!(v, 101);
-				let _v = lock.write();
-				assert_eq!(*rc_clone, 1);
-				*rc_clone += 1;
-				assert_eq!(*rc_clone, 2);
-			})
-			.unwrap();
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let rc = Arc::new(AtomicU64::new(1)).unwrap();
+		let rc_clone = rc.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let v = receiver.recv();
+			assert_eq!(v, 101);
+			let _v = lock_clone.write();
+			assert_eq!(rc_clone.load(), 1);
+			rc_clone.fetch_add(1);
+			assert_eq!(rc_clone.load(), 2);
+		})
+		.unwrap();
 
-			sender.send(101).unwrap();
+		sender.send(101).unwrap();
 
-			loop {
-				{
-					let _v = lock.read();
-					if *rc == 1 {
-					} else {
-						assert_eq!(*rc, 2);
-						break;
-					}
-				}
-				unsafe {
-					crate::ffi::sleep_millis(1);
+		loop {
+			{
+				let _v = lock.read();
+				if rc.load() == 1 {
+				} else {
+					assert_eq!(rc.load(), 2);
+					break;
 				}
 			}
-			assert!(jh.join().is_ok());
+			unsafe {
+				crate::ffi::sleep_millis(1);
+			}
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert!(jh.join().is_ok());
 	}
 
 	#[test]
 	fn test_channel_clone() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (sender, receiver) = channel().unwrap();
-			let _sender2: Sender<i32> = sender.clone().unwrap();
-			let _recevier2: Receiver<i32> = receiver.clone().unwrap();
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		let _sender2: Sender<i32> = sender.clone().unwrap();
+		let _recevier2: Receiver<i32> = receiver.clone().unwrap();
+	}
+
+	#[test]
+	fn test_channel_len() {
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		assert_eq!(receiver.len(), 0);
+		for i in 0..3 {
+			sender.send(i).unwrap();
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(receiver.len(), 3);
+		assert_eq!(receiver.recv(), 0);
+		assert_eq!(receiver.len(), 2);
 	}
 
 	#[test]
 	fn test_channel_move_std() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (sender, receiver) = channel().unwrap();
-			let lock = lock_box!().unwrap();
-			let lock_clone = lock.clone().unwrap();
-			let rc = Rc::new(1).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
-			let mut jh = spawnj(move || {
-				let v = receiver.recv();
-				assert_eq!(v, 101);
-				let _v = lock_clone.write();
-				assert_eq!(*rc_clone, 1);
-				*rc_clone += 1;
-				assert_eq!(*rc_clone, 2);
-			})
-			.unwrap();
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let rc = Arc::new(AtomicU64::new(1)).unwrap();
+		let rc_clone = rc.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let v = receiver.recv();
+			assert_eq!(v, 101);
+			let _v = lock_clone.write();
+			assert_eq!(rc_clone.load(), 1);
+			rc_clone.fetch_add(1);
+			assert_eq!(rc_clone.load(), 2);
+		})
+		.unwrap();
 
-			sender.send(101).unwrap();
+		sender.send(101).unwrap();
 
-			loop {
-				{
-					let _v = lock.read();
-					if *rc == 1 {
-					} else {
-						assert_eq!(*rc, 2);
-						break;
-					}
-				}
-				unsafe {
-					crate::ffi::sleep_millis(1);
+		loop {
+			{
+				let _v = lock.read();
+				if rc.load() == 1 {
+				} else {
+					assert_eq!(rc.load(), 2);
+					break;
 				}
 			}
-			assert!(jh.join().is_ok());
+			unsafe {
+				crate::ffi::sleep_millis(1);
+			}
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert!(jh.join().is_ok());
 	}
 
 	#[test]
 	fn test_channel_result() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (sender, receiver) = channel().unwrap();
-			let (sender2, receiver2) = channel().unwrap();
-			let lock = lock_box!().unwrap();
-			let lock_clone = lock.clone().unwrap();
-			let rc = Rc::new(0).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
-
-			let mut jh = spawnj(move || {
-				{
-					let input = receiver.recv();
-					let _v = lock_clone.write();
-					*rc_clone = input + 100;
-				}
-				sender2.send(()).unwrap();
-			})
-			.unwrap();
-
-			sender.send(301).unwrap();
-			let result = receiver2.recv();
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		let (sender2, receiver2) = channel().unwrap();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let rc = Arc::new(AtomicU64::new(0)).unwrap();
+		let rc_clone = rc.clone().unwrap();
+
+		let mut jh = spawnj(move || {
+			{
+				let input: u64 = receiver.recv();
+				let _v = lock_clone.write();
+				rc_clone.store(input + 100);
+			}
+			sender2.send(()).unwrap();
+		})
+		.unwrap();
 
-			assert_eq!(result, ());
-			assert_eq!(*rc, 401);
+		sender.send(301).unwrap();
+		receiver2.recv();
+		assert_eq!(rc.load(), 401);
 
-			assert!(jh.join().is_ok());
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert!(jh.join().is_ok());
 	}
 
 	struct DropTest {
@@ -275,20 +356,20 @@ mod test {
 
 	#[test]
 	fn test_channel_drop() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 		{
 			let (sender, receiver) = channel().unwrap();
 			let (sender2, receiver2) = channel().unwrap();
 			let lock = lock_box!().unwrap();
 			let lock_clone = lock.clone().unwrap();
-			let rc = Rc::new(0).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
+			let rc = Arc::new(AtomicU64::new(0)).unwrap();
+			let rc_clone = rc.clone().unwrap();
 
 			let mut jh = spawnj(move || {
 				{
 					let input: DropTest = receiver.recv();
 					let _v = lock_clone.write();
-					*rc_clone = input.x + 100;
+					rc_clone.store(input.x as u64 + 100);
 					assert_eq!(unsafe { DROPCOUNT }, 0);
 				}
 				assert_eq!(unsafe { DROPCOUNT }, 1);
@@ -300,42 +381,100 @@ mod test {
 			let result = receiver2.recv();
 
 			assert_eq!(result.x, 4);
-			assert_eq!(*rc, 401);
+			assert_eq!(rc.load(), 401);
 			assert!(jh.join().is_ok());
 			assert_eq!(unsafe { DROPCOUNT }, 1);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
 		assert_eq!(unsafe { DROPCOUNT }, 2);
 		assert_eq!(unsafe { DROPSUM }, 305);
 	}
 
+	#[test]
+	fn test_channel_try_recv_timeout() {
+		let _alloc = AllocGuard::new();
+		let (sender, receiver) = channel().unwrap();
+		assert!(receiver.try_recv().is_none());
+		let start = getmicros!();
+		assert!(receiver.recv_timeout(20_000).is_err());
+		assert!(getmicros!() - start >= 20_000);
+
+		sender.send(1).unwrap();
+		sender.send(2).unwrap();
+		assert!(receiver.try_recv() == Some(1));
+		assert!(receiver.recv_timeout(0) == Ok(2));
+		assert_eq!(receiver.len(), 0);
+
+		// a message sent while waiting ends the wait early
+		let sender2 = sender.clone().unwrap();
+		let mut jh = spawnj(move || {
+			unsafe {
+				crate::ffi::sleep_millis(10);
+			}
+			sender2.send(3).unwrap();
+		})
+		.unwrap();
+		assert!(receiver.recv_timeout(10_000_000) == Ok(3));
+		assert!(jh.join().is_ok());
+	}
+
 	#[test]
 	fn test_cleanup() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (send, _recv) = channel().unwrap();
-			send.send(0).unwrap();
-			send.send(0).unwrap();
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		let _alloc = AllocGuard::new();
+		let (send, _recv) = channel().unwrap();
+		send.send(0).unwrap();
+		send.send(0).unwrap();
 	}
 
 	#[test]
 	fn test_multisend_chan() {
-		let initial = unsafe { getalloccount() };
-		{
-			let (channel, recv) = channel().unwrap();
-			channel.send(0).unwrap();
-			channel.send(1).unwrap();
-			channel.send(2).unwrap();
-			channel.send(3).unwrap();
-			channel.send(4).unwrap();
-			channel.send(5).unwrap();
-
-			assert_eq!(recv.recv(), 0);
-			assert_eq!(recv.recv(), 1);
-			assert_eq!(recv.recv(), 2);
+		let _alloc = AllocGuard::new();
+		let (channel, recv) = channel().unwrap();
+		channel.send(0).unwrap();
+		channel.send(1).unwrap();
+		channel.send(2).unwrap();
+		channel.send(3).unwrap();
+		channel.send(4).unwrap();
+		channel.send(5).unwrap();
+
+		assert_eq!(recv.recv(), 0);
+		assert_eq!(recv.recv(), 1);
+		assert_eq!(recv.recv(), 2);
+	}
+
+	#[test]
+	fn test_channel_bounded() {
+		// values own allocations so that the guard catches a rejected value that is not dropped
+		let _alloc = AllocGuard::new();
+		assert!(channel_bounded::<u32>(0).is_err());
+		let (sender, receiver) = channel_bounded(2).unwrap();
+		sender.send(String::new("a").unwrap()).unwrap();
+		sender.try_send(String::new("b").unwrap()).unwrap();
+		match sender.try_send(String::new("c").unwrap()) {
+			Ok(_) => panic!("sent on a full channel"),
+			Err(e) => assert!(e.kind == ChannelFull),
+		}
+		assert_eq!(receiver.len(), 2);
+		assert_eq!(receiver.recv().to_str(), "a");
+		sender.try_send(String::new("d").unwrap()).unwrap();
+
+		// a blocked sender resumes once the receiver makes room
+		let sender2 = sender.clone().unwrap();
+		let sent = Arc::new(AtomicU64::new(0)).unwrap();
+		let sent_clone = sent.clone().unwrap();
+		let mut jh = spawnj(move || {
+			sender2.send(String::new("e").unwrap()).unwrap();
+			sent.store(1);
+		})
+		.unwrap();
+		unsafe {
+			crate::ffi::sleep_millis(20);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(sent_clone.load(), 0);
+		assert_eq!(receiver.recv().to_str(), "b");
+		assert!(jh.join().is_ok());
+		assert_eq!(sent_clone.load(), 1);
+		assert_eq!(receiver.recv().to_str(), "d");
+		assert_eq!(receiver.recv().to_str(), "e");
+		assert!(receiver.try_recv().is_none());
 	}
 }
@@ -0,0 +1,390 @@
+//! # Deque
+//! A growable ring buffer with O(1) push and pop at both ends. Elements occupy a power of
+//! two buffer starting at head and wrapping around its end, so the front of the deque is
+//! always available as one contiguous slice, which is what a writer draining a queue of
+//! bytes into a socket needs. Removing from the front never moves the remaining elements.
+
+use core::iter::Iterator;
+use core::marker::{Copy, PhantomData};
+use core::mem::{needs_drop, size_of};
+use core::ops::{Drop, Index, IndexMut};
+use core::option::Option as CoreOption;
+use core::ptr::{copy_nonoverlapping, drop_in_place, read, write};
+use core::slice::from_raw_parts;
+use ffi::alloc;
+use prelude::*;
+
+const MIN_CAPACITY: usize = 16;
+
+pub struct Deque<T> {
+	value: Ptr<u8>,
+	capacity: usize,
+	head: usize,
+	elements: usize,
+	_marker: PhantomData<T>,
+}
+
+pub struct DequeIterator<'a, T> {
+	deque: &'a Deque<T>,
+	index: usize,
+}
+
+impl<'a, T> Iterator for DequeIterator<'a, T> {
+	type Item = &'a T;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		if self.index < self.deque.elements {
+			let v = &self.deque[self.index];
+			self.index += 1;
+			CoreOption::Some(v)
+		} else {
+			CoreOption::None
+		}
+	}
+}
+
+unsafe impl<T: Send> Send for Deque<T> {}
+unsafe impl<T: Sync> Sync for Deque<T> {}
+
+impl<T> Drop for Deque<T> {
+	fn drop(&mut self) {
+		self.drop_front(self.elements);
+		if !self.value.is_null() {
+			self.value.release();
+		}
+	}
+}
+
+impl<T> Index<usize> for Deque<T> {
+	type Output = T;
+
+	fn index(&self, index: usize) -> &Self::Output {
+		if index >= self.elements {
+			panic!("array index out of bounds!");
+		}
+		unsafe { &*self.slot(index) }
+	}
+}
+
+impl<T> IndexMut<usize> for Deque<T> {
+	fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+		if index >= self.elements {
+			panic!("array index out of bounds!");
+		}
+		unsafe { &mut *self.slot(index) }
+	}
+}
+
+impl<T> Deque<T> {
+	/// An empty deque. Nothing is allocated until the first element is added.
+	pub fn new() -> Self {
+		Self {
+			value: Ptr::null(),
+			capacity: 0,
+			head: 0,
+			elements: 0,
+			_marker: PhantomData,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.elements
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.elements == 0
+	}
+
+	/// Number of elements that fit without reallocating
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Ensures there is room for at least additional more elements
+	pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+		let needed = self.elements + additional;
+		if needed <= self.capacity {
+			return Ok(());
+		}
+		let mut ncapacity = if self.capacity == 0 {
+			MIN_CAPACITY
+		} else {
+			self.capacity
+		};
+		while ncapacity < needed {
+			ncapacity *= 2;
+		}
+		let nptr = unsafe { alloc(ncapacity * size_of::<T>()) } as *mut T;
+		if nptr.is_null() {
+			return Err(err!(Alloc));
+		}
+		// unwrap the elements to the start of the new buffer
+		let (front, back) = self.as_slices();
+		unsafe {
+			copy_nonoverlapping(front.as_ptr(), nptr, front.len());
+			copy_nonoverlapping(back.as_ptr(), nptr.add(front.len()), back.len());
+		}
+		if !self.value.is_null() {
+			self.value.release();
+		}
+		self.value = Ptr::new(nptr as *mut u8);
+		self.capacity = ncapacity;
+		self.head = 0;
+		Ok(())
+	}
+
+	pub fn push_back(&mut self, v: T) -> Result<(), Error> {
+		match self.reserve(1) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		unsafe {
+			write(self.slot(self.elements), v);
+		}
+		self.elements += 1;
+		Ok(())
+	}
+
+	pub fn push_front(&mut self, v: T) -> Result<(), Error> {
+		match self.reserve(1) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		self.head = (self.head + self.capacity - 1) & (self.capacity - 1);
+		self.elements += 1;
+		unsafe {
+			write(self.slot(0), v);
+		}
+		Ok(())
+	}
+
+	pub fn pop_front(&mut self) -> Option<T> {
+		if self.elements == 0 {
+			return None;
+		}
+		let v = unsafe { read(self.slot(0)) };
+		self.head = (self.head + 1) & (self.capacity - 1);
+		self.elements -= 1;
+		Some(v)
+	}
+
+	pub fn pop_back(&mut self) -> Option<T> {
+		if self.elements == 0 {
+			return None;
+		}
+		self.elements -= 1;
+		Some(unsafe { read(self.slot(self.elements)) })
+	}
+
+	pub fn front(&self) -> Option<&T> {
+		if self.elements == 0 {
+			None
+		} else {
+			Some(&self[0])
+		}
+	}
+
+	pub fn back(&self) -> Option<&T> {
+		if self.elements == 0 {
+			None
+		} else {
+			Some(&self[self.elements - 1])
+		}
+	}
+
+	/// Drops the first n elements. OutOfBounds if there are fewer than n.
+	pub fn shift(&mut self, n: usize) -> Result<(), Error> {
+		if n > self.elements {
+			return Err(err!(OutOfBounds));
+		}
+		self.drop_front(n);
+		if n > 0 {
+			self.head = (self.head + n) & (self.capacity - 1);
+			self.elements -= n;
+		}
+		Ok(())
+	}
+
+	/// The elements from the front up to the end of the buffer or of the deque, whichever
+	/// comes first. Empty only if the deque is.
+	pub fn front_slice(&self) -> &[T] {
+		self.as_slices().0
+	}
+
+	/// The elements in order, as the run from head to the end of the buffer and the run
+	/// which wrapped around to its start
+	pub fn as_slices(&self) -> (&[T], &[T]) {
+		if self.value.is_null() {
+			return (&[], &[]);
+		}
+		let first = if self.head + self.elements > self.capacity {
+			self.capacity - self.head
+		} else {
+			self.elements
+		};
+		let base = self.value.raw() as *const T;
+		unsafe {
+			(
+				from_raw_parts(base.add(self.head), first),
+				from_raw_parts(base, self.elements - first),
+			)
+		}
+	}
+
+	pub fn iter(&self) -> DequeIterator<'_, T> {
+		DequeIterator {
+			deque: self,
+			index: 0,
+		}
+	}
+
+	/// Drops every element and releases the buffer
+	pub fn clear(&mut self) {
+		self.drop_front(self.elements);
+		if !self.value.is_null() {
+			self.value.release();
+		}
+		self.value = Ptr::null();
+		self.capacity = 0;
+		self.head = 0;
+		self.elements = 0;
+	}
+
+	fn slot(&self, index: usize) -> *mut T {
+		unsafe { (self.value.raw() as *mut T).add((self.head + index) & (self.capacity - 1)) }
+	}
+
+	// drops the values of the first n elements without removing them
+	fn drop_front(&mut self, n: usize) {
+		if needs_drop::<T>() {
+			for i in 0..n {
+				unsafe {
+					drop_in_place(self.slot(i));
+				}
+			}
+		}
+	}
+}
+
+impl<T: Copy> Deque<T> {
+	/// Appends a copy of values to the back
+	pub fn extend_from_slice(&mut self, values: &[T]) -> Result<(), Error> {
+		if values.len() == 0 {
+			return Ok(());
+		}
+		match self.reserve(values.len()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let tail = (self.head + self.elements) & (self.capacity - 1);
+		let first = if tail + values.len() > self.capacity {
+			self.capacity - tail
+		} else {
+			values.len()
+		};
+		let base = self.value.raw() as *mut T;
+		unsafe {
+			copy_nonoverlapping(values.as_ptr(), base.add(tail), first);
+			copy_nonoverlapping(values.as_ptr().add(first), base, values.len() - first);
+		}
+		self.elements += values.len();
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_deque() {
+		let _alloc = AllocGuard::new();
+		let mut d = Deque::new();
+		assert!(d.is_empty());
+		assert!(d.pop_front().is_none() && d.pop_back().is_none());
+		assert_eq!(d.front_slice().len(), 0);
+		for i in 0..10u64 {
+			d.push_back(i).unwrap();
+		}
+		for i in 1..4u64 {
+			d.push_front(100 + i).unwrap();
+		}
+		assert_eq!(d.len(), 13);
+		assert_eq!(d.capacity(), 16);
+		assert_eq!(*d.front().unwrap(), 103);
+		assert_eq!(*d.back().unwrap(), 9);
+
+		// the elements pushed to the front wrapped to the end of the buffer
+		let (front, back) = d.as_slices();
+		assert_eq!(front, &[103, 102, 101]);
+		assert_eq!(back.len(), 10);
+		assert_eq!(d.front_slice(), &[103, 102, 101]);
+		assert!(d.pop_front() == Some(103));
+		assert!(d.pop_back() == Some(9));
+		d[0] += 1;
+		assert_eq!(d[0], 103);
+		assert_eq!(d[1], 101);
+
+		// growing unwraps the elements
+		for i in 9..20u64 {
+			d.push_back(i).unwrap();
+		}
+		assert_eq!(d.capacity(), 32);
+		assert_eq!(d.front_slice().len(), 22);
+		let mut expected = 0;
+		for (i, v) in d.iter().enumerate() {
+			if i >= 2 {
+				assert_eq!(*v, expected);
+				expected += 1;
+			}
+		}
+		assert_eq!(expected, 20);
+
+		assert!(d.shift(23).unwrap_err().kind == OutOfBounds);
+		d.shift(12).unwrap();
+		assert_eq!(d[0], 10);
+		assert_eq!(d.len(), 10);
+		d.clear();
+		assert!(d.is_empty());
+		assert_eq!(d.capacity(), 0);
+	}
+
+	#[test]
+	fn test_deque_bytes() {
+		let _alloc = AllocGuard::new();
+		let mut d = Deque::new();
+		d.extend_from_slice(b"0123456789").unwrap();
+		d.shift(8).unwrap();
+		// wraps around the end of the 16 byte buffer
+		d.extend_from_slice(b"abcdefghij").unwrap();
+		assert_eq!(d.capacity(), 16);
+		let (front, back) = d.as_slices();
+		assert_eq!(front, b"89abcdef");
+		assert_eq!(back, b"ghij");
+		d.shift(8).unwrap();
+		assert_eq!(d.front_slice(), b"ghij");
+		d.extend_from_slice(&[b'x'; 30]).unwrap();
+		assert_eq!(d.capacity(), 64);
+		assert_eq!(d.len(), 34);
+		assert_eq!(&d.front_slice()[0..6], b"ghijxx");
+	}
+
+	#[test]
+	fn test_deque_drop() {
+		let _alloc = AllocGuard::new();
+		let mut d = Deque::new();
+		for i in 0..40u64 {
+			let mut v = Vec::new();
+			v.push(i).unwrap();
+			if i % 2 == 0 {
+				d.push_back(v).unwrap();
+			} else {
+				d.push_front(v).unwrap();
+			}
+		}
+		assert_eq!(d.pop_back().unwrap()[0], 38);
+		assert_eq!(d.pop_front().unwrap()[0], 39);
+		d.shift(5).unwrap();
+		assert_eq!(d[0][0], 27);
+	}
+}
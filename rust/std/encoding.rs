@@ -0,0 +1,310 @@
+//! # Hex and Base58
+//! Text encodings of byte strings. Hex is used to show keys, signatures and commitments,
+//! Base58Check (Base58 followed by the first four bytes of the sha256d of the payload) for
+//! addresses and extended keys, where a mistyped character must be caught.
+
+use prelude::*;
+use std::hash::sha256d;
+
+/// Length of the checksum appended by base58check_encode
+pub const BASE58_CHECKSUM_SIZE: usize = 4;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+const HEX_DIGITS_UPPER: &[u8; 16] = b"0123456789ABCDEF";
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Lower case hex of bytes
+pub fn hex_encode(bytes: &[u8]) -> Result<String, Error> {
+	let mut out = Vec::new();
+	match out.resize(bytes.len() * 2) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	for i in 0..bytes.len() {
+		out[2 * i] = HEX_DIGITS[(bytes[i] >> 4) as usize];
+		out[2 * i + 1] = HEX_DIGITS[(bytes[i] & 0xf) as usize];
+	}
+	String::from_utf8(out.as_slice())
+}
+
+/// Writes the hex of bytes without allocating, for the Display impls of types which wrap
+/// binary data. The digits are upper case for {:X}.
+pub fn hex_format(bytes: &[u8], f: &mut Formatter) -> Result<(), Error> {
+	let digits = if f.spec().upper {
+		HEX_DIGITS_UPPER
+	} else {
+		HEX_DIGITS
+	};
+	let mut buf = [0u8; 64];
+	let mut offset = 0;
+	while offset < bytes.len() {
+		let n = if bytes.len() - offset < buf.len() / 2 {
+			bytes.len() - offset
+		} else {
+			buf.len() / 2
+		};
+		for i in 0..n {
+			buf[2 * i] = digits[(bytes[offset + i] >> 4) as usize];
+			buf[2 * i + 1] = digits[(bytes[offset + i] & 0xf) as usize];
+		}
+		match f.write_str(unsafe { from_utf8_unchecked(&buf[0..2 * n]) }, 2 * n) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		offset += n;
+	}
+	Ok(())
+}
+
+fn hex_nibble(c: u8) -> Option<u8> {
+	match c {
+		b'0'..=b'9' => Some(c - b'0'),
+		b'a'..=b'f' => Some(c - b'a' + 10),
+		b'A'..=b'F' => Some(c - b'A' + 10),
+		_ => None,
+	}
+}
+
+/// Bytes of a hex string of either case. IllegalArgument if the length is odd or a
+/// character is not a hex digit.
+pub fn hex_decode(s: &str) -> Result<Vec<u8>, Error> {
+	let s = s.as_bytes();
+	if s.len() % 2 != 0 {
+		return Err(err!(IllegalArgument));
+	}
+	let mut out = Vec::new();
+	match out.resize(s.len() / 2) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	match hex_decode_into(s, out.as_mut_slice()) {
+		Ok(_) => Ok(out),
+		Err(e) => Err(e),
+	}
+}
+
+/// The N bytes of a hex string of either case, for fixed size values such as keys.
+/// IllegalArgument if it is not 2 * N hex digits.
+pub fn hex_decode_array<const N: usize>(s: &str) -> Result<[u8; N], Error> {
+	let s = s.as_bytes();
+	if s.len() != 2 * N {
+		return Err(err!(IllegalArgument));
+	}
+	let mut out = [0u8; N];
+	match hex_decode_into(s, &mut out) {
+		Ok(_) => Ok(out),
+		Err(e) => Err(e),
+	}
+}
+
+// decodes the first 2 * out.len() digits of s into out
+fn hex_decode_into(s: &[u8], out: &mut [u8]) -> Result<(), Error> {
+	for i in 0..out.len() {
+		match (hex_nibble(s[2 * i]), hex_nibble(s[2 * i + 1])) {
+			(Some(hi), Some(lo)) => out[i] = (hi << 4) | lo,
+			_ => return Err(err!(IllegalArgument)),
+		}
+	}
+	Ok(())
+}
+
+/// Base58 of bytes in the bitcoin alphabet, each leading zero byte written as a '1'
+pub fn base58_encode(bytes: &[u8]) -> Result<String, Error> {
+	let mut zeros = 0;
+	while zeros < bytes.len() && bytes[zeros] == 0 {
+		zeros += 1;
+	}
+	// log(256) / log(58) is just under 1.38 digits per byte
+	let size = (bytes.len() - zeros) * 138 / 100 + 1;
+	let mut digits = Vec::new();
+	match digits.resize(size) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	// digits[size - len..] holds the number read so far, most significant digit first
+	let mut len = 0;
+	for i in zeros..bytes.len() {
+		let mut carry = bytes[i] as u32;
+		let mut j = 0;
+		while j < len || carry != 0 {
+			let k = size - 1 - j;
+			carry += 256 * digits[k] as u32;
+			digits[k] = (carry % 58) as u8;
+			carry /= 58;
+			j += 1;
+		}
+		len = j;
+	}
+	let mut out = Vec::new();
+	match out.resize(zeros + len) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	for i in 0..zeros {
+		out[i] = BASE58_ALPHABET[0];
+	}
+	for i in 0..len {
+		out[zeros + i] = BASE58_ALPHABET[digits[size - len + i] as usize];
+	}
+	String::from_utf8(out.as_slice())
+}
+
+fn base58_digit(c: u8) -> Option<u8> {
+	for i in 0..BASE58_ALPHABET.len() {
+		if BASE58_ALPHABET[i] == c {
+			return Some(i as u8);
+		}
+	}
+	None
+}
+
+/// Bytes of a base58 string. IllegalArgument if a character is not in the alphabet.
+pub fn base58_decode(s: &str) -> Result<Vec<u8>, Error> {
+	let s = s.as_bytes();
+	let mut zeros = 0;
+	while zeros < s.len() && s[zeros] == BASE58_ALPHABET[0] {
+		zeros += 1;
+	}
+	// log(58) / log(256) is just under 0.733 bytes per digit
+	let size = (s.len() - zeros) * 733 / 1000 + 1;
+	let mut bytes = Vec::new();
+	match bytes.resize(size) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	let mut len = 0;
+	for i in zeros..s.len() {
+		let mut carry = match base58_digit(s[i]) {
+			Some(d) => d as u32,
+			None => return Err(err!(IllegalArgument)),
+		};
+		let mut j = 0;
+		while j < len || carry != 0 {
+			let k = size - 1 - j;
+			carry += 58 * bytes[k] as u32;
+			bytes[k] = (carry % 256) as u8;
+			carry /= 256;
+			j += 1;
+		}
+		len = j;
+	}
+	let mut out = Vec::new();
+	match out.resize(zeros + len) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	for i in 0..len {
+		out[zeros + i] = bytes[size - len + i];
+	}
+	Ok(out)
+}
+
+/// Base58 of payload followed by its checksum
+pub fn base58check_encode(payload: &[u8]) -> Result<String, Error> {
+	let mut data = Vec::new();
+	match data.resize(payload.len() + BASE58_CHECKSUM_SIZE) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	let hash = sha256d(payload);
+	data[0..payload.len()].copy_from_slice(payload);
+	data[payload.len()..payload.len() + BASE58_CHECKSUM_SIZE]
+		.copy_from_slice(&hash[0..BASE58_CHECKSUM_SIZE]);
+	base58_encode(data.as_slice())
+}
+
+/// The payload of a base58check string. IllegalArgument if a character is not in the
+/// alphabet, CorruptedData if the checksum is missing or does not match.
+pub fn base58check_decode(s: &str) -> Result<Vec<u8>, Error> {
+	let mut data = match base58_decode(s) {
+		Ok(data) => data,
+		Err(e) => return Err(e),
+	};
+	if data.len() < BASE58_CHECKSUM_SIZE {
+		return Err(err!(CorruptedData));
+	}
+	let n = data.len() - BASE58_CHECKSUM_SIZE;
+	let hash = sha256d(&data[0..n]);
+	if data[n..n + BASE58_CHECKSUM_SIZE] != hash[0..BASE58_CHECKSUM_SIZE] {
+		return Err(err!(CorruptedData));
+	}
+	data.truncate(n);
+	Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_hex() {
+		let _alloc = AllocGuard::new();
+		let s = hex_encode(&[0x00, 0x01, 0xab, 0xff]).unwrap();
+		assert_eq!(s.to_str(), "0001abff");
+		assert_eq!(hex_encode(&[]).unwrap().len(), 0);
+		assert_eq!(
+			hex_decode("0001abFF").unwrap().as_slice(),
+			&[0x00, 0x01, 0xab, 0xff]
+		);
+		assert_eq!(hex_decode("").unwrap().len(), 0);
+		assert!(hex_decode("abc").unwrap_err().kind == IllegalArgument);
+		assert!(hex_decode("zz").unwrap_err().kind == IllegalArgument);
+		assert_eq!(hex_decode_array::<2>("01aB").unwrap(), [0x01, 0xab]);
+		assert!(hex_decode_array::<2>("01ab00").unwrap_err().kind == IllegalArgument);
+		assert!(hex_decode_array::<2>("01a").is_err());
+		assert!(hex_decode_array::<2>("01ag").is_err());
+
+		// longer than the stack buffer of hex_format, and padded by the formatter
+		let mut bytes = [0u8; 40];
+		for i in 0..bytes.len() {
+			bytes[i] = i as u8;
+		}
+		let mut f = Formatter::new();
+		hex_format(&bytes, &mut f).unwrap();
+		assert_eq!(f.as_str(), hex_encode(&bytes).unwrap().to_str());
+		assert_eq!(hex_decode(f.as_str()).unwrap().as_slice(), &bytes);
+	}
+
+	#[test]
+	fn test_base58() {
+		let _alloc = AllocGuard::new();
+		// vectors from bitcoin core's base58_encode_decode.json
+		let vectors = [
+			("", ""),
+			("61", "2g"),
+			("626262", "a3gV"),
+			("636363", "aPEr"),
+			(
+				"73696d706c792061206c6f6e6720737472696e67",
+				"2cFupjhnEsSn59qHXstmK2ffpLv2",
+			),
+			(
+				"00eb15231dfceb60925886b67d065299925915aeb172c06647",
+				"1NS17iag9jJgTHD1VXjvLCEnZuQ3rJDE9L",
+			),
+			("516b6fcd0f", "ABnLTmg"),
+			("bf4f89001e670274dd", "3SEo3LWLoPntC"),
+			("572e4794", "3EFU7m"),
+			("ecac89cad93923c02321", "EJDM8drfXA6uyA"),
+			("10c8511e", "Rt5zm"),
+			("00000000000000000000", "1111111111"),
+		];
+		for (hex, b58) in vectors {
+			let bytes = hex_decode(hex).unwrap();
+			assert_eq!(base58_encode(bytes.as_slice()).unwrap().to_str(), b58);
+			assert_eq!(base58_decode(b58).unwrap().as_slice(), bytes.as_slice());
+		}
+		assert!(base58_decode("0OIl").unwrap_err().kind == IllegalArgument);
+
+		// the address paid by the genesis block, version byte 0 and a hash160
+		let payload = hex_decode("0062e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap();
+		let address = base58check_encode(payload.as_slice()).unwrap();
+		assert_eq!(address.to_str(), "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa");
+		let decoded = base58check_decode(address.to_str()).unwrap();
+		assert_eq!(decoded.as_slice(), payload.as_slice());
+		// one character changed
+		let e = base58check_decode("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb").unwrap_err();
+		assert!(e.kind == CorruptedData);
+		assert!(base58check_decode("2g").unwrap_err().kind == CorruptedData);
+	}
+}
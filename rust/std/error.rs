@@ -1,9 +1,12 @@
+use core::panic::Location;
 use core::ptr::null;
+use core::slice::from_raw_parts;
+use ffi::{cstring_len, errno_name as ffi_errno_name, socket_errno};
 use prelude::*;
 
 macro_rules! define_enum_with_strings {
     ($enum_name:ident { $($variant:ident),* $(,)? }) => {
-        #[derive(PartialEq)]
+        #[derive(PartialEq, Clone, Copy)]
         pub enum $enum_name {
             $($variant),*
         }
@@ -52,15 +55,83 @@ define_enum_with_strings!(ErrorKind {
 	IO,
 	Bind,
 	InsufficientFunds,
+	WouldBlock,
+	Utf8,
+	Unauthorized,
+	Timeout,
+	ChannelFull,
+	CondvarInit,
+	ThreadName,
+	ThreadAffinity,
+	Cancelled,
+	ConnectionRefused,
+	ConnectionReset,
+	BrokenPipe,
+	TooManyOpenFiles,
+	AddrInUse,
+	Interrupted,
+	InvalidMessage,
 	Todo,
 });
 
+impl ErrorKind {
+	/// The kind naming errno's condition, such as ConnectionRefused for ECONNREFUSED, or
+	/// None when errno says no more than the failed call does
+	pub fn from_errno(errno: i32) -> Option<ErrorKind> {
+		// matched by name since the values differ between linux and macos
+		match errno_name(errno) {
+			"ECONNREFUSED" => Some(ConnectionRefused),
+			"ECONNRESET" | "ECONNABORTED" => Some(ConnectionReset),
+			"EPIPE" => Some(BrokenPipe),
+			"EMFILE" | "ENFILE" => Some(TooManyOpenFiles),
+			"EADDRINUSE" => Some(AddrInUse),
+			"EINTR" => Some(Interrupted),
+			"EAGAIN" => Some(WouldBlock),
+			"ETIMEDOUT" => Some(Timeout),
+			_ => None,
+		}
+	}
+}
+
 #[derive(PartialEq)]
 pub struct Error {
 	pub kind: ErrorKind,
 	pub line: u32,
 	pub file: String,
 	pub backtrace: Backtrace,
+	/// errno of the failed system call behind this error, 0 if there was none
+	pub errno: i32,
+	/// what was being done when the error occurred, empty if nothing was attached
+	pub message: String,
+	/// the lower level error which caused this one
+	pub source: Option<Box<Error>>,
+}
+
+/// An error which costs nothing to build: the kind, location and errno of an Error without
+/// the copy of the file name or the backtrace. Returned on hot paths whose errors are mostly
+/// handled on the spot, such as parsing and writing frames, and converted into an Error
+/// with into() where it crosses an API boundary.
+#[derive(PartialEq, Clone, Copy)]
+pub struct StaticError {
+	pub kind: ErrorKind,
+	pub line: u32,
+	pub file: &'static str,
+	pub errno: i32,
+}
+
+/// errno of the calling thread. Must be read before anything else which may fail.
+pub fn last_errno() -> i32 {
+	unsafe { socket_errno() }
+}
+
+/// Symbolic name of errno such as "EMFILE", or "UNKNOWN" for values without one
+pub fn errno_name(errno: i32) -> &'static str {
+	let name = unsafe { ffi_errno_name(errno) };
+	if name.is_null() {
+		"UNKNOWN"
+	} else {
+		unsafe { from_utf8_unchecked(from_raw_parts(name, cstring_len(name))) }
+	}
 }
 
 impl Error {
@@ -89,24 +160,187 @@ impl Error {
 				Ok(file) => file,
 				Err(_) => String::empty(),
 			},
+			errno: 0,
+			message: String::empty(),
+			source: None,
+		}
+	}
+
+	/// An error of the given kind caused by inner, located where this is called. Display
+	/// prints the whole chain, outermost first.
+	#[track_caller]
+	pub fn with_source(kind: ErrorKind, inner: Error) -> Self {
+		let loc = Location::caller();
+		let mut e = Error::new(kind, loc.line(), loc.file());
+		// if the box cannot be allocated the cause is lost but the error is still returned
+		e.source = match Box::new(inner) {
+			Ok(inner) => Some(inner),
+			Err(_) => None,
+		};
+		e
+	}
+
+	pub fn with_errno(mut self, errno: i32) -> Self {
+		self.errno = errno;
+		self
+	}
+
+	/// Attaches a description of what was being done, as err!(IO, "sending frame header")
+	pub fn with_message(mut self, message: &str) -> Self {
+		self.message = match String::new(message) {
+			Ok(message) => message,
+			Err(_) => String::empty(),
+		};
+		self
+	}
+
+	/// True if the operation may succeed when tried again later, such as a connection
+	/// refused by a server which is not up yet or a full descriptor table. Errors such as
+	/// a reset connection or a broken pipe are fatal to what failed.
+	pub fn is_retryable(&self) -> bool {
+		match self.kind {
+			WouldBlock | Interrupted | Timeout | ChannelFull | TooManyOpenFiles
+			| ConnectionRefused => true,
+			_ => false,
+		}
+	}
+
+	/// The error which caused this one, if any
+	pub fn source(&self) -> Option<&Error> {
+		match self.source {
+			Some(ref source) => Some(source.as_ref()),
+			None => None,
+		}
+	}
+
+	/// The innermost error of the chain, which is self if there is no source
+	pub fn root_cause(&self) -> &Error {
+		let mut e = self;
+		while let Some(ref source) = e.source {
+			e = source.as_ref();
+		}
+		e
+	}
+
+	fn format_one(&self, f: &mut Formatter) -> Result<(), Error> {
+		let res = if self.errno != 0 {
+			writeb!(
+				*f,
+				"Error[kind={},loc={}:{},errno={}({})]",
+				self.kind.as_str(),
+				self.file,
+				self.line,
+				errno_name(self.errno),
+				self.errno
+			)
+		} else {
+			writeb!(
+				*f,
+				"Error[kind={},loc={}:{}]",
+				self.kind.as_str(),
+				self.file,
+				self.line
+			)
+		};
+		match res {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if self.message.len() > 0 {
+			match writeb!(*f, " {}", self.message) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		writeb!(*f, "\n")
+	}
+}
+
+/// The optional second argument of err!: an errno (i32) or a message (&str)
+pub trait ErrorDetail {
+	fn attach(self, e: Error) -> Error;
+}
+
+impl ErrorDetail for i32 {
+	fn attach(self, e: Error) -> Error {
+		e.with_errno(self)
+	}
+}
+
+impl ErrorDetail for &str {
+	fn attach(self, e: Error) -> Error {
+		e.with_message(self)
+	}
+}
+
+impl StaticError {
+	pub const fn new(kind: ErrorKind, line: u32, file: &'static str) -> Self {
+		Self {
+			kind,
+			line,
+			file,
+			errno: 0,
+		}
+	}
+
+	pub const fn with_errno(mut self, errno: i32) -> Self {
+		self.errno = errno;
+		self
+	}
+}
+
+// the backtrace, if any, is taken here rather than where the error occurred
+impl From<StaticError> for Error {
+	fn from(e: StaticError) -> Self {
+		Error::new(e.kind, e.line, e.file).with_errno(e.errno)
+	}
+}
+
+impl Display for StaticError {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		if self.errno != 0 {
+			writeb!(
+				*f,
+				"Error[kind={},loc={}:{},errno={}({})]\n",
+				self.kind.as_str(),
+				self.file,
+				self.line,
+				errno_name(self.errno),
+				self.errno
+			)
+		} else {
+			writeb!(
+				*f,
+				"Error[kind={},loc={}:{}]\n",
+				self.kind.as_str(),
+				self.file,
+				self.line
+			)
 		}
 	}
 }
 
 impl Display for Error {
 	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
-		match writeb!(
-			*f,
-			"Error[kind={},loc={}:{}]\n",
-			self.kind.as_str(),
-			self.file,
-			self.line
-		) {
-			Ok(_) => match self.backtrace.to_string() {
-				Ok(bt) => writeb!(*f, "{}", bt),
-				Err(_) => Ok(()),
-			},
-			Err(e) => Err(e),
+		match self.format_one(f) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut cause = self.source();
+		while let Some(e) = cause {
+			match writeb!(*f, "caused by: ") {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			match e.format_one(f) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			cause = e.source();
+		}
+		match self.backtrace.to_string() {
+			Ok(bt) => writeb!(*f, "{}", bt),
+			Err(_) => Ok(()),
 		}
 	}
 }
@@ -114,9 +348,104 @@ impl Display for Error {
 #[cfg(test)]
 mod test {
 	use super::*;
+	use ffi::{getalloccount, socket_send};
 	#[test]
 	fn test_err() {
 		let _x = err!(Alloc);
 		//println!("x=\n'{}'", _x);
 	}
+
+	#[test]
+	fn test_err_errno() {
+		let _alloc = AllocGuard::new();
+		let e = err!(Alloc);
+		assert_eq!(e.errno, 0);
+		let s = format!("{}", e).unwrap();
+		assert!(s.find("errno").is_none());
+
+		// EMFILE is 24 on both linux and macos
+		let e = err!(CreateFileDescriptor, 24);
+		assert_eq!(e.errno, 24);
+		let s = format!("{}", e).unwrap();
+		assert!(s.find("kind=CreateFileDescriptor").is_some());
+		assert!(s.find("errno=EMFILE(24)").is_some());
+		assert_eq!(errno_name(24), "EMFILE");
+		assert_eq!(errno_name(0), "UNKNOWN");
+		assert_eq!(errno_name(-1), "UNKNOWN");
+	}
+
+	#[test]
+	fn test_err_chain() {
+		let _alloc = AllocGuard::new();
+		let e = err!(IO, "sending frame header");
+		assert!(e.kind == IO);
+		assert_eq!(e.errno, 0);
+		assert_eq!(e.message.to_str(), "sending frame header");
+		assert!(e.source().is_none());
+		let s = format!("{}", e).unwrap();
+		assert!(s.find("kind=IO").is_some());
+		assert!(s.find("] sending frame header").is_some());
+
+		// socket -> ws -> handler
+		let socket = err!(ConnectionClosed, 32);
+		let ws = Error::with_source(WsStop, socket).with_message("writing close frame");
+		let handler = Error::with_source(IllegalState, ws);
+		assert!(handler.file.ends_with("error.rs"));
+		assert!(handler.source().unwrap().kind == WsStop);
+		assert!(handler.root_cause().kind == ConnectionClosed);
+		assert_eq!(handler.root_cause().errno, 32);
+		let s = format!("{}", handler).unwrap();
+		let outer = s.find("kind=IllegalState").unwrap();
+		let middle = s.find("caused by: Error[kind=WsStop").unwrap();
+		let inner = s.find("caused by: Error[kind=ConnectionClosed").unwrap();
+		assert!(outer < middle && middle < inner);
+		assert!(s.find("writing close frame").is_some());
+		assert!(s.find("errno=EPIPE(32)").is_some());
+	}
+
+	#[test]
+	fn test_oserr() {
+		let _alloc = AllocGuard::new();
+		// EPIPE is 32 and EMFILE is 24 on both linux and macos
+		assert!(ErrorKind::from_errno(32) == Some(BrokenPipe));
+		assert!(ErrorKind::from_errno(24) == Some(TooManyOpenFiles));
+		assert!(ErrorKind::from_errno(0).is_none());
+		assert!(ErrorKind::from_errno(-1).is_none());
+
+		// a descriptor which is not open
+		let handle = [0xffu8; 4];
+		assert!(unsafe { socket_send(&handle as *const u8, b"x".as_ptr(), 1) } < 0);
+		let e = oserr!(IO);
+		// EBADF has no kind of its own so the given one is kept
+		assert!(e.kind == IO);
+		assert_eq!(errno_name(e.errno), "EBADF");
+		assert!(!e.is_retryable());
+
+		assert!(err!(TooManyOpenFiles, 24).is_retryable());
+		assert!(err!(ConnectionRefused).is_retryable());
+		assert!(!err!(BrokenPipe).is_retryable());
+		assert!(!err!(ConnectionReset).is_retryable());
+	}
+
+	#[test]
+	fn test_static_err() {
+		let _alloc = AllocGuard::new();
+		// nothing is allocated until the error is converted
+		let before = unsafe { getalloccount() };
+		let e = serr!(WouldBlock);
+		let e2 = serr!(Connect, 111);
+		assert_eq!(unsafe { getalloccount() }, before);
+		assert!(e.kind == WouldBlock);
+		assert_eq!(e.errno, 0);
+		assert_eq!(e2.errno, 111);
+		assert!(e.file.ends_with("error.rs"));
+		let s = format!("{}", e).unwrap();
+		assert!(s.find("kind=WouldBlock").is_some());
+
+		let rich: Error = e2.into();
+		assert!(rich.kind == Connect);
+		assert_eq!(rich.errno, 111);
+		assert_eq!(rich.line, e2.line);
+		assert_eq!(rich.file.to_str(), e2.file);
+	}
 }
@@ -1,12 +1,109 @@
-use core::ptr::copy_nonoverlapping;
+use core::ptr::{copy, copy_nonoverlapping, write_bytes};
 use core::slice::from_raw_parts;
 use core::str::from_utf8_unchecked;
 use ffi::f64_to_str;
 use prelude::*;
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum Align {
+	/// right for numbers and left for everything else
+	Default,
+	Left,
+	Right,
+}
+
+/// The directive of a placeholder: everything after the colon of {:>8}, {:08x} or {:.3}
+#[derive(PartialEq, Clone, Copy)]
+pub struct FormatSpec {
+	/// pad numbers with zeros after the sign instead of spaces
+	pub zero: bool,
+	pub align: Align,
+	/// minimum number of characters written, 0 for no padding
+	pub width: usize,
+	/// digits after the decimal point of floats
+	pub precision: usize,
+	/// 16 for {:x} and {:X}, otherwise 10
+	pub radix: u8,
+	/// upper case hex digits for {:X}
+	pub upper: bool,
+}
+
+impl FormatSpec {
+	pub const DEFAULT: FormatSpec = FormatSpec {
+		zero: false,
+		align: Align::Default,
+		width: 0,
+		precision: 5,
+		radix: 10,
+		upper: false,
+	};
+
+	/// Parses the text between the colon and the closing brace. {:?} is accepted and
+	/// formats like {}, as there is no separate debug form.
+	pub fn parse(spec: &[u8]) -> Option<FormatSpec> {
+		let mut ret = FormatSpec::DEFAULT;
+		let mut i = 0;
+		if i < spec.len() && (spec[i] == b'<' || spec[i] == b'>') {
+			ret.align = if spec[i] == b'<' {
+				Align::Left
+			} else {
+				Align::Right
+			};
+			i += 1;
+		}
+		if i < spec.len() && spec[i] == b'0' {
+			ret.zero = true;
+			i += 1;
+		}
+		while i < spec.len() && spec[i] >= b'0' && spec[i] <= b'9' {
+			ret.width = ret.width * 10 + (spec[i] - b'0') as usize;
+			i += 1;
+		}
+		if i < spec.len() && spec[i] == b'.' {
+			i += 1;
+			let start = i;
+			let mut precision = 0;
+			while i < spec.len() && spec[i] >= b'0' && spec[i] <= b'9' {
+				precision = precision * 10 + (spec[i] - b'0') as usize;
+				i += 1;
+			}
+			if i == start {
+				return None;
+			}
+			ret.precision = precision;
+		}
+		if i < spec.len() {
+			match spec[i] {
+				b'x' => ret.radix = 16,
+				b'X' => {
+					ret.radix = 16;
+					ret.upper = true;
+				}
+				b'?' => {}
+				_ => return None,
+			}
+			i += 1;
+		}
+		if i == spec.len() {
+			Some(ret)
+		} else {
+			None
+		}
+	}
+}
+
+/// A placeholder being formatted, returned by begin_arg and passed back to end_arg
+pub struct Placeholder {
+	start: usize,
+	spec: FormatSpec,
+}
+
 pub struct Formatter {
 	buffer: Vec<u8>,
 	pos: usize,
+	spec: FormatSpec,
+	// set by write_num so that the argument is padded as a number
+	numeric: bool,
 }
 
 impl Formatter {
@@ -14,8 +111,132 @@ impl Formatter {
 		Self {
 			buffer: Vec::new(),
 			pos: 0,
+			spec: FormatSpec::DEFAULT,
+			numeric: false,
+		}
+	}
+
+	/// The directive of the placeholder being formatted
+	pub fn spec(&self) -> &FormatSpec {
+		&self.spec
+	}
+
+	/// Writes the text of fmt from offset cur up to the next placeholder, {} or {:spec},
+	/// and makes that placeholder's directive current. cur is moved past the placeholder,
+	/// or left alone if there is none, in which case the argument is appended as it is.
+	/// Braces which do not form a placeholder are written as they are.
+	pub fn begin_arg(&mut self, fmt: &str, cur: &mut usize) -> Result<Placeholder, Error> {
+		let bytes = fmt.as_bytes();
+		let mut spec = FormatSpec::DEFAULT;
+		let mut open = *cur;
+		let mut end = *cur;
+		while open < bytes.len() {
+			if bytes[open] == b'{' {
+				let mut close = open + 1;
+				while close < bytes.len() && bytes[close] != b'}' && bytes[close] != b'{' {
+					close += 1;
+				}
+				if close < bytes.len() && bytes[close] == b'}' {
+					if close == open + 1 {
+						end = close + 1;
+						break;
+					}
+					if bytes[open + 1] == b':' {
+						match FormatSpec::parse(&bytes[open + 2..close]) {
+							Some(s) => {
+								spec = s;
+								end = close + 1;
+								break;
+							}
+							None => {}
+						}
+					}
+				}
+			}
+			open += 1;
+		}
+		if end > *cur {
+			let text = unsafe { from_utf8_unchecked(&bytes[*cur..open]) };
+			match self.write_str(text, text.len()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			*cur = end;
 		}
+		self.spec = spec;
+		self.numeric = false;
+		Ok(Placeholder {
+			start: self.pos,
+			spec,
+		})
 	}
+
+	/// Pads what was written for the placeholder to its width and resets the directive
+	pub fn end_arg(&mut self, p: Placeholder) -> Result<(), Error> {
+		let numeric = self.numeric;
+		self.spec = FormatSpec::DEFAULT;
+		self.numeric = false;
+		let mut chars = 0;
+		for b in &self.buffer[p.start..self.pos] {
+			// count the first byte of each utf8 sequence
+			if *b & 0xC0 != 0x80 {
+				chars += 1;
+			}
+		}
+		if chars >= p.spec.width {
+			return Ok(());
+		}
+		let n = p.spec.width - chars;
+		let zero = p.spec.zero && numeric;
+		let right = match p.spec.align {
+			Align::Left => false,
+			Align::Right => true,
+			Align::Default => numeric,
+		};
+		// zeros go after the sign, spaces before it
+		let at = if zero {
+			if self.pos > p.start && self.buffer[p.start] == b'-' {
+				p.start + 1
+			} else {
+				p.start
+			}
+		} else if right {
+			p.start
+		} else {
+			self.pos
+		};
+		match self.buffer.resize(self.pos + n) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		unsafe {
+			let ptr = self.buffer.as_mut_ptr() as *mut u8;
+			copy(ptr.add(at), ptr.add(at + n), self.pos - at);
+			write_bytes(ptr.add(at), if zero { b'0' } else { b' ' }, n);
+		}
+		self.pos += n;
+		Ok(())
+	}
+
+	/// Writes the digits of a number, which are padded as a number and put in upper case
+	/// for {:X}
+	pub fn write_num(&mut self, s: &str, len: usize) -> Result<(), Error> {
+		self.numeric = true;
+		let start = self.pos;
+		match self.write_str(s, len) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		if self.spec.upper {
+			for b in &mut self.buffer[start..self.pos] {
+				if *b >= b'a' && *b <= b'f' {
+					*b -= b'a' - b'A';
+				}
+			}
+		}
+		Ok(())
+	}
+
 	pub fn write_str(&mut self, s: &str, len: usize) -> Result<(), Error> {
 		let bytes = s.as_bytes();
 		match self.buffer.resize(len + self.pos) {
@@ -31,6 +252,15 @@ impl Formatter {
 
 		Ok(())
 	}
+	pub fn len(&self) -> usize {
+		self.pos
+	}
+	pub fn clear(&mut self) {
+		self.buffer.clear();
+		self.pos = 0;
+		self.spec = FormatSpec::DEFAULT;
+		self.numeric = false;
+	}
 	pub fn as_str(&self) -> &str {
 		let ret = if self.pos == 0 {
 			""
@@ -47,8 +277,8 @@ macro_rules! impl_display_unsigned {
             impl Display for $t {
                 fn format(&self, f: &mut Formatter) -> Result<(), Error> {
                     let mut buf = [0u8; 64];
-                    let len = u128_to_str((*self).into(), 0, &mut buf, 10);
-                    unsafe { f.write_str(from_utf8_unchecked(&buf), len) }
+                    let len = u128_to_str((*self).into(), 0, &mut buf, f.spec().radix);
+                    unsafe { f.write_num(from_utf8_unchecked(&buf), len) }
                 }
             }
         )*
@@ -60,8 +290,8 @@ impl_display_unsigned!(u8, u16, u32, u64, u128);
 impl Display for usize {
 	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
 		let mut buf = [0u8; 64];
-		let len = u128_to_str(*self as u128, 0, &mut buf, 10);
-		unsafe { f.write_str(from_utf8_unchecked(&buf), len) }
+		let len = u128_to_str(*self as u128, 0, &mut buf, f.spec().radix);
+		unsafe { f.write_num(from_utf8_unchecked(&buf), len) }
 	}
 }
 
@@ -80,27 +310,42 @@ impl Display for char {
 }
 
 macro_rules! impl_display_signed {
-    ($($t:ty),*) => {
+    ($($t:ty => $u:ty),*) => {
         $(
             impl Display for $t {
                 fn format(&self, f: &mut Formatter) -> Result<(), Error> {
                     let mut buf = [0u8; 64];
-                    let len = i128_to_str((*self).into(), &mut buf, 10);
-                    unsafe { f.write_str(from_utf8_unchecked(&buf), len) }
+                    // hex shows the two's complement bits, as {:x} does in std
+                    let len = if f.spec().radix == 16 {
+                        u128_to_str((*self as $u).into(), 0, &mut buf, 16)
+                    } else {
+                        i128_to_str((*self).into(), &mut buf, 10)
+                    };
+                    unsafe { f.write_num(from_utf8_unchecked(&buf), len) }
                 }
             }
         )*
     };
 }
 
-impl_display_signed!(i8, i16, i32, i64, i128);
+impl_display_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+// the precision of the placeholder, limited to leave room in the 512 byte buffer for the
+// 309 integer digits of f64::MAX
+fn float_precision(f: &Formatter) -> i32 {
+	if f.spec().precision > 150 {
+		150
+	} else {
+		f.spec().precision as i32
+	}
+}
 
 impl Display for f64 {
 	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
 		let mut buf = [0u8; 512];
-		let len = unsafe { f64_to_str(*self, buf.as_mut_ptr(), 512) };
-		if len > 0 {
-			unsafe { f.write_str(from_utf8_unchecked(&buf), len as usize) }
+		let len = unsafe { f64_to_str(*self, buf.as_mut_ptr(), 512, float_precision(f)) };
+		if len > 0 && (len as usize) < buf.len() {
+			unsafe { f.write_num(from_utf8_unchecked(&buf), len as usize) }
 		} else {
 			Err(err!(IO))
 		}
@@ -120,9 +365,9 @@ impl Display for bool {
 impl Display for f32 {
 	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
 		let mut buf = [0u8; 512];
-		let len = unsafe { f64_to_str((*self).into(), buf.as_mut_ptr(), 512) };
-		if len > 0 {
-			unsafe { f.write_str(from_utf8_unchecked(&buf), len as usize) }
+		let len = unsafe { f64_to_str((*self).into(), buf.as_mut_ptr(), 512, float_precision(f)) };
+		if len > 0 && (len as usize) < buf.len() {
+			unsafe { f.write_num(from_utf8_unchecked(&buf), len as usize) }
 		} else {
 			Err(err!(IO))
 		}
@@ -225,4 +470,37 @@ mod test {
 		let x = format!("this is a test {} {}", 7, 8).unwrap();
 		assert_eq!(x.to_str(), "this is a test 7 8");
 	}
+
+	#[test]
+	fn test_format_spec() {
+		let _alloc = AllocGuard::new();
+		let s = format!("{:x} {:X} {:x} {:x}", 255u8, 48879u32, -1i8, 0usize).unwrap();
+		assert_eq!(s.to_str(), "ff BEEF ff 0");
+		let s = format!("[{:08}] [{:08}] [{:08x}]", 42u16, -42i32, 3054u64).unwrap();
+		assert_eq!(s.to_str(), "[00000042] [-0000042] [00000bee]");
+		let s = format!("[{:.3}] [{:.0}] [{:8.2}]", 1.23456f64, 2.5f32, -3.14159f64).unwrap();
+		assert_eq!(s.to_str(), "[1.235] [2] [   -3.14]");
+
+		// numbers go right and everything else left unless an alignment is given
+		let s = format!("[{:4}] [{:4}] [{:<4}] [{:>4}]", 7, "ab", 7, "ab").unwrap();
+		assert_eq!(s.to_str(), "[   7] [ab  ] [7   ] [  ab]");
+		let s = format!("[{:3}] [{:1}] [{:04}]", "é", "long", "ab").unwrap();
+		assert_eq!(s.to_str(), "[é  ] [long] [ab  ]");
+
+		// the width applies to the whole output of a type which formats with writeb!
+		struct Point(i32, i32);
+		impl Display for Point {
+			fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+				writeb!(*f, "({:x}, {:03})", self.0, self.1)
+			}
+		}
+		let s = format!("[{:?}] [{:>12}] [{:12}]", 1, Point(10, 2), Point(-1, -2)).unwrap();
+		assert_eq!(s.to_str(), "[1] [    (a, 002)] [(ffffffff, -02)]");
+
+		// braces which are not placeholders are kept
+		let s = format!("{a} {:z} {} {{}}", 1, 2).unwrap();
+		assert_eq!(s.to_str(), "{a} {:z} 1 {2}");
+		let s = format!("{:05} and {}", 3).unwrap();
+		assert_eq!(s.to_str(), "00003 and {}");
+	}
 }
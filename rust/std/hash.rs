@@ -0,0 +1,184 @@
+//! # Cryptographic hashes
+//! Safe wrappers around the SHA-1, SHA-2, RIPEMD-160 and HMAC implementations in the C
+//! layer. Each function hashes a complete message and returns the digest by value.
+//! CRC32C, a checksum rather than a cryptographic hash, is implemented here directly.
+
+use ffi::{HMAC_SHA256, HMAC_SHA512, RIPEMD160, SHA1, SHA256, SHA512};
+
+pub const SHA1_SIZE: usize = 20;
+pub const SHA256_SIZE: usize = 32;
+pub const SHA512_SIZE: usize = 64;
+pub const RIPEMD160_SIZE: usize = 20;
+
+pub fn sha1(data: &[u8]) -> [u8; SHA1_SIZE] {
+	let mut ret = [0u8; SHA1_SIZE];
+	unsafe { SHA1(data.as_ptr(), data.len(), ret.as_mut_ptr()) };
+	ret
+}
+
+pub fn sha256(data: &[u8]) -> [u8; SHA256_SIZE] {
+	let mut ret = [0u8; SHA256_SIZE];
+	unsafe { SHA256(data.as_ptr(), data.len(), ret.as_mut_ptr()) };
+	ret
+}
+
+/// SHA-256 applied twice, as used for bitcoin style checksums and ids
+pub fn sha256d(data: &[u8]) -> [u8; SHA256_SIZE] {
+	sha256(&sha256(data))
+}
+
+pub fn sha512(data: &[u8]) -> [u8; SHA512_SIZE] {
+	let mut ret = [0u8; SHA512_SIZE];
+	unsafe { SHA512(data.as_ptr(), data.len(), ret.as_mut_ptr()) };
+	ret
+}
+
+pub fn ripemd160(data: &[u8]) -> [u8; RIPEMD160_SIZE] {
+	let mut ret = [0u8; RIPEMD160_SIZE];
+	unsafe { RIPEMD160(data.as_ptr(), data.len(), ret.as_mut_ptr()) };
+	ret
+}
+
+/// RIPEMD-160 of the SHA-256 of data, used to fingerprint public keys
+pub fn hash160(data: &[u8]) -> [u8; RIPEMD160_SIZE] {
+	ripemd160(&sha256(data))
+}
+
+// reflected Castagnoli polynomial
+const CRC32C_POLY: u32 = 0x82f6_3b78;
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+	let mut table = [0u32; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut j = 0;
+		while j < 8 {
+			crc = if crc & 1 != 0 {
+				(crc >> 1) ^ CRC32C_POLY
+			} else {
+				crc >> 1
+			};
+			j += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+}
+
+/// CRC-32C (Castagnoli) as used by iSCSI and SCTP
+pub fn crc32c(data: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for b in data {
+		crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+	}
+	!crc
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; SHA256_SIZE] {
+	let mut ret = [0u8; SHA256_SIZE];
+	unsafe {
+		HMAC_SHA256(
+			key.as_ptr(),
+			key.len(),
+			data.as_ptr(),
+			data.len(),
+			ret.as_mut_ptr(),
+		)
+	};
+	ret
+}
+
+pub fn hmac_sha512(key: &[u8], data: &[u8]) -> [u8; SHA512_SIZE] {
+	let mut ret = [0u8; SHA512_SIZE];
+	unsafe {
+		HMAC_SHA512(
+			key.as_ptr(),
+			key.len(),
+			data.as_ptr(),
+			data.len(),
+			ret.as_mut_ptr(),
+		)
+	};
+	ret
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use prelude::*;
+	use std::encoding::hex_decode_array;
+
+	#[test]
+	fn test_hash_vectors() {
+		let _alloc = AllocGuard::new();
+		assert_eq!(
+			sha1(b"abc"),
+			hex_decode_array("a9993e364706816aba3e25717850c26c9cd0d89d").unwrap()
+		);
+		assert_eq!(
+			sha256(b""),
+			hex_decode_array("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+				.unwrap()
+		);
+		assert_eq!(
+			sha256(b"abc"),
+			hex_decode_array("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+				.unwrap()
+		);
+		assert_eq!(sha256d(b"abc"), sha256(&sha256(b"abc")));
+		assert_eq!(
+			sha512(b"abc"),
+			hex_decode_array(
+				"ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+				 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+			)
+			.unwrap()
+		);
+		assert_eq!(
+			ripemd160(b"abc"),
+			hex_decode_array("8eb208f7e05d987a9b044a8e98c6b087f15a0bfc").unwrap()
+		);
+		assert_eq!(hash160(b"abc"), ripemd160(&sha256(b"abc")));
+	}
+
+	#[test]
+	fn test_crc32c() {
+		assert_eq!(crc32c(b""), 0);
+		assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+		// RFC 3720 B.4: 32 bytes of zeros and of ones
+		assert_eq!(crc32c(&[0u8; 32]), 0x8a91_36aa);
+		assert_eq!(crc32c(&[0xffu8; 32]), 0x62a8_ab43);
+	}
+
+	#[test]
+	fn test_hmac_vectors() {
+		let _alloc = AllocGuard::new();
+		// RFC 4231 test cases 2 and 6 (key longer than the block size)
+		let data = b"what do ya want for nothing?";
+		assert_eq!(
+			hmac_sha256(b"Jefe", data),
+			hex_decode_array("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+				.unwrap()
+		);
+		assert_eq!(
+			hmac_sha512(b"Jefe", data),
+			hex_decode_array(
+				"164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea250554\
+				 9758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+			)
+			.unwrap()
+		);
+		let key = [0xaau8; 131];
+		assert_eq!(
+			hmac_sha256(
+				&key,
+				b"Test Using Larger Than Block-Size Key - Hash Key First"
+			),
+			hex_decode_array("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+				.unwrap()
+		);
+	}
+}
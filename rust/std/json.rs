@@ -0,0 +1,691 @@
+//! # JSON
+//! A document tree which is parsed from and written to JSON text (RFC 8259). Numbers
+//! without a fraction or exponent which fit in an i64 are kept as Int so that ids and
+//! amounts are exact, all others are Float. Objects keep their members in the order they
+//! were parsed or set. Display writes the compact form, with no whitespace.
+
+use ffi::{f64_to_str_shortest, str_to_f64};
+use prelude::*;
+
+/// Nesting of arrays and objects beyond which parsing fails, so that hostile input cannot
+/// exhaust the stack
+pub const JSON_MAX_DEPTH: usize = 128;
+
+pub enum Json {
+	Null,
+	Bool(bool),
+	Int(i64),
+	Float(f64),
+	String(String),
+	Array(Vec<Json>),
+	Object(Vec<(String, Json)>),
+}
+
+impl Json {
+	/// Parses a complete JSON text. Syntax errors are CorruptedData, strings which are not
+	/// UTF-8 are Utf8.
+	pub fn parse(s: &str) -> Result<Json, Error> {
+		Self::parse_bytes(s.as_bytes())
+	}
+
+	/// Parses a JSON text received as bytes, such as a websocket message
+	pub fn parse_bytes(s: &[u8]) -> Result<Json, Error> {
+		let mut p = Parser { s, pos: 0 };
+		let ret = match p.value(0) {
+			Ok(v) => v,
+			Err(e) => return Err(e),
+		};
+		p.skip_ws();
+		if p.pos != s.len() {
+			return Err(err!(CorruptedData, "json: trailing characters"));
+		}
+		Ok(ret)
+	}
+
+	pub fn object() -> Json {
+		Json::Object(Vec::new())
+	}
+
+	pub fn array() -> Json {
+		Json::Array(Vec::new())
+	}
+
+	pub fn string(s: &str) -> Result<Json, Error> {
+		match String::new(s) {
+			Ok(s) => Ok(Json::String(s)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Sets a member of an object, replacing one with the same key. IllegalState if this
+	/// is not an object.
+	pub fn set(&mut self, key: &str, value: Json) -> Result<(), Error> {
+		match self {
+			Json::Object(members) => {
+				for member in members.as_mut_slice() {
+					if member.0.to_str() == key {
+						member.1 = value;
+						return Ok(());
+					}
+				}
+				match String::new(key) {
+					Ok(key) => members.push((key, value)),
+					Err(e) => Err(e),
+				}
+			}
+			_ => Err(err!(IllegalState)),
+		}
+	}
+
+	/// Appends to an array. IllegalState if this is not an array.
+	pub fn push(&mut self, value: Json) -> Result<(), Error> {
+		match self {
+			Json::Array(values) => values.push(value),
+			_ => Err(err!(IllegalState)),
+		}
+	}
+
+	/// The member of an object with the given key
+	pub fn get(&self, key: &str) -> Option<&Json> {
+		match self {
+			Json::Object(members) => {
+				for member in members {
+					if member.0.to_str() == key {
+						return Some(&member.1);
+					}
+				}
+				None
+			}
+			_ => None,
+		}
+	}
+
+	/// The element of an array at index i
+	pub fn at(&self, i: usize) -> Option<&Json> {
+		match self {
+			Json::Array(values) if i < values.len() => Some(&values[i]),
+			_ => None,
+		}
+	}
+
+	/// Number of elements of an array or members of an object, 0 for anything else
+	pub fn len(&self) -> usize {
+		match self {
+			Json::Array(values) => values.len(),
+			Json::Object(members) => members.len(),
+			_ => 0,
+		}
+	}
+
+	pub fn is_null(&self) -> bool {
+		match self {
+			Json::Null => true,
+			_ => false,
+		}
+	}
+
+	pub fn as_bool(&self) -> Option<bool> {
+		match self {
+			Json::Bool(b) => Some(*b),
+			_ => None,
+		}
+	}
+
+	pub fn as_i64(&self) -> Option<i64> {
+		match self {
+			Json::Int(n) => Some(*n),
+			_ => None,
+		}
+	}
+
+	/// The value of a Float, or of an Int converted to f64
+	pub fn as_f64(&self) -> Option<f64> {
+		match self {
+			Json::Int(n) => Some(*n as f64),
+			Json::Float(n) => Some(*n),
+			_ => None,
+		}
+	}
+
+	pub fn as_str(&self) -> Option<&str> {
+		match self {
+			Json::String(s) => Some(s.to_str()),
+			_ => None,
+		}
+	}
+}
+
+impl Display for Json {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		match self {
+			Json::Null => f.write_str("null", 4),
+			Json::Bool(b) => b.format(f),
+			Json::Int(n) => {
+				// not n.format, whose output a placeholder such as {:x} changes
+				let mut buf = [0u8; 32];
+				let len = i128_to_str(*n as i128, &mut buf, 10);
+				f.write_str(unsafe { from_utf8_unchecked(&buf[0..len]) }, len)
+			}
+			Json::Float(n) => write_float(*n, f),
+			Json::String(s) => write_string(s.to_str(), f),
+			Json::Array(values) => {
+				match f.write_str("[", 1) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+				let mut first = true;
+				for v in values {
+					if !first {
+						match f.write_str(",", 1) {
+							Ok(_) => {}
+							Err(e) => return Err(e),
+						}
+					}
+					first = false;
+					match v.format(f) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+				}
+				f.write_str("]", 1)
+			}
+			Json::Object(members) => {
+				match f.write_str("{", 1) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+				let mut first = true;
+				for member in members {
+					if !first {
+						match f.write_str(",", 1) {
+							Ok(_) => {}
+							Err(e) => return Err(e),
+						}
+					}
+					first = false;
+					match write_string(member.0.to_str(), f) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+					match f.write_str(":", 1) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+					match member.1.format(f) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+				}
+				f.write_str("}", 1)
+			}
+		}
+	}
+}
+
+// JSON has no infinity or NaN, so those are IllegalArgument
+fn write_float(n: f64, f: &mut Formatter) -> Result<(), Error> {
+	if !n.is_finite() {
+		return Err(err!(IllegalArgument, "json: float is not finite"));
+	}
+	let mut buf = [0u8; 64];
+	let len = unsafe { f64_to_str_shortest(n, buf.as_mut_ptr(), buf.len() as u64) };
+	if len <= 0 {
+		return Err(err!(IO));
+	}
+	let len = len as usize;
+	match f.write_str(unsafe { from_utf8_unchecked(&buf[0..len]) }, len) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	// keep a float whole number a float when it is read back
+	for b in &buf[0..len] {
+		if *b == b'.' || *b == b'e' {
+			return Ok(());
+		}
+	}
+	f.write_str(".0", 2)
+}
+
+fn write_string(s: &str, f: &mut Formatter) -> Result<(), Error> {
+	const HEX: &[u8; 16] = b"0123456789abcdef";
+	match f.write_str("\"", 1) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	let bytes = s.as_bytes();
+	// runs of bytes which need no escape are written at once
+	let mut start = 0;
+	for i in 0..bytes.len() {
+		let mut unicode = [b'\\', b'u', b'0', b'0', 0, 0];
+		let escape: &[u8] = match bytes[i] {
+			b'"' => b"\\\"",
+			b'\\' => b"\\\\",
+			b'\n' => b"\\n",
+			b'\r' => b"\\r",
+			b'\t' => b"\\t",
+			0x08 => b"\\b",
+			0x0c => b"\\f",
+			b if b < 0x20 => {
+				unicode[4] = HEX[(b >> 4) as usize];
+				unicode[5] = HEX[(b & 0xf) as usize];
+				&unicode
+			}
+			_ => continue,
+		};
+		let run = &s[start..i];
+		match f.write_str(run, run.len()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match f.write_str(unsafe { from_utf8_unchecked(escape) }, escape.len()) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		start = i + 1;
+	}
+	let run = &s[start..];
+	match f.write_str(run, run.len()) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	f.write_str("\"", 1)
+}
+
+struct Parser<'a> {
+	s: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn skip_ws(&mut self) {
+		while self.pos < self.s.len() {
+			match self.s[self.pos] {
+				b' ' | b'\t' | b'\n' | b'\r' => self.pos += 1,
+				_ => break,
+			}
+		}
+	}
+
+	// consumes the literal word if the input continues with it
+	fn literal(&mut self, word: &[u8]) -> bool {
+		if self.s.len() - self.pos >= word.len() && &self.s[self.pos..self.pos + word.len()] == word
+		{
+			self.pos += word.len();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn value(&mut self, depth: usize) -> Result<Json, Error> {
+		if depth >= JSON_MAX_DEPTH {
+			return Err(err!(CorruptedData, "json: nested too deeply"));
+		}
+		self.skip_ws();
+		if self.pos == self.s.len() {
+			return Err(err!(CorruptedData, "json: unexpected end"));
+		}
+		match self.s[self.pos] {
+			b'{' => self.object(depth),
+			b'[' => self.array(depth),
+			b'"' => match self.string() {
+				Ok(s) => Ok(Json::String(s)),
+				Err(e) => Err(e),
+			},
+			b'-' | b'0'..=b'9' => self.number(),
+			_ => {
+				if self.literal(b"null") {
+					Ok(Json::Null)
+				} else if self.literal(b"true") {
+					Ok(Json::Bool(true))
+				} else if self.literal(b"false") {
+					Ok(Json::Bool(false))
+				} else {
+					Err(err!(CorruptedData, "json: unexpected character"))
+				}
+			}
+		}
+	}
+
+	// true if the next non whitespace character is c, which is consumed
+	fn next_is(&mut self, c: u8) -> bool {
+		self.skip_ws();
+		if self.pos < self.s.len() && self.s[self.pos] == c {
+			self.pos += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	fn array(&mut self, depth: usize) -> Result<Json, Error> {
+		self.pos += 1;
+		let mut values = Vec::new();
+		if self.next_is(b']') {
+			return Ok(Json::Array(values));
+		}
+		loop {
+			match self.value(depth + 1) {
+				Ok(v) => match values.push(v) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => return Err(e),
+			}
+			if self.next_is(b']') {
+				return Ok(Json::Array(values));
+			}
+			if !self.next_is(b',') {
+				return Err(err!(CorruptedData, "json: expected ',' or ']'"));
+			}
+		}
+	}
+
+	fn object(&mut self, depth: usize) -> Result<Json, Error> {
+		self.pos += 1;
+		let mut members = Vec::new();
+		if self.next_is(b'}') {
+			return Ok(Json::Object(members));
+		}
+		loop {
+			self.skip_ws();
+			if self.pos == self.s.len() || self.s[self.pos] != b'"' {
+				return Err(err!(CorruptedData, "json: expected a key"));
+			}
+			let key = match self.string() {
+				Ok(key) => key,
+				Err(e) => return Err(e),
+			};
+			if !self.next_is(b':') {
+				return Err(err!(CorruptedData, "json: expected ':'"));
+			}
+			match self.value(depth + 1) {
+				Ok(v) => match members.push((key, v)) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => return Err(e),
+			}
+			if self.next_is(b'}') {
+				return Ok(Json::Object(members));
+			}
+			if !self.next_is(b',') {
+				return Err(err!(CorruptedData, "json: expected ',' or '}'"));
+			}
+		}
+	}
+
+	fn hex4(&mut self) -> Result<u32, Error> {
+		if self.s.len() - self.pos < 4 {
+			return Err(err!(CorruptedData, "json: short unicode escape"));
+		}
+		let mut ret = 0u32;
+		for _ in 0..4 {
+			let c = self.s[self.pos];
+			let d = match c {
+				b'0'..=b'9' => c - b'0',
+				b'a'..=b'f' => c - b'a' + 10,
+				b'A'..=b'F' => c - b'A' + 10,
+				_ => return Err(err!(CorruptedData, "json: bad unicode escape")),
+			};
+			ret = (ret << 4) | d as u32;
+			self.pos += 1;
+		}
+		Ok(ret)
+	}
+
+	// the code point of a \u escape, joining a surrogate pair
+	fn unicode_escape(&mut self) -> Result<u32, Error> {
+		let hi = match self.hex4() {
+			Ok(hi) => hi,
+			Err(e) => return Err(e),
+		};
+		if hi >= 0xDC00 && hi <= 0xDFFF {
+			return Err(err!(CorruptedData, "json: unpaired surrogate"));
+		}
+		if hi < 0xD800 || hi > 0xDBFF {
+			return Ok(hi);
+		}
+		if !self.literal(b"\\u") {
+			return Err(err!(CorruptedData, "json: unpaired surrogate"));
+		}
+		match self.hex4() {
+			Ok(lo) if lo >= 0xDC00 && lo <= 0xDFFF => {
+				Ok(0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00))
+			}
+			Ok(_) => Err(err!(CorruptedData, "json: unpaired surrogate")),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn string(&mut self) -> Result<String, Error> {
+		self.pos += 1;
+		let mut out = Vec::new();
+		loop {
+			if self.pos == self.s.len() {
+				return Err(err!(CorruptedData, "json: unterminated string"));
+			}
+			let c = self.s[self.pos];
+			self.pos += 1;
+			let res = match c {
+				b'"' => return String::from_utf8(out.as_slice()),
+				b'\\' => {
+					if self.pos == self.s.len() {
+						return Err(err!(CorruptedData, "json: unterminated string"));
+					}
+					let e = self.s[self.pos];
+					self.pos += 1;
+					match e {
+						b'"' | b'\\' | b'/' => out.push(e),
+						b'b' => out.push(0x08),
+						b'f' => out.push(0x0c),
+						b'n' => out.push(b'\n'),
+						b'r' => out.push(b'\r'),
+						b't' => out.push(b'\t'),
+						b'u' => match self.unicode_escape() {
+							Ok(cp) => push_utf8(&mut out, cp),
+							Err(e) => return Err(e),
+						},
+						_ => return Err(err!(CorruptedData, "json: bad escape")),
+					}
+				}
+				c if c < 0x20 => {
+					return Err(err!(CorruptedData, "json: control character in string"))
+				}
+				c => out.push(c),
+			};
+			match res {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+
+	fn digits(&mut self) -> usize {
+		let start = self.pos;
+		while self.pos < self.s.len() && self.s[self.pos] >= b'0' && self.s[self.pos] <= b'9' {
+			self.pos += 1;
+		}
+		self.pos - start
+	}
+
+	fn number(&mut self) -> Result<Json, Error> {
+		let start = self.pos;
+		let negative = self.s[self.pos] == b'-';
+		if negative {
+			self.pos += 1;
+		}
+		let int_start = self.pos;
+		let int_digits = self.digits();
+		if int_digits == 0 || (int_digits > 1 && self.s[int_start] == b'0') {
+			return Err(err!(CorruptedData, "json: bad number"));
+		}
+		let mut float = false;
+		if self.pos < self.s.len() && self.s[self.pos] == b'.' {
+			self.pos += 1;
+			if self.digits() == 0 {
+				return Err(err!(CorruptedData, "json: bad number"));
+			}
+			float = true;
+		}
+		if self.pos < self.s.len() && (self.s[self.pos] == b'e' || self.s[self.pos] == b'E') {
+			self.pos += 1;
+			if self.pos < self.s.len() && (self.s[self.pos] == b'+' || self.s[self.pos] == b'-') {
+				self.pos += 1;
+			}
+			if self.digits() == 0 {
+				return Err(err!(CorruptedData, "json: bad number"));
+			}
+			float = true;
+		}
+		// 19 digits can always be summed in an i128, larger values are read as floats
+		if !float && int_digits <= 19 {
+			let mut n = 0i128;
+			for c in &self.s[int_start..self.pos] {
+				n = n * 10 + (*c - b'0') as i128;
+			}
+			let n = if negative { -n } else { n };
+			if n >= i64::MIN as i128 && n <= i64::MAX as i128 {
+				return Ok(Json::Int(n as i64));
+			}
+		}
+		let text = &self.s[start..self.pos];
+		let mut n = 0.0f64;
+		if unsafe { str_to_f64(text.as_ptr(), text.len() as u64, &mut n) } != 0 {
+			return Err(err!(CorruptedData, "json: bad number"));
+		}
+		if !n.is_finite() {
+			return Err(err!(CorruptedData, "json: number out of range"));
+		}
+		Ok(Json::Float(n))
+	}
+}
+
+fn push_utf8(out: &mut Vec<u8>, cp: u32) -> Result<(), Error> {
+	let mut buf = [0u8; 4];
+	let len = if cp < 0x80 {
+		buf[0] = cp as u8;
+		1
+	} else if cp < 0x800 {
+		buf[0] = 0xC0 | (cp >> 6) as u8;
+		buf[1] = 0x80 | (cp & 0x3F) as u8;
+		2
+	} else if cp < 0x10000 {
+		buf[0] = 0xE0 | (cp >> 12) as u8;
+		buf[1] = 0x80 | ((cp >> 6) & 0x3F) as u8;
+		buf[2] = 0x80 | (cp & 0x3F) as u8;
+		3
+	} else {
+		buf[0] = 0xF0 | (cp >> 18) as u8;
+		buf[1] = 0x80 | ((cp >> 12) & 0x3F) as u8;
+		buf[2] = 0x80 | ((cp >> 6) & 0x3F) as u8;
+		buf[3] = 0x80 | (cp & 0x3F) as u8;
+		4
+	};
+	out.append_ptr(buf.as_ptr(), len)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_json_parse() {
+		let _alloc = AllocGuard::new();
+		let doc = Json::parse(
+			" {\"id\": 12345678901234, \"price\": -1.5e2, \"ok\": true, \"none\": null,\n\
+			 \t\"tags\": [\"a\", [], {}], \"name\": \"caf\\u00e9 \\ud83d\\ude00\\n\\\"q\\\"\"} ",
+		)
+		.unwrap();
+		assert_eq!(doc.len(), 6);
+		assert_eq!(doc.get("id").unwrap().as_i64().unwrap(), 12345678901234);
+		assert_eq!(doc.get("price").unwrap().as_f64().unwrap(), -150.0);
+		assert!(doc.get("price").unwrap().as_i64().is_none());
+		assert_eq!(doc.get("ok").unwrap().as_bool().unwrap(), true);
+		assert!(doc.get("none").unwrap().is_null());
+		assert!(doc.get("missing").is_none());
+		let tags = doc.get("tags").unwrap();
+		assert_eq!(tags.len(), 3);
+		assert_eq!(tags.at(0).unwrap().as_str().unwrap(), "a");
+		assert_eq!(tags.at(1).unwrap().len(), 0);
+		assert!(tags.at(3).is_none());
+		assert_eq!(doc.get("name").unwrap().as_str().unwrap(), "café 😀\n\"q\"");
+
+		// integers beyond i64 become floats
+		let big = Json::parse("[9223372036854775807, -9223372036854775808, 9223372036854775808]")
+			.unwrap();
+		assert_eq!(big.at(0).unwrap().as_i64().unwrap(), i64::MAX);
+		assert_eq!(big.at(1).unwrap().as_i64().unwrap(), i64::MIN);
+		assert_eq!(big.at(2).unwrap().as_f64().unwrap(), 9223372036854775808.0);
+
+		let bad = [
+			"",
+			"{",
+			"[1,]",
+			"{\"a\" 1}",
+			"{1: 2}",
+			"01",
+			"1.",
+			"-",
+			"1e",
+			"tru",
+			"\"abc",
+			"\"\\x\"",
+			"\"\\ud800\"",
+			"1e400",
+			"\"\\udc00\"",
+			"\"a\tb\"",
+			"[1] 2",
+			"nul",
+		];
+		for s in bad {
+			assert!(Json::parse(s).unwrap_err().kind == CorruptedData);
+		}
+		assert!(Json::parse_bytes(b"\"\xff\"").unwrap_err().kind == Utf8);
+
+		// nesting is limited
+		let mut deep = Vec::new();
+		for _ in 0..JSON_MAX_DEPTH + 1 {
+			deep.push(b'[').unwrap();
+		}
+		assert!(Json::parse_bytes(deep.as_slice()).unwrap_err().kind == CorruptedData);
+	}
+
+	#[test]
+	fn test_json_write() {
+		let _alloc = AllocGuard::new();
+		let mut doc = Json::object();
+		doc.set("method", Json::string("subscribe").unwrap())
+			.unwrap();
+		doc.set("id", Json::Int(7)).unwrap();
+		let mut params = Json::array();
+		params.push(Json::Float(0.1)).unwrap();
+		params.push(Json::Float(3.0)).unwrap();
+		params.push(Json::Float(-2.5e-8)).unwrap();
+		params.push(Json::Null).unwrap();
+		params
+			.push(Json::string("tab\there \"x\" \\ \u{1}").unwrap())
+			.unwrap();
+		doc.set("params", params).unwrap();
+		doc.set("id", Json::Int(8)).unwrap();
+		assert!(doc.push(Json::Null).unwrap_err().kind == IllegalState);
+
+		let s = format!("{}", doc).unwrap();
+		assert_eq!(
+			s.to_str(),
+			"{\"method\":\"subscribe\",\"id\":8,\"params\":[0.1,3.0,-2.5e-08,null,\
+			 \"tab\\there \\\"x\\\" \\\\ \\u0001\"]}"
+		);
+
+		// what is written parses back to the same document
+		let read = Json::parse(s.to_str()).unwrap();
+		assert_eq!(format!("{}", read).unwrap().to_str(), s.to_str());
+		assert_eq!(
+			read.get("params").unwrap().at(1).unwrap().as_f64().unwrap(),
+			3.0
+		);
+
+		let inf = Json::Float(1.0 / 0.0);
+		assert!(format!("{}", inf).unwrap_err().kind == IllegalArgument);
+	}
+}
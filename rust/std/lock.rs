@@ -1,8 +1,14 @@
 use core::cell::UnsafeCell;
+use core::marker::Sized;
+use core::ops::{Deref, DerefMut};
+use ffi::{
+	condvar_destroy, condvar_handle_size, condvar_init, condvar_notify, condvar_seq, condvar_wait,
+};
 use prelude::*;
 
 const WFLAG: u64 = 0x1u64 << 63u64;
 const WREQUEST: u64 = 0x1u64 << 62u64;
+const CONDVAR_HANDLE_SIZE: usize = 128;
 
 pub struct Lock {
 	pub state: UnsafeCell<u64>,
@@ -13,7 +19,7 @@ struct LockBoxInner {
 }
 
 pub struct LockBox {
-	inner: Rc<LockBoxInner>,
+	inner: Arc<LockBoxInner>,
 }
 
 pub struct LockReadGuard<'a> {
@@ -26,6 +32,12 @@ pub struct LockWriteGuard<'a> {
 	need_unlock: bool,
 }
 
+// the state is only changed atomically
+unsafe impl Send for Lock {}
+unsafe impl Sync for Lock {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
 impl LockWriteGuard<'_> {
 	pub fn unlock(&mut self) {
 		if self.need_unlock {
@@ -34,6 +46,13 @@ impl LockWriteGuard<'_> {
 			self.need_unlock = false;
 		}
 	}
+
+	fn relock(&mut self) {
+		if !self.need_unlock {
+			self.lock.acquire_write();
+			self.need_unlock = true;
+		}
+	}
 }
 
 impl LockReadGuard<'_> {
@@ -82,6 +101,14 @@ impl Lock {
 	}
 
 	pub fn write<'a>(&'a self) -> LockWriteGuard<'a> {
+		self.acquire_write();
+		LockWriteGuard {
+			lock: self,
+			need_unlock: true,
+		}
+	}
+
+	fn acquire_write(&self) {
 		let state = unsafe { &mut *self.state.get() };
 
 		loop {
@@ -98,16 +125,217 @@ impl Lock {
 			}
 			sched_yield!();
 		}
-		LockWriteGuard {
-			lock: self,
-			need_unlock: true,
+	}
+}
+
+/// Owns a value which can only be reached through the guards returned by read and write,
+/// so the data cannot be touched without holding the lock. Any number of readers or a
+/// single writer. Share it between threads in an Arc.
+pub struct RwLock<T: ?Sized> {
+	lock: Lock,
+	data: UnsafeCell<T>,
+}
+
+/// Like RwLock, but every access is exclusive
+pub struct Mutex<T: ?Sized> {
+	lock: Lock,
+	data: UnsafeCell<T>,
+}
+
+/// Lets threads sleep until another thread changes the data behind a Mutex. wait releases
+/// the guard while the thread sleeps and takes the lock again before returning. Wakeups
+/// may be spurious, so callers wait in a loop which checks the condition they wait for.
+/// A notify made after the waiter released the guard is never missed.
+pub struct Condvar {
+	handle: Box<[u8; CONDVAR_HANDLE_SIZE]>,
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+	_guard: LockReadGuard<'a>,
+	data: &'a T,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+	_guard: LockWriteGuard<'a>,
+	data: &'a mut T,
+}
+
+pub struct MutexGuard<'a, T: ?Sized> {
+	_guard: LockWriteGuard<'a>,
+	data: &'a mut T,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		self.data
+	}
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		self.data
+	}
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.data
+	}
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		self.data
+	}
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		self.data
+	}
+}
+
+impl<T> RwLock<T> {
+	pub fn new(value: T) -> Self {
+		Self {
+			lock: Lock::new(),
+			data: UnsafeCell::new(value),
+		}
+	}
+
+	pub fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+}
+
+impl<T: ?Sized> RwLock<T> {
+	pub fn read<'a>(&'a self) -> RwLockReadGuard<'a, T> {
+		let guard = self.lock.read();
+		RwLockReadGuard {
+			_guard: guard,
+			data: unsafe { &*self.data.get() },
+		}
+	}
+
+	pub fn write<'a>(&'a self) -> RwLockWriteGuard<'a, T> {
+		let guard = self.lock.write();
+		RwLockWriteGuard {
+			_guard: guard,
+			data: unsafe { &mut *self.data.get() },
+		}
+	}
+
+	/// The value without locking, which the exclusive borrow makes safe
+	pub fn get_mut(&mut self) -> &mut T {
+		self.data.get_mut()
+	}
+}
+
+impl<T> Mutex<T> {
+	pub fn new(value: T) -> Self {
+		Self {
+			lock: Lock::new(),
+			data: UnsafeCell::new(value),
+		}
+	}
+
+	pub fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+}
+
+impl<T: ?Sized> Mutex<T> {
+	pub fn lock<'a>(&'a self) -> MutexGuard<'a, T> {
+		let guard = self.lock.write();
+		MutexGuard {
+			_guard: guard,
+			data: unsafe { &mut *self.data.get() },
+		}
+	}
+
+	/// The value without locking, which the exclusive borrow makes safe
+	pub fn get_mut(&mut self) -> &mut T {
+		self.data.get_mut()
+	}
+}
+
+impl Condvar {
+	pub fn new() -> Result<Self, Error> {
+		if unsafe { condvar_handle_size() } > CONDVAR_HANDLE_SIZE {
+			exit!("condvar_handle_size() > 128");
+		}
+		// boxed so that the pthread objects in the handle do not move
+		let handle = match Box::new([0u8; CONDVAR_HANDLE_SIZE]) {
+			Ok(handle) => handle,
+			Err(e) => return Err(e),
+		};
+		if unsafe { condvar_init((*handle).as_ptr()) } < 0 {
+			Err(err!(CondvarInit))
+		} else {
+			Ok(Self { handle })
+		}
+	}
+
+	pub fn wait<'a, T: ?Sized>(&self, mut guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+		self.sleep(&mut guard._guard, 0);
+		guard
+	}
+
+	/// Like wait but returns after at most micros. The flag is true if the wait timed out
+	/// without a notify.
+	pub fn wait_timeout<'a, T: ?Sized>(
+		&self,
+		mut guard: MutexGuard<'a, T>,
+		micros: u64,
+	) -> (MutexGuard<'a, T>, bool) {
+		// a timeout of 0 means forever to condvar_wait
+		let micros = if micros == 0 { 1 } else { micros };
+		let timed_out = !self.sleep(&mut guard._guard, micros);
+		(guard, timed_out)
+	}
+
+	/// Wakes one waiting thread
+	pub fn notify_one(&self) {
+		unsafe {
+			condvar_notify(self.handle(), false);
+		}
+	}
+
+	/// Wakes every waiting thread
+	pub fn notify_all(&self) {
+		unsafe {
+			condvar_notify(self.handle(), true);
+		}
+	}
+
+	fn handle(&self) -> *const u8 {
+		(*self.handle).as_ptr()
+	}
+
+	fn sleep(&self, guard: &mut LockWriteGuard, timeout_micros: u64) -> bool {
+		// read before unlocking, so that a notify which follows the unlock changes it
+		let seq = unsafe { condvar_seq(self.handle()) };
+		guard.unlock();
+		let notified = unsafe { condvar_wait(self.handle(), seq, timeout_micros) } == 0;
+		guard.relock();
+		notified
+	}
+}
+
+impl Drop for Condvar {
+	fn drop(&mut self) {
+		unsafe {
+			condvar_destroy(self.handle());
 		}
 	}
 }
 
 impl Clone for LockBox {
 	fn clone(&self) -> Result<Self, Error> {
-		// SAFETY: clone always succeeds on rc
+		// SAFETY: clone always succeeds on arc
 		Ok(LockBox {
 			inner: self.inner.clone().unwrap(),
 		})
@@ -116,7 +344,7 @@ impl Clone for LockBox {
 
 impl LockBox {
 	pub fn new() -> Result<Self, Error> {
-		match Rc::new(LockBoxInner { lock: lock!() }) {
+		match Arc::new(LockBoxInner { lock: lock!() }) {
 			Ok(inner) => Ok(Self { inner }),
 			Err(e) => Err(e),
 		}
@@ -134,8 +362,9 @@ impl LockBox {
 #[cfg(test)]
 mod test {
 	use super::WFLAG;
+	use core::mem::drop;
 	use prelude::*;
-	use std::lock::Lock;
+	use std::lock::{Condvar, Lock, Mutex, RwLock};
 	#[test]
 	fn test_lock() {
 		let x = Lock::new();
@@ -196,4 +425,109 @@ mod test {
 		}
 		assert_eq!(unsafe { *y.inner.lock.state.get() }, 0);
 	}
+
+	#[test]
+	fn test_rwlock_mutex() {
+		let _alloc = AllocGuard::new();
+		let x = RwLock::new(1u64);
+		{
+			let a = x.read();
+			let b = x.read();
+			assert_eq!(*a + *b, 2);
+			assert_eq!(unsafe { *x.lock.state.get() }, 2);
+		}
+		{
+			let mut w = x.write();
+			*w += 1;
+			assert_eq!(unsafe { *x.lock.state.get() }, WFLAG);
+		}
+		assert_eq!(unsafe { *x.lock.state.get() }, 0);
+		assert_eq!(*x.read(), 2);
+		assert_eq!(x.into_inner(), 2);
+
+		// writers from several threads each see the value the last one left
+		let m = Arc::new(Mutex::new(Vec::new())).unwrap();
+		let mut jhs = Vec::new();
+		for t in 0..4u64 {
+			let m = m.clone().unwrap();
+			jhs.push(
+				spawnj(move || {
+					for i in 0..100 {
+						let mut v = m.lock();
+						let len = v.len();
+						v.push(t * 100 + i).unwrap();
+						assert_eq!(v.len(), len + 1);
+					}
+				})
+				.unwrap(),
+			)
+			.unwrap();
+		}
+		for i in 0..jhs.len() {
+			jhs[i].join().unwrap();
+		}
+		let mut sum = 0;
+		let v = m.lock();
+		for i in 0..v.len() {
+			sum += v[i];
+		}
+		assert_eq!(v.len(), 400);
+		assert_eq!(sum, 399 * 400 / 2);
+	}
+
+	#[test]
+	fn test_condvar() {
+		let _alloc = AllocGuard::new();
+		let pair = Arc::new((Mutex::new(0u64), Condvar::new().unwrap())).unwrap();
+
+		// nothing notifies, so the wait times out with the lock held again
+		let (guard, timed_out) = pair.1.wait_timeout(pair.0.lock(), 1_000);
+		assert!(timed_out);
+		assert_eq!(unsafe { *pair.0.lock.state.get() }, WFLAG);
+		drop(guard);
+
+		// each thread waits for its turn, bumps the counter and wakes the others
+		let mut jhs = Vec::new();
+		for t in 0..4u64 {
+			let pair = pair.clone().unwrap();
+			jhs.push(
+				spawnj(move || {
+					let mut turn = pair.0.lock();
+					while *turn != t {
+						turn = pair.1.wait(turn);
+					}
+					*turn += 1;
+					pair.1.notify_all();
+				})
+				.unwrap(),
+			)
+			.unwrap();
+		}
+		{
+			let mut turn = pair.0.lock();
+			while *turn != 4 {
+				turn = pair.1.wait(turn);
+			}
+		}
+		for i in 0..jhs.len() {
+			jhs[i].join().unwrap();
+		}
+
+		// a notify before the timeout is reported as one
+		let pair2 = pair.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let mut v = pair2.0.lock();
+			*v = 5;
+			pair2.1.notify_one();
+		})
+		.unwrap();
+		let mut v = pair.0.lock();
+		while *v != 5 {
+			let (guard, timed_out) = pair.1.wait_timeout(v, 10_000_000);
+			assert!(!timed_out);
+			v = guard;
+		}
+		drop(v);
+		jh.join().unwrap();
+	}
 }
@@ -31,48 +31,27 @@ macro_rules! writeb {
         }};
         ($f:expr, $fmt:expr, $($t:expr),*) => {{
             let mut err = err!(Unknown);
-            match String::new($fmt) {
-                Ok(fmt) => {
-                    let mut cur = 0;
-                    $(
-                        match fmt.findn("{}", cur) {
-                            Some(index) => {
-                                match fmt.substring( cur, cur + index) {
-                                    Ok(s) => {
-                                        let s = s.to_str();
-                                        match $f.write_str(s, s.len()) {
-                                            Ok(_) => {},
-                                            Err(e) => err = e,
-                                        }
-                                        cur += index + 2;
-                                    }
-                                    Err(e) => err = e,
-                                }
-                            },
-                            None => {
-                            },
-                        }
-                        match $t.format(&mut $f) {
+            let fmt: &str = $fmt;
+            let mut cur = 0;
+            $(
+                match $f.begin_arg(fmt, &mut cur) {
+                    Ok(placeholder) => match $t.format(&mut $f) {
+                        Ok(_) => match $f.end_arg(placeholder) {
                             Ok(_) => {},
                             Err(e) => err = e,
-                        }
-                    )*
-
-                    match fmt.substring( cur, fmt.len()) {
-                        Ok(s) => {
-                            let s = s.to_str();
-                            match $f.write_str(s, s.len()) {
-                                Ok(_) =>{},
-                                Err(e) => err = e,
-                            }
-                        }
+                        },
                         Err(e) => err = e,
-                    }
+                    },
+                    Err(e) => err = e,
                 }
+            )*
+
+            let rest = &fmt[cur..];
+            match $f.write_str(rest, rest.len()) {
+                Ok(_) => {},
                 Err(e) => err = e,
             }
 
-
             if err.kind == ErrorKind::Unknown {
                 Ok(())
             } else {
@@ -173,6 +152,87 @@ macro_rules! err {
 	($kind:expr) => {{
 		Error::new($kind, line!(), file!())
 	}};
+	($kind:expr, $detail:expr) => {{
+		use std::error::ErrorDetail;
+		// evaluated first so that building the error cannot clobber errno
+		let detail = $detail;
+		ErrorDetail::attach(detail, Error::new($kind, line!(), file!()))
+	}};
+}
+
+/// An Error for a system call which just failed. errno is read at once and, if it names
+/// a condition of its own such as ECONNREFUSED, picks the kind instead of the given one.
+#[macro_export]
+macro_rules! oserr {
+	($kind:expr) => {{
+		use std::error::last_errno;
+		let errno = last_errno();
+		let kind = match ErrorKind::from_errno(errno) {
+			Some(kind) => kind,
+			None => $kind,
+		};
+		Error::new(kind, line!(), file!()).with_errno(errno)
+	}};
+}
+
+/// Like err! but builds a StaticError, which does not allocate
+#[macro_export]
+macro_rules! serr {
+	($kind:expr) => {{
+		StaticError::new($kind, line!(), file!())
+	}};
+	($kind:expr, $errno:expr) => {{
+		StaticError::new($kind, line!(), file!()).with_errno($errno)
+	}};
+}
+
+/// Implements std::ser::Writeable and Readable for a struct by writing its fields in the
+/// order given, as impl_ser!(Header { version, height }), or for a tuple struct with a
+/// single field, as impl_ser!(Id([u8; 32]))
+#[macro_export]
+macro_rules! impl_ser {
+	($name:ident { $($field:ident),* $(,)? }) => {
+		impl ::std::ser::Writeable for $name {
+			fn write<W: ::std::ser::Writer>(&self, w: &mut W) -> Result<(), Error> {
+				$(
+					match ::std::ser::Writeable::write(&self.$field, w) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+				)*
+				Ok(())
+			}
+		}
+
+		impl ::std::ser::Readable for $name {
+			fn read<R: ::std::ser::Reader>(r: &mut R) -> Result<Self, Error> {
+				Ok($name {
+					$(
+						$field: match ::std::ser::Readable::read(r) {
+							Ok(v) => v,
+							Err(e) => return Err(e),
+						},
+					)*
+				})
+			}
+		}
+	};
+	($name:ident($inner:ty)) => {
+		impl ::std::ser::Writeable for $name {
+			fn write<W: ::std::ser::Writer>(&self, w: &mut W) -> Result<(), Error> {
+				::std::ser::Writeable::write(&self.0, w)
+			}
+		}
+
+		impl ::std::ser::Readable for $name {
+			fn read<R: ::std::ser::Reader>(r: &mut R) -> Result<Self, Error> {
+				match <$inner as ::std::ser::Readable>::read(r) {
+					Ok(v) => Ok($name(v)),
+					Err(e) => Err(e),
+				}
+			}
+		}
+	};
 }
 
 #[macro_export]
@@ -0,0 +1,144 @@
+//! # Memory accounting
+//! Heap usage for monitoring a running process. The C allocator counts the bytes and
+//! blocks allocated through it in every build. On top of that, the owners of long lived
+//! memory charge it to a MemTag with a MemCharge, which is credited back when dropped, so
+//! that mem_stats can say what the heap is held by. Tags overlap the heap totals rather
+//! than dividing them up, and also cover memory the allocator does not see, such as the
+//! secp256k1 contexts which the library allocates itself.
+
+use ffi::{getallocblocks, getallocbytes};
+use prelude::*;
+
+const MEM_TAGS: usize = 3;
+
+static mut TAGGED_BYTES: [u64; MEM_TAGS] = [0; MEM_TAGS];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MemTag {
+	/// Send and receive buffers of connections
+	Buffer,
+	/// Connection state, not counting its buffers
+	Connection,
+	/// Contexts of the random number generator and of secp256k1
+	Crypto,
+}
+
+/// Bytes charged to a tag for as long as the charge lives
+pub struct MemCharge {
+	tag: MemTag,
+	bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct MemStats {
+	/// Bytes allocated and not yet released, as sized by the allocator
+	pub heap_bytes: u64,
+	/// Allocations not yet released
+	pub heap_blocks: u64,
+	pub buffer_bytes: u64,
+	pub connection_bytes: u64,
+	pub crypto_bytes: u64,
+}
+
+impl Drop for MemCharge {
+	fn drop(&mut self) {
+		self.set(0);
+	}
+}
+
+impl MemCharge {
+	pub fn new(tag: MemTag, bytes: u64) -> Self {
+		let mut ret = Self { tag, bytes: 0 };
+		ret.set(bytes);
+		ret
+	}
+
+	/// Changes the charge to bytes
+	#[allow(static_mut_refs)]
+	pub fn set(&mut self, bytes: u64) {
+		let counter = unsafe { &mut TAGGED_BYTES[self.tag as usize] };
+		if bytes > self.bytes {
+			aadd!(counter, bytes - self.bytes);
+		} else if bytes < self.bytes {
+			asub!(counter, self.bytes - bytes);
+		}
+		self.bytes = bytes;
+	}
+
+	pub fn bytes(&self) -> u64 {
+		self.bytes
+	}
+}
+
+impl Display for MemStats {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		writeb!(
+			*f,
+			"heap={} bytes in {} blocks, buffers={}, connections={}, crypto={}",
+			self.heap_bytes,
+			self.heap_blocks,
+			self.buffer_bytes,
+			self.connection_bytes,
+			self.crypto_bytes
+		)
+	}
+}
+
+/// Bytes currently charged to tag
+#[allow(static_mut_refs)]
+pub fn tagged_bytes(tag: MemTag) -> u64 {
+	aload!(&TAGGED_BYTES[tag as usize])
+}
+
+/// A snapshot of heap usage. Each figure is read on its own, so they may be a moment apart
+/// while other threads allocate.
+pub fn mem_stats() -> MemStats {
+	MemStats {
+		heap_bytes: unsafe { getallocbytes() },
+		heap_blocks: unsafe { getallocblocks() },
+		buffer_bytes: tagged_bytes(MemTag::Buffer),
+		connection_bytes: tagged_bytes(MemTag::Connection),
+		crypto_bytes: tagged_bytes(MemTag::Crypto),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+	use ffi::{alloc, resize};
+
+	#[test]
+	fn test_mem_stats() {
+		let _alloc = AllocGuard::new();
+		// other tests may run alongside, so only what this test holds is checked
+		let mut v: Vec<u8> = Vec::new();
+		v.resize(100_000).unwrap();
+		let stats = mem_stats();
+		assert!(stats.heap_bytes >= 100_000);
+		assert!(stats.heap_blocks > 0);
+		drop(v);
+
+		// a block resized to nothing is freed, which the guard checks was counted
+		let p = unsafe { alloc(64) };
+		assert!(!p.is_null());
+		assert!(unsafe { resize(p, 0) }.is_null());
+
+		let mut charge = MemCharge::new(MemTag::Crypto, 0);
+		charge.set(1 << 40);
+		assert!(tagged_bytes(MemTag::Crypto) >= 1 << 40);
+		assert_eq!(charge.bytes(), 1 << 40);
+		charge.set(1 << 41);
+		assert!(tagged_bytes(MemTag::Crypto) >= 1 << 41);
+		charge.set(1);
+		assert!(tagged_bytes(MemTag::Crypto) < 1 << 40);
+		let big = MemCharge::new(MemTag::Buffer, 1 << 42);
+		assert!(mem_stats().buffer_bytes >= 1 << 42);
+		drop(big);
+		assert!(mem_stats().buffer_bytes < 1 << 42);
+		drop(charge);
+
+		let s = format!("{}", mem_stats()).unwrap();
+		assert!(s.to_str().starts_with("heap="));
+	}
+}
@@ -1,21 +1,35 @@
 #[macro_use]
 pub mod macros;
 
+pub mod aead;
+pub mod arc;
+pub mod atomic;
 pub mod backtrace;
 pub mod boxed;
 pub mod channel;
 pub mod clone;
+pub mod cpsrng;
+pub mod deque;
+pub mod encoding;
 pub mod error;
 pub mod format;
+pub mod hash;
+pub mod json;
 pub mod lock;
+pub mod mem;
 pub mod murmur128;
 pub mod murmur32;
 pub mod option;
 pub mod ptr;
 pub mod rc;
 pub mod result;
+pub mod ser;
 pub mod string;
+pub mod test_support;
 pub mod thread;
 pub mod traits;
 pub mod util;
 pub mod vec;
+pub mod zeroize;
+
+pub use std::mem::mem_stats;
@@ -127,12 +127,8 @@ pub fn murmur3_x64_128_of_slice(source: &[u8], seed: u32) -> u128 {
 				k1 = k1.wrapping_mul(C2);
 				h1 ^= k1;
 
-				// handle checks for mrustc by checking read < len.
-				// this should always be true
-				let len = buf.len();
-				if read < len {
-					buf = &buf[read..len];
-				}
+				// the tail is always the final block
+				buf = &[];
 			}
 		}
 	}
@@ -150,3 +146,26 @@ fn fmix64(k: u64) -> u64 {
 	tmp ^= tmp >> R;
 	tmp
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_murmur128_vectors() {
+		// lengths with and without a partial final block
+		assert_eq!(murmur3_x64_128_of_slice(b"", 0), 0);
+		assert_eq!(
+			murmur3_x64_128_of_slice(b"hello", 0),
+			(0x5b1e906a48ae1d19 << 64) | 0xcbd8a7b341bd9b02
+		);
+		assert_eq!(
+			murmur3_x64_128_of_slice(b"The quick brown fox jumps over the lazy dog", 0),
+			(0x7a433ca9c49a9347 << 64) | 0xe34bbc7bbc071b6c
+		);
+		assert_eq!(
+			murmur3_128_of_u64(7, 1),
+			murmur3_x64_128_of_slice(&[7, 0, 0, 0, 0, 0, 0, 0], 1)
+		);
+	}
+}
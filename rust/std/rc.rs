@@ -1,31 +1,88 @@
-use core::marker::Sized;
+//! # Rc
+//! A reference counted pointer for values used from a single thread. The counts are plain
+//! integers, so an Rc is neither Send nor Sync and cannot be moved into a spawned thread;
+//! share values between threads with Arc instead.
+
+use core::marker::{PhantomData, Sized};
 use core::ops::{Deref, DerefMut, Drop};
+use core::ptr::drop_in_place;
+use ffi::release;
 use prelude::*;
 
+// weak counts the Weak references plus one held jointly by all the Rc references. The value
+// is dropped with the last Rc and the allocation released when weak reaches zero.
 struct RcInner<T: ?Sized> {
 	count: u64,
+	weak: u64,
 	value: T,
 }
 
+// the marker keeps an Rc from being Send or Sync, which spawn requires of what it moves to
+// the new thread
 pub struct Rc<T: ?Sized> {
 	inner: Box<RcInner<T>>,
+	_marker: PhantomData<*mut ()>,
+}
+
+/// A non-owning reference to the value of an Rc. It does not keep the value alive, so
+/// structures which point back at their owner can use it without forming a cycle. Call
+/// upgrade to get an Rc while the value still exists.
+pub struct Weak<T: ?Sized> {
+	inner: Box<RcInner<T>>,
+	_marker: PhantomData<*mut ()>,
 }
 
 impl<T: ?Sized> Clone for Rc<T> {
 	fn clone(&self) -> Result<Self, Error> {
-		let ptr = self.inner.as_ptr();
-		let mut inner: Box<RcInner<T>> = Box::from_raw(ptr);
+		let mut inner: Box<RcInner<T>> = Box::from_raw(self.inner.as_ptr());
 		inner.leak();
-		aadd!(&mut inner.count, 1);
-		Ok(Rc { inner })
+		inner.count += 1;
+		Ok(Rc {
+			inner,
+			_marker: PhantomData,
+		})
 	}
 }
 
 impl<T: ?Sized> Drop for Rc<T> {
 	fn drop(&mut self) {
 		let rci = self.inner.as_mut();
-		if asub!(&mut rci.count, 1) == 1 {
-			self.inner.unleak();
+		rci.count -= 1;
+		if rci.count == 0 {
+			unsafe {
+				drop_in_place(&mut rci.value);
+			}
+			release_weak(&mut self.inner);
+		}
+	}
+}
+
+impl<T: ?Sized> Clone for Weak<T> {
+	fn clone(&self) -> Result<Self, Error> {
+		let mut inner: Box<RcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		inner.weak += 1;
+		Ok(Weak {
+			inner,
+			_marker: PhantomData,
+		})
+	}
+}
+
+impl<T: ?Sized> Drop for Weak<T> {
+	fn drop(&mut self) {
+		release_weak(&mut self.inner);
+	}
+}
+
+// frees the allocation, but not the value which the last Rc has already dropped, once the
+// final weak reference goes
+fn release_weak<T: ?Sized>(inner: &mut Box<RcInner<T>>) {
+	let rci = inner.as_mut();
+	rci.weak -= 1;
+	if rci.weak == 0 {
+		unsafe {
+			release(inner.as_ptr().raw() as *const u8);
 		}
 	}
 }
@@ -49,7 +106,8 @@ impl<T: ?Sized> Rc<T> {
 	}
 
 	pub fn get_mut(&mut self) -> Option<&mut T> {
-		if aload!(&mut (*self.inner).count) == 1 {
+		// a Weak could otherwise be upgraded while the reference is held
+		if self.inner.count == 1 && self.inner.weak == 1 {
 			Some(&mut self.inner.value)
 		} else {
 			None
@@ -59,14 +117,60 @@ impl<T: ?Sized> Rc<T> {
 	pub unsafe fn get_mut_unchecked(&mut self) -> &mut T {
 		&mut self.inner.value
 	}
+
+	/// Creates a Weak reference to this value
+	pub fn downgrade(&self) -> Weak<T> {
+		let mut inner: Box<RcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		inner.weak += 1;
+		Weak {
+			inner,
+			_marker: PhantomData,
+		}
+	}
+
+	pub fn strong_count(&self) -> u64 {
+		self.inner.count
+	}
+
+	pub fn weak_count(&self) -> u64 {
+		self.inner.weak - 1
+	}
+}
+
+impl<T: ?Sized> Weak<T> {
+	/// Returns an Rc to the value, or None if every Rc has been dropped
+	pub fn upgrade(&self) -> Option<Rc<T>> {
+		if self.inner.count == 0 {
+			return None;
+		}
+		let mut inner: Box<RcInner<T>> = Box::from_raw(self.inner.as_ptr());
+		inner.leak();
+		inner.count += 1;
+		Some(Rc {
+			inner,
+			_marker: PhantomData,
+		})
+	}
+
+	pub fn strong_count(&self) -> u64 {
+		self.inner.count
+	}
 }
 
 impl<T> Rc<T> {
 	pub fn new(value: T) -> Result<Self, Error> {
-		match Box::new(RcInner { value, count: 1 }) {
+		match Box::new(RcInner {
+			value,
+			count: 1,
+			weak: 1,
+		}) {
 			Ok(mut inner) => {
 				inner.leak();
-				Ok(Self { inner })
+				Ok(Self {
+					inner,
+					_marker: PhantomData,
+				})
 			}
 			Err(e) => Err(e),
 		}
@@ -77,25 +181,22 @@ impl<T> Rc<T> {
 mod test {
 	#![allow(static_mut_refs)]
 	use super::*;
-	use ffi::getalloccount;
+	use core::mem::drop;
 
 	#[test]
 	fn test_rc1() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut x1 = Rc::new(1).unwrap();
-			assert!(x1.get_mut().is_some());
-			let mut x2 = x1.clone().unwrap();
-			assert!(x1.get_mut().is_none());
-			assert!(x2.get_mut().is_none());
+		let _alloc = AllocGuard::new();
+		let mut x1 = Rc::new(1).unwrap();
+		assert!(x1.get_mut().is_some());
+		let mut x2 = x1.clone().unwrap();
+		assert!(x1.get_mut().is_none());
+		assert!(x2.get_mut().is_none());
 
-			unsafe {
-				*x1.get_mut_unchecked() += 1;
-			}
-			assert_eq!(*x1.get(), 2);
-			assert_eq!(*x2.get(), 2);
+		unsafe {
+			*x1.get_mut_unchecked() += 1;
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(*x1.get(), 2);
+		assert_eq!(*x2.get(), 2);
 	}
 
 	static mut VTEST: usize = 0;
@@ -114,27 +215,85 @@ mod test {
 
 	#[test]
 	fn test_rc2() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 		{
+			let x = Rc::new(MyType { v: 1 }).unwrap();
+			assert_eq!(x.get().v, 1);
 			{
-				let x = Rc::new(MyType { v: 1 }).unwrap();
-				assert_eq!(x.get().v, 1);
-				{
-					let _y = x.clone();
-					let _z = MyType { v: 2 };
-					unsafe {
-						assert_eq!(VTEST, 0);
-					}
-				}
+				let _y = x.clone();
+				let _z = MyType { v: 2 };
 				unsafe {
-					assert_eq!(VTEST, 1);
+					assert_eq!(VTEST, 0);
 				}
 			}
 			unsafe {
-				assert_eq!(VTEST, 2);
+				assert_eq!(VTEST, 1);
 			}
 		}
+		unsafe {
+			assert_eq!(VTEST, 2);
+		}
+	}
+
+	struct Node {
+		parent: Option<Weak<Node>>,
+		children: Vec<Rc<Node>>,
+	}
 
-		assert_eq!(initial, unsafe { getalloccount() });
+	impl Drop for Node {
+		fn drop(&mut self) {
+			unsafe {
+				WTEST += 1;
+			}
+		}
+	}
+
+	static mut WTEST: usize = 0;
+
+	#[test]
+	fn test_rc_weak() {
+		let _alloc = AllocGuard::new();
+		let mut x = Rc::new(7u64).unwrap();
+		let w = x.downgrade();
+		assert_eq!(x.strong_count(), 1);
+		assert_eq!(x.weak_count(), 1);
+		// a live Weak could alias the mutable reference
+		assert!(x.get_mut().is_none());
+		{
+			let w2 = w.clone().unwrap();
+			let y = w2.upgrade().unwrap();
+			assert_eq!(*y, 7);
+			assert_eq!(x.strong_count(), 2);
+			assert_eq!(x.weak_count(), 2);
+		}
+		assert_eq!(x.strong_count(), 1);
+		assert_eq!(x.weak_count(), 1);
+		drop(x);
+		assert_eq!(w.strong_count(), 0);
+		assert!(w.upgrade().is_none());
+
+		// a weak back-reference lets a parent and child free each other
+		{
+			let mut parent = Rc::new(Node {
+				parent: None,
+				children: Vec::new(),
+			})
+			.unwrap();
+			let child = Rc::new(Node {
+				parent: Some(parent.downgrade()),
+				children: Vec::new(),
+			})
+			.unwrap();
+			unsafe {
+				parent.get_mut_unchecked().children.push(child).unwrap();
+			}
+			match &parent.children[0].parent {
+				Some(p) => assert_eq!(p.upgrade().unwrap().children.len(), 1),
+				None => panic!("the child has no parent"),
+			}
+		}
+		unsafe {
+			assert_eq!(WTEST, 2);
+		}
 	}
 }
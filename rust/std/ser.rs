@@ -0,0 +1,434 @@
+//! # Binary serialization
+//! Types implement Writeable and Readable to convert to and from bytes. The encoding is
+//! fixed so that equal values always produce equal bytes, which lets serialized data be
+//! hashed and signed. Integers are big endian, usize is written as a u64, bool and
+//! Option take one byte, and Vec and String are prefixed by their length as a u64.
+//! Fixed size byte arrays are written as they are. impl_ser! implements both traits for
+//! a struct from the list of its fields.
+//!
+//! A Reader rejects lengths larger than the bytes left, so a corrupted or hostile length
+//! prefix fails instead of allocating. Any malformed input is reported as CorruptedData.
+
+use core::marker::Sized;
+use prelude::*;
+
+/// Destination of serialized bytes
+pub trait Writer {
+	fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error>;
+
+	fn write_u8(&mut self, n: u8) -> Result<(), Error> {
+		self.write_bytes(&[n])
+	}
+
+	fn write_u16(&mut self, n: u16) -> Result<(), Error> {
+		self.write_bytes(&n.to_be_bytes())
+	}
+
+	fn write_u32(&mut self, n: u32) -> Result<(), Error> {
+		self.write_bytes(&n.to_be_bytes())
+	}
+
+	fn write_u64(&mut self, n: u64) -> Result<(), Error> {
+		self.write_bytes(&n.to_be_bytes())
+	}
+
+	/// Writes the length of bytes followed by the bytes
+	fn write_var_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		match self.write_u64(bytes.len() as u64) {
+			Ok(_) => self.write_bytes(bytes),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Source of serialized bytes
+pub trait Reader {
+	/// Fills out, or fails with CorruptedData if fewer bytes are left
+	fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), Error>;
+
+	/// Number of bytes left to read
+	fn remaining(&self) -> usize;
+
+	fn read_u8(&mut self) -> Result<u8, Error> {
+		let mut b = [0u8; 1];
+		match self.read_bytes(&mut b) {
+			Ok(_) => Ok(b[0]),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn read_u16(&mut self) -> Result<u16, Error> {
+		let mut b = [0u8; 2];
+		match self.read_bytes(&mut b) {
+			Ok(_) => Ok(u16::from_be_bytes(b)),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn read_u32(&mut self) -> Result<u32, Error> {
+		let mut b = [0u8; 4];
+		match self.read_bytes(&mut b) {
+			Ok(_) => Ok(u32::from_be_bytes(b)),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn read_u64(&mut self) -> Result<u64, Error> {
+		let mut b = [0u8; 8];
+		match self.read_bytes(&mut b) {
+			Ok(_) => Ok(u64::from_be_bytes(b)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Reads a length prefix. Each of the items it counts takes at least one byte, so a
+	/// length larger than the bytes left is CorruptedData.
+	fn read_len(&mut self) -> Result<usize, Error> {
+		match self.read_u64() {
+			Ok(n) if n <= self.remaining() as u64 => Ok(n as usize),
+			Ok(_) => Err(err!(CorruptedData)),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Reads bytes written by write_var_bytes
+	fn read_var_bytes(&mut self) -> Result<Vec<u8>, Error> {
+		let len = match self.read_len() {
+			Ok(len) => len,
+			Err(e) => return Err(e),
+		};
+		let mut ret = Vec::new();
+		match ret.resize(len) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		match self.read_bytes(ret.as_mut_slice()) {
+			Ok(_) => Ok(ret),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+pub trait Writeable {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error>;
+}
+
+pub trait Readable: Sized {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error>;
+}
+
+impl Writer for Vec<u8> {
+	fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		if bytes.len() == 0 {
+			return Ok(());
+		}
+		self.append_ptr(bytes.as_ptr(), bytes.len())
+	}
+}
+
+/// Reads from a slice of bytes
+pub struct SliceReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+	pub fn new(data: &'a [u8]) -> Self {
+		Self { data, pos: 0 }
+	}
+}
+
+impl<'a> Reader for SliceReader<'a> {
+	fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), Error> {
+		if out.len() > self.data.len() - self.pos {
+			return Err(err!(CorruptedData));
+		}
+		out.copy_from_slice(&self.data[self.pos..self.pos + out.len()]);
+		self.pos += out.len();
+		Ok(())
+	}
+
+	fn remaining(&self) -> usize {
+		self.data.len() - self.pos
+	}
+}
+
+/// The bytes of t
+pub fn serialize<T: Writeable>(t: &T) -> Result<Vec<u8>, Error> {
+	let mut ret = Vec::new();
+	match t.write(&mut ret) {
+		Ok(_) => Ok(ret),
+		Err(e) => Err(e),
+	}
+}
+
+/// The value serialized in data, which must hold nothing else
+pub fn deserialize<T: Readable>(data: &[u8]) -> Result<T, Error> {
+	let mut r = SliceReader::new(data);
+	match T::read(&mut r) {
+		Ok(t) if r.remaining() == 0 => Ok(t),
+		Ok(_) => Err(err!(CorruptedData)),
+		Err(e) => Err(e),
+	}
+}
+
+macro_rules! impl_ser_int {
+    ($($t:ty => $write:ident, $read:ident, $u:ty);*) => {
+        $(
+            impl Writeable for $t {
+                fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+                    w.$write(*self as $u)
+                }
+            }
+
+            impl Readable for $t {
+                fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+                    match r.$read() {
+                        Ok(n) => Ok(n as $t),
+                        Err(e) => Err(e),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_ser_int!(
+	u8 => write_u8, read_u8, u8;
+	i8 => write_u8, read_u8, u8;
+	u16 => write_u16, read_u16, u16;
+	i16 => write_u16, read_u16, u16;
+	u32 => write_u32, read_u32, u32;
+	i32 => write_u32, read_u32, u32;
+	u64 => write_u64, read_u64, u64;
+	i64 => write_u64, read_u64, u64;
+	usize => write_u64, read_u64, u64
+);
+
+impl Writeable for bool {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+		w.write_u8(*self as u8)
+	}
+}
+
+impl Readable for bool {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+		match r.read_u8() {
+			Ok(0) => Ok(false),
+			Ok(1) => Ok(true),
+			Ok(_) => Err(err!(CorruptedData)),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl<const N: usize> Writeable for [u8; N] {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+		w.write_bytes(self)
+	}
+}
+
+impl<const N: usize> Readable for [u8; N] {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+		let mut ret = [0u8; N];
+		match r.read_bytes(&mut ret) {
+			Ok(_) => Ok(ret),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl Writeable for String {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+		w.write_var_bytes(self.to_str().as_bytes())
+	}
+}
+
+impl Readable for String {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+		match r.read_var_bytes() {
+			Ok(bytes) => String::from_utf8(bytes.as_slice()),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl<T: Writeable> Writeable for Vec<T> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+		match w.write_u64(self.len() as u64) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		for t in self {
+			match t.write(w) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+}
+
+impl<T: Readable> Readable for Vec<T> {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+		let len = match r.read_len() {
+			Ok(len) => len,
+			Err(e) => return Err(e),
+		};
+		let mut ret = Vec::new();
+		for _ in 0..len {
+			match T::read(r) {
+				Ok(t) => match ret.push(t) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(ret)
+	}
+}
+
+impl<T: Writeable> Writeable for Option<T> {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), Error> {
+		match self {
+			Some(t) => match w.write_u8(1) {
+				Ok(_) => t.write(w),
+				Err(e) => Err(e),
+			},
+			None => w.write_u8(0),
+		}
+	}
+}
+
+impl<T: Readable> Readable for Option<T> {
+	fn read<R: Reader>(r: &mut R) -> Result<Self, Error> {
+		match r.read_u8() {
+			Ok(0) => Ok(None),
+			Ok(1) => match T::read(r) {
+				Ok(t) => Ok(Some(t)),
+				Err(e) => Err(e),
+			},
+			Ok(_) => Err(err!(CorruptedData)),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct Header {
+		version: u16,
+		height: u64,
+		offset: i32,
+		flags: [u8; 4],
+	}
+	impl_ser!(Header {
+		version,
+		height,
+		offset,
+		flags
+	});
+
+	struct Id([u8; 8]);
+	impl_ser!(Id([u8; 8]));
+
+	struct Block {
+		header: Header,
+		id: Id,
+		name: String,
+		parent: Option<Id>,
+		values: Vec<u32>,
+		confirmed: bool,
+	}
+	impl_ser!(Block {
+		header,
+		id,
+		name,
+		parent,
+		values,
+		confirmed,
+	});
+
+	#[test]
+	fn test_ser_primitives() {
+		let _alloc = AllocGuard::new();
+		let bytes = serialize(&0x0102u16).unwrap();
+		assert_eq!(bytes.as_slice(), &[1, 2]);
+		let bytes = serialize(&-2i32).unwrap();
+		assert_eq!(bytes.as_slice(), &[0xff, 0xff, 0xff, 0xfe]);
+		assert_eq!(deserialize::<i32>(bytes.as_slice()).unwrap(), -2);
+		let bytes = serialize(&7usize).unwrap();
+		assert_eq!(bytes.as_slice(), &[0, 0, 0, 0, 0, 0, 0, 7]);
+		assert_eq!(deserialize::<usize>(bytes.as_slice()).unwrap(), 7);
+		assert_eq!(deserialize::<bool>(&[1]).unwrap(), true);
+		assert!(deserialize::<bool>(&[2]).unwrap_err().kind == CorruptedData);
+
+		let s = String::new("héllo").unwrap();
+		let bytes = serialize(&s).unwrap();
+		assert_eq!(bytes.len(), 8 + 6);
+		assert_eq!(
+			deserialize::<String>(bytes.as_slice()).unwrap().to_str(),
+			"héllo"
+		);
+		assert!(
+			deserialize::<String>(&[0, 0, 0, 0, 0, 0, 0, 1, 0xff])
+				.unwrap_err()
+				.kind == Utf8
+		);
+
+		// truncated input, trailing bytes and a length beyond the end
+		assert!(deserialize::<u32>(&[1, 2, 3]).unwrap_err().kind == CorruptedData);
+		assert!(deserialize::<u8>(&[1, 2]).unwrap_err().kind == CorruptedData);
+		let e = deserialize::<Vec<u8>>(&[0xff, 0, 0, 0, 0, 0, 0, 0, 1]).unwrap_err();
+		assert!(e.kind == CorruptedData);
+	}
+
+	#[test]
+	fn test_ser_struct() {
+		let _alloc = AllocGuard::new();
+		let mut values = Vec::new();
+		for i in 0..5 {
+			values.push(i * 1000).unwrap();
+		}
+		let block = Block {
+			header: Header {
+				version: 3,
+				height: 1_000_000,
+				offset: -7,
+				flags: [1, 2, 3, 4],
+			},
+			id: Id([9; 8]),
+			name: String::new("tip").unwrap(),
+			parent: Some(Id([8; 8])),
+			values,
+			confirmed: true,
+		};
+		let bytes = serialize(&block).unwrap();
+		assert_eq!(bytes.len(), 18 + 8 + 11 + 9 + 28 + 1);
+		// the same value always gives the same bytes
+		assert_eq!(serialize(&block).unwrap().as_slice(), bytes.as_slice());
+
+		let read: Block = deserialize(bytes.as_slice()).unwrap();
+		assert_eq!(read.header.version, 3);
+		assert_eq!(read.header.height, 1_000_000);
+		assert_eq!(read.header.offset, -7);
+		assert_eq!(read.header.flags, [1, 2, 3, 4]);
+		assert_eq!(read.id.0, [9; 8]);
+		assert_eq!(read.name.to_str(), "tip");
+		match read.parent {
+			Some(ref id) => assert_eq!(id.0, [8; 8]),
+			None => panic!("no parent"),
+		}
+		assert_eq!(read.values.as_slice(), &[0, 1000, 2000, 3000, 4000]);
+		assert!(read.confirmed);
+		assert_eq!(serialize(&read).unwrap().as_slice(), bytes.as_slice());
+
+		// every truncation of the bytes is rejected
+		for i in 0..bytes.len() {
+			assert!(deserialize::<Block>(&bytes.as_slice()[0..i]).is_err());
+		}
+	}
+}
@@ -1,14 +1,16 @@
 extern crate core;
 use core::cmp::PartialEq;
 use core::fmt::Debug;
+use core::option::Option as CoreOption;
 use core::ptr::copy_nonoverlapping;
+use core::result::Result as CoreResult;
 use core::slice::from_raw_parts;
-use core::str::from_utf8_unchecked;
+use core::str::{from_utf8, from_utf8_unchecked, Chars};
 use prelude::*;
 use std::util::strcmp;
 
 pub struct String {
-	value: Option<Rc<Box<[u8]>>>,
+	value: Option<Arc<Box<[u8]>>>,
 	end: usize,
 	start: usize,
 }
@@ -54,7 +56,7 @@ impl String {
 				unsafe {
 					copy_nonoverlapping(s.as_ptr(), valueptr, end);
 				}
-				match Rc::new(value) {
+				match Arc::new(value) {
 					Ok(rc) => Ok(Self {
 						value: Some(rc),
 						start,
@@ -67,6 +69,14 @@ impl String {
 		}
 	}
 
+	/// Copies bytes into a new String, returning Utf8 if they are not valid UTF-8
+	pub fn from_utf8(bytes: &[u8]) -> Result<Self, Error> {
+		match from_utf8(bytes) {
+			CoreResult::Ok(s) => Self::new(s),
+			CoreResult::Err(_e) => Err(err!(Utf8)),
+		}
+	}
+
 	pub fn empty() -> Self {
 		Self {
 			value: None,
@@ -77,6 +87,8 @@ impl String {
 
 	pub fn to_str(&self) -> &str {
 		match &self.value {
+			// the buffer of an empty string may be null
+			Some(_) if self.end == self.start => "",
 			Some(value) => {
 				let ptr = value.get().as_ptr().raw() as *const u8;
 				let ptr = unsafe { ptr.add(self.start) };
@@ -157,56 +169,394 @@ impl String {
 		}
 		None
 	}
+	/// Iterates over the characters of the string
+	pub fn chars(&self) -> Chars<'_> {
+		self.to_str().chars()
+	}
+
+	pub fn starts_with(&self, s: &str) -> bool {
+		let b = self.to_str().as_bytes();
+		b.len() >= s.len() && &b[0..s.len()] == s.as_bytes()
+	}
+
+	pub fn ends_with(&self, s: &str) -> bool {
+		let b = self.to_str().as_bytes();
+		b.len() >= s.len() && &b[b.len() - s.len()..] == s.as_bytes()
+	}
+
+	/// Substring with leading and trailing ASCII whitespace removed
+	pub fn trim(&self) -> Result<Self, Error> {
+		let b = self.to_str().as_bytes();
+		let mut start = 0;
+		let mut end = b.len();
+		while start < end && b[start].is_ascii_whitespace() {
+			start += 1;
+		}
+		while end > start && b[end - 1].is_ascii_whitespace() {
+			end -= 1;
+		}
+		self.substring(start, end)
+	}
+
+	/// Iterates over the substrings separated by pat, which may be a char or a &str. An
+	/// empty separator yields the whole string. The substrings share this string's buffer.
+	pub fn split<P: Pattern>(&self, pat: P) -> Split<'_, P> {
+		Split {
+			s: self,
+			pat,
+			pos: 0,
+			done: false,
+		}
+	}
+
+	/// Parses an unsigned decimal integer. Anything other than digits is IllegalArgument.
+	pub fn parse_u64(&self) -> Result<u64, Error> {
+		let b = self.to_str().as_bytes();
+		if b.len() == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut v: u64 = 0;
+		for c in b {
+			if !c.is_ascii_digit() {
+				return Err(err!(IllegalArgument));
+			}
+			v = match v.checked_mul(10) {
+				CoreOption::Some(v) => match v.checked_add((c - b'0') as u64) {
+					CoreOption::Some(v) => v,
+					CoreOption::None => return Err(err!(Overflow)),
+				},
+				CoreOption::None => return Err(err!(Overflow)),
+			};
+		}
+		Ok(v)
+	}
+
+	/// Parses a decimal integer with an optional leading '-' or '+'
+	pub fn parse_i64(&self) -> Result<i64, Error> {
+		let negative = self.starts_with("-");
+		let digits = if negative || self.starts_with("+") {
+			match self.substring(1, self.len()) {
+				Ok(digits) => digits,
+				Err(e) => return Err(e),
+			}
+		} else {
+			match self.clone() {
+				Ok(digits) => digits,
+				Err(e) => return Err(e),
+			}
+		};
+		let v = match digits.parse_u64() {
+			Ok(v) => v,
+			Err(e) => return Err(e),
+		};
+		if negative {
+			if v > i64::MAX as u64 + 1 {
+				Err(err!(Overflow))
+			} else {
+				Ok((v as i64).wrapping_neg())
+			}
+		} else if v > i64::MAX as u64 {
+			Err(err!(Overflow))
+		} else {
+			Ok(v as i64)
+		}
+	}
+}
+
+/// Separator for String::split
+pub trait Pattern {
+	/// Offset and length in bytes of the first match in s
+	fn find_in(&self, s: &str) -> Option<(usize, usize)>;
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+	if needle.len() > haystack.len() {
+		return None;
+	}
+	for i in 0..haystack.len() - needle.len() + 1 {
+		if &haystack[i..i + needle.len()] == needle {
+			return Some(i);
+		}
+	}
+	None
+}
+
+impl Pattern for char {
+	fn find_in(&self, s: &str) -> Option<(usize, usize)> {
+		let mut buf = [0u8; 4];
+		let c = self.encode_utf8(&mut buf).as_bytes();
+		match find_bytes(s.as_bytes(), c) {
+			Some(i) => Some((i, c.len())),
+			None => None,
+		}
+	}
+}
+
+impl Pattern for &str {
+	fn find_in(&self, s: &str) -> Option<(usize, usize)> {
+		if self.len() == 0 {
+			return None;
+		}
+		match find_bytes(s.as_bytes(), self.as_bytes()) {
+			Some(i) => Some((i, self.len())),
+			None => None,
+		}
+	}
+}
+
+pub struct Split<'a, P: Pattern> {
+	s: &'a String,
+	pat: P,
+	pos: usize,
+	done: bool,
+}
+
+impl<P: Pattern> Iterator for Split<'_, P> {
+	type Item = String;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		if self.done {
+			return CoreOption::None;
+		}
+		let start = self.pos;
+		let end = match self.pat.find_in(&self.s.to_str()[start..]) {
+			Some((offset, len)) => {
+				self.pos = start + offset + len;
+				start + offset
+			}
+			None => {
+				self.done = true;
+				self.s.len()
+			}
+		};
+		match self.s.substring(start, end) {
+			Ok(s) => CoreOption::Some(s),
+			Err(_e) => CoreOption::None,
+		}
+	}
+}
+
+/// Growable text for assembling strings piece by piece. Appends are amortized by the
+/// underlying buffer and to_string produces an immutable String once done.
+pub struct StringBuilder {
+	f: Formatter,
+}
+
+impl Display for StringBuilder {
+	fn format(&self, f: &mut Formatter) -> Result<(), Error> {
+		f.write_str(self.as_str(), self.len())
+	}
+}
+
+impl StringBuilder {
+	pub fn new() -> Self {
+		Self {
+			f: Formatter::new(),
+		}
+	}
+
+	pub fn push_str(&mut self, s: &str) -> Result<(), Error> {
+		self.f.write_str(s, s.len())
+	}
+
+	/// Appends each of parts in order
+	pub fn push_strs(&mut self, parts: &[&str]) -> Result<(), Error> {
+		for part in parts {
+			match self.f.write_str(part, part.len()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(())
+	}
+
+	pub fn push_char(&mut self, c: char) -> Result<(), Error> {
+		c.format(&mut self.f)
+	}
+
+	/// Appends v as it would be formatted by writeb!
+	pub fn push<T: Display>(&mut self, v: &T) -> Result<(), Error> {
+		v.format(&mut self.f)
+	}
+
+	pub fn len(&self) -> usize {
+		self.f.len()
+	}
+
+	pub fn as_str(&self) -> &str {
+		self.f.as_str()
+	}
+
+	pub fn clear(&mut self) {
+		self.f.clear();
+	}
+
+	pub fn to_string(&self) -> Result<String, Error> {
+		String::new(self.as_str())
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
-	use ffi::getalloccount;
 
 	#[test]
 	fn test_strings() {
-		let initial = unsafe { getalloccount() };
-		{
-			let x1 = String::new("abcdefghijkl").unwrap();
-			assert_eq!(x1.len(), 12);
-			assert_eq!(x1.to_str(), "abcdefghijkl");
-			assert_eq!(x1.substring(3, 6).unwrap().to_str(), "def");
-			let x2 = x1.substring(3, 9).unwrap();
-			assert_eq!(x2.to_str(), "defghi");
-			assert_eq!(x2, String::new("defghi").unwrap());
-			assert_eq!(x1, String::new("abcdefghijkl").unwrap());
-			assert_eq!(x1.find("bc"), Some(1));
-			assert_eq!(x1.find("aa"), None);
-			assert_eq!(x1.find(""), Some(0));
-			let x2 = String::new("").unwrap();
-			assert_eq!(x2.len(), 0);
-			let x3 = String::new("aaabbbcccaaa").unwrap();
-			assert_eq!(x3.rfind("aaa"), Some(9));
-			assert_eq!(x3.rfind("ajlsfdjklasdjlfalsjkdfjklasdf"), None);
-			assert_eq!(x3.rfind("aaaa"), None);
-			assert_eq!(x3.find("ajlsfdjklasdjlfalsjkdfjklasdf"), None);
-			let x4 = String::new("0123456789012345678901234567890123456789").unwrap();
-			assert_eq!(x4.find("012"), Some(0));
-
-			let x5 = x4.clone().unwrap();
-			assert_eq!(x5.find("012"), Some(0));
-			assert_eq!(x5.rfind("012"), Some(30));
-
-			let x6 = x5.substring(5, 15).unwrap();
-			let x7 = x6.to_str().as_bytes();
-			assert_eq!(x7.len(), 10);
-			assert_eq!(x7[0], b'5');
-			let x8 = x5.substring(6, 6).unwrap();
-			assert_eq!(x8.len(), 0);
-
-			let x9 = match String::new("test") {
-				Ok(s) => s,
-				Err(_e) => String::new("").unwrap(),
-			};
-			assert_eq!(x9.len(), 4);
+		let _alloc = AllocGuard::new();
+		let x1 = String::new("abcdefghijkl").unwrap();
+		assert_eq!(x1.len(), 12);
+		assert_eq!(x1.to_str(), "abcdefghijkl");
+		assert_eq!(x1.substring(3, 6).unwrap().to_str(), "def");
+		let x2 = x1.substring(3, 9).unwrap();
+		assert_eq!(x2.to_str(), "defghi");
+		assert_eq!(x2, String::new("defghi").unwrap());
+		assert_eq!(x1, String::new("abcdefghijkl").unwrap());
+		assert_eq!(x1.find("bc"), Some(1));
+		assert_eq!(x1.find("aa"), None);
+		assert_eq!(x1.find(""), Some(0));
+		let x2 = String::new("").unwrap();
+		assert_eq!(x2.len(), 0);
+		let x3 = String::new("aaabbbcccaaa").unwrap();
+		assert_eq!(x3.rfind("aaa"), Some(9));
+		assert_eq!(x3.rfind("ajlsfdjklasdjlfalsjkdfjklasdf"), None);
+		assert_eq!(x3.rfind("aaaa"), None);
+		assert_eq!(x3.find("ajlsfdjklasdjlfalsjkdfjklasdf"), None);
+		let x4 = String::new("0123456789012345678901234567890123456789").unwrap();
+		assert_eq!(x4.find("012"), Some(0));
+
+		let x5 = x4.clone().unwrap();
+		assert_eq!(x5.find("012"), Some(0));
+		assert_eq!(x5.rfind("012"), Some(30));
+
+		let x6 = x5.substring(5, 15).unwrap();
+		let x7 = x6.to_str().as_bytes();
+		assert_eq!(x7.len(), 10);
+		assert_eq!(x7[0], b'5');
+		let x8 = x5.substring(6, 6).unwrap();
+		assert_eq!(x8.len(), 0);
+
+		let x9 = match String::new("test") {
+			Ok(s) => s,
+			Err(_e) => String::new("").unwrap(),
+		};
+		assert_eq!(x9.len(), 4);
+	}
+
+	#[test]
+	fn test_string_builder() {
+		let _alloc = AllocGuard::new();
+		let mut b = StringBuilder::new();
+		assert_eq!(b.len(), 0);
+		assert_eq!(b.as_str(), "");
+		assert!(b.push_str("GET / HTTP/1.1").is_ok());
+		assert!(b.push_strs(&["\r\n", "Host: ", "", "x"]).is_ok());
+		assert!(b.push_char('\n').is_ok());
+		assert!(b.push_char('é').is_ok());
+		assert!(b.push(&-42i32).is_ok());
+		assert!(b.push(&true).is_ok());
+		assert!(b.push(&String::new(" end").unwrap()).is_ok());
+		assert_eq!(b.as_str(), "GET / HTTP/1.1\r\nHost: x\né-42true end");
+		assert_eq!(b.len(), b.as_str().len());
+
+		let s = b.to_string().unwrap();
+		assert_eq!(s.to_str(), b.as_str());
+		assert_eq!(
+			format!("[{}]", b).unwrap().to_str(),
+			"[GET / HTTP/1.1\r\nHost: x\né-42true end]"
+		);
+
+		b.clear();
+		assert_eq!(b.as_str(), "");
+		for i in 0..1000 {
+			assert!(b.push(&(i % 10)).is_ok());
 		}
+		assert_eq!(b.len(), 1000);
+		assert_eq!(&b.as_str()[995..], "56789");
+		assert_eq!(s.len(), 37);
+	}
+
+	#[test]
+	fn test_string_split_trim_parse() {
+		let _alloc = AllocGuard::new();
+		let s = String::new("Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n").unwrap();
+		let mut lines = s.split("\r\n");
+		assert_eq!(lines.next().unwrap().to_str(), "Upgrade: websocket");
+		assert_eq!(lines.next().unwrap().to_str(), "Connection: Upgrade");
+		assert_eq!(lines.next().unwrap().len(), 0);
+		assert_eq!(lines.next().unwrap().len(), 0);
+		assert!(lines.next().is_none());
+
+		let mut count = 0;
+		for (i, part) in String::new("a,b,,c").unwrap().split(',').enumerate() {
+			assert_eq!(part.to_str(), ["a", "b", "", "c"][i]);
+			count += 1;
+		}
+		assert_eq!(count, 4);
+		assert_eq!(String::new("").unwrap().split(',').count(), 1);
+		assert_eq!(String::new("abc").unwrap().split("").count(), 1);
+		let s = String::new("1é2").unwrap();
+		let mut parts = s.split('é');
+		assert_eq!(parts.next().unwrap().to_str(), "1");
+		assert_eq!(parts.next().unwrap().to_str(), "2");
+
+		let header = String::new("  Sec-WebSocket-Version: 13 \r\n").unwrap();
+		let header = header.trim().unwrap();
+		assert_eq!(header.to_str(), "Sec-WebSocket-Version: 13");
+		assert!(header.starts_with("Sec-WebSocket-"));
+		assert!(header.ends_with(": 13"));
+		assert!(header.starts_with(""));
+		assert!(!header.ends_with("12"));
+		assert!(!String::new("a").unwrap().starts_with("ab"));
+		assert_eq!(String::new(" \t ").unwrap().trim().unwrap().len(), 0);
+		let mut kv = header.split(':');
+		assert_eq!(kv.next().unwrap().to_str(), "Sec-WebSocket-Version");
+		let v = kv.next().unwrap().trim().unwrap();
+		assert!(v.parse_u64() == Ok(13));
 
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert!(String::new("18446744073709551615").unwrap().parse_u64() == Ok(u64::MAX));
+		let e = String::new("18446744073709551616").unwrap().parse_u64();
+		assert!(e.unwrap_err().kind == ErrorKind::Overflow);
+		assert!(String::new("").unwrap().parse_u64().is_err());
+		assert!(String::new("12a").unwrap().parse_u64().is_err());
+		assert!(String::new("-1").unwrap().parse_u64().is_err());
+		assert!(String::new("-9223372036854775808").unwrap().parse_i64() == Ok(i64::MIN));
+		assert!(String::new("+9223372036854775807").unwrap().parse_i64() == Ok(i64::MAX));
+		assert!(String::new("9223372036854775808")
+			.unwrap()
+			.parse_i64()
+			.is_err());
+		assert!(String::new("-").unwrap().parse_i64().is_err());
+		assert!(String::new("-42").unwrap().parse_i64() == Ok(-42));
+	}
+
+	#[test]
+	fn test_string_from_utf8() {
+		let _alloc = AllocGuard::new();
+		let s = String::from_utf8("héllo €𝄞".as_bytes()).unwrap();
+		assert_eq!(s.to_str(), "héllo €𝄞");
+		assert_eq!(s.chars().count(), 8);
+		let mut chars = s.chars();
+		assert_eq!(chars.next(), CoreOption::Some('h'));
+		assert_eq!(chars.next(), CoreOption::Some('é'));
+		assert_eq!(s.chars().last(), CoreOption::Some('𝄞'));
+		assert_eq!(String::from_utf8(b"").unwrap().chars().count(), 0);
+		assert_eq!(
+			s.substring(1, 3).unwrap().chars().next(),
+			CoreOption::Some('é')
+		);
+
+		// truncated sequence, overlong encoding, surrogate and stray continuation byte
+		for bad in [
+			&b"\xc3"[..],
+			b"\xc0\xaf",
+			b"\xed\xa0\x80",
+			b"a\x80b",
+			b"\xff",
+		] {
+			match String::from_utf8(bad) {
+				Ok(_) => panic!("accepted invalid utf8"),
+				Err(e) => assert!(e.kind == ErrorKind::Utf8),
+			}
+		}
 	}
 }
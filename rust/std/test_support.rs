@@ -0,0 +1,228 @@
+//! # Leak-check guards
+//! RAII guards which snapshot the allocation or file descriptor count on creation and
+//! assert it is unchanged when they are dropped. Counts are only tracked when the C
+//! objects are built with `-DTEST`, otherwise both counts stay at zero and the guards
+//! never fire. A guard dropped while its test is already panicking does not check, as a
+//! failed test rarely cleans up and a second panic would abort the test run.
+//!
+//! Guards should be declared first in a test so they are dropped after everything else.
+//!
+//! TestCounts lets a test block until handlers running on other threads have been called
+//! a number of times, rather than polling for it.
+
+use ffi::{getalloccount, getfdcount, getmicros};
+use prelude::*;
+use std::mem::{tagged_bytes, MemTag};
+
+const TAGS: [MemTag; 3] = [MemTag::Buffer, MemTag::Connection, MemTag::Crypto];
+// how long TestCounts::wait blocks before giving up
+const WAIT_MICROS: i64 = 5_000_000;
+
+pub struct AllocGuard {
+	initial: i64,
+	tagged: [u64; 3],
+}
+
+pub struct FdGuard {
+	initial: i64,
+}
+
+/// Four counters shared between a test and the threads it starts. Clones share the counts.
+pub struct TestCounts {
+	inner: Arc<(Mutex<[u64; 4]>, Condvar)>,
+}
+
+impl Drop for AllocGuard {
+	fn drop(&mut self) {
+		if panicking() {
+			return;
+		}
+		let cur = unsafe { getalloccount() };
+		if cur != self.initial {
+			// the tags are shared with any test running alongside, so they are only reported
+			let tagged = self.tagged_diff();
+			panic!(
+				"AllocGuard: allocation count changed by {} (initial={}, now={}), tagged bytes changed by buffers={:+} connections={:+} crypto={:+}",
+				cur - self.initial,
+				self.initial,
+				cur,
+				tagged[0],
+				tagged[1],
+				tagged[2]
+			);
+		}
+	}
+}
+
+impl AllocGuard {
+	pub fn new() -> Self {
+		let mut tagged = [0u64; 3];
+		for i in 0..TAGS.len() {
+			tagged[i] = tagged_bytes(TAGS[i]);
+		}
+		Self {
+			initial: unsafe { getalloccount() },
+			tagged,
+		}
+	}
+
+	/// Allocations made (positive) or released (negative) since the guard was created
+	pub fn diff(&self) -> i64 {
+		unsafe { getalloccount() - self.initial }
+	}
+
+	/// Bytes charged to the buffer, connection and crypto tags since the guard was created
+	pub fn tagged_diff(&self) -> [i64; 3] {
+		let mut ret = [0i64; 3];
+		for i in 0..TAGS.len() {
+			ret[i] = tagged_bytes(TAGS[i]) as i64 - self.tagged[i] as i64;
+		}
+		ret
+	}
+}
+
+impl Drop for FdGuard {
+	fn drop(&mut self) {
+		if panicking() {
+			return;
+		}
+		let cur = unsafe { getfdcount() };
+		if cur != self.initial {
+			panic!(
+				"FdGuard: open file descriptor count changed by {} (initial={}, now={})",
+				cur - self.initial,
+				self.initial,
+				cur
+			);
+		}
+	}
+}
+
+impl FdGuard {
+	pub fn new() -> Self {
+		Self {
+			initial: unsafe { getfdcount() },
+		}
+	}
+
+	/// File descriptors opened (positive) or closed (negative) since the guard was created
+	pub fn diff(&self) -> i64 {
+		unsafe { getfdcount() - self.initial }
+	}
+}
+
+impl Clone for TestCounts {
+	fn clone(&self) -> Result<Self, Error> {
+		match self.inner.clone() {
+			Ok(inner) => Ok(Self { inner }),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+impl TestCounts {
+	pub fn new() -> Result<Self, Error> {
+		let condvar = match Condvar::new() {
+			Ok(condvar) => condvar,
+			Err(e) => return Err(e),
+		};
+		match Arc::new((Mutex::new([0u64; 4]), condvar)) {
+			Ok(inner) => Ok(Self { inner }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Increments the count at index and wakes any waiters
+	pub fn add(&self, index: usize) {
+		let mut counts = self.inner.0.lock();
+		counts[index] += 1;
+		self.inner.1.notify_all();
+	}
+
+	pub fn get(&self, index: usize) -> u64 {
+		self.inner.0.lock()[index]
+	}
+
+	/// Blocks until the count at index reaches target. False if that has not happened
+	/// within five seconds.
+	pub fn wait(&self, index: usize, target: u64) -> bool {
+		let deadline = unsafe { getmicros() } + WAIT_MICROS;
+		let mut counts = self.inner.0.lock();
+		while counts[index] < target {
+			let now = unsafe { getmicros() };
+			if now >= deadline {
+				return false;
+			}
+			counts = self.inner.1.wait_timeout(counts, (deadline - now) as u64).0;
+		}
+		true
+	}
+}
+
+#[cfg(test)]
+fn panicking() -> bool {
+	extern crate std as test_std;
+	test_std::thread::panicking()
+}
+
+// without the test harness a panic aborts, so a guard never runs while one unwinds
+#[cfg(not(test))]
+fn panicking() -> bool {
+	false
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+	use std::mem::MemCharge;
+
+	#[test]
+	fn test_alloc_guard() {
+		let _alloc = AllocGuard::new();
+		let inner = AllocGuard::new();
+		let b = Box::new(1u64).unwrap();
+		assert_eq!(inner.diff(), 1);
+		drop(b);
+		assert_eq!(inner.diff(), 0);
+	}
+
+	#[test]
+	#[should_panic(expected = "allocation count changed by 1")]
+	fn test_alloc_guard_leak() {
+		let alloc = AllocGuard::new();
+		let _b = Box::new(1u64).unwrap();
+		let _charge = MemCharge::new(MemTag::Buffer, 4096);
+		assert_eq!(alloc.tagged_diff()[0], 4096);
+		// _b is still live when the guard is dropped and is freed during unwinding
+		drop(alloc);
+	}
+
+	#[test]
+	fn test_counts() {
+		let _alloc = AllocGuard::new();
+		let counts = TestCounts::new().unwrap();
+		let counts_clone = counts.clone().unwrap();
+		let mut jh = spawnj(move || {
+			for _i in 0..3 {
+				counts_clone.add(1);
+			}
+		})
+		.unwrap();
+		assert!(counts.wait(1, 3));
+		jh.join().unwrap();
+		assert_eq!(counts.get(1), 3);
+		assert_eq!(counts.get(0), 0);
+		assert!(counts.wait(0, 0));
+	}
+
+	#[test]
+	#[should_panic(expected = "the test failed")]
+	fn test_alloc_guard_failed_test() {
+		let b = Box::new(1u64).unwrap();
+		let _alloc = AllocGuard::new();
+		let _b = b;
+		// _b is freed while unwinding, before the guard, which must not panic again
+		panic!("the test failed");
+	}
+}
@@ -1,7 +1,8 @@
 use core::ops::FnOnce;
 use core::ptr;
 use ffi::{
-	release, thread_create, thread_create_joinable, thread_detach, thread_handle_size, thread_join,
+	release, thread_cpu_count, thread_create, thread_create_joinable, thread_detach,
+	thread_get_name, thread_handle_size, thread_join, thread_set_affinity, thread_set_name,
 };
 use prelude::*;
 
@@ -57,7 +58,7 @@ where
 
 pub fn spawn<F>(f: F) -> Result<(), Error>
 where
-	F: FnOnce(),
+	F: FnOnce() + Send + 'static,
 {
 	match Box::new(f) {
 		Ok(mut b) => {
@@ -73,7 +74,7 @@ where
 
 pub fn spawnj<F>(f: F) -> Result<JoinHandle, Error>
 where
-	F: FnOnce(),
+	F: FnOnce() + Send + 'static,
 {
 	if unsafe { thread_handle_size() } > 8 {
 		exit!("thread_handle_size() > 8 ({})", unsafe {
@@ -103,95 +104,163 @@ where
 	}
 }
 
+/// Names the calling thread as debuggers and top show it. Names longer than 15 bytes are
+/// truncated.
+pub fn set_thread_name(name: &str) -> Result<(), Error> {
+	if unsafe { thread_set_name(name.as_ptr(), name.len()) } != 0 {
+		Err(err!(ThreadName))
+	} else {
+		Ok(())
+	}
+}
+
+/// Pins the calling thread to cpu, counted from 0. ThreadAffinity if cpu is not online or
+/// the platform does not let threads be pinned, which is anywhere but Linux.
+pub fn set_thread_affinity(cpu: usize) -> Result<(), Error> {
+	if unsafe { thread_set_affinity(cpu as u64) } != 0 {
+		Err(err!(ThreadAffinity))
+	} else {
+		Ok(())
+	}
+}
+
+/// Number of CPUs online, at least 1
+pub fn cpu_count() -> usize {
+	unsafe { thread_cpu_count() as usize }
+}
+
+/// Name of the calling thread, empty if it was never named
+pub fn thread_name() -> Result<String, Error> {
+	let mut buf = [0u8; 16];
+	if unsafe { thread_get_name(buf.as_mut_ptr(), buf.len()) } != 0 {
+		return Err(err!(ThreadName));
+	}
+	let mut len = 0;
+	while len < buf.len() && buf[len] != 0 {
+		len += 1;
+	}
+	String::from_utf8(&buf[0..len])
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
-	use ffi::{getalloccount, sleep_millis};
+	use ffi::sleep_millis;
 
 	#[test]
 	fn test_threads() {
-		let initial = unsafe { getalloccount() };
-		{
-			let lock = lock!();
-			let mut x = 1u32;
-			let rc = Rc::new(1).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
-			let mut jh = spawnj(|| {
-				let _v = lock.write();
-				x += 1;
-				assert_eq!(x, 2);
-				assert_eq!(*rc_clone, 1);
-				*rc_clone += 1;
-				assert_eq!(*rc_clone, 2);
-			})
-			.unwrap();
-
-			loop {
-				let _v = lock.write();
-				if *rc != 1 {
-					assert_eq!(*rc, 2);
-					assert_eq!(x, 2);
-					break;
-				}
-			}
+		let _alloc = AllocGuard::new();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let x = Arc::new(AtomicU64::new(1)).unwrap();
+		let x_clone = x.clone().unwrap();
+		let rc = Arc::new(AtomicU64::new(1)).unwrap();
+		let rc_clone = rc.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let _v = lock_clone.write();
+			x_clone.fetch_add(1);
+			assert_eq!(x_clone.load(), 2);
+			assert_eq!(rc_clone.load(), 1);
+			rc_clone.fetch_add(1);
+			assert_eq!(rc_clone.load(), 2);
+		})
+		.unwrap();
 
-			assert!(jh.join().is_ok());
+		loop {
+			let _v = lock.write();
+			if rc.load() != 1 {
+				assert_eq!(rc.load(), 2);
+				assert_eq!(x.load(), 2);
+				break;
+			}
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+
+		assert!(jh.join().is_ok());
 	}
 	#[test]
 	fn test_threads2() {
-		let initial = unsafe { getalloccount() };
-		{
-			let lock = lock!();
-			let mut x = 1u32;
-			let mut jh = spawnj(|| {
-				let _v = lock.write();
-				unsafe {
-					sleep_millis(50);
-				}
-				x += 1;
-				assert_eq!(x, 2);
-			})
-			.unwrap();
-
-			loop {
-				let _v = lock.write();
-				if x != 1 {
-					assert_eq!(x, 2);
-					break;
-				}
+		let _alloc = AllocGuard::new();
+		let lock = lock_box!().unwrap();
+		let lock_clone = lock.clone().unwrap();
+		let x = Arc::new(AtomicU64::new(1)).unwrap();
+		let x_clone = x.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let _v = lock_clone.write();
+			unsafe {
+				sleep_millis(50);
 			}
+			x_clone.fetch_add(1);
+			assert_eq!(x_clone.load(), 2);
+		})
+		.unwrap();
 
-			assert!(jh.join().is_ok());
+		loop {
+			let _v = lock.write();
+			if x.load() != 1 {
+				assert_eq!(x.load(), 2);
+				break;
+			}
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+
+		assert!(jh.join().is_ok());
 	}
 
 	#[test]
 	fn test_thread_join() {
-		let initial = unsafe { getalloccount() };
-		{
-			let lock = lock!();
-			let mut x = 1;
-			let rc = Rc::new(1).unwrap();
-			let mut rc_clone = rc.clone().unwrap();
-			let mut jh = spawnj(|| {
-				let _v = lock.read(); // memory fence only
-				x += 1;
-				assert_eq!(x, 2);
-				assert_eq!(*rc_clone, 1);
-				unsafe {
-					sleep_millis(100);
-				}
-				*rc_clone += 1;
-				assert_eq!(*rc_clone, 2);
-			})
-			.unwrap();
-
-			assert!(jh.join().is_ok());
-			assert_eq!(*rc, 2);
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		let _alloc = AllocGuard::new();
+		let lock = lock_box!().unwrap();
+		let x = Arc::new(AtomicU64::new(1)).unwrap();
+		let x_clone = x.clone().unwrap();
+		let rc = Arc::new(AtomicU64::new(1)).unwrap();
+		let rc_clone = rc.clone().unwrap();
+		let mut jh = spawnj(move || {
+			let _v = lock.read(); // memory fence only
+			x_clone.fetch_add(1);
+			assert_eq!(x_clone.load(), 2);
+			assert_eq!(rc_clone.load(), 1);
+			unsafe {
+				sleep_millis(100);
+			}
+			rc_clone.fetch_add(1);
+			assert_eq!(rc_clone.load(), 2);
+		})
+		.unwrap();
+
+		assert!(jh.join().is_ok());
+		assert_eq!(x.load(), 2);
+		assert_eq!(rc.load(), 2);
+	}
+
+	#[test]
+	fn test_thread_name() {
+		let _alloc = AllocGuard::new();
+		let (send, recv) = channel().unwrap();
+		let mut jh = spawnj(move || {
+			set_thread_name("short").unwrap();
+			send.send(thread_name().unwrap()).unwrap();
+			// truncated to what the kernel keeps
+			set_thread_name("a-rather-long-thread-name").unwrap();
+			send.send(thread_name().unwrap()).unwrap();
+		})
+		.unwrap();
+		assert_eq!(recv.recv().to_str(), "short");
+		assert_eq!(recv.recv().to_str(), "a-rather-long-t");
+		assert!(jh.join().is_ok());
+	}
+
+	#[test]
+	fn test_thread_affinity() {
+		let _alloc = AllocGuard::new();
+		assert!(cpu_count() >= 1);
+		let (send, recv) = channel().unwrap();
+		let mut jh = spawnj(move || {
+			send.send(set_thread_affinity(cpu_count() - 1).is_ok())
+				.unwrap();
+			send.send(set_thread_affinity(1 << 20).is_ok()).unwrap();
+		})
+		.unwrap();
+		assert_eq!(recv.recv(), cfg!(target_os = "linux"));
+		assert!(!recv.recv());
+		assert!(jh.join().is_ok());
 	}
 }
@@ -1,7 +1,7 @@
 use core::cmp::PartialEq;
 use core::iter::{IntoIterator, Iterator};
 use core::marker::PhantomData;
-use core::mem::{needs_drop, replace, size_of, zeroed};
+use core::mem::{needs_drop, size_of};
 use core::ops::{Drop, Index, IndexMut, Range};
 use core::option::Option as CoreOption;
 use core::ptr;
@@ -18,6 +18,9 @@ pub struct Vec<T> {
 	_marker: PhantomData<T>,
 }
 
+unsafe impl<T: Send> Send for Vec<T> {}
+unsafe impl<T: Sync> Sync for Vec<T> {}
+
 impl<T> Clone for Vec<T> {
 	fn clone(&self) -> Result<Self, Error> {
 		let value_ptr = unsafe { alloc(size_of::<T>() * self.capacity) };
@@ -59,20 +62,68 @@ impl<T> Iterator for VecIterator<T> {
 	type Item = T;
 
 	fn next(&mut self) -> CoreOption<Self::Item> {
-		let size = size_of::<T>();
 		if self.index < self.vec.elements {
-			let ptr = self.vec.value.raw() as *const u8;
-			let ptr = unsafe { ptr.add(self.index * size) as *mut T };
-			let element = unsafe { replace(&mut *ptr, zeroed()) };
+			let ptr = unsafe { (self.vec.value.raw() as *const T).add(self.index) };
+			self.index += 1;
+			CoreOption::Some(unsafe { ptr::read(ptr) })
+		} else {
+			CoreOption::None
+		}
+	}
+}
+
+impl<T> Drop for VecIterator<T> {
+	fn drop(&mut self) {
+		// elements before index were moved out, drop the rest and let the vec free the buffer
+		let remaining = self.vec.elements;
+		self.vec.elements = 0;
+		for i in self.index..remaining {
+			unsafe {
+				drop_in_place((self.vec.value.raw() as *mut T).add(i));
+			}
+		}
+	}
+}
+
+/// Iterator returned by Vec::drain. Elements of the range that are not consumed are
+/// dropped with the iterator, which then closes the gap in the vec.
+pub struct Drain<'a, T> {
+	vec: &'a mut Vec<T>,
+	index: usize,
+	end: usize,
+	tail: usize,
+}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+	type Item = T;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		if self.index < self.end {
+			let ptr = unsafe { (self.vec.value.raw() as *const T).add(self.index) };
 			self.index += 1;
-			CoreOption::Some(element)
+			CoreOption::Some(unsafe { ptr::read(ptr) })
 		} else {
-			self.vec.elements = 0;
 			CoreOption::None
 		}
 	}
 }
 
+impl<'a, T> Drop for Drain<'a, T> {
+	fn drop(&mut self) {
+		let base = self.vec.value.raw() as *mut T;
+		let start = self.vec.elements;
+		unsafe {
+			for i in self.index..self.end {
+				drop_in_place(base.add(i));
+			}
+			if self.tail > 0 {
+				copy(base.add(self.end), base.add(start), self.tail);
+			}
+		}
+		self.vec.elements = start + self.tail;
+	}
+}
+
 impl<T> IntoIterator for Vec<T> {
 	type Item = T;
 	type IntoIter = VecIterator<T>;
@@ -231,8 +282,13 @@ impl<T> Vec<T> {
 			return Err(err!(OutOfBounds));
 		}
 		self.elements -= n;
+		let size = size_of::<T>();
 		unsafe {
-			copy(self.value.raw().add(n), self.value.raw(), self.elements);
+			copy(
+				self.value.raw().add(n * size),
+				self.value.raw(),
+				self.elements * size,
+			);
 		}
 		Ok(())
 	}
@@ -256,6 +312,119 @@ impl<T> Vec<T> {
 		Ok(())
 	}
 
+	/// Inserts v at index, shifting the elements after it to the right
+	pub fn insert(&mut self, index: usize, v: T) -> Result<(), Error> {
+		if index > self.elements {
+			return Err(err!(OutOfBounds));
+		}
+		if self.elements + 1 > self.capacity {
+			if !self.resize_impl(self.elements + 1) {
+				return Err(err!(Alloc));
+			}
+		}
+
+		unsafe {
+			let ptr = (self.value.raw() as *mut T).add(index);
+			copy(ptr, ptr.add(1), self.elements - index);
+			ptr::write(ptr, v);
+		}
+		self.elements += 1;
+
+		Ok(())
+	}
+
+	/// Removes and returns the element at index, shifting the elements after it to the left
+	pub fn remove(&mut self, index: usize) -> T {
+		if index >= self.elements {
+			panic!("array index out of bounds!");
+		}
+
+		self.elements -= 1;
+		unsafe {
+			let ptr = (self.value.raw() as *mut T).add(index);
+			let ret = ptr::read(ptr);
+			copy(ptr.add(1), ptr, self.elements - index);
+			ret
+		}
+	}
+
+	/// Removes and returns the element at index, replacing it with the last element
+	pub fn swap_remove(&mut self, index: usize) -> T {
+		if index >= self.elements {
+			panic!("array index out of bounds!");
+		}
+
+		self.elements -= 1;
+		unsafe {
+			let base = self.value.raw() as *mut T;
+			let ret = ptr::read(base.add(index));
+			if index != self.elements {
+				copy_nonoverlapping(base.add(self.elements), base.add(index), 1);
+			}
+			ret
+		}
+	}
+
+	pub fn pop(&mut self) -> Option<T> {
+		if self.elements == 0 {
+			None
+		} else {
+			self.elements -= 1;
+			unsafe { Some(ptr::read((self.value.raw() as *const T).add(self.elements))) }
+		}
+	}
+
+	/// Keeps only the elements for which f returns true, preserving their order. Removed
+	/// elements are dropped in place.
+	pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+		let len = self.elements;
+		// if f panics the remaining elements are leaked rather than dropped twice
+		self.elements = 0;
+		let base = self.value.raw() as *mut T;
+		let mut kept = 0;
+		for i in 0..len {
+			unsafe {
+				let ptr = base.add(i);
+				if f(&*ptr) {
+					if kept != i {
+						copy_nonoverlapping(ptr, base.add(kept), 1);
+					}
+					kept += 1;
+				} else {
+					drop_in_place(ptr);
+				}
+			}
+		}
+		self.elements = kept;
+	}
+
+	/// Removes the elements in range and returns them as an iterator. The elements after
+	/// the range are moved down when the iterator is dropped.
+	pub fn drain(&mut self, range: Range<usize>) -> Drain<'_, T> {
+		if range.start > range.end || range.end > self.elements {
+			panic!("Index out of bounds");
+		}
+		let tail = self.elements - range.end;
+		// a leaked Drain leaks the range and the tail but never exposes moved elements
+		self.elements = range.start;
+		Drain {
+			vec: self,
+			index: range.start,
+			end: range.end,
+			tail,
+		}
+	}
+
+	/// Drops the elements at and after n. Capacity is unchanged.
+	pub fn truncate(&mut self, n: usize) {
+		while self.elements > n {
+			self.elements -= 1;
+			unsafe {
+				drop_in_place((self.value.raw() as *mut T).add(self.elements));
+			}
+		}
+	}
+
 	fn next_power_of_two(&self, mut n: usize) -> usize {
 		if n < self.min {
 			return self.min;
@@ -310,9 +479,13 @@ impl<T> Vec<T> {
 				self.value = nptr;
 			}
 			true
-		} else {
+		} else if ncapacity == 0 {
 			self.value = Ptr::null();
-			ncapacity == 0
+			self.capacity = 0;
+			true
+		} else {
+			// the old allocation is still valid when resize fails
+			false
 		}
 	}
 
@@ -320,6 +493,28 @@ impl<T> Vec<T> {
 		self.elements
 	}
 
+	/// Number of elements that fit without reallocating
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Ensures there is room for at least additional more elements. Capacity grows to the
+	/// next power of two so repeated small reservations are amortized.
+	pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+		let needed = self.elements + additional;
+		if needed > self.capacity && !self.resize_impl(needed) {
+			Err(err!(Alloc))
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Releases unused capacity, keeping at least the configured minimum
+	pub fn shrink_to_fit(&mut self) {
+		// on failure the existing allocation is kept
+		let _ = self.resize_impl(self.elements);
+	}
+
 	pub fn clear(&mut self) {
 		self.resize_impl(self.min);
 		self.elements = 0;
@@ -335,20 +530,39 @@ impl<T> Vec<T> {
 	}
 
 	pub fn as_slice(&self) -> &[T] {
+		// nothing is allocated until the first element is added
+		if self.value.raw().is_null() {
+			return &[];
+		}
 		unsafe { from_raw_parts(self.value.raw() as *const T, self.elements) }
 	}
 
 	pub fn as_mut_slice(&mut self) -> &mut [T] {
+		if self.value.raw().is_null() {
+			return &mut [];
+		}
 		unsafe { from_raw_parts_mut(self.value.raw() as *mut T, self.elements) }
 	}
 
+	/// Sets the length to n. New elements are zeroed. Capacity only grows, except that
+	/// resizing to 0 releases the buffer down to the configured minimum; use shrink_to_fit
+	/// to release memory otherwise.
 	pub fn resize(&mut self, n: usize) -> Result<(), Error> {
-		if self.resize_impl(n) {
-			self.elements = n;
-			Ok(())
-		} else {
-			Err(err!(Alloc))
+		if (n > self.capacity || n == 0) && !self.resize_impl(n) {
+			return Err(err!(Alloc));
 		}
+		if n > self.elements {
+			let size = size_of::<T>();
+			unsafe {
+				write_bytes(
+					self.value.raw().add(self.elements * size),
+					0,
+					(n - self.elements) * size,
+				);
+			}
+		}
+		self.elements = n;
+		Ok(())
 	}
 
 	pub fn append_ptr(&mut self, ptr: *const u8, elems: usize) -> Result<(), Error> {
@@ -356,7 +570,7 @@ impl<T> Vec<T> {
 			return Err(err!(IllegalArgument));
 		}
 		let size = size_of::<T>();
-		let needed = size * (self.elements + elems);
+		let needed = self.elements + elems;
 		if needed > self.capacity {
 			if !self.resize_impl(needed) {
 				return Err(err!(Alloc));
@@ -377,7 +591,7 @@ impl<T> Vec<T> {
 	pub fn append(&mut self, v: &Vec<T>) -> Result<(), Error> {
 		let size = size_of::<T>();
 		let len = v.len();
-		let needed = size * (self.elements + len);
+		let needed = self.elements + len;
 		if needed > self.capacity {
 			if !self.resize_impl(needed) {
 				return Err(err!(Alloc));
@@ -386,7 +600,7 @@ impl<T> Vec<T> {
 
 		let dest_ptr = self.value.raw() as *mut u8;
 		unsafe {
-			let dest_ptr = dest_ptr.add(size * len) as *mut u8;
+			let dest_ptr = dest_ptr.add(size * self.elements) as *mut u8;
 			copy_nonoverlapping(v.value.raw() as *mut u8, dest_ptr, size * len);
 		}
 
@@ -395,15 +609,72 @@ impl<T> Vec<T> {
 	}
 }
 
+impl<T: Ord> Vec<T> {
+	/// Sorts in place using heapsort. The sort is not stable and does not allocate.
+	pub fn sort(&mut self) {
+		let n = self.elements;
+		if n < 2 {
+			return;
+		}
+		let v = self.as_mut_slice();
+		let mut i = n / 2;
+		while i > 0 {
+			i -= 1;
+			sift_down(v, i, n);
+		}
+		let mut end = n;
+		while end > 1 {
+			end -= 1;
+			v.swap(0, end);
+			sift_down(v, 0, end);
+		}
+	}
+
+	/// Searches a sorted vec for x. Returns Ok with the index of a matching element, or
+	/// Err with the index where x could be inserted to keep the vec sorted.
+	pub fn binary_search(&self, x: &T) -> Result<usize, usize> {
+		let mut low = 0;
+		let mut high = self.elements;
+		while low < high {
+			let mid = low + (high - low) / 2;
+			match self[mid].compare(x) {
+				0 => return Ok(mid),
+				c if c < 0 => low = mid + 1,
+				_ => high = mid,
+			}
+		}
+		Err(low)
+	}
+}
+
+// restore the max-heap property of v[root..end] assuming both subtrees are heaps
+fn sift_down<T: Ord>(v: &mut [T], mut root: usize, end: usize) {
+	loop {
+		let mut child = 2 * root + 1;
+		if child >= end {
+			break;
+		}
+		if child + 1 < end && v[child].compare(&v[child + 1]) < 0 {
+			child += 1;
+		}
+		if v[root].compare(&v[child]) >= 0 {
+			break;
+		}
+		v.swap(root, child);
+		root = child;
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
 	use core::fmt::Debug;
 	use core::fmt::Error as CoreError;
 	use core::fmt::Formatter;
+	use core::mem::drop;
 	use core::ops::Drop;
 	use core::result::Result as CoreResult;
-	use ffi::getalloccount;
+	use util::proptest::*;
 
 	#[test]
 	fn test_vec1() {
@@ -421,30 +692,27 @@ mod test {
 
 	#[test]
 	fn test_vec2() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut v1 = Vec::new();
-			for i in 0..100000 {
-				assert!(v1.push(i).is_ok());
-				assert_eq!(v1[i], i);
-			}
+		let _alloc = AllocGuard::new();
+		let mut v1 = Vec::new();
+		for i in 0..100000 {
+			assert!(v1.push(i).is_ok());
+			assert_eq!(v1[i], i);
+		}
 
-			for i in 0..100000 {
-				v1[i] = i + 100;
-			}
-			for i in 0..100000 {
-				assert_eq!(v1[i], i + 100);
-			}
+		for i in 0..100000 {
+			v1[i] = i + 100;
+		}
+		for i in 0..100000 {
+			assert_eq!(v1[i], i + 100);
+		}
 
-			let v2 = vec![1, 2, 3].unwrap();
-			let mut count = 0;
-			for x in v2 {
-				count += 1;
-				assert_eq!(x, count);
-			}
-			assert_eq!(count, 3);
+		let v2 = vec![1, 2, 3].unwrap();
+		let mut count = 0;
+		for x in v2 {
+			count += 1;
+			assert_eq!(x, count);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(count, 3);
 	}
 
 	impl<T> Debug for Vec<T> {
@@ -455,18 +723,35 @@ mod test {
 
 	#[test]
 	fn test_vec_append() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut v1 = vec![1, 2, 3].unwrap();
-			let v2 = vec![4, 5, 6].unwrap();
-			assert!(v1.append(&v2).is_ok());
+		let _alloc = AllocGuard::new();
+		let mut v1 = vec![1, 2, 3].unwrap();
+		let v2 = vec![4, 5, 6].unwrap();
+		assert!(v1.append(&v2).is_ok());
+
+		assert_eq!(v1, vec![1, 2, 3, 4, 5, 6].unwrap());
+		assert!(v1 != vec![1, 2, 3, 4, 6, 6].unwrap());
+		assert!(v1 == vec![1, 2, 3, 4, 5, 6].unwrap());
+		assert!(v1 != v2);
+	}
 
-			assert_eq!(v1, vec![1, 2, 3, 4, 5, 6].unwrap());
-			assert!(v1 != vec![1, 2, 3, 4, 6, 6].unwrap());
-			assert!(v1 == vec![1, 2, 3, 4, 5, 6].unwrap());
-			assert!(v1 != v2);
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+	#[test]
+	fn test_vec_shift_append_sizes() {
+		let _alloc = AllocGuard::new();
+		// elements wider than a byte, and vectors of different lengths
+		let mut v1 = vec![1u64, 2, 3, 4, 5].unwrap();
+		v1.shift(2).unwrap();
+		assert_eq!(v1, vec![3u64, 4, 5].unwrap());
+		assert!(v1.shift(4).is_err());
+		let v2 = vec![6u64].unwrap();
+		v1.append(&v2).unwrap();
+		assert_eq!(v1, vec![3u64, 4, 5, 6].unwrap());
+		let mut v3 = vec![7u64].unwrap();
+		v3.append(&v1).unwrap();
+		assert_eq!(v3, vec![7u64, 3, 4, 5, 6].unwrap());
+		let bytes = [8u8; 16];
+		v3.append_ptr(bytes.as_ptr(), 2).unwrap();
+		assert_eq!(v3.len(), 7);
+		assert_eq!(v3[6], u64::from_le_bytes([8u8; 8]));
 	}
 
 	struct DropTest {
@@ -487,7 +772,7 @@ mod test {
 	fn test_vec_drop() {
 		let x = DropTest { x: 8 };
 
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 		{
 			let mut v: Vec<DropTest> = vec![].unwrap();
 			assert!(v.resize(1).is_ok());
@@ -496,25 +781,131 @@ mod test {
 		}
 
 		assert_eq!(unsafe { VTEST }, 2);
-		assert_eq!(initial, unsafe { getalloccount() });
 	}
 
 	#[test]
 	fn test_vec_iter_drop() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
+		unsafe {
+			VTEST = 0;
+		}
 		{
-			unsafe {
-				VTEST = 0;
+			let v = vec![DropTest { x: 1 }, DropTest { x: 2 }, DropTest { x: 3 }].unwrap();
+			for y in v {
+				let _z = y;
 			}
-			{
-				let v = vec![DropTest { x: 1 }, DropTest { x: 2 }, DropTest { x: 3 }].unwrap();
-				for y in v {
-					let _z = y;
-				}
+		}
+		assert_eq!(unsafe { VTEST }, 3);
+	}
+
+	#[test]
+	fn test_vec_remove_drop() {
+		let _alloc = AllocGuard::new();
+		unsafe {
+			VTEST = 0;
+		}
+		{
+			let mut v = Vec::new();
+			for x in 0..6 {
+				assert!(v.push(DropTest { x }).is_ok());
+			}
+			assert!(v.insert(7, DropTest { x: 7 }).is_err());
+			assert_eq!(unsafe { VTEST }, 1);
+			assert!(v.insert(0, DropTest { x: 6 }).is_ok());
+
+			// removed elements are owned by the caller, not dropped by the vec
+			let r = v.remove(1);
+			assert_eq!(r.x, 0);
+			let r2 = v.swap_remove(0);
+			assert_eq!(r2.x, 6);
+			assert_eq!(v[0].x, 5);
+			assert_eq!(unsafe { VTEST }, 1);
+			match v.pop() {
+				Some(p) => assert_eq!(p.x, 4),
+				None => panic!("expected an element"),
+			}
+			assert_eq!(unsafe { VTEST }, 2);
+
+			// [5, 1, 2, 3] -> [5]
+			v.truncate(1);
+			assert_eq!(v.len(), 1);
+			assert_eq!(v[0].x, 5);
+			assert_eq!(unsafe { VTEST }, 5);
+			v.truncate(4);
+			assert_eq!(v.len(), 1);
+		}
+		assert_eq!(unsafe { VTEST }, 8);
+	}
+
+	#[test]
+	fn test_vec_retain_drain() {
+		let _alloc = AllocGuard::new();
+		unsafe {
+			VTEST = 0;
+		}
+		{
+			let mut v = Vec::new();
+			for x in 0..10 {
+				assert!(v.push(DropTest { x }).is_ok());
+			}
+			v.retain(|d| d.x % 2 == 0);
+			assert_eq!(unsafe { VTEST }, 5);
+			assert_eq!(v.len(), 5);
+			for i in 0..5 {
+				assert_eq!(v[i].x, i as u32 * 2);
+			}
+
+			// [0, 2, 4, 6, 8] -> [0, 8], one drained element consumed and two dropped
+			match v.drain(1..4).next() {
+				CoreOption::Some(d) => assert_eq!(d.x, 2),
+				CoreOption::None => panic!("expected an element"),
+			}
+			assert_eq!(unsafe { VTEST }, 8);
+			assert_eq!(v.len(), 2);
+			assert_eq!(v[0].x, 0);
+			assert_eq!(v[1].x, 8);
+
+			let mut drained = v.drain(0..2);
+			match drained.next() {
+				CoreOption::Some(d) => assert_eq!(d.x, 0),
+				CoreOption::None => panic!("expected an element"),
+			}
+			assert_eq!(unsafe { VTEST }, 9);
+			drop(drained);
+			assert_eq!(unsafe { VTEST }, 10);
+			assert_eq!(v.len(), 0);
+			assert!(v.drain(0..0).next().is_none());
+		}
+		assert_eq!(unsafe { VTEST }, 10);
+
+		// a partially consumed into_iter drops only the remaining elements
+		unsafe {
+			VTEST = 0;
+		}
+		{
+			let v = vec![DropTest { x: 1 }, DropTest { x: 2 }, DropTest { x: 3 }].unwrap();
+			let mut iter = v.into_iter();
+			match iter.next() {
+				CoreOption::Some(y) => assert_eq!(y.x, 1),
+				CoreOption::None => panic!("expected an element"),
 			}
-			assert_eq!(unsafe { VTEST }, 3);
+			assert_eq!(unsafe { VTEST }, 1);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		assert_eq!(unsafe { VTEST }, 3);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_vec_drain_out_of_bounds() {
+		let mut v = vec![1, 2, 3].unwrap();
+		v.drain(2..4);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_vec_remove_out_of_bounds() {
+		let mut v = vec![1, 2, 3].unwrap();
+		v.remove(3);
 	}
 
 	#[test]
@@ -533,25 +924,261 @@ mod test {
 
 	#[test]
 	fn test_set_min0() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut v = Vec::new();
-			v.set_min(0);
-			assert!(v.push(1).is_ok());
-			assert!(v.resize(128).is_ok());
-			assert!(v.resize(0).is_ok());
-			// it's already freed at this point
-			assert_eq!(initial, unsafe { getalloccount() });
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		let alloc = AllocGuard::new();
+		let mut v = Vec::new();
+		v.set_min(0);
+		assert!(v.push(1).is_ok());
+		assert!(v.resize(128).is_ok());
+		assert!(v.resize(0).is_ok());
+		// it's already freed at this point
+		assert_eq!(alloc.diff(), 0);
+	}
+
+	#[test]
+	fn test_vec_reserve() {
+		let alloc = AllocGuard::new();
+		let mut v: Vec<u8> = Vec::new();
+		v.set_min(0);
+		assert_eq!(v.capacity(), 0);
+		assert!(v.reserve(300).is_ok());
+		assert_eq!(v.capacity(), 512);
+		assert_eq!(v.len(), 0);
+		assert!(v.reserve(512).is_ok());
+		assert_eq!(v.capacity(), 512);
+
+		// shrinking the length keeps the capacity and growing again zeroes the new elements
+		assert!(v.resize(400).is_ok());
+		v[399] = 7;
+		assert!(v.resize(10).is_ok());
+		assert_eq!(v.capacity(), 512);
+		assert!(v.resize(400).is_ok());
+		assert_eq!(v[399], 0);
+		assert!(v.resize(10).is_ok());
+
+		v.shrink_to_fit();
+		assert_eq!(v.capacity(), 16);
+		assert_eq!(v.len(), 10);
+		assert!(v.resize(0).is_ok());
+		assert_eq!(v.capacity(), 0);
+		assert_eq!(alloc.diff(), 0);
+		assert!(v.push(1).is_ok());
+		assert_eq!(v[0], 1);
+
+		let mut v: Vec<u64> = Vec::new();
+		v.shrink_to_fit();
+		assert_eq!(v.capacity(), 16);
+		assert!(v.reserve(17).is_ok());
+		assert_eq!(v.capacity(), 32);
+		v.shrink_to_fit();
+		assert_eq!(v.capacity(), 16);
 	}
 
 	#[test]
 	fn test_vec_alloc() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut _v: Vec<i32> = vec![].unwrap();
+		let _alloc = AllocGuard::new();
+		let mut _v: Vec<i32> = vec![].unwrap();
+	}
+
+	#[test]
+	fn test_vec_sort() {
+		let _alloc = AllocGuard::new();
+		let mut v: Vec<i32> = Vec::new();
+		v.sort();
+		assert!(v.binary_search(&1) == Err(0));
+		let mut v = vec![5, -1, 3, 3, 0, 9, -7].unwrap();
+		v.sort();
+		assert_eq!(v, vec![-7, -1, 0, 3, 3, 5, 9].unwrap());
+		assert!(v.binary_search(&-7) == Ok(0));
+		assert!(v.binary_search(&9) == Ok(6));
+		assert!(v.binary_search(&4) == Err(5));
+		assert!(v.binary_search(&10) == Err(7));
+		assert!(v.binary_search(&-8) == Err(0));
+		match v.binary_search(&3) {
+			Ok(i) => assert_eq!(v[i], 3),
+			Err(_) => panic!("3 not found"),
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+
+		check(PropConfig::default(), |input: &Vec<u64>| {
+			let mut v = copy_vec(input).unwrap();
+			v.sort();
+			if v.len() != input.len() {
+				return false;
+			}
+			for i in 1..v.len() {
+				if v[i - 1] > v[i] {
+					return false;
+				}
+			}
+			// same multiset: every input element is found and accounted for once
+			let mut used = [false; 64];
+			for x in input {
+				let mut found = false;
+				for i in 0..v.len() {
+					if !used[i] && v[i] == *x {
+						used[i] = true;
+						found = true;
+						break;
+					}
+				}
+				if !found {
+					return false;
+				}
+			}
+			for x in input {
+				match v.binary_search(x) {
+					Ok(i) => {
+						if v[i] != *x {
+							return false;
+						}
+					}
+					Err(_) => return false,
+				}
+			}
+			match v.binary_search(&u64::MAX) {
+				Ok(i) => v[i] == u64::MAX,
+				Err(i) => i == v.len(),
+			}
+		});
+	}
+
+	#[test]
+	fn test_vec_prop_model() {
+		let _alloc = AllocGuard::new();
+		// each op is a push, shift, append, resize, insert, remove, pop, swap_remove,
+		// retain, drain or truncate, checked against an array model
+		check(PropConfig::default(), |ops: &Vec<u64>| {
+			let mut v = Vec::new();
+			let mut model = [0u64; 256];
+			let mut len = 0;
+			for op in ops {
+				let arg = *op >> 3;
+				match *op % 12 {
+					0 | 1 => {
+						if v.push(arg).is_err() {
+							return false;
+						}
+						model[len] = arg;
+						len += 1;
+					}
+					2 => {
+						// one past the end must fail and leave the vec unchanged
+						let n = (arg % (len as u64 + 2)) as usize;
+						if v.shift(n).is_ok() != (n <= len) {
+							return false;
+						}
+						if n <= len {
+							for i in n..len {
+								model[i - n] = model[i];
+							}
+							len -= n;
+						}
+					}
+					3 => {
+						let mut other = Vec::new();
+						for i in 0..arg % 4 {
+							if other.push(arg + i).is_err() {
+								return false;
+							}
+							model[len] = arg + i;
+							len += 1;
+						}
+						if v.append(&other).is_err() {
+							return false;
+						}
+					}
+					4 => {
+						len = (arg % (len as u64 + 1)) as usize;
+						if v.resize(len).is_err() {
+							return false;
+						}
+					}
+					5 => {
+						let idx = (arg % (len as u64 + 1)) as usize;
+						if v.insert(idx, arg).is_err() {
+							return false;
+						}
+						let mut i = len;
+						while i > idx {
+							model[i] = model[i - 1];
+							i -= 1;
+						}
+						model[idx] = arg;
+						len += 1;
+					}
+					6 => {
+						if len > 0 {
+							let idx = (arg % len as u64) as usize;
+							if v.remove(idx) != model[idx] {
+								return false;
+							}
+							for i in idx + 1..len {
+								model[i - 1] = model[i];
+							}
+							len -= 1;
+						}
+					}
+					7 => match v.pop() {
+						Some(x) => {
+							if len == 0 || x != model[len - 1] {
+								return false;
+							}
+							len -= 1;
+						}
+						None => {
+							if len != 0 {
+								return false;
+							}
+						}
+					},
+					8 => {
+						if len > 0 {
+							let idx = (arg % len as u64) as usize;
+							if v.swap_remove(idx) != model[idx] {
+								return false;
+							}
+							model[idx] = model[len - 1];
+							len -= 1;
+						}
+					}
+					9 => {
+						v.retain(|x| x % 3 != arg % 3);
+						let mut kept = 0;
+						for i in 0..len {
+							if model[i] % 3 != arg % 3 {
+								model[kept] = model[i];
+								kept += 1;
+							}
+						}
+						len = kept;
+					}
+					10 => {
+						let end = (arg % (len as u64 + 1)) as usize;
+						let start = ((arg >> 8) % (end as u64 + 1)) as usize;
+						let mut i = start;
+						for x in v.drain(start..end) {
+							if x != model[i] {
+								return false;
+							}
+							i += 1;
+						}
+						if i != end {
+							return false;
+						}
+						for j in end..len {
+							model[j - (end - start)] = model[j];
+						}
+						len -= end - start;
+					}
+					_ => {
+						len = (arg % (len as u64 + 1)) as usize;
+						v.truncate(len);
+					}
+				}
+				if v.len() != len || &v[0..len] != &model[0..len] {
+					return false;
+				}
+			}
+			true
+		});
 	}
 }
@@ -0,0 +1,311 @@
+//! # Zeroizing containers
+//! Owners of key material which wipe it with volatile writes when dropped, so that secrets
+//! do not linger in freed memory. SecureBox holds a fixed size value such as a seed,
+//! SecureVec a byte buffer which may grow, wiping the old allocation each time it moves.
+//! Either may be created locked, in which case the memory is pages of its own locked with
+//! mlock so that it is never written to swap, and on linux left out of core dumps. Locked
+//! memory is limited per process (RLIMIT_MEMLOCK), so it is best kept for long lived keys.
+
+use core::marker::{Copy, PhantomData};
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{copy_nonoverlapping, null_mut, write, write_volatile};
+use core::slice::{from_raw_parts, from_raw_parts_mut};
+use ffi::{alloc, release, secure_alloc, secure_release};
+use prelude::*;
+
+/// Wipes data in a way the compiler may not remove
+pub fn zeroize(data: &mut [u8]) {
+	for i in 0..data.len() {
+		unsafe {
+			write_volatile(&mut data[i], 0);
+		}
+	}
+}
+
+// memory which is wiped before it is released
+struct SecureMem {
+	ptr: *mut u8,
+	size: usize,
+	locked: bool,
+}
+
+/// A heap allocated value which is wiped when dropped. Only plain data (Copy) may be held
+/// since the bytes are wiped in place of running a destructor.
+pub struct SecureBox<T: Copy> {
+	mem: SecureMem,
+	_marker: PhantomData<T>,
+}
+
+/// A byte buffer which is wiped when dropped, cleared or moved by growth
+pub struct SecureVec {
+	mem: SecureMem,
+	len: usize,
+}
+
+impl Drop for SecureMem {
+	fn drop(&mut self) {
+		if self.ptr.is_null() {
+			return;
+		}
+		unsafe {
+			zeroize(from_raw_parts_mut(self.ptr, self.size));
+			if self.locked {
+				secure_release(self.ptr, self.size as u64);
+			} else {
+				release(self.ptr);
+			}
+		}
+	}
+}
+
+impl SecureMem {
+	fn new(size: usize, locked: bool) -> Result<Self, Error> {
+		if size == 0 {
+			return Ok(Self {
+				ptr: null_mut(),
+				size,
+				locked,
+			});
+		}
+		let ptr = unsafe {
+			if locked {
+				secure_alloc(size as u64)
+			} else {
+				alloc(size) as *mut u8
+			}
+		};
+		if ptr.is_null() {
+			return Err(oserr!(Alloc));
+		}
+		Ok(Self { ptr, size, locked })
+	}
+}
+
+impl<T: Copy> Deref for SecureBox<T> {
+	type Target = T;
+
+	fn deref(&self) -> &Self::Target {
+		unsafe { &*(self.mem.ptr as *const T) }
+	}
+}
+
+impl<T: Copy> DerefMut for SecureBox<T> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		unsafe { &mut *(self.mem.ptr as *mut T) }
+	}
+}
+
+impl<T: Copy> SecureBox<T> {
+	/// Moves value to the heap. The copy passed in is the caller's to wipe, so a value
+	/// which is secret from the start is better written through the box once created.
+	pub fn new(value: T) -> Result<Self, Error> {
+		Self::init(value, false)
+	}
+
+	/// As new, in locked memory. An error if the pages could not be locked.
+	pub fn new_locked(value: T) -> Result<Self, Error> {
+		Self::init(value, true)
+	}
+
+	fn init(value: T, locked: bool) -> Result<Self, Error> {
+		// a zero sized value still gets memory so that deref has somewhere to point
+		let size = if size_of::<T>() == 0 {
+			1
+		} else {
+			size_of::<T>()
+		};
+		let mem = match SecureMem::new(size, locked) {
+			Ok(mem) => mem,
+			Err(e) => return Err(e),
+		};
+		unsafe {
+			write(mem.ptr as *mut T, value);
+		}
+		Ok(Self {
+			mem,
+			_marker: PhantomData,
+		})
+	}
+
+	pub fn is_locked(&self) -> bool {
+		self.mem.locked
+	}
+}
+
+impl SecureVec {
+	pub fn new() -> Self {
+		Self {
+			mem: SecureMem {
+				ptr: null_mut(),
+				size: 0,
+				locked: false,
+			},
+			len: 0,
+		}
+	}
+
+	/// An empty buffer with room for capacity bytes, so that it does not move until it
+	/// holds more
+	pub fn with_capacity(capacity: usize) -> Result<Self, Error> {
+		Self::init(capacity, false)
+	}
+
+	/// As with_capacity, in locked memory. An error if the pages could not be locked.
+	/// Growing past capacity locks the new memory too.
+	pub fn with_capacity_locked(capacity: usize) -> Result<Self, Error> {
+		Self::init(capacity, true)
+	}
+
+	fn init(capacity: usize, locked: bool) -> Result<Self, Error> {
+		match SecureMem::new(capacity, locked) {
+			Ok(mem) => Ok(Self { mem, len: 0 }),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.mem.size
+	}
+
+	pub fn is_locked(&self) -> bool {
+		self.mem.locked
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		if self.len == 0 {
+			return &[];
+		}
+		unsafe { from_raw_parts(self.mem.ptr, self.len) }
+	}
+
+	pub fn as_mut_slice(&mut self) -> &mut [u8] {
+		if self.len == 0 {
+			return &mut [];
+		}
+		unsafe { from_raw_parts_mut(self.mem.ptr, self.len) }
+	}
+
+	/// Appends bytes, moving to a larger allocation and wiping the old one if they do not
+	/// fit
+	pub fn extend_from_slice(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		let needed = self.len + bytes.len();
+		if needed > self.mem.size {
+			let mut size = if self.mem.size == 0 {
+				32
+			} else {
+				self.mem.size * 2
+			};
+			while size < needed {
+				size *= 2;
+			}
+			let mem = match SecureMem::new(size, self.mem.locked) {
+				Ok(mem) => mem,
+				Err(e) => return Err(e),
+			};
+			if self.len > 0 {
+				unsafe {
+					copy_nonoverlapping(self.mem.ptr, mem.ptr, self.len);
+				}
+			}
+			// the old memory is wiped as it is dropped
+			self.mem = mem;
+		}
+		if bytes.len() > 0 {
+			unsafe {
+				copy_nonoverlapping(bytes.as_ptr(), self.mem.ptr.add(self.len), bytes.len());
+			}
+		}
+		self.len = needed;
+		Ok(())
+	}
+
+	/// Wipes the contents, keeping the memory for reuse
+	pub fn clear(&mut self) {
+		zeroize(self.as_mut_slice());
+		self.len = 0;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+
+	#[test]
+	fn test_secure_box() {
+		let _alloc = AllocGuard::new();
+		let mut seed = SecureBox::new([0u8; 32]).unwrap();
+		assert!(!seed.is_locked());
+		seed[0] = 7;
+		seed[31] = 9;
+		assert_eq!(seed[0], 7);
+		assert_eq!(*seed, {
+			let mut expected = [0u8; 32];
+			expected[0] = 7;
+			expected[31] = 9;
+			expected
+		});
+		drop(seed);
+
+		let locked = SecureBox::new_locked([5u8; 64]);
+		// locking may be refused by RLIMIT_MEMLOCK in a restricted environment
+		match locked {
+			Ok(locked) => {
+				assert!(locked.is_locked());
+				assert_eq!(locked[63], 5);
+			}
+			Err(e) => assert!(e.kind == Alloc),
+		}
+	}
+
+	#[test]
+	fn test_secure_vec() {
+		let _alloc = AllocGuard::new();
+		let mut v = SecureVec::new();
+		assert!(v.is_empty());
+		assert_eq!(v.as_slice(), &[]);
+		v.extend_from_slice(b"secret").unwrap();
+		assert_eq!(v.as_slice(), b"secret");
+		assert_eq!(v.capacity(), 32);
+
+		// growing moves the bytes and keeps them
+		let more = [3u8; 100];
+		v.extend_from_slice(&more).unwrap();
+		assert_eq!(v.len(), 106);
+		assert_eq!(v.capacity(), 128);
+		assert_eq!(&v.as_slice()[0..6], b"secret");
+		assert_eq!(v.as_slice()[105], 3);
+
+		// clear wipes the bytes in place
+		let ptr = v.mem.ptr;
+		v.clear();
+		assert!(v.is_empty());
+		assert_eq!(v.capacity(), 128);
+		let left = unsafe { from_raw_parts(ptr, 106) };
+		for b in left {
+			assert_eq!(*b, 0);
+		}
+		v.extend_from_slice(b"again").unwrap();
+		assert_eq!(v.mem.ptr, ptr);
+		assert_eq!(v.as_slice(), b"again");
+
+		let mut fixed = SecureVec::with_capacity(64).unwrap();
+		fixed.extend_from_slice(&[1u8; 64]).unwrap();
+		assert_eq!(fixed.capacity(), 64);
+		if let Ok(mut locked) = SecureVec::with_capacity_locked(16) {
+			assert!(locked.is_locked());
+			locked.extend_from_slice(&[2u8; 40]).unwrap();
+			assert!(locked.is_locked());
+			assert_eq!(locked.as_mut_slice()[39], 2);
+		}
+	}
+}
@@ -0,0 +1,210 @@
+//! # Arena
+//! A bump allocator for short lived values. Allocations are carved out of large chunks and
+//! are all released together by reset, which takes the arena mutably so that no reference
+//! it handed out can be used afterwards. Values are never dropped, so only types which do
+//! not need drop may be allocated.
+//!
+//! reset keeps the first chunk, so a loop which resets the arena on each iteration and
+//! allocates less than a chunk in between does not reach the allocator after the first.
+
+use core::cell::UnsafeCell;
+use core::mem::{align_of, needs_drop, size_of};
+use core::ptr::{copy_nonoverlapping, write, write_bytes};
+use core::slice::from_raw_parts_mut;
+use ffi::{alloc, release};
+use prelude::*;
+
+/// Chunk size used by the WebSocket workers' arenas
+pub const ARENA_CHUNK_SIZE: usize = 16 * 1024;
+
+struct ArenaState {
+	// every chunk allocated since the last reset, the first is kept by reset
+	chunks: Vec<Ptr<u8>>,
+	// the chunk being bumped, its size and the first free byte in it
+	cur: Ptr<u8>,
+	cur_size: usize,
+	offset: usize,
+	chunk_size: usize,
+}
+
+pub struct Arena {
+	state: UnsafeCell<ArenaState>,
+}
+
+impl Drop for Arena {
+	fn drop(&mut self) {
+		let state = self.state.get_mut();
+		for chunk in &state.chunks {
+			chunk.release();
+		}
+	}
+}
+
+impl Arena {
+	/// An arena which allocates chunk_size bytes at a time. Nothing is allocated until the
+	/// first value. IllegalArgument if chunk_size is 0.
+	pub fn new(chunk_size: usize) -> Result<Self, Error> {
+		if chunk_size == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		Ok(Self {
+			state: UnsafeCell::new(ArenaState {
+				chunks: Vec::new(),
+				cur: Ptr::null(),
+				cur_size: 0,
+				offset: 0,
+				chunk_size,
+			}),
+		})
+	}
+
+	/// Moves value into the arena. IllegalArgument if T needs drop, as the arena would
+	/// leak whatever the value owns.
+	#[allow(clippy::mut_from_ref)]
+	pub fn alloc<T>(&self, value: T) -> Result<&mut T, Error> {
+		if needs_drop::<T>() {
+			return Err(err!(IllegalArgument));
+		}
+		match self.bump(size_of::<T>(), align_of::<T>()) {
+			Ok(ptr) => unsafe {
+				write(ptr as *mut T, value);
+				Ok(&mut *(ptr as *mut T))
+			},
+			Err(e) => Err(e),
+		}
+	}
+
+	/// n zeroed bytes
+	#[allow(clippy::mut_from_ref)]
+	pub fn alloc_bytes(&self, n: usize) -> Result<&mut [u8], Error> {
+		match self.bump(n, 1) {
+			Ok(ptr) => unsafe {
+				write_bytes(ptr, 0, n);
+				Ok(from_raw_parts_mut(ptr, n))
+			},
+			Err(e) => Err(e),
+		}
+	}
+
+	/// A copy of bytes
+	#[allow(clippy::mut_from_ref)]
+	pub fn alloc_copy(&self, bytes: &[u8]) -> Result<&mut [u8], Error> {
+		match self.bump(bytes.len(), 1) {
+			Ok(ptr) => unsafe {
+				copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+				Ok(from_raw_parts_mut(ptr, bytes.len()))
+			},
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Releases every allocation, keeping the first chunk for reuse
+	pub fn reset(&mut self) {
+		let state = self.state.get_mut();
+		for i in 1..state.chunks.len() {
+			state.chunks[i].release();
+		}
+		state.chunks.truncate(1);
+		if state.chunks.len() == 1 {
+			state.cur = state.chunks[0];
+			state.cur_size = state.chunk_size;
+		}
+		state.offset = 0;
+	}
+
+	/// Number of chunks currently held
+	pub fn chunks(&self) -> usize {
+		unsafe { (*self.state.get()).chunks.len() }
+	}
+
+	fn bump(&self, size: usize, align: usize) -> Result<*mut u8, Error> {
+		let state = unsafe { &mut *self.state.get() };
+		if !state.cur.is_null() {
+			let start = state.cur.raw() as usize + state.offset;
+			let pad = (align - start % align) % align;
+			if state.offset + pad + size <= state.cur_size {
+				state.offset += pad + size;
+				return Ok((start + pad) as *mut u8);
+			}
+		}
+		// chunks come from the allocator aligned for any type
+		let chunk_size = if size > state.chunk_size {
+			size
+		} else {
+			state.chunk_size
+		};
+		let chunk = unsafe { alloc(chunk_size) } as *mut u8;
+		if chunk.is_null() {
+			return Err(err!(Alloc));
+		}
+		match state.chunks.push(Ptr::new(chunk)) {
+			Ok(_) => {}
+			Err(e) => {
+				unsafe {
+					release(chunk);
+				}
+				return Err(e);
+			}
+		}
+		// a value too large for a chunk of its own size does not replace the current chunk,
+		// which may still have room for the values after it
+		if chunk_size == state.chunk_size || state.cur.is_null() {
+			state.cur = Ptr::new(chunk);
+			state.cur_size = chunk_size;
+			state.offset = size;
+		}
+		Ok(chunk)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct Point {
+		x: u64,
+		y: u8,
+	}
+
+	#[test]
+	fn test_arena() {
+		let _alloc = AllocGuard::new();
+		assert!(Arena::new(0).unwrap_err().kind == IllegalArgument);
+		let mut arena = Arena::new(64).unwrap();
+		assert_eq!(arena.chunks(), 0);
+		{
+			let a = arena.alloc(1u8).unwrap();
+			let b = arena.alloc(Point { x: 2, y: 3 }).unwrap();
+			let c = arena.alloc_copy(b"hello").unwrap();
+			assert_eq!(b as *mut Point as usize % align_of::<Point>(), 0);
+			*a += 1;
+			b.x += 1;
+			c[0] = b'j';
+			assert_eq!(*a, 2);
+			assert!(b.x == 3 && b.y == 3);
+			assert_eq!(c, b"jello");
+			assert_eq!(arena.alloc_bytes(4).unwrap(), &[0u8; 4]);
+			assert_eq!(arena.chunks(), 1);
+
+			// larger than a chunk, the current chunk stays in use
+			let big = arena.alloc_bytes(100).unwrap();
+			assert_eq!(big.len(), 100);
+			assert_eq!(arena.chunks(), 2);
+			let _d = arena.alloc(4u64).unwrap();
+			assert_eq!(arena.chunks(), 2);
+			for _ in 0..8 {
+				arena.alloc(5u64).unwrap();
+			}
+			assert_eq!(arena.chunks(), 3);
+		}
+		let v: Vec<u8> = Vec::new();
+		assert!(arena.alloc(v).unwrap_err().kind == IllegalArgument);
+
+		arena.reset();
+		assert_eq!(arena.chunks(), 1);
+		for _ in 0..8 {
+			arena.alloc(6u64).unwrap();
+		}
+		assert_eq!(arena.chunks(), 1);
+	}
+}
@@ -0,0 +1,119 @@
+//! # BloomFilter
+//! Probabilistic set membership in a fixed number of bits. contains never misses an item
+//! that was inserted but may report one that was not; the chance grows with the number of
+//! items relative to the size. Each of the k bit positions of an item is a murmur3_32 of
+//! its bytes under a different seed. Items cannot be removed, only cleared all at once.
+
+use prelude::*;
+
+const WORD_BITS: usize = 64;
+
+pub struct BloomFilter {
+	words: Vec<u64>,
+	bits: usize,
+	hashes: u32,
+}
+
+impl BloomFilter {
+	/// A filter of bits bits, rounded up to a multiple of 64, setting hashes bits per item.
+	/// IllegalArgument if either is 0.
+	pub fn new(bits: usize, hashes: u32) -> Result<Self, Error> {
+		if bits == 0 || hashes == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut words = Vec::new();
+		match words.resize((bits + WORD_BITS - 1) / WORD_BITS) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		Ok(Self {
+			bits: words.len() * WORD_BITS,
+			words,
+			hashes,
+		})
+	}
+
+	/// Adds item, returning true if any of its bits was clear, meaning it was certainly not
+	/// in the filter before. Seen-message suppression can drop an item when this is false.
+	pub fn insert(&mut self, item: &[u8]) -> bool {
+		let mut added = false;
+		for i in 0..self.hashes {
+			let (word, mask) = self.position(item, i);
+			if self.words[word] & mask == 0 {
+				self.words[word] |= mask;
+				added = true;
+			}
+		}
+		added
+	}
+
+	pub fn contains(&self, item: &[u8]) -> bool {
+		for i in 0..self.hashes {
+			let (word, mask) = self.position(item, i);
+			if self.words[word] & mask == 0 {
+				return false;
+			}
+		}
+		true
+	}
+
+	pub fn clear(&mut self) {
+		for i in 0..self.words.len() {
+			self.words[i] = 0;
+		}
+	}
+
+	/// Size in bits after rounding
+	pub fn bits(&self) -> usize {
+		self.bits
+	}
+
+	pub fn hashes(&self) -> u32 {
+		self.hashes
+	}
+
+	fn position(&self, item: &[u8], i: u32) -> (usize, u64) {
+		let bit = murmur3_32_of_slice(item, MURMUR_SEED.wrapping_add(i)) as usize % self.bits;
+		(bit / WORD_BITS, 1u64 << (bit % WORD_BITS))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_bloom() {
+		let _alloc = AllocGuard::new();
+		assert!(BloomFilter::new(0, 3).unwrap_err().kind == IllegalArgument);
+		assert!(BloomFilter::new(64, 0).unwrap_err().kind == IllegalArgument);
+
+		let mut filter = BloomFilter::new(10_000, 5).unwrap();
+		assert_eq!(filter.bits(), 10_048);
+		assert_eq!(filter.hashes(), 5);
+		// a new item can already look present once the filter fills up
+		let mut added = 0;
+		for i in 0..1_000u64 {
+			if filter.insert(&i.to_be_bytes()) {
+				added += 1;
+			}
+		}
+		assert!(added > 980);
+		for i in 0..1_000u64 {
+			assert!(filter.contains(&i.to_be_bytes()));
+			assert!(!filter.insert(&i.to_be_bytes()));
+		}
+		// about 1% is expected at 10 bits per item and 5 hashes
+		let mut false_positives = 0;
+		for i in 1_000..11_000u64 {
+			if filter.contains(&i.to_be_bytes()) {
+				false_positives += 1;
+			}
+		}
+		assert!(false_positives < 300);
+
+		filter.clear();
+		assert!(!filter.contains(&0u64.to_be_bytes()));
+		assert!(filter.insert(&0u64.to_be_bytes()));
+	}
+}
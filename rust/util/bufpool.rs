@@ -0,0 +1,176 @@
+//! # BufferPool
+//! Byte buffers kept for reuse in power of two size classes, so that connections can take
+//! read buffers while data arrives and give them back once it has been consumed, without a
+//! trip to the allocator each time. A Vec's capacity is always a power of two, which is the
+//! class a returned buffer goes to. Buffers smaller than MIN_BUFFER_SIZE are served from
+//! the smallest class and those larger than MAX_BUFFER_SIZE are never kept.
+//!
+//! Handles are cloned to share a pool, and buffers may be returned from any thread.
+
+use prelude::*;
+
+/// Smallest size class
+pub const MIN_BUFFER_SIZE: usize = 1 << MIN_CLASS;
+/// Largest size class
+pub const MAX_BUFFER_SIZE: usize = 1 << MAX_CLASS;
+
+const MIN_CLASS: usize = 8;
+const MAX_CLASS: usize = 24;
+
+struct BufferPoolInner {
+	// classes[i] holds buffers with a capacity of 1 << (MIN_CLASS + i)
+	classes: Vec<Vec<Vec<u8>>>,
+	max_per_class: usize,
+}
+
+pub struct BufferPool {
+	inner: Arc<Mutex<BufferPoolInner>>,
+}
+
+impl Clone for BufferPool {
+	fn clone(&self) -> Result<Self, Error> {
+		match self.inner.clone() {
+			Ok(inner) => Ok(Self { inner }),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+// index into classes of the smallest class holding size bytes
+fn class_of(size: usize) -> usize {
+	let mut class = MIN_CLASS;
+	while (1 << class) < size {
+		class += 1;
+	}
+	class - MIN_CLASS
+}
+
+impl BufferPool {
+	/// A pool keeping at most max_per_class buffers of each size
+	pub fn new(max_per_class: usize) -> Result<Self, Error> {
+		let mut classes = Vec::new();
+		for _ in MIN_CLASS..=MAX_CLASS {
+			match classes.push(Vec::new()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		match Arc::new(Mutex::new(BufferPoolInner {
+			classes,
+			max_per_class,
+		})) {
+			Ok(inner) => Ok(Self { inner }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// A buffer of len bytes. A reused buffer is not cleared, so the caller must not read
+	/// bytes it has not written.
+	pub fn get(&self, len: usize) -> Result<Vec<u8>, Error> {
+		if len == 0 {
+			return Ok(Vec::new());
+		}
+		let mut buf = if len <= MAX_BUFFER_SIZE {
+			let mut inner = self.inner.lock();
+			match inner.classes[class_of(len)].pop() {
+				Some(buf) => buf,
+				None => Vec::new(),
+			}
+		} else {
+			Vec::new()
+		};
+		if buf.len() == 0 {
+			// allocate the whole class so that put files the buffer back under it
+			buf.set_min(0);
+			let class_size = if len < MIN_BUFFER_SIZE {
+				MIN_BUFFER_SIZE
+			} else {
+				len
+			};
+			match buf.reserve(class_size) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		// a reused buffer keeps its contents, only growing past them zeroes
+		if buf.len() > len {
+			buf.truncate(len);
+		} else {
+			match buf.resize(len) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(buf)
+	}
+
+	/// Takes buf back for reuse, or drops it if its class is full or it is out of range
+	pub fn put(&self, buf: Vec<u8>) {
+		let capacity = buf.capacity();
+		if capacity < MIN_BUFFER_SIZE || capacity > MAX_BUFFER_SIZE {
+			return;
+		}
+		let mut inner = self.inner.lock();
+		let max = inner.max_per_class;
+		let class = &mut inner.classes[class_of(capacity)];
+		if class.len() < max {
+			let _ = class.push(buf);
+		}
+	}
+
+	/// Number of buffers held in the class serving len bytes
+	pub fn available(&self, len: usize) -> usize {
+		if len > MAX_BUFFER_SIZE {
+			return 0;
+		}
+		self.inner.lock().classes[class_of(len)].len()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_bufpool() {
+		let _alloc = AllocGuard::new();
+		let pool = BufferPool::new(2).unwrap();
+		let mut a = pool.get(1000).unwrap();
+		assert_eq!(a.len(), 1000);
+		assert_eq!(a.capacity(), 1024);
+		a[500] = 7;
+		pool.put(a);
+		assert_eq!(pool.available(1000), 1);
+		assert_eq!(pool.available(513), 1);
+		assert_eq!(pool.available(1025), 0);
+
+		// the same buffer comes back, contents and all, for any size in its class
+		let b = pool.get(600).unwrap();
+		assert_eq!(b.len(), 600);
+		assert_eq!(b.capacity(), 1024);
+		assert_eq!(pool.available(1000), 0);
+		pool.put(b);
+		let b = pool.get(1000).unwrap();
+		assert_eq!(b[500], 7);
+
+		// small requests come from the smallest class
+		let c = pool.get(10).unwrap();
+		assert_eq!(c.capacity(), MIN_BUFFER_SIZE);
+		pool.put(c);
+		assert_eq!(pool.available(1), 1);
+
+		// a full class drops what is returned, other handles share the buffers
+		let clone = pool.clone().unwrap();
+		let d = pool.get(1024).unwrap();
+		let e = pool.get(1024).unwrap();
+		clone.put(b);
+		clone.put(d);
+		clone.put(e);
+		assert_eq!(pool.available(1024), 2);
+		let mut v = Vec::new();
+		v.push(1u8).unwrap();
+		pool.put(v);
+		assert_eq!(pool.available(1), 1);
+		assert_eq!(pool.get(0).unwrap().len(), 0);
+	}
+}
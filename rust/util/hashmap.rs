@@ -0,0 +1,270 @@
+//! # HashMap
+//! An owning map built on the intrusive Hashtable. Entries are allocated on insert and
+//! released on remove, clear or drop so callers never handle nodes directly.
+
+use core::iter::{IntoIterator, Iterator};
+use core::marker::PhantomData;
+use core::mem::replace;
+use core::option::Option as CoreOption;
+use core::ptr::{drop_in_place, read};
+use prelude::*;
+
+/// Default number of buckets, grown as needed by the table's rehash policy
+const DEFAULT_BUCKETS: usize = 16;
+
+struct Entry<K: Hash + PartialEq, V> {
+	key: K,
+	value: V,
+}
+
+impl<K: Hash + PartialEq, V> PartialEq for Entry<K, V> {
+	fn eq(&self, other: &Self) -> bool {
+		self.key == other.key
+	}
+}
+
+impl<K: Hash + PartialEq, V> Hash for Entry<K, V> {
+	fn hash(&self) -> usize {
+		self.key.hash()
+	}
+}
+
+pub struct HashMap<K: Hash + PartialEq, V> {
+	table: Hashtable<Entry<K, V>>,
+}
+
+pub struct HashMapIter<'a, K: Hash + PartialEq, V> {
+	inner: HashtableRefIterator<'a, Entry<K, V>>,
+}
+
+pub struct HashMapIterMut<'a, K: Hash + PartialEq, V> {
+	inner: HashtableRefIterator<'a, Entry<K, V>>,
+	_marker: PhantomData<&'a mut V>,
+}
+
+impl<'a, K: Hash + PartialEq, V> Iterator for HashMapIter<'a, K, V> {
+	type Item = (&'a K, &'a V);
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		match self.inner.next() {
+			CoreOption::Some(node) => {
+				let entry = unsafe { &(*node.raw()).value };
+				CoreOption::Some((&entry.key, &entry.value))
+			}
+			CoreOption::None => CoreOption::None,
+		}
+	}
+}
+
+impl<'a, K: Hash + PartialEq, V> Iterator for HashMapIterMut<'a, K, V> {
+	type Item = (&'a K, &'a mut V);
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		match self.inner.next() {
+			CoreOption::Some(node) => {
+				let entry = unsafe { &mut (*node.raw()).value };
+				CoreOption::Some((&entry.key, &mut entry.value))
+			}
+			CoreOption::None => CoreOption::None,
+		}
+	}
+}
+
+// the map owns its entries
+unsafe impl<K: Hash + PartialEq + Send, V: Send> Send for HashMap<K, V> {}
+unsafe impl<K: Hash + PartialEq + Sync, V: Sync> Sync for HashMap<K, V> {}
+
+impl<K: Hash + PartialEq, V> Drop for HashMap<K, V> {
+	fn drop(&mut self) {
+		self.release_all();
+	}
+}
+
+impl<K: Hash + PartialEq, V> HashMap<K, V> {
+	pub fn new() -> Result<Self, Error> {
+		Self::with_buckets(DEFAULT_BUCKETS)
+	}
+
+	pub fn with_buckets(buckets: usize) -> Result<Self, Error> {
+		if buckets == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		match Hashtable::new(buckets) {
+			Ok(table) => Ok(Self { table }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Inserts value for key, returning the value it replaced if key was already present
+	pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Error> {
+		match self.table.find_hashed(key.hash(), |e| e.key == key) {
+			Some(mut node) => return Ok(Some(replace(&mut node.value.value, value))),
+			None => {}
+		}
+		let node = match Ptr::alloc(Node::new(Entry { key, value })) {
+			Ok(node) => node,
+			Err(e) => return Err(e),
+		};
+		self.table.insert(node);
+		Ok(None)
+	}
+
+	pub fn get(&self, key: &K) -> Option<&V> {
+		match self.table.find_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => Some(unsafe { &(*node.raw()).value.value }),
+			None => None,
+		}
+	}
+
+	pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+		match self.table.find_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => Some(unsafe { &mut (*node.raw()).value.value }),
+			None => None,
+		}
+	}
+
+	pub fn contains_key(&self, key: &K) -> bool {
+		self.get(key).is_some()
+	}
+
+	/// Removes key, returning its value if it was present
+	pub fn remove(&mut self, key: &K) -> Option<V> {
+		match self.table.remove_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => {
+				let entry = unsafe { read(&(*node.raw()).value) };
+				node.release();
+				Some(entry.value)
+			}
+			None => None,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.table.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.table.len() == 0
+	}
+
+	pub fn clear(&mut self) {
+		self.release_all();
+		self.table.clear();
+	}
+
+	pub fn iter(&self) -> HashMapIter<'_, K, V> {
+		HashMapIter {
+			inner: (&self.table).into_iter(),
+		}
+	}
+
+	pub fn iter_mut(&mut self) -> HashMapIterMut<'_, K, V> {
+		HashMapIterMut {
+			inner: (&self.table).into_iter(),
+			_marker: PhantomData,
+		}
+	}
+
+	// the iterator reads a node's successor before returning it, so each node can be
+	// released as soon as it is returned
+	fn release_all(&mut self) {
+		for node in &self.table {
+			unsafe {
+				drop_in_place(node.raw());
+			}
+			node.release();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct DropCounter {
+		drops: Rc<u64>,
+	}
+
+	impl Drop for DropCounter {
+		fn drop(&mut self) {
+			aadd!(&mut *self.drops, 1);
+		}
+	}
+
+	#[test]
+	fn test_hashmap() {
+		let _alloc = AllocGuard::new();
+		let mut map: HashMap<u64, u64> = HashMap::new().unwrap();
+		assert!(HashMap::<u64, u64>::with_buckets(0).is_err());
+		assert!(map.is_empty());
+		for i in 0..100u64 {
+			assert!(map.insert(i, i * 10).unwrap().is_none());
+		}
+		assert_eq!(map.len(), 100);
+		assert!(map.insert(7, 77).unwrap() == Some(70));
+		assert_eq!(map.len(), 100);
+		assert_eq!(*map.get(&7).unwrap(), 77);
+		assert!(map.get(&100).is_none());
+		assert!(map.contains_key(&99));
+
+		*map.get_mut(&8).unwrap() += 1;
+		assert_eq!(*map.get(&8).unwrap(), 81);
+		assert!(map.get_mut(&1000).is_none());
+
+		assert!(map.remove(&8) == Some(81));
+		assert!(map.remove(&8).is_none());
+		assert_eq!(map.len(), 99);
+
+		let mut sum = 0;
+		let mut count = 0;
+		for (k, v) in map.iter() {
+			assert!(*k != 8);
+			sum += *v;
+			count += 1;
+		}
+		assert_eq!(count, 99);
+		assert_eq!(sum, 49_500 - 80 + 7);
+		for (_k, v) in map.iter_mut() {
+			*v = 1;
+		}
+		assert_eq!(map.iter().map(|(_k, v)| *v).sum::<u64>(), 99);
+
+		map.clear();
+		assert!(map.is_empty());
+		assert!(map.get(&1).is_none());
+		assert!(map.insert(1, 2).unwrap().is_none());
+		assert_eq!(*map.get(&1).unwrap(), 2);
+	}
+
+	#[test]
+	fn test_hashmap_drop() {
+		let _alloc = AllocGuard::new();
+		let drops = Rc::new(0u64).unwrap();
+		{
+			let mut map = HashMap::new().unwrap();
+			for i in 0..10i32 {
+				let v = DropCounter {
+					drops: drops.clone().unwrap(),
+				};
+				assert!(map.insert(i, v).unwrap().is_none());
+			}
+			// the replaced value is handed back and dropped by the caller
+			let v = DropCounter {
+				drops: drops.clone().unwrap(),
+			};
+			assert!(map.insert(0, v).unwrap().is_some());
+			assert_eq!(*drops, 1);
+			assert!(map.remove(&1).is_some());
+			assert_eq!(*drops, 2);
+			map.clear();
+			assert_eq!(*drops, 11);
+			for i in 0..5i32 {
+				let v = DropCounter {
+					drops: drops.clone().unwrap(),
+				};
+				assert!(map.insert(i, v).unwrap().is_none());
+			}
+		}
+		assert_eq!(*drops, 16);
+	}
+}
@@ -0,0 +1,112 @@
+//! # HashSet
+//! A set of owned values, a HashMap whose values carry no data. Exact membership, unlike
+//! the BloomFilter, at the cost of storing every value.
+
+use core::iter::Iterator;
+use core::option::Option as CoreOption;
+use prelude::*;
+
+pub struct HashSet<T: Hash + PartialEq> {
+	map: HashMap<T, ()>,
+}
+
+pub struct HashSetIter<'a, T: Hash + PartialEq> {
+	inner: HashMapIter<'a, T, ()>,
+}
+
+impl<'a, T: Hash + PartialEq> Iterator for HashSetIter<'a, T> {
+	type Item = &'a T;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		match self.inner.next() {
+			CoreOption::Some((value, _)) => CoreOption::Some(value),
+			CoreOption::None => CoreOption::None,
+		}
+	}
+}
+
+impl<T: Hash + PartialEq> HashSet<T> {
+	pub fn new() -> Result<Self, Error> {
+		match HashMap::new() {
+			Ok(map) => Ok(Self { map }),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn with_buckets(buckets: usize) -> Result<Self, Error> {
+		match HashMap::with_buckets(buckets) {
+			Ok(map) => Ok(Self { map }),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Adds value, returning false if an equal value was already present. The set keeps
+	/// the value it already held in that case.
+	pub fn insert(&mut self, value: T) -> Result<bool, Error> {
+		if self.map.contains_key(&value) {
+			return Ok(false);
+		}
+		match self.map.insert(value, ()) {
+			Ok(_) => Ok(true),
+			Err(e) => Err(e),
+		}
+	}
+
+	pub fn contains(&self, value: &T) -> bool {
+		self.map.contains_key(value)
+	}
+
+	/// Removes value, returning whether it was present
+	pub fn remove(&mut self, value: &T) -> bool {
+		self.map.remove(value).is_some()
+	}
+
+	pub fn len(&self) -> usize {
+		self.map.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.map.is_empty()
+	}
+
+	pub fn clear(&mut self) {
+		self.map.clear();
+	}
+
+	pub fn iter(&self) -> HashSetIter<'_, T> {
+		HashSetIter {
+			inner: self.map.iter(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_hashset() {
+		let _alloc = AllocGuard::new();
+		let mut set = HashSet::new().unwrap();
+		assert!(HashSet::<u64>::with_buckets(0).is_err());
+		assert!(set.is_empty());
+		for i in 0..50u64 {
+			assert!(set.insert(i * 2).unwrap());
+		}
+		assert!(!set.insert(10).unwrap());
+		assert_eq!(set.len(), 50);
+		assert!(set.contains(&98));
+		assert!(!set.contains(&99));
+
+		assert!(set.remove(&98));
+		assert!(!set.remove(&98));
+		assert!(!set.contains(&98));
+		assert_eq!(set.len(), 49);
+		assert_eq!(set.iter().sum::<u64>(), 2 * (0..49).sum::<u64>());
+
+		set.clear();
+		assert!(set.is_empty());
+		assert!(!set.contains(&0));
+		assert!(set.insert(0).unwrap());
+	}
+}
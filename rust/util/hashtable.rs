@@ -1,6 +1,6 @@
 use core::iter::IntoIterator;
 use core::iter::Iterator;
-use core::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut, Fn};
 use core::option::Option as CoreOption;
 use core::ptr::null_mut;
 use prelude::*;
@@ -39,8 +39,18 @@ impl<V: PartialEq> Node<V> {
 	}
 }
 
+/// When a Hashtable grows its bucket array. After an insert leaves more than
+/// max_load_percent entries per 100 buckets the array is rebuilt growth_factor times larger.
+#[derive(Clone, Copy)]
+pub struct RehashPolicy {
+	pub max_load_percent: usize,
+	pub growth_factor: usize,
+}
+
 pub struct Hashtable<V: PartialEq + Hash> {
 	arr: Vec<Ptr<Node<V>>>,
+	len: usize,
+	policy: RehashPolicy,
 }
 
 pub struct HashtableIterator<V: PartialEq + Hash> {
@@ -143,15 +153,106 @@ impl<'a, V: PartialEq + Hash> IntoIterator for &'a Hashtable<V> {
 	}
 }
 
+impl Default for RehashPolicy {
+	fn default() -> Self {
+		Self {
+			max_load_percent: 100,
+			growth_factor: 2,
+		}
+	}
+}
+
+impl RehashPolicy {
+	/// Never rehash, keeping the bucket count given at construction
+	pub fn fixed() -> Self {
+		Self {
+			max_load_percent: 0,
+			growth_factor: 1,
+		}
+	}
+}
+
 impl<V: PartialEq + Hash> Hashtable<V> {
 	pub fn new(size: usize) -> Result<Self, Error> {
+		Self::with_policy(size, RehashPolicy::default())
+	}
+
+	pub fn with_policy(size: usize, policy: RehashPolicy) -> Result<Self, Error> {
 		let mut arr = Vec::new();
 		match arr.resize(size) {
-			Ok(_) => Ok(Self { arr }),
+			Ok(_) => Ok(Self {
+				arr,
+				len: 0,
+				policy,
+			}),
 			Err(e) => Err(e),
 		}
 	}
 
+	/// Number of nodes in the table
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Number of buckets
+	pub fn buckets(&self) -> usize {
+		self.arr.len()
+	}
+
+	/// Unlinks every node without releasing them. Callers that own the nodes must release
+	/// them first, for instance while iterating.
+	pub fn clear(&mut self) {
+		for i in 0..self.arr.len() {
+			self.arr[i] = Ptr::null();
+		}
+		self.len = 0;
+	}
+
+	/// Average number of nodes per bucket
+	pub fn load_factor(&self) -> f64 {
+		if self.arr.len() == 0 {
+			0.0
+		} else {
+			self.len as f64 / self.arr.len() as f64
+		}
+	}
+
+	/// Rebuilds the bucket array with size buckets, relinking every node. The nodes themselves
+	/// are not moved so pointers to them stay valid.
+	pub fn rehash(&mut self, size: usize) -> Result<(), Error> {
+		if size == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut arr: Vec<Ptr<Node<V>>> = Vec::new();
+		match arr.resize(size) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		for i in 0..self.arr.len() {
+			let mut ptr = self.arr[i];
+			while !ptr.is_null() {
+				let mut node = ptr;
+				ptr = (*ptr).next;
+				let index = node.value.hash() % size;
+				(*node).next = arr[index];
+				arr[index] = node;
+			}
+		}
+		self.arr = arr;
+		Ok(())
+	}
+
+	fn check_load(&mut self) {
+		let policy = self.policy;
+		if policy.max_load_percent == 0 || policy.growth_factor < 2 {
+			return;
+		}
+		if self.len * 100 > self.arr.len() * policy.max_load_percent {
+			// on allocation failure keep the current buckets, lookups stay correct
+			let _ = self.rehash(self.arr.len() * policy.growth_factor);
+		}
+	}
+
 	pub fn insert(&mut self, mut node: Ptr<Node<V>>) -> bool {
 		(*node).next = Ptr::null();
 		let value = &*node;
@@ -174,16 +275,24 @@ impl<V: PartialEq + Hash> Hashtable<V> {
 
 			(*prev).next = node;
 		}
+		self.len += 1;
+		self.check_load();
 		true
 	}
 
 	pub fn find(&self, value: &V) -> Option<Ptr<Node<V>>> {
+		self.find_hashed(value.hash(), |v| v == value)
+	}
+
+	/// Finds the node in the bucket for hash whose value satisfies eq. This allows lookups by
+	/// a part of the value, such as a key, without constructing a whole value.
+	pub fn find_hashed<F: Fn(&V) -> bool>(&self, hash: usize, eq: F) -> Option<Ptr<Node<V>>> {
 		if self.arr.len() == 0 {
 			return None;
 		}
-		let mut ptr = self.arr[value.hash() % self.arr.len()];
+		let mut ptr = self.arr[hash % self.arr.len()];
 		while !ptr.is_null() {
-			if &ptr.value == value {
+			if eq(&ptr.value) {
 				return Some(Ptr::new(ptr.raw()));
 			}
 			ptr = (ptr.as_ref()).next;
@@ -192,19 +301,26 @@ impl<V: PartialEq + Hash> Hashtable<V> {
 	}
 
 	pub fn remove(&mut self, value: &V) -> Option<Ptr<Node<V>>> {
+		self.remove_hashed(value.hash(), |v| v == value)
+	}
+
+	/// Unlinks and returns the node in the bucket for hash whose value satisfies eq
+	pub fn remove_hashed<F: Fn(&V) -> bool>(&mut self, hash: usize, eq: F) -> Option<Ptr<Node<V>>> {
 		if self.arr.len() > 0 {
-			let index = value.hash() % self.arr.len();
+			let index = hash % self.arr.len();
 			let mut ptr = self.arr[index];
 
-			if !ptr.is_null() && (*ptr).value == *value {
+			if !ptr.is_null() && eq(&(*ptr).value) {
 				self.arr[index] = (*ptr).next;
+				self.len -= 1;
 				return Some(Ptr::new(ptr.raw()));
 			}
 			let mut prev = self.arr[index];
 
 			while !ptr.is_null() {
-				if (*ptr).value == *value {
+				if eq(&(*ptr).value) {
 					(*prev).next = (*ptr).next;
+					self.len -= 1;
 					return Some(Ptr::new(ptr.raw()));
 				}
 				prev = ptr;
@@ -221,8 +337,8 @@ mod test {
 	use crate::ffi::alloc;
 	use core::mem::size_of;
 	use core::slice::from_raw_parts;
-	use ffi::getalloccount;
 	use std::murmur32::MURMUR_SEED;
+	use util::proptest::*;
 
 	struct TestValue {
 		k: i32,
@@ -251,7 +367,7 @@ mod test {
 
 	#[test]
 	fn test_hashtable1() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 		let v;
 		unsafe {
 			v = alloc(size_of::<Node<TestValue>>()) as *mut Node<TestValue>;
@@ -273,12 +389,11 @@ mod test {
 			n.release();
 			assert!(hash.remove(&1i32.into()).is_none());
 		}
-		assert_eq!(unsafe { getalloccount() }, initial);
 	}
 
 	#[test]
 	fn test_hashtable_collisions() {
-		let initial = unsafe { getalloccount() };
+		let _alloc = AllocGuard::new();
 
 		let v1 = Ptr::alloc(Node::new(TestValue { k: 1, v: 2 })).unwrap();
 		let v2 = Ptr::alloc(Node::new(TestValue { k: 2, v: 3 })).unwrap();
@@ -289,7 +404,7 @@ mod test {
 		let v6 = Ptr::alloc(Node::new(TestValue { k: 3, v: 4 })).unwrap();
 
 		{
-			let mut hash = Hashtable::new(1).unwrap();
+			let mut hash = Hashtable::with_policy(1, RehashPolicy::fixed()).unwrap();
 			assert!(hash.insert(v1));
 			assert!(hash.insert(v2));
 			assert!(hash.insert(v3));
@@ -339,7 +454,6 @@ mod test {
 			assert!(hash.remove(&3i32.into()).is_none());
 			n.release();
 		}
-		assert_eq!(unsafe { getalloccount() }, initial);
 	}
 
 	#[test]
@@ -359,4 +473,122 @@ mod test {
 			assert_eq!(check[i], 1);
 		}
 	}
+
+	#[test]
+	fn test_hashtable_prop_model() {
+		let _alloc = AllocGuard::new();
+		// each op is an insert, remove or find of one of 32 keys, checked against an array
+		// model. Small bucket counts force long collision chains.
+		check(PropConfig::default(), |ops: &Vec<u64>| {
+			let policy = if ops.len() % 2 == 0 {
+				RehashPolicy::fixed()
+			} else {
+				RehashPolicy::default()
+			};
+			let mut hash = Hashtable::with_policy(ops.len() % 4 + 1, policy).unwrap();
+			// i64::MIN marks a key which is not present
+			let mut model = [i64::MIN; 32];
+			let mut ok = true;
+			for op in ops {
+				let k = ((*op >> 2) % 32) as i32;
+				let v = (*op >> 7) as i32;
+				match *op % 3 {
+					0 => {
+						let node = Ptr::alloc(Node::new(TestValue { k, v })).unwrap();
+						let inserted = hash.insert(node);
+						ok = inserted == (model[k as usize] == i64::MIN);
+						if inserted {
+							model[k as usize] = v as i64;
+						} else {
+							node.release();
+						}
+					}
+					1 => match hash.remove(&k.into()) {
+						Some(n) => {
+							ok = model[k as usize] == n.v as i64;
+							model[k as usize] = i64::MIN;
+							n.release();
+						}
+						None => ok = model[k as usize] == i64::MIN,
+					},
+					_ => match hash.find(&k.into()) {
+						Some(n) => ok = model[k as usize] == n.v as i64,
+						None => ok = model[k as usize] == i64::MIN,
+					},
+				}
+				if !ok {
+					break;
+				}
+			}
+
+			let mut count = 0;
+			for _n in &hash {
+				count += 1;
+			}
+			ok = ok && count == hash.len();
+			for k in 0..32 {
+				if model[k] != i64::MIN {
+					count -= 1;
+				}
+				match hash.remove(&(k as i32).into()) {
+					Some(n) => n.release(),
+					None => {}
+				}
+			}
+			ok && count == 0
+		});
+	}
+
+	#[test]
+	fn test_hashtable_rehash() {
+		let _alloc = AllocGuard::new();
+		let mut hash = Hashtable::new(2).unwrap();
+		let mut nodes = Vec::new();
+		for i in 0..100 {
+			let v = Ptr::alloc(Node::new(TestValue { k: i, v: i * 2 })).unwrap();
+			assert!(hash.insert(v));
+			assert!(nodes.push(v).is_ok());
+			assert!(hash.load_factor() <= 1.0);
+		}
+		assert_eq!(hash.len(), 100);
+		assert_eq!(hash.buckets(), 128);
+		// nodes are relinked in place, never copied
+		for i in 0..100 {
+			let n = hash.find(&i.into()).unwrap();
+			assert_eq!(n.raw(), nodes[i as usize].raw());
+			assert_eq!(n.v, i * 2);
+		}
+
+		let mut fixed = Hashtable::with_policy(2, RehashPolicy::fixed()).unwrap();
+		let mut lazy = Hashtable::with_policy(
+			2,
+			RehashPolicy {
+				max_load_percent: 400,
+				growth_factor: 4,
+			},
+		)
+		.unwrap();
+		for i in 0..10 {
+			assert!(fixed.insert(Ptr::alloc(Node::new(TestValue { k: i, v: i })).unwrap()));
+			assert!(lazy.insert(Ptr::alloc(Node::new(TestValue { k: i, v: i })).unwrap()));
+		}
+		assert_eq!(fixed.buckets(), 2);
+		assert!(fixed.load_factor() == 5.0);
+		assert_eq!(lazy.buckets(), 8);
+		assert!(fixed.rehash(0).is_err());
+		assert!(fixed.rehash(16).is_ok());
+		assert_eq!(fixed.buckets(), 16);
+
+		for i in 0..100 {
+			hash.remove(&i.into()).unwrap().release();
+			if i < 10 {
+				fixed.remove(&i.into()).unwrap().release();
+				lazy.remove(&i.into()).unwrap().release();
+			}
+		}
+		assert_eq!(hash.len(), 0);
+		assert_eq!(fixed.len(), 0);
+		assert!(hash.load_factor() == 0.0);
+		assert!(hash.find(&1i32.into()).is_none());
+	}
 }
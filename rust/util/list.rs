@@ -0,0 +1,227 @@
+//! # IntrusiveList
+//! A doubly linked list whose links are stored inside the elements. The list never allocates
+//! or frees: callers own the elements and must remove an element before releasing it.
+
+use core::iter::Iterator;
+use core::marker::{PhantomData, Sized};
+use core::option::Option as CoreOption;
+use prelude::*;
+
+/// The next and previous pointers embedded in each element
+pub struct ListLinks<T> {
+	next: Ptr<T>,
+	prev: Ptr<T>,
+}
+
+/// Implemented by types which can be linked into an IntrusiveList
+pub trait Linked: Sized {
+	fn links(&self) -> &ListLinks<Self>;
+	fn links_mut(&mut self) -> &mut ListLinks<Self>;
+}
+
+pub struct IntrusiveList<T: Linked> {
+	head: Ptr<T>,
+	tail: Ptr<T>,
+	len: usize,
+}
+
+/// Iterator over the elements from the head. The successor is read before an element is
+/// returned so that a list which is being discarded can release its elements as it goes.
+pub struct IntrusiveListIter<'a, T: Linked> {
+	cur: Ptr<T>,
+	_marker: PhantomData<&'a IntrusiveList<T>>,
+}
+
+impl<T> ListLinks<T> {
+	pub fn new() -> Self {
+		Self {
+			next: Ptr::null(),
+			prev: Ptr::null(),
+		}
+	}
+
+	pub fn next(&self) -> Ptr<T> {
+		self.next
+	}
+
+	pub fn prev(&self) -> Ptr<T> {
+		self.prev
+	}
+}
+
+impl<'a, T: Linked> Iterator for IntrusiveListIter<'a, T> {
+	type Item = Ptr<T>;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		if self.cur.is_null() {
+			return CoreOption::None;
+		}
+		let ret = self.cur;
+		self.cur = ret.links().next;
+		CoreOption::Some(ret)
+	}
+}
+
+impl<T: Linked> IntrusiveList<T> {
+	pub fn new() -> Self {
+		Self {
+			head: Ptr::null(),
+			tail: Ptr::null(),
+			len: 0,
+		}
+	}
+
+	pub fn head(&self) -> Ptr<T> {
+		self.head
+	}
+
+	/// The element pushed earliest of those still in the list
+	pub fn tail(&self) -> Ptr<T> {
+		self.tail
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.head.is_null()
+	}
+
+	/// Links elem in at the head. elem must not already be in a list.
+	pub fn push_front(&mut self, mut elem: Ptr<T>) {
+		let links = elem.links_mut();
+		links.next = self.head;
+		links.prev = Ptr::null();
+		if self.head.is_null() {
+			self.tail = elem;
+		} else {
+			self.head.links_mut().prev = elem;
+		}
+		self.head = elem;
+		self.len += 1;
+	}
+
+	/// Unlinks elem, which must be in this list
+	pub fn remove(&mut self, mut elem: Ptr<T>) {
+		let (mut next, mut prev) = {
+			let links = elem.links();
+			(links.next, links.prev)
+		};
+		if prev.is_null() {
+			self.head = next;
+		} else {
+			prev.links_mut().next = next;
+		}
+		if next.is_null() {
+			self.tail = prev;
+		} else {
+			next.links_mut().prev = prev;
+		}
+		let links = elem.links_mut();
+		links.next = Ptr::null();
+		links.prev = Ptr::null();
+		self.len -= 1;
+	}
+
+	pub fn iter(&self) -> IntrusiveListIter<'_, T> {
+		IntrusiveListIter {
+			cur: self.head,
+			_marker: PhantomData,
+		}
+	}
+
+	/// Checks that every element's links agree with its neighbours' and that the number of
+	/// elements matches len. Intended for tests and debugging as it walks the whole list.
+	pub fn validate(&self) -> bool {
+		let mut count = 0;
+		let mut prev = Ptr::null();
+		for elem in self.iter() {
+			if elem.links().prev != prev {
+				return false;
+			}
+			prev = elem;
+			count += 1;
+		}
+		count == self.len && self.tail == prev
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct Item {
+		links: ListLinks<Item>,
+		value: u64,
+	}
+
+	impl Linked for Item {
+		fn links(&self) -> &ListLinks<Self> {
+			&self.links
+		}
+		fn links_mut(&mut self) -> &mut ListLinks<Self> {
+			&mut self.links
+		}
+	}
+
+	fn values(list: &IntrusiveList<Item>) -> Vec<u64> {
+		let mut ret = Vec::new();
+		for item in list.iter() {
+			ret.push(item.value).unwrap();
+		}
+		ret
+	}
+
+	#[test]
+	fn test_intrusive_list() {
+		let _alloc = AllocGuard::new();
+		let mut list = IntrusiveList::new();
+		assert!(list.is_empty());
+		assert!(list.validate());
+		assert!(list.iter().next().is_none());
+
+		let mut items = [Ptr::null(); 5];
+		for i in 0..5 {
+			items[i] = Ptr::alloc(Item {
+				links: ListLinks::new(),
+				value: i as u64,
+			})
+			.unwrap();
+			list.push_front(items[i]);
+			assert!(list.validate());
+		}
+		assert_eq!(list.len(), 5);
+		assert!(list.head() == items[4]);
+		assert!(list.tail() == items[0]);
+		assert_eq!(values(&list).as_slice(), &[4, 3, 2, 1, 0]);
+
+		// middle, head and tail
+		list.remove(items[2]);
+		assert!(list.validate());
+		assert!(items[2].links().next().is_null() && items[2].links().prev().is_null());
+		list.remove(items[4]);
+		assert!(list.validate());
+		list.remove(items[0]);
+		assert!(list.validate());
+		assert_eq!(values(&list).as_slice(), &[3, 1]);
+		assert!(list.head().links().prev().is_null());
+		assert!(list.tail() == items[1]);
+
+		// removed elements can be linked in again
+		list.push_front(items[2]);
+		assert_eq!(values(&list).as_slice(), &[2, 3, 1]);
+
+		while !list.is_empty() {
+			let item = list.head();
+			list.remove(item);
+			item.release();
+		}
+		assert!(list.is_empty());
+		assert!(list.tail().is_null());
+		assert_eq!(list.len(), 0);
+		assert!(list.validate());
+		items[0].release();
+		items[4].release();
+	}
+}
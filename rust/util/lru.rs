@@ -0,0 +1,277 @@
+//! # LruCache
+//! A map of at most capacity entries which evicts the least recently used entry to make
+//! room for a new one. Entries are Hashtable nodes which are also linked into an
+//! IntrusiveList ordered from most to least recently used, so lookups, inserts and
+//! evictions are O(1). An optional handler receives each evicted key and value.
+
+use core::mem::replace;
+use core::ops::FnMut;
+use core::ptr::{drop_in_place, read};
+use prelude::*;
+
+struct Entry<K: Hash + PartialEq, V> {
+	key: K,
+	value: V,
+	links: ListLinks<Node<Entry<K, V>>>,
+}
+
+impl<K: Hash + PartialEq, V> PartialEq for Entry<K, V> {
+	fn eq(&self, other: &Self) -> bool {
+		self.key == other.key
+	}
+}
+
+impl<K: Hash + PartialEq, V> Hash for Entry<K, V> {
+	fn hash(&self) -> usize {
+		self.key.hash()
+	}
+}
+
+impl<K: Hash + PartialEq, V> Linked for Node<Entry<K, V>> {
+	fn links(&self) -> &ListLinks<Self> {
+		&self.value.links
+	}
+	fn links_mut(&mut self) -> &mut ListLinks<Self> {
+		&mut self.value.links
+	}
+}
+
+pub struct LruCache<K: Hash + PartialEq, V> {
+	table: Hashtable<Entry<K, V>>,
+	// head is the most recently used entry, tail the next to be evicted
+	list: IntrusiveList<Node<Entry<K, V>>>,
+	capacity: usize,
+	evict_handler: Option<Box<dyn FnMut(K, V)>>,
+}
+
+impl<K: Hash + PartialEq, V> Drop for LruCache<K, V> {
+	fn drop(&mut self) {
+		self.release_all();
+	}
+}
+
+impl<K: Hash + PartialEq, V> LruCache<K, V> {
+	/// IllegalArgument if capacity is 0
+	pub fn new(capacity: usize) -> Result<Self, Error> {
+		if capacity == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		// the table never holds more than capacity entries so it is sized once
+		match Hashtable::with_policy(capacity, RehashPolicy::fixed()) {
+			Ok(table) => Ok(Self {
+				table,
+				list: IntrusiveList::new(),
+				capacity,
+				evict_handler: None,
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Called with the key and value of each entry evicted by insert. Entries which are
+	/// removed, cleared or dropped with the cache are not passed to it.
+	pub fn set_evict_handler(&mut self, handler: Box<dyn FnMut(K, V)>) {
+		self.evict_handler = Some(handler);
+	}
+
+	/// Inserts value for key as the most recently used entry, returning the value it
+	/// replaced if key was already present. Otherwise, if the cache is full, the least
+	/// recently used entry is evicted first.
+	pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Error> {
+		match self.table.find_hashed(key.hash(), |e| e.key == key) {
+			Some(mut node) => {
+				self.touch(node);
+				return Ok(Some(replace(&mut node.value.value, value)));
+			}
+			None => {}
+		}
+		// allocate before evicting so that a failed insert leaves the cache unchanged
+		let node = match Ptr::alloc(Node::new(Entry {
+			key,
+			value,
+			links: ListLinks::new(),
+		})) {
+			Ok(node) => node,
+			Err(e) => return Err(e),
+		};
+		if self.list.len() == self.capacity {
+			self.evict();
+		}
+		self.table.insert(node);
+		self.list.push_front(node);
+		Ok(None)
+	}
+
+	/// The value for key, marking it as the most recently used entry
+	pub fn get(&mut self, key: &K) -> Option<&V> {
+		match self.table.find_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => {
+				self.touch(node);
+				Some(unsafe { &(*node.raw()).value.value })
+			}
+			None => None,
+		}
+	}
+
+	pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+		match self.table.find_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => {
+				self.touch(node);
+				Some(unsafe { &mut (*node.raw()).value.value })
+			}
+			None => None,
+		}
+	}
+
+	/// The value for key without changing the eviction order
+	pub fn peek(&self, key: &K) -> Option<&V> {
+		match self.table.find_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => Some(unsafe { &(*node.raw()).value.value }),
+			None => None,
+		}
+	}
+
+	pub fn contains_key(&self, key: &K) -> bool {
+		self.peek(key).is_some()
+	}
+
+	/// Removes key, returning its value if it was present
+	pub fn remove(&mut self, key: &K) -> Option<V> {
+		match self.table.remove_hashed(key.hash(), |e| e.key == *key) {
+			Some(node) => {
+				self.list.remove(node);
+				let entry = unsafe { read(&(*node.raw()).value) };
+				node.release();
+				Some(entry.value)
+			}
+			None => None,
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.list.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.list.is_empty()
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.capacity
+	}
+
+	pub fn clear(&mut self) {
+		self.release_all();
+		self.table.clear();
+		self.list = IntrusiveList::new();
+	}
+
+	fn touch(&mut self, node: Ptr<Node<Entry<K, V>>>) {
+		if self.list.head() != node {
+			self.list.remove(node);
+			self.list.push_front(node);
+		}
+	}
+
+	fn evict(&mut self) {
+		let node = self.list.tail();
+		self.list.remove(node);
+		let hash = node.value.key.hash();
+		let _ = self
+			.table
+			.remove_hashed(hash, |e| e as *const Entry<K, V> == &node.value as *const _);
+		let entry = unsafe { read(&(*node.raw()).value) };
+		node.release();
+		match &mut self.evict_handler {
+			Some(handler) => handler(entry.key, entry.value),
+			None => {}
+		}
+	}
+
+	// the list iterator reads an element's successor before returning it, so each node
+	// can be released as soon as it is returned
+	fn release_all(&mut self) {
+		for node in self.list.iter() {
+			unsafe {
+				drop_in_place(node.raw());
+			}
+			node.release();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_lru() {
+		let _alloc = AllocGuard::new();
+		assert!(LruCache::<u64, u64>::new(0).unwrap_err().kind == IllegalArgument);
+
+		let evicted = Rc::new((0u64, 0u64)).unwrap();
+		let mut evicted_clone = evicted.clone().unwrap();
+		let mut cache = LruCache::new(3).unwrap();
+		cache.set_evict_handler(
+			Box::new(move |k: u64, v: u64| {
+				evicted_clone.0 += 1;
+				evicted_clone.1 = k * 1_000 + v;
+			})
+			.unwrap(),
+		);
+		assert!(cache.is_empty());
+		assert_eq!(cache.capacity(), 3);
+		for i in 0..3u64 {
+			assert!(cache.insert(i, i * 10).unwrap().is_none());
+		}
+		assert_eq!(cache.len(), 3);
+		assert_eq!(evicted.0, 0);
+
+		// 0 becomes the most recently used so 1 is evicted
+		assert_eq!(*cache.get(&0).unwrap(), 0);
+		assert!(cache.insert(3, 30).unwrap().is_none());
+		assert_eq!(cache.len(), 3);
+		assert_eq!(evicted.0, 1);
+		assert_eq!(evicted.1, 1_010);
+		assert!(!cache.contains_key(&1));
+
+		// peek does not protect 2, replacing a value does protect 0
+		assert_eq!(*cache.peek(&2).unwrap(), 20);
+		assert!(cache.insert(0, 5).unwrap() == Some(0));
+		assert_eq!(evicted.0, 1);
+		assert!(cache.insert(4, 40).unwrap().is_none());
+		assert_eq!(evicted.1, 2_020);
+		assert!(cache.contains_key(&0) && cache.contains_key(&3) && cache.contains_key(&4));
+
+		*cache.get_mut(&3).unwrap() += 1;
+		assert!(cache.remove(&3) == Some(31));
+		assert!(cache.remove(&3).is_none());
+		assert_eq!(cache.len(), 2);
+		assert!(cache.insert(5, 50).unwrap().is_none());
+		assert_eq!(evicted.0, 2);
+
+		cache.clear();
+		assert!(cache.is_empty());
+		assert!(cache.get(&0).is_none());
+		assert_eq!(evicted.0, 2);
+		for i in 0..10u64 {
+			assert!(cache.insert(i, i).unwrap().is_none());
+		}
+		assert_eq!(cache.len(), 3);
+		assert_eq!(evicted.0, 9);
+		assert_eq!(evicted.1, 6_006);
+	}
+
+	#[test]
+	fn test_lru_drop() {
+		let _alloc = AllocGuard::new();
+		let mut cache = LruCache::new(2).unwrap();
+		for i in 0..5u64 {
+			let mut v = Vec::new();
+			v.push(i).unwrap();
+			assert!(cache.insert(i, v).unwrap().is_none());
+		}
+		assert_eq!(cache.peek(&4).unwrap()[0], 4);
+		assert!(cache.peek(&2).is_none());
+	}
+}
@@ -1,3 +1,18 @@
+pub mod arena;
+pub mod bloom;
+pub mod bufpool;
+pub mod hashmap;
+pub mod hashset;
 pub mod hashtable;
+pub mod list;
+pub mod lru;
+pub mod mpmc;
+pub mod orderedmap;
+pub mod pool;
+#[cfg(test)]
+pub mod proptest;
 pub mod rbtree;
+pub mod rope;
 pub mod runtime;
+#[cfg(test)]
+mod stress;
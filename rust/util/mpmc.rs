@@ -0,0 +1,253 @@
+//! # MpmcQueue
+//! A fixed capacity lock-free queue for any number of producers and consumers (Vyukov's
+//! bounded queue). Each slot carries a sequence number which tells a producer whether the
+//! slot is free for its lap of the ring and a consumer whether it has been filled, so the
+//! only contended operations are one compare and swap on the head or tail position.
+//! Nothing blocks: push hands the value back when the queue is full and pop returns None
+//! when it is empty. Share a queue between threads by wrapping it in an Arc.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ptr::{read, write};
+use prelude::*;
+
+struct Slot<T> {
+	seq: UnsafeCell<u64>,
+	value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// the positions are kept on separate cache lines so that producers and consumers do not
+// invalidate each other's line on every operation
+#[repr(C)]
+pub struct MpmcQueue<T> {
+	slots: Vec<Slot<T>>,
+	mask: u64,
+	_pad0: [u64; 8],
+	enqueue_pos: UnsafeCell<u64>,
+	_pad1: [u64; 8],
+	dequeue_pos: UnsafeCell<u64>,
+	_pad2: [u64; 8],
+}
+
+// a slot's value is only reached by the thread which won it with the compare and swap
+unsafe impl<T: Send> Send for MpmcQueue<T> {}
+unsafe impl<T: Send> Sync for MpmcQueue<T> {}
+
+impl<T> Drop for MpmcQueue<T> {
+	fn drop(&mut self) {
+		while self.pop().is_some() {}
+	}
+}
+
+impl<T> MpmcQueue<T> {
+	/// Creates a queue holding at least capacity values. The capacity is rounded up to a
+	/// power of two.
+	pub fn new(capacity: usize) -> Result<Self, Error> {
+		if capacity == 0 || capacity > 1 << 32 {
+			return Err(err!(IllegalArgument));
+		}
+		let capacity = capacity.next_power_of_two();
+		let mut slots = Vec::new();
+		match slots.resize(capacity) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		for i in 0..capacity {
+			slots[i] = Slot {
+				seq: (i as u64).into(),
+				value: MaybeUninit::uninit().into(),
+			};
+		}
+		Ok(Self {
+			slots,
+			mask: capacity as u64 - 1,
+			_pad0: [0; 8],
+			enqueue_pos: 0_u64.into(),
+			_pad1: [0; 8],
+			dequeue_pos: 0_u64.into(),
+			_pad2: [0; 8],
+		})
+	}
+
+	/// Appends value, handing it back if the queue is full
+	pub fn push(&self, value: T) -> Result<(), T> {
+		let enqueue_pos = unsafe { &mut *self.enqueue_pos.get() };
+		loop {
+			let pos = aload!(enqueue_pos);
+			let slot = &self.slots[(pos & self.mask) as usize];
+			let seq = unsafe { &mut *slot.seq.get() };
+			let s = aload!(seq);
+			if s == pos {
+				if cas!(enqueue_pos, &pos, pos + 1) {
+					unsafe {
+						write((*slot.value.get()).as_mut_ptr(), value);
+					}
+					astore!(seq, pos + 1);
+					return Ok(());
+				}
+			} else if s < pos {
+				// the value written a lap ago has not been taken yet
+				return Err(value);
+			}
+			sched_yield!();
+		}
+	}
+
+	/// Removes the oldest value, or returns None if the queue is empty
+	pub fn pop(&self) -> Option<T> {
+		let dequeue_pos = unsafe { &mut *self.dequeue_pos.get() };
+		loop {
+			let pos = aload!(dequeue_pos);
+			let slot = &self.slots[(pos & self.mask) as usize];
+			let seq = unsafe { &mut *slot.seq.get() };
+			let s = aload!(seq);
+			if s == pos + 1 {
+				if cas!(dequeue_pos, &pos, pos + 1) {
+					let value = unsafe { read((*slot.value.get()).as_ptr()) };
+					// free the slot for the producer one lap ahead
+					astore!(seq, pos + self.mask + 1);
+					return Some(value);
+				}
+			} else if s < pos + 1 {
+				return None;
+			}
+			sched_yield!();
+		}
+	}
+
+	pub fn capacity(&self) -> usize {
+		self.slots.len()
+	}
+
+	/// Number of values in the queue. Only a snapshot while other threads are active.
+	pub fn len(&self) -> usize {
+		let dequeue = aload!(self.dequeue_pos.get());
+		let enqueue = aload!(self.enqueue_pos.get());
+		if enqueue > dequeue {
+			(enqueue - dequeue) as usize
+		} else {
+			0
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	struct DropCounter {
+		drops: Rc<u64>,
+	}
+
+	impl Drop for DropCounter {
+		fn drop(&mut self) {
+			aadd!(&mut *self.drops, 1);
+		}
+	}
+
+	#[test]
+	fn test_mpmc_queue() {
+		let _alloc = AllocGuard::new();
+		assert!(MpmcQueue::<u64>::new(0).is_err());
+		let q = MpmcQueue::new(6).unwrap();
+		assert_eq!(q.capacity(), 8);
+		assert!(q.is_empty());
+		assert!(q.pop().is_none());
+
+		// several laps of the ring, filling it each time
+		for lap in 0..3u64 {
+			for i in 0..8 {
+				assert!(q.push(lap * 8 + i).is_ok());
+			}
+			assert_eq!(q.len(), 8);
+			match q.push(100) {
+				Err(v) => assert_eq!(v, 100),
+				Ok(_) => panic!("pushed to a full queue"),
+			}
+			for i in 0..8 {
+				assert!(q.pop() == Some(lap * 8 + i));
+			}
+			assert!(q.pop().is_none());
+		}
+
+		// values still queued are dropped with the queue
+		let drops = Rc::new(0u64).unwrap();
+		{
+			let q = MpmcQueue::new(4).unwrap();
+			for _i in 0..3 {
+				let v = DropCounter {
+					drops: drops.clone().unwrap(),
+				};
+				match q.push(v) {
+					Ok(_) => {}
+					Err(_v) => panic!("could not push to a queue with room"),
+				}
+			}
+			let _v = q.pop();
+			assert_eq!(*drops, 0);
+		}
+		assert_eq!(*drops, 3);
+	}
+
+	#[test]
+	fn test_mpmc_queue_threads() {
+		let _alloc = AllocGuard::new();
+		let threads = 4u64;
+		let iterations = 10_000u64;
+		let q = Arc::new(MpmcQueue::new(64).unwrap()).unwrap();
+		let sum = Arc::new(AtomicU64::new(0)).unwrap();
+		let count = Arc::new(AtomicU64::new(0)).unwrap();
+		let mut jhs = Vec::new();
+		for t in 0..threads {
+			let q = q.clone().unwrap();
+			jhs.push(
+				spawnj(move || {
+					for i in 0..iterations {
+						let mut v = t * iterations + i;
+						loop {
+							match q.push(v) {
+								Ok(_) => break,
+								Err(back) => v = back,
+							}
+							sched_yield!();
+						}
+					}
+				})
+				.unwrap(),
+			)
+			.unwrap();
+		}
+		let total = threads * iterations;
+		for _t in 0..threads {
+			let q = q.clone().unwrap();
+			let sum = sum.clone().unwrap();
+			let count = count.clone().unwrap();
+			jhs.push(
+				spawnj(move || loop {
+					if count.load() >= total {
+						break;
+					}
+					match q.pop() {
+						Some(v) => {
+							sum.fetch_add(v);
+							count.fetch_add(1);
+						}
+						None => sched_yield!(),
+					}
+				})
+				.unwrap(),
+			)
+			.unwrap();
+		}
+		for i in 0..jhs.len() {
+			jhs[i].join().unwrap();
+		}
+		assert_eq!(count.load(), total);
+		assert_eq!(sum.load(), total * (total - 1) / 2);
+		assert!(q.is_empty());
+	}
+}
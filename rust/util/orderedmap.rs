@@ -0,0 +1,352 @@
+//! # OrderedMap
+//! An owning map kept in key order, built on the intrusive RbTree. Nodes are allocated on
+//! insert and released on remove, clear or drop so callers never write search closures or
+//! handle nodes directly.
+
+use core::iter::{IntoIterator, Iterator};
+use core::marker::PhantomData;
+use core::mem::replace;
+use core::option::Option as CoreOption;
+use core::ptr::{drop_in_place, read};
+use prelude::*;
+
+struct Entry<K: Ord, V> {
+	key: K,
+	value: V,
+}
+
+impl<K: Ord, V> Ord for Entry<K, V> {
+	fn compare(&self, other: &Self) -> i8 {
+		self.key.compare(&other.key)
+	}
+}
+
+type EntryNode<K, V> = Ptr<RbTreeNode<Entry<K, V>>>;
+
+pub struct OrderedMap<K: Ord, V> {
+	tree: RbTree<Entry<K, V>>,
+	len: usize,
+}
+
+pub struct OrderedMapIter<'a, K: Ord, V> {
+	inner: RbTreeIterator<'a, Entry<K, V>>,
+}
+
+pub struct OrderedMapIterMut<'a, K: Ord, V> {
+	inner: RbTreeIterator<'a, Entry<K, V>>,
+	_marker: PhantomData<&'a mut V>,
+}
+
+// locates key, or the position at which it would be inserted
+fn find<K: Ord, V>(base: EntryNode<K, V>, key: &K) -> RbNodePair<Entry<K, V>> {
+	let mut is_right = false;
+	let mut cur = base;
+	let mut parent = Ptr::null();
+
+	while !cur.is_null() {
+		let cmp = key.compare(&cur.value.key);
+		if cmp == 0 {
+			break;
+		}
+		parent = cur;
+		is_right = cmp > 0;
+		cur = if is_right { cur.right } else { cur.left };
+	}
+
+	RbNodePair {
+		cur,
+		parent,
+		is_right,
+	}
+}
+
+impl<'a, K: Ord, V> Iterator for OrderedMapIter<'a, K, V> {
+	type Item = (&'a K, &'a V);
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		match self.inner.next() {
+			CoreOption::Some(node) => {
+				let entry = unsafe { &(*node.raw()).value };
+				CoreOption::Some((&entry.key, &entry.value))
+			}
+			CoreOption::None => CoreOption::None,
+		}
+	}
+}
+
+impl<'a, K: Ord, V> Iterator for OrderedMapIterMut<'a, K, V> {
+	type Item = (&'a K, &'a mut V);
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		match self.inner.next() {
+			CoreOption::Some(node) => {
+				let entry = unsafe { &mut (*node.raw()).value };
+				CoreOption::Some((&entry.key, &mut entry.value))
+			}
+			CoreOption::None => CoreOption::None,
+		}
+	}
+}
+
+impl<K: Ord, V> Drop for OrderedMap<K, V> {
+	fn drop(&mut self) {
+		Self::release_all(self.tree.root());
+	}
+}
+
+impl<K: Ord, V> OrderedMap<K, V> {
+	pub fn new() -> Self {
+		Self {
+			tree: RbTree::new(),
+			len: 0,
+		}
+	}
+
+	/// Inserts value for key, returning the value it replaced if key was already present
+	pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, Error> {
+		let mut pair = find(self.tree.root(), &key);
+		if !pair.cur.is_null() {
+			return Ok(Some(replace(&mut pair.cur.value.value, value)));
+		}
+		let node = match Ptr::alloc(RbTreeNode::new(Entry { key, value })) {
+			Ok(node) => node,
+			Err(e) => return Err(e),
+		};
+		// the key was not found above so nothing is replaced
+		let _ = self.tree.insert(node, &mut |base, n: EntryNode<K, V>| {
+			find(base, &n.value.key)
+		});
+		self.len += 1;
+		Ok(None)
+	}
+
+	pub fn get(&self, key: &K) -> Option<&V> {
+		let pair = find(self.tree.root(), key);
+		if pair.cur.is_null() {
+			None
+		} else {
+			Some(unsafe { &(*pair.cur.raw()).value.value })
+		}
+	}
+
+	pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+		let pair = find(self.tree.root(), key);
+		if pair.cur.is_null() {
+			None
+		} else {
+			Some(unsafe { &mut (*pair.cur.raw()).value.value })
+		}
+	}
+
+	pub fn contains_key(&self, key: &K) -> bool {
+		self.get(key).is_some()
+	}
+
+	/// Removes key, returning its value if it was present
+	pub fn remove(&mut self, key: &K) -> Option<V> {
+		match self
+			.tree
+			.remove(Ptr::null(), &mut |base, _n| find(base, key))
+		{
+			Some(node) => {
+				self.len -= 1;
+				let entry = unsafe { read(&(*node.raw()).value) };
+				node.release();
+				Some(entry.value)
+			}
+			None => None,
+		}
+	}
+
+	/// Entry with the smallest key
+	pub fn first(&self) -> Option<(&K, &V)> {
+		let node = self.tree.min();
+		if node.is_null() {
+			None
+		} else {
+			let entry = unsafe { &(*node.raw()).value };
+			Some((&entry.key, &entry.value))
+		}
+	}
+
+	/// Entry with the largest key
+	pub fn last(&self) -> Option<(&K, &V)> {
+		let node = self.tree.max();
+		if node.is_null() {
+			None
+		} else {
+			let entry = unsafe { &(*node.raw()).value };
+			Some((&entry.key, &entry.value))
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	pub fn clear(&mut self) {
+		Self::release_all(self.tree.root());
+		self.tree = RbTree::new();
+		self.len = 0;
+	}
+
+	/// Entries in ascending key order
+	pub fn iter(&self) -> OrderedMapIter<'_, K, V> {
+		OrderedMapIter {
+			inner: (&self.tree).into_iter(),
+		}
+	}
+
+	pub fn iter_mut(&mut self) -> OrderedMapIterMut<'_, K, V> {
+		OrderedMapIterMut {
+			inner: (&self.tree).into_iter(),
+			_marker: PhantomData,
+		}
+	}
+
+	/// Entries with keys in [from, to) in ascending order
+	pub fn range(&self, from: &K, to: &K) -> OrderedMapIter<'_, K, V> {
+		let end = self.tree.lower_bound(|e| e.key.compare(to));
+		let cur = if from.compare(to) < 0 {
+			self.tree.lower_bound(|e| e.key.compare(from))
+		} else {
+			end
+		};
+		OrderedMapIter {
+			inner: self.tree.range(cur, end),
+		}
+	}
+
+	fn release_all(node: EntryNode<K, V>) {
+		if node.is_null() {
+			return;
+		}
+		Self::release_all(node.left);
+		Self::release_all(node.right);
+		unsafe {
+			drop_in_place(node.raw());
+		}
+		node.release();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::murmur32::murmur3_32_of_u64;
+
+	struct DropCounter {
+		drops: Rc<u64>,
+	}
+
+	impl Drop for DropCounter {
+		fn drop(&mut self) {
+			aadd!(&mut *self.drops, 1);
+		}
+	}
+
+	#[test]
+	fn test_ordered_map() {
+		let _alloc = AllocGuard::new();
+		let mut map: OrderedMap<u64, u64> = OrderedMap::new();
+		assert!(map.is_empty());
+		assert!(map.first().is_none());
+		assert!(map.last().is_none());
+		assert!(map.iter().next().is_none());
+
+		// keys arrive in hash order but are iterated sorted
+		for i in 0..200u64 {
+			let k = murmur3_32_of_u64(i, 0x1234) as u64 % 1_000;
+			let _ = map.insert(k, k * 10).unwrap();
+		}
+		let mut prev = None;
+		let mut count = 0;
+		for (k, v) in map.iter() {
+			match prev {
+				Some(p) => assert!(*k > p),
+				None => {}
+			}
+			assert_eq!(*v, *k * 10);
+			prev = Some(*k);
+			count += 1;
+		}
+		assert_eq!(count, map.len());
+
+		map.clear();
+		assert!(map.is_empty());
+		for i in 0..100u64 {
+			assert!(map.insert(i * 2, i).unwrap().is_none());
+		}
+		assert!(map.insert(10, 50).unwrap() == Some(5));
+		assert_eq!(map.len(), 100);
+		assert_eq!(*map.get(&10).unwrap(), 50);
+		assert!(map.get(&11).is_none());
+		assert!(map.contains_key(&198));
+		*map.get_mut(&12).unwrap() += 1;
+		assert_eq!(*map.get(&12).unwrap(), 7);
+		assert!(map.first() == Some((&0, &0)));
+		assert!(map.last() == Some((&198, &99)));
+
+		// [from, to) with bounds between and on keys
+		let keys: Vec<u64> = {
+			let mut v = Vec::new();
+			for (k, _v) in map.range(&9, &17) {
+				v.push(*k).unwrap();
+			}
+			v
+		};
+		assert_eq!(keys.as_slice(), &[10, 12, 14, 16]);
+		assert_eq!(map.range(&10, &16).count(), 3);
+		assert_eq!(map.range(&190, &1_000).count(), 5);
+		assert_eq!(map.range(&16, &10).count(), 0);
+		assert_eq!(map.range(&11, &12).count(), 0);
+
+		assert!(map.remove(&0) == Some(0));
+		assert!(map.remove(&0).is_none());
+		assert!(map.remove(&198) == Some(99));
+		assert!(map.first() == Some((&2, &1)));
+		assert!(map.last() == Some((&196, &98)));
+		assert_eq!(map.len(), 98);
+
+		for (_k, v) in map.iter_mut() {
+			*v = 1;
+		}
+		assert_eq!(map.iter().map(|(_k, v)| *v).sum::<u64>(), 98);
+	}
+
+	#[test]
+	fn test_ordered_map_drop() {
+		let _alloc = AllocGuard::new();
+		let drops = Rc::new(0u64).unwrap();
+		{
+			let mut map = OrderedMap::new();
+			for i in 0..10i32 {
+				let v = DropCounter {
+					drops: drops.clone().unwrap(),
+				};
+				assert!(map.insert(i, v).unwrap().is_none());
+			}
+			// the replaced value is handed back and dropped by the caller
+			let v = DropCounter {
+				drops: drops.clone().unwrap(),
+			};
+			assert!(map.insert(0, v).unwrap().is_some());
+			assert_eq!(*drops, 1);
+			assert!(map.remove(&1).is_some());
+			assert_eq!(*drops, 2);
+			map.clear();
+			assert_eq!(*drops, 11);
+			for i in 0..5i32 {
+				let v = DropCounter {
+					drops: drops.clone().unwrap(),
+				};
+				assert!(map.insert(i, v).unwrap().is_none());
+			}
+		}
+		assert_eq!(*drops, 16);
+	}
+}
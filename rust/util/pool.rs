@@ -0,0 +1,299 @@
+//! # Pool
+//! A freelist of fixed size blocks for values which are allocated and released at a high
+//! rate, such as connections and channel messages. Released blocks are kept for reuse, up
+//! to max_free of them, instead of going back to the allocator.
+//!
+//! A Pool handle is cloned to share the pool. Every handle and every block which has not
+//! been released holds a reference, so the pool outlives the last of either. Pools made
+//! with new may be used from any thread. Pools made with local skip the lock and must
+//! only be used by the thread which owns them.
+
+use core::marker::{PhantomData, Sized};
+use core::mem::size_of;
+use core::ptr::{drop_in_place, read, write};
+use ffi::{alloc, release};
+use prelude::*;
+
+struct FreeBlock {
+	next: Ptr<FreeBlock>,
+}
+
+struct PoolInner {
+	lock: Lock,
+	shared: bool,
+	block_size: usize,
+	max_free: usize,
+	free: Ptr<FreeBlock>,
+	free_len: usize,
+	// handles plus blocks which have not been released
+	refs: u64,
+}
+
+pub struct Pool<T: ?Sized> {
+	inner: Ptr<PoolInner>,
+	_marker: PhantomData<T>,
+}
+
+// the counts are atomic and the freelist is locked, except in a local pool which the
+// documentation above keeps on one thread
+unsafe impl<T: ?Sized> Send for Pool<T> {}
+unsafe impl<T: ?Sized> Sync for Pool<T> {}
+
+impl<T: ?Sized> Clone for Pool<T> {
+	fn clone(&self) -> Result<Self, Error> {
+		aadd!(&mut (*self.inner.raw()).refs, 1);
+		Ok(Self {
+			inner: self.inner,
+			_marker: PhantomData,
+		})
+	}
+}
+
+impl<T: ?Sized> Drop for Pool<T> {
+	fn drop(&mut self) {
+		unref(self.inner);
+	}
+}
+
+impl<T: ?Sized> Pool<T> {
+	/// Returns a block from this pool whose value has already been dropped or moved out,
+	/// for owners such as Arc which drop the value before releasing the memory
+	///
+	/// # Safety
+	/// block must have been allocated by this pool, or a clone of it, and not used again.
+	pub unsafe fn recycle(&self, block: *mut u8) {
+		recycle(self.inner, block);
+	}
+}
+
+impl<T> Pool<T> {
+	/// A pool which may be shared between threads, keeping at most max_free released blocks
+	pub fn new(max_free: usize) -> Result<Self, Error> {
+		Self::with_lock(max_free, true)
+	}
+
+	/// A pool for a single thread, which does not lock
+	pub fn local(max_free: usize) -> Result<Self, Error> {
+		Self::with_lock(max_free, false)
+	}
+
+	fn with_lock(max_free: usize, shared: bool) -> Result<Self, Error> {
+		let block_size = if size_of::<T>() > size_of::<FreeBlock>() {
+			size_of::<T>()
+		} else {
+			size_of::<FreeBlock>()
+		};
+		match Ptr::alloc(PoolInner {
+			lock: Lock::new(),
+			shared,
+			block_size,
+			max_free,
+			free: Ptr::null(),
+			free_len: 0,
+			refs: 1,
+		}) {
+			Ok(inner) => Ok(Self {
+				inner,
+				_marker: PhantomData,
+			}),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Fills the freelist up to n blocks, or max_free if that is smaller, so that the
+	/// first allocations do not reach the allocator either
+	pub fn reserve(&self, n: usize) -> Result<(), Error> {
+		let mut inner = self.inner;
+		// the guard borrows its own copy of the pointer so that inner can be changed
+		let state = inner;
+		let _l = if state.shared {
+			Some(state.lock.write())
+		} else {
+			None
+		};
+		while inner.free_len < n && inner.free_len < inner.max_free {
+			let block = unsafe { alloc(inner.block_size) } as *mut FreeBlock;
+			if block.is_null() {
+				return Err(err!(Alloc));
+			}
+			unsafe {
+				write(block, FreeBlock { next: inner.free });
+			}
+			inner.free = Ptr::new(block);
+			inner.free_len += 1;
+		}
+		Ok(())
+	}
+
+	/// Moves value into a block from the freelist, or a new one if the freelist is empty.
+	/// The block must be given back with release or take.
+	pub fn alloc(&self, value: T) -> Result<Ptr<T>, Error> {
+		let mut inner = self.inner;
+		let block = {
+			let state = inner;
+			let _l = if state.shared {
+				Some(state.lock.write())
+			} else {
+				None
+			};
+			let block = inner.free;
+			if !block.is_null() {
+				inner.free = block.next;
+				inner.free_len -= 1;
+			}
+			block.raw() as *mut T
+		};
+		let block = if block.is_null() {
+			let block = unsafe { alloc(inner.block_size) } as *mut T;
+			if block.is_null() {
+				return Err(err!(Alloc));
+			}
+			block
+		} else {
+			block
+		};
+		unsafe {
+			write(block, value);
+		}
+		aadd!(&mut inner.refs, 1);
+		Ok(Ptr::new(block))
+	}
+
+	/// Drops the value of a block from this pool and returns the block
+	pub fn release(&self, ptr: Ptr<T>) {
+		unsafe {
+			drop_in_place(ptr.raw());
+		}
+		recycle(self.inner, ptr.raw() as *mut u8);
+	}
+
+	/// Moves the value out of a block from this pool and returns the block
+	pub fn take(&self, ptr: Ptr<T>) -> T {
+		let value = unsafe { read(ptr.raw()) };
+		recycle(self.inner, ptr.raw() as *mut u8);
+		value
+	}
+
+	/// Number of released blocks waiting to be reused
+	pub fn free_len(&self) -> usize {
+		let inner = self.inner;
+		let _l = if inner.shared {
+			Some(inner.lock.read())
+		} else {
+			None
+		};
+		inner.free_len
+	}
+}
+
+// returns a block to the pool, or to the allocator if the freelist is full, and drops the
+// block's reference
+fn recycle(mut inner: Ptr<PoolInner>, block: *mut u8) {
+	let kept = {
+		let state = inner;
+		let _l = if state.shared {
+			Some(state.lock.write())
+		} else {
+			None
+		};
+		if inner.free_len < inner.max_free {
+			unsafe {
+				write(block as *mut FreeBlock, FreeBlock { next: inner.free });
+			}
+			inner.free = Ptr::new(block as *mut FreeBlock);
+			inner.free_len += 1;
+			true
+		} else {
+			false
+		}
+	};
+	if !kept {
+		unsafe {
+			release(block);
+		}
+	}
+	unref(inner);
+}
+
+fn unref(mut inner: Ptr<PoolInner>) {
+	if asub!(&mut inner.refs, 1) == 1 {
+		let mut block = inner.free;
+		while !block.is_null() {
+			let next = block.next;
+			block.release();
+			block = next;
+		}
+		unsafe {
+			drop_in_place(inner.raw());
+		}
+		inner.release();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+
+	struct DropCounter {
+		drops: Rc<u64>,
+	}
+
+	impl Drop for DropCounter {
+		fn drop(&mut self) {
+			aadd!(&mut *self.drops, 1);
+		}
+	}
+
+	#[test]
+	fn test_pool() {
+		let _alloc = AllocGuard::new();
+		let pool = Pool::local(2).unwrap();
+		pool.reserve(5).unwrap();
+		assert_eq!(pool.free_len(), 2);
+
+		let a = pool.alloc(1u64).unwrap();
+		let b = pool.alloc(2u64).unwrap();
+		let c = pool.alloc(3u64).unwrap();
+		assert_eq!(pool.free_len(), 0);
+		assert_eq!(*a + *b + *c, 6);
+
+		// the most recently released block is reused first
+		assert_eq!(pool.take(b), 2);
+		let d = pool.alloc(4u64).unwrap();
+		assert!(d == b);
+		pool.release(a);
+		pool.release(c);
+		pool.release(d);
+		assert_eq!(pool.free_len(), 2);
+	}
+
+	#[test]
+	fn test_pool_refs() {
+		let _alloc = AllocGuard::new();
+		let drops = Rc::new(0u64).unwrap();
+		let pool = Pool::new(8).unwrap();
+		let mut blocks = Vec::new();
+		for _ in 0..4 {
+			let v = DropCounter {
+				drops: drops.clone().unwrap(),
+			};
+			blocks.push(pool.alloc(v).unwrap()).unwrap();
+		}
+		let pool2 = pool.clone().unwrap();
+		pool.release(blocks[0]);
+		assert_eq!(*drops, 1);
+		let v = pool2.take(blocks[1]);
+		assert_eq!(*drops, 1);
+		drop(v);
+		assert_eq!(*drops, 2);
+		assert_eq!(pool2.free_len(), 2);
+
+		// either handle returns blocks to the same freelist
+		drop(pool);
+		pool2.release(blocks[2]);
+		pool2.release(blocks[3]);
+		assert_eq!(*drops, 4);
+		assert_eq!(pool2.free_len(), 4);
+	}
+}
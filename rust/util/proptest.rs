@@ -0,0 +1,290 @@
+//! # Property-based testing
+//! A minimal property testing helper. Inputs are generated from a seeded PRNG and checked
+//! against a property; when the property fails the input is shrunk to a smaller input which
+//! still fails and the test panics with the seed needed to reproduce the run.
+//!
+//! Properties return false (rather than asserting) so that failures can be shrunk. A
+//! failure in an `assert!` inside a property is still reported, just not minimized.
+
+use core::marker::{Copy, Sized};
+use core::mem::size_of;
+use core::ops::Fn;
+use ffi::rand_bytes;
+use prelude::*;
+
+/// splitmix64, small and fast with good statistical properties for test inputs
+pub struct Rng {
+	state: u64,
+}
+
+impl Rng {
+	pub fn new(seed: u64) -> Self {
+		Self { state: seed }
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+		z ^ (z >> 31)
+	}
+
+	/// Returns a value in [0, n). n must be greater than 0.
+	pub fn below(&mut self, n: u64) -> u64 {
+		self.next_u64() % n
+	}
+}
+
+#[derive(Clone, Copy)]
+pub struct PropConfig {
+	/// Number of random inputs to check
+	pub cases: u64,
+	/// Seed of the PRNG. 0 selects a random seed which is reported on failure.
+	pub seed: u64,
+	/// Maximum length of generated vectors
+	pub max_len: usize,
+}
+
+impl Default for PropConfig {
+	fn default() -> Self {
+		Self {
+			cases: 100,
+			seed: 0,
+			max_len: 64,
+		}
+	}
+}
+
+pub trait Arbitrary: Sized {
+	fn arbitrary(rng: &mut Rng, max_len: usize) -> Result<Self, Error>;
+	/// Returns smaller variants of this value, most aggressive first
+	fn shrink(&self) -> Result<Vec<Self>, Error>;
+	fn print(&self);
+}
+
+impl Arbitrary for u8 {
+	fn arbitrary(rng: &mut Rng, _max_len: usize) -> Result<Self, Error> {
+		Ok(rng.next_u64() as u8)
+	}
+
+	fn shrink(&self) -> Result<Vec<Self>, Error> {
+		shrink_integer(*self as u64, |v| v as u8)
+	}
+
+	fn print(&self) {
+		print!("{}", *self);
+	}
+}
+
+impl Arbitrary for u64 {
+	/// Biased towards small values and values around powers of two, which is where
+	/// length and capacity edge cases tend to be.
+	fn arbitrary(rng: &mut Rng, _max_len: usize) -> Result<Self, Error> {
+		Ok(match rng.below(4) {
+			0 => rng.below(256),
+			1 => {
+				let base = 1u64 << rng.below(64);
+				base.wrapping_add(rng.below(3)).wrapping_sub(1)
+			}
+			_ => rng.next_u64(),
+		})
+	}
+
+	fn shrink(&self) -> Result<Vec<Self>, Error> {
+		shrink_integer(*self, |v| v)
+	}
+
+	fn print(&self) {
+		print!("{}", *self);
+	}
+}
+
+impl<T: Arbitrary + Copy + Display> Arbitrary for Vec<T> {
+	fn arbitrary(rng: &mut Rng, max_len: usize) -> Result<Self, Error> {
+		let len = rng.below(max_len as u64 + 1) as usize;
+		let mut ret = Vec::new();
+		for _i in 0..len {
+			match T::arbitrary(rng, max_len) {
+				Ok(v) => match ret.push(v) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				},
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(ret)
+	}
+
+	fn shrink(&self) -> Result<Vec<Self>, Error> {
+		let len = self.len();
+		let mut ret = Vec::new();
+		if len == 0 {
+			return Ok(ret);
+		}
+		// drop the back half, the front half, then each single element
+		let mut ranges = Vec::new();
+		if len > 1 {
+			let res = ranges.push((len / 2, len));
+			if res.is_err() || ranges.push((0, len / 2)).is_err() {
+				return Err(err!(Alloc));
+			}
+		}
+		for i in 0..len {
+			match ranges.push((i, i + 1)) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		for (start, end) in &ranges {
+			let mut v = Vec::new();
+			for i in 0..len {
+				if (i < *start || i >= *end) && v.push(self[i]).is_err() {
+					return Err(err!(Alloc));
+				}
+			}
+			match ret.push(v) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		// then simplify each element in place
+		for i in 0..len {
+			let candidates = match self[i].shrink() {
+				Ok(candidates) => candidates,
+				Err(e) => return Err(e),
+			};
+			for c in &candidates {
+				let mut v = match copy_vec(self) {
+					Ok(v) => v,
+					Err(e) => return Err(e),
+				};
+				v[i] = *c;
+				match ret.push(v) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+			}
+		}
+		Ok(ret)
+	}
+
+	fn print(&self) {
+		print!("{}", &self[0..self.len()]);
+	}
+}
+
+/// Checks that prop holds for config.cases random inputs. On failure the input is shrunk
+/// and the test panics with the seed and the minimal failing input.
+pub fn check<T: Arbitrary, F: FnMut(&T) -> bool>(config: PropConfig, mut prop: F) {
+	let mut seed = config.seed;
+	while seed == 0 {
+		unsafe {
+			rand_bytes(&mut seed as *mut u64 as *mut u8, size_of::<u64>());
+		}
+	}
+	let mut rng = Rng::new(seed);
+	for case in 0..config.cases {
+		let input = T::arbitrary(&mut rng, config.max_len).unwrap();
+		if !prop(&input) {
+			let (minimal, steps) = minimize(input, &mut prop);
+			print!("proptest: minimal failing input after {} shrinks: ", steps);
+			minimal.print();
+			println!("");
+			panic!("property failed (seed={}, case={})", seed, case);
+		}
+	}
+}
+
+fn minimize<T: Arbitrary, F: FnMut(&T) -> bool>(mut input: T, prop: &mut F) -> (T, u64) {
+	let mut steps = 0;
+	'outer: loop {
+		let candidates = input.shrink().unwrap();
+		for c in candidates {
+			if !prop(&c) {
+				input = c;
+				steps += 1;
+				continue 'outer;
+			}
+		}
+		return (input, steps);
+	}
+}
+
+fn shrink_integer<T, F: Fn(u64) -> T>(v: u64, conv: F) -> Result<Vec<T>, Error> {
+	let mut ret = Vec::new();
+	if v == 0 {
+		return Ok(ret);
+	}
+	let half = v / 2;
+	let res = ret.push(conv(0));
+	if res.is_err()
+		|| (half > 0 && ret.push(conv(half)).is_err())
+		|| (v - 1 > half && ret.push(conv(v - 1)).is_err())
+	{
+		return Err(err!(Alloc));
+	}
+	Ok(ret)
+}
+
+/// Element-wise copy of a vector (Vec::clone does not copy the elements)
+pub fn copy_vec<T: Copy>(v: &Vec<T>) -> Result<Vec<T>, Error> {
+	let mut ret = Vec::new();
+	for x in v {
+		match ret.push(*x) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(ret)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_rng_deterministic() {
+		let mut r1 = Rng::new(1234);
+		let mut r2 = Rng::new(1234);
+		for _i in 0..100 {
+			assert_eq!(r1.next_u64(), r2.next_u64());
+			assert!(r1.below(10) < 10);
+			r2.below(10);
+		}
+		assert!(Rng::new(1).next_u64() != Rng::new(2).next_u64());
+	}
+
+	#[test]
+	fn test_shrink_minimal() {
+		let _alloc = AllocGuard::new();
+		// shrinks to the smallest value over the threshold
+		let (v, _) = minimize(1_000_000u64, &mut |v: &u64| *v < 1000);
+		assert_eq!(v, 1000);
+
+		// shrinks to a single element vector holding the smallest failing element
+		let input = vec![3u8, 200, 7, 150, 9].unwrap();
+		let (v, _) = minimize(input, &mut |v: &Vec<u8>| {
+			for x in v {
+				if *x >= 100 {
+					return false;
+				}
+			}
+			true
+		});
+		assert_eq!(v.len(), 1);
+		assert_eq!(v[0], 100);
+	}
+
+	#[test]
+	fn test_check_passes() {
+		let _alloc = AllocGuard::new();
+		check(PropConfig::default(), |v: &Vec<u64>| v.len() <= 64);
+	}
+
+	#[test]
+	#[should_panic]
+	fn test_check_fails() {
+		check(PropConfig::default(), |v: &Vec<u8>| v.len() < 10);
+	}
+}
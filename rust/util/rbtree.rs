@@ -1,5 +1,8 @@
 use core::clone::Clone;
+use core::iter::{IntoIterator, Iterator};
+use core::marker::PhantomData;
 use core::ops::FnMut;
+use core::option::Option as CoreOption;
 use core::ptr::null_mut;
 use prelude::*;
 
@@ -9,7 +12,7 @@ pub struct RbNodePair<V: Ord> {
 	pub is_right: bool,
 }
 
-type RbTreeSearch<V> = dyn FnMut(Ptr<RbTreeNode<V>>, Ptr<RbTreeNode<V>>) -> RbNodePair<V>;
+type RbTreeSearch<'a, V> = dyn FnMut(Ptr<RbTreeNode<V>>, Ptr<RbTreeNode<V>>) -> RbNodePair<V> + 'a;
 
 pub struct RbTreeNode<V: Ord> {
 	pub parent: Ptr<RbTreeNode<V>>,
@@ -92,6 +95,36 @@ pub struct RbTree<V: Ord> {
 	root: Ptr<RbTreeNode<V>>,
 }
 
+/// In-order iterator over the nodes of a tree. end is the first node not returned, null to
+/// run past the largest node.
+pub struct RbTreeIterator<'a, V: Ord> {
+	cur: Ptr<RbTreeNode<V>>,
+	end: Ptr<RbTreeNode<V>>,
+	_marker: PhantomData<&'a RbTree<V>>,
+}
+
+impl<'a, V: Ord> Iterator for RbTreeIterator<'a, V> {
+	type Item = Ptr<RbTreeNode<V>>;
+
+	fn next(&mut self) -> CoreOption<Self::Item> {
+		if self.cur.is_null() || self.cur == self.end {
+			return CoreOption::None;
+		}
+		let ret = self.cur;
+		self.cur = RbTree::successor(ret);
+		CoreOption::Some(ret)
+	}
+}
+
+impl<'a, V: Ord> IntoIterator for &'a RbTree<V> {
+	type Item = Ptr<RbTreeNode<V>>;
+	type IntoIter = RbTreeIterator<'a, V>;
+
+	fn into_iter(self) -> Self::IntoIter {
+		self.range(self.min(), Ptr::null())
+	}
+}
+
 impl<V: Ord> RbTree<V> {
 	pub fn new() -> Self {
 		Self { root: Ptr::null() }
@@ -101,10 +134,95 @@ impl<V: Ord> RbTree<V> {
 		self.root
 	}
 
+	/// Node with the smallest value, null if the tree is empty
+	pub fn min(&self) -> Ptr<RbTreeNode<V>> {
+		Self::leftmost(self.root)
+	}
+
+	/// Node with the largest value, null if the tree is empty
+	pub fn max(&self) -> Ptr<RbTreeNode<V>> {
+		Self::rightmost(self.root)
+	}
+
+	/// Next node in order, null if node holds the largest value
+	pub fn successor(mut node: Ptr<RbTreeNode<V>>) -> Ptr<RbTreeNode<V>> {
+		if !node.right.is_null() {
+			return Self::leftmost(node.right);
+		}
+		// parent pointers carry the node color in their low bit, which is cleared here
+		let mut parent = Ptr::new(node.parent.raw());
+		while !parent.is_null() && node == parent.right {
+			node = parent;
+			parent = Ptr::new(node.parent.raw());
+		}
+		parent
+	}
+
+	/// Previous node in order, null if node holds the smallest value
+	pub fn predecessor(mut node: Ptr<RbTreeNode<V>>) -> Ptr<RbTreeNode<V>> {
+		if !node.left.is_null() {
+			return Self::rightmost(node.left);
+		}
+		let mut parent = Ptr::new(node.parent.raw());
+		while !parent.is_null() && node == parent.left {
+			node = parent;
+			parent = Ptr::new(node.parent.raw());
+		}
+		parent
+	}
+
+	/// First node for which cmp does not return a negative value, null if there is none.
+	/// cmp compares a node's value with the bound and must be consistent with the tree
+	/// order.
+	pub fn lower_bound<F>(&self, mut cmp: F) -> Ptr<RbTreeNode<V>>
+	where
+		F: FnMut(&V) -> i8,
+	{
+		let mut ret = Ptr::null();
+		let mut cur = self.root;
+		while !cur.is_null() {
+			if cmp(&cur.value) >= 0 {
+				ret = cur;
+				cur = cur.left;
+			} else {
+				cur = cur.right;
+			}
+		}
+		ret
+	}
+
+	/// Nodes in order starting at from and stopping before end, or after the largest node
+	/// if end is null. end must not precede from.
+	pub fn range(
+		&self,
+		from: Ptr<RbTreeNode<V>>,
+		end: Ptr<RbTreeNode<V>>,
+	) -> RbTreeIterator<'_, V> {
+		RbTreeIterator {
+			cur: from,
+			end,
+			_marker: PhantomData,
+		}
+	}
+
+	fn leftmost(mut node: Ptr<RbTreeNode<V>>) -> Ptr<RbTreeNode<V>> {
+		while !node.is_null() && !node.left.is_null() {
+			node = node.left;
+		}
+		node
+	}
+
+	fn rightmost(mut node: Ptr<RbTreeNode<V>>) -> Ptr<RbTreeNode<V>> {
+		while !node.is_null() && !node.right.is_null() {
+			node = node.right;
+		}
+		node
+	}
+
 	pub fn insert(
 		&mut self,
 		n: Ptr<RbTreeNode<V>>,
-		search: &mut RbTreeSearch<V>,
+		search: &mut RbTreeSearch<'_, V>,
 	) -> Option<Ptr<RbTreeNode<V>>> {
 		let pair = search(self.root, n);
 		let ret = self.insert_impl(n, pair);
@@ -117,7 +235,7 @@ impl<V: Ord> RbTree<V> {
 	pub fn remove(
 		&mut self,
 		n: Ptr<RbTreeNode<V>>,
-		search: &mut RbTreeSearch<V>,
+		search: &mut RbTreeSearch<'_, V>,
 	) -> Option<Ptr<RbTreeNode<V>>> {
 		let pair = search(self.root, n);
 		if pair.cur.is_null() {
@@ -375,12 +493,13 @@ impl<V: Ord> RbTree<V> {
 				prev.parent.left = next;
 			}
 		}
+		// set_parent preserves the color bit stored in the children's parent pointers
 		if !prev.right.is_null() {
-			prev.right.parent = next;
+			prev.right.set_parent(next);
 		}
 
 		if !prev.left.is_null() {
-			prev.left.parent = next;
+			prev.left.set_parent(next);
 		}
 	}
 
@@ -471,9 +590,10 @@ impl<V: Ord> RbTree<V> {
 mod test {
 	use super::*;
 	use std::murmur32::murmur3_32_of_u64;
+	use util::proptest::*;
 
-	fn validate_node(
-		node: Ptr<RbTreeNode<u64>>,
+	fn validate_node<V: Ord>(
+		node: Ptr<RbTreeNode<V>>,
 		mut black_count: Ptr<i32>,
 		mut current_black_count: i32,
 	) {
@@ -498,7 +618,7 @@ mod test {
 		validate_node(node.left, black_count, current_black_count);
 	}
 
-	fn validate_tree(root: Ptr<RbTreeNode<u64>>) {
+	fn validate_tree<V: Ord>(root: Ptr<RbTreeNode<V>>) {
 		let black_count = Ptr::alloc(0).unwrap();
 		if !root.is_null() {
 			assert!(root.is_black());
@@ -574,7 +694,7 @@ mod test {
 		};
 
 		let size = 100;
-		let initial = unsafe { crate::ffi::getalloccount() };
+		let _alloc = AllocGuard::new();
 		for x in 0..5 {
 			let seed = 0x1234 + x;
 			for i in 0..size {
@@ -679,7 +799,6 @@ mod test {
 			}
 			assert_eq!(c, size);
 		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
 	}
 
 	#[derive(Debug, PartialEq, Clone)]
@@ -726,78 +845,285 @@ mod test {
 			}
 		};
 
-		let initial = unsafe { crate::ffi::getalloccount() };
-		{
-			let size = 3;
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i };
-				let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
-				let res = tree.insert(next, &mut search);
-				assert!(res.is_none());
-			}
+		let _alloc = AllocGuard::new();
+		let size = 3;
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i };
+			let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			let res = tree.insert(next, &mut search);
+			assert!(res.is_none());
+		}
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i };
-				let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
-				let res = search(tree.root(), ptr);
-				assert!(!res.cur.is_null());
-				assert_eq!((*(res.cur)).value, v);
-				ptr.release();
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i };
+			let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
+			let res = search(tree.root(), ptr);
+			assert!(!res.cur.is_null());
+			assert_eq!((*(res.cur)).value, v);
+			ptr.release();
+		}
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 1 };
+			let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			let res = tree.insert(next, &mut search);
+			assert!(res.is_some());
+			res.unwrap().release();
+		}
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 1 };
+			let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
+			let res = search(tree.root(), ptr);
+			assert!(!res.cur.is_null());
+			assert_eq!((*(res.cur)).value, v);
+			ptr.release();
+		}
+
+		// replacing the root keeps the colors of its children
+		let root = tree.root();
+		assert!(root.is_black());
+		assert!(root.left.is_red());
+		assert!(root.right.is_red());
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 91 };
+			let ptr = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			let res = tree.remove(ptr, &mut search);
+			res.unwrap().release();
+			let res = search(tree.root(), ptr);
+			assert!(res.cur.is_null());
+			ptr.release();
+		}
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 10 };
+			let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			let res = tree.insert(next, &mut search);
+			assert!(res.is_none());
+		}
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 10 };
+			let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
+			let res = search(tree.root(), ptr);
+			assert!(!res.cur.is_null());
+			assert_eq!((*(res.cur)).value, v);
+			ptr.release();
+		}
+
+		for i in 0..size {
+			let v = TestTransplant { x: i, y: i + 91 };
+			let ptr = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			let res = tree.remove(ptr, &mut search);
+			res.unwrap().release();
+			let res = search(tree.root(), ptr);
+			assert!(res.cur.is_null());
+			ptr.release();
+		}
+	}
+
+	/// Returns the black height of the subtree or -1 if it violates the red-black properties
+	fn black_height<V: Ord>(node: Ptr<RbTreeNode<V>>) -> i32 {
+		if node.is_null() {
+			return 1;
+		}
+		if node.is_red() && !node.parent.is_null() && node.parent.is_red() {
+			return -1;
+		}
+		let left = black_height(node.left);
+		let right = black_height(node.right);
+		if left < 0 || left != right {
+			-1
+		} else if node.is_black() {
+			left + 1
+		} else {
+			left
+		}
+	}
+
+	fn count_nodes<V: Ord>(node: Ptr<RbTreeNode<V>>) -> usize {
+		if node.is_null() {
+			0
+		} else {
+			1 + count_nodes(node.left) + count_nodes(node.right)
+		}
+	}
+
+	#[test]
+	fn test_rbtree_prop_model() {
+		let mut search = move |base: Ptr<RbTreeNode<TestTransplant>>,
+		                       value: Ptr<RbTreeNode<TestTransplant>>| {
+			let mut is_right = false;
+			let mut cur = base;
+			let mut parent = Ptr::null();
+
+			while !cur.is_null() {
+				let cmp = (*value).value.compare(&(*cur).value);
+				if cmp == 0 {
+					break;
+				} else if cmp < 0 {
+					parent = cur;
+					is_right = false;
+					cur = cur.left;
+				} else {
+					parent = cur;
+					is_right = true;
+					cur = cur.right;
+				}
 			}
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 1 };
-				let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
-				let res = tree.insert(next, &mut search);
-				assert!(res.is_some());
-				res.unwrap().release();
+			RbNodePair {
+				cur,
+				parent,
+				is_right,
 			}
+		};
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 1 };
-				let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
-				let res = search(tree.root(), ptr);
-				assert!(!res.cur.is_null());
-				assert_eq!((*(res.cur)).value, v);
-				ptr.release();
+		let _alloc = AllocGuard::new();
+		// each op inserts (or replaces) or removes one of 64 keys, checked against an array
+		// model. The red-black invariants are validated after every op.
+		check(PropConfig::default(), |ops: &Vec<u64>| {
+			let mut tree = RbTree::new();
+			// u64::MAX marks a key which is not present
+			let mut model = [u64::MAX; 64];
+			let mut ok = true;
+			for op in ops {
+				let x = (*op >> 1) % 64;
+				let y = *op >> 7;
+				let node = Ptr::alloc(RbTreeNode::new(TestTransplant { x, y })).unwrap();
+				if *op % 2 == 0 {
+					match tree.insert(node, &mut search) {
+						Some(prev) => {
+							ok = prev.value.y == model[x as usize];
+							prev.release();
+						}
+						None => ok = model[x as usize] == u64::MAX,
+					}
+					model[x as usize] = y;
+				} else {
+					match tree.remove(node, &mut search) {
+						Some(prev) => {
+							ok = prev.value.y == model[x as usize];
+							prev.release();
+						}
+						None => ok = model[x as usize] == u64::MAX,
+					}
+					model[x as usize] = u64::MAX;
+					node.release();
+				}
+				let root = tree.root();
+				ok = ok && (root.is_null() || root.is_black()) && black_height(root) > 0;
+				if !ok {
+					break;
+				}
 			}
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 91 };
-				let ptr = Ptr::alloc(RbTreeNode::new(v)).unwrap();
-				let res = tree.remove(ptr, &mut search);
-				res.unwrap().release();
-				let res = search(tree.root(), ptr);
-				assert!(res.cur.is_null());
-				ptr.release();
+			let mut count = count_nodes(tree.root());
+			for x in 0..64 {
+				let node = Ptr::alloc(RbTreeNode::new(TestTransplant { x, y: 0 })).unwrap();
+				match tree.remove(node, &mut search) {
+					Some(prev) => {
+						ok = ok && prev.value.y == model[x as usize];
+						prev.release();
+						count -= 1;
+					}
+					None => ok = ok && model[x as usize] == u64::MAX,
+				}
+				node.release();
 			}
+			ok && count == 0 && tree.root().is_null()
+		});
+	}
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 10 };
-				let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
-				let res = tree.insert(next, &mut search);
-				assert!(res.is_none());
+	#[test]
+	fn test_rbtree_navigation() {
+		let mut search = move |base: Ptr<RbTreeNode<u64>>, value: Ptr<RbTreeNode<u64>>| {
+			let mut is_right = false;
+			let mut cur = base;
+			let mut parent = Ptr::null();
+
+			while !cur.is_null() {
+				let cmp = (*value).value.compare(&(*cur).value);
+				if cmp == 0 {
+					break;
+				}
+				parent = cur;
+				is_right = cmp > 0;
+				cur = if is_right { cur.right } else { cur.left };
 			}
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 10 };
-				let ptr = Ptr::alloc(RbTreeNode::new(v.clone())).unwrap();
-				let res = search(tree.root(), ptr);
-				assert!(!res.cur.is_null());
-				assert_eq!((*(res.cur)).value, v);
-				ptr.release();
+			RbNodePair {
+				cur,
+				parent,
+				is_right,
 			}
+		};
 
-			for i in 0..size {
-				let v = TestTransplant { x: i, y: i + 91 };
-				let ptr = Ptr::alloc(RbTreeNode::new(v)).unwrap();
-				let res = tree.remove(ptr, &mut search);
-				res.unwrap().release();
-				let res = search(tree.root(), ptr);
-				assert!(res.cur.is_null());
-				ptr.release();
+		let _alloc = AllocGuard::new();
+		let mut tree = RbTree::new();
+		assert!(tree.min().is_null());
+		assert!(tree.max().is_null());
+		assert!((&tree).into_iter().next().is_none());
+
+		// even values 0..200 inserted in hash order
+		let size = 100;
+		let mut inserted = 0;
+		for i in 0..size * 4 {
+			let v = (murmur3_32_of_u64(i, 0x1234) as u64 % size) * 2;
+			let next = Ptr::alloc(RbTreeNode::new(v)).unwrap();
+			match tree.insert(next, &mut search) {
+				Some(prev) => prev.release(),
+				None => inserted += 1,
 			}
 		}
-		assert_eq!(initial, unsafe { crate::ffi::getalloccount() });
+		assert_eq!(tree.min().value, 0);
+		assert_eq!(tree.max().value, (size - 1) * 2);
+
+		// successor and predecessor walk the tree in both directions
+		let mut count = 0;
+		let mut cur = tree.min();
+		let mut prev: Ptr<RbTreeNode<u64>> = Ptr::null();
+		while !cur.is_null() {
+			if !prev.is_null() {
+				assert!(prev.value < cur.value);
+				assert!(RbTree::predecessor(cur) == prev);
+			}
+			prev = cur;
+			cur = RbTree::successor(cur);
+			count += 1;
+		}
+		assert_eq!(count, inserted);
+		assert!(prev == tree.max());
+		assert!(RbTree::predecessor(tree.min()).is_null());
+
+		assert_eq!(tree.lower_bound(|v| v.compare(&41)).value, 42);
+		assert_eq!(tree.lower_bound(|v| v.compare(&42)).value, 42);
+		assert!(tree.lower_bound(|v| v.compare(&1_000)).is_null());
+
+		// [41, 50) and everything from 190
+		let from = tree.lower_bound(|v| v.compare(&41));
+		let end = tree.lower_bound(|v| v.compare(&50));
+		let mut expected = 42;
+		for node in tree.range(from, end) {
+			assert_eq!(node.value, expected);
+			expected += 2;
+		}
+		assert_eq!(expected, 50);
+		let from = tree.lower_bound(|v| v.compare(&190));
+		assert_eq!(tree.range(from, Ptr::null()).count(), 5);
+		assert_eq!(tree.range(from, from).count(), 0);
+
+		assert_eq!((&tree).into_iter().count(), inserted);
+
+		// the successor stays valid while the current node is removed
+		let mut cur = tree.min();
+		while !cur.is_null() {
+			let next = RbTree::successor(cur);
+			assert!(tree.remove(cur, &mut search).unwrap() == cur);
+			cur.release();
+			cur = next;
+		}
+		assert!(tree.root().is_null());
 	}
 }
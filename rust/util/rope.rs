@@ -0,0 +1,338 @@
+//! # Rope
+//! A byte buffer kept as a chain of fixed size chunks, used to receive from sockets. Data
+//! is written into the spare room of the last chunk and a chunk is added when that fills,
+//! so bytes already received are never moved as the buffer grows. Readers take a range
+//! with contiguous, which returns the bytes in place when they lie within one chunk and
+//! otherwise copies them once into a scratch buffer. A message of many megabytes is thus
+//! copied a single time, once it is complete, instead of on every reallocation of a flat
+//! buffer and again each time a message in front of it is removed.
+
+use prelude::*;
+use util::arena::Arena;
+use util::bufpool::BufferPool;
+
+/// Chunk size used for connection receive buffers
+pub const ROPE_CHUNK_SIZE: usize = 16 * 1024;
+
+pub struct Rope {
+	// every chunk is chunk_size long, only the last one may be partly written
+	chunks: Vec<Vec<u8>>,
+	// offset of the first unconsumed byte in the first chunk
+	head: usize,
+	// bytes written to the last chunk
+	tail: usize,
+	len: usize,
+	chunk_size: usize,
+	// the copy made by contiguous when a range spans chunks
+	scratch: Vec<u8>,
+	// where chunks come from and go back to, if not the allocator
+	pool: Option<BufferPool>,
+}
+
+impl Drop for Rope {
+	fn drop(&mut self) {
+		let n = self.chunks.len();
+		self.release_chunks(n);
+	}
+}
+
+impl Rope {
+	pub fn new(chunk_size: usize) -> Result<Self, Error> {
+		Self::init(chunk_size, None)
+	}
+
+	/// A rope which takes its chunks from pool and returns them as they are consumed
+	pub fn with_pool(chunk_size: usize, pool: BufferPool) -> Result<Self, Error> {
+		Self::init(chunk_size, Some(pool))
+	}
+
+	fn init(chunk_size: usize, pool: Option<BufferPool>) -> Result<Self, Error> {
+		if chunk_size == 0 {
+			return Err(err!(IllegalArgument));
+		}
+		let mut chunks = Vec::new();
+		chunks.set_min(0);
+		let mut scratch = Vec::new();
+		scratch.set_min(0);
+		Ok(Self {
+			chunks,
+			head: 0,
+			tail: 0,
+			len: 0,
+			chunk_size,
+			scratch,
+			pool,
+		})
+	}
+
+	/// Number of unconsumed bytes
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Bytes held in chunks and the scratch copy, whether or not they are in use
+	pub fn capacity(&self) -> usize {
+		self.chunks.len() * self.chunk_size + self.scratch.capacity()
+	}
+
+	/// Room at the end of the buffer, adding a chunk if the last one is full. Bytes written
+	/// to it become part of the buffer once commit is called.
+	pub fn spare(&mut self) -> Result<&mut [u8], Error> {
+		if self.chunks.len() == 0 || self.tail == self.chunk_size {
+			let chunk = match &self.pool {
+				Some(pool) => match pool.get(self.chunk_size) {
+					Ok(chunk) => chunk,
+					Err(e) => return Err(e),
+				},
+				None => {
+					let mut chunk = Vec::new();
+					chunk.set_min(0);
+					match chunk.resize(self.chunk_size) {
+						Ok(_) => {}
+						Err(e) => return Err(e),
+					}
+					chunk
+				}
+			};
+			match self.chunks.push(chunk) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+			self.tail = 0;
+		}
+		let last = self.chunks.len() - 1;
+		let end = self.chunk_size;
+		Ok(&mut self.chunks[last][self.tail..end])
+	}
+
+	/// Appends the first n bytes of the slice last returned by spare
+	pub fn commit(&mut self, n: usize) {
+		debug_assert!(self.tail + n <= self.chunk_size);
+		self.tail += n;
+		self.len += n;
+	}
+
+	/// Copies bytes onto the end of the buffer
+	pub fn extend(&mut self, bytes: &[u8]) -> Result<(), Error> {
+		let mut offset = 0;
+		while offset < bytes.len() {
+			let n = match self.spare() {
+				Ok(spare) => {
+					let n = if spare.len() < bytes.len() - offset {
+						spare.len()
+					} else {
+						bytes.len() - offset
+					};
+					spare[0..n].copy_from_slice(&bytes[offset..offset + n]);
+					n
+				}
+				Err(e) => return Err(e),
+			};
+			self.commit(n);
+			offset += n;
+		}
+		Ok(())
+	}
+
+	/// The n bytes that start offset bytes into the buffer, as one slice. They are returned
+	/// in place when they lie in a single chunk, otherwise they are copied, so changes made
+	/// through the slice are only certain to be seen by the caller.
+	pub fn contiguous(&mut self, offset: usize, n: usize) -> Result<&mut [u8], Error> {
+		if offset + n > self.len {
+			return Err(err!(OutOfBounds));
+		}
+		if n == 0 {
+			return Ok(&mut []);
+		}
+		let mut index = (self.head + offset) / self.chunk_size;
+		let mut pos = (self.head + offset) % self.chunk_size;
+		if pos + n <= self.chunk_size {
+			return Ok(&mut self.chunks[index][pos..pos + n]);
+		}
+		match self.scratch.resize(n) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		let mut copied = 0;
+		while copied < n {
+			let take = if self.chunk_size - pos < n - copied {
+				self.chunk_size - pos
+			} else {
+				n - copied
+			};
+			self.scratch[copied..copied + take]
+				.copy_from_slice(&self.chunks[index][pos..pos + take]);
+			copied += take;
+			index += 1;
+			pos = 0;
+		}
+		Ok(&mut self.scratch[0..n])
+	}
+
+	/// Like contiguous, but a range which spans chunks is copied into arena rather than the
+	/// scratch buffer, so that it does not reach the allocator once the arena has room
+	pub fn contiguous_in<'a>(
+		&'a mut self,
+		offset: usize,
+		n: usize,
+		arena: &'a Arena,
+	) -> Result<&'a mut [u8], Error> {
+		if offset + n > self.len {
+			return Err(err!(OutOfBounds));
+		}
+		let mut index = (self.head + offset) / self.chunk_size;
+		let mut pos = (self.head + offset) % self.chunk_size;
+		if pos + n <= self.chunk_size {
+			return self.contiguous(offset, n);
+		}
+		let buf = match arena.alloc_bytes(n) {
+			Ok(buf) => buf,
+			Err(e) => return Err(e),
+		};
+		let mut copied = 0;
+		while copied < n {
+			let take = if self.chunk_size - pos < n - copied {
+				self.chunk_size - pos
+			} else {
+				n - copied
+			};
+			buf[copied..copied + take].copy_from_slice(&self.chunks[index][pos..pos + take]);
+			copied += take;
+			index += 1;
+			pos = 0;
+		}
+		Ok(buf)
+	}
+
+	/// Drops the first n bytes, releasing the chunks they occupied
+	pub fn consume(&mut self, n: usize) {
+		if n >= self.len {
+			self.clear();
+			return;
+		}
+		self.len -= n;
+		self.head += n;
+		let done = self.head / self.chunk_size;
+		if done > 0 {
+			self.release_chunks(done);
+			self.head %= self.chunk_size;
+		}
+		// keep one copy of a small message for the next, but not one of a large message
+		if self.scratch.len() > self.chunk_size {
+			self.scratch.clear();
+		}
+	}
+
+	/// Drops everything and releases the memory
+	pub fn clear(&mut self) {
+		// Vec::clear releases the memory without dropping the chunks in it
+		let n = self.chunks.len();
+		self.release_chunks(n);
+		self.chunks.clear();
+		self.scratch.clear();
+		self.head = 0;
+		self.tail = 0;
+		self.len = 0;
+	}
+
+	// removes the first n chunks, giving them back to the pool if there is one
+	fn release_chunks(&mut self, n: usize) {
+		match &self.pool {
+			Some(pool) => {
+				for chunk in self.chunks.drain(0..n) {
+					pool.put(chunk);
+				}
+			}
+			None => {
+				let _ = self.chunks.drain(0..n);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use core::mem::drop;
+
+	#[test]
+	fn test_rope() {
+		let _alloc = AllocGuard::new();
+		assert!(Rope::new(0).is_err());
+		let mut rope = Rope::new(8).unwrap();
+		assert!(rope.is_empty());
+		assert_eq!(rope.contiguous(0, 0).unwrap().len(), 0);
+		assert!(rope.contiguous(0, 1).is_err());
+
+		// writes go to the spare room of the last chunk
+		let spare = rope.spare().unwrap();
+		assert_eq!(spare.len(), 8);
+		spare[0..3].copy_from_slice(b"abc");
+		rope.commit(3);
+		assert_eq!(rope.spare().unwrap().len(), 5);
+		rope.extend(b"defghijklmnopqrstu").unwrap();
+		assert_eq!(rope.len(), 21);
+		assert_eq!(rope.chunks.len(), 3);
+
+		// within a chunk the bytes are returned in place, across chunks they are copied
+		assert_eq!(rope.contiguous(2, 4).unwrap(), b"cdef");
+		assert_eq!(rope.scratch.len(), 0);
+		assert_eq!(rope.contiguous(6, 12).unwrap(), b"ghijklmnopqr");
+		assert_eq!(rope.scratch.len(), 12);
+		rope.contiguous(8, 2).unwrap()[0] = b'I';
+		assert_eq!(rope.contiguous(0, 21).unwrap(), b"abcdefghIjklmnopqrstu");
+		let arena = Arena::new(64).unwrap();
+		assert_eq!(rope.contiguous_in(2, 4, &arena).unwrap(), b"cdef");
+		assert_eq!(arena.chunks(), 0);
+		rope.scratch.clear();
+		assert_eq!(rope.contiguous_in(6, 12, &arena).unwrap(), b"ghIjklmnopqr");
+		assert_eq!(arena.chunks(), 1);
+		assert_eq!(rope.scratch.len(), 0);
+
+		// consuming releases whole chunks only
+		rope.consume(10);
+		assert_eq!(rope.len(), 11);
+		assert_eq!(rope.chunks.len(), 2);
+		assert_eq!(rope.contiguous(0, 11).unwrap(), b"klmnopqrstu");
+		rope.consume(6);
+		assert_eq!(rope.chunks.len(), 1);
+		assert_eq!(rope.contiguous(0, 5).unwrap(), b"qrstu");
+		rope.extend(b"vwxyz").unwrap();
+		assert_eq!(rope.contiguous(3, 7).unwrap(), b"tuvwxyz");
+		rope.consume(100);
+		assert!(rope.is_empty());
+		assert_eq!(rope.chunks.len(), 0);
+
+		// a message much larger than a chunk, left in the buffer when it is dropped
+		let mut big = Vec::new();
+		for i in 0..1000 {
+			big.push((i % 251) as u8).unwrap();
+		}
+		rope.extend(b"x").unwrap();
+		rope.extend(big.as_slice()).unwrap();
+		assert_eq!(rope.contiguous(1, 1000).unwrap(), big.as_slice());
+		rope.consume(1);
+		assert_eq!(rope.contiguous(0, 1000).unwrap(), big.as_slice());
+
+		// chunks go back to the pool as they are consumed and when the rope is dropped
+		let pool = BufferPool::new(8).unwrap();
+		let mut rope = Rope::with_pool(256, pool.clone().unwrap()).unwrap();
+		rope.extend(big.as_slice()).unwrap();
+		assert_eq!(pool.available(256), 0);
+		rope.consume(600);
+		assert_eq!(pool.available(256), 2);
+		rope.extend(b"abc").unwrap();
+		assert_eq!(pool.available(256), 2);
+		assert_eq!(rope.contiguous(400, 3).unwrap(), b"abc");
+		rope.consume(200);
+		assert_eq!(pool.available(256), 3);
+		rope.extend(big.as_slice()).unwrap();
+		assert_eq!(pool.available(256), 0);
+		drop(rope);
+		assert_eq!(pool.available(256), 5);
+	}
+}
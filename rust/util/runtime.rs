@@ -1,122 +1,365 @@
+//! # Runtime
+//! A pool of worker threads which grows from min_threads to max_threads while every worker
+//! is busy and shrinks back once they are idle. Each worker has its own lock-free queue.
+//! Tasks are pushed onto the queues in turn, and a worker takes from its own queue first
+//! and then steals from the others, so submitting threads and workers do not all meet on
+//! one lock. Idle workers sleep on a condition variable, which submitters only touch when
+//! some worker is asleep.
+//!
+//! The queues have a fixed capacity. Tasks which do not fit wait in a shared overflow queue
+//! behind a lock, so execute only fails for lack of room when bounded is set.
+//!
+//! Failures are not isolated. A task which calls panic! or exit! ends the process as on
+//! any other thread, the crate has no unwinding to stop it at the worker. Tasks which can
+//! fail are submitted with execute_fallible and return their error instead.
+
+use core::cell::UnsafeCell;
+use core::mem::{drop, replace};
+use ffi::{getmicros, thread_cpu_micros};
 use prelude::*;
 
-type Task<T> = Box<dyn FnMut() -> T>;
+type Task<T> = Box<dyn FnMut() -> Result<T, Error> + Send>;
+
+/// Number of latency histogram buckets. Bucket i counts tasks that took less than 2^i
+/// microseconds but at least half that, the last bucket also counts everything slower.
+pub const LATENCY_BUCKETS: usize = 24;
+/// Tag of tasks submitted with execute
+pub const DEFAULT_TASK_TAG: &str = "default";
 
 pub struct RuntimeConfig {
+	/// Worker threads are named `<name>-<n>` and log lines are prefixed with the name
+	pub name: &'static str,
 	pub min_threads: u64,
 	pub max_threads: u64,
+	/// Capacity of each worker's lock-free queue
+	pub queue_capacity: usize,
+	/// Fail execute with ChannelFull when every worker's queue is full, rather than holding
+	/// the task in the overflow queue
+	pub bounded: bool,
+	/// Record wall and CPU time of every task, grouped by the tag given to execute_tagged
+	pub task_stats: bool,
+}
+
+/// Accumulated timings of the tasks executed with one tag
+#[derive(Clone, Copy)]
+pub struct TaskStats {
+	pub tag: &'static str,
+	pub count: u64,
+	pub wall_micros: u64,
+	pub cpu_micros: u64,
+	pub max_wall_micros: u64,
+	pub latency: [u64; LATENCY_BUCKETS],
 }
 
 pub struct Handle<T> {
-	channel: Receiver<T>,
-	is_complete: Rc<bool>,
+	// Cancelled when the task was aborted before it started, or the error a fallible task
+	// returned
+	channel: Receiver<Result<T, Error>>,
+	is_complete: Arc<AtomicU64>,
+	cancelled: Arc<AtomicU64>,
 }
 
-struct JhEntry {
-	id: u64,
-	jh: Option<JoinHandle>,
+/// Given to tasks submitted with execute_cancellable so that they can stop early once their
+/// Handle has been aborted. Cancellation is cooperative, a task which never checks runs to
+/// completion.
+pub struct CancelToken {
+	cancelled: Arc<AtomicU64>,
 }
 
 struct State {
 	total_workers: u64,
 	waiting_workers: u64,
-	halt: bool,
-	jhs: Hashtable<JhEntry>,
+	jhs: HashMap<u64, JoinHandle>,
+	stats: Vec<TaskStats>,
+	// tasks submitted with execute_fallible which returned an error
+	failed: u64,
 }
 
-enum Message<T> {
-	Task((Task<T>, Sender<T>, Rc<bool>)),
-	Halt,
+struct Job<T> {
+	task: Task<T>,
+	result: Sender<Result<T, Error>>,
+	is_complete: Arc<AtomicU64>,
+	tag: &'static str,
+	cancelled: Arc<AtomicU64>,
 }
 
-pub struct Runtime<T> {
-	config: RuntimeConfig,
-	send: Sender<Message<T>>,
-	recv: Receiver<Message<T>>,
-	state: Rc<State>,
-	lock: LockBox,
-	counter: u64,
+// used by submitters and workers without taking the state lock
+struct Shared<T> {
+	queues: Vec<MpmcQueue<Job<T>>>,
+	// tasks which did not fit in any queue
+	overflow: Mutex<Deque<Job<T>>>,
+	// length of overflow, so that workers only lock it when it has tasks
+	overflowed: UnsafeCell<u64>,
+	// workers blocked on the work condvar
+	sleeping: UnsafeCell<u64>,
+	halt: UnsafeCell<u64>,
 }
 
-impl PartialEq for JhEntry {
-	fn eq(&self, other: &Self) -> bool {
-		self.id == other.id
-	}
+// overflowed, sleeping and halt are only read and written atomically
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+// what a worker thread takes from its Runtime, which is enough to start further workers
+struct Worker<T> {
+	shared: Arc<Shared<T>>,
+	state: Arc<Mutex<State>>,
+	work: Arc<Condvar>,
+	changed: Arc<Condvar>,
+	counter: Arc<AtomicU64>,
+	name: &'static str,
+	task_stats: bool,
 }
 
-impl Hash for JhEntry {
-	fn hash(&self) -> usize {
-		murmur3_32_of_u64(self.id, get_murmur_seed()) as usize
-	}
+pub struct Runtime<T> {
+	config: RuntimeConfig,
+	shared: Arc<Shared<T>>,
+	state: Arc<Mutex<State>>,
+	// notified, with the state lock held, when a task is queued while a worker sleeps
+	work: Arc<Condvar>,
+	// notified whenever a worker starts waiting, stops waiting or exits
+	changed: Arc<Condvar>,
+	counter: Arc<AtomicU64>,
+	// queue the next task is pushed onto first
+	next: u64,
 }
 
 impl Default for RuntimeConfig {
 	fn default() -> Self {
 		Self {
+			name: "runtime",
 			min_threads: 4,
 			max_threads: 8,
+			queue_capacity: 1024,
+			bounded: false,
+			task_stats: false,
+		}
+	}
+}
+
+impl TaskStats {
+	fn new(tag: &'static str) -> Self {
+		Self {
+			tag,
+			count: 0,
+			wall_micros: 0,
+			cpu_micros: 0,
+			max_wall_micros: 0,
+			latency: [0; LATENCY_BUCKETS],
+		}
+	}
+
+	fn record(&mut self, wall: u64, cpu: u64) {
+		self.count += 1;
+		self.wall_micros += wall;
+		self.cpu_micros += cpu;
+		if wall > self.max_wall_micros {
+			self.max_wall_micros = wall;
+		}
+		let bucket = (64 - wall.leading_zeros()) as usize;
+		if bucket >= LATENCY_BUCKETS {
+			self.latency[LATENCY_BUCKETS - 1] += 1;
+		} else {
+			self.latency[bucket] += 1;
 		}
 	}
 }
 
+impl State {
+	fn record(&mut self, tag: &'static str, wall: u64, cpu: u64) {
+		for i in 0..self.stats.len() {
+			if self.stats[i].tag == tag {
+				self.stats[i].record(wall, cpu);
+				return;
+			}
+		}
+		let mut stats = TaskStats::new(tag);
+		stats.record(wall, cpu);
+		// on allocation failure the sample is dropped, the task itself has already run
+		let _ = self.stats.push(stats);
+	}
+}
+
+impl<T> Shared<T> {
+	fn is_halted(&self) -> bool {
+		aload!(self.halt.get()) != 0
+	}
+
+	// the worker's own queue first, then the others in order, then the overflow
+	fn find(&self, slot: usize) -> Option<Job<T>> {
+		let n = self.queues.len();
+		for i in 0..n {
+			match self.queues[(slot + i) % n].pop() {
+				Some(job) => return Some(job),
+				None => {}
+			}
+		}
+		if aload!(self.overflowed.get()) == 0 {
+			return None;
+		}
+		let mut overflow = self.overflow.lock();
+		let job = overflow.pop_front();
+		if job.is_some() {
+			asub!(self.overflowed.get(), 1);
+		}
+		job
+	}
+}
+
+impl<T> Clone for Worker<T> {
+	fn clone(&self) -> Result<Self, Error> {
+		// SAFETY: unwraps are ok because they are clone for arc which does not fail
+		Ok(Self {
+			shared: self.shared.clone().unwrap(),
+			state: self.state.clone().unwrap(),
+			work: self.work.clone().unwrap(),
+			changed: self.changed.clone().unwrap(),
+			counter: self.counter.clone().unwrap(),
+			name: self.name,
+			task_stats: self.task_stats,
+		})
+	}
+}
+
 impl<T> Drop for Runtime<T> {
 	fn drop(&mut self) {
 		let _ = self.stop();
 	}
 }
 
+impl<T> Runtime<T> {
+	pub fn stop(&mut self) -> Result<(), Error> {
+		// the handles are joined without the lock, which exiting workers take
+		let mut jhs = {
+			let mut state = self.state.lock();
+			if self.shared.is_halted() {
+				return Err(err!(NotInitialized));
+			}
+			let empty = match HashMap::with_buckets(1) {
+				Ok(empty) => empty,
+				Err(e) => return Err(e),
+			};
+			astore!(self.shared.halt.get(), 1);
+			// under the lock, so that no worker is between checking halt and sleeping
+			self.work.notify_all();
+			replace(&mut state.jhs, empty)
+		};
+		for (_id, jh) in jhs.iter_mut() {
+			let _ = jh.join();
+		}
+
+		Ok(())
+	}
+}
+
 impl<T> Handle<T> {
-	pub fn block_on(&self) -> T {
+	/// Waits for the value the task returned. Fails with Cancelled if the task was aborted
+	/// before it started, since it never ran, and with the task's error if a task submitted
+	/// with execute_fallible failed.
+	pub fn block_on(&self) -> Result<T, Error> {
 		self.channel.recv()
 	}
 
+	/// Like block_on but gives up with a Timeout error if the task has not completed
+	/// within micros. The task keeps running and can still be waited for afterwards.
+	pub fn block_on_timeout(&self, micros: u64) -> Result<T, Error> {
+		match self.channel.recv_timeout(micros) {
+			Ok(res) => res,
+			Err(e) => Err(e),
+		}
+	}
+
+	/// True once the task has returned or failed, or was skipped because it was aborted
 	pub fn is_complete(&self) -> bool {
-		*self.is_complete
+		self.is_complete.load() != 0
+	}
+
+	/// Cancels the task. If no worker has picked it up yet it never runs, otherwise a task
+	/// submitted with execute_cancellable sees it through its CancelToken.
+	pub fn abort(&self) {
+		self.cancelled.store(1);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load() != 0
 	}
 }
 
-impl<T> Runtime<T> {
+impl CancelToken {
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load() != 0
+	}
+}
+
+impl<T: Send + 'static> Runtime<T> {
 	pub fn new(config: RuntimeConfig) -> Result<Self, Error> {
-		let (send, recv) = match channel() {
-			Ok((send, recv)) => (send, recv),
+		let mut queues = Vec::new();
+		for _i in 0..config.max_threads {
+			let queue = match MpmcQueue::new(config.queue_capacity) {
+				Ok(queue) => queue,
+				Err(e) => return Err(e),
+			};
+			match queues.push(queue) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		let shared = match Arc::new(Shared {
+			queues,
+			overflow: Mutex::new(Deque::new()),
+			overflowed: 0_u64.into(),
+			sleeping: 0_u64.into(),
+			halt: 0_u64.into(),
+		}) {
+			Ok(shared) => shared,
 			Err(e) => return Err(e),
 		};
-		let jhs = match Hashtable::new(config.max_threads as usize * 2) {
+		let jhs = match HashMap::with_buckets(config.max_threads as usize * 2) {
 			Ok(jhs) => jhs,
 			Err(e) => return Err(e),
 		};
-		let state = match Rc::new(State {
+		let state = match Arc::new(Mutex::new(State {
 			waiting_workers: 0,
 			total_workers: config.min_threads,
-			halt: false,
 			jhs,
-		}) {
+			stats: Vec::new(),
+			failed: 0,
+		})) {
 			Ok(state) => state,
 			Err(e) => return Err(e),
 		};
-		let lock = match lock_box!() {
-			Ok(lock) => lock,
+		let work = match Condvar::new() {
+			Ok(work) => match Arc::new(work) {
+				Ok(work) => work,
+				Err(e) => return Err(e),
+			},
+			Err(e) => return Err(e),
+		};
+		let changed = match Condvar::new() {
+			Ok(changed) => match Arc::new(changed) {
+				Ok(changed) => changed,
+				Err(e) => return Err(e),
+			},
+			Err(e) => return Err(e),
+		};
+		let counter = match Arc::new(AtomicU64::new(0)) {
+			Ok(counter) => counter,
 			Err(e) => return Err(e),
 		};
 
 		Ok(Self {
 			config,
-			send,
-			recv,
+			shared,
 			state,
-			lock,
-			counter: 0,
+			work,
+			changed,
+			counter,
+			next: 0,
 		})
 	}
 
 	pub fn start(&mut self) -> Result<(), Error> {
-		// SAFETY: clone always succeeds on LockBox
-		let lock = self.lock.clone().unwrap();
-		{
-			let _l = lock.read();
-			if self.state.halt {
-				return Err(err!(NotInitialized));
-			}
+		if self.shared.is_halted() {
+			return Err(err!(NotInitialized));
 		}
 		for _i in 0..self.config.min_threads {
 			match self.thread(self.config.min_threads, self.config.max_threads) {
@@ -127,141 +370,307 @@ impl<T> Runtime<T> {
 		Ok(())
 	}
 
-	pub fn stop(&mut self) -> Result<(), Error> {
-		{
-			let _l = self.lock.write();
-			if self.state.halt {
-				return Err(err!(NotInitialized));
-			}
-			self.state.halt = true;
-		}
-		for _i in 0..self.config.max_threads {
-			match self.send.send(Message::Halt) {
-				Ok(_) => {}
-				Err(e) => return Err(e),
-			}
-		}
-		for mut ent in &self.state.jhs {
-			match &mut (*ent).value.jh {
-				Some(entry) => {
-					let _ = entry.join();
-				}
-				None => {}
-			}
-			ent.release();
-		}
+	pub fn execute<F>(&mut self, task: F) -> Result<Handle<T>, Error>
+	where
+		F: FnMut() -> T + Send + 'static,
+	{
+		self.execute_tagged(DEFAULT_TASK_TAG, task)
+	}
 
-		Ok(())
+	/// Like execute but accounts the task's time to tag when task_stats is enabled
+	pub fn execute_tagged<F>(&mut self, tag: &'static str, mut task: F) -> Result<Handle<T>, Error>
+	where
+		F: FnMut() -> T + Send + 'static,
+	{
+		let cancelled = match Arc::new(AtomicU64::new(0)) {
+			Ok(cancelled) => cancelled,
+			Err(e) => return Err(e),
+		};
+		self.submit(tag, move || Ok(task()), cancelled)
 	}
 
-	pub fn execute<F>(&mut self, task: F) -> Result<Handle<T>, Error>
+	/// Like execute for a task which can fail. An error is counted in failed_tasks, logged
+	/// and returned by the Handle, and the worker goes on to the next task. Only returned
+	/// errors are caught, a panic! in the task still ends the process.
+	pub fn execute_fallible<F>(&mut self, task: F) -> Result<Handle<T>, Error>
 	where
-		F: FnMut() -> T + 'static,
+		F: FnMut() -> Result<T, Error> + Send + 'static,
 	{
-		{
-			let _l = self.lock.read();
-			if self.state.halt {
-				return Err(err!(NotInitialized));
-			}
+		let cancelled = match Arc::new(AtomicU64::new(0)) {
+			Ok(cancelled) => cancelled,
+			Err(e) => return Err(e),
+		};
+		self.submit(DEFAULT_TASK_TAG, task, cancelled)
+	}
+
+	/// Like execute but passes the task a CancelToken, which it can poll to stop early once
+	/// the Handle has been aborted
+	pub fn execute_cancellable<F>(&mut self, mut task: F) -> Result<Handle<T>, Error>
+	where
+		F: FnMut(&CancelToken) -> T + Send + 'static,
+	{
+		let cancelled = match Arc::new(AtomicU64::new(0)) {
+			Ok(cancelled) => cancelled,
+			Err(e) => return Err(e),
+		};
+		// SAFETY: clone always succeeds on arc
+		let token = CancelToken {
+			cancelled: cancelled.clone().unwrap(),
+		};
+		self.submit(DEFAULT_TASK_TAG, move || Ok(task(&token)), cancelled)
+	}
+
+	fn submit<F>(
+		&mut self,
+		tag: &'static str,
+		task: F,
+		cancelled: Arc<AtomicU64>,
+	) -> Result<Handle<T>, Error>
+	where
+		F: FnMut() -> Result<T, Error> + Send + 'static,
+	{
+		if self.shared.is_halted() {
+			return Err(err!(NotInitialized));
 		}
 		let (send, recv) = match channel() {
 			Ok((send, recv)) => (send, recv),
 			Err(e) => return Err(e),
 		};
-		let rc = match Rc::new(false) {
+		let rc = match Arc::new(AtomicU64::new(0)) {
 			Ok(rc) => rc,
 			Err(e) => return Err(e),
 		};
 		// SAFETY: rc.clone always succeeds
 		let rc_clone = rc.clone().unwrap();
+		let cancelled_clone = cancelled.clone().unwrap();
 		let task = match Box::new(task) {
 			Ok(task) => task,
 			Err(e) => return Err(e),
 		};
-		let msg = Message::Task((task, send, rc));
-		match self.send.send(msg) {
-			Ok(_) => {}
-			Err(e) => return Err(e),
+		// None once a queue has taken the job
+		let mut unqueued = Some(Job {
+			task,
+			result: send,
+			is_complete: rc,
+			tag,
+			cancelled,
+		});
+		let n = self.shared.queues.len();
+		let first = aadd!(&mut self.next, 1) as usize;
+		for i in 0..n {
+			match replace(&mut unqueued, None) {
+				Some(job) => match self.shared.queues[(first + i) % n].push(job) {
+					Ok(_) => {}
+					Err(job) => unqueued = Some(job),
+				},
+				None => break,
+			}
+		}
+		match unqueued {
+			Some(job) => {
+				if self.config.bounded {
+					return Err(err!(ChannelFull));
+				}
+				let mut overflow = self.shared.overflow.lock();
+				match overflow.push_back(job) {
+					Ok(_) => {}
+					Err(e) => return Err(e),
+				}
+				aadd!(self.shared.overflowed.get(), 1);
+			}
+			None => {}
+		}
+		// a read-modify-write so that it is ordered after the push. A worker counts itself
+		// as sleeping before it looks at the queues for the last time.
+		if aadd!(self.shared.sleeping.get(), 0) > 0 {
+			let _state = self.state.lock();
+			self.work.notify_one();
 		}
 		Ok(Handle {
 			channel: recv,
 			is_complete: rc_clone,
+			cancelled: cancelled_clone,
 		})
 	}
 
+	/// Timings per task tag, empty unless task_stats is enabled. Tags appear in the order in
+	/// which their first task completed.
+	pub fn task_stats(&self) -> Result<Vec<TaskStats>, Error> {
+		let state = self.state.lock();
+		let mut ret = Vec::new();
+		for i in 0..state.stats.len() {
+			match ret.push(state.stats[i]) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(ret)
+	}
+
+	/// Number of tasks submitted with execute_fallible which returned an error
+	pub fn failed_tasks(&self) -> u64 {
+		self.state.lock().failed
+	}
+
 	#[cfg(test)]
 	fn cur_threads(&self) -> u64 {
-		let _l = self.lock.read();
-		self.state.total_workers
+		self.state.lock().total_workers
 	}
 
 	#[cfg(test)]
 	fn idle_threads(&self) -> u64 {
-		let _l = self.lock.read();
-		self.state.waiting_workers
-	}
-
-	fn thread(&mut self, min: u64, max: u64) -> Result<(), Error> {
-		let id = aadd!(&mut self.counter, 1);
-		// SAFETY: unwraps are ok because they are clone for rc, lock, and channels
-		// which do not fail
-		let recv = self.recv.clone().unwrap();
-		let mut state = self.state.clone().unwrap();
-		let mut state_clone = state.clone().unwrap();
-		let lock = self.lock.clone().unwrap();
-		let lock_clone = lock.clone().unwrap();
-
-		let jh = match spawnj(move || loop {
-			{
-				let _l = lock.write();
-				if state.halt {
-					state.total_workers -= 1;
-					break;
-				} else {
-					state.waiting_workers += 1;
-					if state.waiting_workers > min {
-						state.total_workers -= 1;
-						state.waiting_workers -= 1;
-						let jhent = state.jhs.remove(&JhEntry { id, jh: None }).unwrap();
-						jhent.release();
-						break;
-					}
+		self.state.lock().waiting_workers
+	}
+
+	/// Blocks until the worker counts satisfy cond
+	#[cfg(test)]
+	fn wait_for<F: FnMut(&State) -> bool>(&self, mut cond: F) {
+		let mut state = self.state.lock();
+		while !cond(&state) {
+			state = self.changed.wait(state);
+		}
+	}
+
+	fn thread(&self, min: u64, max: u64) -> Result<(), Error> {
+		// SAFETY: unwraps are ok because they are clone for arc which does not fail
+		let worker = Worker {
+			shared: self.shared.clone().unwrap(),
+			state: self.state.clone().unwrap(),
+			work: self.work.clone().unwrap(),
+			changed: self.changed.clone().unwrap(),
+			counter: self.counter.clone().unwrap(),
+			name: self.config.name,
+			task_stats: self.config.task_stats,
+		};
+		worker.spawn(min, max)
+	}
+}
+
+impl<T: Send + 'static> Worker<T> {
+	fn spawn(&self, min: u64, max: u64) -> Result<(), Error> {
+		let id = self.counter.fetch_add(1);
+		// SAFETY: unwraps are ok because they are clone for arc which does not fail
+		let worker = self.clone().unwrap();
+		let shared = self.shared.clone().unwrap();
+		let state = self.state.clone().unwrap();
+		let state_clone = state.clone().unwrap();
+		let work = self.work.clone().unwrap();
+		let changed = self.changed.clone().unwrap();
+		let task_stats = self.task_stats;
+		let name = self.name;
+		let slot = if max == 0 { 0 } else { (id % max) as usize };
+
+		// held until the handle is recorded so that the new worker cannot look it up first
+		let mut guard = state_clone.lock();
+		let jh = match spawnj(move || {
+			// only a label for debuggers, so the worker runs unnamed if this fails
+			match format!("{}-{}", name, id) {
+				Ok(thread) => {
+					let _ = set_thread_name(thread.to_str());
 				}
+				Err(_e) => {}
 			}
-			match recv.recv() {
-				Message::Task(mut t) => {
-					{
-						let mut do_spawn = false;
-						{
-							let _l = lock.write();
+			let mut fresh = true;
+			loop {
+				if shared.is_halted() {
+					state.lock().total_workers -= 1;
+					changed.notify_all();
+					break;
+				}
+				// a busy worker goes straight on to the next task, the counts only change
+				// when it runs out of work. A new worker is counted as waiting before its
+				// first task, so that taking one can start the next worker.
+				let next = if fresh { None } else { shared.find(slot) };
+				fresh = false;
+				let mut job = match next {
+					Some(job) => job,
+					None => {
+						let mut state = state.lock();
+						state.waiting_workers += 1;
+						if state.waiting_workers > min && !shared.is_halted() {
+							state.total_workers -= 1;
 							state.waiting_workers -= 1;
-							if state.waiting_workers == 0
-								&& state.total_workers < max
-								&& !state.halt
-							{
-								state.total_workers += 1;
-								do_spawn = true;
+							// dropping the handle detaches the exiting thread
+							let _jh = state.jhs.remove(&id).unwrap();
+							changed.notify_all();
+							break;
+						}
+						changed.notify_all();
+						aadd!(shared.sleeping.get(), 1);
+						let job = loop {
+							if shared.is_halted() {
+								break None;
 							}
+							match shared.find(slot) {
+								Some(job) => break Some(job),
+								None => state = work.wait(state),
+							}
+						};
+						asub!(shared.sleeping.get(), 1);
+						state.waiting_workers -= 1;
+						let job = match job {
+							Some(job) => job,
+							None => {
+								state.total_workers -= 1;
+								changed.notify_all();
+								break;
+							}
+						};
+						// keep a worker waiting for the next task while there is room
+						let do_spawn = state.waiting_workers == 0 && state.total_workers < max;
+						if do_spawn {
+							state.total_workers += 1;
 						}
+						drop(state);
+						changed.notify_all();
 						if do_spawn {
-							match self.thread(min, max) {
+							match worker.spawn(min, max) {
 								Ok(_) => {}
 								Err(e) => {
-									println!("WARN: Could not start additional thread: ", e)
+									println!(
+										"WARN: [{}] could not start additional thread: {}",
+										name, e
+									)
 								}
 							}
 						}
+						job
 					}
-					let res = t.0();
-					*t.2 = true;
-					match t.1.send(res) {
-						Ok(_) => {}
+				};
+				// a task aborted while it was queued is skipped
+				let skip = job.cancelled.load() != 0;
+				let (wall, cpu) = if task_stats && !skip {
+					unsafe { (getmicros(), thread_cpu_micros()) }
+				} else {
+					(0, 0)
+				};
+				let res = if skip {
+					Err(err!(Cancelled))
+				} else {
+					match (job.task)() {
+						Ok(v) => Ok(v),
 						Err(e) => {
-							println!("WARN: could not send result: ", e);
+							state.lock().failed += 1;
+							println!("WARN: [{}] task failed: {}", name, e);
+							Err(e)
 						}
 					}
+				};
+				if task_stats && !skip {
+					let (wall_end, cpu_end) = unsafe { (getmicros(), thread_cpu_micros()) };
+					state.lock().record(
+						job.tag,
+						wall_end.saturating_sub(wall) as u64,
+						cpu_end.saturating_sub(cpu),
+					);
+				}
+				job.is_complete.store(1);
+				match job.result.send(res) {
+					Ok(_) => {}
+					Err(e) => {
+						println!("WARN: [{}] could not send result: {}", name, e);
+					}
 				}
-				Message::Halt => {}
 			}
 		}) {
 			Ok(jh) => jh,
@@ -270,13 +679,10 @@ impl<T> Runtime<T> {
 			}
 		};
 
-		let _l = lock_clone.write();
-		let jhent = JhEntry { jh: Some(jh), id };
-		let ptr = match Ptr::alloc(Node::new(jhent)) {
-			Ok(ptr) => ptr,
+		match guard.jhs.insert(id, jh) {
+			Ok(_) => {}
 			Err(e) => return Err(e),
-		};
-		state_clone.jhs.insert(ptr);
+		}
 
 		Ok(())
 	}
@@ -285,42 +691,40 @@ impl<T> Runtime<T> {
 #[cfg(test)]
 mod test {
 	use super::*;
-	use ffi::getalloccount;
+	use ffi::sleep_millis;
 	#[test]
 	fn test_runtime1() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut x = Runtime::new(RuntimeConfig::default()).unwrap();
-			assert!(x.start().is_ok());
-			let (send1, recv1) = channel().unwrap();
-			let (send2, recv2) = channel().unwrap();
-			let handle1 = x
-				.execute(move || -> i32 {
-					assert_eq!(recv1.recv(), 8);
-					7
-				})
-				.unwrap();
-
-			assert!(!handle1.is_complete());
-			send1.send(8).unwrap();
-
-			assert_eq!(handle1.block_on(), 7);
-			assert!(handle1.is_complete());
-
-			let handle2 = x
-				.execute(move || -> i32 {
-					send2.send(9).unwrap();
-					6
-				})
-				.unwrap();
-
-			assert_eq!(recv2.recv(), 9);
-			assert_eq!(handle2.block_on(), 6);
-			assert!(handle2.is_complete());
-
-			assert!(x.stop().is_ok());
-		}
-		assert_eq!(initial, unsafe { getalloccount() });
+		let _alloc = AllocGuard::new();
+		let mut x = Runtime::new(RuntimeConfig::default()).unwrap();
+		assert!(x.start().is_ok());
+		let (send1, recv1) = channel().unwrap();
+		let (send2, recv2) = channel().unwrap();
+		let handle1 = x
+			.execute(move || -> i32 {
+				assert_eq!(recv1.recv(), 8);
+				7
+			})
+			.unwrap();
+
+		assert!(!handle1.is_complete());
+		assert!(handle1.block_on_timeout(1_000).is_err());
+		send1.send(8).unwrap();
+
+		assert!(handle1.block_on_timeout(10_000_000) == Ok(7));
+		assert!(handle1.is_complete());
+
+		let handle2 = x
+			.execute(move || -> i32 {
+				send2.send(9).unwrap();
+				6
+			})
+			.unwrap();
+
+		assert_eq!(recv2.recv(), 9);
+		assert_eq!(handle2.block_on().unwrap(), 6);
+		assert!(handle2.is_complete());
+
+		assert!(x.stop().is_ok());
 	}
 
 	#[test]
@@ -328,6 +732,7 @@ mod test {
 		let config = RuntimeConfig {
 			min_threads: 2,
 			max_threads: 3,
+			..RuntimeConfig::default()
 		};
 		let mut x: Runtime<()> = Runtime::new(config).unwrap();
 		assert!(x.start().is_ok());
@@ -353,17 +758,17 @@ mod test {
 		recv1.recv();
 		recv2.recv();
 
-		while x.idle_threads() != 1 {}
+		x.wait_for(|s| s.waiting_workers == 1);
 		assert_eq!(x.idle_threads(), 1);
 		assert_eq!(x.cur_threads(), 3);
 
 		assert!(senda1.send(()).is_ok());
 		assert!(senda2.send(()).is_ok());
 
-		assert_eq!(h1.block_on(), ());
-		assert_eq!(h2.block_on(), ());
+		assert!(h1.block_on().is_ok());
+		assert!(h2.block_on().is_ok());
 
-		while x.cur_threads() != 2 {}
+		x.wait_for(|s| s.total_workers == 2);
 		assert_eq!(x.cur_threads(), 2);
 		assert_eq!(x.idle_threads(), 2);
 
@@ -372,166 +777,430 @@ mod test {
 
 	#[test]
 	fn test_thread_pool_size() {
-		let initial = unsafe { getalloccount() };
-		{
-			let mut r = Runtime::new(RuntimeConfig {
-				min_threads: 2,
-				max_threads: 4,
+		let _alloc = AllocGuard::new();
+		let mut r = Runtime::new(RuntimeConfig {
+			min_threads: 2,
+			max_threads: 4,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		r.start().unwrap();
+
+		r.wait_for(|s| s.waiting_workers == 2);
+
+		let (senda1, recva1) = channel().unwrap();
+		let (sendb1, recvb1) = channel().unwrap();
+		let (sendc1, recvc1) = channel().unwrap();
+
+		let x1 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva1.recv(), 1);
+				sendb1.send(1).unwrap();
+				assert_eq!(recvc1.recv(), 1);
+				Ok(1)
+			})
+			.unwrap();
+
+		let (senda2, recva2) = channel().unwrap();
+		let (sendb2, recvb2) = channel().unwrap();
+		let (sendc2, recvc2) = channel().unwrap();
+
+		let x2 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva2.recv(), 2);
+				sendb2.send(2).unwrap();
+				assert_eq!(recvc2.recv(), 2);
+				Ok(2)
+			})
+			.unwrap();
+
+		senda1.send(1).unwrap();
+		senda2.send(2).unwrap();
+
+		assert_eq!(recvb1.recv(), 1);
+		assert_eq!(recvb2.recv(), 2);
+
+		// we know there should be three threads spawned at this point because at least one
+		// waiting worker is maintained.
+		assert_eq!(r.cur_threads(), 3);
+
+		sendc1.send(1).unwrap();
+		sendc2.send(2).unwrap();
+
+		assert_eq!(x1.block_on().unwrap().unwrap(), 1);
+		assert_eq!(x2.block_on().unwrap().unwrap(), 2);
+
+		r.wait_for(|s| s.total_workers == 2);
+
+		// The other two threads have exited so we should be back down to our min
+		assert_eq!(r.cur_threads(), 2);
+
+		// now start up 5 threads (we'll hit our limit of 4)
+		let (senda1, recva1) = channel().unwrap();
+		let (sendb1, recvb1) = channel().unwrap();
+		let (sendc1, recvc1) = channel().unwrap();
+
+		let x1 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva1.recv(), 1);
+				sendb1.send(1).unwrap();
+				assert_eq!(recvc1.recv(), 1);
+				Ok(1)
+			})
+			.unwrap();
+
+		let (senda2, recva2) = channel().unwrap();
+		let (sendb2, recvb2) = channel().unwrap();
+		let (sendc2, recvc2) = channel().unwrap();
+
+		let x2 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva2.recv(), 2);
+				sendb2.send(2).unwrap();
+				assert_eq!(recvc2.recv(), 2);
+				Ok(2)
+			})
+			.unwrap();
+
+		let (senda3, recva3) = channel().unwrap();
+		let (sendb3, recvb3) = channel().unwrap();
+		let (sendc3, recvc3) = channel().unwrap();
+
+		let x3 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva3.recv(), 3);
+				sendb3.send(3).unwrap();
+				assert_eq!(recvc3.recv(), 3);
+				Ok(3)
+			})
+			.unwrap();
+
+		let (senda4, recva4) = channel().unwrap();
+		let (sendb4, recvb4) = channel().unwrap();
+		let (sendc4, recvc4) = channel().unwrap();
+
+		let x4 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva4.recv(), 4);
+				sendb4.send(4).unwrap();
+				assert_eq!(recvc4.recv(), 4);
+				Ok(4)
+			})
+			.unwrap();
+
+		let (senda5, recva5) = channel().unwrap();
+		let (sendb5, recvb5) = channel().unwrap();
+		let (sendc5, recvc5) = channel().unwrap();
+
+		let x5 = r
+			.execute(move || -> Result<i32, Error> {
+				assert_eq!(recva5.recv(), 5);
+				sendb5.send(5).unwrap();
+				assert_eq!(recvc5.recv(), 5);
+				Ok(5)
 			})
 			.unwrap();
-			r.start().unwrap();
-
-			while r.idle_threads() != 2 {}
-
-			let (senda1, recva1) = channel().unwrap();
-			let (sendb1, recvb1) = channel().unwrap();
-			let (sendc1, recvc1) = channel().unwrap();
-
-			let x1 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva1.recv(), 1);
-					sendb1.send(1).unwrap();
-					assert_eq!(recvc1.recv(), 1);
-					Ok(1)
-				})
-				.unwrap();
-
-			let (senda2, recva2) = channel().unwrap();
-			let (sendb2, recvb2) = channel().unwrap();
-			let (sendc2, recvc2) = channel().unwrap();
-
-			let x2 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva2.recv(), 2);
-					sendb2.send(2).unwrap();
-					assert_eq!(recvc2.recv(), 2);
-					Ok(2)
-				})
-				.unwrap();
-
-			senda1.send(1).unwrap();
-			senda2.send(2).unwrap();
-
-			assert_eq!(recvb1.recv(), 1);
-			assert_eq!(recvb2.recv(), 2);
-
-			// we know there should be three threads spawned at this point because at least one
-			// waiting worker is maintained.
-			assert_eq!(r.cur_threads(), 3);
-
-			sendc1.send(1).unwrap();
-			sendc2.send(2).unwrap();
-
-			assert_eq!(x1.block_on().unwrap(), 1);
-			assert_eq!(x2.block_on().unwrap(), 2);
-
-			while r.cur_threads() != 2 {}
-
-			// The other two threads have exited so we should be back down to our min
-			assert_eq!(r.cur_threads(), 2);
-
-			// now start up 5 threads (we'll hit our limit of 4)
-			let (senda1, recva1) = channel().unwrap();
-			let (sendb1, recvb1) = channel().unwrap();
-			let (sendc1, recvc1) = channel().unwrap();
-
-			let x1 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva1.recv(), 1);
-					sendb1.send(1).unwrap();
-					assert_eq!(recvc1.recv(), 1);
-					Ok(1)
-				})
-				.unwrap();
-
-			let (senda2, recva2) = channel().unwrap();
-			let (sendb2, recvb2) = channel().unwrap();
-			let (sendc2, recvc2) = channel().unwrap();
-
-			let x2 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva2.recv(), 2);
-					sendb2.send(2).unwrap();
-					assert_eq!(recvc2.recv(), 2);
-					Ok(2)
-				})
-				.unwrap();
-
-			let (senda3, recva3) = channel().unwrap();
-			let (sendb3, recvb3) = channel().unwrap();
-			let (sendc3, recvc3) = channel().unwrap();
-
-			let x3 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva3.recv(), 3);
-					sendb3.send(3).unwrap();
-					assert_eq!(recvc3.recv(), 3);
-					Ok(3)
-				})
-				.unwrap();
-
-			let (senda4, recva4) = channel().unwrap();
-			let (sendb4, recvb4) = channel().unwrap();
-			let (sendc4, recvc4) = channel().unwrap();
-
-			let x4 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva4.recv(), 4);
-					sendb4.send(4).unwrap();
-					assert_eq!(recvc4.recv(), 4);
-					Ok(4)
-				})
-				.unwrap();
-
-			let (senda5, recva5) = channel().unwrap();
-			let (sendb5, recvb5) = channel().unwrap();
-			let (sendc5, recvc5) = channel().unwrap();
-
-			let x5 = r
-				.execute(move || -> Result<i32, Error> {
-					assert_eq!(recva5.recv(), 5);
-					sendb5.send(5).unwrap();
-					assert_eq!(recvc5.recv(), 5);
-					Ok(5)
-				})
-				.unwrap();
-
-			senda1.send(1).unwrap();
-			senda2.send(2).unwrap();
-			senda3.send(3).unwrap();
-			senda4.send(4).unwrap();
-
-			assert_eq!(recvb1.recv(), 1);
-			assert_eq!(recvb2.recv(), 2);
-			assert_eq!(recvb3.recv(), 3);
-			assert_eq!(recvb4.recv(), 4);
-
-			// we are now at our max threads (4) there would have been a 5th, but we hit the
-			// max.
-			assert_eq!(r.cur_threads(), 4);
-
-			// send messages to release all threads
-			senda5.send(5).unwrap();
-			sendc1.send(1).unwrap();
-			sendc2.send(2).unwrap();
-			sendc3.send(3).unwrap();
-			sendc4.send(4).unwrap();
-			sendc5.send(5).unwrap();
-
-			// thread 5 can now complete
-			assert_eq!(recvb5.recv(), 5);
-
-			while r.cur_threads() != 2 {}
-
-			// After things settle down we should return to our min thread level of 2
-			assert_eq!(r.cur_threads(), 2);
-
-			assert_eq!(x1.block_on().unwrap(), 1);
-			assert_eq!(x2.block_on().unwrap(), 2);
-			assert_eq!(x3.block_on().unwrap(), 3);
-			assert_eq!(x4.block_on().unwrap(), 4);
-			assert_eq!(x5.block_on().unwrap(), 5);
+
+		senda1.send(1).unwrap();
+		senda2.send(2).unwrap();
+		senda3.send(3).unwrap();
+		senda4.send(4).unwrap();
+
+		assert_eq!(recvb1.recv(), 1);
+		assert_eq!(recvb2.recv(), 2);
+		assert_eq!(recvb3.recv(), 3);
+		assert_eq!(recvb4.recv(), 4);
+
+		// we are now at our max threads (4) there would have been a 5th, but we hit the
+		// max.
+		assert_eq!(r.cur_threads(), 4);
+
+		// send messages to release all threads
+		senda5.send(5).unwrap();
+		sendc1.send(1).unwrap();
+		sendc2.send(2).unwrap();
+		sendc3.send(3).unwrap();
+		sendc4.send(4).unwrap();
+		sendc5.send(5).unwrap();
+
+		// thread 5 can now complete
+		assert_eq!(recvb5.recv(), 5);
+
+		r.wait_for(|s| s.total_workers == 2);
+
+		// After things settle down we should return to our min thread level of 2
+		assert_eq!(r.cur_threads(), 2);
+
+		assert_eq!(x1.block_on().unwrap().unwrap(), 1);
+		assert_eq!(x2.block_on().unwrap().unwrap(), 2);
+		assert_eq!(x3.block_on().unwrap().unwrap(), 3);
+		assert_eq!(x4.block_on().unwrap().unwrap(), 4);
+		assert_eq!(x5.block_on().unwrap().unwrap(), 5);
+	}
+
+	#[test]
+	fn test_task_stats() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig {
+			min_threads: 2,
+			max_threads: 2,
+			task_stats: true,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+		assert_eq!(r.task_stats().unwrap().len(), 0);
+
+		for i in 0..3 {
+			let h = r.execute_tagged("slow", move || {
+				unsafe {
+					sleep_millis(5);
+				}
+				i
+			});
+			assert_eq!(h.unwrap().block_on().unwrap(), i);
+		}
+		for i in 0..5 {
+			assert_eq!(r.execute(move || i).unwrap().block_on().unwrap(), i);
 		}
-		assert_eq!(initial, unsafe { getalloccount() });
+
+		let stats = r.task_stats().unwrap();
+		assert_eq!(stats.len(), 2);
+		assert_eq!(stats[0].tag, "slow");
+		assert_eq!(stats[0].count, 3);
+		assert!(stats[0].max_wall_micros >= 5_000);
+		assert!(stats[0].wall_micros >= 15_000);
+		// sleeping uses almost no CPU
+		assert!(stats[0].cpu_micros < stats[0].wall_micros);
+		let mut slow = 0;
+		for b in 13..LATENCY_BUCKETS {
+			slow += stats[0].latency[b];
+		}
+		assert_eq!(slow, 3);
+		assert_eq!(stats[1].tag, DEFAULT_TASK_TAG);
+		assert_eq!(stats[1].count, 5);
+		let mut total = 0;
+		for b in 0..LATENCY_BUCKETS {
+			total += stats[1].latency[b];
+		}
+		assert_eq!(total, 5);
+		assert!(r.stop().is_ok());
+
+		// disabled by default
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig::default()).unwrap();
+		assert!(r.start().is_ok());
+		assert_eq!(
+			r.execute_tagged("slow", || 1).unwrap().block_on().unwrap(),
+			1
+		);
+		assert_eq!(r.task_stats().unwrap().len(), 0);
+		assert!(r.stop().is_ok());
+	}
+
+	#[test]
+	fn test_runtime_name() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<String> = Runtime::new(RuntimeConfig {
+			name: "pool",
+			min_threads: 1,
+			max_threads: 1,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+		let name = r
+			.execute(|| thread_name().unwrap())
+			.unwrap()
+			.block_on()
+			.unwrap();
+		assert!(name.to_str().starts_with("pool-"));
+		assert!(r.stop().is_ok());
+	}
+
+	#[test]
+	fn test_abort() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig {
+			min_threads: 1,
+			max_threads: 1,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+
+		// the only worker is busy, so the second task is aborted while queued
+		let (send, recv) = channel().unwrap();
+		let busy = r
+			.execute(move || {
+				recv.recv();
+				1
+			})
+			.unwrap();
+		let queued = r.execute(|| 2).unwrap();
+		assert!(!queued.is_cancelled());
+		queued.abort();
+		assert!(queued.is_cancelled());
+		send.send(()).unwrap();
+		assert_eq!(busy.block_on().unwrap(), 1);
+		assert!(queued.block_on().unwrap_err().kind == Cancelled);
+		assert!(queued.is_complete());
+		assert!(queued.block_on_timeout(1_000).unwrap_err().kind == Timeout);
+
+		// a running task stops once it sees the abort
+		let (started_send, started_recv) = channel().unwrap();
+		let running = r
+			.execute_cancellable(move |token: &CancelToken| {
+				started_send.send(()).unwrap();
+				let mut polls = 0;
+				while !token.is_cancelled() {
+					polls += 1;
+					unsafe {
+						sleep_millis(1);
+					}
+				}
+				polls
+			})
+			.unwrap();
+		started_recv.recv();
+		assert!(running.block_on_timeout(1_000).is_err());
+		running.abort();
+		assert!(running.block_on_timeout(10_000_000).is_ok());
+
+		// the pool is still usable
+		assert_eq!(r.execute(|| 3).unwrap().block_on().unwrap(), 3);
+		assert!(r.stop().is_ok());
+	}
+
+	#[test]
+	fn test_work_stealing() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig {
+			min_threads: 2,
+			max_threads: 2,
+			queue_capacity: 4,
+			bounded: true,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+		r.wait_for(|s| s.waiting_workers == 2);
+
+		// one worker is held, the tasks pushed onto its queue are taken by the other
+		let (send, recv) = channel().unwrap();
+		let held = r
+			.execute(move || {
+				recv.recv();
+				0
+			})
+			.unwrap();
+		let mut handles = Vec::new();
+		for i in 1..7 {
+			handles.push(r.execute(move || i).unwrap()).unwrap();
+		}
+		for i in 0..handles.len() {
+			assert_eq!(handles[i].block_on().unwrap(), i as u64 + 1);
+		}
+		assert!(!held.is_complete());
+
+		// with both workers held the queues fill up
+		let (send2, recv2) = channel().unwrap();
+		let held2 = r
+			.execute(move || {
+				recv2.recv();
+				0
+			})
+			.unwrap();
+		let mut queued = Vec::new();
+		loop {
+			match r.execute(|| 1) {
+				Ok(h) => queued.push(h).unwrap(),
+				Err(e) => {
+					assert!(e.kind == ChannelFull);
+					break;
+				}
+			}
+		}
+		assert!(queued.len() <= 8);
+		send.send(()).unwrap();
+		send2.send(()).unwrap();
+		assert_eq!(held.block_on().unwrap(), 0);
+		assert_eq!(held2.block_on().unwrap(), 0);
+		for i in 0..queued.len() {
+			assert_eq!(queued[i].block_on().unwrap(), 1);
+		}
+		assert!(r.stop().is_ok());
+		assert!(r.execute(|| 1).is_err());
+	}
+
+	#[test]
+	fn test_overflow() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig {
+			min_threads: 1,
+			max_threads: 1,
+			queue_capacity: 2,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+
+		// with the only worker held, tasks beyond the queue's capacity are still accepted
+		let (send, recv) = channel().unwrap();
+		let held = r
+			.execute(move || {
+				recv.recv();
+				0
+			})
+			.unwrap();
+		let mut handles = Vec::new();
+		for i in 0..20 {
+			handles.push(r.execute(move || i).unwrap()).unwrap();
+		}
+		assert!(aload!(r.shared.overflowed.get()) > 0);
+		send.send(()).unwrap();
+		assert_eq!(held.block_on().unwrap(), 0);
+		for i in 0..handles.len() {
+			assert_eq!(handles[i].block_on().unwrap(), i as u64);
+		}
+		assert_eq!(aload!(r.shared.overflowed.get()), 0);
+
+		// tasks still in the overflow queue are freed with the runtime
+		let (send, recv) = channel().unwrap();
+		let held = r
+			.execute(move || {
+				recv.recv();
+				0
+			})
+			.unwrap();
+		for i in 0..8 {
+			let _ = r.execute(move || i).unwrap();
+		}
+		send.send(()).unwrap();
+		assert_eq!(held.block_on().unwrap(), 0);
+		assert!(r.stop().is_ok());
+	}
+
+	#[test]
+	fn test_execute_fallible() {
+		let _alloc = AllocGuard::new();
+		let mut r: Runtime<u64> = Runtime::new(RuntimeConfig {
+			min_threads: 1,
+			max_threads: 1,
+			..RuntimeConfig::default()
+		})
+		.unwrap();
+		assert!(r.start().is_ok());
+
+		let failed = r.execute_fallible(|| Err(err!(IllegalState))).unwrap();
+		assert!(failed.block_on().unwrap_err().kind == IllegalState);
+		assert!(failed.is_complete());
+		assert_eq!(r.failed_tasks(), 1);
+
+		// the only worker survived and runs the next task
+		assert_eq!(r.execute_fallible(|| Ok(5)).unwrap().block_on().unwrap(), 5);
+		assert_eq!(r.execute(|| 4).unwrap().block_on().unwrap(), 4);
+		assert_eq!(r.failed_tasks(), 1);
+		assert_eq!(r.cur_threads(), 1);
+		assert!(r.stop().is_ok());
 	}
 }
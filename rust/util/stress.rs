@@ -0,0 +1,259 @@
+//! # Concurrency stress tests
+//! Hammers the channel, Arc, runtime and ws paths from many threads. Thread counts and
+//! iterations are controlled through [`StressConfig`] so the same scenarios can be run
+//! briefly as part of the normal test suite or for much longer under a thread sanitizer
+//! (build the C objects with `-fsanitize=thread` and pass `--cfg tsan` to rustc to
+//! enable the Arc annotations).
+
+use core::str::from_utf8_unchecked;
+use net::ws::*;
+use prelude::*;
+
+#[derive(Clone, Copy)]
+pub struct StressConfig {
+	pub threads: u64,
+	pub iterations: u64,
+}
+
+impl Default for StressConfig {
+	fn default() -> Self {
+		Self {
+			threads: 4,
+			iterations: 100,
+		}
+	}
+}
+
+/// Multiple producers sending on clones of the same channel while a single consumer drains it.
+pub fn stress_channel(config: StressConfig) -> Result<(), Error> {
+	let (send, recv) = match channel() {
+		Ok((send, recv)) => (send, recv),
+		Err(e) => return Err(e),
+	};
+	let mut jhs = Vec::new();
+	for t in 0..config.threads {
+		let send = match send.clone() {
+			Ok(send) => send,
+			Err(e) => return Err(e),
+		};
+		let iterations = config.iterations;
+		let jh = match spawnj(move || {
+			for i in 0..iterations {
+				send.send(t * iterations + i).unwrap();
+			}
+		}) {
+			Ok(jh) => jh,
+			Err(e) => return Err(e),
+		};
+		match jhs.push(jh) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+
+	let total = config.threads * config.iterations;
+	let mut sum = 0;
+	for _i in 0..total {
+		sum += recv.recv();
+	}
+	for i in 0..jhs.len() {
+		match jhs[i].join() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	if total > 0 && sum != total * (total - 1) / 2 {
+		return Err(err!(IllegalState));
+	}
+	Ok(())
+}
+
+/// Clone and drop a shared Arc from many threads, checking the reference count settles.
+pub fn stress_arc(config: StressConfig) -> Result<(), Error> {
+	let mut arc = match Arc::new(AtomicU64::new(0)) {
+		Ok(arc) => arc,
+		Err(e) => return Err(e),
+	};
+	let mut jhs = Vec::new();
+	for _t in 0..config.threads {
+		let arc = match arc.clone() {
+			Ok(arc) => arc,
+			Err(e) => return Err(e),
+		};
+		let iterations = config.iterations;
+		let jh = match spawnj(move || {
+			for _i in 0..iterations {
+				let clone = arc.clone().unwrap();
+				clone.fetch_add(1);
+			}
+		}) {
+			Ok(jh) => jh,
+			Err(e) => return Err(e),
+		};
+		match jhs.push(jh) {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	for i in 0..jhs.len() {
+		match jhs[i].join() {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+	}
+	match arc.get_mut() {
+		Some(v) => {
+			if *v.get_mut() != config.threads * config.iterations {
+				return Err(err!(IllegalState));
+			}
+		}
+		None => return Err(err!(IllegalState)),
+	}
+	Ok(())
+}
+
+/// Bursts of blocking tasks which force the runtime to scale up to its maximum and back down.
+pub fn stress_runtime(config: StressConfig) -> Result<(), Error> {
+	let mut r = match Runtime::new(RuntimeConfig {
+		min_threads: 1,
+		max_threads: config.threads + 1,
+		..RuntimeConfig::default()
+	}) {
+		Ok(r) => r,
+		Err(e) => return Err(e),
+	};
+	match r.start() {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	for i in 0..config.iterations {
+		let (send, recv) = match channel() {
+			Ok((send, recv)) => (send, recv),
+			Err(e) => return Err(e),
+		};
+		let mut handles = Vec::new();
+		for t in 0..config.threads {
+			let recv = match recv.clone() {
+				Ok(recv) => recv,
+				Err(e) => return Err(e),
+			};
+			let handle = match r.execute(move || -> u64 {
+				let _: () = recv.recv();
+				i + t
+			}) {
+				Ok(handle) => handle,
+				Err(e) => return Err(e),
+			};
+			match handles.push(handle) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		for _t in 0..config.threads {
+			match send.send(()) {
+				Ok(_) => {}
+				Err(e) => return Err(e),
+			}
+		}
+		for t in 0..handles.len() {
+			match handles[t].block_on() {
+				Ok(v) => {
+					if v != i + t as u64 {
+						return Err(err!(IllegalState));
+					}
+				}
+				Err(e) => return Err(e),
+			}
+		}
+	}
+	r.stop()
+}
+
+/// Repeatedly connect loopback clients, exchange a message and close the connection.
+pub fn stress_ws(config: StressConfig) -> Result<(), Error> {
+	let mut ws = match WebSocket::new(WsConfig {
+		threads: config.threads,
+		..WsConfig::default()
+	}) {
+		Ok(ws) => ws,
+		Err(e) => return Err(e),
+	};
+	let (send, recv) = match channel() {
+		Ok((send, recv)) => (send, recv),
+		Err(e) => return Err(e),
+	};
+	let handler = match Box::new(move |req: WsRequest, mut resp: WsResponse| {
+		let s = unsafe { from_utf8_unchecked(req.msg()) };
+		if s == "ping" {
+			resp.send("pong")
+		} else {
+			send.send(())
+		}
+	}) {
+		Ok(handler) => handler,
+		Err(e) => return Err(e),
+	};
+	match ws.register_handler(handler) {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	match ws.start() {
+		Ok(_) => {}
+		Err(e) => return Err(e),
+	}
+	for _i in 0..config.iterations {
+		let mut client = match ws.add_loopback() {
+			Ok(client) => client,
+			Err(e) => return Err(e),
+		};
+		match client.send("ping") {
+			Ok(_) => {}
+			Err(e) => return Err(e),
+		}
+		recv.recv();
+		client.close(1000);
+	}
+	ws.stop()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_stress_channel() {
+		let _alloc = AllocGuard::new();
+		assert!(stress_channel(StressConfig::default()).is_ok());
+	}
+
+	#[test]
+	fn test_stress_arc() {
+		let _alloc = AllocGuard::new();
+		assert!(stress_arc(StressConfig {
+			threads: 8,
+			iterations: 1_000,
+		})
+		.is_ok());
+	}
+
+	#[test]
+	fn test_stress_runtime() {
+		let _alloc = AllocGuard::new();
+		assert!(stress_runtime(StressConfig {
+			threads: 4,
+			iterations: 20,
+		})
+		.is_ok());
+	}
+
+	#[test]
+	fn test_stress_ws() {
+		let _alloc = AllocGuard::new();
+		let _fds = FdGuard::new();
+		assert!(stress_ws(StressConfig {
+			threads: 4,
+			iterations: 50,
+		})
+		.is_ok());
+	}
+}